@@ -6,18 +6,23 @@ use log::*;
 use stderrlog;
 use structopt::StructOpt;
 
+use dychatat_lib::exit_status::{ExitStatus, EXIT_STATUS_HELP};
+
 use sub_cmds::ManageRepositories;
 
 /// A StructOpt example
 #[derive(StructOpt, Debug)]
 #[structopt()]
 struct Dychatat {
-    /// Silence all output
+    /// Silence all messages (results are still written to stdout)
     #[structopt(short = "q", long = "quiet")]
     quiet: bool,
-    /// Verbose mode (-v, -vv, -vvv, etc)
+    /// Verbose mode (-v warnings, -vv information, -vvv etc debugging)
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: usize,
+    /// Colour messages (auto, always, never).  "auto" colours them if stderr is a terminal and NO_COLOR isn't set
+    #[structopt(long = "color", value_name = "when", default_value = "auto", parse(try_from_str = parse_color_choice))]
+    color: stderrlog::ColorChoice,
     /// Timestamp (sec, ms, ns, none)
     #[structopt(short = "t", long = "timestamp")]
     ts: Option<stderrlog::Timestamp>,
@@ -26,13 +31,32 @@ struct Dychatat {
     sub_cmd: ManageRepositories,
 }
 
+fn parse_color_choice(text: &str) -> Result<stderrlog::ColorChoice, String> {
+    match text {
+        "auto" => Ok(stderrlog::ColorChoice::Auto),
+        "always" => Ok(stderrlog::ColorChoice::Always),
+        "never" => Ok(stderrlog::ColorChoice::Never),
+        _ => Err(format!("{}: expected auto, always or never", text)),
+    }
+}
+
 fn main() {
-    let dychatat = Dychatat::from_args();
+    let app = Dychatat::clap().after_help(EXIT_STATUS_HELP);
+    let dychatat = match app.get_matches_safe() {
+        Ok(matches) => Dychatat::from_clap(&matches),
+        // help and version "errors" aren't failures
+        Err(err) if !err.use_stderr() => err.exit(),
+        Err(err) => {
+            eprintln!("{}", err.message);
+            std::process::exit(ExitStatus::Usage.code())
+        }
+    };
 
     stderrlog::new()
         //.module(module_path!())
         .quiet(dychatat.quiet)
         .verbosity(dychatat.verbose)
+        .color(dychatat.color)
         .timestamp(dychatat.ts.unwrap_or(stderrlog::Timestamp::Off))
         .init()
         .unwrap();
@@ -42,8 +66,11 @@ fn main() {
         ManageRepositories::List(sub_cmd) => sub_cmd.exec(),
         ManageRepositories::NewRepo(sub_cmd) => sub_cmd.exec(),
         ManageRepositories::Prune(sub_cmd) => sub_cmd.exec(),
+        ManageRepositories::Relocate(sub_cmd) => sub_cmd.exec(),
+        ManageRepositories::Export(sub_cmd) => sub_cmd.exec(),
+        ManageRepositories::Import(sub_cmd) => sub_cmd.exec(),
     } {
-        error!("{:?}", err);
-        std::process::exit(1);
+        error!("{}", err);
+        std::process::exit(ExitStatus::from(&err).code());
     }
 }
@@ -1,4 +1,6 @@
 // Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au> <pwil3058@outlook.com>
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 
 use structopt::StructOpt;
@@ -20,6 +22,12 @@ pub enum ManageRepositories {
     /// Create a new repository
     #[structopt(alias = "new")]
     NewRepo(NewRepository),
+    /// Record that a repository's directory has been moved
+    Relocate(RelocateRepository),
+    /// Export a repository's contents (with their reference counts)
+    Export(ExportRepository),
+    /// Import exported contents into a repository
+    Import(ImportRepository),
 }
 //
 // impl ManageRepositories {
@@ -30,6 +38,9 @@ pub enum ManageRepositories {
 //             Delete(sub_cmd) => sub_cmd.exec(),
 //             Prune(sub_cmd) => sub_cmd.exec(),
 //             NewRepo(sub_cmd) => sub_cmd.exec(),
+//             Relocate(sub_cmd) => sub_cmd.exec(),
+//             Export(sub_cmd) => sub_cmd.exec(),
+//             Import(sub_cmd) => sub_cmd.exec(),
 //         }
 //     }
 // }
@@ -104,3 +115,88 @@ impl NewRepository {
         content::create_new_repo(&self.repo_name, &self.location, &self.algorithm)
     }
 }
+
+#[derive(Debug, StructOpt)]
+/// Update a content repository's specification after its directory has been moved.
+///
+/// The old location is remembered so that snapshots made before the move can
+/// still find their contents.
+pub struct RelocateRepository {
+    /// The name of the repository that has been moved
+    #[structopt(long = "repo")]
+    repo_name: String,
+    /// The repository's new location (i.e. the directory containing its "ref_count" file)
+    #[structopt(long = "to", parse(from_os_str))]
+    location: PathBuf,
+}
+
+impl RelocateRepository {
+    pub fn exec(&self) -> RepoResult<()> {
+        content::relocate_repo(&self.repo_name, &self.location)
+    }
+}
+
+#[derive(Debug, StructOpt)]
+/// Export a content repository's referenced contents (uncompressed) and their
+/// reference counts to a new directory or (if its name ends with ".tar") tar file.
+///
+/// The export can be imported into another repository, e.g. one in a new
+/// location or using pack files.
+pub struct ExportRepository {
+    /// The name of the repository whose contents are to be exported
+    #[structopt(long = "repo")]
+    repo_name: String,
+    /// The directory or tar file to be created
+    #[structopt(long = "to", parse(from_os_str))]
+    path: PathBuf,
+}
+
+impl ExportRepository {
+    pub fn exec(&self) -> RepoResult<()> {
+        let stats = content::export_repository(&self.repo_name, &self.path)?;
+        println!(
+            "{} items ({} bytes) with {} references exported",
+            stats.items_exported, stats.bytes_exported, stats.references_exported
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, StructOpt)]
+/// Import contents exported (by "export") into a content repository adding
+/// their reference counts to any it already has.
+///
+/// Contents exported from a repository using a different hash algorithm are
+/// only imported if a token map is requested (so that references to them can
+/// be re-keyed).
+pub struct ImportRepository {
+    /// The name of the repository that the contents are to be imported into
+    #[structopt(long = "repo")]
+    repo_name: String,
+    /// The export directory or tar file
+    #[structopt(long = "from", parse(from_os_str))]
+    path: PathBuf,
+    /// Store contents hashed with a different algorithm under new tokens and
+    /// write their old and new tokens (one "OLD NEW" pair per line) to this file
+    #[structopt(long = "token-map", parse(from_os_str))]
+    token_map: Option<PathBuf>,
+}
+
+impl ImportRepository {
+    pub fn exec(&self) -> RepoResult<()> {
+        let rekey = self.token_map.is_some();
+        let stats = content::import_repository(&self.repo_name, &self.path, rekey)?;
+        if let Some(ref token_map_path) = self.token_map {
+            let mut file = BufWriter::new(File::create(token_map_path)?);
+            for (old_token, new_token) in stats.token_map.iter() {
+                writeln!(file, "{} {}", old_token, new_token)?;
+            }
+            file.flush()?;
+        }
+        println!(
+            "{} items added ({} bytes stored), {} merged, {} references imported",
+            stats.items_added, stats.bytes_stored, stats.items_merged, stats.references_imported
+        );
+        Ok(())
+    }
+}
@@ -45,12 +45,59 @@ pub fn get_repo_config_dir_path() -> PathBuf {
     get_config_dir_path().join("repos")
 }
 
+pub fn get_relocations_file_path() -> PathBuf {
+    get_config_dir_path().join("relocations")
+}
+
+/// Point the configuration at a new temporary directory (its "config"
+/// subdirectory) for the lifetime of the guard.  Guards are held one at a
+/// time as the environment is shared by the (parallel) test threads.
+#[cfg(test)]
+pub(crate) struct TestConfigGuard {
+    saved: Option<std::ffi::OsString>,
+    dir: tempdir::TempDir,
+    _lock: std::sync::MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+static CONFIG_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[cfg(test)]
+impl TestConfigGuard {
+    pub fn new() -> Self {
+        let lock = CONFIG_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let dir = tempdir::TempDir::new("TEST_CONFIG").unwrap();
+        let saved = env::var_os(DCDP_OVERRIDE_ENVAR);
+        env::set_var(DCDP_OVERRIDE_ENVAR, dir.path().join("config"));
+        Self {
+            saved,
+            dir,
+            _lock: lock,
+        }
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        self.dir.path()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestConfigGuard {
+    fn drop(&mut self) {
+        match self.saved {
+            Some(ref value) => env::set_var(DCDP_OVERRIDE_ENVAR, value),
+            None => env::remove_var(DCDP_OVERRIDE_ENVAR),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn get_config_dir_works() {
+        let _guard = TestConfigGuard::new();
         let new_path = "./TEST/config";
         env::set_var(DCDP_OVERRIDE_ENVAR, new_path);
         assert_eq!(get_config_dir_path(), PathBuf::from(new_path));
@@ -1,14 +1,46 @@
 // Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au> <pwil3058@outlook.com>
+use std::collections::BTreeMap;
 use std::fs::{self, File};
+use std::io::BufWriter;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::UNIX_EPOCH;
 
+use serde::Serialize;
+
+pub use crate::content_store::{ContentStore, MemoryContentStore, ReadSeek, TieredContentStore};
+pub use crate::journal::new_journal_id;
 use crate::UnreferencedContentData;
-pub use crate::{ContentManager, ContentMgmtKey, HashAlgorithm, Mutability, RepoSpec};
+pub use crate::{
+    CacheStats, ContentManager, ContentManagerOptions, ContentMgmtKey, ExportStats, HashAlgorithm,
+    ImportStats, Mutability, RepackStats, RepoComparison, RepoSpec, StoreStats,
+};
 
 use crate::config;
 use crate::{RepoError, RepoResult};
 
+/// The maximum length of a repository (or archive) name.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Why `name` isn't acceptable as a repository (or archive) name (which
+/// are used as file names) or `None` if it is.
+pub fn name_problem(name: &str) -> Option<&'static str> {
+    if name.is_empty() {
+        Some("empty")
+    } else if name.len() > MAX_NAME_LEN {
+        Some("longer than 64 characters")
+    } else if name.starts_with('.') || name.starts_with('-') {
+        Some("starts with '.' or '-'")
+    } else if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        Some("contains characters other than letters, digits, '-', '_' and '.'")
+    } else {
+        None
+    }
+}
+
 pub fn content_repo_exists(repo_name: &str) -> bool {
     get_repo_spec_file_path(repo_name).exists()
 }
@@ -27,6 +59,9 @@ pub fn create_new_repo<P: AsRef<Path>>(
     location: P,
     hash_algortithm_str: &str,
 ) -> RepoResult<()> {
+    if let Some(problem) = name_problem(name) {
+        return Err(RepoError::InvalidRepoName(name.to_string(), problem));
+    }
     if content_repo_exists(name) {
         return Err(RepoError::RepoExists(name.to_string()));
     }
@@ -58,7 +93,7 @@ pub fn read_repo_spec(repo_name: &str) -> RepoResult<RepoSpec> {
     Ok(spec)
 }
 
-fn write_repo_spec(repo_name: &str, repo_spec: &RepoSpec) -> RepoResult<()> {
+pub fn write_repo_spec(repo_name: &str, repo_spec: &RepoSpec) -> RepoResult<()> {
     let spec_file_path = get_repo_spec_file_path(repo_name);
     if spec_file_path.exists() {
         return Err(RepoError::RepoExists(repo_name.to_string()));
@@ -78,6 +113,19 @@ fn write_repo_spec(repo_name: &str, repo_spec: &RepoSpec) -> RepoResult<()> {
     Ok(())
 }
 
+// Replace the named repository's (existing) specification in one go so
+// that an interrupted write leaves the previous specification intact.
+fn replace_repo_spec(repo_name: &str, repo_spec: &RepoSpec) -> RepoResult<()> {
+    let spec_file_path = get_repo_spec_file_path(repo_name);
+    let tmp_file_path = spec_file_path.with_file_name(format!(".{}.tmp", repo_name));
+    let mut writer = BufWriter::new(File::create(&tmp_file_path)?);
+    repo_spec.to_writer(&mut writer)?;
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp_file_path, &spec_file_path)?;
+    Ok(())
+}
+
 pub fn get_repo_names() -> Vec<String> {
     let mut names = Vec::new();
     if let Ok(dir_entries) = fs::read_dir(config::get_repo_config_dir_path()) {
@@ -86,8 +134,11 @@ pub fn get_repo_names() -> Vec<String> {
                 let path = entry.path();
                 if path.is_file() {
                     if let Some(file_name) = path.file_name() {
+                        // skipping any temporary file left by replace_repo_spec()
                         if let Some(file_name) = file_name.to_str() {
-                            names.push(file_name.to_string());
+                            if !file_name.starts_with('.') {
+                                names.push(file_name.to_string());
+                            }
                         }
                     }
                 }
@@ -97,6 +148,133 @@ pub fn get_repo_names() -> Vec<String> {
     names
 }
 
+/// Find the name of the configured repository (if any) that `key` refers to.
+pub fn get_repo_name_for_key(key: &ContentMgmtKey) -> Option<String> {
+    get_repo_names()
+        .into_iter()
+        .find(|repo_name| match read_repo_spec(repo_name) {
+            Ok(spec) => ContentMgmtKey::from(&spec) == *key,
+            Err(_) => false,
+        })
+}
+
+// The maximum size of the configured repository (if any) that `key` refers to.
+pub(crate) fn get_repo_max_size_for_key(key: &ContentMgmtKey) -> Option<u64> {
+    let repo_name = get_repo_name_for_key(key)?;
+    read_repo_spec(&repo_name).ok()?.max_size()
+}
+
+/// Limit the space that the repository's stored contents may occupy (or
+/// remove the limit).  Storing new contents that would take the repository
+/// over the limit fails with `RepoError::RepoQuotaExceeded`.
+pub fn set_repo_max_size(repo_name: &str, max_size: Option<u64>) -> RepoResult<()> {
+    let mut spec = read_repo_spec(repo_name)?;
+    spec.set_max_size(max_size);
+    replace_repo_spec(repo_name, &spec)
+}
+
+type Relocations = BTreeMap<PathBuf, PathBuf>;
+
+fn read_relocations() -> RepoResult<Relocations> {
+    let file_path = config::get_relocations_file_path();
+    if file_path.exists() {
+        let file = File::open(&file_path)?;
+        Ok(serde_yaml::from_reader(file)?)
+    } else {
+        Ok(Relocations::new())
+    }
+}
+
+fn write_relocations(relocations: &Relocations) -> RepoResult<()> {
+    let file_path = config::get_relocations_file_path();
+    if let Some(config_dir_path) = file_path.parent() {
+        if !config_dir_path.exists() {
+            fs::create_dir_all(config_dir_path)?;
+        }
+    }
+    let file = File::create(&file_path)?;
+    serde_yaml::to_writer(file, relocations)?;
+    Ok(())
+}
+
+/// Update the named repository's specification after its directory has been
+/// moved to `new_base_dir_path` and record the move so that keys embedded in
+/// existing data (e.g. ergibus snapshots) can be redirected.
+pub fn relocate_repo<P: AsRef<Path>>(repo_name: &str, new_base_dir_path: P) -> RepoResult<()> {
+    if !content_repo_exists(repo_name) {
+        return Err(RepoError::UnknownRepo(repo_name.to_string()));
+    }
+    let new_base_dir_path = new_base_dir_path.as_ref();
+    if !new_base_dir_path.join("ref_count").is_file() {
+        return Err(RepoError::NotARepoDir(new_base_dir_path.to_path_buf()));
+    }
+    let new_base_dir_path = new_base_dir_path.canonicalize()?;
+    let spec = read_repo_spec(repo_name)?;
+    let mut new_spec = RepoSpec::new(&new_base_dir_path, spec.hash_algorithm);
+    new_spec.set_max_size(spec.max_size());
+    replace_repo_spec(repo_name, &new_spec)?;
+    let mut relocations = read_relocations()?;
+    for target in relocations.values_mut() {
+        if *target == spec.base_dir_path {
+            *target = new_base_dir_path.clone();
+        }
+    }
+    relocations.insert(spec.base_dir_path, new_base_dir_path);
+    relocations.retain(|old, new| old != new);
+    write_relocations(&relocations)
+}
+
+/// The key adjusted for any recorded relocation of its repository.
+pub fn relocated_key(key: &ContentMgmtKey) -> RepoResult<ContentMgmtKey> {
+    match read_relocations()?.get(key.base_dir_path()) {
+        Some(new_base_dir_path) => Ok(key.with_base_dir_path(new_base_dir_path)),
+        None => Ok(key.clone()),
+    }
+}
+
+/// A summary of a repository's configuration and contents.
+#[derive(Serialize, Debug, Clone)]
+pub struct RepoUsage {
+    pub name: String,
+    pub location: PathBuf,
+    pub hash_algorithm: HashAlgorithm,
+    pub blob_count: u64,
+    pub content_bytes: u128,
+    pub stored_bytes: u128,
+    /// When the repository's contents last changed (in seconds since the epoch)
+    pub last_modified: Option<u64>,
+    /// Contents smaller than this are appended to pack files
+    pub pack_threshold: Option<u64>,
+    /// The maximum space that the stored contents may occupy
+    pub max_size: Option<u64>,
+}
+
+pub fn get_repo_usage(repo_name: &str) -> RepoResult<RepoUsage> {
+    if !content_repo_exists(repo_name) {
+        return Err(RepoError::UnknownRepo(repo_name.to_string()));
+    }
+    let spec = read_repo_spec(repo_name)?;
+    let repo_key = ContentMgmtKey::from(&spec);
+    let content_manager = repo_key.open_content_manager(Mutability::Immutable)?;
+    let content_data = content_manager.content_data();
+    let last_modified = repo_key
+        .last_modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs());
+    Ok(RepoUsage {
+        name: repo_name.to_string(),
+        location: repo_key.base_dir_path().to_path_buf(),
+        hash_algorithm: repo_key.hash_algorithm(),
+        blob_count: content_data.num_items(),
+        content_bytes: content_data.sum_content(),
+        stored_bytes: content_data.sum_storage(),
+        last_modified,
+        pack_threshold: content_manager.pack_threshold(),
+        max_size: spec.max_size(),
+    })
+}
+
 pub fn delete_repository(repo_name: &str) -> RepoResult<()> {
     let repo_key = get_content_mgmt_key(repo_name)?;
     let content_manager = repo_key.open_content_manager(Mutability::Mutable)?;
@@ -112,36 +290,89 @@ pub fn prune_repository(repo_name: &str) -> RepoResult<UnreferencedContentData>
     Ok(content_manager.prune_contents()?)
 }
 
+/// Consolidate the repository's contents into pack files.
+pub fn repack_repository(repo_name: &str) -> RepoResult<RepackStats> {
+    let repo_key = get_content_mgmt_key(repo_name)?;
+    let content_manager = repo_key.open_content_manager(Mutability::Mutable)?;
+    content_manager.repack()
+}
+
+/// Compare the contents of two repositories (e.g. a repository and an
+/// off-site copy of it) by token and size (see `ContentManager::compare_with()`).
+pub fn compare_repositories(
+    repo_name: &str,
+    other_repo_name: &str,
+    deep: bool,
+) -> RepoResult<RepoComparison> {
+    let content_manager =
+        get_content_mgmt_key(repo_name)?.open_content_manager(Mutability::Immutable)?;
+    let other_content_manager =
+        get_content_mgmt_key(other_repo_name)?.open_content_manager(Mutability::Immutable)?;
+    content_manager.compare_with(&other_content_manager, deep)
+}
+
+/// Export the repository's contents (see `ContentManager::export_to()`).
+pub fn export_repository<P: AsRef<Path>>(repo_name: &str, path: P) -> RepoResult<ExportStats> {
+    let content_manager =
+        get_content_mgmt_key(repo_name)?.open_content_manager(Mutability::Immutable)?;
+    content_manager.export_to(path)
+}
+
+/// Import exported contents into the repository (see
+/// `ContentManager::import_from()`).
+pub fn import_repository<P: AsRef<Path>>(
+    repo_name: &str,
+    path: P,
+    rekey: bool,
+) -> RepoResult<ImportStats> {
+    let content_manager =
+        get_content_mgmt_key(repo_name)?.open_content_manager(Mutability::Mutable)?;
+    content_manager.import_from(path, rekey)
+}
+
+/// Append contents smaller than `pack_threshold` bytes to pack files as
+/// they're stored (and pack the small contents already stored loose) or,
+/// if it's `None`, store each new content in its own file.
+pub fn set_repo_pack_threshold(
+    repo_name: &str,
+    pack_threshold: Option<u64>,
+) -> RepoResult<RepackStats> {
+    let repo_key = get_content_mgmt_key(repo_name)?;
+    let content_manager = repo_key.open_content_manager(Mutability::Mutable)?;
+    content_manager.set_pack_threshold(pack_threshold);
+    match pack_threshold {
+        Some(_) => content_manager.repack(),
+        None => Ok(RepackStats::default()),
+    }
+}
+
 #[cfg(test)]
 mod content_tests {
     use super::*;
+    use crate::config::TestConfigGuard;
     use crate::Mutability;
-    use fs2::FileExt;
-    use std::env;
-    use std::fs::OpenOptions;
-    use tempdir::TempDir;
+    use std::io;
 
     #[test]
     fn repo_works() {
-        let file = OpenOptions::new()
-            .write(true)
-            .open("../test_lock_file")
-            .unwrap();
-        assert!(file.lock_exclusive().is_ok());
-
-        let temp_dir = TempDir::new("REPO_TEST").unwrap();
-
-        env::set_var("DYCHATAT_CONFIG_DIR", temp_dir.path().join("config"));
-        let data_dir = temp_dir.path().join("data");
+        let guard = TestConfigGuard::new();
+        let data_dir = guard.path().join("data");
         let data_dir_str = data_dir.to_str().unwrap();
         assert!(create_new_repo("test_repo", data_dir_str, "Sha1").is_ok());
-        assert!(temp_dir
+        for bad_name in ["", "..", "a/b", ".hidden", "-r", &"x".repeat(65)] {
+            match create_new_repo(bad_name, data_dir_str, "Sha1") {
+                Err(RepoError::InvalidRepoName(name, _)) => assert_eq!(name, bad_name),
+                result => panic!("{:?}: unexpected result {:?}", bad_name, result),
+            }
+        }
+        assert!(create_new_repo("ok-name_1.2", data_dir_str, "Sha1").is_ok());
+        assert!(guard
             .path()
             .join("config")
             .join("repos")
             .join("test_repo")
             .exists());
-        assert!(temp_dir
+        assert!(guard
             .path()
             .join("data")
             .join("dychatat")
@@ -162,15 +393,157 @@ mod content_tests {
                 let (token, _, _) = cm.store_contents(&mut file).unwrap();
                 assert_eq!(cm.ref_count_for_token(&token).unwrap(), i);
             }
+            let mut file = File::open("./src/error.rs").unwrap();
+            let (token, _, _) = cm.store_contents(&mut file).unwrap();
+            let contents = std::fs::read("./src/error.rs").unwrap();
+            let prefix = cm.read_contents_prefix(&token, 10).unwrap();
+            assert_eq!(prefix, &contents[..10]);
+            let prefix = cm
+                .read_contents_prefix(&token, contents.len() + 10)
+                .unwrap();
+            assert_eq!(prefix, contents);
+            assert!(cm.read_contents_prefix("no such token", 10).is_err());
         }
         {
             assert!(key.open_content_manager(Mutability::Mutable).is_ok())
         }
+        let usage = get_repo_usage("test_repo").unwrap();
+        assert_eq!(usage.blob_count, 2);
+        assert_eq!(usage.location, key.base_dir_path());
+        assert!(usage.stored_bytes > 0 && usage.last_modified.is_some());
+        assert_eq!(usage.pack_threshold, None);
+        let stats = set_repo_pack_threshold("test_repo", Some(1 << 16)).unwrap();
+        assert_eq!(stats.items_packed, 2);
+        let usage = get_repo_usage("test_repo").unwrap();
+        assert_eq!(usage.pack_threshold, Some(1 << 16));
+        assert_eq!(usage.blob_count, 2);
         {
             let _cm1 = key.open_content_manager(Mutability::Immutable).unwrap();
             let _cm2 = key.open_content_manager(Mutability::Immutable).unwrap();
         }
-        assert!(temp_dir.close().is_ok());
-        assert!(file.unlock().is_ok());
+    }
+
+    #[test]
+    fn repo_quotas_are_enforced() {
+        let guard = TestConfigGuard::new();
+        create_new_repo("test_repo", guard.path().join("data"), "Sha1").unwrap();
+        // pseudo random (so incompressible) contents
+        let contents = |seed: u32| -> Vec<u8> {
+            let mut state = seed;
+            (0..600)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    (state >> 16) as u8
+                })
+                .collect()
+        };
+        set_repo_max_size("test_repo", Some(1000)).unwrap();
+        assert_eq!(get_repo_usage("test_repo").unwrap().max_size, Some(1000));
+        // the specification is replaced in place
+        assert_eq!(get_repo_names(), vec!["test_repo".to_string()]);
+        assert_eq!(
+            fs::read_dir(config::get_repo_config_dir_path())
+                .unwrap()
+                .count(),
+            1
+        );
+        let key = get_content_mgmt_key("test_repo").unwrap();
+        {
+            let cm = key.open_content_manager(Mutability::Mutable).unwrap();
+            cm.store_contents(&mut io::Cursor::new(contents(1)))
+                .unwrap();
+            // already stored contents don't take any more space
+            cm.store_contents(&mut io::Cursor::new(contents(1)))
+                .unwrap();
+            match cm.store_contents(&mut io::Cursor::new(contents(2))) {
+                Err(RepoError::RepoQuotaExceeded(_, 600, 1000, used)) => assert!(used >= 600),
+                result => panic!("unexpected result: {:?}", result),
+            }
+        }
+        set_repo_max_size("test_repo", None).unwrap();
+        assert_eq!(get_repo_usage("test_repo").unwrap().max_size, None);
+        let cm = key.open_content_manager(Mutability::Mutable).unwrap();
+        cm.store_contents(&mut io::Cursor::new(contents(2)))
+            .unwrap();
+    }
+
+    #[test]
+    fn repo_comparison_finds_differences() {
+        let guard = TestConfigGuard::new();
+        let data_dir = guard.path().join("data");
+        let mut tokens = vec![];
+        for repo_name in ["original", "copy"] {
+            create_new_repo(repo_name, &data_dir, "Sha1").unwrap();
+            let cm = get_content_mgmt_key(repo_name)
+                .unwrap()
+                .open_content_manager(Mutability::Mutable)
+                .unwrap();
+            tokens.clear();
+            for file_name in ["./src/content.rs", "./src/error.rs", "./src/pack.rs"] {
+                let mut file = File::open(file_name).unwrap();
+                tokens.push(cm.store_contents(&mut file).unwrap().0);
+            }
+        }
+        assert!(compare_repositories("original", "copy", true)
+            .unwrap()
+            .is_match());
+
+        let copy_key = get_content_mgmt_key("copy").unwrap();
+        let content_path = |token: &str| {
+            copy_key
+                .base_dir_path()
+                .join(&token[0..3])
+                .join(&token[3..])
+        };
+        fs::remove_file(content_path(&tokens[0])).unwrap();
+        // same size but different contents
+        let corrupted_path = content_path(&tokens[1]);
+        let size = corrupted_path.metadata().unwrap().len() as usize;
+        fs::write(&corrupted_path, vec![0u8; size]).unwrap();
+        {
+            let cm = copy_key.open_content_manager(Mutability::Mutable).unwrap();
+            let mut file = File::open("./src/lib.rs").unwrap();
+            tokens.push(cm.store_contents(&mut file).unwrap().0);
+        }
+
+        let comparison = compare_repositories("original", "copy", false).unwrap();
+        assert_eq!(comparison.tokens_compared, 3);
+        assert_eq!(comparison.missing, vec![tokens[0].clone()]);
+        assert_eq!(comparison.extra, vec![tokens[3].clone()]);
+        assert!(comparison.size_mismatches.is_empty());
+        assert!(comparison.corrupt.is_empty());
+        let comparison = compare_repositories("original", "copy", true).unwrap();
+        assert_eq!(comparison.corrupt, vec![tokens[1].clone()]);
+
+        create_new_repo("sha256", &data_dir, "Sha256").unwrap();
+        assert!(matches!(
+            compare_repositories("original", "sha256", false),
+            Err(RepoError::HashAlgorithmMismatch(_, _))
+        ));
+    }
+
+    #[test]
+    fn relocate_repo_works() {
+        let guard = TestConfigGuard::new();
+        let data_dir = guard.path().join("data");
+        assert!(create_new_repo("test_repo", &data_dir, "Sha1").is_ok());
+        let old_key = get_content_mgmt_key("test_repo").unwrap();
+        assert_eq!(relocated_key(&old_key).unwrap(), old_key);
+
+        let moved_1 = guard.path().join("moved_1");
+        fs::rename(old_key.base_dir_path(), &moved_1).unwrap();
+        assert!(relocate_repo("test_repo", guard.path()).is_err());
+        assert!(relocate_repo("test_repo", &moved_1).is_ok());
+        let moved_1 = moved_1.canonicalize().unwrap();
+        let key = get_content_mgmt_key("test_repo").unwrap();
+        assert_eq!(key.base_dir_path(), moved_1);
+        assert_eq!(relocated_key(&old_key).unwrap(), key);
+
+        let moved_2 = guard.path().join("moved_2");
+        fs::rename(&moved_1, &moved_2).unwrap();
+        assert!(relocate_repo("test_repo", &moved_2).is_ok());
+        let key = get_content_mgmt_key("test_repo").unwrap();
+        assert_eq!(relocated_key(&old_key).unwrap(), key);
+        assert!(key.open_content_manager(Mutability::Immutable).is_ok());
     }
 }
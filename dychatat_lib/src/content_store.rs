@@ -0,0 +1,626 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au> <pwil3058@outlook.com>
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::{CacheStats, ContentManager, HashAlgorithm, RefCountData, RepoError, StoreStats};
+
+/// A reader that can be rewound (as contents may need to be read twice).
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// The operations needed to store, reference and retrieve file contents.
+/// Implemented by `ContentManager` (for on disk repositories) and by
+/// `MemoryContentStore` (for fast hermetic tests).
+pub trait ContentStore {
+    /// Store the contents (or add a reference to identical contents that
+    /// are already stored) returning their token, their stored size and how
+    /// much the store grew.
+    fn store_contents(&self, reader: &mut dyn ReadSeek) -> Result<(String, u64, u64), RepoError>;
+
+    /// Add a reference to already stored contents returning their stored size.
+    fn claim_contents(&self, content_token: &str) -> Result<u64, RepoError>;
+
+    fn release_contents(&self, content_token: &str) -> Result<RefCountData, RepoError>;
+
+    /// Release a reference for each of the tokens returning the resulting
+    /// reference count data for each of them.  Nothing is released if any
+    /// of the tokens is unknown (or appears more times than it has references).
+    fn release_contents_batch(
+        &self,
+        content_tokens: &[&str],
+    ) -> Result<Vec<RefCountData>, RepoError>;
+
+    /// Whether the reader's contents are those identified by the token.
+    fn check_content_token(&self, reader: &mut dyn Read, token: &str) -> Result<bool, RepoError>;
+
+    fn write_contents_for_token(
+        &self,
+        content_token: &str,
+        writer: &mut dyn Write,
+    ) -> Result<u64, RepoError>;
+
+    /// The first `n` (or fewer if the contents are shorter) bytes of the contents.
+    fn read_contents_prefix(&self, content_token: &str, n: usize) -> Result<Vec<u8>, RepoError>;
+
+    /// Whether the contents for `token` were added by this store (rather
+    /// than being already present).
+    fn is_new_token(&self, token: &str) -> bool;
+
+    /// The number of references to the contents for `token` (if present).
+    fn token_ref_count(&self, token: &str) -> Option<u64>;
+
+    /// The space occupied by the contents for `token` (if present).
+    fn token_stored_size(&self, token: &str) -> Option<u64>;
+
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+
+    /// Where the time went when storing contents.
+    fn store_stats(&self) -> StoreStats {
+        StoreStats::default()
+    }
+
+    /// Journal the references claimed via this store (see
+    /// `ContentManager::journal_claims()`).  Stores without a journal
+    /// ignore this.
+    fn journal_claims(&self, _id: &str) {}
+
+    /// Journal the references released via this store (see
+    /// `ContentManager::journal_releases()`).  Stores without a journal
+    /// ignore this.
+    fn journal_releases(&self, _id: &str, _unless_exists: &Path) -> Result<(), RepoError> {
+        Ok(())
+    }
+}
+
+impl ContentStore for ContentManager {
+    fn store_contents(
+        &self,
+        mut reader: &mut dyn ReadSeek,
+    ) -> Result<(String, u64, u64), RepoError> {
+        ContentManager::store_contents(self, &mut reader)
+    }
+
+    fn claim_contents(&self, content_token: &str) -> Result<u64, RepoError> {
+        ContentManager::claim_contents(self, content_token)
+    }
+
+    fn release_contents(&self, content_token: &str) -> Result<RefCountData, RepoError> {
+        ContentManager::release_contents(self, content_token)
+    }
+
+    fn release_contents_batch(
+        &self,
+        content_tokens: &[&str],
+    ) -> Result<Vec<RefCountData>, RepoError> {
+        ContentManager::release_contents_batch(self, content_tokens)
+    }
+
+    fn check_content_token(
+        &self,
+        mut reader: &mut dyn Read,
+        token: &str,
+    ) -> Result<bool, RepoError> {
+        ContentManager::check_content_token(self, &mut reader, token)
+    }
+
+    fn write_contents_for_token(
+        &self,
+        content_token: &str,
+        mut writer: &mut dyn Write,
+    ) -> Result<u64, RepoError> {
+        ContentManager::write_contents_for_token(self, content_token, &mut writer)
+    }
+
+    fn read_contents_prefix(&self, content_token: &str, n: usize) -> Result<Vec<u8>, RepoError> {
+        ContentManager::read_contents_prefix(self, content_token, n)
+    }
+
+    fn is_new_token(&self, token: &str) -> bool {
+        ContentManager::is_new_token(self, token)
+    }
+
+    fn token_ref_count(&self, token: &str) -> Option<u64> {
+        ContentManager::ref_count_for_token(self, token).ok()
+    }
+
+    fn token_stored_size(&self, token: &str) -> Option<u64> {
+        ContentManager::stored_size_for_token(self, token).ok()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        ContentManager::cache_stats(self)
+    }
+
+    fn store_stats(&self) -> StoreStats {
+        ContentManager::store_stats(self)
+    }
+
+    fn journal_claims(&self, id: &str) {
+        ContentManager::journal_claims(self, id)
+    }
+
+    fn journal_releases(&self, id: &str, unless_exists: &Path) -> Result<(), RepoError> {
+        ContentManager::journal_releases(self, id, unless_exists)
+    }
+}
+
+/// A content store that keeps everything in memory.  Contents are stored
+/// uncompressed so their stored size is their content size.
+///
+/// ```
+/// use dychatat_lib::content::{ContentStore, HashAlgorithm, MemoryContentStore};
+/// use std::io::Cursor;
+///
+/// let store = MemoryContentStore::new(HashAlgorithm::Sha256);
+/// let (token, _, growth) = store.store_contents(&mut Cursor::new(b"data")).unwrap();
+/// assert_eq!(growth, 4);
+/// let (_, _, growth) = store.store_contents(&mut Cursor::new(b"data")).unwrap();
+/// assert_eq!(growth, 0);
+/// assert_eq!(store.ref_count_for_token(&token), Some(2));
+/// let mut copy = vec![];
+/// store.write_contents_for_token(&token, &mut copy).unwrap();
+/// assert_eq!(copy, b"data");
+/// ```
+#[derive(Debug)]
+pub struct MemoryContentStore {
+    hash_algorithm: HashAlgorithm,
+    contents: RefCell<HashMap<String, (Vec<u8>, RefCountData)>>,
+    new_tokens: RefCell<HashSet<String>>,
+}
+
+impl MemoryContentStore {
+    pub fn new(hash_algorithm: HashAlgorithm) -> Self {
+        Self {
+            hash_algorithm,
+            contents: RefCell::new(HashMap::new()),
+            new_tokens: RefCell::new(HashSet::new()),
+        }
+    }
+
+    pub fn ref_count_for_token(&self, token: &str) -> Option<u64> {
+        self.contents
+            .borrow()
+            .get(token)
+            .map(|(_, rcd)| rcd.ref_count)
+    }
+
+    /// The number of distinct contents stored.
+    pub fn len(&self) -> usize {
+        self.contents.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contents.borrow().is_empty()
+    }
+}
+
+impl ContentStore for MemoryContentStore {
+    fn store_contents(&self, reader: &mut dyn ReadSeek) -> Result<(String, u64, u64), RepoError> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        let digest = self.hash_algorithm.data_digest(&data)?;
+        let mut contents = self.contents.borrow_mut();
+        if let Some((_, rcd)) = contents.get_mut(&digest) {
+            rcd.incr_ref_count();
+            return Ok((digest, rcd.stored_size, 0));
+        }
+        let size = data.len() as u64;
+        let rcd = RefCountData {
+            ref_count: 1,
+            content_size: size,
+            stored_size: size,
+        };
+        contents.insert(digest.clone(), (data, rcd));
+        self.new_tokens.borrow_mut().insert(digest.clone());
+        Ok((digest, size, size))
+    }
+
+    fn claim_contents(&self, content_token: &str) -> Result<u64, RepoError> {
+        match self.contents.borrow_mut().get_mut(content_token) {
+            Some((_, rcd)) => {
+                rcd.incr_ref_count();
+                Ok(rcd.stored_size)
+            }
+            None => Err(RepoError::UnknownToken(content_token.to_string())),
+        }
+    }
+
+    fn release_contents(&self, content_token: &str) -> Result<RefCountData, RepoError> {
+        match self.contents.borrow_mut().get_mut(content_token) {
+            Some((_, rcd)) => {
+                rcd.decr_ref_count();
+                Ok(*rcd)
+            }
+            None => Err(RepoError::UnknownToken(content_token.to_string())),
+        }
+    }
+
+    fn release_contents_batch(
+        &self,
+        content_tokens: &[&str],
+    ) -> Result<Vec<RefCountData>, RepoError> {
+        let mut contents = self.contents.borrow_mut();
+        if let Some(token) = content_tokens
+            .iter()
+            .find(|token| !contents.contains_key(**token))
+        {
+            return Err(RepoError::UnknownToken(token.to_string()));
+        }
+        Ok(content_tokens
+            .iter()
+            .map(|token| {
+                let (_, rcd) = contents.get_mut(*token).expect("checked above");
+                rcd.decr_ref_count();
+                *rcd
+            })
+            .collect())
+    }
+
+    fn check_content_token(
+        &self,
+        mut reader: &mut dyn Read,
+        token: &str,
+    ) -> Result<bool, RepoError> {
+        Ok(self.hash_algorithm.reader_digest(&mut reader)? == token)
+    }
+
+    fn write_contents_for_token(
+        &self,
+        content_token: &str,
+        writer: &mut dyn Write,
+    ) -> Result<u64, RepoError> {
+        match self.contents.borrow().get(content_token) {
+            Some((data, _)) => {
+                writer.write_all(data)?;
+                Ok(data.len() as u64)
+            }
+            None => Err(RepoError::UnknownToken(content_token.to_string())),
+        }
+    }
+
+    fn read_contents_prefix(&self, content_token: &str, n: usize) -> Result<Vec<u8>, RepoError> {
+        match self.contents.borrow().get(content_token) {
+            Some((data, _)) => Ok(data[..n.min(data.len())].to_vec()),
+            None => Err(RepoError::UnknownToken(content_token.to_string())),
+        }
+    }
+
+    fn is_new_token(&self, token: &str) -> bool {
+        self.new_tokens.borrow().contains(token)
+    }
+
+    fn token_ref_count(&self, token: &str) -> Option<u64> {
+        self.ref_count_for_token(token)
+    }
+
+    fn token_stored_size(&self, token: &str) -> Option<u64> {
+        self.contents
+            .borrow()
+            .get(token)
+            .map(|(_, rcd)| rcd.stored_size)
+    }
+}
+
+/// A pair of content stores where contents of at least `min_file_size`
+/// bytes go to the overflow store (e.g. a large NAS) and the rest go to
+/// the primary store (e.g. a fast local SSD).  Operations on existing
+/// contents use whichever store holds them (preferring the primary) so
+/// changing the threshold only affects where new contents go.
+///
+/// ```
+/// use dychatat_lib::content::{ContentStore, HashAlgorithm, MemoryContentStore, TieredContentStore};
+/// use std::io::Cursor;
+///
+/// let store = TieredContentStore::new(
+///     MemoryContentStore::new(HashAlgorithm::Sha256),
+///     Some((MemoryContentStore::new(HashAlgorithm::Sha256), 8)),
+/// );
+/// let (small, _, _) = store.store_contents(&mut Cursor::new(b"small")).unwrap();
+/// let (large, _, _) = store.store_contents(&mut Cursor::new(b"rather large")).unwrap();
+/// assert_eq!(store.primary().ref_count_for_token(&small), Some(1));
+/// assert_eq!(store.overflow().unwrap().ref_count_for_token(&large), Some(1));
+/// ```
+#[derive(Debug)]
+pub struct TieredContentStore<S: ContentStore> {
+    primary: S,
+    overflow: Option<(S, u64)>,
+}
+
+impl<S: ContentStore> TieredContentStore<S> {
+    pub fn new(primary: S, overflow: Option<(S, u64)>) -> Self {
+        Self { primary, overflow }
+    }
+
+    pub fn primary(&self) -> &S {
+        &self.primary
+    }
+
+    pub fn overflow(&self) -> Option<&S> {
+        self.overflow.as_ref().map(|(store, _)| store)
+    }
+
+    fn tiers(&self) -> impl Iterator<Item = &S> {
+        std::iter::once(&self.primary).chain(self.overflow())
+    }
+
+    fn tier_holding(&self, token: &str) -> Result<&S, RepoError> {
+        self.tiers()
+            .find(|tier| tier.token_ref_count(token).is_some())
+            .ok_or_else(|| RepoError::UnknownToken(token.to_string()))
+    }
+
+    // The tier to release a reference from given how many releases have
+    // already been allocated to each tier.
+    fn tier_index_for_release(
+        &self,
+        token: &str,
+        allocated: &HashMap<(usize, &str), u64>,
+    ) -> Result<usize, RepoError> {
+        let mut held = None;
+        for (index, tier) in self.tiers().enumerate() {
+            if let Some(ref_count) = tier.token_ref_count(token) {
+                let already = allocated.get(&(index, token)).copied().unwrap_or(0);
+                if ref_count > already {
+                    return Ok(index);
+                }
+                let (ref_counts, releases) = held.get_or_insert((0, 0));
+                *ref_counts += ref_count;
+                *releases += already;
+            }
+        }
+        match held {
+            Some((ref_counts, releases)) => Err(RepoError::TooFewReferences(
+                token.to_string(),
+                ref_counts,
+                releases + 1,
+            )),
+            None => Err(RepoError::UnknownToken(token.to_string())),
+        }
+    }
+
+    fn tier(&self, index: usize) -> &S {
+        self.tiers().nth(index).expect("valid tier index")
+    }
+}
+
+impl<S: ContentStore> ContentStore for TieredContentStore<S> {
+    fn store_contents(&self, reader: &mut dyn ReadSeek) -> Result<(String, u64, u64), RepoError> {
+        if let Some((overflow, min_file_size)) = self.overflow.as_ref() {
+            let start = reader.stream_position()?;
+            let end = reader.seek(SeekFrom::End(0))?;
+            reader.seek(SeekFrom::Start(start))?;
+            if end - start >= *min_file_size {
+                return overflow.store_contents(reader);
+            }
+        }
+        self.primary.store_contents(reader)
+    }
+
+    fn claim_contents(&self, content_token: &str) -> Result<u64, RepoError> {
+        self.tier_holding(content_token)?
+            .claim_contents(content_token)
+    }
+
+    fn release_contents(&self, content_token: &str) -> Result<RefCountData, RepoError> {
+        let index = self.tier_index_for_release(content_token, &HashMap::new())?;
+        self.tier(index).release_contents(content_token)
+    }
+
+    fn release_contents_batch(
+        &self,
+        content_tokens: &[&str],
+    ) -> Result<Vec<RefCountData>, RepoError> {
+        // allocating every release to a tier with references to spare
+        // checks the whole batch before any tier is touched
+        let mut allocated: HashMap<(usize, &str), u64> = HashMap::new();
+        let mut indices = Vec::with_capacity(content_tokens.len());
+        for token in content_tokens.iter() {
+            let index = self.tier_index_for_release(token, &allocated)?;
+            *allocated.entry((index, token)).or_insert(0) += 1;
+            indices.push(index);
+        }
+        let mut results = vec![None; content_tokens.len()];
+        let mut released: Vec<(&S, Vec<&str>)> = vec![];
+        for (tier_index, tier) in self.tiers().enumerate() {
+            let positions: Vec<usize> = (0..content_tokens.len())
+                .filter(|position| indices[*position] == tier_index)
+                .collect();
+            if positions.is_empty() {
+                continue;
+            }
+            let tokens: Vec<&str> = positions
+                .iter()
+                .map(|position| content_tokens[*position])
+                .collect();
+            match tier.release_contents_batch(&tokens) {
+                Ok(rcds) => {
+                    for (position, rcd) in positions.iter().zip(rcds) {
+                        results[*position] = Some(rcd);
+                    }
+                    released.push((tier, tokens));
+                }
+                Err(err) => {
+                    // put back what the earlier tiers released
+                    for (tier, tokens) in released {
+                        for token in tokens {
+                            tier.claim_contents(token)?;
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(results
+            .into_iter()
+            .map(|rcd| rcd.expect("every token allocated to a tier"))
+            .collect())
+    }
+
+    fn check_content_token(&self, reader: &mut dyn Read, token: &str) -> Result<bool, RepoError> {
+        self.tier_holding(token)
+            .unwrap_or(&self.primary)
+            .check_content_token(reader, token)
+    }
+
+    fn write_contents_for_token(
+        &self,
+        content_token: &str,
+        writer: &mut dyn Write,
+    ) -> Result<u64, RepoError> {
+        self.tier_holding(content_token)?
+            .write_contents_for_token(content_token, writer)
+    }
+
+    fn read_contents_prefix(&self, content_token: &str, n: usize) -> Result<Vec<u8>, RepoError> {
+        self.tier_holding(content_token)?
+            .read_contents_prefix(content_token, n)
+    }
+
+    fn is_new_token(&self, token: &str) -> bool {
+        self.tiers().any(|tier| tier.is_new_token(token))
+    }
+
+    fn token_ref_count(&self, token: &str) -> Option<u64> {
+        self.tiers()
+            .filter_map(|tier| tier.token_ref_count(token))
+            .reduce(|a, b| a + b)
+    }
+
+    fn token_stored_size(&self, token: &str) -> Option<u64> {
+        self.tiers()
+            .filter_map(|tier| tier.token_stored_size(token))
+            .reduce(|a, b| a + b)
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        let mut cache_stats = CacheStats::default();
+        for tier in self.tiers() {
+            cache_stats += tier.cache_stats();
+        }
+        cache_stats
+    }
+
+    fn store_stats(&self) -> StoreStats {
+        let mut store_stats = StoreStats::default();
+        for tier in self.tiers() {
+            store_stats += tier.store_stats();
+        }
+        store_stats
+    }
+
+    fn journal_claims(&self, id: &str) {
+        for tier in self.tiers() {
+            tier.journal_claims(id);
+        }
+    }
+
+    fn journal_releases(&self, id: &str, unless_exists: &Path) -> Result<(), RepoError> {
+        for tier in self.tiers() {
+            tier.journal_releases(id, unless_exists)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod content_store_tests {
+    use super::*;
+    use crate::HashAlgorithm;
+    use std::io::Cursor;
+
+    fn tiered_store() -> TieredContentStore<MemoryContentStore> {
+        TieredContentStore::new(
+            MemoryContentStore::new(HashAlgorithm::Sha256),
+            Some((MemoryContentStore::new(HashAlgorithm::Sha256), 10)),
+        )
+    }
+
+    #[test]
+    fn tiers_are_chosen_by_size_and_token() {
+        let store = tiered_store();
+        let (small, _, growth) = store.store_contents(&mut Cursor::new(b"tiny")).unwrap();
+        assert_eq!(growth, 4);
+        let mut cursor = Cursor::new(b"skip:large enough".to_vec());
+        cursor.set_position(5);
+        let (large, _, _) = store.store_contents(&mut cursor).unwrap();
+        assert_eq!(store.primary().ref_count_for_token(&small), Some(1));
+        assert_eq!(store.overflow().unwrap().ref_count_for_token(&small), None);
+        assert_eq!(
+            store.overflow().unwrap().ref_count_for_token(&large),
+            Some(1)
+        );
+        assert!(store.is_new_token(&small) && store.is_new_token(&large));
+
+        assert_eq!(store.claim_contents(&large).unwrap(), 12);
+        assert_eq!(store.token_ref_count(&large), Some(2));
+        let mut copy = vec![];
+        store.write_contents_for_token(&large, &mut copy).unwrap();
+        assert_eq!(copy, b"large enough");
+        assert_eq!(store.read_contents_prefix(&small, 2).unwrap(), b"ti");
+        assert!(store.claim_contents("unknown").is_err());
+
+        assert!(store
+            .release_contents_batch(&[&small, "unknown", &large])
+            .is_err());
+        assert_eq!(store.token_ref_count(&large), Some(2));
+        let rcds = store
+            .release_contents_batch(&[&large, &small, &large])
+            .unwrap();
+        let ref_counts: Vec<u64> = rcds.iter().map(|rcd| rcd.ref_count).collect();
+        assert_eq!(ref_counts, vec![1, 0, 0]);
+    }
+
+    #[test]
+    fn contents_in_both_tiers_are_released_where_referenced() {
+        let store = tiered_store();
+        // as if the threshold had been raised after "0123456789" was stored
+        let (token, _, _) = store
+            .store_contents(&mut Cursor::new(b"0123456789"))
+            .unwrap();
+        store
+            .primary()
+            .store_contents(&mut Cursor::new(b"0123456789"))
+            .unwrap();
+        store.release_contents(&token).unwrap();
+        assert_eq!(store.primary().ref_count_for_token(&token), Some(0));
+        assert_eq!(
+            store.overflow().unwrap().ref_count_for_token(&token),
+            Some(1)
+        );
+        store.claim_contents(&token).unwrap();
+        let rcds = store.release_contents_batch(&[&token, &token]).unwrap();
+        assert_eq!(rcds.len(), 2);
+        assert_eq!(store.token_ref_count(&token), Some(0));
+    }
+
+    #[test]
+    fn tiers_cant_release_more_references_than_they_have() {
+        let store = tiered_store();
+        let (token, _, _) = store
+            .store_contents(&mut Cursor::new(b"0123456789"))
+            .unwrap();
+        store
+            .primary()
+            .store_contents(&mut Cursor::new(b"0123456789"))
+            .unwrap();
+        assert!(matches!(
+            store.release_contents_batch(&[&token, &token, &token]),
+            Err(RepoError::TooFewReferences(_, 2, 3))
+        ));
+        assert_eq!(store.primary().ref_count_for_token(&token), Some(1));
+        assert_eq!(
+            store.overflow().unwrap().ref_count_for_token(&token),
+            Some(1)
+        );
+        store.release_contents_batch(&[&token, &token]).unwrap();
+        assert!(matches!(
+            store.release_contents(&token),
+            Err(RepoError::TooFewReferences(_, 0, 1))
+        ));
+    }
+}
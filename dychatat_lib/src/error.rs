@@ -17,20 +17,46 @@ pub enum RepoError {
     NotImplemented,
     #[error("{0:?}: a repository with that name already exists")]
     RepoExists(String),
+    #[error("{0:?}: invalid repository name: {1}")]
+    InvalidRepoName(String, &'static str),
     #[error("{0:?}: repository path already exists")]
     RepoDirExists(PathBuf),
     #[error("{0:?}: no repository with that name exists")]
     UnknownRepo(String),
+    #[error("{0:?}: is not a repository directory")]
+    NotARepoDir(PathBuf),
     #[error("{0}: unknown hash algorithm")]
     UnknownHashAlgorithm(String),
     #[error("{0}: unknown content token")]
     UnknownToken(String),
+    #[error("{0}: only {1} references so {2} can't be released")]
+    TooFewReferences(String, u64, u64),
     #[error("Serde Yaml Error")]
     YamlError(#[from] serde_yaml::Error),
     #[error("{0:?}: malformed string")]
     BadOsString(OsString),
+    #[error(
+        "{0:?}: no room for {1} bytes without using the reserved free space ({2} bytes available)"
+    )]
+    InsufficientSpace(PathBuf, u64, u64),
+    #[error(
+        "{0:?}: storing {1} more bytes would exceed the repository's maximum size of {2} bytes ({3} bytes used)"
+    )]
+    RepoQuotaExceeded(PathBuf, u64, u64, u128),
     #[error("Still has {0} references to {1} items")]
     StillBeingReferenced(u128, u64),
+    #[error("repositories using different hash algorithms ({0} and {1}) can't be compared")]
+    HashAlgorithmMismatch(String, String),
+    #[error("{0:?} and {1:?}: contents differ")]
+    ContentsDiffer(String, String),
+    #[error("{0:?}: export destination already exists")]
+    ExportExists(PathBuf),
+    #[error("{0:?}: not a repository export: {1}")]
+    BadExport(PathBuf, String),
+    #[error("{0}: exported contents don't match their token")]
+    CorruptExport(String),
+    #[error("contents hashed with {0} can't be imported into a {1} repository without re-keying")]
+    RekeyRequired(String, String),
 }
 
 impl From<OsString> for RepoError {
@@ -0,0 +1,89 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au> <pwil3058@outlook.com>
+
+//! The exit statuses used by the command line interfaces (so that scripts
+//! can tell what kind of failure occurred) and their mapping from errors.
+
+use crate::RepoError;
+
+/// The exit status of a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// Everything succeeded
+    Success = 0,
+    /// Some (or all) of the operations failed (e.g. one of several back ups)
+    Failure = 1,
+    /// The command line was invalid
+    Usage = 2,
+    /// The configuration is wrong or incomplete (e.g. an unknown archive)
+    Config = 3,
+    /// A content repository couldn't be used
+    Repo = 4,
+    /// Snapshots or their contents are damaged, altered or untrusted
+    Integrity = 5,
+    /// Files couldn't be read or written (including lack of space)
+    FileSystem = 6,
+    /// The operation was cancelled
+    Cancelled = 7,
+}
+
+/// A description of the exit statuses used by "dychatat" (e.g. for help messages).
+pub const EXIT_STATUS_HELP: &str = "EXIT STATUS:
+    0  success
+    2  usage error
+    3  configuration error (e.g. unknown repository)
+    4  content repository error
+    5  integrity error (e.g. unknown or corrupt contents)
+    6  file system error (including lack of space)";
+
+impl ExitStatus {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+impl From<&RepoError> for ExitStatus {
+    fn from(error: &RepoError) -> Self {
+        use RepoError::*;
+        match error {
+            IOError(_) | InsufficientSpace(..) => ExitStatus::FileSystem,
+            RepoExists(_) | InvalidRepoName(..) | RepoDirExists(_) | UnknownRepo(_) => {
+                ExitStatus::Config
+            }
+            UnknownToken(_) | TooFewReferences(..) | ContentsDiffer(..) | CorruptExport(_) => {
+                ExitStatus::Integrity
+            }
+            JsonError(_)
+            | NotImplemented
+            | NotARepoDir(_)
+            | UnknownHashAlgorithm(_)
+            | YamlError(_)
+            | BadOsString(_)
+            | RepoQuotaExceeded(..)
+            | StillBeingReferenced(..)
+            | HashAlgorithmMismatch(..)
+            | ExportExists(_)
+            | BadExport(..)
+            | RekeyRequired(..) => ExitStatus::Repo,
+        }
+    }
+}
+
+#[cfg(test)]
+mod exit_status_tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn repo_errors_map_to_exit_statuses() {
+        let status = |error: RepoError| ExitStatus::from(&error).code();
+        assert_eq!(status(RepoError::UnknownRepo("r".to_string())), 3);
+        assert_eq!(status(RepoError::NotImplemented), 4);
+        assert_eq!(status(RepoError::UnknownToken("t".to_string())), 5);
+        assert_eq!(status(RepoError::CorruptExport("t".to_string())), 5);
+        assert_eq!(status(std::io::Error::other("oops").into()), 6);
+        assert_eq!(
+            status(RepoError::InsufficientSpace(PathBuf::new(), 2, 1)),
+            6
+        );
+    }
+}
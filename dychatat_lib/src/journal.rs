@@ -0,0 +1,253 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au> <pwil3058@outlook.com>
+
+//! A write-ahead journal of reference releases that are pending on the
+//! fate of a file (e.g. a snapshot file) so that reference counts stay
+//! consistent with those files if a process dies part way through making
+//! or deleting them.  Each entry lists tokens whose references are to be
+//! released unless its guard file exists.  Entries left behind by
+//! processes that are no longer running are replayed when the repository
+//! is next opened for writing.
+//!
+//! The journal is only read or written while the repository's reference
+//! count file is exclusively locked.  Writes are ordered so that a crash
+//! can, at worst, leave references that are never released (which wastes
+//! space but loses nothing) and never releases a reference twice.
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::RepoError;
+
+const JOURNAL_FILE_NAME: &str = "ref_journal";
+
+static ENTRY_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// A new (unique) id for a journal entry.
+pub fn new_journal_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+    format!(
+        "{}-{}-{}",
+        std::process::id(),
+        nanos,
+        ENTRY_COUNT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn host_name() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct PendingRelease {
+    pub id: String,
+    /// A token appears once for each reference to be released
+    pub tokens: Vec<String>,
+    /// The references are still needed if this file exists
+    pub unless_exists: Option<PathBuf>,
+    pub host: String,
+    pub pid: u32,
+}
+
+impl PendingRelease {
+    pub fn new(id: &str, tokens: Vec<String>, unless_exists: Option<&Path>) -> Self {
+        Self {
+            id: id.to_string(),
+            tokens,
+            unless_exists: unless_exists.map(|path| path.to_path_buf()),
+            host: host_name(),
+            pid: std::process::id(),
+        }
+    }
+
+    // Entries made on other hosts (or where it can't be determined) are
+    // assumed to belong to running processes.
+    pub fn is_abandoned(&self) -> bool {
+        !self.host.is_empty()
+            && self.host == host_name()
+            && Path::new("/proc/self").exists()
+            && !Path::new("/proc").join(self.pid.to_string()).exists()
+    }
+
+    pub fn is_needed(&self) -> bool {
+        match self.unless_exists {
+            Some(ref path) => !path.exists(),
+            None => true,
+        }
+    }
+}
+
+fn journal_path(base_dir_path: &Path) -> PathBuf {
+    base_dir_path.join(JOURNAL_FILE_NAME)
+}
+
+/// The journal's entries.  A journal that can't be read is moved aside
+/// (as its releases are safe to forget).
+pub(crate) fn read(base_dir_path: &Path) -> Result<Vec<PendingRelease>, RepoError> {
+    let path = journal_path(base_dir_path);
+    match fs::read(&path) {
+        Ok(bytes) => match serde_json::from_slice(&bytes) {
+            Ok(entries) => Ok(entries),
+            Err(_) => {
+                fs::rename(&path, path.with_extension("corrupt"))?;
+                Ok(vec![])
+            }
+        },
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replace the journal's entries (atomically).
+pub(crate) fn write(base_dir_path: &Path, entries: &[PendingRelease]) -> Result<(), RepoError> {
+    let path = journal_path(base_dir_path);
+    if entries.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        };
+    }
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&serde_json::to_vec(entries)?)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, &path)?;
+    Ok(())
+}
+
+/// Add the tokens to the entry with the entry's id (creating it if needed).
+pub(crate) fn add(base_dir_path: &Path, entry: PendingRelease) -> Result<(), RepoError> {
+    let mut entries = read(base_dir_path)?;
+    match entries.iter_mut().find(|e| e.id == entry.id) {
+        Some(existing) => existing.tokens.extend(entry.tokens),
+        None => entries.push(entry),
+    }
+    write(base_dir_path, &entries)
+}
+
+/// Make the entry's releases conditional on `unless_exists` not existing.
+pub(crate) fn set_guard(
+    base_dir_path: &Path,
+    id: &str,
+    unless_exists: &Path,
+) -> Result<(), RepoError> {
+    let mut entries = read(base_dir_path)?;
+    if let Some(entry) = entries.iter_mut().find(|e| e.id == id) {
+        entry.unless_exists = Some(unless_exists.to_path_buf());
+        write(base_dir_path, &entries)?;
+    }
+    Ok(())
+}
+
+/// Remove the entry (without releasing anything).
+pub(crate) fn discard(base_dir_path: &Path, id: &str) -> Result<(), RepoError> {
+    let mut entries = read(base_dir_path)?;
+    let len = entries.len();
+    entries.retain(|e| e.id != id);
+    if entries.len() != len {
+        write(base_dir_path, &entries)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use super::*;
+    use crate::config::TestConfigGuard;
+    use crate::content::{create_new_repo, get_content_mgmt_key};
+    use crate::Mutability;
+
+    fn dead_process_entry(
+        id: &str,
+        tokens: &[&str],
+        unless_exists: Option<&Path>,
+    ) -> PendingRelease {
+        let mut entry = PendingRelease::new(
+            id,
+            tokens.iter().map(|token| token.to_string()).collect(),
+            unless_exists,
+        );
+        // no such process can exist
+        entry.pid = u32::MAX;
+        entry
+    }
+
+    #[test]
+    fn abandoned_releases_are_replayed() {
+        let guard = TestConfigGuard::new();
+        create_new_repo("test_repo", guard.path().join("data"), "Sha1").unwrap();
+        let key = get_content_mgmt_key("test_repo").unwrap();
+        let (token_a, token_b) = {
+            let cm = key.open_content_manager(Mutability::Mutable).unwrap();
+            let token_a = cm.store_contents(&mut File::open("./src/journal.rs").unwrap());
+            let token_b = cm.store_contents(&mut File::open("./src/error.rs").unwrap());
+            (token_a.unwrap().0, token_b.unwrap().0)
+        };
+        let snapshot_path = guard.path().join("snapshot");
+        fs::write(&snapshot_path, "references").unwrap();
+        let entries = vec![
+            dead_process_entry("dead", &[&token_a, "unknown"], None),
+            dead_process_entry("committed", &[&token_b], Some(&snapshot_path)),
+            PendingRelease::new("alive", vec![token_b.clone()], None),
+        ];
+        write(key.base_dir_path(), &entries).unwrap();
+
+        // only writers replay the journal
+        let cm = key.open_content_manager(Mutability::Immutable).unwrap();
+        assert_eq!(cm.ref_count_for_token(&token_a).unwrap(), 1);
+        drop(cm);
+        let cm = key.open_content_manager(Mutability::Mutable).unwrap();
+        assert_eq!(cm.ref_count_for_token(&token_a).unwrap(), 0);
+        assert_eq!(cm.ref_count_for_token(&token_b).unwrap(), 1);
+        assert_eq!(read(key.base_dir_path()).unwrap(), entries[2..].to_vec());
+    }
+
+    #[test]
+    fn claims_and_releases_are_journalled() {
+        let guard = TestConfigGuard::new();
+        create_new_repo("test_repo", guard.path().join("data"), "Sha1").unwrap();
+        let key = get_content_mgmt_key("test_repo").unwrap();
+        let token = {
+            let cm = key.open_content_manager(Mutability::Mutable).unwrap();
+            cm.journal_claims("claims");
+            let mut file = File::open("./src/journal.rs").unwrap();
+            cm.store_contents(&mut file).unwrap().0
+        };
+        let entries = read(key.base_dir_path()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            (entries[0].id.as_str(), &entries[0].tokens),
+            ("claims", &vec![token.clone()])
+        );
+        let snapshot_path = guard.path().join("snapshot");
+        key.guard_journal_entry("claims", &snapshot_path).unwrap();
+        assert_eq!(
+            read(key.base_dir_path()).unwrap()[0].unless_exists,
+            Some(snapshot_path.clone())
+        );
+        key.discard_journal_entry("claims").unwrap();
+        assert!(read(key.base_dir_path()).unwrap().is_empty());
+
+        {
+            let cm = key.open_content_manager(Mutability::Mutable).unwrap();
+            cm.release_contents(&token).unwrap();
+            cm.journal_releases("releases", &snapshot_path).unwrap();
+            assert_eq!(
+                read(key.base_dir_path()).unwrap()[0].tokens,
+                vec![token.clone()]
+            );
+        }
+        assert!(read(key.base_dir_path()).unwrap().is_empty());
+        let cm = key.open_content_manager(Mutability::Immutable).unwrap();
+        assert_eq!(cm.ref_count_for_token(&token).unwrap(), 0);
+    }
+}
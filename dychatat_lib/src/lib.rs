@@ -2,19 +2,21 @@
 // extern crate serde_derive;
 
 use std::{
-    cell::RefCell,
-    collections::HashMap,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
     fmt,
     fs::{create_dir_all, remove_dir_all, remove_file, File, OpenOptions},
     io::{self, Read, Seek, SeekFrom, Write},
     ops::AddAssign,
     path::{Path, PathBuf},
     str::FromStr,
+    time::{Duration, Instant, SystemTime},
 };
 
 use crypto_hash;
 use fs2::FileExt;
 use hex::ToHex;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use serde_yaml;
@@ -22,12 +24,71 @@ use snap;
 
 mod config;
 pub mod content;
+mod content_store;
 mod error;
+pub mod exit_status;
+mod journal;
+mod pack;
+mod read_cache;
+mod transfer;
 
 pub use crate::error::*;
+pub use crate::pack::RepackStats;
+pub use crate::read_cache::CacheStats;
+use crate::read_cache::ReadCache;
+pub use crate::transfer::{ExportStats, ImportStats};
+
+/// How a content manager is to behave (see
+/// `ContentMgmtKey::open_content_manager_with_options()`).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ContentManagerOptions {
+    /// Refuse to store new contents that would leave fewer than this many
+    /// bytes free on the file system holding the repository.  `None` (the
+    /// default) means don't check.
+    pub free_space_reserve: Option<u64>,
+    /// The maximum number of bytes of decompressed content to keep in
+    /// memory for reuse when the same contents are written out more than
+    /// once (e.g. when extracting).  Zero (the default) disables caching.
+    pub read_cache_capacity: usize,
+}
+
+// Whether there's room in `dir_path`'s file system for contents of `size`
+// bytes (ignoring compression) without eating into the reserved free space.
+fn check_free_space(dir_path: &Path, size: u64, reserve: Option<u64>) -> Result<(), RepoError> {
+    if let Some(reserve) = reserve {
+        let available = fs2::available_space(dir_path)?;
+        if available < size.saturating_add(reserve) {
+            return Err(RepoError::InsufficientSpace(
+                dir_path.to_path_buf(),
+                size,
+                available,
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Where a content manager's time went when storing contents.
+#[derive(PartialEq, Clone, Copy, Default, Debug)]
+pub struct StoreStats {
+    /// Time spent calculating content digests
+    pub hash_time: Duration,
+    /// Time spent compressing and writing new contents
+    pub store_time: Duration,
+    /// Bytes written to storage for new contents
+    pub bytes_written: u64,
+}
+
+impl AddAssign for StoreStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.hash_time += rhs.hash_time;
+        self.store_time += rhs.store_time;
+        self.bytes_written += rhs.bytes_written;
+    }
+}
 
 /// A type to provide hash digest calculation methods.
-#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Copy, Debug)]
 pub enum HashAlgorithm {
     Sha1,
     Sha256,
@@ -57,6 +118,20 @@ impl fmt::Display for HashAlgorithm {
 }
 
 impl HashAlgorithm {
+    /// Whether `token` has the form of the digests that the algorithm produces
+    /// (i.e. upper case hexadecimal of the algorithm's digest length).
+    pub fn is_valid_token(&self, token: &str) -> bool {
+        let digest_len = match self {
+            HashAlgorithm::Sha1 => 40,
+            HashAlgorithm::Sha256 => 64,
+            HashAlgorithm::Sha512 => 128,
+        };
+        token.len() == digest_len
+            && token
+                .bytes()
+                .all(|byte| matches!(byte, b'0'..=b'9' | b'A'..=b'F'))
+    }
+
     /// Returns the hash digest for `data` as a hexadecimal string.
     pub fn data_digest(&self, data: &[u8]) -> Result<String, io::Error> {
         let mut hasher = match self {
@@ -75,7 +150,13 @@ impl HashAlgorithm {
 
     /// Returns the hash digest for `reader`'s as a hexadecimal string.crypto_hash.
     pub fn reader_digest<R: Read>(&self, reader: &mut R) -> Result<String, io::Error> {
+        Ok(self.reader_digest_and_size(reader)?.0)
+    }
+
+    // The digest and the number of bytes read.
+    fn reader_digest_and_size<R: Read>(&self, reader: &mut R) -> Result<(String, u64), io::Error> {
         let mut buffer = [0; 512000];
+        let mut size = 0;
         let mut hasher = match self {
             HashAlgorithm::Sha1 => crypto_hash::Hasher::new(crypto_hash::Algorithm::SHA1),
             HashAlgorithm::Sha256 => crypto_hash::Hasher::new(crypto_hash::Algorithm::SHA256),
@@ -86,6 +167,7 @@ impl HashAlgorithm {
             if n_bytes == 0 {
                 break;
             };
+            size += n_bytes as u64;
             hasher.write_all(&buffer[..n_bytes])?;
         }
         let mut s = String::new();
@@ -93,7 +175,7 @@ impl HashAlgorithm {
             .finish()
             .write_hex_upper(&mut s)
             .expect("HEX format failed");
-        Ok(s)
+        Ok((s, size))
     }
 }
 
@@ -104,6 +186,9 @@ pub struct RepoSpec {
     base_dir_path: PathBuf,
     /// The hash algorithm to be used when calculating content digests.
     hash_algorithm: HashAlgorithm,
+    /// The maximum space (in bytes) that the stored contents may occupy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_size: Option<u64>,
 }
 
 impl fmt::Display for RepoSpec {
@@ -123,9 +208,18 @@ impl RepoSpec {
         Self {
             base_dir_path,
             hash_algorithm,
+            max_size: None,
         }
     }
 
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    pub fn set_max_size(&mut self, max_size: Option<u64>) {
+        self.max_size = max_size;
+    }
+
     pub fn from_reader(reader: impl Read) -> Result<Self, RepoError> {
         let spec: Self = serde_yaml::from_reader(reader)?;
         Ok(spec)
@@ -143,7 +237,7 @@ pub enum Mutability {
     Mutable,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Debug)]
 pub struct ContentMgmtKey {
     base_dir_path: PathBuf,
     ref_counter_path: PathBuf,
@@ -161,7 +255,40 @@ impl From<&RepoSpec> for ContentMgmtKey {
     }
 }
 
+impl From<&ContentMgmtKey> for RepoSpec {
+    fn from(key: &ContentMgmtKey) -> RepoSpec {
+        RepoSpec {
+            base_dir_path: key.base_dir_path.clone(),
+            hash_algorithm: key.hash_algortithm,
+            max_size: None,
+        }
+    }
+}
+
 impl ContentMgmtKey {
+    pub fn base_dir_path(&self) -> &Path {
+        &self.base_dir_path
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algortithm
+    }
+
+    /// When the repository's contents were last changed.
+    pub fn last_modified(&self) -> Result<SystemTime, RepoError> {
+        Ok(self.ref_counter_path.metadata()?.modified()?)
+    }
+
+    /// A copy of this key for the same repository at a different location.
+    pub fn with_base_dir_path<P: AsRef<Path>>(&self, base_dir_path: P) -> Self {
+        let base_dir_path = base_dir_path.as_ref().to_path_buf();
+        ContentMgmtKey {
+            ref_counter_path: base_dir_path.join("ref_count"),
+            base_dir_path,
+            hash_algortithm: self.hash_algortithm,
+        }
+    }
+
     pub fn create_repo_dir(&self) -> Result<(), RepoError> {
         if self.base_dir_path.exists() {
             return Err(RepoError::RepoDirExists(self.base_dir_path.clone()));
@@ -175,20 +302,100 @@ impl ContentMgmtKey {
     pub fn open_content_manager(
         &self,
         mutability: Mutability,
+    ) -> Result<ContentManager, RepoError> {
+        self.open_content_manager_with_options(mutability, &ContentManagerOptions::default())
+    }
+
+    pub fn open_content_manager_with_options(
+        &self,
+        mutability: Mutability,
+        options: &ContentManagerOptions,
     ) -> Result<ContentManager, RepoError> {
         let mut hash_map_file = self.locked_ref_count_file(mutability)?;
         let ref_counter = ProtectedRefCounter::from_file(&mut hash_map_file, mutability)?;
-        let storage = Storage {
-            base_dir_path: self.base_dir_path.clone(),
+        if mutability == Mutability::Mutable {
+            self.replay_journal(&ref_counter, &mut hash_map_file)?;
+        }
+        let storage = Storage::new(&self.base_dir_path)?;
+        let quota = match mutability {
+            Mutability::Mutable => content::get_repo_max_size_for_key(self).map(|max_size| Quota {
+                max_size,
+                stored_bytes: Cell::new(ref_counter.content_data().sum_storage()),
+            }),
+            Mutability::Immutable => None,
         };
         Ok(ContentManager {
             content_mgmt_key: self.clone(),
             ref_counter,
             storage,
+            quota,
+            free_space_reserve: options.free_space_reserve,
             hash_map_file,
+            read_cache: RefCell::new(ReadCache::new(options.read_cache_capacity)),
+            cache_stats: Cell::new(CacheStats::default()),
+            store_stats: Cell::new(StoreStats::default()),
+            new_tokens: RefCell::new(HashSet::new()),
+            claimed_tokens: RefCell::new(vec![]),
+            released_tokens: RefCell::new(vec![]),
+            claims_journal_id: RefCell::new(None),
+            releases_journal_id: RefCell::new(None),
         })
     }
 
+    // Release the references pending in the journal entries left by
+    // processes that died (unless the entries' guard files exist).
+    fn replay_journal(
+        &self,
+        ref_counter: &ProtectedRefCounter,
+        hash_map_file: &mut File,
+    ) -> Result<(), RepoError> {
+        let (abandoned, current): (Vec<_>, Vec<_>) = journal::read(&self.base_dir_path)?
+            .into_iter()
+            .partition(|entry| entry.is_abandoned());
+        if abandoned.is_empty() {
+            return Ok(());
+        }
+        // NB: a crash after this leaves the references unreleased (rather
+        // than releasing them twice)
+        journal::write(&self.base_dir_path, &current)?;
+        for entry in abandoned.iter().filter(|entry| entry.is_needed()) {
+            ref_counter.release_pending(&entry.tokens);
+        }
+        ref_counter.to_file(hash_map_file)
+    }
+
+    /// Make the releases in the journal entry with the given id (see
+    /// `ContentManager::journal_claims()`) conditional on the file at
+    /// `unless_exists` not existing (e.g. just before a snapshot file
+    /// containing the references is written).
+    pub fn guard_journal_entry(&self, id: &str, unless_exists: &Path) -> Result<(), RepoError> {
+        let _lock = self.locked_ref_count_file(Mutability::Mutable)?;
+        journal::set_guard(&self.base_dir_path, id, unless_exists)
+    }
+
+    /// Remove the journal entry with the given id without releasing its
+    /// references (e.g. once they're recorded in a snapshot file or have
+    /// been released some other way).
+    pub fn discard_journal_entry(&self, id: &str) -> Result<(), RepoError> {
+        let _lock = self.locked_ref_count_file(Mutability::Mutable)?;
+        journal::discard(&self.base_dir_path, id)
+    }
+
+    /// Whether the repository could be locked for writing now (i.e. it's
+    /// writable and no other process has it locked).  The lock is released
+    /// again immediately.
+    pub fn try_lock(&self) -> Result<bool, RepoError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.ref_counter_path)?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == fs2::lock_contended_error().kind() => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     fn locked_ref_count_file(&self, mutability: Mutability) -> Result<File, RepoError> {
         let mutable = mutability == Mutability::Mutable;
         let file = OpenOptions::new()
@@ -212,6 +419,14 @@ pub struct RefCountData {
 }
 
 impl RefCountData {
+    pub fn is_referenced(&self) -> bool {
+        self.ref_count > 0
+    }
+
+    pub fn stored_size(&self) -> u64 {
+        self.stored_size
+    }
+
     fn decr_ref_count(&mut self) {
         if self.ref_count > 0 {
             self.ref_count -= 1;
@@ -274,6 +489,23 @@ pub struct ContentData {
     unreferenced_content_data: UnreferencedContentData,
 }
 
+impl ContentData {
+    /// The number of content items (blobs) held in the repository.
+    pub fn num_items(&self) -> u64 {
+        self.referenced_content_data.num_items + self.unreferenced_content_data.num_items
+    }
+
+    /// The total size of the (uncompressed) contents.
+    pub fn sum_content(&self) -> u128 {
+        self.referenced_content_data.sum_content + self.unreferenced_content_data.sum_content
+    }
+
+    /// The total space occupied by the stored (compressed) contents.
+    pub fn sum_storage(&self) -> u128 {
+        self.referenced_content_data.sum_storage + self.unreferenced_content_data.sum_storage
+    }
+}
+
 impl AddAssign<&RefCountData> for ContentData {
     fn add_assign(&mut self, ref_count_data: &RefCountData) {
         if ref_count_data.ref_count > 0 {
@@ -312,9 +544,32 @@ impl RefCounter {
         file.set_len(0)?;
         let mut snappy_wtr = snap::write::FrameEncoder::new(file);
         snappy_wtr.write_all(json_text.as_bytes())?;
+        // NB: finishing explicitly so that write errors aren't lost on drop
+        snappy_wtr.into_inner().map_err(|err| err.into_error())?;
         Ok(())
     }
 
+    fn content_sizes(&self) -> Vec<(String, u64)> {
+        self.0
+            .iter()
+            .map(|(token, rcd)| (token.clone(), rcd.content_size))
+            .collect()
+    }
+
+    fn stored_sizes(&self) -> Vec<(String, u64)> {
+        self.0
+            .iter()
+            .map(|(token, rcd)| (token.clone(), rcd.stored_size))
+            .collect()
+    }
+
+    fn ref_counts(&self) -> Vec<(String, RefCountData)> {
+        self.0
+            .iter()
+            .map(|(token, rcd)| (token.clone(), *rcd))
+            .collect()
+    }
+
     fn unreferenced_tokens(&self) -> Vec<String> {
         self.0
             .iter()
@@ -352,6 +607,43 @@ impl RefCounter {
         }
     }
 
+    // All or nothing: no counts are changed if any token is unknown or
+    // would be released more times than it's referenced
+    fn decr_ref_counts(&mut self, tokens: &[&str]) -> Result<Vec<RefCountData>, RepoError> {
+        let mut releases: HashMap<&str, u64> = HashMap::new();
+        for token in tokens.iter() {
+            *releases.entry(token).or_insert(0) += 1;
+        }
+        for token in tokens.iter() {
+            match self.0.get(*token) {
+                Some(ref_count_data) if ref_count_data.ref_count >= releases[token] => (),
+                Some(ref_count_data) => {
+                    return Err(RepoError::TooFewReferences(
+                        token.to_string(),
+                        ref_count_data.ref_count,
+                        releases[token],
+                    ))
+                }
+                None => return Err(RepoError::UnknownToken(token.to_string())),
+            }
+        }
+        tokens
+            .iter()
+            .map(|token| self.decr_ref_count(token))
+            .collect()
+    }
+
+    // Release the references (ignoring tokens without any).
+    fn release_pending(&mut self, tokens: &[String]) {
+        for token in tokens.iter() {
+            if let Some(ref_count_data) = self.0.get_mut(token) {
+                if ref_count_data.is_referenced() {
+                    ref_count_data.decr_ref_count();
+                }
+            }
+        }
+    }
+
     fn incr_ref_count(&mut self, token: &str) -> Result<RefCountData, RepoError> {
         match self.0.get_mut(token) {
             Some(ref_count_data) => {
@@ -459,6 +751,24 @@ impl ProtectedRefCounter {
         }
     }
 
+    fn decr_ref_counts_for_tokens(&self, tokens: &[&str]) -> Result<Vec<RefCountData>, RepoError> {
+        match *self {
+            ProtectedRefCounter::Immutable(_) => {
+                panic!("{:?}: line {:?}: immutability breach", file!(), line!())
+            }
+            ProtectedRefCounter::Mutable(ref rc) => rc.borrow_mut().decr_ref_counts(tokens),
+        }
+    }
+
+    fn release_pending(&self, tokens: &[String]) {
+        match *self {
+            ProtectedRefCounter::Immutable(_) => {
+                panic!("{:?}: line {:?}: immutability breach", file!(), line!())
+            }
+            ProtectedRefCounter::Mutable(ref rc) => rc.borrow_mut().release_pending(tokens),
+        }
+    }
+
     fn incr_ref_count_for_token(&self, token: &str) -> Result<RefCountData, RepoError> {
         match *self {
             ProtectedRefCounter::Immutable(_) => {
@@ -498,6 +808,27 @@ impl ProtectedRefCounter {
         }
     }
 
+    fn content_sizes(&self) -> Vec<(String, u64)> {
+        match *self {
+            ProtectedRefCounter::Mutable(ref rc) => rc.borrow().content_sizes(),
+            ProtectedRefCounter::Immutable(ref rc) => rc.content_sizes(),
+        }
+    }
+
+    fn stored_sizes(&self) -> Vec<(String, u64)> {
+        match *self {
+            ProtectedRefCounter::Mutable(ref rc) => rc.borrow().stored_sizes(),
+            ProtectedRefCounter::Immutable(ref rc) => rc.stored_sizes(),
+        }
+    }
+
+    fn ref_counts(&self) -> Vec<(String, RefCountData)> {
+        match *self {
+            ProtectedRefCounter::Mutable(ref rc) => rc.borrow().ref_counts(),
+            ProtectedRefCounter::Immutable(ref rc) => rc.ref_counts(),
+        }
+    }
+
     fn unreferenced_tokens(&self) -> Vec<String> {
         match *self {
             ProtectedRefCounter::Mutable(ref rc) => rc.borrow().unreferenced_tokens(),
@@ -537,6 +868,10 @@ impl ProtectedRefCounter {
 #[derive(Debug)]
 pub struct Storage {
     base_dir_path: PathBuf,
+    pack_index: RefCell<pack::PackIndex>,
+    pack_index_changed: Cell<bool>,
+    // The pack file that small contents are being appended to
+    pack_writer: RefCell<Option<pack::PackWriter>>,
 }
 
 pub enum ContentProblem {
@@ -545,6 +880,16 @@ pub enum ContentProblem {
 }
 
 impl Storage {
+    fn new(base_dir_path: &Path) -> Result<Self, RepoError> {
+        let pack_index = pack::PackIndex::read(&base_dir_path.join(pack::PACKS_DIR_NAME))?;
+        Ok(Self {
+            base_dir_path: base_dir_path.to_path_buf(),
+            pack_index: RefCell::new(pack_index),
+            pack_index_changed: Cell::new(false),
+            pack_writer: RefCell::new(None),
+        })
+    }
+
     fn token_content_file_path(&self, token: &str) -> PathBuf {
         let mut path_buf = self.base_dir_path.clone();
         path_buf.push(PathBuf::from(&token[0..3]));
@@ -553,7 +898,16 @@ impl Storage {
         path_buf
     }
 
-    fn store(&self, token: &str, file: &mut File) -> Result<u64, RepoError> {
+    // Returns the (uncompressed) content size and the stored size
+    fn store<R: Read + Seek>(&self, token: &str, reader: &mut R) -> Result<(u64, u64), RepoError> {
+        let pack_threshold = self.pack_index.borrow().pack_threshold();
+        if let Some(pack_threshold) = pack_threshold {
+            let content_size = reader.seek(SeekFrom::End(0))?;
+            reader.seek(SeekFrom::Start(0))?;
+            if content_size < pack_threshold {
+                return self.store_packed(token, reader);
+            }
+        }
         let content_file_path = self.token_content_file_path(token);
         let content_dir_path = content_file_path
             .parent()
@@ -563,33 +917,73 @@ impl Storage {
         }
         let content_file = File::create(&content_file_path)?;
         let mut compressed_content_file = snap::write::FrameEncoder::new(content_file);
-        io::copy(file, &mut compressed_content_file)?;
+        let content_size = match io::copy(reader, &mut compressed_content_file) {
+            Ok(content_size) => content_size,
+            Err(err) => {
+                // don't leave partial contents lying around
+                drop(compressed_content_file);
+                remove_file(&content_file_path).ok();
+                return Err(err.into());
+            }
+        };
         compressed_content_file.flush()?;
         let metadata = content_file_path.metadata()?;
-        Ok(metadata.len())
+        Ok((content_size, metadata.len()))
     }
 
+    // Packed contents are only removed from the index
     fn remove(&self, token: &str) -> Result<(), RepoError> {
         let path = self.token_content_file_path(token);
+        if self.pack_index.borrow_mut().remove(token).is_some() {
+            self.pack_index_changed.set(true);
+            if !path.exists() {
+                return Ok(());
+            }
+        }
         remove_file(&path)?;
         Ok(())
     }
 
-    fn write<W: Write>(&self, content_token: &str, writer: &mut W) -> Result<u64, RepoError> {
+    // The compressed contents whether they're loose or packed
+    fn compressed_reader(&self, content_token: &str) -> Result<Box<dyn Read>, RepoError> {
         let content_file_path = self.token_content_file_path(content_token);
-        if !content_file_path.exists() {
-            return Err(RepoError::UnknownToken(content_token.to_string()));
+        if content_file_path.exists() {
+            Ok(Box::new(File::open(content_file_path)?))
+        } else if let Some(location) = self.pack_index.borrow().get(content_token) {
+            Ok(Box::new(location.reader(&self.packs_dir_path())?))
+        } else {
+            Err(RepoError::UnknownToken(content_token.to_string()))
         }
-        let content_file = File::open(content_file_path)?;
-        let mut compressed_content_file = snap::read::FrameDecoder::new(content_file);
-        let n = io::copy(&mut compressed_content_file, writer)?;
+    }
+
+    fn write<W: Write>(&self, content_token: &str, writer: &mut W) -> Result<u64, RepoError> {
+        let compressed_reader = self.compressed_reader(content_token)?;
+        let mut decoder = snap::read::FrameDecoder::new(compressed_reader);
+        let n = io::copy(&mut decoder, writer)?;
         Ok(n)
     }
 
+    // Only the start of the contents is decompressed
+    fn read_prefix(&self, content_token: &str, n: usize) -> Result<Vec<u8>, RepoError> {
+        let compressed_reader = self.compressed_reader(content_token)?;
+        let mut prefix = Vec::with_capacity(n);
+        snap::read::FrameDecoder::new(compressed_reader)
+            .take(n as u64)
+            .read_to_end(&mut prefix)?;
+        Ok(prefix)
+    }
+
     fn stored_size(&self, token: &str) -> Result<u64, RepoError> {
         let content_file_path = self.token_content_file_path(token);
-        let metadata = content_file_path.metadata()?;
-        Ok(metadata.len())
+        match content_file_path.metadata() {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(err) => match self.pack_index.borrow().get(token) {
+                Some(location) if location.is_present(&self.packs_dir_path()) => {
+                    Ok(location.length)
+                }
+                _ => Err(err.into()),
+            },
+        }
     }
 
     fn content_problems(
@@ -599,7 +993,7 @@ impl Storage {
         let mut problems = vec![];
         for r_tl_entry in self.base_dir_path.read_dir()? {
             let tl_entry = r_tl_entry?;
-            if tl_entry.file_type()?.is_dir() {
+            if tl_entry.file_type()?.is_dir() && tl_entry.file_name() != pack::PACKS_DIR_NAME {
                 let dir_name = tl_entry.file_name().into_string()?;
                 for r_sl_entry in self.base_dir_path.join(&dir_name).read_dir()? {
                     let sl_entry = r_sl_entry?;
@@ -617,24 +1011,89 @@ impl Storage {
                 }
             }
         }
+        for (token, location) in self.pack_index.borrow().iter() {
+            if let Ok(ref_count_data) = ref_counter.ref_count_data_for_token(token) {
+                if ref_count_data.stored_size != location.length {
+                    problems.push(ContentProblem::Inconsistent(token.clone()));
+                }
+            } else {
+                problems.push(ContentProblem::Orphaned(token.clone()));
+            }
+        }
         Ok(problems)
     }
 }
 
+// The limit on a repository's size (from its spec) and the space its
+// stored contents currently occupy
+#[derive(Debug)]
+struct Quota {
+    max_size: u64,
+    stored_bytes: Cell<u128>,
+}
+
+impl Quota {
+    fn check(&self, base_dir_path: &Path, size: u64) -> Result<(), RepoError> {
+        let stored_bytes = self.stored_bytes.get();
+        if stored_bytes + size as u128 > self.max_size as u128 {
+            Err(RepoError::RepoQuotaExceeded(
+                base_dir_path.to_path_buf(),
+                size,
+                self.max_size,
+                stored_bytes,
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ContentManager {
     content_mgmt_key: ContentMgmtKey,
     ref_counter: ProtectedRefCounter,
     storage: Storage,
+    quota: Option<Quota>,
+    free_space_reserve: Option<u64>,
     hash_map_file: File,
+    read_cache: RefCell<ReadCache>,
+    cache_stats: Cell<CacheStats>,
+    store_stats: Cell<StoreStats>,
+    new_tokens: RefCell<HashSet<String>>,
+    // References claimed and released via this manager (for the journal)
+    claimed_tokens: RefCell<Vec<String>>,
+    released_tokens: RefCell<Vec<String>>,
+    claims_journal_id: RefCell<Option<String>>,
+    releases_journal_id: RefCell<Option<String>>,
 }
 
 impl Drop for ContentManager {
     fn drop(&mut self) {
         if self.ref_counter.is_mutable() {
+            // the contents must be safely stored before they're counted
+            if let Err(err) = self.storage.flush() {
+                panic!("{:?}: line {:?}: {:?}", file!(), line!(), err);
+            };
             if let Err(err) = self.ref_counter.to_file(&mut self.hash_map_file) {
                 panic!("{:?}: line {:?}: {:?}", file!(), line!(), err);
             };
+            // the releases are only done once the counts are written so
+            // their journal entry is kept until then
+            if let Some(ref id) = *self.releases_journal_id.borrow() {
+                if let Err(err) = journal::discard(&self.content_mgmt_key.base_dir_path, id) {
+                    panic!("{:?}: line {:?}: {:?}", file!(), line!(), err);
+                }
+            }
+            // the claims can only be released once they've been counted
+            if let Some(ref id) = *self.claims_journal_id.borrow() {
+                let tokens = self.claimed_tokens.take();
+                if !tokens.is_empty() {
+                    let entry = journal::PendingRelease::new(id, tokens, None);
+                    if let Err(err) = journal::add(&self.content_mgmt_key.base_dir_path, entry) {
+                        panic!("{:?}: line {:?}: {:?}", file!(), line!(), err);
+                    }
+                }
+            }
         };
         if let Err(err) = self.hash_map_file.unlock() {
             panic!("{:?}: line {:?}: {:?}", file!(), line!(), err);
@@ -642,6 +1101,32 @@ impl Drop for ContentManager {
     }
 }
 
+/// The differences between the contents of two repositories (see
+/// `ContentManager::compare_with()`).
+#[derive(Debug, Default, PartialEq)]
+pub struct RepoComparison {
+    /// The number of tokens (in the first repository) compared
+    pub tokens_compared: u64,
+    /// Tokens whose contents are in the first repository but not the second
+    pub missing: Vec<String>,
+    /// Tokens whose contents are in the second repository but not the first
+    pub extra: Vec<String>,
+    /// Tokens whose contents are stored with different sizes
+    pub size_mismatches: Vec<String>,
+    /// Tokens whose contents in the second repository don't match the
+    /// token (only checked by deep comparisons)
+    pub corrupt: Vec<String>,
+}
+
+impl RepoComparison {
+    pub fn is_match(&self) -> bool {
+        self.missing.is_empty()
+            && self.extra.is_empty()
+            && self.size_mismatches.is_empty()
+            && self.corrupt.is_empty()
+    }
+}
+
 pub struct Problems {
     pub token_problems: Vec<TokenProblem>,
     pub content_problems: Vec<ContentProblem>,
@@ -691,15 +1176,72 @@ impl ContentManager {
         Ok(rcd.ref_count)
     }
 
+    pub fn stored_size_for_token(&self, token: &str) -> Result<u64, RepoError> {
+        let rcd = self.ref_counter.ref_count_data_for_token(token)?;
+        Ok(rcd.stored_size)
+    }
+
     pub fn write_contents_for_token<W: Write>(
         &self,
         content_token: &str,
         writer: &mut W,
     ) -> Result<u64, RepoError> {
-        let n = self.storage.write(content_token, writer)?;
+        let capacity = self.read_cache.borrow().capacity();
+        if capacity == 0 {
+            return self.storage.write(content_token, writer);
+        }
+        let mut cache_stats = self.cache_stats.get();
+        let cache_key = content_token.to_string();
+        let cached = self.read_cache.borrow_mut().get(&cache_key);
+        let n = if let Some(data) = cached {
+            cache_stats.hits += 1;
+            writer.write_all(&data)?;
+            data.len() as u64
+        } else {
+            cache_stats.misses += 1;
+            let rcd = self.ref_counter.ref_count_data_for_token(content_token)?;
+            if rcd.content_size as usize <= capacity {
+                let mut data = Vec::with_capacity(rcd.content_size as usize);
+                self.storage.write(content_token, &mut data)?;
+                writer.write_all(&data)?;
+                let n = data.len() as u64;
+                self.read_cache
+                    .borrow_mut()
+                    .insert(cache_key, std::sync::Arc::new(data));
+                n
+            } else {
+                self.storage.write(content_token, writer)?
+            }
+        };
+        self.cache_stats.set(cache_stats);
         Ok(n)
     }
 
+    /// The first `n` (or fewer if the contents are shorter) bytes of the
+    /// contents without decompressing the rest of them.
+    pub fn read_contents_prefix(
+        &self,
+        content_token: &str,
+        n: usize,
+    ) -> Result<Vec<u8>, RepoError> {
+        self.storage.read_prefix(content_token, n)
+    }
+
+    /// Read cache hits and misses for contents written by this manager.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache_stats.get()
+    }
+
+    /// Time spent (and bytes written) storing contents via this manager.
+    pub fn store_stats(&self) -> StoreStats {
+        self.store_stats.get()
+    }
+
+    /// Whether the contents for `token` were added to the repository by this manager.
+    pub fn is_new_token(&self, token: &str) -> bool {
+        self.new_tokens.borrow().contains(token)
+    }
+
     pub fn prune_contents(&self) -> Result<UnreferencedContentData, RepoError> {
         if !self.is_mutable() {
             panic!("{:?}: line {:?}: immutability breach", file!(), line!());
@@ -713,33 +1255,155 @@ impl ContentManager {
         Ok(unreferenced_content_data)
     }
 
+    /// Consolidate the stored contents into pack files (see `pack`).
+    pub fn repack(&self) -> Result<RepackStats, RepoError> {
+        if !self.is_mutable() {
+            panic!("{:?}: line {:?}: immutability breach", file!(), line!());
+        }
+        self.storage.repack(&self.ref_counter.content_sizes())
+    }
+
+    /// The size below which new contents are appended to pack files
+    /// rather than stored in their own file (if any).
+    pub fn pack_threshold(&self) -> Option<u64> {
+        self.storage.pack_index.borrow().pack_threshold()
+    }
+
+    pub fn set_pack_threshold(&self, pack_threshold: Option<u64>) {
+        if !self.is_mutable() {
+            panic!("{:?}: line {:?}: immutability breach", file!(), line!());
+        }
+        self.storage
+            .pack_index
+            .borrow_mut()
+            .set_pack_threshold(pack_threshold);
+        self.storage.pack_index_changed.set(true);
+    }
+
     pub fn release_contents(&self, content_token: &str) -> Result<RefCountData, RepoError> {
-        self.ref_counter.decr_ref_count_for_token(&content_token)
+        let rcd = self.ref_counter.decr_ref_count_for_token(content_token)?;
+        self.released_tokens
+            .borrow_mut()
+            .push(content_token.to_string());
+        Ok(rcd)
+    }
+
+    /// Release a reference for each of the tokens (in one go) returning
+    /// the resulting reference count data for each of them.  If any of
+    /// the tokens is unknown (or appears more times than it has references)
+    /// nothing is released.
+    pub fn release_contents_batch(
+        &self,
+        content_tokens: &[&str],
+    ) -> Result<Vec<RefCountData>, RepoError> {
+        let rcds = self
+            .ref_counter
+            .decr_ref_counts_for_tokens(content_tokens)?;
+        self.released_tokens
+            .borrow_mut()
+            .extend(content_tokens.iter().map(|token| token.to_string()));
+        Ok(rcds)
+    }
+
+    /// Add a reference to already stored contents returning their stored size.
+    pub fn claim_contents(&self, content_token: &str) -> Result<u64, RepoError> {
+        let rcd = self.ref_counter.incr_ref_count_for_token(content_token)?;
+        self.claimed_tokens
+            .borrow_mut()
+            .push(content_token.to_string());
+        Ok(rcd.stored_size)
+    }
+
+    /// Record the references claimed via this manager in the repository's
+    /// journal (under `id`) when it's dropped so that they'll be released
+    /// if this process dies before they're recorded elsewhere (e.g. in a
+    /// snapshot file).  See `ContentMgmtKey::guard_journal_entry()` and
+    /// `ContentMgmtKey::discard_journal_entry()`.
+    pub fn journal_claims(&self, id: &str) {
+        *self.claims_journal_id.borrow_mut() = Some(id.to_string());
+    }
+
+    /// Record the references released via this manager in the repository's
+    /// journal so that they'll still be released if this process dies
+    /// after `unless_exists` (e.g. the snapshot file that held them) is
+    /// removed but before the new counts are written.
+    pub fn journal_releases(&self, id: &str, unless_exists: &Path) -> Result<(), RepoError> {
+        if !self.is_mutable() {
+            panic!("{:?}: line {:?}: immutability breach", file!(), line!());
+        }
+        let tokens = self.released_tokens.borrow().clone();
+        let entry = journal::PendingRelease::new(id, tokens, Some(unless_exists));
+        journal::add(&self.content_mgmt_key.base_dir_path, entry)?;
+        *self.releases_journal_id.borrow_mut() = Some(id.to_string());
+        Ok(())
     }
 
-    pub fn store_contents(&self, file: &mut File) -> Result<(String, u64, u64), RepoError> {
-        let digest = self.content_mgmt_key.hash_algortithm.reader_digest(file)?;
+    pub fn store_contents<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+    ) -> Result<(String, u64, u64), RepoError> {
+        let mut store_stats = self.store_stats.get();
+        let started = Instant::now();
+        let (digest, size) = self
+            .content_mgmt_key
+            .hash_algortithm
+            .reader_digest_and_size(reader)?;
+        store_stats.hash_time += started.elapsed();
+        self.store_stats.set(store_stats);
         match self.ref_counter.incr_ref_count_for_token(&digest) {
-            Ok(rcd) => Ok((digest, rcd.stored_size, 0)),
+            Ok(rcd) => {
+                self.claimed_tokens.borrow_mut().push(digest.clone());
+                Ok((digest, rcd.stored_size, 0))
+            }
             Err(_) => {
-                // NB: reader_digest will have moved the pointer
-                file.seek(io::SeekFrom::Start(0))?;
-                let content_size = match file.metadata() {
-                    Ok(metadata) => metadata.len(),
-                    Err(err) => panic!("{:?}: line {:?}: {:?}", file!(), line!(), err),
-                };
-                let stored_size = self.storage.store(&digest, file)?;
-                let rcd = RefCountData {
-                    content_size: content_size,
-                    stored_size: stored_size,
-                    ref_count: 1,
-                };
-                self.ref_counter.insert(&digest, rcd);
+                let stored_size = self.store_new_contents(&digest, size, 1, reader)?;
+                self.claimed_tokens.borrow_mut().push(digest.clone());
                 Ok((digest, stored_size, stored_size))
             }
         }
     }
 
+    // Store contents (of `size` bytes) that aren't in the repository under
+    // `digest` with `ref_count` references returning their stored size
+    fn store_new_contents<R: Read + Seek>(
+        &self,
+        digest: &str,
+        size: u64,
+        ref_count: u64,
+        reader: &mut R,
+    ) -> Result<u64, RepoError> {
+        check_free_space(
+            &self.content_mgmt_key.base_dir_path,
+            size,
+            self.free_space_reserve,
+        )?;
+        if let Some(ref quota) = self.quota {
+            // NB: the (uncompressed) size is the worst case
+            quota.check(&self.content_mgmt_key.base_dir_path, size)?;
+        }
+        // NB: reader_digest will have moved the pointer
+        reader.seek(io::SeekFrom::Start(0))?;
+        let mut store_stats = self.store_stats.get();
+        let started = Instant::now();
+        let (content_size, stored_size) = self.storage.store(digest, reader)?;
+        store_stats.store_time += started.elapsed();
+        store_stats.bytes_written += stored_size;
+        self.store_stats.set(store_stats);
+        if let Some(ref quota) = self.quota {
+            quota
+                .stored_bytes
+                .set(quota.stored_bytes.get() + stored_size as u128);
+        }
+        let rcd = RefCountData {
+            content_size,
+            stored_size,
+            ref_count,
+        };
+        self.ref_counter.insert(digest, rcd);
+        self.new_tokens.borrow_mut().insert(digest.to_string());
+        Ok(stored_size)
+    }
+
     pub fn delete(&self) -> Result<(), RepoError> {
         self.prune_contents()?;
         let rcd = self.referenced_content_data();
@@ -751,6 +1415,59 @@ impl ContentManager {
         }
     }
 
+    /// Compare this repository's contents with those of `other` (e.g. an
+    /// off-site copy) by token and stored size.  The sizes used for
+    /// `other` are those of its stored contents rather than those it has
+    /// recorded.  A `deep` comparison also checks that each of the contents
+    /// in `other` still hashes to its token (which means reading them all).
+    pub fn compare_with(
+        &self,
+        other: &ContentManager,
+        deep: bool,
+    ) -> Result<RepoComparison, RepoError> {
+        let hash_algorithm = self.content_mgmt_key.hash_algortithm;
+        if hash_algorithm != other.content_mgmt_key.hash_algortithm {
+            return Err(RepoError::HashAlgorithmMismatch(
+                hash_algorithm.to_string(),
+                other.content_mgmt_key.hash_algortithm.to_string(),
+            ));
+        }
+        let mut comparison = RepoComparison::default();
+        let mut stored_sizes = self.ref_counter.stored_sizes();
+        stored_sizes.sort();
+        for (token, stored_size) in stored_sizes {
+            comparison.tokens_compared += 1;
+            match other.storage.stored_size(&token) {
+                Err(_) => comparison.missing.push(token),
+                Ok(other_stored_size) if other_stored_size != stored_size => {
+                    comparison.size_mismatches.push(token)
+                }
+                Ok(_) if deep => {
+                    let hashes_to_token = other
+                        .storage
+                        .compressed_reader(&token)
+                        .and_then(|reader| {
+                            let mut decoder = snap::read::FrameDecoder::new(reader);
+                            Ok(hash_algorithm.reader_digest(&mut decoder)?)
+                        })
+                        .is_ok_and(|digest| digest == token);
+                    if !hashes_to_token {
+                        comparison.corrupt.push(token);
+                    }
+                }
+                Ok(_) => (),
+            }
+        }
+        let mut other_tokens = other.ref_counter.stored_sizes();
+        other_tokens.sort();
+        for (token, _) in other_tokens {
+            if self.ref_counter.ref_count_data_for_token(&token).is_err() {
+                comparison.extra.push(token);
+            }
+        }
+        Ok(comparison)
+    }
+
     pub fn problems(&self) -> Result<Problems, RepoError> {
         let token_problems = self.ref_counter.token_problems(&self.storage);
         let content_problems = self.storage.content_problems(&self.ref_counter)?;
@@ -769,6 +1486,75 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn free_space_reserve_is_respected() {
+        let dir = TempDir::new("FREE_SPACE_TEST").unwrap();
+        assert!(check_free_space(dir.path(), u64::MAX, None).is_ok());
+        assert!(check_free_space(dir.path(), 1, Some(0)).is_ok());
+        match check_free_space(dir.path(), 1, Some(u64::MAX)) {
+            Err(RepoError::InsufficientSpace(path, 1, available)) => {
+                assert_eq!(path, dir.path());
+                assert_eq!(available, fs2::available_space(dir.path()).unwrap());
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn content_managers_keep_their_free_space_reserve() {
+        let tmp_dir = TempDir::new("TEST").unwrap();
+        let repo_spec = RepoSpec::new(tmp_dir.path().join("repo"), HashAlgorithm::Sha1);
+        let cm_key: ContentMgmtKey = (&repo_spec).into();
+        cm_key.create_repo_dir().unwrap();
+        let options = ContentManagerOptions {
+            free_space_reserve: Some(u64::MAX),
+            ..ContentManagerOptions::default()
+        };
+        let cmgr = cm_key
+            .open_content_manager_with_options(Mutability::Mutable, &options)
+            .unwrap();
+        assert!(matches!(
+            cmgr.store_contents(&mut io::Cursor::new("big")),
+            Err(RepoError::InsufficientSpace(..))
+        ));
+        drop(cmgr);
+        let cmgr = cm_key.open_content_manager(Mutability::Mutable).unwrap();
+        assert!(cmgr.store_contents(&mut io::Cursor::new("big")).is_ok());
+    }
+
+    #[test]
+    fn content_managers_keep_their_own_read_cache() {
+        let tmp_dir = TempDir::new("TEST").unwrap();
+        let repo_spec = RepoSpec::new(tmp_dir.path().join("repo"), HashAlgorithm::Sha1);
+        let cm_key: ContentMgmtKey = (&repo_spec).into();
+        cm_key.create_repo_dir().unwrap();
+        let (token, _, _) = cm_key
+            .open_content_manager(Mutability::Mutable)
+            .unwrap()
+            .store_contents(&mut io::Cursor::new("cached"))
+            .unwrap();
+        let options = ContentManagerOptions {
+            read_cache_capacity: 1024,
+            ..ContentManagerOptions::default()
+        };
+        let cmgr = cm_key
+            .open_content_manager_with_options(Mutability::Immutable, &options)
+            .unwrap();
+        for _ in 0..3 {
+            let mut contents = vec![];
+            cmgr.write_contents_for_token(&token, &mut contents)
+                .unwrap();
+            assert_eq!(contents, b"cached");
+        }
+        assert_eq!(cmgr.cache_stats(), CacheStats { hits: 2, misses: 1 });
+        drop(cmgr);
+        let cmgr = cm_key.open_content_manager(Mutability::Immutable).unwrap();
+        let mut contents = vec![];
+        cmgr.write_contents_for_token(&token, &mut contents)
+            .unwrap();
+        assert_eq!(cmgr.cache_stats(), CacheStats::default());
+    }
+
     #[test]
     fn repo_spec() {
         let repo_spec = RepoSpec::new("~/whatever", HashAlgorithm::Sha256);
@@ -802,11 +1588,43 @@ mod tests {
     fn storage_file_name() {
         let storage = Storage {
             base_dir_path: PathBuf::from("data"),
+            pack_index: RefCell::new(pack::PackIndex::default()),
+            pack_index_changed: Cell::new(false),
+            pack_writer: RefCell::new(None),
         };
         let token_file_path = storage.token_content_file_path("AAGH");
         assert_eq!(token_file_path, PathBuf::from("data/AAG/H"));
     }
 
+    #[test]
+    fn batches_cant_release_more_references_than_there_are() {
+        let tmp_dir = TempDir::new("TEST").unwrap();
+        let repo_spec = RepoSpec::new(tmp_dir.path().join("repo"), HashAlgorithm::Sha1);
+        let cm_key: ContentMgmtKey = (&repo_spec).into();
+        cm_key.create_repo_dir().unwrap();
+        let cmgr = cm_key.open_content_manager(Mutability::Mutable).unwrap();
+        let once = cmgr.store_contents(&mut io::Cursor::new("once")).unwrap().0;
+        let twice = cmgr
+            .store_contents(&mut io::Cursor::new("twice"))
+            .unwrap()
+            .0;
+        cmgr.claim_contents(&twice).unwrap();
+        assert!(matches!(
+            cmgr.release_contents_batch(&[&twice, &once, &once]),
+            Err(RepoError::TooFewReferences(token, 1, 2)) if token == once
+        ));
+        // nothing was released
+        assert_eq!(cmgr.ref_count_for_token(&once).unwrap(), 1);
+        assert_eq!(cmgr.ref_count_for_token(&twice).unwrap(), 2);
+        let rcds = cmgr
+            .release_contents_batch(&[&twice, &once, &twice])
+            .unwrap();
+        let referenced: Vec<bool> = rcds.iter().map(|rcd| rcd.is_referenced()).collect();
+        assert_eq!(referenced, vec![true, false, false]);
+        assert_eq!(cmgr.ref_count_for_token(&once).unwrap(), 0);
+        assert_eq!(cmgr.ref_count_for_token(&twice).unwrap(), 0);
+    }
+
     #[test]
     fn repo_use() {
         let tmp_dir = TempDir::new("TEST").unwrap();
@@ -831,6 +1649,8 @@ mod tests {
             "92170CDC034B2FF819323FF670D3B7266C8BFFCD".to_string(),
         );
         assert_eq!(cmgr.ref_count_for_token(&result.0).unwrap(), 1);
+        assert!(cmgr.is_new_token(&result.0));
+        assert_eq!(cmgr.store_stats().bytes_written, 5816);
         assert_eq!(cmgr.problems().unwrap().total(), 0);
         assert_eq!(
             cmgr.unreferenced_content_data(),
@@ -847,6 +1667,7 @@ mod tests {
         let mut file = File::open("../LICENSE-APACHE").unwrap();
         let result = cmgr.store_contents(&mut file).unwrap();
         assert_eq!(cmgr.ref_count_for_token(&result.0).unwrap(), 2);
+        assert_eq!(cmgr.store_stats().bytes_written, 5816);
         assert_eq!(
             cmgr.unreferenced_content_data(),
             UnreferencedContentData::default()
@@ -861,7 +1682,13 @@ mod tests {
         assert!(cmgr.delete().is_err());
         assert_eq!(cmgr.referenced_content_data(), expected);
         assert_eq!(cmgr.problems().unwrap().total(), 0);
-        assert!(cmgr.release_contents(&result.0).is_ok());
+        assert!(cmgr
+            .release_contents_batch(&[&result.0, "NONEXISTENT"])
+            .is_err());
+        assert_eq!(cmgr.ref_count_for_token(&result.0).unwrap(), 2);
+        let rcds = cmgr.release_contents_batch(&[&result.0]).unwrap();
+        assert_eq!(rcds.len(), 1);
+        assert!(rcds[0].is_referenced());
         assert_eq!(cmgr.problems().unwrap().total(), 0);
         assert_eq!(cmgr.ref_count_for_token(&result.0).unwrap(), 1);
         assert_eq!(
@@ -0,0 +1,517 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au> <pwil3058@outlook.com>
+
+//! Pack files: (compressed) contents consolidated into a few large files
+//! in the repository's `packs` directory rather than one file per content
+//! token.  An index records where each packed token's contents are and
+//! `Storage` reads loose and packed contents alike.  Repositories with a
+//! pack threshold append small contents to the current pack file as they
+//! are stored (saving the file system from millions of tiny files).
+//! Pruning packed contents only removes them from the index and the space
+//! they occupied is reclaimed when the repository is next repacked.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{RepoError, Storage};
+
+pub(crate) const PACKS_DIR_NAME: &str = "packs";
+const INDEX_FILE_NAME: &str = "index";
+const PACK_SUFFIX: &str = ".pack";
+
+/// Contents of at least this many bytes are left loose by repacking (unless
+/// the repository has its own pack threshold) as there's nothing to be
+/// gained by packing them.
+pub const DEFAULT_PACK_THRESHOLD: u64 = 1 << 20;
+
+// Once the current pack file reaches this size stored contents go in a new one
+const PACK_FILE_MAX_SIZE: u64 = 1 << 26;
+
+fn pack_file_path(packs_dir_path: &Path, pack: u64) -> PathBuf {
+    packs_dir_path.join(format!("{:08}{}", pack, PACK_SUFFIX))
+}
+
+// The numbers and sizes of the pack files present
+fn pack_file_sizes(packs_dir_path: &Path) -> Result<HashMap<u64, u64>, RepoError> {
+    let mut sizes = HashMap::new();
+    let entries = match packs_dir_path.read_dir() {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(sizes),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name().into_string()?;
+        if let Some(Ok(pack)) = file_name.strip_suffix(PACK_SUFFIX).map(str::parse) {
+            sizes.insert(pack, entry.metadata()?.len());
+        }
+    }
+    Ok(sizes)
+}
+
+/// Where a token's (compressed) contents are in the pack files.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub(crate) struct PackLocation {
+    pub pack: u64,
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl PackLocation {
+    pub fn reader(&self, packs_dir_path: &Path) -> Result<io::Take<File>, RepoError> {
+        let mut file = File::open(pack_file_path(packs_dir_path, self.pack))?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        Ok(file.take(self.length))
+    }
+
+    // The contents can actually be read from the pack file
+    pub fn is_present(&self, packs_dir_path: &Path) -> bool {
+        match pack_file_path(packs_dir_path, self.pack).metadata() {
+            Ok(metadata) => metadata.len() >= self.offset + self.length,
+            Err(_) => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Default, Debug)]
+pub(crate) struct PackIndex {
+    next_pack: u64,
+    locations: HashMap<String, PackLocation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pack_threshold: Option<u64>,
+}
+
+impl PackIndex {
+    pub fn read(packs_dir_path: &Path) -> Result<Self, RepoError> {
+        match File::open(packs_dir_path.join(INDEX_FILE_NAME)) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // The index is replaced in one go so that an interrupted write leaves
+    // the previous index intact
+    pub fn write(&self, packs_dir_path: &Path) -> Result<(), RepoError> {
+        fs::create_dir_all(packs_dir_path)?;
+        let tmp_file_path = packs_dir_path.join(format!("{}.tmp", INDEX_FILE_NAME));
+        let mut writer = BufWriter::new(File::create(&tmp_file_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp_file_path, packs_dir_path.join(INDEX_FILE_NAME))?;
+        Ok(())
+    }
+
+    pub fn pack_threshold(&self) -> Option<u64> {
+        self.pack_threshold
+    }
+
+    pub fn set_pack_threshold(&mut self, pack_threshold: Option<u64>) {
+        self.pack_threshold = pack_threshold;
+    }
+
+    pub fn get(&self, token: &str) -> Option<PackLocation> {
+        self.locations.get(token).copied()
+    }
+
+    pub fn remove(&mut self, token: &str) -> Option<PackLocation> {
+        self.locations.remove(token)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &PackLocation)> {
+        self.locations.iter()
+    }
+
+    // The number of bytes in each pack that are still in use
+    fn live_bytes(&self) -> HashMap<u64, u64> {
+        let mut live_bytes = HashMap::new();
+        for location in self.locations.values() {
+            *live_bytes.entry(location.pack).or_insert(0) += location.length;
+        }
+        live_bytes
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct PackWriter {
+    pack: u64,
+    writer: BufWriter<File>,
+    offset: u64,
+}
+
+impl PackWriter {
+    fn create(packs_dir_path: &Path, pack: u64) -> Result<Self, RepoError> {
+        fs::create_dir_all(packs_dir_path)?;
+        let file = File::create(pack_file_path(packs_dir_path, pack))?;
+        Ok(Self {
+            pack,
+            writer: BufWriter::new(file),
+            offset: 0,
+        })
+    }
+
+    fn open_for_append(packs_dir_path: &Path, pack: u64) -> Result<Self, RepoError> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .open(pack_file_path(packs_dir_path, pack))?;
+        let offset = file.metadata()?.len();
+        Ok(Self {
+            pack,
+            writer: BufWriter::new(file),
+            offset,
+        })
+    }
+
+    fn append<R: Read>(&mut self, reader: &mut R) -> Result<PackLocation, RepoError> {
+        let length = io::copy(reader, &mut self.writer)?;
+        let location = PackLocation {
+            pack: self.pack,
+            offset: self.offset,
+            length,
+        };
+        self.offset += length;
+        Ok(location)
+    }
+
+    fn finish(self) -> Result<(), RepoError> {
+        let file = self.writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+// The new pack file is only created once there's something to put in it
+fn open_pack<'a>(
+    pack_writer: &'a mut Option<PackWriter>,
+    packs_dir_path: &Path,
+    next_pack: &mut u64,
+) -> Result<&'a mut PackWriter, RepoError> {
+    if pack_writer.is_none() {
+        *pack_writer = Some(PackWriter::create(packs_dir_path, *next_pack)?);
+        *next_pack += 1;
+    }
+    Ok(pack_writer.as_mut().expect("pack writer was just created"))
+}
+
+/// What repacking a repository achieved.
+#[derive(PartialEq, Clone, Copy, Default, Debug)]
+pub struct RepackStats {
+    /// Contents moved into the new pack file
+    pub items_packed: u64,
+    pub loose_files_removed: u64,
+    pub pack_files_removed: u64,
+    /// Space occupied by the removed files less the size of the new pack
+    pub bytes_freed: u64,
+}
+
+impl Storage {
+    pub(crate) fn packs_dir_path(&self) -> PathBuf {
+        self.base_dir_path.join(PACKS_DIR_NAME)
+    }
+
+    // Returns the (uncompressed) content size and the stored size
+    pub(crate) fn store_packed<R: Read>(
+        &self,
+        token: &str,
+        reader: &mut R,
+    ) -> Result<(u64, u64), RepoError> {
+        let mut compressed = vec![];
+        let mut encoder = snap::write::FrameEncoder::new(&mut compressed);
+        let content_size = io::copy(reader, &mut encoder)?;
+        encoder.flush()?;
+        drop(encoder);
+        let mut pack_writer = self.pack_writer.borrow_mut();
+        let mut pack_index = self.pack_index.borrow_mut();
+        if let Some(writer) = pack_writer.take() {
+            if writer.offset < PACK_FILE_MAX_SIZE {
+                *pack_writer = Some(writer);
+            } else {
+                writer.finish()?;
+            }
+        }
+        if pack_writer.is_none() {
+            let packs_dir_path = self.packs_dir_path();
+            let last_pack = pack_index.next_pack.checked_sub(1);
+            let last_pack_size = last_pack
+                .and_then(|pack| pack_file_path(&packs_dir_path, pack).metadata().ok())
+                .map(|metadata| metadata.len());
+            *pack_writer = match (last_pack, last_pack_size) {
+                (Some(pack), Some(size)) if size < PACK_FILE_MAX_SIZE => {
+                    Some(PackWriter::open_for_append(&packs_dir_path, pack)?)
+                }
+                _ => {
+                    let pack = pack_index.next_pack;
+                    pack_index.next_pack += 1;
+                    Some(PackWriter::create(&packs_dir_path, pack)?)
+                }
+            };
+        }
+        let writer = pack_writer.as_mut().expect("pack writer was just opened");
+        let location = writer.append(&mut compressed.as_slice())?;
+        // so that the contents can be read straight away
+        writer.writer.flush()?;
+        pack_index.locations.insert(token.to_string(), location);
+        self.pack_index_changed.set(true);
+        Ok((content_size, location.length))
+    }
+
+    fn finish_pack_writer(&self) -> Result<(), RepoError> {
+        if let Some(writer) = self.pack_writer.borrow_mut().take() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Make sure that packed contents (and the index) are on disk.
+    pub(crate) fn flush(&self) -> Result<(), RepoError> {
+        self.finish_pack_writer()?;
+        if self.pack_index_changed.get() {
+            self.pack_index.borrow().write(&self.packs_dir_path())?;
+            self.pack_index_changed.set(false);
+        }
+        Ok(())
+    }
+
+    /// Move the loose contents for `tokens` (other than those whose content
+    /// size is at or above the pack threshold) into a new pack file along
+    /// with the live contents of any pack files that contain unused space
+    /// and then remove the files that are no longer needed.  Packed contents
+    /// not belonging to `tokens` are dropped.
+    pub(crate) fn repack(&self, tokens: &[(String, u64)]) -> Result<RepackStats, RepoError> {
+        self.finish_pack_writer()?;
+        let packs_dir_path = self.packs_dir_path();
+        let mut pack_index = self.pack_index.borrow_mut();
+        let wanted: HashSet<&String> = tokens.iter().map(|(token, _)| token).collect();
+        pack_index
+            .locations
+            .retain(|token, _| wanted.contains(token));
+        let pack_sizes = pack_file_sizes(&packs_dir_path)?;
+        let live_bytes = pack_index.live_bytes();
+        let rewrite: HashSet<u64> = pack_sizes
+            .iter()
+            .filter(|(pack, size)| live_bytes.get(pack) != Some(size))
+            .map(|(pack, _)| *pack)
+            .collect();
+        let mut stats = RepackStats::default();
+        let mut next_pack = pack_index.next_pack;
+        let pack_threshold = pack_index.pack_threshold.unwrap_or(DEFAULT_PACK_THRESHOLD);
+        let mut pack_writer: Option<PackWriter> = None;
+        let mut locations = HashMap::new();
+        let mut redundant_loose_files = vec![];
+        let mut sorted_tokens: Vec<&(String, u64)> = tokens.iter().collect();
+        sorted_tokens.sort();
+        for (token, content_size) in sorted_tokens {
+            let loose_file_path = self.token_content_file_path(token);
+            let loose_size = match loose_file_path.metadata() {
+                Ok(metadata) => Some(metadata.len()),
+                Err(err) if err.kind() == ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            let location = pack_index.get(token);
+            if let Some(location) = location {
+                if !rewrite.contains(&location.pack) {
+                    if let Some(size) = loose_size {
+                        // left behind by an interrupted repack
+                        redundant_loose_files.push((loose_file_path, size));
+                    }
+                    locations.insert(token.to_string(), location);
+                    continue;
+                }
+            }
+            let new_location = match (loose_size, location) {
+                (Some(_), _) if *content_size >= pack_threshold => continue,
+                (Some(size), _) => {
+                    let mut reader = File::open(&loose_file_path)?;
+                    let writer = open_pack(&mut pack_writer, &packs_dir_path, &mut next_pack)?;
+                    let new_location = writer.append(&mut reader)?;
+                    redundant_loose_files.push((loose_file_path, size));
+                    new_location
+                }
+                (None, Some(location)) => {
+                    let mut reader = location.reader(&packs_dir_path)?;
+                    let writer = open_pack(&mut pack_writer, &packs_dir_path, &mut next_pack)?;
+                    writer.append(&mut reader)?
+                }
+                // missing contents are reported by problems()
+                (None, None) => continue,
+            };
+            locations.insert(token.to_string(), new_location);
+            stats.items_packed += 1;
+        }
+        let mut bytes_written = 0;
+        if let Some(writer) = pack_writer {
+            bytes_written = writer.offset;
+            writer.finish()?;
+        }
+        let new_pack_index = PackIndex {
+            next_pack,
+            locations,
+            pack_threshold: pack_index.pack_threshold,
+        };
+        new_pack_index.write(&packs_dir_path)?;
+        *pack_index = new_pack_index;
+        self.pack_index_changed.set(false);
+
+        let mut bytes_removed = 0;
+        let live_bytes = pack_index.live_bytes();
+        for (pack, size) in pack_sizes {
+            if !live_bytes.contains_key(&pack) {
+                fs::remove_file(pack_file_path(&packs_dir_path, pack))?;
+                stats.pack_files_removed += 1;
+                bytes_removed += size;
+            }
+        }
+        for (loose_file_path, size) in redundant_loose_files {
+            fs::remove_file(loose_file_path)?;
+            stats.loose_files_removed += 1;
+            bytes_removed += size;
+        }
+        stats.bytes_freed = bytes_removed.saturating_sub(bytes_written);
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod pack_tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{ContentMgmtKey, HashAlgorithm, Mutability, RepoSpec};
+
+    const FILE_PATHS: [&str; 4] = [
+        "./src/content.rs",
+        "./src/error.rs",
+        "./src/config.rs",
+        "./src/read_cache.rs",
+    ];
+
+    fn contents_for_token(key: &ContentMgmtKey, token: &str) -> Vec<u8> {
+        let cm = key.open_content_manager(Mutability::Immutable).unwrap();
+        let mut contents = vec![];
+        cm.write_contents_for_token(token, &mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn small_contents_are_packed_as_they_are_stored() {
+        let tmp_dir = TempDir::new("PACK_TEST").unwrap();
+        let repo_spec = RepoSpec::new(tmp_dir.path().join("repo"), HashAlgorithm::Sha1);
+        let key = ContentMgmtKey::from(&repo_spec);
+        key.create_repo_dir().unwrap();
+        let mut tokens = vec![];
+        {
+            let cm = key.open_content_manager(Mutability::Mutable).unwrap();
+            assert_eq!(cm.pack_threshold(), None);
+            let (token, _, _) = cm
+                .store_contents(&mut File::open(FILE_PATHS[1]).unwrap())
+                .unwrap();
+            assert!(cm.storage.token_content_file_path(&token).exists());
+            tokens.push(token);
+            // migrate the existing small contents
+            cm.set_pack_threshold(Some(8192));
+            assert_eq!(cm.repack().unwrap().items_packed, 1);
+            for file_path in [FILE_PATHS[2], FILE_PATHS[0]].iter() {
+                let (token, _, _) = cm
+                    .store_contents(&mut File::open(file_path).unwrap())
+                    .unwrap();
+                tokens.push(token);
+            }
+            assert!(!cm.storage.token_content_file_path(&tokens[1]).exists());
+            assert!(cm.storage.token_content_file_path(&tokens[2]).exists());
+            let mut contents = vec![];
+            cm.write_contents_for_token(&tokens[1], &mut contents)
+                .unwrap();
+            assert_eq!(contents, fs::read(FILE_PATHS[2]).unwrap());
+        }
+        let cm = key.open_content_manager(Mutability::Immutable).unwrap();
+        assert_eq!(cm.pack_threshold(), Some(8192));
+        assert_eq!(cm.problems().unwrap().total(), 0);
+        assert_eq!(
+            pack_file_sizes(&cm.storage.packs_dir_path()).unwrap().len(),
+            1
+        );
+        drop(cm);
+        for (token, file_path) in tokens
+            .iter()
+            .zip([FILE_PATHS[1], FILE_PATHS[2], FILE_PATHS[0]].iter())
+        {
+            assert_eq!(
+                contents_for_token(&key, token),
+                fs::read(file_path).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn loose_and_packed_contents_are_read_alike() {
+        let tmp_dir = TempDir::new("PACK_TEST").unwrap();
+        let repo_spec = RepoSpec::new(tmp_dir.path().join("repo"), HashAlgorithm::Sha1);
+        let key = ContentMgmtKey::from(&repo_spec);
+        key.create_repo_dir().unwrap();
+        let mut tokens = vec![];
+        {
+            let cm = key.open_content_manager(Mutability::Mutable).unwrap();
+            for file_path in FILE_PATHS.iter() {
+                let (token, _, _) = cm
+                    .store_contents(&mut File::open(file_path).unwrap())
+                    .unwrap();
+                tokens.push(token);
+            }
+            cm.release_contents(&tokens[1]).unwrap();
+            let stats = cm.repack().unwrap();
+            assert_eq!(stats.items_packed, 4);
+            assert_eq!(stats.loose_files_removed, 4);
+            assert_eq!(stats.pack_files_removed, 0);
+            assert!(!cm.storage.token_content_file_path(&tokens[0]).exists());
+            assert_eq!(cm.problems().unwrap().total(), 0);
+            let prefix = cm.read_contents_prefix(&tokens[0], 10).unwrap();
+            assert_eq!(prefix, &fs::read(FILE_PATHS[0]).unwrap()[..10]);
+        }
+        for (token, file_path) in tokens.iter().zip(FILE_PATHS.iter()) {
+            assert_eq!(
+                contents_for_token(&key, token),
+                fs::read(file_path).unwrap()
+            );
+        }
+        let pruned_size = {
+            let cm = key.open_content_manager(Mutability::Mutable).unwrap();
+            cm.prune_contents().unwrap().sum_storage
+        };
+        {
+            let cm = key.open_content_manager(Mutability::Mutable).unwrap();
+            assert_eq!(cm.problems().unwrap().total(), 0);
+            assert!(cm.storage.pack_index.borrow().get(&tokens[1]).is_none());
+            let stats = cm.repack().unwrap();
+            assert_eq!(stats.items_packed, 3);
+            assert_eq!(stats.pack_files_removed, 1);
+            assert_eq!(stats.bytes_freed as u128, pruned_size);
+            let (token, _, _) = cm
+                .store_contents(&mut File::open("./src/lib.rs").unwrap())
+                .unwrap();
+            assert!(cm.storage.token_content_file_path(&token).exists());
+            assert_eq!(cm.problems().unwrap().total(), 0);
+            // only the new loose contents need packing
+            let stats = cm.repack().unwrap();
+            assert_eq!(stats.items_packed, 1);
+            assert_eq!(stats.pack_files_removed, 0);
+            assert_eq!(cm.problems().unwrap().total(), 0);
+            assert_eq!(
+                pack_file_sizes(&cm.storage.packs_dir_path()).unwrap().len(),
+                2
+            );
+            tokens.push(token);
+        }
+        assert_eq!(
+            contents_for_token(&key, &tokens[4]),
+            fs::read("./src/lib.rs").unwrap()
+        );
+        assert_eq!(
+            contents_for_token(&key, &tokens[3]),
+            fs::read(FILE_PATHS[3]).unwrap()
+        );
+    }
+}
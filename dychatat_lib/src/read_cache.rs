@@ -0,0 +1,117 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au> <pwil3058@outlook.com>
+use std::collections::{BTreeMap, HashMap};
+use std::ops::AddAssign;
+use std::sync::Arc;
+
+/// Read cache performance for a content manager.
+#[derive(PartialEq, Clone, Copy, Default, Debug)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl AddAssign for CacheStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.hits += rhs.hits;
+        self.misses += rhs.misses;
+    }
+}
+
+// Content token
+type CacheKey = String;
+
+// A size bounded least recently used cache of decompressed contents.
+#[derive(Debug, Default)]
+pub(crate) struct ReadCache {
+    capacity: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<CacheKey, (Arc<Vec<u8>>, u64)>,
+    recency: BTreeMap<u64, CacheKey>,
+}
+
+impl ReadCache {
+    /// A cache holding at most `capacity` bytes of decompressed content.
+    /// Zero disables caching.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn evict_until_fits(&mut self, size: usize) {
+        while self.used + size > self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            let key = self.recency.remove(&oldest).expect("key is present");
+            if let Some((data, _)) = self.entries.remove(&key) {
+                self.used -= data.len();
+            }
+        }
+    }
+
+    pub fn get(&mut self, key: &CacheKey) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (data, last_used) = self.entries.get_mut(key)?;
+        let key = self.recency.remove(last_used).expect("key is present");
+        self.recency.insert(tick, key);
+        *last_used = tick;
+        Some(Arc::clone(data))
+    }
+
+    pub fn insert(&mut self, key: CacheKey, data: Arc<Vec<u8>>) {
+        if data.len() > self.capacity || self.entries.contains_key(&key) {
+            return;
+        }
+        self.evict_until_fits(data.len());
+        self.tick += 1;
+        self.used += data.len();
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (data, self.tick));
+    }
+}
+
+#[cfg(test)]
+mod read_cache_tests {
+    use super::*;
+
+    fn key(token: &str) -> CacheKey {
+        token.to_string()
+    }
+
+    #[test]
+    fn least_recently_used_are_evicted() {
+        let mut cache = ReadCache::new(10);
+        cache.insert(key("a"), Arc::new(vec![0; 4]));
+        cache.insert(key("b"), Arc::new(vec![0; 4]));
+        assert!(cache.get(&key("a")).is_some());
+        cache.insert(key("c"), Arc::new(vec![0; 4]));
+        assert!(cache.get(&key("b")).is_none());
+        assert!(cache.get(&key("a")).is_some());
+        assert!(cache.get(&key("c")).is_some());
+        assert_eq!(cache.used, 8);
+        cache.insert(key("too_big"), Arc::new(vec![0; 11]));
+        assert!(cache.get(&key("too_big")).is_none());
+        cache.insert(key("d"), Arc::new(vec![0; 6]));
+        assert!(cache.get(&key("a")).is_none());
+        assert!(cache.get(&key("c")).is_some());
+        assert!(cache.get(&key("d")).is_some());
+        assert_eq!(cache.used, 10);
+    }
+
+    #[test]
+    fn zero_capacity_caches_nothing() {
+        let mut cache = ReadCache::new(0);
+        cache.insert(key("a"), Arc::new(vec![0; 1]));
+        assert!(cache.get(&key("a")).is_none());
+        assert!(cache.entries.is_empty() && cache.recency.is_empty());
+    }
+}
@@ -0,0 +1,524 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au> <pwil3058@outlook.com>
+
+//! Export of a repository's (referenced) contents, with their reference
+//! counts, and their import into another repository.  This allows a
+//! repository to be migrated to a new location or storage format (e.g.
+//! from loose to packed contents) and, with re-keying, to a different hash
+//! algorithm.  An export is a directory (or a tar file holding the same
+//! entries) containing a "manifest.json" that lists the tokens with their
+//! reference counts and sizes and the uncompressed contents in
+//! "contents/<token[0..3]>/<token[3..]>".  In tar files the manifest is the
+//! first entry.
+//!
+//! Importing adds the exported reference counts to any the repository
+//! already has for the same tokens.  Contents exported from a repository
+//! using a different hash algorithm are stored under new tokens and the
+//! map from the old tokens to the new ones is returned so that references
+//! to them (e.g. in snapshots) can be re-keyed.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ContentManager, HashAlgorithm, RefCountData, RepoError, RepoResult};
+
+/// The version of the export format written by `ContentManager::export_to()`.
+pub const EXPORT_FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+const CONTENTS_DIR_NAME: &str = "contents";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportManifest {
+    format_version: u32,
+    hash_algorithm: HashAlgorithm,
+    contents: Vec<ExportedContent>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportedContent {
+    token: String,
+    ref_count: u64,
+    content_size: u64,
+}
+
+fn content_entry_path(token: &str) -> PathBuf {
+    Path::new(CONTENTS_DIR_NAME)
+        .join(&token[0..3])
+        .join(&token[3..])
+}
+
+fn is_tar_path(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "tar")
+}
+
+/// What exporting a repository's contents achieved.
+#[derive(PartialEq, Clone, Copy, Default, Debug)]
+pub struct ExportStats {
+    pub items_exported: u64,
+    /// The (uncompressed) size of the exported contents
+    pub bytes_exported: u128,
+    pub references_exported: u128,
+}
+
+/// What importing exported contents into a repository achieved.
+#[derive(PartialEq, Clone, Default, Debug)]
+pub struct ImportStats {
+    /// Contents that the repository didn't already have
+    pub items_added: u64,
+    /// Contents already in the repository whose reference counts were increased
+    pub items_merged: u64,
+    pub bytes_stored: u128,
+    pub references_imported: u128,
+    /// The new tokens of re-keyed contents (by their exported tokens)
+    pub token_map: BTreeMap<String, String>,
+}
+
+impl ContentManager {
+    /// Export the referenced contents and their reference counts to a new
+    /// directory or (if `path` has a "tar" extension) tar file.
+    pub fn export_to<P: AsRef<Path>>(&self, path: P) -> RepoResult<ExportStats> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(RepoError::ExportExists(path.to_path_buf()));
+        }
+        let mut ref_counts: Vec<(String, RefCountData)> = self
+            .ref_counter
+            .ref_counts()
+            .into_iter()
+            .filter(|(_, rcd)| rcd.is_referenced())
+            .collect();
+        ref_counts.sort_by(|a, b| a.0.cmp(&b.0));
+        let manifest = ExportManifest {
+            format_version: EXPORT_FORMAT_VERSION,
+            hash_algorithm: self.content_mgmt_key.hash_algortithm,
+            contents: ref_counts
+                .iter()
+                .map(|(token, rcd)| ExportedContent {
+                    token: token.clone(),
+                    ref_count: rcd.ref_count,
+                    content_size: rcd.content_size,
+                })
+                .collect(),
+        };
+        let result = if is_tar_path(path) {
+            self.export_to_tar(path, &manifest)
+        } else {
+            self.export_to_dir(path, &manifest)
+        };
+        if result.is_err() {
+            // don't leave a partial export lying around
+            if path.is_dir() {
+                fs::remove_dir_all(path).ok();
+            } else {
+                fs::remove_file(path).ok();
+            }
+        }
+        result
+    }
+
+    fn export_to_dir(&self, dir_path: &Path, manifest: &ExportManifest) -> RepoResult<ExportStats> {
+        let mut stats = ExportStats::default();
+        fs::create_dir_all(dir_path)?;
+        for exported in manifest.contents.iter() {
+            let file_path = dir_path.join(content_entry_path(&exported.token));
+            if let Some(content_dir_path) = file_path.parent() {
+                fs::create_dir_all(content_dir_path)?;
+            }
+            let mut file = io::BufWriter::new(File::create(&file_path)?);
+            let size = self.storage.write(&exported.token, &mut file)?;
+            file.flush()?;
+            if size != exported.content_size {
+                return Err(RepoError::ContentsDiffer(
+                    exported.token.clone(),
+                    file_path.to_string_lossy().to_string(),
+                ));
+            }
+            stats.items_exported += 1;
+            stats.bytes_exported += size as u128;
+            stats.references_exported += exported.ref_count as u128;
+        }
+        // NB: the manifest is written last so that a complete export has one
+        let manifest_file = File::create(dir_path.join(MANIFEST_FILE_NAME))?;
+        serde_json::to_writer_pretty(manifest_file, manifest)?;
+        Ok(stats)
+    }
+
+    fn export_to_tar(&self, tar_path: &Path, manifest: &ExportManifest) -> RepoResult<ExportStats> {
+        let mut stats = ExportStats::default();
+        let mtime = UNIX_EPOCH
+            .elapsed()
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let new_header = |size: u64| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(size);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            header
+        };
+        let mut builder = tar::Builder::new(io::BufWriter::new(File::create(tar_path)?));
+        let manifest_json = serde_json::to_vec_pretty(manifest)?;
+        let mut header = new_header(manifest_json.len() as u64);
+        builder.append_data(&mut header, MANIFEST_FILE_NAME, manifest_json.as_slice())?;
+        for exported in manifest.contents.iter() {
+            let compressed_reader = self.storage.compressed_reader(&exported.token)?;
+            let mut reader =
+                snap::read::FrameDecoder::new(compressed_reader).take(exported.content_size);
+            let mut header = new_header(exported.content_size);
+            builder.append_data(
+                &mut header,
+                content_entry_path(&exported.token),
+                &mut reader,
+            )?;
+            // NB: the entry's size (in its header) can't be changed now
+            if reader.limit() > 0 {
+                return Err(RepoError::ContentsDiffer(
+                    exported.token.clone(),
+                    tar_path.to_string_lossy().to_string(),
+                ));
+            }
+            stats.items_exported += 1;
+            stats.bytes_exported += exported.content_size as u128;
+            stats.references_exported += exported.ref_count as u128;
+        }
+        builder.into_inner()?.flush()?;
+        Ok(stats)
+    }
+
+    /// Import the contents (and reference counts) exported (by `export_to()`)
+    /// to the directory or tar file at `path`.  Unless `rekey` is true the
+    /// export must have been made from a repository using this repository's
+    /// hash algorithm.  If the import fails the reference counts are left
+    /// as they were (and any contents added are left for pruning).
+    pub fn import_from<P: AsRef<Path>>(&self, path: P, rekey: bool) -> RepoResult<ImportStats> {
+        if !self.is_mutable() {
+            panic!("{:?}: line {:?}: immutability breach", file!(), line!());
+        }
+        let path = path.as_ref();
+        let mut stats = ImportStats::default();
+        let mut imported = vec![];
+        let result = if path.is_dir() {
+            self.import_from_dir(path, rekey, &mut stats, &mut imported)
+        } else {
+            self.import_from_tar(path, rekey, &mut stats, &mut imported)
+        };
+        if let Err(err) = result {
+            for (token, ref_count) in imported {
+                let mut rcd = self.ref_counter.ref_count_data_for_token(&token)?;
+                rcd.ref_count -= ref_count;
+                self.ref_counter.insert(&token, rcd);
+            }
+            return Err(err);
+        }
+        Ok(stats)
+    }
+
+    fn check_manifest(
+        &self,
+        path: &Path,
+        manifest: &ExportManifest,
+        rekey: bool,
+    ) -> RepoResult<()> {
+        if manifest.format_version > EXPORT_FORMAT_VERSION {
+            let reason = format!("unknown format version {}", manifest.format_version);
+            return Err(RepoError::BadExport(path.to_path_buf(), reason));
+        }
+        let hash_algorithm = self.content_mgmt_key.hash_algortithm;
+        if manifest.hash_algorithm != hash_algorithm && !rekey {
+            return Err(RepoError::RekeyRequired(
+                manifest.hash_algorithm.to_string(),
+                hash_algorithm.to_string(),
+            ));
+        }
+        // tokens become file paths so they mustn't be allowed to escape the export
+        if let Some(exported) = manifest
+            .contents
+            .iter()
+            .find(|exported| !manifest.hash_algorithm.is_valid_token(&exported.token))
+        {
+            let reason = format!("bad token {:?}", exported.token);
+            return Err(RepoError::BadExport(path.to_path_buf(), reason));
+        }
+        Ok(())
+    }
+
+    fn import_from_dir(
+        &self,
+        dir_path: &Path,
+        rekey: bool,
+        stats: &mut ImportStats,
+        imported: &mut Vec<(String, u64)>,
+    ) -> RepoResult<()> {
+        let manifest_file = match File::open(dir_path.join(MANIFEST_FILE_NAME)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let reason = format!("no {:?}", MANIFEST_FILE_NAME);
+                return Err(RepoError::BadExport(dir_path.to_path_buf(), reason));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let manifest: ExportManifest = serde_json::from_reader(io::BufReader::new(manifest_file))?;
+        self.check_manifest(dir_path, &manifest, rekey)?;
+        for exported in manifest.contents.iter() {
+            let mut file = File::open(dir_path.join(content_entry_path(&exported.token)))?;
+            let digest =
+                self.import_content(&mut file, exported, manifest.hash_algorithm, stats)?;
+            imported.push((digest, exported.ref_count));
+        }
+        Ok(())
+    }
+
+    fn import_from_tar(
+        &self,
+        tar_path: &Path,
+        rekey: bool,
+        stats: &mut ImportStats,
+        imported: &mut Vec<(String, u64)>,
+    ) -> RepoResult<()> {
+        let mut archive = tar::Archive::new(io::BufReader::new(File::open(tar_path)?));
+        let mut entries = archive.entries()?;
+        let bad_export =
+            |reason: &str| RepoError::BadExport(tar_path.to_path_buf(), reason.to_string());
+        let manifest: ExportManifest = match entries.next() {
+            Some(entry) => {
+                let entry = entry?;
+                if entry.path()? != Path::new(MANIFEST_FILE_NAME) {
+                    return Err(bad_export("the manifest isn't the first entry"));
+                }
+                serde_json::from_reader(entry)?
+            }
+            None => return Err(bad_export("empty")),
+        };
+        self.check_manifest(tar_path, &manifest, rekey)?;
+        let mut exported_contents: HashMap<PathBuf, &ExportedContent> = manifest
+            .contents
+            .iter()
+            .map(|exported| (content_entry_path(&exported.token), exported))
+            .collect();
+        // NB: the contents need to be seekable to be stored
+        let temp_dir = tempdir::TempDir::new_in(&self.content_mgmt_key.base_dir_path, "import")?;
+        let temp_file_path = temp_dir.path().join("contents");
+        for entry in entries {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let entry_path = entry.path()?.to_path_buf();
+            let exported = match exported_contents.remove(&entry_path) {
+                Some(exported) => exported,
+                None => {
+                    let reason = format!("{:?}: unexpected entry", entry_path);
+                    return Err(bad_export(&reason));
+                }
+            };
+            let mut temp_file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&temp_file_path)?;
+            io::copy(&mut entry, &mut temp_file)?;
+            temp_file.seek(SeekFrom::Start(0))?;
+            let digest =
+                self.import_content(&mut temp_file, exported, manifest.hash_algorithm, stats)?;
+            imported.push((digest, exported.ref_count));
+        }
+        if let Some(exported) = exported_contents.values().next() {
+            let reason = format!("{}: contents missing", exported.token);
+            return Err(bad_export(&reason));
+        }
+        Ok(())
+    }
+
+    // Returns the token the contents are stored under
+    fn import_content<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        exported: &ExportedContent,
+        exported_hash_algorithm: HashAlgorithm,
+        stats: &mut ImportStats,
+    ) -> RepoResult<String> {
+        let hash_algorithm = self.content_mgmt_key.hash_algortithm;
+        let (digest, size) = hash_algorithm.reader_digest_and_size(reader)?;
+        let is_rekeyed = exported_hash_algorithm != hash_algorithm;
+        if size != exported.content_size || (!is_rekeyed && digest != exported.token) {
+            return Err(RepoError::CorruptExport(exported.token.clone()));
+        }
+        match self.ref_counter.ref_count_data_for_token(&digest) {
+            Ok(mut rcd) => {
+                rcd.ref_count += exported.ref_count;
+                self.ref_counter.insert(&digest, rcd);
+                stats.items_merged += 1;
+            }
+            Err(_) => {
+                let stored_size =
+                    self.store_new_contents(&digest, size, exported.ref_count, reader)?;
+                stats.items_added += 1;
+                stats.bytes_stored += stored_size as u128;
+            }
+        }
+        stats.references_imported += exported.ref_count as u128;
+        if is_rekeyed {
+            stats
+                .token_map
+                .insert(exported.token.clone(), digest.clone());
+        }
+        Ok(digest)
+    }
+}
+
+#[cfg(test)]
+mod transfer_tests {
+    use super::*;
+    use crate::config::TestConfigGuard;
+    use crate::content::{create_new_repo, get_content_mgmt_key, set_repo_pack_threshold};
+    use crate::Mutability;
+
+    fn store_files(repo_name: &str) -> Vec<String> {
+        let cm = get_content_mgmt_key(repo_name)
+            .unwrap()
+            .open_content_manager(Mutability::Mutable)
+            .unwrap();
+        let mut tokens = vec![];
+        for file_name in ["./src/content.rs", "./src/error.rs", "./src/error.rs"] {
+            let mut file = File::open(file_name).unwrap();
+            tokens.push(cm.store_contents(&mut file).unwrap().0);
+        }
+        // unreferenced contents aren't exported
+        let mut file = File::open("./src/pack.rs").unwrap();
+        let (token, _, _) = cm.store_contents(&mut file).unwrap();
+        cm.release_contents(&token).unwrap();
+        tokens
+    }
+
+    #[test]
+    fn exports_can_be_imported() {
+        let guard = TestConfigGuard::new();
+        let data_dir = guard.path().join("data");
+        create_new_repo("original", &data_dir, "Sha1").unwrap();
+        let tokens = store_files("original");
+        let original = get_content_mgmt_key("original")
+            .unwrap()
+            .open_content_manager(Mutability::Immutable)
+            .unwrap();
+        for export_name in ["export", "export.tar"] {
+            let export_path = guard.path().join(export_name);
+            let stats = original.export_to(&export_path).unwrap();
+            assert_eq!(stats.items_exported, 2);
+            assert_eq!(stats.references_exported, 3);
+            assert!(matches!(
+                original.export_to(&export_path),
+                Err(RepoError::ExportExists(_))
+            ));
+
+            // into a repository that packs its contents
+            let repo_name = format!("packed_{}", export_name);
+            create_new_repo(&repo_name, &data_dir, "Sha1").unwrap();
+            set_repo_pack_threshold(&repo_name, Some(1 << 16)).unwrap();
+            let key = get_content_mgmt_key(&repo_name).unwrap();
+            {
+                let cm = key.open_content_manager(Mutability::Mutable).unwrap();
+                let stats = cm.import_from(&export_path, false).unwrap();
+                assert_eq!((stats.items_added, stats.items_merged), (2, 0));
+                assert_eq!(stats.references_imported, 3);
+                assert!(stats.token_map.is_empty());
+            }
+            let cm = key.open_content_manager(Mutability::Mutable).unwrap();
+            for token in tokens.iter() {
+                assert_eq!(
+                    cm.ref_count_for_token(token).unwrap(),
+                    original.ref_count_for_token(token).unwrap()
+                );
+            }
+            assert!(!key.base_dir_path().join(&tokens[0][0..3]).exists());
+            let mut contents = vec![];
+            cm.write_contents_for_token(&tokens[1], &mut contents)
+                .unwrap();
+            assert_eq!(contents, fs::read("./src/error.rs").unwrap());
+
+            // importing again merges the reference counts
+            let stats = cm.import_from(&export_path, false).unwrap();
+            assert_eq!((stats.items_added, stats.items_merged), (0, 2));
+            assert_eq!(cm.ref_count_for_token(&tokens[1]).unwrap(), 4);
+        }
+
+        create_new_repo("sha256", &data_dir, "Sha256").unwrap();
+        let cm = get_content_mgmt_key("sha256")
+            .unwrap()
+            .open_content_manager(Mutability::Mutable)
+            .unwrap();
+        let export_path = guard.path().join("export");
+        assert!(matches!(
+            cm.import_from(&export_path, false),
+            Err(RepoError::RekeyRequired(_, _))
+        ));
+        let stats = cm.import_from(&export_path, true).unwrap();
+        assert_eq!(stats.token_map.len(), 2);
+        let new_token = &stats.token_map[&tokens[1]];
+        assert_eq!(new_token.len(), 64);
+        assert_eq!(cm.ref_count_for_token(new_token).unwrap(), 2);
+
+        // corrupted contents are detected
+        let content_path = export_path.join(content_entry_path(&tokens[0]));
+        let mut corrupted = fs::read(&content_path).unwrap();
+        corrupted[0] ^= 1;
+        fs::write(&content_path, corrupted).unwrap();
+        create_new_repo("copy", &data_dir, "Sha1").unwrap();
+        let cm = get_content_mgmt_key("copy")
+            .unwrap()
+            .open_content_manager(Mutability::Mutable)
+            .unwrap();
+        match cm.import_from(&export_path, false) {
+            Err(RepoError::CorruptExport(token)) => assert_eq!(token, tokens[0]),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(cm.referenced_content_data().num_references, 0);
+        assert!(matches!(
+            cm.import_from(guard.path(), false),
+            Err(RepoError::BadExport(_, _))
+        ));
+    }
+
+    #[test]
+    fn manifest_tokens_must_be_digests() {
+        let guard = TestConfigGuard::new();
+        create_new_repo("test_repo", guard.path().join("data"), "Sha1").unwrap();
+        let cm = get_content_mgmt_key("test_repo")
+            .unwrap()
+            .open_content_manager(Mutability::Mutable)
+            .unwrap();
+        let digest = HashAlgorithm::Sha1.data_digest(b"contents").unwrap();
+        for token in [
+            "../../../escaped".to_string(),
+            "ab".to_string(),
+            digest.to_lowercase(),
+            digest[..39].to_string(),
+        ] {
+            let export_path = guard.path().join("export");
+            fs::create_dir_all(&export_path).unwrap();
+            let manifest = ExportManifest {
+                format_version: EXPORT_FORMAT_VERSION,
+                hash_algorithm: HashAlgorithm::Sha1,
+                contents: vec![ExportedContent {
+                    token,
+                    ref_count: 1,
+                    content_size: 8,
+                }],
+            };
+            let file = File::create(export_path.join(MANIFEST_FILE_NAME)).unwrap();
+            serde_json::to_writer(file, &manifest).unwrap();
+            assert!(matches!(
+                cm.import_from(&export_path, false),
+                Err(RepoError::BadExport(_, _))
+            ));
+            fs::remove_dir_all(&export_path).unwrap();
+        }
+        assert!(HashAlgorithm::Sha1.is_valid_token(&digest));
+        assert_eq!(cm.referenced_content_data().num_references, 0);
+    }
+}
@@ -1,9 +1,14 @@
 // Copyright 2021 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
 use std::path::PathBuf;
+use std::time::Duration;
 
 use structopt::StructOpt;
 
-use ergibus_lib::{archive, EResult};
+use ergibus_lib::{
+    archive,
+    archive::{BudgetAction, OverflowSpec, PreviewStatus},
+    encryption, global_config, signing, snapshot, EResult,
+};
 
 #[derive(Debug, StructOpt)]
 /// Manage snapshot archives
@@ -13,13 +18,17 @@ pub enum ManageArchives {
         /// the name of the new snapshot archive to be created.
         #[structopt(short, long = "archive")]
         archive_name: String,
-        /// the name of the repository that the new archive should use to store file contents.
+        /// the name of the repository that the new archive should use to store file contents
+        /// (defaults to the default repository or one named after the host).
         #[structopt(short = "r", long = "repo")]
-        content_repo_name: String,
+        content_repo_name: Option<String>,
         /// the directory path of the location where the archive should store its snapshots.
         #[structopt(short, long = "location", parse(from_os_str))]
         location: PathBuf,
         /// the path of a file/directory that should be included in the archive's snapshots.
+        ///
+        /// May be a glob pattern (e.g. '~/projects/*/src', quoted to stop the shell
+        /// expanding it) which is expanded each time the archive is backed up.
         #[structopt(short, long = "include", parse(from_os_str))]
         inclusions: Vec<PathBuf>,
         /// exclude directories matching this glob expression from patches.
@@ -28,6 +37,17 @@ pub enum ManageArchives {
         /// exclude files matching this glob expression from patches.
         #[structopt(short, long = "exclude_files", required = false)]
         file_exclusions: Vec<String>,
+        /// also exclude the patterns in this exclusion profile (from the global configuration).
+        #[structopt(long = "profile", value_name = "P")]
+        profiles: Vec<String>,
+        /// also exclude commonly volatile paths (caches, trash, thumbnails, browsers' caches)
+        /// i.e. use the built in "standard" exclusion profile.
+        #[structopt(long = "standard-exclusions")]
+        standard_exclusions: bool,
+        /// don't create a repository named after the host if no repository is nominated
+        /// and there's no default repository.
+        #[structopt(long = "no-auto-repo")]
+        no_auto_repo: bool,
     },
     /// List defined archives.
     List,
@@ -37,6 +57,180 @@ pub enum ManageArchives {
         /// The name of the archive to be deleted
         archive_name: String,
     },
+    /// Show which files and directories the archive's snapshots would include or exclude.
+    Preview {
+        /// the name of the archive whose inclusions are to be previewed.
+        archive_name: String,
+        /// don't show files and directories more than this many levels below an inclusion.
+        #[structopt(long = "max-depth", value_name = "D")]
+        max_depth: Option<usize>,
+    },
+    /// Set (or show) limits on the size of the archive's snapshots.
+    Budget {
+        /// the name of the archive whose budget is to be set.
+        archive_name: String,
+        /// the most (content) bytes a snapshot should contain (0 removes the limit).
+        #[structopt(long = "max-snapshot-bytes", value_name = "N")]
+        max_snapshot_bytes: Option<u64>,
+        /// the most a back up should add to the repository's storage (0 removes the limit).
+        #[structopt(long = "max-repo-growth", value_name = "N")]
+        max_repo_growth_per_run: Option<u64>,
+        /// what a back up should do when the budget is exceeded.
+        #[structopt(long = "on-exceeded", possible_values = &["warn", "abort"])]
+        on_exceeded: Option<BudgetAction>,
+    },
+    /// Set (or show) how often the archive is expected to be backed up.
+    ///
+    /// Archives that haven't been backed up within this interval are shown as
+    /// stale in the GUI's dashboard.
+    Schedule {
+        /// the name of the archive whose schedule is to be set.
+        archive_name: String,
+        /// the expected number of hours between back ups (0 restores the default of 24).
+        #[structopt(long = "every-hours", value_name = "N")]
+        every_hours: Option<u64>,
+    },
+    /// Set (or show) how many levels below each inclusion the archive's back ups read.
+    ///
+    /// Directories at the maximum depth are included without their contents
+    /// (e.g. to back up only the top levels of a huge shared mount) and are
+    /// counted in the back up's statistics.
+    MaxDepth {
+        /// the name of the archive whose maximum depth is to be set.
+        archive_name: String,
+        /// the number of levels to read (0 removes the limit).
+        #[structopt(long = "set", value_name = "N")]
+        max_depth: Option<usize>,
+    },
+    /// Set (or show) the repository used for the contents of the archive's large files.
+    ///
+    /// Files of at least the given size have their contents stored in the
+    /// overflow repository (e.g. on a NAS) rather than the archive's own
+    /// repository (e.g. on a local SSD).  Contents that are already stored
+    /// stay where they are.
+    Overflow {
+        /// the name of the archive whose overflow repository is to be set.
+        archive_name: String,
+        /// the name of the repository for large files' contents.
+        #[structopt(long = "repo")]
+        content_repo_name: Option<String>,
+        /// the size (in bytes) at which files' contents go to the overflow repository.
+        #[structopt(long = "min-size", value_name = "N", default_value = "1048576")]
+        min_file_size: u64,
+        /// store all contents in the archive's own repository again.
+        #[structopt(long = "clear", conflicts_with = "content-repo-name")]
+        clear: bool,
+    },
+    /// Set (or show) the command run after the archive's snapshots are deleted.
+    ///
+    /// The command is run (with "sh -c") after snapshots are deleted by
+    /// "ergibus ss delete" or "ergibus prune" with a JSON summary (the
+    /// snapshots deleted, the bytes freed and the number remaining) on its
+    /// standard input and in ERGIBUS_* environment variables so that
+    /// external monitoring or manifests can be kept in sync.
+    PruneHook {
+        /// the name of the archive whose prune hook is to be set.
+        archive_name: String,
+        /// the command to run.
+        #[structopt(long = "command", value_name = "cmd")]
+        command: Option<String>,
+        /// stop running a command when snapshots are deleted.
+        #[structopt(long = "clear", conflicts_with = "command")]
+        clear: bool,
+    },
+    /// Set (or show) whether the archive's exclusion globs ignore case.
+    ///
+    /// Useful for data from case insensitive file systems (e.g. restored
+    /// Windows data or FAT mounts) where "*.jpg" should also exclude "*.JPG".
+    CaseInsensitiveGlobs {
+        /// the name of the archive whose glob matching is to be set.
+        archive_name: String,
+        /// match the exclusion globs ignoring case.
+        #[structopt(long = "on")]
+        on: bool,
+        /// match the exclusion globs exactly.
+        #[structopt(long = "off", conflicts_with = "on")]
+        off: bool,
+    },
+    /// Set (or show) the exclusion profiles used by the archive.
+    ///
+    /// Exclusion profiles are named sets of exclusion patterns defined in the
+    /// "profiles" section of the global configuration file ("global.yaml" in
+    /// the configuration directory).  The built in "standard" profile excludes
+    /// commonly volatile paths (caches, trash, etc.).  Without an archive name
+    /// the defined profiles are listed.
+    Profiles {
+        /// the name of the archive whose profiles are to be set.
+        archive_name: Option<String>,
+        /// the name(s) of the profile(s) that the archive should use.
+        #[structopt(long = "set", value_name = "P", requires = "archive-name")]
+        profiles: Vec<String>,
+        /// stop the archive using any profiles.
+        #[structopt(long = "clear", conflicts_with = "profiles", requires = "archive-name")]
+        clear: bool,
+    },
+    /// List the archive groups and their member archives.
+    ///
+    /// Archive groups are defined in the "groups" section of the global
+    /// configuration file (e.g. "nightly: [home, etc, projects]") and can be
+    /// backed up together with "ergibus bu --group <name>".
+    Groups,
+    /// Generate a key pair with which the archive's new (and existing unsigned) snapshots
+    /// will be signed (or, with --encryption, a key with which new snapshots will be encrypted).
+    Keygen {
+        /// the name of the archive whose snapshots are to be signed.
+        archive_name: String,
+        /// generate a key to encrypt new snapshot files (which can't be read without it).
+        #[structopt(long = "encryption")]
+        encryption: bool,
+    },
+    /// Remove the archive's stale partial snapshot files and empty snapshot directories.
+    Gc {
+        /// the name of the archive to be tidied up.
+        archive_name: String,
+        /// only remove partial files that are at least this many hours old.
+        #[structopt(long = "min-age-hours", value_name = "N", default_value = "24")]
+        min_age_hours: u64,
+    },
+    /// Export archive (and their repositories') specifications to a file for disaster recovery.
+    ExportConfig {
+        /// export the specifications of all defined archives.
+        #[structopt(
+            long = "all",
+            conflicts_with = "archive-names",
+            required_unless = "archive-names"
+        )]
+        all: bool,
+        /// the name(s) of the archive(s) whose specifications are to be exported.
+        #[structopt(short, long = "archive")]
+        archive_names: Vec<String>,
+        /// the path of the file to which the specifications should be written.
+        #[structopt(long = "to", parse(from_os_str))]
+        to_file_path: PathBuf,
+    },
+    /// Import archive (and repository) specifications from a file created by export-config.
+    ImportConfig {
+        /// the path of the file from which the specifications should be read.
+        #[structopt(long = "from", parse(from_os_str))]
+        from_file_path: PathBuf,
+        /// overwrite existing archive specifications with the same names.
+        #[structopt(long = "overwrite")]
+        overwrite: bool,
+    },
+    /// Import a copy of one of the archive's directories made by another back up tool
+    /// (e.g. rsync) as a snapshot dated by the copy's newest modification time.
+    Import {
+        /// the name of the archive into which the copy is to be imported.
+        #[structopt(short, long = "archive")]
+        archive_name: String,
+        /// the path of the directory containing the copy.
+        #[structopt(long = "from-dir", parse(from_os_str))]
+        from_dir_path: PathBuf,
+        /// the path of the directory that the copy was made from (required if the
+        /// archive has more than one inclusion).
+        #[structopt(long = "as", value_name = "path", parse(from_os_str))]
+        as_dir_path: Option<PathBuf>,
+    },
 }
 
 impl ManageArchives {
@@ -50,15 +244,31 @@ impl ManageArchives {
                 inclusions,
                 dir_exclusions,
                 file_exclusions,
+                profiles,
+                standard_exclusions,
+                no_auto_repo,
             } => {
-                archive::create_new_archive(
+                let mut profiles = profiles.clone();
+                if *standard_exclusions
+                    && !profiles
+                        .iter()
+                        .any(|p| p == global_config::STANDARD_EXCLUSIONS)
+                {
+                    profiles.push(global_config::STANDARD_EXCLUSIONS.to_string());
+                }
+                let repo_name = archive::create_new_archive(
                     archive_name,
-                    content_repo_name,
+                    content_repo_name.as_deref(),
                     location,
                     inclusions,
                     dir_exclusions,
                     file_exclusions,
+                    &profiles,
+                    !no_auto_repo,
                 )?;
+                if content_repo_name.is_none() {
+                    println!("{}: using repository \"{}\"", archive_name, repo_name);
+                }
                 Ok(())
             }
             List => {
@@ -68,6 +278,261 @@ impl ManageArchives {
                 Ok(())
             }
             Delete { archive_name } => archive::delete_archive(archive_name),
+            Preview {
+                archive_name,
+                max_depth,
+            } => {
+                for item in archive::preview_archive(archive_name, *max_depth)? {
+                    let indent = "  ".repeat(item.depth);
+                    let suffix = if item.is_dir { "/" } else { "" };
+                    match item.status {
+                        PreviewStatus::Included => {
+                            println!("+ {}{}{}", indent, item.path.display(), suffix)
+                        }
+                        PreviewStatus::Excluded(pattern) => println!(
+                            "- {}{}{} (excluded by \"{}\")",
+                            indent,
+                            item.path.display(),
+                            suffix,
+                            pattern
+                        ),
+                        PreviewStatus::Unsupported => println!(
+                            "- {}{}{} (unsupported file type)",
+                            indent,
+                            item.path.display(),
+                            suffix
+                        ),
+                    }
+                }
+                Ok(())
+            }
+            Budget {
+                archive_name,
+                max_snapshot_bytes,
+                max_repo_growth_per_run,
+                on_exceeded,
+            } => {
+                let mut budget = archive::get_snapshot_budget(archive_name)?;
+                if let Some(max_snapshot_bytes) = max_snapshot_bytes {
+                    budget.max_snapshot_bytes = Some(*max_snapshot_bytes).filter(|n| *n > 0);
+                }
+                if let Some(max_repo_growth_per_run) = max_repo_growth_per_run {
+                    budget.max_repo_growth_per_run =
+                        Some(*max_repo_growth_per_run).filter(|n| *n > 0);
+                }
+                if let Some(on_exceeded) = on_exceeded {
+                    budget.on_exceeded = *on_exceeded;
+                }
+                archive::set_snapshot_budget(archive_name, budget)?;
+                let show = |limit: Option<u64>| match limit {
+                    Some(limit) => format!("{} bytes", limit),
+                    None => "unlimited".to_string(),
+                };
+                println!("max snapshot size: {}", show(budget.max_snapshot_bytes));
+                println!(
+                    "max repository growth: {}",
+                    show(budget.max_repo_growth_per_run)
+                );
+                println!("on exceeded: {:?}", budget.on_exceeded);
+                Ok(())
+            }
+            Schedule {
+                archive_name,
+                every_hours,
+            } => {
+                if let Some(every_hours) = every_hours {
+                    archive::set_backup_interval_hours(
+                        archive_name,
+                        Some(*every_hours).filter(|n| *n > 0),
+                    )?;
+                }
+                match archive::get_backup_interval_hours(archive_name)? {
+                    Some(hours) => println!("{}: back up every {} hours", archive_name, hours),
+                    None => println!("{}: back up every 24 hours (default)", archive_name),
+                }
+                Ok(())
+            }
+            MaxDepth {
+                archive_name,
+                max_depth,
+            } => {
+                if let Some(max_depth) = max_depth {
+                    archive::set_max_depth(archive_name, Some(*max_depth).filter(|n| *n > 0))?;
+                }
+                match archive::get_max_depth(archive_name)? {
+                    Some(max_depth) => println!("{}: maximum depth {}", archive_name, max_depth),
+                    None => println!("{}: no maximum depth", archive_name),
+                }
+                Ok(())
+            }
+            Overflow {
+                archive_name,
+                content_repo_name,
+                min_file_size,
+                clear,
+            } => {
+                if *clear {
+                    archive::set_archive_overflow(archive_name, None)?;
+                } else if let Some(content_repo_name) = content_repo_name {
+                    archive::set_archive_overflow(
+                        archive_name,
+                        Some(OverflowSpec {
+                            content_repo_name: content_repo_name.to_string(),
+                            min_file_size: *min_file_size,
+                        }),
+                    )?;
+                }
+                match archive::get_archive_overflow(archive_name)? {
+                    Some(overflow) => println!(
+                        "{}: files of {} bytes or more go to \"{}\"",
+                        archive_name, overflow.min_file_size, overflow.content_repo_name
+                    ),
+                    None => println!("{}: no overflow repository", archive_name),
+                }
+                Ok(())
+            }
+            PruneHook {
+                archive_name,
+                command,
+                clear,
+            } => {
+                if *clear {
+                    archive::set_prune_hook(archive_name, None)?;
+                } else if let Some(command) = command {
+                    archive::set_prune_hook(archive_name, Some(command))?;
+                }
+                match archive::get_prune_hook(archive_name)? {
+                    Some(command) => println!("{}: prune hook: {}", archive_name, command),
+                    None => println!("{}: no prune hook", archive_name),
+                }
+                Ok(())
+            }
+            CaseInsensitiveGlobs {
+                archive_name,
+                on,
+                off,
+            } => {
+                if *on || *off {
+                    archive::set_case_insensitive_globs(archive_name, *on)?;
+                }
+                if archive::get_case_insensitive_globs(archive_name)? {
+                    println!("{}: exclusion globs ignore case", archive_name);
+                } else {
+                    println!("{}: exclusion globs are case sensitive", archive_name);
+                }
+                Ok(())
+            }
+            Profiles {
+                archive_name: None, ..
+            } => {
+                for (profile_name, profile) in global_config::get_exclusion_profiles()? {
+                    println!("{}:", profile_name);
+                    for pattern in profile.dir_exclusions.iter() {
+                        println!("  exclude dirs: {}", pattern);
+                    }
+                    for pattern in profile.file_exclusions.iter() {
+                        println!("  exclude files: {}", pattern);
+                    }
+                }
+                Ok(())
+            }
+            Profiles {
+                archive_name: Some(archive_name),
+                profiles,
+                clear,
+            } => {
+                if *clear || !profiles.is_empty() {
+                    archive::set_archive_profiles(archive_name, profiles)?;
+                }
+                for profile_name in archive::get_archive_profiles(archive_name)? {
+                    println!("{}", profile_name);
+                }
+                Ok(())
+            }
+            Groups => {
+                for (group_name, archive_names) in global_config::get_archive_groups()? {
+                    println!("{}: {}", group_name, archive_names.join(", "));
+                }
+                Ok(())
+            }
+            Keygen {
+                archive_name,
+                encryption,
+            } => {
+                if *encryption {
+                    let key_file_path = encryption::generate_encryption_key(archive_name)?;
+                    println!(
+                        "encryption key: {:?} (keep a copy somewhere safe)",
+                        key_file_path
+                    );
+                } else {
+                    let public_key = signing::generate_signing_key(archive_name)?;
+                    println!("public key: {}", public_key);
+                }
+                Ok(())
+            }
+            Gc {
+                archive_name,
+                min_age_hours,
+            } => {
+                let stats = archive::collect_garbage(
+                    archive_name,
+                    Duration::from_secs(min_age_hours * 60 * 60),
+                )?;
+                for file_path in stats.removed_files.iter() {
+                    println!("removed file: {:?}", file_path);
+                }
+                for dir_path in stats.removed_dirs.iter() {
+                    println!("removed directory: {:?}", dir_path);
+                }
+                Ok(())
+            }
+            ExportConfig {
+                all,
+                archive_names,
+                to_file_path,
+            } => {
+                if *all {
+                    archive::export_config(&archive::get_archive_names(), to_file_path)
+                } else {
+                    archive::export_config(archive_names, to_file_path)
+                }
+            }
+            ImportConfig {
+                from_file_path,
+                overwrite,
+            } => {
+                let (archive_names, repo_names) =
+                    archive::import_config(from_file_path, *overwrite)?;
+                for repo_name in repo_names.iter() {
+                    println!("repository: {}", repo_name);
+                }
+                for archive_name in archive_names.iter() {
+                    println!("archive: {}", archive_name);
+                }
+                Ok(())
+            }
+            Import {
+                archive_name,
+                from_dir_path,
+                as_dir_path,
+            } => {
+                let (ss_file_path, file_stats, sym_link_stats, delta_repo_size) =
+                    snapshot::import_snapshot_from_dir(
+                        archive_name,
+                        from_dir_path,
+                        as_dir_path.as_deref(),
+                    )?;
+                println!(
+                    "{}: imported {} files ({} bytes) and {} sym links: repository grew by {} bytes",
+                    ss_file_path.display(),
+                    file_stats.file_count,
+                    file_stats.byte_count,
+                    sym_link_stats.dir_sym_link_count + sym_link_stats.file_sym_link_count,
+                    delta_repo_size
+                );
+                Ok(())
+            }
         }
     }
 }
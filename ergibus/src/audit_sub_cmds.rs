@@ -0,0 +1,50 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+use structopt::StructOpt;
+
+use ergibus_lib::{audit, EResult};
+
+#[derive(Debug, StructOpt)]
+/// Review the log of destructive operations
+pub enum Audit {
+    /// Show the audit log (oldest first).
+    Show {
+        /// only show the most recent N records.
+        #[structopt(short, long = "last", value_name = "N")]
+        last: Option<usize>,
+        /// write the records as JSON lines.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+}
+
+impl Audit {
+    pub fn exec(&self) -> EResult<()> {
+        match self {
+            Audit::Show { last, json } => {
+                let (records, damaged) = audit::read_audit_log()?;
+                let skip = match last {
+                    Some(last) => records.len().saturating_sub(*last),
+                    None => 0,
+                };
+                for record in records.iter().skip(skip) {
+                    if *json {
+                        println!("{}", serde_json::to_string(record).expect("serializable"));
+                    } else {
+                        println!(
+                            "{} {} {:?}: {} ({} bytes freed)",
+                            record.time,
+                            record.user,
+                            record.operation,
+                            record.target,
+                            record.bytes_freed
+                        );
+                    }
+                }
+                if damaged > 0 {
+                    log::warn!("{} damaged audit log records skipped", damaged);
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local};
+use structopt::StructOpt;
+
+use ergibus_lib::{move_aside, EResult};
+
+#[derive(Debug, StructOpt)]
+/// Find (and optionally delete) the files and directories that extractions
+/// moved aside instead of overwriting.
+pub struct CleanAsides {
+    /// only those moved aside more than this many days ago.
+    #[structopt(long = "older-than", value_name = "days")]
+    older_than: Option<u64>,
+    /// delete them instead of just listing them.
+    #[structopt(long)]
+    delete: bool,
+    /// the directory to be searched.
+    #[structopt(parse(from_os_str))]
+    dir_path: PathBuf,
+}
+
+impl CleanAsides {
+    pub fn exec(&self) -> EResult<()> {
+        let cutoff = self
+            .older_than
+            .map(|days| SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60));
+        for moved_aside in move_aside::find_moved_aside(&self.dir_path)? {
+            if let Some(cutoff) = cutoff {
+                if moved_aside.moved_at > cutoff {
+                    continue;
+                }
+            }
+            let moved_at = DateTime::<Local>::from(moved_aside.moved_at);
+            if self.delete {
+                match moved_aside.delete() {
+                    Ok(()) => println!("deleted {:?}", moved_aside.path),
+                    Err(err) => log::error!("{:?}: {:?}", moved_aside.path, err),
+                }
+            } else {
+                println!(
+                    "{} {:?}",
+                    moved_at.format("%Y-%m-%d %H:%M:%S"),
+                    moved_aside.path
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Local;
+use log::*;
+use structopt::StructOpt;
+
+use ergibus_lib::reporters::{self, BackUpReport};
+use ergibus_lib::snapshot::BackUpOutcome;
+use ergibus_lib::{archive, global_config, monitoring, snapshot, EResult};
+
+#[derive(Debug, StructOpt)]
+/// Back up archives whenever their back up interval has passed and serve
+/// their status (at "/metrics" in Prometheus format and at "/status" as
+/// JSON) over HTTP for the benefit of monitoring systems.
+pub struct Daemon {
+    /// the address (and port) at which the status is served.
+    #[structopt(
+        long = "listen",
+        value_name = "address",
+        default_value = "127.0.0.1:9977"
+    )]
+    listen: SocketAddr,
+    /// how often (in minutes) to check for archives that are due for back up.
+    #[structopt(long = "check-every", value_name = "minutes", default_value = "10")]
+    check_every: u64,
+    /// only back up the named archives (the status of all archives is served).
+    #[structopt(short, long = "archive", value_name = "name")]
+    archives: Vec<String>,
+    /// only back up the named archives and the member archives of this group.
+    #[structopt(long = "group", value_name = "name", number_of_values = 1)]
+    groups: Vec<String>,
+    /// serve the status without doing any back ups.
+    #[structopt(long = "no-back-ups")]
+    no_back_ups: bool,
+}
+
+// Limits on reading a status request's request line
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LINE_LEN: u64 = 8192;
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()) {
+        warn!("status request: {:?}", err);
+    }
+}
+
+fn serve_request(mut stream: TcpStream) {
+    // a client that's slow (or that never ends its request line) mustn't
+    // hold up the requests queued behind it
+    if let Err(err) = stream.set_read_timeout(Some(REQUEST_READ_TIMEOUT)) {
+        warn!("status request: {:?}", err);
+        return;
+    }
+    let mut request_line = String::new();
+    if let Err(err) =
+        BufReader::new((&stream).take(MAX_REQUEST_LINE_LEN)).read_line(&mut request_line)
+    {
+        warn!("status request: {:?}", err);
+        return;
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+    if method != Some("GET") {
+        respond(&mut stream, "405 Method Not Allowed", "text/plain", "");
+        return;
+    }
+    match path {
+        Some("/metrics") => {
+            let metrics = monitoring::prometheus_metrics(&monitoring::get_archive_statuses());
+            respond(&mut stream, "200 OK", "text/plain; version=0.0.4", &metrics);
+        }
+        Some("/status") => {
+            let json = monitoring::status_json(&monitoring::get_archive_statuses(), Local::now());
+            respond(&mut stream, "200 OK", "application/json", &json);
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "not found\n"),
+    }
+}
+
+fn serve_status(listener: TcpListener) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => serve_request(stream),
+            Err(err) => warn!("status connection: {:?}", err),
+        }
+    }
+}
+
+impl Daemon {
+    fn back_up_due_archives(&self) {
+        let archive_names = if self.archives.is_empty() && self.groups.is_empty() {
+            archive::get_archive_names()
+        } else {
+            // groups are expanded each time so that changes are noticed
+            match global_config::expand_archive_groups(&self.archives, &self.groups) {
+                Ok(archive_names) => archive_names,
+                Err(err) => {
+                    error!("{}", err);
+                    return;
+                }
+            }
+        };
+        for archive_name in archive_names.iter() {
+            match monitoring::back_up_due(archive_name) {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(err) => {
+                    error!("{}: {}", archive_name, err);
+                    monitoring::note_back_up_failure(archive_name, &err);
+                    continue;
+                }
+            }
+            let result = snapshot::generate_snapshot(archive_name).map(BackUpOutcome::Written);
+            reporters::report_back_up(&BackUpReport::new(archive_name, &result));
+            match result {
+                Ok(BackUpOutcome::Written((time_taken, file_stats, _, _, _))) => {
+                    info!(
+                        "{}: backed up {} files ({} bytes) in {:?}",
+                        archive_name, file_stats.file_count, file_stats.byte_count, time_taken
+                    );
+                    monitoring::note_back_up_success(archive_name);
+                }
+                // only back ups that skip unchanged snapshots are unchanged
+                Ok(BackUpOutcome::Unchanged(_)) => monitoring::note_back_up_success(archive_name),
+                Err(err) => {
+                    error!("{}: {}", archive_name, err);
+                    monitoring::note_back_up_failure(archive_name, &err);
+                }
+            }
+        }
+    }
+
+    pub fn exec(&self) -> EResult<()> {
+        let listener = TcpListener::bind(self.listen)?;
+        info!("serving status at http://{}/", listener.local_addr()?);
+        let server = thread::spawn(move || serve_status(listener));
+        if self.no_back_ups {
+            server.join().ok();
+            return Ok(());
+        }
+        loop {
+            self.back_up_due_archives();
+            thread::sleep(Duration::from_secs(
+                self.check_every.max(1).saturating_mul(60),
+            ));
+        }
+    }
+}
@@ -0,0 +1,49 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+use structopt::StructOpt;
+
+use ergibus_lib::doctor::{self, CheckStatus};
+use ergibus_lib::{EResult, Error};
+
+#[derive(Debug, StructOpt)]
+/// Check the environment that back ups depend on (the configuration directory,
+/// the content repositories, the archives' snapshot directories and
+/// specifications and the system clock) and suggest fixes for any problems.
+pub struct Doctor {
+    /// write the report as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+}
+
+impl Doctor {
+    pub fn exec(&self) -> EResult<()> {
+        let results = doctor::run_checks();
+        if self.json {
+            let text =
+                serde_json::to_string_pretty(&results).expect("check results are serializable");
+            println!("{}", text);
+        } else {
+            for result in results.iter() {
+                let status = match result.status {
+                    CheckStatus::Pass => "PASS",
+                    CheckStatus::Warn => "WARN",
+                    CheckStatus::Fail => "FAIL",
+                };
+                println!("{} {}: {}", status, result.check, result.detail);
+                if let Some(hint) = &result.hint {
+                    println!("     hint: {}", hint);
+                }
+            }
+        }
+        let failures = results
+            .iter()
+            .filter(|result| result.status == CheckStatus::Fail)
+            .count();
+        if !self.json {
+            println!("{} checks: {} failed", results.len(), failures);
+        }
+        match failures {
+            0 => Ok(()),
+            count => Err(Error::DoctorChecksFailed(count)),
+        }
+    }
+}
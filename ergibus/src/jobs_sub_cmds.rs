@@ -0,0 +1,114 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+use std::env;
+use std::ffi::OsString;
+use std::fs::File;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+use chrono::{DateTime, Local};
+use structopt::StructOpt;
+
+use ergibus_lib::job::{self, JobId, JobKind, JobState, JobStatus};
+use ergibus_lib::EResult;
+
+#[derive(Debug, StructOpt)]
+/// Monitor and cancel jobs started with --detach
+pub enum Jobs {
+    /// List the recorded jobs.
+    List,
+    /// Show the progress of a job.
+    Show {
+        /// the id of the job to be shown.
+        job_id: JobId,
+    },
+    /// Ask a running job to stop.
+    Cancel {
+        /// the id of the job to be cancelled.
+        job_id: JobId,
+    },
+    /// Remove the records (and output) of jobs that are no longer running.
+    Clean,
+}
+
+fn state_text(status: &JobStatus) -> String {
+    match status.state {
+        _ if status.is_abandoned() => "abandoned".to_string(),
+        JobState::Running => "running".to_string(),
+        JobState::Finished(ref summary) => format!("finished: {}", summary),
+        JobState::Failed(ref message) => format!("failed: {}", message),
+        JobState::Cancelled => "cancelled".to_string(),
+    }
+}
+
+/// Run this command again in the background (as a job with a newly
+/// reserved id) with its output going to the job's log file.  The
+/// `--detach` flag is replaced by the job's id.
+pub fn detach(kind: JobKind, description: &str) -> EResult<()> {
+    let id = job::reserve_job_id(kind, description)?;
+    let args = env::args_os().skip(1).map(|arg| {
+        if arg == "--detach" {
+            OsString::from(format!("--job-id={}", id))
+        } else {
+            arg
+        }
+    });
+    let log_file = File::create(job::job_log_file_path(id))?;
+    Command::new(env::current_exe()?)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(log_file.try_clone()?)
+        .stderr(log_file)
+        // so that it survives interrupts aimed at this terminal's jobs
+        .process_group(0)
+        .spawn()?;
+    println!("{}", id);
+    Ok(())
+}
+
+impl Jobs {
+    pub fn exec(&self) -> EResult<()> {
+        match self {
+            Jobs::List => {
+                for status in job::read_job_records()? {
+                    println!(
+                        "{:>4} {} {}: {}",
+                        status.id,
+                        DateTime::<Local>::from(status.started).format("%Y-%m-%d %H:%M:%S"),
+                        status.description,
+                        state_text(&status)
+                    );
+                }
+                Ok(())
+            }
+            Jobs::Show { job_id } => {
+                let status = job::read_job_record(*job_id)?;
+                println!("job {}: {}", status.id, status.description);
+                println!(
+                    "started: {} (process {})",
+                    DateTime::<Local>::from(status.started).format("%Y-%m-%d %H:%M:%S"),
+                    status.pid
+                );
+                println!("state: {}", state_text(&status));
+                println!(
+                    "progress: {} files ({} bytes)",
+                    status.file_count, status.byte_count
+                );
+                if let Some(ref current_dir) = status.current_dir {
+                    println!("last directory: {:?}", current_dir);
+                }
+                for warning in status.warnings.iter() {
+                    println!("warning: {}", warning);
+                }
+                println!("output: {:?}", job::job_log_file_path(status.id));
+                Ok(())
+            }
+            Jobs::Cancel { job_id } => job::request_job_cancel(*job_id),
+            Jobs::Clean => {
+                for job_id in job::remove_finished_job_records()? {
+                    println!("removed job {}", job_id);
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -1,28 +1,64 @@
 // Copyright 2021 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
 
 mod archive_sub_cmds;
+mod audit_sub_cmds;
+mod clean_asides_sub_cmd;
+mod daemon_sub_cmd;
+mod doctor_sub_cmd;
+mod jobs_sub_cmds;
+mod merged_restore_sub_cmd;
+mod prune_sub_cmd;
+mod repo_sub_cmds;
+mod sizes;
 mod snapshot_sub_cmds;
 
+use std::path::PathBuf;
+use std::sync::Arc;
+
 use log::*;
 use stderrlog;
 use structopt::StructOpt;
 
+use ergibus_lib::exit_status::{ExitStatus, EXIT_STATUS_HELP};
+use ergibus_lib::report::{self, LogSink, Severity, Warning, WarningSink};
+use ergibus_lib::{config, staging, tr};
+
 use crate::archive_sub_cmds::ManageArchives;
+use crate::audit_sub_cmds::Audit;
+use crate::clean_asides_sub_cmd::CleanAsides;
+use crate::daemon_sub_cmd::Daemon;
+use crate::doctor_sub_cmd::Doctor;
+use crate::jobs_sub_cmds::Jobs;
+use crate::merged_restore_sub_cmd::MergedRestore;
+use crate::prune_sub_cmd::Prune;
+use crate::repo_sub_cmds::ManageRepositories;
 use crate::snapshot_sub_cmds::{BackUp, SnapshotContents, SnapshotManager};
 
 /// A StructOpt example
 #[derive(StructOpt, Debug)]
 #[structopt()]
 struct Ergibus {
-    /// Silence all output
+    /// Silence all messages (results are still written to stdout)
     #[structopt(short = "q", long = "quiet")]
     quiet: bool,
-    /// Verbose mode (-v, -vv, -vvv, etc)
+    /// Verbose mode (-v warnings, -vv information, -vvv etc debugging)
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: usize,
+    /// Colour messages (auto, always, never).  "auto" colours them if stderr is a terminal and NO_COLOR isn't set
+    #[structopt(long = "color", value_name = "when", default_value = "auto", parse(try_from_str = parse_color_choice))]
+    color: stderrlog::ColorChoice,
     /// Timestamp (sec, ms, ns, none)
     #[structopt(short = "t", long = "timestamp")]
     ts: Option<stderrlog::Timestamp>,
+    /// Refuse to use snapshots that haven't been signed
+    #[structopt(long = "require-signed")]
+    require_signed: bool,
+    /// Use this configuration directory (instead of $ERGIBUS_CONFIG_DIR or the default)
+    #[structopt(long = "config-dir", value_name = "path", parse(from_os_str))]
+    config_dir_path: Option<PathBuf>,
+    /// Use the named configuration profile (kept in the configuration directory's "profiles")
+    #[structopt(long = "config-profile", value_name = "name")]
+    config_profile: Option<String>,
     /// Sub commands
     #[structopt(subcommand)]
     sub_cmd: SubCommands,
@@ -34,8 +70,11 @@ enum SubCommands {
     /// Manage archives
     #[structopt(alias = "ar")]
     Archive(ManageArchives),
+    /// Manage content repositories
+    #[structopt(alias = "re")]
+    Repo(ManageRepositories),
     /// Manage archive snapshots
-    #[structopt(alias = "ms")]
+    #[structopt(alias = "ms", alias = "ss")]
     ManageSnapshots(SnapshotManager),
     /// Manage snapshot contents
     #[structopt(alias = "sc")]
@@ -43,26 +82,112 @@ enum SubCommands {
     /// Take backup snapshots
     #[structopt(alias = "bu")]
     BackUp(BackUp),
+    /// Review the log of destructive operations
+    Audit(Audit),
+    /// Monitor and cancel detached jobs
+    Jobs(Jobs),
+    /// Find (and delete) files moved aside by extractions
+    CleanAsides(CleanAsides),
+    /// Delete the oldest snapshots until enough repository space will be freed
+    Prune(Prune),
+    /// Back up archives as they fall due and serve their status over HTTP
+    Daemon(Daemon),
+    /// Restore several archives' snapshots from around the same time under a common root
+    #[structopt(alias = "mr")]
+    MergedRestore(MergedRestore),
+    /// Check the environment that back ups depend on
+    Doctor(Doctor),
+}
+
+// Print warnings met by commands (unless quiet) rather than leave them
+// to the log's verbosity.
+struct StderrSink {
+    quiet: bool,
+}
+
+impl WarningSink for StderrSink {
+    fn emit(&self, warning: &Warning) {
+        if self.quiet || warning.severity == Severity::Info {
+            LogSink.emit(warning)
+        } else {
+            eprintln!("{}: {}", warning.severity, warning)
+        }
+    }
+}
+
+fn parse_color_choice(text: &str) -> Result<stderrlog::ColorChoice, String> {
+    match text {
+        "auto" => Ok(stderrlog::ColorChoice::Auto),
+        "always" => Ok(stderrlog::ColorChoice::Always),
+        "never" => Ok(stderrlog::ColorChoice::Never),
+        _ => Err(format!("{}: expected auto, always or never", text)),
+    }
+}
+
+/// Report a command line usage error and exit with the usage error status.
+pub fn usage_exit(description: &str, kind: structopt::clap::ErrorKind) -> ! {
+    let err = structopt::clap::Error::with_description(description, kind);
+    eprintln!("{}", err.message);
+    std::process::exit(ExitStatus::Usage.code())
 }
 
 fn main() {
-    let ergibus = Ergibus::from_args();
+    let about = tr!("cli-about");
+    let app = Ergibus::clap()
+        .about(about.as_str())
+        .after_help(EXIT_STATUS_HELP);
+    let ergibus = match app.get_matches_safe() {
+        Ok(matches) => Ergibus::from_clap(&matches),
+        // help and version "errors" aren't failures
+        Err(err) if !err.use_stderr() => err.exit(),
+        Err(err) => {
+            eprintln!("{}", err.message);
+            std::process::exit(ExitStatus::Usage.code())
+        }
+    };
 
     stderrlog::new()
         //.module(module_path!())
         .quiet(ergibus.quiet)
         .verbosity(ergibus.verbose)
+        .color(ergibus.color)
         .timestamp(ergibus.ts.unwrap_or(stderrlog::Timestamp::Off))
         .init()
         .unwrap();
 
-    if let Err(err) = match ergibus.sub_cmd {
+    config::set_config_dir_path(ergibus.config_dir_path.as_deref());
+    if let Err(err) = config::set_profile(ergibus.config_profile.as_deref()) {
+        error!("{}", err);
+        std::process::exit(ExitStatus::from(&err).code());
+    }
+
+    match staging::remove_abandoned_staging_areas() {
+        Ok(removed) => {
+            for path in removed.iter() {
+                info!("{:?}: removed abandoned staging area", path);
+            }
+        }
+        Err(err) => warn!("failed to remove abandoned staging areas: {}", err),
+    }
+
+    let sink = Arc::new(StderrSink {
+        quiet: ergibus.quiet,
+    });
+    if let Err(err) = report::with_warning_sink(sink, || match ergibus.sub_cmd {
         SubCommands::Archive(sub_cmd) => sub_cmd.exec(),
-        SubCommands::ManageSnapshots(sub_cmd) => sub_cmd.exec(),
-        SubCommands::SnapshotContents(sub_cmd) => sub_cmd.exec(),
+        SubCommands::Repo(sub_cmd) => sub_cmd.exec(),
+        SubCommands::ManageSnapshots(sub_cmd) => sub_cmd.exec(ergibus.require_signed),
+        SubCommands::SnapshotContents(sub_cmd) => sub_cmd.exec(ergibus.require_signed),
         SubCommands::BackUp(sub_cmd) => sub_cmd.exec(),
-    } {
-        error!("{:?}", err);
-        std::process::exit(1);
+        SubCommands::Audit(sub_cmd) => sub_cmd.exec(),
+        SubCommands::Jobs(sub_cmd) => sub_cmd.exec(),
+        SubCommands::CleanAsides(sub_cmd) => sub_cmd.exec(),
+        SubCommands::Prune(sub_cmd) => sub_cmd.exec(),
+        SubCommands::Daemon(sub_cmd) => sub_cmd.exec(),
+        SubCommands::MergedRestore(sub_cmd) => sub_cmd.exec(ergibus.require_signed),
+        SubCommands::Doctor(sub_cmd) => sub_cmd.exec(),
+    }) {
+        error!("{}", err);
+        std::process::exit(ExitStatus::from(&err).code());
     }
 }
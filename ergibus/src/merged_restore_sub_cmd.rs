@@ -0,0 +1,140 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use structopt::StructOpt;
+
+use ergibus_lib::{fs_objects::CopyOptions, global_config, merged_restore, EResult, Error};
+
+fn parse_time(text: &str) -> Result<DateTime<Local>, String> {
+    if text == "now" {
+        return Ok(Local::now());
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.with_timezone(&Local));
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| {
+            format!(
+                "{}: expected \"now\" or a time like \"2024-03-01 12:00\"",
+                text
+            )
+        })?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| format!("{}: not a valid local time", text))
+}
+
+fn format_offset(offset: chrono::Duration) -> String {
+    let seconds = offset.num_seconds();
+    let (sign, seconds) = if seconds < 0 {
+        ("-", -seconds)
+    } else {
+        ("+", seconds)
+    };
+    format!(
+        "{}{}d{:02}h{:02}m",
+        sign,
+        seconds / 86_400,
+        (seconds % 86_400) / 3600,
+        (seconds % 3600) / 60
+    )
+}
+
+#[derive(Debug, StructOpt)]
+/// Restore several archives' snapshots from around the same time under a common
+/// root directory (e.g. for full machine recovery drills).  Each archive's snapshot
+/// nearest the nominated time is restored with its paths placed under the root.
+pub struct MergedRestore {
+    /// the directory under which the archives' contents are to be restored.
+    #[structopt(long = "into", value_name = "dir", parse(from_os_str))]
+    target_root: PathBuf,
+    /// restore the snapshots taken nearest this time (e.g. "2024-03-01 12:00").
+    #[structopt(long = "at", value_name = "time", default_value = "now", parse(try_from_str = parse_time))]
+    at: DateTime<Local>,
+    /// overwrite existing files instead of moving them aside.
+    #[structopt(long)]
+    overwrite: bool,
+    /// restore even if there doesn't appear to be enough free space at the target.
+    #[structopt(long)]
+    force: bool,
+    /// leave the restored directories with the time of the restore as their
+    /// modification time (instead of restoring their original times).
+    #[structopt(long = "no-dir-mtimes")]
+    no_dir_mtimes: bool,
+    /// also restore the member archives of this group (from the "groups" section of
+    /// the global configuration).
+    #[structopt(long = "group", value_name = "name", number_of_values = 1)]
+    groups: Vec<String>,
+    /// names of the archives to be restored.
+    #[structopt(required_unless = "groups")]
+    archives: Vec<String>,
+}
+
+impl MergedRestore {
+    pub fn exec(&self, require_signed: bool) -> EResult<()> {
+        let archives = global_config::expand_archive_groups(&self.archives, &self.groups)?;
+        let report = merged_restore::restore_archives(
+            &archives,
+            self.at,
+            &self.target_root,
+            &CopyOptions {
+                overwrite: self.overwrite,
+                preserve_dir_mtimes: !self.no_dir_mtimes,
+                force: self.force,
+                ..CopyOptions::default()
+            },
+            require_signed,
+        )?;
+        println!(
+            "Restoring snapshots nearest {} into {}:",
+            report.at.format("%Y-%m-%d %H:%M:%S %z"),
+            report.target_root.display()
+        );
+        for restore in report.restores.iter() {
+            let snapshot_name = match &restore.snapshot_name {
+                Some(snapshot_name) => snapshot_name.to_string_lossy().to_string(),
+                None => "-".to_string(),
+            };
+            let offset = restore.offset.map(format_offset).unwrap_or_default();
+            match &restore.result {
+                Ok(stats) => println!(
+                    "{:>16}: {} ({}) {} files, {} bytes, {} sym links in {} dirs",
+                    restore.archive_name,
+                    snapshot_name,
+                    offset,
+                    stats.file_count,
+                    stats.bytes_count,
+                    stats.dir_sym_link_count + stats.file_sym_link_count,
+                    stats.dir_count
+                ),
+                Err(err) => println!(
+                    "{:>16}: {} FAILED: {}",
+                    restore.archive_name, snapshot_name, err
+                ),
+            }
+        }
+        let totals = report.totals();
+        println!(
+            "{} archives: {} restored, {} failed: {} files containing {} bytes in {:?}",
+            report.restores.len(),
+            report.restores.len() - report.failure_count(),
+            report.failure_count(),
+            totals.file_count,
+            totals.bytes_count,
+            report.duration
+        );
+        match report.failure_count() {
+            0 => Ok(()),
+            count => Err(Error::RestoresFailed(count)),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+use std::convert::TryFrom;
+
+use structopt::StructOpt;
+
+use ergibus_lib::{archive::Snapshots, EResult};
+
+use crate::sizes::parse_size;
+
+#[derive(Debug, StructOpt)]
+/// Delete an archive's oldest snapshots until enough repository space will
+/// be freed.  The space is reclaimed when the repository is next pruned.
+pub struct Prune {
+    /// the name of the archive whose snapshots are to be deleted.
+    #[structopt(short, long = "archive", value_name = "name")]
+    archive_name: String,
+    /// stop once the contents no longer referenced are estimated to occupy
+    /// this much space (e.g. 500M or 50G).
+    #[structopt(long = "until-free", value_name = "size", parse(try_from_str = parse_size))]
+    target_bytes: u64,
+    /// always keep (at least) this many of the newest snapshots (or more if
+    /// the archive's retention policy says so).
+    #[structopt(long = "keep", value_name = "N", default_value = "1")]
+    keep_newest: usize,
+    /// report what would be deleted without deleting anything.
+    #[structopt(short = "n", long = "dry-run")]
+    dry_run: bool,
+}
+
+impl Prune {
+    pub fn exec(&self) -> EResult<()> {
+        let snapshots = Snapshots::try_from(self.archive_name.as_str())?;
+        let outcome =
+            snapshots.prune_until_free(self.target_bytes, self.keep_newest, self.dry_run)?;
+        let verb = if self.dry_run {
+            "would delete"
+        } else {
+            "deleted"
+        };
+        for name in outcome.deleted.iter() {
+            println!("{} {}", verb, name.to_string_lossy());
+        }
+        if self.dry_run {
+            println!(
+                "{}: {} snapshots, an estimated {} bytes",
+                self.archive_name,
+                outcome.deleted.len(),
+                outcome.estimated_bytes
+            );
+        } else {
+            println!(
+                "{}: {} snapshots deleted, {} bytes no longer referenced",
+                self.archive_name,
+                outcome.deleted.len(),
+                outcome.reclaimed_bytes
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+use std::path::PathBuf;
+
+use chrono::{Local, TimeZone};
+use serde::Serialize;
+use structopt::StructOpt;
+
+use dychatat_lib::content::{self, RepackStats, RepoUsage};
+use dychatat_lib::RepoError;
+use ergibus_lib::{archive, EResult};
+
+use crate::sizes::parse_size;
+
+#[derive(Debug, StructOpt)]
+/// Manage content repositories
+pub enum ManageRepositories {
+    /// List content repositories.
+    #[structopt(alias = "ls")]
+    List {
+        /// show each repository's location, usage and the archives that use it.
+        #[structopt(short, long = "long")]
+        long: bool,
+        /// write the long listing as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Show (or set) the repository used by new archives that don't nominate one.
+    Default {
+        /// the name of the repository to become the default.
+        repo_name: Option<String>,
+    },
+    /// Show (or set) the size below which new contents are appended to pack
+    /// files rather than stored in their own file.  Setting it also packs
+    /// the small contents already in the repository.
+    Packing {
+        /// the name of the repository.
+        #[structopt(long = "repo")]
+        repo_name: String,
+        /// pack contents smaller than this many bytes.
+        #[structopt(long = "below")]
+        pack_threshold: Option<u64>,
+        /// store each new content in its own file.
+        #[structopt(long = "off", conflicts_with = "pack-threshold")]
+        off: bool,
+    },
+    /// Show (or set) the maximum space that a repository's stored contents
+    /// may occupy.  Back ups that would take the repository over it fail.
+    Quota {
+        /// the name of the repository.
+        #[structopt(long = "repo")]
+        repo_name: String,
+        /// the maximum size (e.g. "500G").
+        #[structopt(long = "max", value_name = "size", parse(try_from_str = parse_size))]
+        max_size: Option<u64>,
+        /// remove the repository's quota.
+        #[structopt(long = "off", conflicts_with = "max-size")]
+        off: bool,
+    },
+    /// Consolidate a repository's contents into pack files (reclaiming the
+    /// space left by pruned contents).
+    Repack {
+        /// the name of the repository to be repacked.
+        #[structopt(long = "repo")]
+        repo_name: String,
+    },
+    /// Update a repository's specification after its directory has been
+    /// moved.  The old location is remembered so that snapshots made before
+    /// the move can still find their contents.
+    Relocate {
+        /// the name of the repository that has been moved.
+        #[structopt(long = "repo")]
+        repo_name: String,
+        /// the repository's new location (i.e. the directory containing its "ref_count" file).
+        #[structopt(long = "to", parse(from_os_str))]
+        location: PathBuf,
+    },
+    /// Compare two repositories' contents (e.g. a repository and an off-site
+    /// copy of it) reporting contents missing from the second, extra contents
+    /// in the second and contents whose sizes differ.
+    Compare {
+        /// the names of the two repositories (given as "--repo A --repo B").
+        #[structopt(long = "repo", value_name = "name", number_of_values = 1)]
+        repo_names: Vec<String>,
+        /// also check that the second repository's contents still match their
+        /// tokens (which means reading them all).
+        #[structopt(long)]
+        deep: bool,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct RepoListing {
+    #[serde(flatten)]
+    usage: RepoUsage,
+    archives: Vec<String>,
+}
+
+fn format_time(secs: Option<u64>) -> String {
+    match secs.and_then(|secs| Local.timestamp_opt(secs as i64, 0).single()) {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S %z").to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+fn format_quota(stored_bytes: u128, max_size: u64) -> String {
+    let percent = if max_size > 0 {
+        stored_bytes as f64 * 100.0 / max_size as f64
+    } else {
+        100.0
+    };
+    format!(
+        "{} of {} bytes used ({:.1}%)",
+        stored_bytes, max_size, percent
+    )
+}
+
+fn print_repack_stats(repo_name: &str, stats: &RepackStats) {
+    println!(
+        "{}: packed {} items, removed {} loose and {} pack files, freed {} bytes",
+        repo_name,
+        stats.items_packed,
+        stats.loose_files_removed,
+        stats.pack_files_removed,
+        stats.bytes_freed
+    );
+}
+
+impl ManageRepositories {
+    pub fn exec(&self) -> EResult<()> {
+        use ManageRepositories::*;
+        match self {
+            List { long, json } => {
+                let mut repo_names = content::get_repo_names();
+                repo_names.sort();
+                if !(*long || *json) {
+                    for repo_name in repo_names {
+                        println!("{}", repo_name);
+                    }
+                    return Ok(());
+                }
+                let mut listings = vec![];
+                for repo_name in repo_names {
+                    listings.push(RepoListing {
+                        usage: content::get_repo_usage(&repo_name)?,
+                        archives: archive::get_archive_names_using_repo(&repo_name),
+                    });
+                }
+                if *json {
+                    let text = serde_json::to_string_pretty(&listings)
+                        .expect("repository listings are serializable");
+                    println!("{}", text);
+                } else {
+                    for listing in listings {
+                        let usage = &listing.usage;
+                        println!("{}: {}", usage.name, usage.location.display());
+                        println!(
+                            "    digest: {} blobs: {} bytes: {} stored: {} modified: {}",
+                            usage.hash_algorithm,
+                            usage.blob_count,
+                            usage.content_bytes,
+                            usage.stored_bytes,
+                            format_time(usage.last_modified)
+                        );
+                        if let Some(max_size) = usage.max_size {
+                            println!("    quota: {}", format_quota(usage.stored_bytes, max_size));
+                        }
+                        println!("    archives: {}", listing.archives.join(", "));
+                    }
+                }
+                Ok(())
+            }
+            Default { repo_name } => {
+                match repo_name {
+                    Some(repo_name) => archive::set_default_repo_name(repo_name)?,
+                    None => match archive::get_default_repo_name()? {
+                        Some(repo_name) => println!("{}", repo_name),
+                        None => println!("no default repository"),
+                    },
+                }
+                Ok(())
+            }
+            Packing {
+                repo_name,
+                pack_threshold,
+                off,
+            } => {
+                if *off {
+                    content::set_repo_pack_threshold(repo_name, None)?;
+                } else if let Some(pack_threshold) = pack_threshold {
+                    let stats = content::set_repo_pack_threshold(repo_name, Some(*pack_threshold))?;
+                    print_repack_stats(repo_name, &stats);
+                }
+                match content::get_repo_usage(repo_name)?.pack_threshold {
+                    Some(pack_threshold) => println!(
+                        "{}: contents smaller than {} bytes are packed",
+                        repo_name, pack_threshold
+                    ),
+                    None => println!("{}: contents are stored in their own files", repo_name),
+                }
+                Ok(())
+            }
+            Quota {
+                repo_name,
+                max_size,
+                off,
+            } => {
+                if *off {
+                    content::set_repo_max_size(repo_name, None)?;
+                } else if let Some(max_size) = max_size {
+                    content::set_repo_max_size(repo_name, Some(*max_size))?;
+                }
+                let usage = content::get_repo_usage(repo_name)?;
+                match usage.max_size {
+                    Some(max_size) => println!(
+                        "{}: {}",
+                        repo_name,
+                        format_quota(usage.stored_bytes, max_size)
+                    ),
+                    None => println!("{}: no quota", repo_name),
+                }
+                Ok(())
+            }
+            Repack { repo_name } => {
+                let stats = content::repack_repository(repo_name)?;
+                print_repack_stats(repo_name, &stats);
+                Ok(())
+            }
+            Relocate {
+                repo_name,
+                location,
+            } => {
+                content::relocate_repo(repo_name, location)?;
+                println!("{}: relocated to {}", repo_name, location.display());
+                Ok(())
+            }
+            Compare { repo_names, deep } => {
+                let (repo_name, other_repo_name) = match &repo_names[..] {
+                    [repo_name, other_repo_name] => (repo_name, other_repo_name),
+                    _ => crate::usage_exit(
+                        "exactly two repositories must be nominated with --repo",
+                        structopt::clap::ErrorKind::WrongNumberOfValues,
+                    ),
+                };
+                let comparison = content::compare_repositories(repo_name, other_repo_name, *deep)?;
+                for (what, tokens) in [
+                    ("missing", &comparison.missing),
+                    ("extra", &comparison.extra),
+                    ("size mismatch", &comparison.size_mismatches),
+                    ("corrupt", &comparison.corrupt),
+                ] {
+                    for token in tokens.iter() {
+                        println!("{}: {}", what, token);
+                    }
+                }
+                println!(
+                    "{} contents compared: {} missing, {} extra, {} size mismatches{}",
+                    comparison.tokens_compared,
+                    comparison.missing.len(),
+                    comparison.extra.len(),
+                    comparison.size_mismatches.len(),
+                    if *deep {
+                        format!(", {} corrupt", comparison.corrupt.len())
+                    } else {
+                        String::new()
+                    }
+                );
+                if comparison.is_match() {
+                    Ok(())
+                } else {
+                    Err(
+                        RepoError::ContentsDiffer(repo_name.clone(), other_repo_name.clone())
+                            .into(),
+                    )
+                }
+            }
+        }
+    }
+}
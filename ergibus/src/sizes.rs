@@ -0,0 +1,23 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+// A number of bytes optionally followed by K, M, G or T (powers of 1024).
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let text = text
+        .strip_suffix("iB")
+        .or_else(|| text.strip_suffix('B'))
+        .unwrap_or(text);
+    let (digits, multiplier) = match text.chars().last() {
+        Some('K') | Some('k') => (&text[..text.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&text[..text.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&text[..text.len() - 1], 1 << 30),
+        Some('T') | Some('t') => (&text[..text.len() - 1], 1 << 40),
+        _ => (text, 1),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("{}: expected a size such as 500M or 50G", text))
+}
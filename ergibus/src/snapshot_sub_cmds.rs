@@ -1,16 +1,42 @@
 // Copyright 2021 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
 
 use std::convert::TryFrom;
+use std::io::{self, Write};
 use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, Instant};
 
+use chrono::{Local, TimeZone};
+use log::*;
 use structopt::{clap::ArgGroup, StructOpt};
 
-use ergibus_lib::snapshot::Order;
-use ergibus_lib::{archive::Snapshots, snapshot, EResult, Error};
+use ergibus_lib::attributes::AttributesIfce;
+use ergibus_lib::job::{JobId, JobKind, JobRunner};
+use ergibus_lib::snapshot::{BackUpOptions, BackUpOutcome, Order};
+use ergibus_lib::snapshot_meta::SnapshotMetadata;
+use ergibus_lib::{
+    archive::{self, Snapshots},
+    checksums::ChecksumStatus,
+    estimate::{self, BackUpEstimate},
+    free_space::LowSpaceAction,
+    fs_objects::{self, CopyOptions},
+    global_config,
+    owner_map::OwnerMap,
+    progress::{self, ProgressEvent},
+    read_policy::{SpecialFilePolicy, StorageOrder},
+    reporters::{self, BackUpReport},
+    resource_stats::{ResourceCounters, ResourceMeter, ResourceStats},
+    snapshot, snapshot_schema,
+    staging::StagingArea,
+    EResult, Error,
+};
 use std::env;
 
+use crate::jobs_sub_cmds;
+use crate::sizes::parse_size;
+
 #[derive(Debug, StructOpt)]
-#[structopt(group = ArgGroup::with_name("which").required(true))]
+#[structopt(group = ArgGroup::with_name("which"))]
 pub struct SnapshotManager {
     /// the name of the snapshot archive that contains the snapshot(s) to be acted on.
     #[structopt(short, long = "archive", group = "which")]
@@ -32,6 +58,55 @@ pub struct SnapshotManager {
 pub enum SubCmd {
     /// List the snapshots for a nominated archive (or in a nominated directory).
     List,
+    /// Print the path of the newest snapshot.
+    Latest {
+        /// print the snapshot's name instead of its path.
+        #[structopt(short, long)]
+        name_only: bool,
+    },
+    /// Print the hash of a snapshot's tree (which is the same for snapshots whose
+    /// contents, modes, ownership and link targets are the same).
+    Hash {
+        /// use the snapshot "N" places before the most recent (rather than the most
+        /// recent). Use -1 to select oldest.
+        #[structopt(short, long, value_name = "N")]
+        back_n: Option<i64>,
+        /// check that the snapshot still matches the hash recorded when it was made
+        /// (e.g. after it has been copied elsewhere).
+        #[structopt(long)]
+        verify: bool,
+    },
+    /// Check each snapshot file against the checksum recorded (in the snapshot
+    /// directory's "CHECKSUMS" file) when it was written and that it can still be
+    /// read and matches its recorded tree hash.
+    Verify,
+    /// Print (as JSON) a snapshot's structural metadata (sizes, counts, depth and size
+    /// distributions, timings and the paths in it) e.g. for reporting performance problems.
+    ExportMeta {
+        /// use the snapshot "N" places before the most recent (rather than the most
+        /// recent). Use -1 to select oldest.
+        #[structopt(short, long, value_name = "N")]
+        back_n: Option<i64>,
+        /// replace the archive's name and every path component with salted hashes
+        /// (so that the metadata can be shared without revealing file names).
+        #[structopt(long)]
+        anonymize: bool,
+        /// omit the list of the paths in the snapshot.
+        #[structopt(long)]
+        summary: bool,
+    },
+    /// Print a snapshot's file statistics (numbers, sizes and how much was new or
+    /// deduplicated).
+    Stats {
+        /// use the snapshot "N" places before the most recent (rather than the most
+        /// recent). Use -1 to select oldest.
+        #[structopt(short, long, value_name = "N")]
+        back_n: Option<i64>,
+        /// also show the numbers and sizes of the files by type (code, images, video,
+        /// archives and other) to help find what bloats the back ups.
+        #[structopt(long)]
+        breakdown: bool,
+    },
     /// Delete the specified snapshot(s).
     #[structopt(alias = "del", group = ArgGroup::with_name("which_ss").required(true))]
     Delete {
@@ -41,6 +116,9 @@ pub enum SubCmd {
         /// delete the snapshot "N" places before the most recent. Use -1 to select oldest.
         #[structopt(short, long, value_name = "N", group = "which_ss")]
         back_n: Option<i64>,
+        /// the snapshots that have expired under the archive's retention policy.
+        #[structopt(long, group = "which_ss")]
+        expired: bool,
         /// authorise deletion of the last remaining snapshot in the archive.
         #[structopt(short, long)]
         clear_fell: bool,
@@ -48,26 +126,202 @@ pub enum SubCmd {
         #[structopt(short, long)]
         verbose: bool,
     },
+    /// Search a directory tree (e.g. a back up drive) for snapshot directories whose
+    /// configuration has been lost.  Does not need --archive or --exigency.
+    Discover {
+        /// the directory to be searched.
+        #[structopt(long = "under", parse(from_os_str))]
+        dir_path: PathBuf,
+        /// regenerate archive (and repository) specifications for the discovered directories.
+        ///
+        /// Inclusions are deduced from the latest snapshot and no exclusions are
+        /// set so the regenerated specifications should be reviewed.
+        #[structopt(long)]
+        regenerate: bool,
+    },
+    /// Print the JSON Schema describing the (decompressed) contents of snapshot files
+    /// (for use by third party tools).  Does not need --archive or --exigency.
+    Schema,
 }
 
 impl SnapshotManager {
-    pub fn exec(&self) -> EResult<()> {
-        let snapshot_dir = if let Some(archive_name) = &self.archive_name {
+    fn discover(dir_path: &PathBuf, regenerate: bool) -> EResult<()> {
+        for discovered in archive::discover_archives(dir_path)? {
+            println!(
+                "{}: {} snapshots in {:?}",
+                discovered.name, discovered.snapshot_count, discovered.snapshot_dir_path
+            );
+            if regenerate {
+                match archive::regenerate_archive_spec(&discovered) {
+                    Ok(repo_name) => println!(
+                        "\tregenerated archive \"{}\" using repository \"{}\"",
+                        discovered.name, repo_name
+                    ),
+                    Err(err) => error!("{}: regeneration failed: {}", discovered.name, err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn exec(&self, require_signed: bool) -> EResult<()> {
+        if let SubCmd::Discover {
+            dir_path,
+            regenerate,
+        } = &self.sub_cmd
+        {
+            return Self::discover(dir_path, *regenerate);
+        }
+        if let SubCmd::Schema = self.sub_cmd {
+            let schema = snapshot_schema::snapshot_schema();
+            let json =
+                serde_json::to_string_pretty(&schema).map_err(Error::SnapshotSerializeError)?;
+            println!("{}", json);
+            return Ok(());
+        }
+        let mut snapshot_dir = if let Some(archive_name) = &self.archive_name {
             Snapshots::try_from(archive_name.as_str())?
         } else if let Some(dir_path) = &self.exigency_dir_path {
             Snapshots::try_from(dir_path.as_path())?
         } else {
-            panic!("either --archive or --exigency must be present");
+            crate::usage_exit(
+                "one of --archive or --exigency must be present",
+                structopt::clap::ErrorKind::MissingRequiredArgument,
+            )
         };
+        snapshot_dir.set_require_signed(require_signed);
         match self.sub_cmd {
             SubCmd::List => {
                 for name in snapshot_dir.get_snapshot_names(Order::Ascending)?.iter() {
                     println!("{:?}", name);
                 }
             }
+            SubCmd::Latest { name_only } => {
+                let path = snapshot_dir.get_latest_snapshot_path()?;
+                match path.file_name() {
+                    Some(name) if name_only => println!("{}", name.to_string_lossy()),
+                    _ => println!("{}", path.display()),
+                }
+            }
+            SubCmd::Hash { back_n, verify } => {
+                let path = match back_n {
+                    Some(back_n) => snapshot_dir.get_snapshot_path_back_n(back_n)?,
+                    None => snapshot_dir.get_latest_snapshot_path()?,
+                };
+                let snapshot = snapshot_dir.read_snapshot(&path)?;
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                match snapshot.tree_hash() {
+                    Some(tree_hash) => println!("{}  {}", tree_hash, name),
+                    None => println!(
+                        "{}  {} (computed: none recorded)",
+                        snapshot.root_dir().tree_hash(),
+                        name
+                    ),
+                }
+                if verify && snapshot.verify_tree_hash() == Some(false) {
+                    return Err(Error::SnapshotTreeHashMismatch(path));
+                }
+            }
+            SubCmd::Verify => {
+                let mut failures = 0;
+                for (path, status) in snapshot_dir.verify_checksums()? {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    let mut problems = vec![];
+                    if let ChecksumStatus::Differs { recorded, actual } = &status {
+                        problems.push(format!(
+                            "checksum {} differs from that recorded ({})",
+                            actual, recorded
+                        ));
+                    }
+                    match snapshot_dir.read_snapshot(&path) {
+                        Ok(snapshot) => {
+                            if snapshot.verify_tree_hash() == Some(false) {
+                                problems.push("tree hash mismatch".to_string());
+                            }
+                        }
+                        Err(err) => problems.push(format!("unreadable: {}", err)),
+                    }
+                    if problems.is_empty() {
+                        match status {
+                            ChecksumStatus::Unrecorded => {
+                                println!("{}: ok (no checksum recorded)", name)
+                            }
+                            _ => println!("{}: ok", name),
+                        }
+                    } else {
+                        failures += 1;
+                        for problem in problems {
+                            println!("{}: {}", name, problem);
+                        }
+                    }
+                }
+                if failures > 0 {
+                    return Err(Error::SnapshotsUnverified(failures));
+                }
+            }
+            SubCmd::ExportMeta {
+                back_n,
+                anonymize,
+                summary,
+            } => {
+                let path = match back_n {
+                    Some(back_n) => snapshot_dir.get_snapshot_path_back_n(back_n)?,
+                    None => snapshot_dir.get_latest_snapshot_path()?,
+                };
+                let snapshot = snapshot_dir.read_snapshot(&path)?;
+                let metadata = SnapshotMetadata::new(&snapshot, anonymize, !summary)?;
+                println!("{}", metadata.to_json());
+            }
+            SubCmd::Stats { back_n, breakdown } => {
+                let path = match back_n {
+                    Some(back_n) => snapshot_dir.get_snapshot_path_back_n(back_n)?,
+                    None => snapshot_dir.get_latest_snapshot_path()?,
+                };
+                let snapshot = snapshot_dir.read_snapshot(&path)?;
+                let file_stats = snapshot.file_stats();
+                let sym_link_stats = snapshot.sym_link_stats();
+                println!("{}", path.file_name().unwrap_or_default().to_string_lossy());
+                println!(
+                    "\tfiles: {} ({} bytes, {} stored)",
+                    file_stats.file_count, file_stats.byte_count, file_stats.stored_byte_count
+                );
+                println!(
+                    "\tnew: {} bytes, already in repository: {} bytes, duplicated in snapshot: {} bytes",
+                    file_stats.new_byte_count,
+                    file_stats.repo_dedup_byte_count,
+                    file_stats.snapshot_dedup_byte_count
+                );
+                println!(
+                    "\tsymbolic links: {} to directories, {} to files",
+                    sym_link_stats.dir_sym_link_count, sym_link_stats.file_sym_link_count
+                );
+                if breakdown {
+                    let type_breakdown = snapshot.type_breakdown();
+                    let total_bytes = type_breakdown.byte_count();
+                    println!(
+                        "{:>10} | {:>12} | {:>14} | {:>6}",
+                        "type", "files", "bytes", "bytes%"
+                    );
+                    for (category, stats) in type_breakdown.by_size() {
+                        let percentage = if total_bytes > 0 {
+                            stats.byte_count as f64 * 100.0 / total_bytes as f64
+                        } else {
+                            0.0
+                        };
+                        println!(
+                            "{:>10} | {:>12} | {:>14} | {:>5.1}%",
+                            category.to_string(),
+                            stats.file_count,
+                            stats.byte_count,
+                            percentage
+                        );
+                    }
+                }
+            }
             SubCmd::Delete {
                 all_but_newest_n,
                 back_n,
+                expired,
                 clear_fell,
                 verbose,
             } => {
@@ -75,6 +329,16 @@ impl SnapshotManager {
                     snapshot_dir.delete_all_but_newest(count, clear_fell)?
                 } else if let Some(back_n) = back_n {
                     snapshot_dir.delete_ss_back_n(back_n, clear_fell)?
+                } else if expired {
+                    let archive_name = match self.archive_name {
+                        Some(ref archive_name) => archive_name,
+                        None => crate::usage_exit(
+                            "--expired needs --archive (for the retention policy)",
+                            structopt::clap::ErrorKind::MissingRequiredArgument,
+                        ),
+                    };
+                    let retention = archive::get_retention_policy(archive_name)?;
+                    snapshot_dir.delete_expired(&retention)?
                 } else {
                     panic!("clap shouldn't let us get here")
                 };
@@ -82,6 +346,7 @@ impl SnapshotManager {
                     println!("{} snapshots deleted.", number)
                 }
             }
+            SubCmd::Discover { .. } | SubCmd::Schema => panic!("handled above"),
         }
         Ok(())
     }
@@ -103,8 +368,15 @@ pub struct SnapshotContents {
     #[structopt(short = "x", long = "exigency", group = "which", parse(from_os_str))]
     exigency_dir_path: Option<PathBuf>,
     /// use the snapshot "N" places before the most recent. Use -1 to select oldest.
+    /// (Required by all but "history".)
     #[structopt(short, long, value_name = "N", group = "which_ss")]
-    back_n: i64,
+    back_n: Option<i64>,
+    /// the location of the content repository if it differs from that recorded in the snapshot.
+    ///
+    /// Relocations made with "dychatat relocate" are applied automatically so
+    /// this is only needed when the repository is mounted somewhere temporarily.
+    #[structopt(long = "repo-location", value_name = "path", parse(from_os_str))]
+    repo_location: Option<PathBuf>,
     #[structopt(subcommand)]
     sub_cmd: ContentsSubCmd,
 }
@@ -135,67 +407,245 @@ pub enum ContentsSubCmd {
         /// overwrite the file/directory if it already exists instead of moving it aside.
         #[structopt(long)]
         overwrite: bool,
+        /// extract even if there doesn't appear to be enough free space at the target.
+        #[structopt(long)]
+        force: bool,
+        /// make symbolic links whose absolute targets are within the extracted directory
+        /// relative (so that they point into the extracted copy).
+        ///
+        /// Links whose absolute targets are outside the extracted directory are left
+        /// unchanged and listed.
+        #[structopt(long = "rewrite-links")]
+        rewrite_links: bool,
+        /// leave the extracted directories with the time of the extraction as their
+        /// modification time (instead of restoring their original times).
+        #[structopt(long = "no-dir-mtimes")]
+        no_dir_mtimes: bool,
         /// the name to be given to the copy of the file/directory.
-        #[structopt(long, value_name = "path")]
+        ///
+        /// A plain name is placed in the target directory but a path (e.g. "~/restored")
+        /// is used as is.
+        #[structopt(long, value_name = "path", parse(from_os_str))]
         with_name: Option<PathBuf>,
-        /// the path of the directory into which the file/directory is to be copied.
-        #[structopt(long, value_name = "path")]
+        /// the path of the directory into which the file/directory is to be copied
+        /// (may be relative to the current directory or start with "~").
+        #[structopt(long, value_name = "path", parse(from_os_str))]
         into_dir: Option<PathBuf>,
         /// show statistics for the extraction process.
         #[structopt(long = "stats")]
         show_stats: bool,
+        /// keep up to this many MiB of file contents in memory for reuse (e.g. for duplicated files).
+        #[structopt(long = "read-cache", value_name = "MiB")]
+        read_cache_mib: Option<usize>,
+        /// when extracting a directory, restore its files and directories owned by uid OLD as owned by uid NEW.
+        #[structopt(long = "uid-map", value_name = "OLD:NEW", number_of_values = 1)]
+        uid_maps: Vec<String>,
+        /// when extracting a directory, restore its files and directories owned by gid OLD as owned by gid NEW.
+        #[structopt(long = "gid-map", value_name = "OLD:NEW", number_of_values = 1)]
+        gid_maps: Vec<String>,
+        /// read uid and gid mappings from a YAML file (with "uids" and "gids" maps).
+        #[structopt(long = "owner-map", value_name = "path", parse(from_os_str))]
+        owner_map_path: Option<PathBuf>,
+        /// restore owners' raw uids and gids instead of matching them by name.
+        #[structopt(long = "numeric-ids")]
+        numeric_ids: bool,
+        /// run the extraction in the background and print its job id (see "ergibus jobs").
+        #[structopt(long = "detach")]
+        detach: bool,
+        /// run as the nominated (previously reserved) job.
+        #[structopt(long = "job-id", hidden = true, conflicts_with = "detach")]
+        job_id: Option<JobId>,
+    },
+    /// Restore a file to where it was when the snapshot was made (creating any
+    /// missing parent directories).
+    Restore {
+        /// the path of the file to be restored.
+        #[structopt(parse(from_os_str))]
+        file_path: PathBuf,
+        /// overwrite the file if it already exists instead of moving it aside.
+        #[structopt(long)]
+        overwrite: bool,
+        /// restore even if there doesn't appear to be enough free space at the target.
+        #[structopt(long)]
+        force: bool,
+        /// report where the file was restored to.
+        #[structopt(short, long)]
+        verbose: bool,
+    },
+    /// Show the start of a (text) file inside a snapshot without extracting it
+    Head {
+        /// the path of the file to be shown
+        #[structopt(parse(from_os_str))]
+        file_path: PathBuf,
+        /// the number of bytes to show
+        #[structopt(short = "c", long = "bytes", value_name = "N", default_value = "1024")]
+        bytes: usize,
+        /// show the bytes even if the file doesn't look like text
+        #[structopt(long)]
+        binary: bool,
     },
     /// List the contents of a directory inside a snapshot
     List {
         /// the path of the directory to be listed
         #[structopt(parse(from_os_str))]
         dir_path: Option<PathBuf>,
+        /// show each item's permissions, size, modification time and creation
+        /// (birth) time ("-" where the file system didn't record it).
+        #[structopt(short, long)]
+        long: bool,
+    },
+    /// Show the differences (as a unified diff) between a file in the snapshot
+    /// and the file as it is now
+    DiffFile {
+        /// the path of the file
+        #[structopt(parse(from_os_str))]
+        file_path: PathBuf,
+    },
+    /// List the snapshots that contain a file with its size, modification time
+    /// and content token in each (marking those where its contents changed)
+    History {
+        /// the path of the file
+        #[structopt(parse(from_os_str))]
+        file_path: PathBuf,
+        /// copy each distinct version of the file into this directory (named
+        /// "<file name>@<snapshot name>").
+        #[structopt(long = "extract-all-versions", value_name = "dir", parse(from_os_str))]
+        extract_dir_path: Option<PathBuf>,
+        /// overwrite existing copies instead of moving them aside.
+        #[structopt(long)]
+        overwrite: bool,
     },
 }
 
+// Seconds since the epoch as a local time
+fn format_time(secs: i64) -> String {
+    Local
+        .timestamp_opt(secs, 0)
+        .single()
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| secs.to_string())
+}
+
 impl SnapshotContents {
-    pub fn exec(&self) -> EResult<()> {
-        let snapshot_dir = if let Some(archive_name) = &self.archive_name {
+    fn back_n(&self) -> i64 {
+        match self.back_n {
+            Some(back_n) => back_n,
+            None => crate::usage_exit(
+                "--back-n is required (except for \"history\")",
+                structopt::clap::ErrorKind::MissingRequiredArgument,
+            ),
+        }
+    }
+
+    pub fn exec(&self, require_signed: bool) -> EResult<()> {
+        let mut snapshot_dir = if let Some(archive_name) = &self.archive_name {
             Snapshots::try_from(archive_name.as_str())?
         } else if let Some(dir_path) = &self.exigency_dir_path {
             Snapshots::try_from(dir_path.as_path())?
         } else {
             panic!("either --archive or --exigency must be present");
         };
+        snapshot_dir.set_repo_location(self.repo_location.clone());
+        snapshot_dir.set_require_signed(require_signed);
         use ContentsSubCmd::*;
         match &self.sub_cmd {
             Extract {
                 file_path,
                 dir_path,
                 overwrite,
+                force,
+                rewrite_links,
+                no_dir_mtimes,
                 with_name,
                 into_dir,
                 show_stats,
+                read_cache_mib,
+                uid_maps,
+                gid_maps,
+                owner_map_path,
+                numeric_ids,
+                detach,
+                job_id,
             } => {
+                if *detach {
+                    let what = file_path.as_ref().or(dir_path.as_ref());
+                    let description = format!("extract {:?}", what.expect("clap requires one"));
+                    return jobs_sub_cmds::detach(JobKind::Extraction, &description);
+                }
+                let mut owner_map = match owner_map_path {
+                    Some(owner_map_path) => OwnerMap::from_file(owner_map_path)?,
+                    None => OwnerMap::default(),
+                };
+                for uid_map in uid_maps.iter() {
+                    owner_map.add_uid_mapping(uid_map)?;
+                }
+                for gid_map in gid_maps.iter() {
+                    owner_map.add_gid_mapping(gid_map)?;
+                }
+                owner_map.numeric_ids |= *numeric_ids;
                 let into_dir = if let Some(into_dir) = into_dir {
                     into_dir.clone()
                 } else {
                     env::current_dir()?
                 };
                 if let Some(file_path) = file_path {
-                    let stats = snapshot_dir.copy_file_to(
-                        self.back_n,
-                        file_path,
-                        &into_dir,
-                        with_name,
-                        *overwrite,
-                    )?;
+                    let stats = match job_id {
+                        Some(job_id) => {
+                            let (n, file_path, with_name, overwrite, force) = (
+                                self.back_n(),
+                                file_path.clone(),
+                                with_name.clone(),
+                                *overwrite,
+                                *force,
+                            );
+                            JobRunner::persistent()
+                                .start_reserved(*job_id, move || {
+                                    snapshot_dir.copy_file_to(
+                                        n, &file_path, &into_dir, &with_name, overwrite, force,
+                                    )
+                                })?
+                                .wait()?
+                        }
+                        None => snapshot_dir.copy_file_to(
+                            self.back_n(),
+                            file_path,
+                            &into_dir,
+                            with_name,
+                            *overwrite,
+                            *force,
+                        )?,
+                    };
                     if *show_stats {
                         println!("Transfered {} bytes in {:?}", stats.0, stats.1)
                     }
                 } else if let Some(dir_path) = dir_path {
-                    let stats = snapshot_dir.copy_dir_to(
-                        self.back_n,
-                        dir_path,
-                        &into_dir,
-                        with_name,
-                        *overwrite,
-                    )?;
+                    let options = CopyOptions {
+                        overwrite: *overwrite,
+                        preserve_dir_mtimes: !*no_dir_mtimes,
+                        rewrite_links: *rewrite_links,
+                        owner_map,
+                        force: *force,
+                        read_cache_capacity: read_cache_mib.unwrap_or(0) * 1024 * 1024,
+                    };
+                    let stats = match job_id {
+                        Some(job_id) => {
+                            let (n, dir_path, with_name) =
+                                (self.back_n(), dir_path.clone(), with_name.clone());
+                            JobRunner::persistent()
+                                .start_reserved(*job_id, move || {
+                                    snapshot_dir
+                                        .copy_dir_to(n, &dir_path, &into_dir, &with_name, &options)
+                                })?
+                                .wait()?
+                        }
+                        None => snapshot_dir.copy_dir_to(
+                            self.back_n(),
+                            dir_path,
+                            &into_dir,
+                            with_name,
+                            &options,
+                        )?,
+                    };
                     if *show_stats {
                         println!("Transfered {} files containing {} bytes and {} sym links in {} dirs in {:?}", 
                                  stats.0.file_count,
@@ -203,15 +653,67 @@ impl SnapshotContents {
                                  (stats.0.dir_sym_link_count + stats.0.file_sym_link_count),
                                  stats.0.dir_count,
                                  stats.1
-                        )
+                        );
+                        if read_cache_mib.is_some() {
+                            println!(
+                                "Read cache: {} hits, {} misses",
+                                stats.0.cache_stats.hits, stats.0.cache_stats.misses
+                            );
+                        }
+                        if stats.0.birth_times_not_restored > 0 {
+                            println!(
+                                "{} creation (birth) times could not be restored (not supported)",
+                                stats.0.birth_times_not_restored
+                            );
+                        }
+                    }
+                    let external_links = &stats.0.external_links;
+                    if *rewrite_links && !external_links.is_empty() {
+                        println!(
+                            "{} links to targets outside the extracted directory were left unchanged:",
+                            external_links.len()
+                        );
+                        for (link_path, link_target) in external_links.iter() {
+                            println!("\t{:?} -> {:?}", link_path, link_target);
+                        }
                     }
                 } else {
                     panic!("clap shouldn't have let us get here")
                 };
                 Ok(())
             }
-            List { dir_path } => {
-                let snapshot_persistent_data = snapshot_dir.get_snapshot_back_n(self.back_n)?;
+            Restore {
+                file_path,
+                overwrite,
+                force,
+                verbose,
+            } => {
+                let (restored_path, bytes) =
+                    snapshot_dir.restore_file(self.back_n(), file_path, *overwrite, *force)?;
+                if *verbose {
+                    println!("Restored {:?} ({} bytes)", restored_path, bytes);
+                }
+                Ok(())
+            }
+            Head {
+                file_path,
+                bytes,
+                binary,
+            } => {
+                let snapshot_persistent_data = snapshot_dir.get_snapshot_back_n(self.back_n())?;
+                let prefix = snapshot_persistent_data.read_file_prefix(file_path, *bytes)?;
+                if *binary || fs_objects::looks_like_text(&prefix) {
+                    io::stdout().write_all(&prefix)?;
+                } else {
+                    println!(
+                        "{:?}: binary file (use --binary to show it anyway)",
+                        file_path
+                    );
+                }
+                Ok(())
+            }
+            List { dir_path, long } => {
+                let snapshot_persistent_data = snapshot_dir.get_snapshot_back_n(self.back_n())?;
                 let dir = if let Some(dir_path) = dir_path {
                     // TODO: be smarter about target path for listing
                     snapshot_persistent_data.find_subdir(dir_path)?
@@ -219,7 +721,86 @@ impl SnapshotContents {
                     snapshot_persistent_data.find_subdir(&PathBuf::new())?
                 };
                 for fso in dir.contents() {
-                    println!("{}", fso)
+                    if *long {
+                        let attributes = fso.attributes();
+                        println!(
+                            "{} {:>12} {} {} {}",
+                            attributes.mode_string(),
+                            attributes.size(),
+                            format_time(attributes.mtime()),
+                            attributes
+                                .btime()
+                                .map(format_time)
+                                .unwrap_or_else(|| format!("{:19}", "-")),
+                            fso
+                        )
+                    } else {
+                        println!("{}", fso)
+                    }
+                }
+                Ok(())
+            }
+            DiffFile { file_path } => {
+                let file_path = env::current_dir()?.join(file_path);
+                let snapshot_path = snapshot_dir.get_snapshot_path_back_n(self.back_n())?;
+                let snapshot_name = snapshot_path.file_name().unwrap_or_default();
+                let snapshot_persistent_data = snapshot_dir.get_snapshot_back_n(self.back_n())?;
+                let staging_area = StagingArea::new("diff")?;
+                let old_file_path = staging_area.path().join("old");
+                snapshot_persistent_data.copy_file_to(&file_path, &old_file_path, false, false)?;
+                // NB: "-N" makes a missing current file look empty
+                let status = Command::new("diff")
+                    .arg("-uN")
+                    .arg("--label")
+                    .arg(format!(
+                        "{} ({})",
+                        file_path.display(),
+                        snapshot_name.to_string_lossy()
+                    ))
+                    .arg("--label")
+                    .arg(file_path.display().to_string())
+                    .arg(&old_file_path)
+                    .arg(&file_path)
+                    .status()?;
+                // diff exits with 1 when there are differences
+                match status.code() {
+                    Some(0) | Some(1) => Ok(()),
+                    _ => Err(io::Error::other(format!("diff failed: {}", status)).into()),
+                }
+            }
+            History {
+                file_path,
+                extract_dir_path,
+                overwrite,
+            } => {
+                let versions = match extract_dir_path {
+                    Some(extract_dir_path) => snapshot_dir.extract_file_versions(
+                        file_path,
+                        extract_dir_path,
+                        *overwrite,
+                    )?,
+                    None => snapshot_dir
+                        .file_history(file_path)?
+                        .into_iter()
+                        .map(|version| (version, None))
+                        .collect(),
+                };
+                if versions.is_empty() {
+                    return Err(Error::SnapshotUnknownFile(file_path.clone()));
+                }
+                for (version, copy_path) in versions.iter() {
+                    let mtime = format_time(version.mtime);
+                    println!(
+                        "{} {} {:>12} {} {}",
+                        if version.content_changed { "*" } else { " " },
+                        version.snapshot_name.to_string_lossy(),
+                        version.size,
+                        mtime,
+                        version.content_token
+                    );
+                    if let Some(copy_path) = copy_path {
+                        println!("\textracted to {}", copy_path.display());
+                    }
                 }
                 Ok(())
             }
@@ -232,19 +813,259 @@ pub struct BackUp {
     /// Show statistics for the generated snapshots.
     #[structopt(long = "stats")]
     show_stats: bool,
+    /// Only rescan these paths (which must be within the archive's inclusions) and
+    /// carry everything else over from the archive's most recent snapshot.
+    ///
+    /// Useful for a quick back up after making big changes in one part of a large
+    /// archive.  Requires that exactly one archive be nominated.
+    #[structopt(
+        long = "only",
+        value_name = "path",
+        number_of_values = 1,
+        parse(from_os_str)
+    )]
+    only: Vec<PathBuf>,
+    /// Only rescan the parts of the archive containing the paths listed (one per
+    /// line or NUL separated) in this file and carry everything else over from the
+    /// archive's most recent snapshot.
+    ///
+    /// The list can come from `find -newer` or an external change journal.  Listed
+    /// paths outside the archive's inclusions are ignored.  Requires that exactly one
+    /// archive be nominated.
+    #[structopt(
+        long = "changed-paths",
+        value_name = "file",
+        conflicts_with = "only",
+        parse(from_os_str)
+    )]
+    changed_paths_file: Option<PathBuf>,
+    /// Skip (with a warning) any file whose contents take more than this many
+    /// seconds to arrive (e.g. on a hung network file system).
+    #[structopt(long = "read-timeout", value_name = "secs")]
+    read_timeout: Option<u64>,
+    /// Leave at least this much free space (e.g. 500M or 10G) on the file systems
+    /// holding the repositories (instead of the "free_space_reserve" in the global
+    /// configuration).
+    #[structopt(long = "reserve", value_name = "size", parse(try_from_str = parse_size))]
+    reserve: Option<u64>,
+    /// What to do with files that won't fit without using the reserved free space:
+    /// "skip" them (with a warning) or "abort" the back up.
+    #[structopt(long = "on-low-space", value_name = "action")]
+    on_low_space: Option<LowSpaceAction>,
+    /// The order in which each directory's files are stored: "directory" (as listed
+    /// by the file system), "name" (the order they're extracted in), "extension" (by
+    /// extension then size) or "size" (smallest first).  Grouping similar files keeps
+    /// their contents together in the repository's pack files.
+    #[structopt(long = "order", value_name = "order", default_value = "directory")]
+    order: StorageOrder,
+    /// Warn about named pipes, sockets and device files (which are never backed up).
+    #[structopt(long = "report-special-files")]
+    report_special_files: bool,
+    /// Report the time taken by each phase of the back up, CPU time, peak memory use
+    /// and the bytes written to the repository (for tuning).
+    #[structopt(long = "resource-stats")]
+    resource_stats: bool,
+    /// Don't write a new snapshot for an archive if nothing (apart from times) has
+    /// changed since its most recent snapshot.
+    #[structopt(long = "skip-if-unchanged")]
+    skip_if_unchanged: bool,
+    /// Make the back up in the background and print its job id (see "ergibus jobs").
+    /// Requires that exactly one archive be nominated.
+    #[structopt(long = "detach")]
+    detach: bool,
+    /// Run as the nominated (previously reserved) job.
+    #[structopt(long = "job-id", hidden = true, conflicts_with = "detach")]
+    job_id: Option<JobId>,
+    /// Estimate the size of each archive's back up (by walking its inclusions without
+    /// reading or storing anything) and print it instead of backing up.
+    #[structopt(long = "estimate", conflicts_with_all = &["only", "changed-paths", "detach"])]
+    estimate: bool,
+    /// Show the progress of each back up on stderr as a percentage of a quick estimate
+    /// of its size (see --estimate) with the estimated time remaining.
+    #[structopt(
+        long = "progress",
+        conflicts_with_all = &["only", "changed-paths", "detach", "job-id"]
+    )]
+    progress: bool,
+    /// Also back up the member archives of this group (from the "groups" section of
+    /// the global configuration) and finish with a summary of the back ups.
+    #[structopt(long = "group", value_name = "name", number_of_values = 1)]
+    groups: Vec<String>,
     /// Names of archives for which back ups are to be made
-    #[structopt(required(true))]
+    #[structopt(required_unless = "groups")]
     archives: Vec<String>,
 }
 
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+fn show_progress(archive: &str, estimate: &BackUpEstimate, bytes: u64, elapsed: Duration) {
+    let fraction_done = estimate.fraction_done(0, bytes);
+    let remaining = match estimate.time_remaining(fraction_done, elapsed) {
+        Some(remaining) => format!("{}s", remaining.as_secs()),
+        None => "?".to_string(),
+    };
+    eprint!(
+        "\r{}: {:5.1}% ({} of about {} bytes), about {} remaining   ",
+        archive,
+        fraction_done * 100.0,
+        bytes,
+        estimate.byte_count,
+        remaining
+    );
+}
+
+// Run the back up showing its progress (relative to the estimate) on stderr.
+fn back_up_with_progress<F>(
+    archive: &str,
+    estimate: &BackUpEstimate,
+    back_up: F,
+) -> EResult<BackUpOutcome>
+where
+    F: FnOnce() -> EResult<BackUpOutcome> + Send + 'static,
+{
+    let started = Instant::now();
+    let mut next_show = started;
+    let mut bytes = 0;
+    for event in progress::observe(back_up) {
+        match event {
+            ProgressEvent::FileStored { bytes: size, .. } => {
+                bytes += size;
+                if Instant::now() >= next_show {
+                    show_progress(archive, estimate, bytes, started.elapsed());
+                    next_show = Instant::now() + PROGRESS_INTERVAL;
+                }
+            }
+            ProgressEvent::Finished { stats } => {
+                eprintln!("\r{}: done in {:?}{:40}", archive, started.elapsed(), "");
+                return Ok(stats);
+            }
+            ProgressEvent::Failed(err) => {
+                eprintln!();
+                return Err(err);
+            }
+            // warnings are passed to the warning sink by the back up itself
+            _ => (),
+        }
+    }
+    Err(Error::AsyncTaskFailed(
+        "back up ended without an outcome".to_string(),
+    ))
+}
+
+fn print_resource_stats(archive: &str, stats: &ResourceStats) {
+    println!("{}: resources used:", archive);
+    println!(
+        "\twall time: {:?} (scan {:?}, hash {:?}, store {:?}, serialize {:?})",
+        stats.wall_time,
+        stats.phase_times.scan,
+        stats.phase_times.hash,
+        stats.phase_times.store,
+        stats.phase_times.serialize
+    );
+    println!(
+        "\tCPU time: {:?} user, {:?} system",
+        stats.user_cpu_time, stats.system_cpu_time
+    );
+    println!("\tpeak RSS: {} KiB", stats.peak_rss_kib);
+    println!("\trepository bytes written: {}", stats.repo_bytes_written);
+}
+
+// The outcome of backing up several archives
+#[derive(Debug, Default)]
+struct BackUpSummary {
+    backed_up: usize,
+    unchanged: usize,
+    failed: Vec<String>,
+    file_count: u64,
+    byte_count: u64,
+    repo_growth: u64,
+}
+
+impl BackUpSummary {
+    fn print(&self) {
+        println!(
+            "{} archives: {} backed up, {} unchanged, {} failed",
+            self.backed_up + self.unchanged + self.failed.len(),
+            self.backed_up,
+            self.unchanged,
+            self.failed.len()
+        );
+        println!(
+            "\t{} files ({} bytes) backed up, repository growth {} bytes",
+            self.file_count, self.byte_count, self.repo_growth
+        );
+        if !self.failed.is_empty() {
+            println!("\tfailed: {}", self.failed.join(", "));
+        }
+    }
+}
+
 impl BackUp {
     pub fn exec(&self) -> EResult<()> {
+        let archives = global_config::expand_archive_groups(&self.archives, &self.groups)?;
+        if !self.only.is_empty() && archives.len() > 1 {
+            crate::usage_exit(
+                "--only may only be used with a single archive",
+                structopt::clap::ErrorKind::ArgumentConflict,
+            )
+        }
+        if self.changed_paths_file.is_some() && archives.len() > 1 {
+            crate::usage_exit(
+                "--changed-paths may only be used with a single archive",
+                structopt::clap::ErrorKind::ArgumentConflict,
+            )
+        }
+        if (self.detach || self.job_id.is_some()) && archives.len() > 1 {
+            crate::usage_exit(
+                "--detach may only be used with a single archive",
+                structopt::clap::ErrorKind::ArgumentConflict,
+            )
+        }
+        if self.estimate {
+            for archive in archives.iter() {
+                let estimate = estimate::estimate_back_up(archive)?;
+                println!(
+                    "{}: {} files ({} bytes) and {} sym links in {} dirs (estimated in {:?})",
+                    archive,
+                    estimate.file_count,
+                    estimate.byte_count,
+                    estimate.sym_link_count,
+                    estimate.dir_count,
+                    estimate.scan_duration
+                );
+            }
+            return Ok(());
+        }
+        if self.detach {
+            let description = format!("back up {}", archives[0]);
+            return jobs_sub_cmds::detach(JobKind::BackUp, &description);
+        }
+        let changed_paths = match self.changed_paths_file {
+            Some(ref file_path) => Some(snapshot::read_changed_paths(file_path)?),
+            None => None,
+        };
+        let mut options = BackUpOptions::from_global_config()?;
+        options.read_policy.timeout = self.read_timeout.map(Duration::from_secs);
+        if self.report_special_files {
+            options.read_policy.special_files = SpecialFilePolicy::Report;
+        }
+        options.read_policy.storage_order = self.order;
+        if let Some(reserve) = self.reserve {
+            options.free_space_reserve = Some(reserve);
+        }
+        if let Some(action) = self.on_low_space {
+            options.read_policy.on_low_space = action;
+        }
         let mut error_count = 0;
+        let mut summary = BackUpSummary::default();
         if self.show_stats {
             println!(
-                "{:>12} | {:>12} | {:>12} | {:>12} | {:>8} | {:>8} | {:>14} | {}",
+                "{:>12} | {:>12} | {:>12} | {:>12} | {:>12} | {:>12} | {:>12} | {:>8} | {:>8} | {:>14} | {}",
                 "#Files",
                 "#Bytes",
+                "#New",
+                "#Dup Repo",
+                "#Dup Snap",
                 "#Stored",
                 "#Change",
                 "#Dir SL",
@@ -253,15 +1074,78 @@ impl BackUp {
                 "Archive Name"
             );
         };
-        for archive in self.archives.iter() {
-            match snapshot::generate_snapshot(&archive) {
-                Ok(stats) => {
+        for archive in archives.iter() {
+            let back_up = {
+                let (archive, only, changed_paths, options) = (
+                    archive.clone(),
+                    self.only.clone(),
+                    changed_paths.clone(),
+                    options.clone(),
+                );
+                let skip_if_unchanged = self.skip_if_unchanged;
+                move || {
+                    if let Some(ref changed_paths) = changed_paths {
+                        snapshot::generate_snapshot_from_changes(
+                            &archive,
+                            changed_paths,
+                            skip_if_unchanged,
+                            &options,
+                        )
+                    } else if !only.is_empty() {
+                        snapshot::generate_partial_snapshot(
+                            &archive,
+                            &only,
+                            skip_if_unchanged,
+                            &options,
+                        )
+                    } else if skip_if_unchanged {
+                        snapshot::generate_snapshot_unless_unchanged(&archive, &options)
+                    } else {
+                        snapshot::generate_snapshot_with_options(&archive, &options)
+                            .map(BackUpOutcome::Written)
+                    }
+                }
+            };
+            let meter = ResourceMeter::start();
+            let result = match self.job_id {
+                Some(job_id) => JobRunner::persistent()
+                    .start_reserved(job_id, back_up)
+                    .and_then(|job| job.wait()),
+                None if self.progress => estimate::estimate_back_up(archive)
+                    .and_then(|estimate| back_up_with_progress(archive, &estimate, back_up)),
+                None => back_up(),
+            };
+            if self.resource_stats {
+                let counters = match result {
+                    Ok(BackUpOutcome::Written(ref stats)) => stats.4,
+                    _ => ResourceCounters::default(),
+                };
+                print_resource_stats(archive, &meter.finish(&counters));
+            }
+            reporters::report_back_up(&BackUpReport::new(archive, &result));
+            match result {
+                Ok(BackUpOutcome::Written(stats)) => {
+                    if stats.1.space_skipped_file_count > 0 {
+                        println!(
+                            "{}: {} files ({} bytes) skipped for lack of space",
+                            archive,
+                            stats.1.space_skipped_file_count,
+                            stats.1.space_skipped_byte_count
+                        );
+                    }
+                    summary.backed_up += 1;
+                    summary.file_count += stats.1.file_count;
+                    summary.byte_count += stats.1.byte_count;
+                    summary.repo_growth += stats.3;
                     if self.show_stats {
                         let time_taken = format!("{:?}", stats.0);
                         println!(
-                            "{:>12} | {:>12} | {:>12} | {:>12} | {:>8} | {:>8} | {:>14} | {}",
+                            "{:>12} | {:>12} | {:>12} | {:>12} | {:>12} | {:>12} | {:>12} | {:>8} | {:>8} | {:>14} | {}",
                             stats.1.file_count,
                             stats.1.byte_count,
+                            stats.1.new_byte_count,
+                            stats.1.repo_dedup_byte_count,
+                            stats.1.snapshot_dedup_byte_count,
                             stats.1.stored_byte_count,
                             stats.3,
                             stats.2.dir_sym_link_count,
@@ -270,13 +1154,31 @@ impl BackUp {
                             archive,
                         );
                     }
+                    if stats.1.depth_skipped_dir_count > 0 {
+                        println!(
+                            "{}: contents of {} directories at the maximum depth skipped",
+                            archive, stats.1.depth_skipped_dir_count
+                        );
+                    }
+                }
+                Ok(BackUpOutcome::Unchanged(latest_path)) => {
+                    summary.unchanged += 1;
+                    println!(
+                        "{}: unchanged since {:?}: no snapshot written",
+                        archive,
+                        latest_path.file_name().unwrap_or_default()
+                    );
                 }
                 Err(err) => {
-                    println!("{:?}: {}", err, archive);
+                    error!("{}: {}", archive, err);
                     error_count += 1;
+                    summary.failed.push(archive.clone());
                 }
             }
         }
+        if !self.groups.is_empty() {
+            summary.print();
+        }
         if error_count > 0 {
             Err(Error::SnapshotsFailed(error_count))
         } else {
@@ -0,0 +1,92 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Locale dependent formatting of the counts, sizes and durations shown
+//! in the GUI.  The locale is chosen in the preferences dialog and, if
+//! none has been chosen, is taken from the environment.
+
+use std::cell::Cell;
+use std::env;
+use std::time::Duration;
+
+use num_format::{Locale, ToFormattedString};
+
+use recollections;
+
+pub const LOCALE_KEY: &str = "preferences::locale";
+
+// Used when neither the preferences nor the environment nominate a locale
+const FALLBACK_LOCALE: Locale = Locale::en_AU;
+
+thread_local! {
+    static LOCALE: Cell<Option<Locale>> = Cell::new(None);
+}
+
+// Interpret a POSIX locale name such as "de_CH.UTF-8@euro"
+fn locale_from_posix_name(name: &str) -> Option<Locale> {
+    let name = name.split(|c| c == '.' || c == '@').next()?;
+    if name.is_empty() || name == "C" || name == "POSIX" {
+        return None;
+    }
+    let language = name.split('_').next().unwrap_or(name);
+    Locale::from_name(name)
+        .or_else(|_| Locale::from_name(language))
+        .ok()
+}
+
+pub fn environment_locale() -> Option<Locale> {
+    ["LC_ALL", "LC_NUMERIC", "LANG"]
+        .iter()
+        .filter_map(|envar| env::var(envar).ok())
+        .find(|value| !value.is_empty())
+        .and_then(|value| locale_from_posix_name(&value))
+}
+
+/// The name of the locale chosen in the preferences (if any).
+pub fn preferred_locale_name() -> Option<String> {
+    recollections::recall(LOCALE_KEY).filter(|name| Locale::from_name(name).is_ok())
+}
+
+/// Choose the locale to be used (`None` means use the environment's).
+pub fn set_preferred_locale_name(name: Option<&str>) {
+    recollections::remember(LOCALE_KEY, name.unwrap_or(""));
+    LOCALE.with(|locale| locale.set(None));
+}
+
+pub fn locale() -> Locale {
+    LOCALE.with(|cell| match cell.get() {
+        Some(locale) => locale,
+        None => {
+            let locale = match preferred_locale_name() {
+                Some(name) => Locale::from_name(name).unwrap_or(FALLBACK_LOCALE),
+                None => environment_locale().unwrap_or(FALLBACK_LOCALE),
+            };
+            cell.set(Some(locale));
+            locale
+        }
+    })
+}
+
+pub fn format_count<N: ToFormattedString>(number: N) -> String {
+    number.to_formatted_string(&locale())
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    format!("{:.1}s", duration.as_secs_f64()).replace('.', locale().decimal())
+}
+
+/// How long ago something happened in the largest whole unit (e.g. "3 hours ago").
+pub fn format_age(age: Duration) -> String {
+    let seconds = age.as_secs();
+    let (count, unit) = if seconds < 60 * 60 {
+        (seconds / 60, "minute")
+    } else if seconds < 2 * 24 * 60 * 60 {
+        (seconds / (60 * 60), "hour")
+    } else {
+        (seconds / (24 * 60 * 60), "day")
+    };
+    if count == 1 {
+        format!("1 {} ago", unit)
+    } else {
+        format!("{} {}s ago", format_count(count), unit)
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Taking snapshots from the GUI and telling the user how they went
+//! (including any files that had to be skipped).
+
+use pw_gtk_ext::{
+    gtk::{self, prelude::*},
+    wrapper::*,
+};
+
+use ergibus_lib::{snapshot, EResult};
+
+use crate::format::format_count;
+use crate::preferences;
+
+pub use ergibus_lib::snapshot::BackUpStats;
+
+/// Take a snapshot of the archive returning the outcome and the warnings
+/// (e.g. unreadable files that were skipped) generated along the way.
+pub fn back_up(archive_name: &str) -> (EResult<BackUpStats>, Vec<String>) {
+    snapshot::generate_snapshot_with_progress(archive_name).outcome_and_warnings()
+}
+
+/// Tell the user about a successful back up: a plain report (if they want
+/// one) unless there were warnings in which case those are shown too.
+pub fn report_back_up<W: DialogUser>(
+    parent: &W,
+    archive_name: &str,
+    stats: &BackUpStats,
+    warnings: &[String],
+) {
+    let report = preferences::back_up_report(stats);
+    if warnings.is_empty() {
+        if let Some(report) = report {
+            parent.inform_user(
+                &format!("Back up of \"{}\" complete", archive_name),
+                Some(&report),
+            );
+        }
+        return;
+    }
+    let dialog = parent
+        .new_dialog_builder()
+        .title("Back Up Warnings")
+        .destroy_with_parent(true)
+        .build();
+    let content_area = dialog.get_content_area();
+    let heading = gtk::Label::new(None);
+    heading.set_markup(&format!(
+        "<b>Back up of \"{}\" complete with {} warning{}</b>",
+        archive_name,
+        format_count(warnings.len() as u64),
+        if warnings.len() == 1 { "" } else { "s" }
+    ));
+    heading.set_xalign(0.0);
+    content_area.pack_start(&heading, false, false, 4);
+    if let Some(report) = report {
+        let label = gtk::Label::new(Some(&report));
+        label.set_xalign(0.0);
+        content_area.pack_start(&label, false, false, 4);
+    }
+    let text_view = gtk::TextView::new();
+    text_view.set_editable(false);
+    text_view.set_monospace(true);
+    if let Some(buffer) = text_view.get_buffer() {
+        buffer.set_text(&warnings.join("\n"));
+    }
+    let scrolled_window = gtk::ScrolledWindow::new(
+        Option::<&gtk::Adjustment>::None,
+        Option::<&gtk::Adjustment>::None,
+    );
+    scrolled_window.set_size_request(640, 240);
+    scrolled_window.add(&text_view);
+    let expander = gtk::Expander::new(Some("Details"));
+    expander.add(&scrolled_window);
+    content_area.pack_start(&expander, true, true, 0);
+    dialog.add_button("Close", gtk::ResponseType::Close);
+    dialog.connect_response(|dialog, _| dialog.close());
+    dialog.show_all();
+}
@@ -0,0 +1,156 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! A pie chart of the bytes in an archive's latest snapshot by file type
+//! (code, images, video, archives and other) to show what bloats its back
+//! ups.
+
+use std::cell::RefCell;
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use pw_gtk_ext::{
+    cairo,
+    gtk::{self, prelude::*},
+    wrapper::*,
+};
+
+use ergibus_lib::file_types::{FileCategory, TypeBreakdown};
+use ergibus_lib::snapshot::{self, Order};
+use ergibus_lib::EResult;
+
+use crate::format::format_count;
+
+const MARGIN: f64 = 8.0;
+const TEXT_HEIGHT: f64 = 14.0;
+const LEGEND_WIDTH: f64 = 220.0;
+
+// (red, green, blue) for each category's slice
+fn category_colour(category: FileCategory) -> (f64, f64, f64) {
+    match category {
+        FileCategory::Code => (0.2, 0.4, 0.8),
+        FileCategory::Images => (0.2, 0.7, 0.3),
+        FileCategory::Video => (0.8, 0.3, 0.2),
+        FileCategory::Archives => (0.9, 0.6, 0.1),
+        FileCategory::Other => (0.6, 0.6, 0.6),
+    }
+}
+
+fn draw_breakdown(
+    cairo_context: &cairo::Context,
+    width: f64,
+    height: f64,
+    breakdown: Option<&TypeBreakdown>,
+) {
+    cairo_context.set_source_rgb(1.0, 1.0, 1.0);
+    cairo_context.paint();
+    cairo_context.set_source_rgb(0.0, 0.0, 0.0);
+    let breakdown = match breakdown {
+        Some(breakdown) if breakdown.byte_count() > 0 => breakdown,
+        _ => {
+            cairo_context.move_to(MARGIN, MARGIN + TEXT_HEIGHT);
+            cairo_context.show_text("No file type breakdown recorded");
+            return;
+        }
+    };
+    let total_bytes = breakdown.byte_count() as f64;
+    let radius = ((width - LEGEND_WIDTH).min(height) / 2.0 - MARGIN).max(1.0);
+    let (centre_x, centre_y) = (MARGIN + radius, height / 2.0);
+    let mut angle = -PI / 2.0;
+    let by_size = breakdown.by_size();
+    for (category, stats) in by_size.iter() {
+        if stats.byte_count == 0 {
+            continue;
+        }
+        let sweep = 2.0 * PI * stats.byte_count as f64 / total_bytes;
+        let (red, green, blue) = category_colour(*category);
+        cairo_context.set_source_rgb(red, green, blue);
+        cairo_context.move_to(centre_x, centre_y);
+        cairo_context.arc(centre_x, centre_y, radius, angle, angle + sweep);
+        cairo_context.close_path();
+        cairo_context.fill();
+        angle += sweep;
+    }
+
+    // The legend lists the categories largest first
+    let legend_x = centre_x + radius + 2.0 * MARGIN;
+    let mut legend_y = MARGIN + TEXT_HEIGHT;
+    for (category, stats) in by_size.iter() {
+        let (red, green, blue) = category_colour(*category);
+        cairo_context.set_source_rgb(red, green, blue);
+        cairo_context.rectangle(legend_x, legend_y - 10.0, 10.0, 10.0);
+        cairo_context.fill();
+        cairo_context.set_source_rgb(0.0, 0.0, 0.0);
+        cairo_context.move_to(legend_x + 16.0, legend_y);
+        cairo_context.show_text(&format!(
+            "{}: {} bytes in {} files ({:.1}%)",
+            category,
+            format_count(stats.byte_count),
+            format_count(stats.file_count),
+            stats.byte_count as f64 * 100.0 / total_bytes
+        ));
+        legend_y += TEXT_HEIGHT + 4.0;
+    }
+}
+
+// The breakdown recorded in the statistics of the archive's latest snapshot
+fn latest_breakdown(archive_name: &str) -> EResult<Option<TypeBreakdown>> {
+    match snapshot::iter_snapshot_names_for_archive(archive_name, Order::Descending)?.next() {
+        Some(snapshot_name) => {
+            let stats = snapshot::get_snapshot_stats(archive_name, &snapshot_name)?;
+            Ok(Some(stats.file_stats.type_breakdown))
+        }
+        None => Ok(None),
+    }
+}
+
+#[derive(PWO, Wrapper)]
+pub struct BreakdownChartCore {
+    drawing_area: gtk::DrawingArea,
+    breakdown: RefCell<Option<TypeBreakdown>>,
+}
+
+#[derive(PWO, Wrapper, WClone)]
+pub struct BreakdownChart(Rc<BreakdownChartCore>);
+
+impl BreakdownChart {
+    pub fn new() -> Self {
+        let drawing_area = gtk::DrawingArea::new();
+        drawing_area.set_size_request(360, 160);
+        let chart = Self(Rc::new(BreakdownChartCore {
+            drawing_area,
+            breakdown: RefCell::new(None),
+        }));
+
+        let chart_clone = chart.clone();
+        chart
+            .0
+            .drawing_area
+            .connect_draw(move |drawing_area, cairo_context| {
+                draw_breakdown(
+                    cairo_context,
+                    drawing_area.get_allocated_width() as f64,
+                    drawing_area.get_allocated_height() as f64,
+                    chart_clone.0.breakdown.borrow().as_ref(),
+                );
+                gtk::Inhibit(false)
+            });
+
+        chart
+    }
+
+    /// Show the breakdown of the named archive's latest snapshot (or clear the chart).
+    pub fn set_archive_name(&self, archive_name: Option<&str>) {
+        let breakdown = match archive_name {
+            Some(archive_name) => match latest_breakdown(archive_name) {
+                Ok(breakdown) => breakdown,
+                Err(err) => {
+                    log::error!("{}: file type breakdown: {}", archive_name, err);
+                    None
+                }
+            },
+            None => None,
+        };
+        *self.0.breakdown.borrow_mut() = breakdown;
+        self.0.drawing_area.queue_draw();
+    }
+}
@@ -0,0 +1,343 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::time::Duration;
+
+use pw_gtk_ext::{
+    gtk::{self, prelude::*},
+    wrapper::*,
+};
+
+use dychatat_lib::content::{self, RepoUsage};
+use ergibus_lib::archive;
+use ergibus_lib::snapshot::{self, ArchiveFreshness, ArchiveHealth, ArchiveSummary};
+use ergibus_lib::EResult;
+use recollections;
+
+use crate::format::{format_age, format_count};
+use crate::g_back_up;
+
+const HEADINGS: [&str; 6] = [
+    "Archive",
+    "Last Back Up",
+    "Status",
+    "#Bytes",
+    "#Snapshots",
+    "",
+];
+
+const QUOTA_HEADINGS: [&str; 4] = ["Repository", "Quota", "Used", "#Bytes"];
+
+const SORT_ORDER_KEY: &str = "dashboard::sort_order";
+
+// The orders in which the archives can be listed (combo box id and label)
+const SORT_ORDERS: [(&str, &str); 3] = [
+    ("name", "name"),
+    ("staleness", "staleness (stalest first)"),
+    ("size", "size (largest first)"),
+];
+
+fn health_colour(health: ArchiveHealth) -> &'static str {
+    match health {
+        ArchiveHealth::Ok => "green",
+        ArchiveHealth::Stale => "orange",
+        ArchiveHealth::Overdue => "red",
+    }
+}
+
+// The colour and text used to display an archive's status
+fn status_markup(health: ArchiveHealth, failed: bool) -> String {
+    let (colour, text) = if failed {
+        ("red", "failed")
+    } else {
+        match health {
+            ArchiveHealth::Ok => (health_colour(health), "ok"),
+            ArchiveHealth::Stale | ArchiveHealth::Overdue => (health_colour(health), "stale"),
+        }
+    };
+    format!("<span foreground=\"{}\"><b>{}</b></span>", colour, text)
+}
+
+/// The age of an archive's newest snapshot coloured by its health.
+pub fn age_markup(age: Option<Duration>, health: ArchiveHealth) -> String {
+    let text = match age {
+        Some(age) => format_age(age),
+        None => "never backed up".to_string(),
+    };
+    format!(
+        "<span foreground=\"{}\">{}</span>",
+        health_colour(health),
+        text
+    )
+}
+
+/// Markup describing how recently the archive was backed up.
+pub fn freshness_markup(freshness: &ArchiveFreshness) -> String {
+    format!(
+        "Last back up: {}",
+        age_markup(freshness.age, freshness.health)
+    )
+}
+
+fn summary_labels(summary: &ArchiveSummary) -> [String; 3] {
+    let last_back_up = match summary.last_snapshot_time {
+        Some(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+        None => "never".to_string(),
+    };
+    let byte_count = match summary.last_snapshot_stats {
+        Some(ref stats) => format_count(stats.file_stats.byte_count),
+        None => "-".to_string(),
+    };
+    [
+        last_back_up,
+        byte_count,
+        format_count(summary.snapshot_count),
+    ]
+}
+
+fn summary_byte_count(summary: &ArchiveSummary) -> u64 {
+    summary
+        .last_snapshot_stats
+        .as_ref()
+        .map(|stats| stats.file_stats.byte_count)
+        .unwrap_or(0)
+}
+
+// Sort the archives' summaries (those that couldn't be read last)
+fn sort_summaries(summaries: &mut [(String, EResult<ArchiveSummary>)], sort_order: &str) {
+    summaries.sort_by(|(a_name, a), (b_name, b)| match (a, b) {
+        (Ok(a), Ok(b)) => {
+            let ordering = match sort_order {
+                // archives never backed up are the stalest
+                "staleness" => match (a.age, b.age) {
+                    (Some(a_age), Some(b_age)) => b_age.cmp(&a_age),
+                    (a_age, b_age) => a_age.is_some().cmp(&b_age.is_some()),
+                },
+                "size" => summary_byte_count(b).cmp(&summary_byte_count(a)),
+                _ => Ordering::Equal,
+            };
+            ordering.then_with(|| a_name.cmp(b_name))
+        }
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a_name.cmp(b_name),
+    });
+}
+
+// The colour and text used to display a repository's quota usage
+fn quota_markup(usage: &RepoUsage, max_size: u64) -> String {
+    let percent = if max_size > 0 {
+        usage.stored_bytes as f64 * 100.0 / max_size as f64
+    } else {
+        100.0
+    };
+    let colour = if percent >= 95.0 {
+        "red"
+    } else if percent >= 80.0 {
+        "orange"
+    } else {
+        "green"
+    };
+    format!(
+        "<span foreground=\"{}\"><b>{:.1}%</b></span>",
+        colour, percent
+    )
+}
+
+#[derive(PWO, Wrapper)]
+pub struct ArchiveDashboardCore {
+    vbox: gtk::Box,
+    grid: gtk::Grid,
+    sort_order_combo: gtk::ComboBoxText,
+    // Archives whose most recent back up from the dashboard failed
+    failed: RefCell<HashSet<String>>,
+}
+
+#[derive(PWO, Wrapper, WClone)]
+pub struct ArchiveDashboard(Rc<ArchiveDashboardCore>);
+
+impl ArchiveDashboard {
+    pub fn new() -> Self {
+        let vbox = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        let refresh_button = gtk::Button::with_label("Refresh");
+        hbox.pack_start(&refresh_button, false, false, 0);
+        let sort_order_combo = gtk::ComboBoxText::new();
+        for (id, label) in SORT_ORDERS.iter() {
+            sort_order_combo.append(Some(*id), label);
+        }
+        let sort_order = recollections::recall(SORT_ORDER_KEY).unwrap_or_default();
+        if !sort_order_combo.set_active_id(Some(&sort_order)) {
+            sort_order_combo.set_active_id(Some(SORT_ORDERS[0].0));
+        }
+        hbox.pack_end(&sort_order_combo, false, false, 0);
+        hbox.pack_end(&gtk::Label::new(Some("Sort by:")), false, false, 6);
+        vbox.pack_start(&hbox, false, false, 0);
+        let grid = gtk::GridBuilder::new()
+            .column_spacing(12)
+            .row_spacing(4)
+            .margin(6)
+            .build();
+        let scrolled_window = gtk::ScrolledWindow::new(
+            Option::<&gtk::Adjustment>::None,
+            Option::<&gtk::Adjustment>::None,
+        );
+        scrolled_window.add(&grid);
+        vbox.pack_start(&scrolled_window, true, true, 0);
+        let dashboard = Self(Rc::new(ArchiveDashboardCore {
+            vbox,
+            grid,
+            sort_order_combo,
+            failed: RefCell::new(HashSet::new()),
+        }));
+
+        let dashboard_clone = dashboard.clone();
+        refresh_button.connect_clicked(move |_| dashboard_clone.refresh());
+
+        let dashboard_clone = dashboard.clone();
+        dashboard.0.sort_order_combo.connect_changed(move |combo| {
+            if let Some(id) = combo.get_active_id() {
+                recollections::remember(SORT_ORDER_KEY, id.as_str());
+            }
+            dashboard_clone.refresh()
+        });
+
+        dashboard.refresh();
+        dashboard
+    }
+
+    pub fn refresh(&self) {
+        let grid = &self.0.grid;
+        for child in grid.get_children() {
+            grid.remove(&child);
+        }
+        for (column, heading) in HEADINGS.iter().enumerate() {
+            let label = gtk::Label::new(None);
+            label.set_markup(&format!("<b>{}</b>", heading));
+            label.set_xalign(0.0);
+            grid.attach(&label, column as i32, 0, 1, 1);
+        }
+        let mut summaries: Vec<(String, EResult<ArchiveSummary>)> = archive::get_archive_names()
+            .into_iter()
+            .map(|archive_name| {
+                let summary = snapshot::get_archive_summary(&archive_name);
+                (archive_name, summary)
+            })
+            .collect();
+        let sort_order = self
+            .0
+            .sort_order_combo
+            .get_active_id()
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+        sort_summaries(&mut summaries, &sort_order);
+        for (index, (archive_name, summary)) in summaries.iter().enumerate() {
+            let row = index as i32 + 1;
+            let name_label = gtk::Label::new(Some(archive_name));
+            name_label.set_xalign(0.0);
+            grid.attach(&name_label, 0, row, 1, 1);
+            let failed = self.0.failed.borrow().contains(archive_name);
+            let status_label = gtk::Label::new(None);
+            match summary {
+                Ok(summary) => {
+                    let [last_back_up, byte_count, snapshot_count] = summary_labels(summary);
+                    let last_back_up_label = gtk::Label::new(None);
+                    match summary.age {
+                        Some(age) => last_back_up_label.set_markup(&format!(
+                            "{} ({})",
+                            last_back_up,
+                            age_markup(Some(age), summary.health)
+                        )),
+                        None => last_back_up_label.set_text(&last_back_up),
+                    }
+                    last_back_up_label.set_xalign(1.0);
+                    grid.attach(&last_back_up_label, 1, row, 1, 1);
+                    for (column, text) in [(3, byte_count), (4, snapshot_count)].iter() {
+                        let label = gtk::Label::new(Some(text));
+                        label.set_xalign(1.0);
+                        grid.attach(&label, *column, row, 1, 1);
+                    }
+                    status_label.set_markup(&status_markup(summary.health, failed));
+                    status_label.set_tooltip_text(Some(&format!(
+                        "expected every {} hours",
+                        summary.backup_interval.as_secs() / 3600
+                    )));
+                }
+                Err(err) => {
+                    status_label.set_markup(&status_markup(ArchiveHealth::Overdue, true));
+                    status_label.set_tooltip_text(Some(&err.to_string()));
+                }
+            }
+            grid.attach(&status_label, 2, row, 1, 1);
+            let back_up_button = gtk::Button::with_label("Back up now");
+            let dashboard_clone = self.clone();
+            let archive_name_clone = archive_name.clone();
+            back_up_button.connect_clicked(move |_| dashboard_clone.back_up(&archive_name_clone));
+            grid.attach(&back_up_button, 5, row, 1, 1);
+        }
+        self.add_quota_rows(summaries.len() as i32 + 2);
+        grid.show_all();
+    }
+
+    // Rows for the repositories that have quotas (starting at `row`)
+    fn add_quota_rows(&self, mut row: i32) {
+        let grid = &self.0.grid;
+        let mut repo_names = content::get_repo_names();
+        repo_names.sort();
+        let mut headed = false;
+        for repo_name in repo_names.iter() {
+            let usage = match content::get_repo_usage(repo_name) {
+                Ok(usage) => usage,
+                Err(err) => {
+                    log::warn!("{}: {}", repo_name, err);
+                    continue;
+                }
+            };
+            let max_size = match usage.max_size {
+                Some(max_size) => max_size,
+                None => continue,
+            };
+            if !headed {
+                for (column, heading) in QUOTA_HEADINGS.iter().enumerate() {
+                    let label = gtk::Label::new(None);
+                    label.set_markup(&format!("<b>{}</b>", heading));
+                    label.set_xalign(0.0);
+                    grid.attach(&label, column as i32, row, 1, 1);
+                }
+                row += 1;
+                headed = true;
+            }
+            let name_label = gtk::Label::new(Some(repo_name));
+            name_label.set_xalign(0.0);
+            grid.attach(&name_label, 0, row, 1, 1);
+            let quota_label = gtk::Label::new(Some(&format_count(max_size)));
+            quota_label.set_xalign(1.0);
+            grid.attach(&quota_label, 1, row, 1, 1);
+            let used_label = gtk::Label::new(None);
+            used_label.set_markup(&quota_markup(&usage, max_size));
+            grid.attach(&used_label, 2, row, 1, 1);
+            let bytes_label = gtk::Label::new(Some(&format_count(usage.stored_bytes)));
+            bytes_label.set_xalign(1.0);
+            grid.attach(&bytes_label, 3, row, 1, 1);
+            row += 1;
+        }
+    }
+
+    fn back_up(&self, archive_name: &str) {
+        let cursor = self.show_busy();
+        let (result, warnings) = g_back_up::back_up(archive_name);
+        self.unshow_busy(cursor);
+        match result {
+            Ok(stats) => {
+                self.0.failed.borrow_mut().remove(archive_name);
+                g_back_up::report_back_up(self, archive_name, &stats, &warnings);
+            }
+            Err(err) => {
+                self.0.failed.borrow_mut().insert(archive_name.to_string());
+                self.report_error(&format!("Back up of \"{}\" failed", archive_name), &err);
+            }
+        }
+        self.refresh();
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! A chart of an archive's back up history (the bytes stored and the time
+//! taken by each snapshot) so that sudden growth or slowdowns stand out.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use pw_gtk_ext::{
+    cairo,
+    gtk::{self, prelude::*},
+    wrapper::*,
+};
+
+use ergibus_lib::snapshot::{self, HistoryEntry};
+
+use crate::format::{format_count, format_duration};
+
+const MARGIN: f64 = 8.0;
+const TEXT_HEIGHT: f64 = 14.0;
+// (red, green, blue) for each series
+const BYTES_COLOUR: (f64, f64, f64) = (0.2, 0.4, 0.8);
+const DURATION_COLOUR: (f64, f64, f64) = (0.8, 0.4, 0.1);
+
+// Draw a series scaled so that its maximum value reaches the top of the chart.
+fn draw_series(
+    cairo_context: &cairo::Context,
+    values: &[f64],
+    (x, y, width, height): (f64, f64, f64, f64),
+    (red, green, blue): (f64, f64, f64),
+) {
+    let max = values.iter().cloned().fold(0.0, f64::max);
+    if max <= 0.0 {
+        return;
+    }
+    let x_step = if values.len() > 1 {
+        width / (values.len() - 1) as f64
+    } else {
+        0.0
+    };
+    cairo_context.set_source_rgb(red, green, blue);
+    cairo_context.set_line_width(2.0);
+    for (index, value) in values.iter().enumerate() {
+        let point_x = x + x_step * index as f64;
+        let point_y = y + height * (1.0 - value / max);
+        if index == 0 {
+            cairo_context.move_to(point_x, point_y);
+        } else {
+            cairo_context.line_to(point_x, point_y);
+        }
+    }
+    cairo_context.stroke();
+    if values.len() == 1 {
+        cairo_context.arc(x, y, 3.0, 0.0, 2.0 * std::f64::consts::PI);
+        cairo_context.fill();
+    }
+}
+
+fn draw_history(cairo_context: &cairo::Context, width: f64, height: f64, history: &[HistoryEntry]) {
+    cairo_context.set_source_rgb(1.0, 1.0, 1.0);
+    cairo_context.paint();
+    cairo_context.set_source_rgb(0.0, 0.0, 0.0);
+    if history.is_empty() {
+        cairo_context.move_to(MARGIN, MARGIN + TEXT_HEIGHT);
+        cairo_context.show_text("No back ups");
+        return;
+    }
+    let plot_x = MARGIN;
+    let plot_y = MARGIN + TEXT_HEIGHT + MARGIN;
+    let plot_width = (width - 2.0 * MARGIN).max(1.0);
+    let plot_height = (height - plot_y - MARGIN - TEXT_HEIGHT).max(1.0);
+    cairo_context.set_line_width(1.0);
+    cairo_context.rectangle(plot_x, plot_y, plot_width, plot_height);
+    cairo_context.stroke();
+
+    let bytes: Vec<f64> = history
+        .iter()
+        .map(|entry| entry.stats.file_stats.stored_byte_count as f64)
+        .collect();
+    let durations: Vec<f64> = history
+        .iter()
+        .map(|entry| entry.stats.creation_duration.as_secs_f64())
+        .collect();
+    let plot_area = (plot_x, plot_y, plot_width, plot_height);
+    draw_series(cairo_context, &bytes, plot_area, BYTES_COLOUR);
+    draw_series(cairo_context, &durations, plot_area, DURATION_COLOUR);
+
+    // Legends show the latest values (and the maxima the series are scaled to)
+    let latest = &history[history.len() - 1];
+    let max_bytes = history
+        .iter()
+        .map(|entry| entry.stats.file_stats.stored_byte_count)
+        .max()
+        .unwrap_or(0);
+    let max_duration = history
+        .iter()
+        .map(|entry| entry.stats.creation_duration)
+        .max()
+        .unwrap_or_default();
+    let (red, green, blue) = BYTES_COLOUR;
+    cairo_context.set_source_rgb(red, green, blue);
+    cairo_context.move_to(MARGIN, MARGIN + TEXT_HEIGHT);
+    cairo_context.show_text(&format!(
+        "Bytes stored: {} (max {})",
+        format_count(latest.stats.file_stats.stored_byte_count),
+        format_count(max_bytes)
+    ));
+    let (red, green, blue) = DURATION_COLOUR;
+    cairo_context.set_source_rgb(red, green, blue);
+    cairo_context.move_to(MARGIN + width / 2.0, MARGIN + TEXT_HEIGHT);
+    cairo_context.show_text(&format!(
+        "Time taken: {} (max {})",
+        format_duration(latest.stats.creation_duration),
+        format_duration(max_duration)
+    ));
+    cairo_context.set_source_rgb(0.0, 0.0, 0.0);
+    cairo_context.move_to(MARGIN, height - MARGIN);
+    cairo_context.show_text(&history[0].time.format("%Y-%m-%d %H:%M").to_string());
+    if history.len() > 1 {
+        let last_time = latest.time.format("%Y-%m-%d %H:%M").to_string();
+        let extents = cairo_context.text_extents(&last_time);
+        cairo_context.move_to(width - MARGIN - extents.width, height - MARGIN);
+        cairo_context.show_text(&last_time);
+    }
+}
+
+#[derive(PWO, Wrapper)]
+pub struct HistoryChartCore {
+    drawing_area: gtk::DrawingArea,
+    history: RefCell<Vec<HistoryEntry>>,
+}
+
+#[derive(PWO, Wrapper, WClone)]
+pub struct HistoryChart(Rc<HistoryChartCore>);
+
+impl HistoryChart {
+    pub fn new() -> Self {
+        let drawing_area = gtk::DrawingArea::new();
+        drawing_area.set_size_request(200, 160);
+        let chart = Self(Rc::new(HistoryChartCore {
+            drawing_area,
+            history: RefCell::new(vec![]),
+        }));
+
+        let chart_clone = chart.clone();
+        chart
+            .0
+            .drawing_area
+            .connect_draw(move |drawing_area, cairo_context| {
+                draw_history(
+                    cairo_context,
+                    drawing_area.get_allocated_width() as f64,
+                    drawing_area.get_allocated_height() as f64,
+                    &chart_clone.0.history.borrow(),
+                );
+                gtk::Inhibit(false)
+            });
+
+        chart
+    }
+
+    /// Reload the named archive's history (or clear the chart).
+    pub fn set_archive_name(&self, archive_name: Option<&str>) {
+        let history = match archive_name {
+            Some(archive_name) => match snapshot::get_snapshot_history(archive_name) {
+                Ok(history) => history,
+                Err(err) => {
+                    log::error!("{}: history: {}", archive_name, err);
+                    vec![]
+                }
+            },
+            None => vec![],
+        };
+        *self.0.history.borrow_mut() = history;
+        self.0.drawing_area.queue_draw();
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+use pw_gtk_ext::{
+    gtk::{self, prelude::*},
+    wrapper::*,
+};
+
+use num_format::Locale;
+
+use ergibus_lib::archive;
+
+use crate::format;
+use crate::preferences::{self, ProgressVerbosity};
+
+// Combo box id for "use the environment's locale"
+const ENVIRONMENT_ID: &str = "";
+// Combo box id for "start with the last archive used"
+const LAST_USED_ID: &str = "";
+
+fn new_locale_combo() -> gtk::ComboBoxText {
+    let combo = gtk::ComboBoxText::new();
+    let environment_label = match format::environment_locale() {
+        Some(locale) => format!("from environment ({})", locale.name()),
+        None => "from environment".to_string(),
+    };
+    combo.append(Some(ENVIRONMENT_ID), &environment_label);
+    for name in Locale::available_names() {
+        combo.append(Some(name), name);
+    }
+    let active_id = format::preferred_locale_name().unwrap_or_default();
+    if !combo.set_active_id(Some(&active_id)) {
+        combo.set_active_id(Some(ENVIRONMENT_ID));
+    }
+    combo
+}
+
+fn new_default_archive_combo() -> gtk::ComboBoxText {
+    let combo = gtk::ComboBoxText::new();
+    combo.append(Some(LAST_USED_ID), "the last one used");
+    for archive_name in archive::get_archive_names() {
+        combo.append(Some(&archive_name), &archive_name);
+    }
+    let active_id = preferences::default_archive().unwrap_or_default();
+    if !combo.set_active_id(Some(&active_id)) {
+        combo.set_active_id(Some(LAST_USED_ID));
+    }
+    combo
+}
+
+fn new_verbosity_combo() -> gtk::ComboBoxText {
+    let combo = gtk::ComboBoxText::new();
+    for verbosity in ProgressVerbosity::ALL.iter() {
+        combo.append(Some(verbosity.as_str()), verbosity.as_str());
+    }
+    combo.set_active_id(Some(preferences::progress_verbosity().as_str()));
+    combo
+}
+
+fn attach_labelled(grid: &gtk::Grid, row: i32, text: &str, widget: &impl IsA<gtk::Widget>) {
+    let label = gtk::Label::new(Some(text));
+    label.set_xalign(0.0);
+    grid.attach(&label, 0, row, 1, 1);
+    grid.attach(widget, 1, row, 1, 1);
+}
+
+/// Show the preferences dialog.  Returns true if the preferences were
+/// changed (and views need to be redrawn).
+pub fn show_preferences_dialog<W: DialogUser>(parent: &W) -> bool {
+    let dialog = parent
+        .new_dialog_builder()
+        .title("Preferences")
+        .modal(true)
+        .destroy_with_parent(true)
+        .build();
+    for (label, response) in W::CANCEL_OK_BUTTONS.iter() {
+        dialog.add_button(label, *response);
+    }
+    let grid = gtk::GridBuilder::new()
+        .column_spacing(12)
+        .row_spacing(4)
+        .margin(6)
+        .build();
+    let default_archive_combo = new_default_archive_combo();
+    attach_labelled(
+        &grid,
+        0,
+        "Archive shown at start up:",
+        &default_archive_combo,
+    );
+    let locale_combo = new_locale_combo();
+    attach_labelled(&grid, 1, "Number format:", &locale_combo);
+    let verbosity_combo = new_verbosity_combo();
+    attach_labelled(&grid, 2, "Back up reports:", &verbosity_combo);
+    let confirm_delete_button = gtk::CheckButton::with_label("Confirm before deleting snapshots");
+    confirm_delete_button.set_active(preferences::confirm_before_delete());
+    grid.attach(&confirm_delete_button, 0, 3, 2, 1);
+    let show_hidden_button = gtk::CheckButton::with_label("Show hidden files in snapshots");
+    show_hidden_button.set_active(preferences::show_hidden_files());
+    grid.attach(&show_hidden_button, 0, 4, 2, 1);
+    dialog.get_content_area().pack_start(&grid, true, true, 0);
+    dialog.show_all();
+    let mut changed = false;
+    if dialog.run() == gtk::ResponseType::Ok {
+        let default_archive = default_archive_combo
+            .get_active_id()
+            .map(|id| id.to_string())
+            .filter(|id| id != LAST_USED_ID);
+        preferences::set_default_archive(default_archive.as_deref());
+        if let Some(verbosity) = verbosity_combo
+            .get_active_id()
+            .and_then(|id| id.parse::<ProgressVerbosity>().ok())
+        {
+            preferences::set_progress_verbosity(verbosity);
+        }
+        preferences::set_confirm_before_delete(confirm_delete_button.get_active());
+        let show_hidden = show_hidden_button.get_active();
+        if show_hidden != preferences::show_hidden_files() {
+            preferences::set_show_hidden_files(show_hidden);
+            changed = true;
+        }
+        let locale_name = locale_combo
+            .get_active_id()
+            .map(|id| id.to_string())
+            .filter(|id| id != ENVIRONMENT_ID);
+        if locale_name != format::preferred_locale_name() {
+            format::set_preferred_locale_name(locale_name.as_deref());
+            changed = true;
+        }
+    }
+    dialog.close();
+    changed
+}
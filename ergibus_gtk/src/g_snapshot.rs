@@ -1,5 +1,8 @@
 use std::cell::RefCell;
 use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::process::Command;
 use std::rc::Rc;
 
 use pw_gtk_ext::{
@@ -8,20 +11,31 @@ use pw_gtk_ext::{
     UNEXPECTED,
 };
 
-use ergibus_lib::{snapshot, EResult};
+use ergibus_lib::i18n::translate_static;
+use ergibus_lib::{snapshot, tr, EResult, Error};
 
+use crate::format::format_count;
+use crate::g_snapshots::archive_key;
 use crate::icons;
+use crate::preferences;
 use dychatat_lib::content::Mutability;
-use ergibus_lib::fs_objects::{DirectoryData, ExtractionStats, FileSystemObject, Name};
+use ergibus_lib::fs_objects::{
+    self, CopyOptions, DirectoryData, ExtractionStats, FileData, FileSystemObject, Name,
+};
 use ergibus_lib::snapshot::SnapshotPersistentData;
+use ergibus_lib::staging::StagingArea;
 use pw_gtk_ext::glib::{Type, Value};
 use pw_gtk_ext::gtk::ButtonBuilder;
 use pw_gtk_ext::gtkx::list_store::{ListRowOps, ListViewSpec, WrappedListStore};
 use pw_gtk_ext::gtkx::menu::MenuItemSpec;
 use pw_gtk_ext::gtkx::tree_view::{TreeViewWithPopup, TreeViewWithPopupBuilder};
-use pw_gtk_ext::sav_state::SAV_SELN_MADE;
+use pw_gtk_ext::sav_state::{SAV_SELN_MADE, SAV_SELN_UNIQUE};
+use recollections;
 use std::path::{Path, PathBuf};
 
+// How much of a file is shown by "Preview"
+const PREVIEW_BYTES: usize = 16 * 1024;
+
 #[derive(PWO)]
 pub struct CurrentDirectoryManagerCore {
     h_box: gtk::Box,
@@ -78,9 +92,18 @@ pub struct SnapshotManagerCore {
     v_box: gtk::Box,
     list_view: Rc<TreeViewWithPopup>,
     list_store: WrappedListStore<SnapshotManagerSpec>,
+    list_window: gtk::ScrolledWindow,
+    search_entry: gtk::SearchEntry,
+    search_view: Rc<TreeViewWithPopup>,
+    search_store: WrappedListStore<SearchResultsSpec>,
+    search_window: gtk::ScrolledWindow,
+    search_results: RefCell<Vec<PathBuf>>,
     snapshot: SnapshotPersistentData,
+    archive_name: String,
     current_directory_manager: CurrentDirectoryManager,
     curr_dir_path: RefCell<PathBuf>,
+    // where files are extracted for "Open" (removed when the tab is closed)
+    opened_files_dir: RefCell<Option<StagingArea>>,
 }
 
 #[derive(PWO, WClone, Wrapper)]
@@ -112,15 +135,50 @@ impl ListViewSpec for SnapshotManagerSpec {
     }
 }
 
+#[derive(Default)]
+struct SearchResultsSpec;
+
+impl ListViewSpec for SearchResultsSpec {
+    fn column_types() -> Vec<Type> {
+        vec![Type::U32, Type::String]
+    }
+
+    fn columns() -> Vec<gtk::TreeViewColumn> {
+        let col = gtk::TreeViewColumnBuilder::new()
+            .title("Path")
+            .expand(false)
+            .resizable(false)
+            .build();
+
+        let cell = gtk::CellRendererTextBuilder::new()
+            .editable(false)
+            .xalign(0.0)
+            .build();
+
+        col.pack_start(&cell, false);
+        col.add_attribute(&cell, "text", 1);
+        vec![col]
+    }
+}
+
 impl SnapshotManager {
     pub fn new(archive_name: &str, snapshot_name: &OsStr) -> EResult<Self> {
         let snapshot = snapshot::get_named_snapshot(archive_name, snapshot_name)?;
         let base_dir_path = snapshot.base_dir_path().to_path_buf();
-        let current_directory_manager = CurrentDirectoryManager::new(&base_dir_path);
+        // resume browsing where the user left off (if it's in this snapshot)
+        let curr_dir_path = match recollections::recall(&archive_key(archive_name, "current_dir")) {
+            Some(dir_path) if snapshot.find_subdir(&dir_path).is_ok() => PathBuf::from(dir_path),
+            _ => base_dir_path.clone(),
+        };
+        let current_directory_manager = CurrentDirectoryManager::new(&curr_dir_path);
         let v_box = gtk::BoxBuilder::new()
             .orientation(gtk::Orientation::Vertical)
             .build();
         v_box.pack_start(current_directory_manager.pwo(), false, false, 0);
+        let search_entry = gtk::SearchEntryBuilder::new()
+            .tooltip_text("Search the whole snapshot for names containing the text or matching a glob pattern.")
+            .build();
+        v_box.pack_start(&search_entry, false, false, 0);
         let list_store = WrappedListStore::<SnapshotManagerSpec>::new();
         let list_view = TreeViewWithPopupBuilder::new()
             .enable_grid_lines(gtk::TreeViewGridLines::Horizontal)
@@ -129,29 +187,77 @@ impl SnapshotManager {
             .menu_item((
                 "extract_to",
                 MenuItemSpec(
-                    "Extract To",
+                    translate_static("gui-extract-to"),
                     None,
-                    Some("Extract selected items to nominated directory."),
+                    Some(translate_static("gui-extract-to-tip")),
                 ),
                 SAV_SELN_MADE,
             ))
+            .menu_item((
+                "preview",
+                MenuItemSpec(
+                    translate_static("gui-preview"),
+                    None,
+                    Some(translate_static("gui-preview-tip")),
+                ),
+                SAV_SELN_UNIQUE,
+            ))
+            .menu_item((
+                "open",
+                MenuItemSpec(
+                    translate_static("gui-open"),
+                    None,
+                    Some(translate_static("gui-open-tip")),
+                ),
+                SAV_SELN_UNIQUE,
+            ))
             .build(&list_store);
-        let scrolled_window = gtk::ScrolledWindow::new(
+        let list_window = gtk::ScrolledWindow::new(
             Option::<&gtk::Adjustment>::None,
             Option::<&gtk::Adjustment>::None,
         );
-        scrolled_window.add(list_view.pwo());
-        v_box.pack_start(&scrolled_window, true, true, 0);
+        list_window.add(list_view.pwo());
+        v_box.pack_start(&list_window, true, true, 0);
+        let search_store = WrappedListStore::<SearchResultsSpec>::new();
+        let search_view = TreeViewWithPopupBuilder::new()
+            .enable_grid_lines(gtk::TreeViewGridLines::Horizontal)
+            .width_request(640)
+            .selection_mode(gtk::SelectionMode::Multiple)
+            .menu_item((
+                "extract_to",
+                MenuItemSpec(
+                    translate_static("gui-extract-to"),
+                    None,
+                    Some(translate_static("gui-extract-to-tip")),
+                ),
+                SAV_SELN_MADE,
+            ))
+            .build(&search_store);
+        let search_window = gtk::ScrolledWindow::new(
+            Option::<&gtk::Adjustment>::None,
+            Option::<&gtk::Adjustment>::None,
+        );
+        search_window.add(search_view.pwo());
+        v_box.pack_start(&search_window, true, true, 0);
         v_box.show_all();
+        search_window.hide();
         let snapshot_manager = Self(Rc::new(SnapshotManagerCore {
             v_box,
             list_view,
             list_store,
+            list_window,
+            search_entry,
+            search_view,
+            search_store,
+            search_window,
+            search_results: RefCell::new(vec![]),
             snapshot,
-            curr_dir_path: RefCell::new(base_dir_path.clone()),
+            archive_name: archive_name.to_string(),
+            curr_dir_path: RefCell::new(curr_dir_path.clone()),
             current_directory_manager,
+            opened_files_dir: RefCell::new(None),
         }));
-        snapshot_manager.set_curr_dir_path(&base_dir_path);
+        snapshot_manager.set_curr_dir_path(&curr_dir_path);
         snapshot_manager.repopulate();
 
         let snapshot_manager_clone = snapshot_manager.clone();
@@ -173,6 +279,46 @@ impl SnapshotManager {
                 snapshot_manager_clone.extract_to(&selection)
             });
 
+        let snapshot_manager_clone = snapshot_manager.clone();
+        snapshot_manager
+            .0
+            .list_view
+            .connect_popup_menu_item("preview", move |_, selection| {
+                snapshot_manager_clone.preview(&selection)
+            });
+
+        let snapshot_manager_clone = snapshot_manager.clone();
+        snapshot_manager
+            .0
+            .list_view
+            .connect_popup_menu_item("open", move |_, selection| {
+                snapshot_manager_clone.open(&selection)
+            });
+
+        let snapshot_manager_clone = snapshot_manager.clone();
+        snapshot_manager
+            .0
+            .search_entry
+            .connect_search_changed(move |entry| {
+                snapshot_manager_clone.search(&entry.get_text());
+            });
+
+        let snapshot_manager_clone = snapshot_manager.clone();
+        snapshot_manager
+            .0
+            .search_view
+            .connect_double_click(move |v| {
+                snapshot_manager_clone.process_search_double_click(v);
+            });
+
+        let snapshot_manager_clone = snapshot_manager.clone();
+        snapshot_manager
+            .0
+            .search_view
+            .connect_popup_menu_item("extract_to", move |_, selection| {
+                snapshot_manager_clone.extract_search_results_to(&selection)
+            });
+
         Ok(snapshot_manager)
     }
 
@@ -183,9 +329,11 @@ impl SnapshotManager {
             .snapshot
             .find_subdir(&*curr_dir_path)
             .expect(UNEXPECTED);
+        let show_hidden_files = preferences::show_hidden_files();
         let rows: Vec<Vec<Value>> = curr_dir
-            .contents()
+            .iter()
             .enumerate()
+            .filter(|(_, s)| show_hidden_files || !s.name().as_bytes().starts_with(b"."))
             .map(|(u, s)| vec![(u as u32).to_value(), s.name().to_string_lossy().to_value()])
             .collect();
         self.0.list_store.repopulate_with(&rows);
@@ -202,6 +350,10 @@ impl SnapshotManager {
     fn set_curr_dir_path<P: AsRef<Path>>(&self, path: P) {
         let mut curr_dir_path = self.0.curr_dir_path.borrow_mut();
         *curr_dir_path = path.as_ref().to_path_buf();
+        recollections::remember(
+            &archive_key(&self.0.archive_name, "current_dir"),
+            &curr_dir_path.to_string_lossy(),
+        );
         self.0.current_directory_manager.set_curr_dir_path(path);
         self.0
             .current_directory_manager
@@ -218,41 +370,234 @@ impl SnapshotManager {
 
     fn process_double_click(&self, value: &Value) {
         let index = value.get_some::<u32>().expect(UNEXPECTED) as usize;
+        if let Some(FileSystemObject::Directory(dir_data)) = self.curr_dir().get(index) {
+            self.set_curr_dir_path(dir_data.path());
+            self.repopulate();
+        }
+    }
+
+    fn search(&self, pattern: &str) {
+        let mut search_results = self.0.search_results.borrow_mut();
+        if pattern.is_empty() {
+            search_results.clear();
+            self.0.search_store.clear();
+            self.0.search_window.hide();
+            self.0.list_window.show();
+        } else {
+            // an incomplete glob pattern just means no matches (yet)
+            *search_results = self
+                .0
+                .snapshot
+                .find_matching_paths(pattern)
+                .unwrap_or_default();
+            let rows: Vec<Vec<Value>> = search_results
+                .iter()
+                .enumerate()
+                .map(|(u, p)| vec![(u as u32).to_value(), p.to_string_lossy().to_value()])
+                .collect();
+            self.0.search_store.repopulate_with(&rows);
+            self.0.list_window.hide();
+            self.0.search_window.show();
+        }
+    }
+
+    fn find_object(&self, path: &Path) -> EResult<&FileSystemObject> {
+        let dir = self
+            .0
+            .snapshot
+            .find_subdir(path.parent().expect(UNEXPECTED))?;
+        match dir.index_for(path.file_name().expect(UNEXPECTED)) {
+            Ok(index) => Ok(&dir[index]),
+            Err(_) => Err(Error::SnapshotUnknownFile(path.to_path_buf())),
+        }
+    }
+
+    fn process_search_double_click(&self, value: &Value) {
+        let index = value.get_some::<u32>().expect(UNEXPECTED) as usize;
+        let path = self.0.search_results.borrow()[index].clone();
+        let dir_path = match self.find_object(&path) {
+            Ok(FileSystemObject::Directory(_)) => path.as_path(),
+            _ => path.parent().expect(UNEXPECTED),
+        };
+        self.set_curr_dir_path(dir_path);
+        self.repopulate();
+        // this will trigger a search that hides the search results
+        self.0.search_entry.set_text("");
+    }
+
+    fn extract_to(&self, values: &[Value]) {
+        let curr_dir = self.curr_dir();
+        let fsos: Vec<&FileSystemObject> = values
+            .iter()
+            .filter_map(|v| curr_dir.get(v.get_some::<u32>().expect(UNEXPECTED) as usize))
+            .collect();
+        self.extract_objects_to(&fsos);
+    }
+
+    fn preview(&self, values: &[Value]) {
         let curr_dir = self.curr_dir();
-        match curr_dir[index] {
-            FileSystemObject::Directory(ref dir_data) => {
-                self.set_curr_dir_path(dir_data.path());
-                self.repopulate();
+        for value in values.iter() {
+            match curr_dir.get(value.get_some::<u32>().expect(UNEXPECTED) as usize) {
+                Some(FileSystemObject::File(file_data)) => self.preview_file(file_data),
+                Some(fso) => self.inform_user(
+                    &tr!("gui-not-a-file", name = format!("{:?}", fso.name())),
+                    Some(&tr!("gui-only-files-previewed")),
+                ),
+                None => (),
             }
-            _ => (),
         }
     }
 
-    fn extract_to(&self, values: &[Value]) {
+    fn preview_file(&self, file_data: &FileData) {
+        let content_keys = self.0.snapshot.content_keys();
+        let prefix = match content_keys.open_content_store(Mutability::Immutable) {
+            Ok(content_mgr) => match file_data.read_prefix(PREVIEW_BYTES, &content_mgr) {
+                Ok(prefix) => prefix,
+                Err(err) => return self.report_error("error", &err),
+            },
+            Err(err) => return self.report_error("error", &err),
+        };
+        if !fs_objects::looks_like_text(&prefix) {
+            self.inform_user(
+                &format!("{:?}: is not a text file", file_data.name()),
+                Some("Only text files can be previewed."),
+            );
+            return;
+        }
+        let text_view = gtk::TextView::new();
+        text_view.set_editable(false);
+        text_view.set_monospace(true);
+        if let Some(buffer) = text_view.get_buffer() {
+            buffer.set_text(&String::from_utf8_lossy(&prefix));
+        }
+        let scrolled_window = gtk::ScrolledWindow::new(
+            Option::<&gtk::Adjustment>::None,
+            Option::<&gtk::Adjustment>::None,
+        );
+        scrolled_window.set_size_request(640, 480);
+        scrolled_window.add(&text_view);
+        let dialog = self
+            .new_dialog_builder()
+            .title(&file_data.name().to_string_lossy())
+            .modal(true)
+            .destroy_with_parent(true)
+            .build();
+        for (label, response) in Self::CLOSE_BUTTONS.iter() {
+            dialog.add_button(label, *response);
+        }
+        dialog
+            .get_content_area()
+            .pack_start(&scrolled_window, true, true, 0);
+        dialog.show_all();
+        dialog.run();
+        dialog.close();
+    }
+
+    fn open(&self, values: &[Value]) {
+        let curr_dir = self.curr_dir();
+        for value in values.iter() {
+            match curr_dir.get(value.get_some::<u32>().expect(UNEXPECTED) as usize) {
+                Some(FileSystemObject::File(file_data)) => self.open_file(file_data),
+                Some(fso) => self.inform_user(
+                    &tr!("gui-not-a-file", name = format!("{:?}", fso.name())),
+                    Some(&tr!("gui-only-files-opened")),
+                ),
+                None => (),
+            }
+        }
+    }
+
+    // A directory that only the user can access in which to put files
+    // extracted for opening.
+    fn opened_files_dir_path(&self) -> EResult<PathBuf> {
+        let mut opened_files_dir = self.0.opened_files_dir.borrow_mut();
+        if opened_files_dir.is_none() {
+            *opened_files_dir = Some(StagingArea::new("open")?);
+        }
+        Ok(opened_files_dir
+            .as_ref()
+            .expect(UNEXPECTED)
+            .path()
+            .to_path_buf())
+    }
+
+    fn open_file(&self, file_data: &FileData) {
+        let dir_path = match self.opened_files_dir_path() {
+            Ok(dir_path) => dir_path,
+            Err(err) => return self.report_error("error", &err),
+        };
+        let file_path = dir_path.join(file_data.name());
+        let content_keys = self.0.snapshot.content_keys();
+        match content_keys.open_content_store(Mutability::Immutable) {
+            Ok(content_mgr) => {
+                if let Err(err) = file_data.copy_contents_to(&file_path, &content_mgr, true) {
+                    return self.report_error("error", &err);
+                }
+            }
+            Err(err) => return self.report_error("error", &err),
+        }
+        // it's a copy so discourage editing it
+        if let Ok(metadata) = fs::metadata(&file_path) {
+            let mut permissions = metadata.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(&file_path, permissions).ok();
+        }
+        if let Err(err) = Command::new("xdg-open").arg(&file_path).spawn() {
+            self.report_error("xdg-open", &err);
+        }
+    }
+
+    /// Remove the copies of files made for opening them.
+    pub fn remove_opened_files(&self) {
+        if let Some(staging_area) = self.0.opened_files_dir.borrow_mut().take() {
+            if let Err(err) = staging_area.close() {
+                log::warn!("removing opened files: {:?}", err);
+            }
+        }
+    }
+
+    fn extract_search_results_to(&self, values: &[Value]) {
+        let search_results = self.0.search_results.borrow();
+        let mut fsos = vec![];
+        for index in values
+            .iter()
+            .map(|v| v.get_some::<u32>().expect(UNEXPECTED) as usize)
+        {
+            match self.find_object(&search_results[index]) {
+                Ok(fso) => fsos.push(fso),
+                Err(err) => self.report_error("error", &err),
+            }
+        }
+        self.extract_objects_to(&fsos);
+    }
+
+    fn extract_objects_to(&self, fsos: &[&FileSystemObject]) {
         let extraction_options = ExtractionOptions::new();
         if self.present_widget_cancel_or_ok(extraction_options.pwo()) == gtk::ResponseType::Ok {
             if let Some(target_dir_path) = extraction_options.target_dir_path() {
                 let overwrite = extraction_options.overwrite();
-                let content_mgmt_key = self.0.snapshot.content_mgmt_key();
-                let curr_dir = self.curr_dir();
+                let copy_options = CopyOptions {
+                    overwrite,
+                    ..CopyOptions::default()
+                };
+                let content_keys = self.0.snapshot.content_keys();
+                let owner_mapping = self.0.snapshot.owner_mapping(&copy_options.owner_map);
                 let mut extraction_stats = ExtractionStats::default();
-                for index in values
-                    .iter()
-                    .map(|v| v.get_some::<u32>().expect(UNEXPECTED) as usize)
-                {
-                    match &curr_dir[index] {
+                for fso in fsos.iter() {
+                    match fso {
                         FileSystemObject::Directory(dir_data) => {
                             match dir_data.copy_to(
                                 &target_dir_path.join(dir_data.name()),
-                                content_mgmt_key,
-                                overwrite,
+                                &content_keys,
+                                &owner_mapping,
+                                &copy_options,
                             ) {
                                 Ok(stats) => extraction_stats += stats,
                                 Err(err) => self.report_error("error", &err),
                             }
                         }
                         FileSystemObject::File(file_data) => {
-                            match content_mgmt_key.open_content_manager(Mutability::Immutable) {
+                            match content_keys.open_content_store(Mutability::Immutable) {
                                 Ok(content_mgr) => match file_data.copy_contents_to(
                                     &target_dir_path.join(file_data.name()),
                                     &content_mgr,
@@ -284,7 +629,7 @@ impl SnapshotManager {
                     }
                 }
                 self.inform_user(
-                    "Extraction complete.",
+                    &tr!("gui-extraction-complete"),
                     Some(&format_for_inform(&extraction_stats)),
                 );
             }
@@ -292,28 +637,28 @@ impl SnapshotManager {
     }
 }
 
-fn format_for_inform(extraction_stats: &ExtractionStats) -> String {
-    format!("{:16} Directories\n{:16} Files\n{:16} Bytes\n{:16} Directory Sym Links\n{:16} File Sym Links\n",
-            extraction_stats.dir_count,
-            extraction_stats.file_count,
-            extraction_stats.bytes_count,
-            extraction_stats.dir_sym_link_count,
-            extraction_stats.file_sym_link_count
+pub(crate) fn format_for_inform(extraction_stats: &ExtractionStats) -> String {
+    format!("{:>16} Directories\n{:>16} Files\n{:>16} Bytes\n{:>16} Directory Sym Links\n{:>16} File Sym Links\n",
+            format_count(extraction_stats.dir_count),
+            format_count(extraction_stats.file_count),
+            format_count(extraction_stats.bytes_count),
+            format_count(extraction_stats.dir_sym_link_count),
+            format_count(extraction_stats.file_sym_link_count)
     )
 }
 
 #[derive(PWO)]
-struct ExtractionOptionsCore {
+pub(crate) struct ExtractionOptionsCore {
     v_box: gtk::Box,
     overwrite: gtk::CheckButton,
     file_chooser_button: gtk::FileChooserButton,
 }
 
 #[derive(PWO, WClone)]
-struct ExtractionOptions(Rc<ExtractionOptionsCore>);
+pub(crate) struct ExtractionOptions(Rc<ExtractionOptionsCore>);
 
 impl ExtractionOptions {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         let v_box = gtk::BoxBuilder::new()
             .orientation(gtk::Orientation::Vertical)
             .build();
@@ -341,11 +686,11 @@ impl ExtractionOptions {
         }))
     }
 
-    fn overwrite(&self) -> bool {
+    pub(crate) fn overwrite(&self) -> bool {
         self.0.overwrite.get_active()
     }
 
-    fn target_dir_path(&self) -> Option<PathBuf> {
+    pub(crate) fn target_dir_path(&self) -> Option<PathBuf> {
         self.0.file_chooser_button.get_filename()
     }
 }
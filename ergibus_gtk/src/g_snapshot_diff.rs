@@ -0,0 +1,290 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use pw_gtk_ext::{
+    gtk::{self, prelude::*},
+    wrapper::*,
+    UNEXPECTED,
+};
+
+use ergibus_lib::fs_objects::{CopyOptions, ExtractionStats};
+use ergibus_lib::snapshot::{self, SnapshotPersistentData};
+use ergibus_lib::snapshot_diff::{Change, DiffEntry, SnapshotDiff};
+use ergibus_lib::EResult;
+
+use crate::g_snapshot::{format_for_inform, ExtractionOptions};
+use pw_gtk_ext::glib::{Type, Value};
+use pw_gtk_ext::gtkx::menu::MenuItemSpec;
+use pw_gtk_ext::gtkx::tree_model::WrappedTreeModel;
+use pw_gtk_ext::gtkx::tree_store::TreeRowOps;
+use pw_gtk_ext::gtkx::tree_view::{TreeViewWithPopup, TreeViewWithPopupBuilder};
+use pw_gtk_ext::sav_state::SAV_SELN_MADE_OR_HOVER_OK;
+
+fn change_colour(change: Change) -> &'static str {
+    match change {
+        Change::Added => "green",
+        Change::Removed => "red",
+        Change::Changed => "blue",
+    }
+}
+
+struct SnapshotDiffStore(gtk::TreeStore);
+
+impl WrappedTreeModel<gtk::TreeStore> for SnapshotDiffStore {
+    fn columns() -> Vec<gtk::TreeViewColumn> {
+        let mut cols = vec![];
+        for (column, title) in [(1, "Name"), (2, "Change")].iter() {
+            let col = gtk::TreeViewColumnBuilder::new()
+                .title(title)
+                .expand(false)
+                .resizable(true)
+                .build();
+
+            let cell = gtk::CellRendererTextBuilder::new()
+                .editable(false)
+                .xalign(0.0)
+                .build();
+
+            col.pack_start(&cell, false);
+            col.add_attribute(&cell, "text", *column);
+            col.add_attribute(&cell, "foreground", 3);
+            cols.push(col);
+        }
+        cols
+    }
+
+    fn model(&self) -> &gtk::TreeStore {
+        &self.0
+    }
+}
+
+impl SnapshotDiffStore {
+    fn new() -> Self {
+        // path, name, change, foreground colour
+        Self(gtk::TreeStore::new(&[
+            Type::String,
+            Type::String,
+            Type::String,
+            Type::String,
+        ]))
+    }
+
+    fn append_entry(&self, entry: &DiffEntry, parent: Option<&gtk::TreeIter>) {
+        let iter = self.0.append_row(
+            &[
+                entry.path().to_string_lossy().to_value(),
+                entry.name().to_string_lossy().to_value(),
+                entry.change().to_string().to_value(),
+                change_colour(entry.change()).to_value(),
+            ],
+            parent,
+        );
+        for child in entry.children() {
+            self.append_entry(child, Some(&iter));
+        }
+    }
+}
+
+#[derive(PWO)]
+pub struct SnapshotDiffViewerCore {
+    v_box: gtk::Box,
+    tree_view: Rc<TreeViewWithPopup>,
+    older: SnapshotPersistentData,
+    newer: SnapshotPersistentData,
+}
+
+#[derive(PWO, WClone, Wrapper)]
+pub struct SnapshotDiffViewer(Rc<SnapshotDiffViewerCore>);
+
+impl SnapshotDiffViewer {
+    /// Compare the two named snapshots of the archive (in either order).
+    pub fn new(archive_name: &str, snapshot_names: (&OsStr, &OsStr)) -> EResult<Self> {
+        let (older_name, newer_name) = if snapshot_names.0 <= snapshot_names.1 {
+            snapshot_names
+        } else {
+            (snapshot_names.1, snapshot_names.0)
+        };
+        let older = snapshot::get_named_snapshot(archive_name, older_name)?;
+        let newer = snapshot::get_named_snapshot(archive_name, newer_name)?;
+        let diff = SnapshotDiff::new(&older, &newer);
+
+        let v_box = gtk::BoxBuilder::new()
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        let label = gtk::LabelBuilder::new()
+            .label(&format!(
+                "{}: {} -> {}",
+                archive_name,
+                older_name.to_string_lossy(),
+                newer_name.to_string_lossy()
+            ))
+            .halign(gtk::Align::Start)
+            .xalign(0.0)
+            .build();
+        v_box.pack_start(&label, false, false, 0);
+        let summary = gtk::LabelBuilder::new()
+            .use_markup(true)
+            .label(&format!(
+                "<span foreground=\"{}\">{} added</span>  <span foreground=\"{}\">{} removed</span>  <span foreground=\"{}\">{} changed</span>",
+                change_colour(Change::Added),
+                diff.count(Change::Added),
+                change_colour(Change::Removed),
+                diff.count(Change::Removed),
+                change_colour(Change::Changed),
+                diff.count(Change::Changed),
+            ))
+            .halign(gtk::Align::Start)
+            .xalign(0.0)
+            .build();
+        v_box.pack_start(&summary, false, false, 0);
+
+        let tree_store = SnapshotDiffStore::new();
+        for entry in diff.entries() {
+            tree_store.append_entry(entry, None);
+        }
+        let tree_view = TreeViewWithPopupBuilder::new()
+            .enable_grid_lines(gtk::TreeViewGridLines::Horizontal)
+            .width_request(640)
+            .height_request(480)
+            .selection_mode(gtk::SelectionMode::Multiple)
+            .menu_item((
+                "extract_old",
+                MenuItemSpec(
+                    "Extract Old Version To",
+                    None,
+                    Some("Extract the older snapshot's version of the selected items to nominated directory."),
+                ),
+                SAV_SELN_MADE_OR_HOVER_OK,
+            ))
+            .menu_item((
+                "extract_new",
+                MenuItemSpec(
+                    "Extract New Version To",
+                    None,
+                    Some("Extract the newer snapshot's version of the selected items to nominated directory."),
+                ),
+                SAV_SELN_MADE_OR_HOVER_OK,
+            ))
+            .build(&tree_store);
+        tree_view.pwo().expand_all();
+        let scrolled_window = gtk::ScrolledWindow::new(
+            Option::<&gtk::Adjustment>::None,
+            Option::<&gtk::Adjustment>::None,
+        );
+        scrolled_window.add(tree_view.pwo());
+        v_box.pack_start(&scrolled_window, true, true, 0);
+        v_box.show_all();
+
+        let viewer = Self(Rc::new(SnapshotDiffViewerCore {
+            v_box,
+            tree_view,
+            older,
+            newer,
+        }));
+
+        let viewer_clone = viewer.clone();
+        viewer
+            .0
+            .tree_view
+            .connect_popup_menu_item("extract_old", move |hovered, selection| {
+                viewer_clone.extract_to(&viewer_clone.0.older, hovered, &selection)
+            });
+
+        let viewer_clone = viewer.clone();
+        viewer
+            .0
+            .tree_view
+            .connect_popup_menu_item("extract_new", move |hovered, selection| {
+                viewer_clone.extract_to(&viewer_clone.0.newer, hovered, &selection)
+            });
+
+        Ok(viewer)
+    }
+
+    fn extract_to(
+        &self,
+        snapshot: &SnapshotPersistentData,
+        hovered: Option<Value>,
+        selection: &[Value],
+    ) {
+        let paths: Vec<PathBuf> = if selection.is_empty() {
+            hovered.iter().map(path_from_value).collect()
+        } else {
+            selection.iter().map(path_from_value).collect()
+        };
+        let extraction_options = ExtractionOptions::new();
+        if self.present_widget_cancel_or_ok(extraction_options.pwo()) == gtk::ResponseType::Ok {
+            if let Some(target_dir_path) = extraction_options.target_dir_path() {
+                let overwrite = extraction_options.overwrite();
+                let mut extraction_stats = ExtractionStats::default();
+                for path in paths.iter() {
+                    match extract_version(snapshot, path, &target_dir_path, overwrite) {
+                        Ok(stats) => extraction_stats += stats,
+                        Err(err) => self.report_error(
+                            &format!("Failed to extract \"{}\"", path.display()),
+                            &err,
+                        ),
+                    }
+                }
+                self.inform_user(
+                    "Extraction complete.",
+                    Some(&format_for_inform(&extraction_stats)),
+                );
+            }
+        }
+    }
+}
+
+fn path_from_value(value: &Value) -> PathBuf {
+    PathBuf::from(value.get::<String>().expect(UNEXPECTED).expect(UNEXPECTED))
+}
+
+fn extract_version(
+    snapshot: &SnapshotPersistentData,
+    path: &Path,
+    target_dir_path: &Path,
+    overwrite: bool,
+) -> EResult<ExtractionStats> {
+    let target_path = target_dir_path.join(path.file_name().expect(UNEXPECTED));
+    if snapshot.find_file(path).is_ok() {
+        let bytes_count = snapshot.copy_file_to(path, &target_path, overwrite, false)?;
+        Ok(ExtractionStats {
+            file_count: 1,
+            bytes_count,
+            ..ExtractionStats::default()
+        })
+    } else {
+        let options = CopyOptions {
+            overwrite,
+            ..CopyOptions::default()
+        };
+        snapshot.copy_dir_to(path, &target_path, &options)
+    }
+}
+
+pub fn show_snapshot_diff<W: DialogUser>(
+    parent: &W,
+    archive_name: &str,
+    snapshot_names: (&OsStr, &OsStr),
+) {
+    match SnapshotDiffViewer::new(archive_name, snapshot_names) {
+        Ok(viewer) => {
+            let dialog = parent
+                .new_dialog_builder()
+                .title("Snapshot Differences")
+                .destroy_with_parent(true)
+                .build();
+            dialog
+                .get_content_area()
+                .pack_start(viewer.pwo(), true, true, 0);
+            dialog.add_button("Close", gtk::ResponseType::Close);
+            dialog.connect_response(|dialog, _| dialog.close());
+            dialog.show_all();
+        }
+        Err(err) => parent.report_error(
+            &format!("Error comparing \"{}\" snapshots", archive_name),
+            &err,
+        ),
+    }
+}
@@ -10,12 +10,17 @@ use pw_gtk_ext::{
 };
 
 use crypto_hash::{Algorithm, Hasher};
-use num_format::{Locale, ToFormattedString};
-
 use ergibus_lib::snapshot::Order;
-use ergibus_lib::{archive, snapshot};
+use ergibus_lib::{archive, archive_registry, snapshot};
 
+use crate::format::{format_count, format_duration};
+use crate::g_back_up;
+use crate::g_breakdown::BreakdownChart;
+use crate::g_dashboard::freshness_markup;
+use crate::g_history::HistoryChart;
 use crate::g_snapshot::SnapshotManager;
+use crate::g_snapshot_diff::show_snapshot_diff;
+use crate::preferences;
 use pw_gtk_ext::glib::{Type, Value};
 use pw_gtk_ext::gtkx::buffered_list_store::{BufferedListStore, Row, RowDataSource};
 use pw_gtk_ext::gtkx::combo_box_text::NameSelector;
@@ -25,7 +30,38 @@ use pw_gtk_ext::gtkx::menu::MenuItemSpec;
 use pw_gtk_ext::gtkx::notebook::TabRemoveLabelBuilder;
 use pw_gtk_ext::gtkx::paned::RememberPosition;
 use pw_gtk_ext::gtkx::tree_view::{TreeViewWithPopup, TreeViewWithPopupBuilder};
-use pw_gtk_ext::sav_state::{SAV_SELN_MADE, SAV_SELN_UNIQUE_OR_HOVER_OK};
+use pw_gtk_ext::sav_state::{SAV_SELN_MADE, SAV_SELN_PAIR, SAV_SELN_UNIQUE_OR_HOVER_OK};
+use recollections;
+
+const LAST_ARCHIVE_KEY: &str = "snapshots_manager::last_archive";
+
+// Show how recently the archive was backed up
+fn show_freshness(label: &gtk::Label, archive_name: Option<&str>) {
+    match archive_name.map(snapshot::get_archive_freshness) {
+        Some(Ok(freshness)) => label.set_markup(&freshness_markup(&freshness)),
+        Some(Err(err)) => {
+            log::warn!("{}", err);
+            label.set_text("")
+        }
+        None => label.set_text(""),
+    }
+}
+
+// Recollections key for an item of an archive's UI state
+pub(crate) fn archive_key(archive_name: &str, item: &str) -> String {
+    format!("archive::{}::{}", archive_name, item)
+}
+
+fn remember_open_snapshots(archive_name: &str, open_snapshots: &[(OsString, SnapshotManager)]) {
+    let snapshot_names: Vec<String> = open_snapshots
+        .iter()
+        .map(|(snapshot_name, _)| snapshot_name.to_string_lossy().to_string())
+        .collect();
+    recollections::remember(
+        &archive_key(archive_name, "open_snapshots"),
+        &snapshot_names.join("\n"),
+    );
+}
 
 #[derive(Default)]
 struct SnapshotRowDataCore {
@@ -110,24 +146,12 @@ impl RowDataSource for SnapshotRowData {
                     match snapshot::get_snapshot_stats(archive_name, &snapshot_name) {
                         Ok(stats) => rows.push(vec![
                             snapshot_name.to_string_lossy().to_value(),
-                            stats
-                                .file_stats
-                                .file_count
-                                .to_formatted_string(&Locale::en_AU)
-                                .to_value(),
-                            stats
-                                .file_stats
-                                .byte_count
-                                .to_formatted_string(&Locale::en_AU)
-                                .to_value(),
-                            stats
-                                .file_stats
-                                .stored_byte_count
-                                .to_formatted_string(&Locale::en_AU)
-                                .to_value(),
-                            format!("{}", stats.sym_link_stats.dir_sym_link_count).to_value(),
-                            format!("{}", stats.sym_link_stats.file_sym_link_count).to_value(),
-                            format!("{:.1?}", stats.creation_duration).to_value(),
+                            format_count(stats.file_stats.file_count).to_value(),
+                            format_count(stats.file_stats.byte_count).to_value(),
+                            format_count(stats.file_stats.stored_byte_count).to_value(),
+                            format_count(stats.sym_link_stats.dir_sym_link_count).to_value(),
+                            format_count(stats.sym_link_stats.file_sym_link_count).to_value(),
+                            format_duration(stats.creation_duration).to_value(),
                         ]),
                         Err(_) => rows.push(vec![
                             snapshot_name.to_string_lossy().to_value(),
@@ -296,8 +320,12 @@ impl SnapshotListViewBuilder {
 pub struct SnapshotsManagerCore {
     vbox: gtk::Box,
     archive_selector: Rc<NameSelector>,
+    freshness_label: gtk::Label,
     snapshot_list_view: SnapshotListView,
+    paned: gtk::Paned,
     notebook: gtk::Notebook,
+    history_chart: HistoryChart,
+    breakdown_chart: BreakdownChart,
     open_snapshots: RefCell<Vec<(OsString, SnapshotManager)>>,
 }
 
@@ -314,6 +342,8 @@ impl SnapshotsManager {
         let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 0);
         let archive_selector = NameSelector::new("Archive:", archive::get_archive_names);
         hbox.pack_start(archive_selector.pwo(), false, false, 0);
+        let freshness_label = gtk::Label::new(None);
+        hbox.pack_start(&freshness_label, false, false, 6);
         let take_snapsot_button = gtk::Button::with_label("Take Snapshot");
         hbox.pack_start(&take_snapsot_button, false, false, 0);
         let label = gtk::Label::new(Some("Buttons go here"));
@@ -336,6 +366,16 @@ impl SnapshotsManager {
                 ("Delete", None, Some("Delete the selected snapshot(s).")).into(),
                 SAV_SELN_MADE,
             ))
+            .menu_item((
+                "diff",
+                (
+                    "Diff",
+                    None,
+                    Some("Show the differences between the two selected snapshots."),
+                )
+                    .into(),
+                SAV_SELN_PAIR,
+            ))
             .build();
         vbox.pack_start(&paned, true, true, 0);
         paned.add1(snapshot_list_view.pwo());
@@ -344,11 +384,23 @@ impl SnapshotsManager {
             .enable_popup(true)
             .build();
         paned.add2(&notebook);
+        let history_chart = HistoryChart::new();
+        let expander = gtk::Expander::new(Some("History"));
+        expander.add(history_chart.pwo());
+        vbox.pack_start(&expander, false, false, 0);
+        let breakdown_chart = BreakdownChart::new();
+        let expander = gtk::Expander::new(Some("File Types"));
+        expander.add(breakdown_chart.pwo());
+        vbox.pack_start(&expander, false, false, 0);
         let snapshots_mgr = Self(Rc::new(SnapshotsManagerCore {
             vbox,
             archive_selector,
+            freshness_label,
             snapshot_list_view,
+            paned,
             notebook,
+            history_chart,
+            breakdown_chart,
             open_snapshots: RefCell::new(vec![]),
         }));
 
@@ -391,12 +443,80 @@ impl SnapshotsManager {
                 snapshots_mgr_clone.delete_snapshots(&snapshot_names);
             });
 
+        let snapshots_mgr_clone = snapshots_mgr.clone();
+        snapshots_mgr
+            .0
+            .snapshot_list_view
+            .connect_popup_menu_item("diff", move |_, selected| {
+                let snapshot_names: Vec<OsString> = selected
+                    .iter()
+                    .map(|value| {
+                        OsString::from(value.get::<String>().expect(UNEXPECTED).expect(UNEXPECTED))
+                    })
+                    .collect();
+                if let [first, second] = snapshot_names.as_slice() {
+                    let archive_name = snapshots_mgr_clone
+                        .0
+                        .snapshot_list_view
+                        .archive_name()
+                        .expect(UNEXPECTED);
+                    show_snapshot_diff(&snapshots_mgr_clone, &archive_name, (first, second));
+                }
+            });
+
         let snapshots_mgr_clone = snapshots_mgr.clone();
         snapshots_mgr
             .0
             .snapshot_list_view
             .connect_archive_change(move |_| snapshots_mgr_clone.close_all_snapshots());
 
+        let snapshots_mgr_clone = snapshots_mgr.clone();
+        snapshots_mgr
+            .0
+            .snapshot_list_view
+            .connect_archive_change(move |archive_name| {
+                if let Some(archive_name) = archive_name {
+                    snapshots_mgr_clone.restore_archive_state(&archive_name)
+                }
+            });
+
+        let history_chart_clone = snapshots_mgr.0.history_chart.clone();
+        snapshots_mgr
+            .0
+            .snapshot_list_view
+            .connect_archive_change(move |archive_name| {
+                history_chart_clone.set_archive_name(archive_name.as_deref())
+            });
+
+        let breakdown_chart_clone = snapshots_mgr.0.breakdown_chart.clone();
+        snapshots_mgr
+            .0
+            .snapshot_list_view
+            .connect_archive_change(move |archive_name| {
+                breakdown_chart_clone.set_archive_name(archive_name.as_deref())
+            });
+
+        let freshness_label_clone = snapshots_mgr.0.freshness_label.clone();
+        snapshots_mgr
+            .0
+            .snapshot_list_view
+            .connect_archive_change(move |archive_name| {
+                show_freshness(&freshness_label_clone, archive_name.as_deref())
+            });
+
+        let slv_c = snapshots_mgr.0.snapshot_list_view.clone();
+        snapshots_mgr
+            .0
+            .paned
+            .connect_property_position_notify(move |paned| {
+                if let Some(archive_name) = slv_c.archive_name() {
+                    recollections::remember_int(
+                        &archive_key(&archive_name, "paned_position"),
+                        paned.get_position(),
+                    );
+                }
+            });
+
         let slv_c = snapshots_mgr.0.snapshot_list_view.clone();
         snapshots_mgr
             .0
@@ -404,19 +524,71 @@ impl SnapshotsManager {
             .connect_changed(move |archive_name| slv_c.set_archive_name(archive_name));
 
         let slv_c = snapshots_mgr.0.snapshot_list_view.clone();
+        let history_chart_clone = snapshots_mgr.0.history_chart.clone();
+        let breakdown_chart_clone = snapshots_mgr.0.breakdown_chart.clone();
+        let freshness_label_clone = snapshots_mgr.0.freshness_label.clone();
         take_snapsot_button.connect_clicked(move |_| {
             if let Some(archive_name) = slv_c.archive_name() {
                 slv_c.show_busy();
-                if snapshot::generate_snapshot(&archive_name).is_ok() {
+                let (result, warnings) = g_back_up::back_up(&archive_name);
+                if result.is_ok() {
                     slv_c.repopulate();
+                    history_chart_clone.set_archive_name(Some(&archive_name));
+                    breakdown_chart_clone.set_archive_name(Some(&archive_name));
+                    show_freshness(&freshness_label_clone, Some(&archive_name));
                 }
                 slv_c.unshow_busy(None);
+                if let Ok(stats) = result {
+                    g_back_up::report_back_up(&slv_c, &archive_name, &stats, &warnings);
+                }
             }
         });
 
+        if let Some(archive_name) =
+            preferences::default_archive().or_else(|| recollections::recall(LAST_ARCHIVE_KEY))
+        {
+            if archive_registry::archive_exists(&archive_name) {
+                snapshots_mgr
+                    .0
+                    .archive_selector
+                    .set_selected_archive(&archive_name);
+            }
+        }
+
         snapshots_mgr
     }
 
+    /// Redraw the list of snapshots and the open snapshots (e.g. after the
+    /// preferences change).
+    pub fn repopulate(&self) {
+        self.0.snapshot_list_view.repopulate();
+        for (_, snapshot_manager) in self.0.open_snapshots.borrow().iter() {
+            snapshot_manager.repopulate();
+        }
+    }
+
+    // Return to where the user left off the last time the archive was selected
+    fn restore_archive_state(&self, archive_name: &str) {
+        recollections::remember(LAST_ARCHIVE_KEY, archive_name);
+        match recollections::recall_int(&archive_key(archive_name, "paned_position")) {
+            Ok(Some(position)) => self.0.paned.set_position(position),
+            Ok(None) => (),
+            Err(err) => log::error!("Recollections: {}", err),
+        }
+        if let Some(snapshot_names) =
+            recollections::recall(&archive_key(archive_name, "open_snapshots"))
+        {
+            let available =
+                snapshot::get_snapshot_names_for_archive(archive_name, Order::Ascending)
+                    .unwrap_or_default();
+            for snapshot_name in snapshot_names.lines().map(OsString::from) {
+                if available.contains(&snapshot_name) {
+                    self.open_snapshot(&snapshot_name);
+                }
+            }
+        }
+    }
+
     fn open_snapshot(&self, snapshot_name: &OsStr) {
         let mut open_snapshots = self.0.open_snapshots.borrow_mut();
         match open_snapshots.binary_search_by_key(&snapshot_name, |os| os.0.as_os_str()) {
@@ -446,6 +618,7 @@ impl SnapshotsManager {
                             Some(index as u32),
                         );
                         open_snapshots.insert(index, (snapshot_name.to_os_string(), page));
+                        remember_open_snapshots(&archive_name, &open_snapshots);
                         self.0.notebook.set_current_page(Some(page_no));
                         self.0.notebook.show_all();
                     }
@@ -469,7 +642,11 @@ impl SnapshotsManager {
                 let (_, ref page) = open_snapshots[index];
                 let page_no = self.0.notebook.page_num(page.pwo());
                 self.0.notebook.remove_page(page_no);
+                page.remove_opened_files();
                 open_snapshots.remove(index);
+                if let Some(archive_name) = self.0.snapshot_list_view.archive_name() {
+                    remember_open_snapshots(&archive_name, &open_snapshots);
+                }
             }
             Err(_) => {
                 if !conditional {
@@ -488,7 +665,9 @@ impl SnapshotsManager {
         while let Some(page_no) = self.0.notebook.get_current_page() {
             self.0.notebook.remove_page(Some(page_no))
         }
-        self.0.open_snapshots.borrow_mut().clear();
+        for (_, page) in self.0.open_snapshots.borrow_mut().drain(..) {
+            page.remove_opened_files();
+        }
     }
 
     fn delete_snapshots(&self, snapshot_names: &[OsString]) {
@@ -498,14 +677,28 @@ impl SnapshotsManager {
             question += format!("\t{}\n", snapshot_name.to_string_lossy()).as_str();
         }
         question += format!("belonging to the \"{}\" archive?", archive_name).as_str();
-        let dialog_builder = self.new_message_dialog_builder();
-        let dialog = dialog_builder
-            .buttons(gtk::ButtonsType::OkCancel)
-            .message_type(gtk::MessageType::Question)
-            .modal(true)
-            .text(&question)
-            .build();
-        if dialog.run() == gtk::ResponseType::Ok {
+        let confirmed = if preferences::confirm_before_delete() {
+            let cursor = self.show_busy();
+            let space_freed = match snapshot::estimate_space_freed(&archive_name, snapshot_names) {
+                Ok(bytes) => format!("This will free about {} bytes", format_count(bytes)),
+                Err(err) => format!("The space to be freed is unknown: {}", err),
+            };
+            self.unshow_busy(cursor);
+            let dialog_builder = self.new_message_dialog_builder();
+            let dialog = dialog_builder
+                .buttons(gtk::ButtonsType::OkCancel)
+                .message_type(gtk::MessageType::Question)
+                .modal(true)
+                .text(&question)
+                .secondary_text(&space_freed)
+                .build();
+            let response = dialog.run();
+            dialog.close();
+            response == gtk::ResponseType::Ok
+        } else {
+            true
+        };
+        if confirmed {
             let cursor = self.show_busy();
             if let Err(err) = snapshot::delete_named_snapshots(&archive_name, snapshot_names) {
                 let dialog = self
@@ -525,7 +718,8 @@ impl SnapshotsManager {
             }
             self.unshow_busy(cursor);
         }
-        dialog.close();
         self.0.snapshot_list_view.update();
+        self.0.history_chart.set_archive_name(Some(&archive_name));
+        self.0.breakdown_chart.set_archive_name(Some(&archive_name));
     }
 }
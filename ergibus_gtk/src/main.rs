@@ -6,19 +6,51 @@ use pw_gtk_ext::{
 };
 use recollections;
 
+use crate::g_dashboard::ArchiveDashboard;
+use crate::g_preferences::show_preferences_dialog;
 use crate::g_snapshots::SnapshotsManager;
-use ergibus_lib::config;
+use ergibus_lib::{config, staging};
 
+mod format;
 pub mod g_archive;
+pub mod g_back_up;
+pub mod g_breakdown;
+pub mod g_dashboard;
+pub mod g_history;
+pub mod g_preferences;
 pub mod g_snapshot;
+pub mod g_snapshot_diff;
 pub mod g_snapshots;
 mod icons;
+mod preferences;
 
 fn activate(app: &gtk::Application) {
     let window = gtk::ApplicationWindow::new(app);
     window.set_title("ERGIBUS GUI");
+    let notebook = gtk::Notebook::new();
+    let dashboard = ArchiveDashboard::new();
+    notebook.append_page(dashboard.pwo(), Some(&gtk::Label::new(Some("Dashboard"))));
     let snapshots_manager = SnapshotsManager::new();
-    window.add(snapshots_manager.pwo());
+    notebook.append_page(
+        snapshots_manager.pwo(),
+        Some(&gtk::Label::new(Some("Snapshots"))),
+    );
+    let preferences_button = gtk::Button::with_label("Preferences");
+    preferences_button.show();
+    notebook.set_action_widget(&preferences_button, gtk::PackType::End);
+    let dashboard_clone = dashboard.clone();
+    preferences_button.connect_clicked(move |_| {
+        if show_preferences_dialog(&dashboard_clone) {
+            dashboard_clone.refresh();
+            snapshots_manager.repopulate();
+        }
+    });
+    notebook.connect_switch_page(move |_, _, page_num| {
+        if page_num == 0 {
+            dashboard.refresh()
+        }
+    });
+    window.add(&notebook);
     if let Some(geometry) = recollections::recall("main_window:geometry") {
         window.parse_geometry(&geometry);
     } else {
@@ -32,10 +64,18 @@ fn activate(app: &gtk::Application) {
 }
 
 fn main() {
-    recollections::init(&config::get_gui_config_dir_path().join("recollections"));
+    if let Err(err) = recollections::init(config::get_gui_config_dir_path().join("recollections")) {
+        log::error!("{}", err);
+    }
+    if let Err(err) = staging::remove_abandoned_staging_areas() {
+        log::warn!("failed to remove abandoned staging areas: {}", err);
+    }
     let flags = gio::ApplicationFlags::empty();
     let app = gtk::Application::new(None, flags)
         .unwrap_or_else(|err| panic!("{:?}: line {:?}: {:?}", file!(), line!(), err));
     app.connect_activate(activate);
     app.run(&[]);
+    if let Err(err) = recollections::flush() {
+        log::error!("{}", err);
+    }
 }
@@ -0,0 +1,137 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! User preferences (other than the number format locale which lives with
+//! the formatting code).  They're kept with the GUI's other recollections
+//! and are read whenever they're needed so that changes take effect
+//! without restarting.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use ergibus_lib::fs_objects::{FileStats, SymLinkStats};
+
+use recollections;
+
+use crate::format::{format_count, format_duration};
+
+const DEFAULT_ARCHIVE_KEY: &str = "preferences::default_archive";
+const CONFIRM_BEFORE_DELETE_KEY: &str = "preferences::confirm_before_delete";
+const SHOW_HIDDEN_FILES_KEY: &str = "preferences::show_hidden_files";
+const PROGRESS_VERBOSITY_KEY: &str = "preferences::progress_verbosity";
+
+fn recall_bool_or(name: &str, default: bool) -> bool {
+    match recollections::recall_bool(name) {
+        Ok(Some(value)) => value,
+        Ok(None) => default,
+        Err(err) => {
+            log::error!("Recollections: {}", err);
+            default
+        }
+    }
+}
+
+/// The archive to be selected when the GUI starts (rather than the one
+/// that was last selected).
+pub fn default_archive() -> Option<String> {
+    recollections::recall(DEFAULT_ARCHIVE_KEY).filter(|name| !name.is_empty())
+}
+
+pub fn set_default_archive(archive_name: Option<&str>) {
+    recollections::remember(DEFAULT_ARCHIVE_KEY, archive_name.unwrap_or(""))
+}
+
+pub fn confirm_before_delete() -> bool {
+    recall_bool_or(CONFIRM_BEFORE_DELETE_KEY, true)
+}
+
+pub fn set_confirm_before_delete(confirm: bool) {
+    recollections::remember_bool(CONFIRM_BEFORE_DELETE_KEY, confirm)
+}
+
+/// Whether files whose names start with "." are listed when browsing snapshots.
+pub fn show_hidden_files() -> bool {
+    recall_bool_or(SHOW_HIDDEN_FILES_KEY, true)
+}
+
+pub fn set_show_hidden_files(show: bool) {
+    recollections::remember_bool(SHOW_HIDDEN_FILES_KEY, show)
+}
+
+/// How much the user is told about back ups made from the GUI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressVerbosity {
+    /// Only report failures
+    Quiet,
+    /// Also report the file counts, sizes and time taken
+    Summary,
+    /// Also report symbolic link counts and repository growth
+    Detailed,
+}
+
+impl ProgressVerbosity {
+    pub const ALL: [ProgressVerbosity; 3] = [
+        ProgressVerbosity::Quiet,
+        ProgressVerbosity::Summary,
+        ProgressVerbosity::Detailed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProgressVerbosity::Quiet => "quiet",
+            ProgressVerbosity::Summary => "summary",
+            ProgressVerbosity::Detailed => "detailed",
+        }
+    }
+}
+
+impl FromStr for ProgressVerbosity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|verbosity| verbosity.as_str() == s)
+            .copied()
+            .ok_or_else(|| format!("{}: unknown progress verbosity", s))
+    }
+}
+
+pub fn progress_verbosity() -> ProgressVerbosity {
+    recollections::recall(PROGRESS_VERBOSITY_KEY)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(ProgressVerbosity::Quiet)
+}
+
+pub fn set_progress_verbosity(verbosity: ProgressVerbosity) {
+    recollections::remember(PROGRESS_VERBOSITY_KEY, verbosity.as_str())
+}
+
+/// The report (if any) the user wants after a successful back up.
+pub fn back_up_report(
+    (duration, file_stats, sym_link_stats, delta_repo_size): &(
+        Duration,
+        FileStats,
+        SymLinkStats,
+        u64,
+    ),
+) -> Option<String> {
+    let verbosity = progress_verbosity();
+    if verbosity == ProgressVerbosity::Quiet {
+        return None;
+    }
+    let mut report = format!(
+        "{} files ({} bytes) in {}",
+        format_count(file_stats.file_count),
+        format_count(file_stats.byte_count),
+        format_duration(*duration)
+    );
+    if verbosity == ProgressVerbosity::Detailed {
+        report += &format!(
+            "\n{} directory and {} file symbolic links\nrepository grew by {} bytes",
+            format_count(sym_link_stats.dir_sym_link_count),
+            format_count(sym_link_stats.file_sym_link_count),
+            format_count(*delta_repo_size)
+        );
+    }
+    Some(report)
+}
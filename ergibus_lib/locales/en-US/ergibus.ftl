@@ -0,0 +1,59 @@
+# The built in (English) messages.  Translations go in
+# locales/<language-id>/ergibus.ftl and need only contain the messages
+# that they translate (the rest fall back to these).
+
+## Command line
+
+cli-about = Experimental Rust Git Inspired Back Up System
+cli-invalid-config-profile = Invalid configuration profile: { $details }
+
+## Graphical user interface
+
+gui-extract-to = Extract To
+gui-extract-to-tip = Extract selected items to nominated directory.
+gui-preview = Preview
+gui-preview-tip = Show the start of the selected (text) file.
+gui-open = Open
+gui-open-tip = Open (a copy of) the selected file with its default application.
+gui-not-a-file = { $name }: is not a file
+gui-only-files-previewed = Only files can be previewed.
+gui-only-files-opened = Only files can be opened.
+gui-extraction-complete = Extraction complete.
+
+## Library errors
+
+error-archive-exists = Archive "{ $name }" already exists.
+error-archive-unknown = Archive "{ $name }" is unknown.
+error-invalid-archive-name = "{ $name }" is not a valid archive name: { $problem }.
+error-unknown-repo = Content repository "{ $name }" is unknown.
+error-no-repo-nominated = No content repository has been nominated.
+error-no-snapshot-available = No snapshot is available.
+error-snapshots-failed = { $count ->
+    [one] One back up failed.
+   *[other] { $count } back ups failed.
+}
+error-snapshots-unverified = { $count ->
+    [one] One snapshot failed verification.
+   *[other] { $count } snapshots failed verification.
+}
+error-restores-failed = { $count ->
+    [one] One archive failed to restore.
+   *[other] { $count } archives failed to restore.
+}
+error-doctor-checks-failed = { $count ->
+    [one] One check failed.
+   *[other] { $count } checks failed.
+}
+error-snapshot-exists = Snapshot { $path } already exists.
+error-snapshot-dir-read-only = Snapshots can't be written to { $path } as it's on a read-only file system.
+error-import-target-ambiguous = Archive "{ $name }" has more than one inclusion so the directory that the back up is a copy of must be given.
+error-unknown-exclusion-profile = Exclusion profile "{ $name }" is unknown.
+error-unknown-archive-group = Archive group "{ $name }" is unknown.
+error-bad-config-profile-name = "{ $name }" is not a valid configuration profile name.
+error-encryption-key-missing = Archive "{ $name }" requires encryption but has no encryption key.
+error-unsupported-compression = Archive "{ $name }" requires "{ $compression }" compression which isn't supported.
+error-extract-insufficient-space = Extracting to { $path } needs { $needed } bytes but only { $available } bytes are available.
+error-prune-insufficient-space = { $archive }: only { $available } of the { $wanted } bytes wanted can be freed while keeping the newest { $kept } snapshots.
+error-cancelled = Cancelled.
+error-job-unknown = Job { $id } is unknown.
+error-other = Ergibus library error: { $details }
@@ -1,12 +1,16 @@
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::ffi::OsString;
 use std::fs::{self, File};
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time;
 
-use globset::{Glob, GlobSet, GlobSetBuilder};
+use chrono::{DateTime, Local};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
 use hostname;
+use schemars::JsonSchema;
 use serde_yaml;
 use users;
 use walkdir;
@@ -14,44 +18,97 @@ use walkdir;
 use path_ext::expand_home_dir;
 use path_ext::{absolute_path_buf, PathType};
 
+use crate::archive_registry;
+use crate::atomic_file;
+use crate::attributes::AttributesIfce;
+use crate::audit::{self, AuditOperation};
+use crate::content_keys::{ContentKeys, Overflow, TieredContentManager};
+use crate::progress::{self, ProgressEvents};
+use crate::prune_hooks::{self, PruneOperation, PruneSummary};
 use crate::report::ignore_report_or_fail;
 use crate::snapshot::Order;
 use crate::{
-    config,
-    fs_objects::ExtractionStats,
+    checksums, config, encryption,
+    fs_objects::{self, CopyOptions, ExtractionStats},
+    global_config::{self, ExclusionProfile},
     snapshot::{self, SnapshotPersistentData},
-    EResult, Error,
+    EResult, Error, UNEXPECTED,
 };
-use dychatat_lib::content::{content_repo_exists, get_content_mgmt_key, ContentMgmtKey};
+use dychatat_lib::content::{
+    content_repo_exists, create_new_repo, get_content_mgmt_key, get_repo_name_for_key,
+    name_problem, read_repo_spec, write_repo_spec, ContentMgmtKey, ContentStore, RepoSpec,
+};
+use dychatat_lib::Mutability;
 
 #[derive(Debug)]
 pub struct Exclusions {
     dir_globset: GlobSet,
     file_globset: GlobSet,
+    dir_patterns: Vec<String>,
+    file_patterns: Vec<String>,
 }
 
 impl Exclusions {
-    fn new(dir_patterns: &Vec<String>, file_patterns: &Vec<String>) -> EResult<Exclusions> {
+    pub(crate) fn new(
+        dir_patterns: &Vec<String>,
+        file_patterns: &Vec<String>,
+        case_insensitive: bool,
+    ) -> EResult<Exclusions> {
         let mut dgs_builder = GlobSetBuilder::new();
         for pattern in dir_patterns {
-            let glob = Glob::new(pattern).map_err(|err| Error::GlobError(err))?;
+            let glob = GlobBuilder::new(pattern)
+                .case_insensitive(case_insensitive)
+                .build()
+                .map_err(Error::GlobError)?;
             dgs_builder.add(glob);
         }
-        let dir_globset = dgs_builder.build().map_err(|err| Error::GlobError(err))?;
+        let dir_globset = dgs_builder.build().map_err(Error::GlobError)?;
 
         let mut fgs_builder = GlobSetBuilder::new();
         for pattern in file_patterns {
-            let glob = Glob::new(pattern).map_err(|err| Error::GlobError(err))?;
+            let glob = GlobBuilder::new(pattern)
+                .case_insensitive(case_insensitive)
+                .build()
+                .map_err(Error::GlobError)?;
             fgs_builder.add(glob);
         }
-        let file_globset = fgs_builder.build().map_err(|err| Error::GlobError(err))?;
+        let file_globset = fgs_builder.build().map_err(Error::GlobError)?;
 
         Ok(Exclusions {
             dir_globset,
             file_globset,
+            dir_patterns: dir_patterns.clone(),
+            file_patterns: file_patterns.clone(),
         })
     }
 
+    // The first of the patterns matching the path's name or the path itself
+    fn first_matching_pattern<'a>(
+        globset: &GlobSet,
+        patterns: &'a [String],
+        abs_path: &Path,
+    ) -> Option<&'a str> {
+        if globset.is_empty() {
+            return None;
+        }
+        let mut indices = abs_path
+            .file_name()
+            .map(|name| globset.matches(name))
+            .unwrap_or_default();
+        indices.extend(globset.matches(abs_path));
+        indices.iter().min().map(|index| patterns[*index].as_str())
+    }
+
+    /// The glob pattern (if any) that excludes the directory.
+    pub fn dir_exclusion_pattern(&self, abs_dir_path: &Path) -> Option<&str> {
+        Self::first_matching_pattern(&self.dir_globset, &self.dir_patterns, abs_dir_path)
+    }
+
+    /// The glob pattern (if any) that excludes the file (or symbolic link).
+    pub fn file_exclusion_pattern(&self, abs_file_path: &Path) -> Option<&str> {
+        Self::first_matching_pattern(&self.file_globset, &self.file_patterns, abs_file_path)
+    }
+
     pub fn is_non_excluded_dir(&self, dir_entry: &walkdir::DirEntry) -> bool {
         if dir_entry.file_type().is_dir() {
             if self.dir_globset.is_empty() {
@@ -81,7 +138,8 @@ impl Exclusions {
                     } else {
                         Ok(false)
                     }
-                } else if file_type.is_file() || file_type.is_symlink() {
+                } else {
+                    // NB: special files are subject to file exclusions
                     if self.file_globset.is_empty() {
                         Ok(false)
                     } else if self.file_globset.is_match(&dir_entry.file_name()) {
@@ -91,8 +149,6 @@ impl Exclusions {
                     } else {
                         Ok(false)
                     }
-                } else {
-                    Ok(true)
                 }
             }
             Err(err) => {
@@ -131,6 +187,131 @@ impl Exclusions {
     }
 }
 
+/// What a back up should do when it exceeds its archive's budget.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetAction {
+    /// Keep the snapshot but warn about it
+    #[default]
+    Warn,
+    /// Discard the snapshot
+    Abort,
+}
+
+impl FromStr for BudgetAction {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "warn" => Ok(BudgetAction::Warn),
+            "abort" => Ok(BudgetAction::Abort),
+            _ => Err(format!("{}: unknown budget action", text)),
+        }
+    }
+}
+
+/// Limits on the size of an archive's snapshots intended to catch runaway
+/// growth (e.g. due to accidentally included caches or VM images).
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub struct SnapshotBudget {
+    /// The most (content) bytes a snapshot should contain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_snapshot_bytes: Option<u64>,
+    /// The most a single back up should add to the repository's storage.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_repo_growth_per_run: Option<u64>,
+    #[serde(default)]
+    pub on_exceeded: BudgetAction,
+}
+
+impl SnapshotBudget {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Descriptions of the limits exceeded by a snapshot containing
+    /// `snapshot_bytes` whose creation added `repo_growth` bytes to the
+    /// repository.
+    pub fn excesses(&self, snapshot_bytes: u64, repo_growth: u64) -> Vec<String> {
+        let mut excesses = vec![];
+        if let Some(max_snapshot_bytes) = self.max_snapshot_bytes {
+            if snapshot_bytes > max_snapshot_bytes {
+                excesses.push(format!(
+                    "snapshot size {} bytes exceeds budget of {} bytes",
+                    snapshot_bytes, max_snapshot_bytes
+                ));
+            }
+        }
+        if let Some(max_repo_growth_per_run) = self.max_repo_growth_per_run {
+            if repo_growth > max_repo_growth_per_run {
+                excesses.push(format!(
+                    "repository growth {} bytes exceeds budget of {} bytes",
+                    repo_growth, max_repo_growth_per_run
+                ));
+            }
+        }
+        excesses
+    }
+}
+
+/// The compression algorithm used by repositories for contents.
+pub const SUPPORTED_COMPRESSION: &str = "snappy";
+
+/// Whether an archive's snapshot files are encrypted.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotEncryption {
+    /// Encrypt them if the archive has an encryption key
+    #[default]
+    Auto,
+    /// Refuse to back up unless the archive has an encryption key
+    Required,
+    /// Never encrypt them
+    Off,
+}
+
+impl SnapshotEncryption {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Which of an archive's snapshots are kept by "ergibus ss delete --expired".
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    /// Always keep (at least) this many of the newest snapshots.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_newest: Option<usize>,
+    /// Snapshots older than this (that aren't kept by `keep_newest`) expire.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_days: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The number of the oldest snapshots (taken at the given times, oldest
+    /// first) that have expired at time `now`.
+    pub fn expired_count(
+        &self,
+        snapshot_times: &[time::SystemTime],
+        now: time::SystemTime,
+    ) -> usize {
+        let max_age = match self.max_age_days {
+            Some(days) => time::Duration::from_secs(days.saturating_mul(86_400)),
+            None => return 0,
+        };
+        let keep_newest = self.keep_newest.unwrap_or(0).max(1);
+        let candidates = &snapshot_times[..snapshot_times.len().saturating_sub(keep_newest)];
+        candidates
+            .iter()
+            .take_while(|time| now.duration_since(**time).unwrap_or_default() > max_age)
+            .count()
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 struct ArchiveSpec {
     content_repo_name: String,
@@ -138,6 +319,54 @@ struct ArchiveSpec {
     inclusions: Vec<PathBuf>,
     dir_exclusions: Vec<String>,
     file_exclusions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    profiles: Vec<String>,
+    #[serde(default, skip_serializing_if = "SnapshotBudget::is_default")]
+    budget: SnapshotBudget,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backup_interval_hours: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    overflow: Option<OverflowSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<String>,
+    #[serde(default, skip_serializing_if = "SnapshotEncryption::is_default")]
+    encryption: SnapshotEncryption,
+    #[serde(default, skip_serializing_if = "RetentionPolicy::is_default")]
+    retention: RetentionPolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prune_hook: Option<String>,
+    /// Match the exclusion globs ignoring case (e.g. for restored Windows data)
+    #[serde(default, skip_serializing_if = "is_false")]
+    case_insensitive_globs: bool,
+    /// The number of levels below each inclusion that are read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_depth: Option<usize>,
+    // Fields added by later versions (kept so that rewriting the spec
+    // doesn't lose them)
+    #[serde(flatten)]
+    unknown_fields: BTreeMap<String, serde_yaml::Value>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// A repository for the contents of an archive's large files.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct OverflowSpec {
+    pub content_repo_name: String,
+    /// Files of at least this many bytes go to the overflow repository
+    pub min_file_size: u64,
+}
+
+impl ArchiveSpec {
+    fn uses_repo(&self, repo_name: &str) -> bool {
+        self.content_repo_name == repo_name
+            || self
+                .overflow
+                .as_ref()
+                .is_some_and(|overflow| overflow.content_repo_name == repo_name)
+    }
 }
 
 fn get_archive_spec_file_path(archive_name: &str) -> PathBuf {
@@ -164,36 +393,211 @@ fn write_archive_spec(
     if !overwrite && spec_file_path.exists() {
         return Err(Error::ArchiveExists(archive_name.to_string()));
     }
-    match spec_file_path.parent() {
-        Some(config_dir_path) => {
-            if !config_dir_path.exists() {
-                fs::create_dir_all(&config_dir_path)
-                    .map_err(|err| Error::ArchiveWriteError(err, config_dir_path.to_path_buf()))?;
+    let yaml_text = serde_yaml::to_string(archive_spec)
+        .map_err(|err| Error::ArchiveYamlWriteError(err, archive_name.to_string()))?;
+    // Replaced atomically as a truncated file would lose the archive
+    atomic_file::replace_file(&spec_file_path, |writer| {
+        writer.write_all(yaml_text.as_bytes())
+    })
+    .map_err(|err| Error::ArchiveWriteError(err, spec_file_path.clone()))
+}
+
+const AUTO_REPO_HASH_ALGORITHM: &str = "Sha256";
+
+/// The name of the repository to be used by new archives that don't
+/// nominate one.
+pub fn get_default_repo_name() -> EResult<Option<String>> {
+    match fs::read_to_string(config::get_default_repo_file_path()) {
+        Ok(text) if text.trim().is_empty() => Ok(None),
+        Ok(text) => Ok(Some(text.trim().to_string())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn set_default_repo_name(content_repo_name: &str) -> EResult<()> {
+    if !content_repo_exists(content_repo_name) {
+        return Err(Error::UnknownRepo(content_repo_name.to_string()));
+    }
+    let file_path = config::get_default_repo_file_path();
+    atomic_file::replace_file(&file_path, |writer| {
+        writeln!(writer, "{}", content_repo_name)
+    })?;
+    Ok(())
+}
+
+// The nominated repository, else the configured default, else (if
+// `auto_repo`) one named after the host that is created at `location`
+// if necessary.
+fn choose_content_repo(
+    content_repo_name: Option<&str>,
+    location: &Path,
+    auto_repo: bool,
+) -> EResult<String> {
+    let content_repo_name = match content_repo_name {
+        Some(content_repo_name) => content_repo_name.to_string(),
+        None => match get_default_repo_name()? {
+            Some(content_repo_name) => content_repo_name,
+            None if auto_repo => {
+                let content_repo_name =
+                    hostname::get_hostname().unwrap_or_else(|| "default".to_string());
+                if !content_repo_exists(&content_repo_name) {
+                    log::info!(
+                        "{}: creating repository at {:?}",
+                        content_repo_name,
+                        location
+                    );
+                    create_new_repo(&content_repo_name, location, AUTO_REPO_HASH_ALGORITHM)?;
+                }
+                return Ok(content_repo_name);
+            }
+            None => return Err(Error::NoRepoNominated),
+        },
+    };
+    if content_repo_exists(&content_repo_name) {
+        Ok(content_repo_name)
+    } else {
+        Err(Error::UnknownRepo(content_repo_name))
+    }
+}
+
+/// Create a new archive.  If `content_repo_name` is `None` the default
+/// repository is used or, failing that and if `auto_repo` is true, a
+/// repository named after the host (created in `location` if it doesn't
+/// already exist).  The exclusions of the named `profiles` (from the
+/// global configuration) are added to the archive's own when it is used.
+/// Returns the name of the repository used.
+#[allow(clippy::too_many_arguments)]
+const GLOB_META_CHARS: &[char] = &['*', '?', '[', '{'];
+
+/// Whether the inclusion is a glob pattern (e.g. "~/projects/*/src") to
+/// be expanded into the paths that match it each time the archive is
+/// backed up.  Each component of the pattern is matched against a single
+/// name (so "**" is the same as "*").
+pub fn is_glob_inclusion(inclusion: &Path) -> bool {
+    inclusion
+        .to_str()
+        .is_some_and(|text| text.contains(GLOB_META_CHARS))
+}
+
+/// The paths that a glob inclusion matched when a snapshot was made.
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+pub struct InclusionExpansion {
+    #[serde(with = "fs_objects::os_path")]
+    #[schemars(with = "fs_objects::os_path::StoredPath")]
+    pub pattern: PathBuf,
+    #[serde(
+        serialize_with = "fs_objects::os_path::serialize_vec",
+        deserialize_with = "fs_objects::os_path::deserialize_vec"
+    )]
+    #[schemars(with = "Vec<fs_objects::os_path::StoredPath>")]
+    pub matches: Vec<PathBuf>,
+}
+
+impl InclusionExpansion {
+    fn expand(pattern: &Path) -> EResult<Self> {
+        let mut matches = vec![PathBuf::new()];
+        for component in pattern.iter() {
+            let name = component.to_string_lossy();
+            if !name.contains(GLOB_META_CHARS) {
+                matches.iter_mut().for_each(|path| path.push(component));
+                continue;
+            }
+            let matcher = Glob::new(&name)
+                .map_err(Error::GlobError)?
+                .compile_matcher();
+            let mut expanded = vec![];
+            for dir_path in matches.iter() {
+                // unreadable or missing directories just don't match
+                let read_dir = match fs::read_dir(dir_path) {
+                    Ok(read_dir) => read_dir,
+                    Err(_) => continue,
+                };
+                for entry in read_dir.filter_map(|e| e.ok()) {
+                    if matcher.is_match(entry.file_name()) {
+                        expanded.push(entry.path());
+                    }
+                }
             }
+            matches = expanded;
         }
-        None => (),
+        matches.retain(|path| path.symlink_metadata().is_ok());
+        matches.sort();
+        Ok(Self {
+            pattern: pattern.to_path_buf(),
+            matches,
+        })
     }
-    let spec_file = File::create(&spec_file_path)
-        .map_err(|err| Error::ArchiveWriteError(err, spec_file_path.clone()))?;
-    serde_yaml::to_writer(&spec_file, archive_spec)
-        .map_err(|err| Error::ArchiveYamlWriteError(err, archive_name.to_string()))?;
-    Ok(())
+}
+
+// The indices of the inclusions that are the same as, or within, another
+// inclusion paired with the index of the inclusion that covers them
+fn overlap_indices(inclusions: &[PathBuf]) -> Vec<(usize, usize)> {
+    let mut overlaps = vec![];
+    for (index, inclusion) in inclusions.iter().enumerate() {
+        let covering = inclusions
+            .iter()
+            .enumerate()
+            .position(|(other_index, other)| {
+                if inclusion == other {
+                    other_index < index
+                } else {
+                    inclusion.starts_with(other)
+                }
+            });
+        if let Some(covering) = covering {
+            overlaps.push((index, covering));
+        }
+    }
+    overlaps
+}
+
+/// The inclusions that are the same as, or within, another inclusion
+/// (paired with the inclusion that covers them).  Of duplicates, the
+/// first is taken to cover the others.
+pub fn overlapping_inclusions(inclusions: &[PathBuf]) -> Vec<(PathBuf, PathBuf)> {
+    overlap_indices(inclusions)
+        .into_iter()
+        .map(|(index, covering)| (inclusions[index].clone(), inclusions[covering].clone()))
+        .collect()
+}
+
+// Drop (with a warning) inclusions that would otherwise be scanned twice
+fn without_overlaps(archive_name: &str, inclusions: Vec<PathBuf>) -> Vec<PathBuf> {
+    let overlaps = overlap_indices(&inclusions);
+    for (index, covering) in overlaps.iter() {
+        log::warn!(
+            "{}: inclusion {:?} is ignored as it's covered by {:?}",
+            archive_name,
+            inclusions[*index],
+            inclusions[*covering]
+        );
+    }
+    inclusions
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !overlaps.iter().any(|(overlap, _)| overlap == index))
+        .map(|(_, inclusion)| inclusion)
+        .collect()
 }
 
 pub fn create_new_archive<P: AsRef<Path>>(
     name: &str,
-    content_repo_name: &str,
+    content_repo_name: Option<&str>,
     location: P,
     inclusions: &[PathBuf],
     dir_exclusions: &[String],
     file_exclusions: &[String],
-) -> EResult<()> {
+    profiles: &[String],
+    auto_repo: bool,
+) -> EResult<String> {
+    if let Some(problem) = name_problem(name) {
+        return Err(Error::InvalidArchiveName(name.to_string(), problem));
+    }
     if get_archive_spec_file_path(name).exists() {
         return Err(Error::ArchiveExists(name.to_string()));
     }
-    if !content_repo_exists(content_repo_name) {
-        return Err(Error::UnknownRepo(content_repo_name.to_string()));
-    }
+    global_config::check_exclusion_profiles(profiles)?;
     for pattern in dir_exclusions.iter() {
         let _glob = Glob::new(&pattern).map_err(|err| Error::GlobError(err))?;
     }
@@ -205,8 +609,16 @@ pub fn create_new_archive<P: AsRef<Path>>(
     for inclusion in inclusions {
         let abs_inclusion = absolute_path_buf(inclusion)
             .map_err(|e| Error::ArchiveIncludePathError(e, inclusion.to_path_buf()))?;
-        exp_inclusions.push(abs_inclusion.canonicalize()?);
+        if is_glob_inclusion(&abs_inclusion) {
+            // check that the pattern is usable now rather than at back up time
+            InclusionExpansion::expand(&abs_inclusion)?;
+            exp_inclusions.push(abs_inclusion);
+        } else {
+            exp_inclusions.push(abs_inclusion.canonicalize()?);
+        }
     }
+    let exp_inclusions = without_overlaps(name, exp_inclusions);
+    let content_repo_name = choose_content_repo(content_repo_name, location.as_ref(), auto_repo)?;
     let mut snapshot_dir_path = location.as_ref().to_path_buf();
     snapshot_dir_path.push("ergibus");
     snapshot_dir_path.push("archives");
@@ -222,42 +634,296 @@ pub fn create_new_archive<P: AsRef<Path>>(
     fs::create_dir_all(&snapshot_dir_path)
         .map_err(|err| Error::ArchiveWriteError(err, snapshot_dir_path.clone()))?;
     let spec = ArchiveSpec {
-        content_repo_name: content_repo_name.to_string(),
+        content_repo_name: content_repo_name.clone(),
         snapshot_dir_path: snapshot_dir_path,
         inclusions: exp_inclusions,
         dir_exclusions: dir_exclusions.to_vec(),
         file_exclusions: file_exclusions.to_vec(),
+        profiles: profiles.to_vec(),
+        budget: SnapshotBudget::default(),
+        backup_interval_hours: None,
+        overflow: None,
+        compression: None,
+        encryption: SnapshotEncryption::default(),
+        retention: RetentionPolicy::default(),
+        prune_hook: None,
+        case_insensitive_globs: false,
+        max_depth: None,
+        unknown_fields: BTreeMap::new(),
     };
     write_archive_spec(name, &spec, false)?;
-    Ok(())
+    Ok(content_repo_name)
 }
 
 pub fn delete_archive(archive_name: &str) -> EResult<()> {
     let snapshot_dir = Snapshots::try_from(archive_name)?;
     let spec_file_path = get_archive_spec_file_path(archive_name);
     fs::remove_file(&spec_file_path)?;
-    snapshot_dir.delete()
+    let unreferenced_size = snapshot_dir.delete()?;
+    audit::record(
+        AuditOperation::DeleteArchive,
+        archive_name,
+        unreferenced_size,
+    );
+    Ok(())
+}
+
+// Everything needed to recreate archive (and repository) configurations
+// on a new machine after the configuration directory has been lost.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+struct ConfigBundle {
+    archives: BTreeMap<String, ArchiveSpec>,
+    repos: BTreeMap<String, RepoSpec>,
+    #[serde(default)]
+    profiles: BTreeMap<String, ExclusionProfile>,
+}
+
+impl ConfigBundle {
+    fn write_to_file(&self, file_path: &Path) -> EResult<()> {
+        let json_text = serde_json::to_string(self)
+            .map_err(|err| Error::ConfigBundleJsonError(err, file_path.to_path_buf()))?;
+        atomic_file::replace_file(file_path, |writer| {
+            let mut snappy_wtr = snap::write::FrameEncoder::new(writer);
+            snappy_wtr.write_all(json_text.as_bytes())?;
+            // NB: finishing explicitly so that write errors aren't lost on drop
+            snappy_wtr.into_inner().map_err(|err| err.into_error())?;
+            Ok(())
+        })
+        .map_err(|err| Error::ConfigBundleWriteError(err, file_path.to_path_buf()))
+    }
+
+    fn from_file(file_path: &Path) -> EResult<Self> {
+        let file = File::open(file_path)
+            .map_err(|err| Error::ConfigBundleReadError(err, file_path.to_path_buf()))?;
+        let mut json_text = String::new();
+        snap::read::FrameDecoder::new(file)
+            .read_to_string(&mut json_text)
+            .map_err(|err| Error::ConfigBundleReadError(err, file_path.to_path_buf()))?;
+        serde_json::from_str(&json_text)
+            .map_err(|err| Error::ConfigBundleJsonError(err, file_path.to_path_buf()))
+    }
+}
+
+/// Write the specifications of the named archives, and of the repositories
+/// and exclusion profiles that they use, to a single (compressed) file.
+pub fn export_config<P: AsRef<Path>>(archive_names: &[String], to_file_path: P) -> EResult<()> {
+    let mut bundle = ConfigBundle::default();
+    let profiles = global_config::get_exclusion_profiles()?;
+    for archive_name in archive_names.iter() {
+        let spec = read_archive_spec(archive_name)?;
+        for profile_name in spec.profiles.iter() {
+            let profile = profiles
+                .get(profile_name)
+                .ok_or_else(|| Error::UnknownExclusionProfile(profile_name.to_string()))?;
+            bundle
+                .profiles
+                .insert(profile_name.to_string(), profile.clone());
+        }
+        let overflow_repo_name = spec
+            .overflow
+            .as_ref()
+            .map(|overflow| &overflow.content_repo_name);
+        for repo_name in std::iter::once(&spec.content_repo_name).chain(overflow_repo_name) {
+            if !bundle.repos.contains_key(repo_name) {
+                let repo_spec = read_repo_spec(repo_name)?;
+                bundle.repos.insert(repo_name.clone(), repo_spec);
+            }
+        }
+        bundle.archives.insert(archive_name.to_string(), spec);
+    }
+    bundle.write_to_file(to_file_path.as_ref())
+}
+
+/// Recreate the archive and repository specifications in a file written by
+/// `export_config()`.  Repositories and exclusion profiles that are already
+/// configured are left alone.  Returns the names of the archives and repositories imported.
+pub fn import_config<P: AsRef<Path>>(
+    from_file_path: P,
+    overwrite: bool,
+) -> EResult<(Vec<String>, Vec<String>)> {
+    let bundle = ConfigBundle::from_file(from_file_path.as_ref())?;
+    if !overwrite {
+        // check before we change anything
+        for archive_name in bundle.archives.keys() {
+            if get_archive_spec_file_path(archive_name).exists() {
+                return Err(Error::ArchiveExists(archive_name.to_string()));
+            }
+        }
+    }
+    let mut repo_names = vec![];
+    for (repo_name, repo_spec) in bundle.repos.iter() {
+        if content_repo_exists(repo_name) {
+            log::info!("{}: repository already configured: not imported", repo_name);
+        } else {
+            write_repo_spec(repo_name, repo_spec)?;
+            repo_names.push(repo_name.to_string());
+        }
+    }
+    let profiles = global_config::get_exclusion_profiles()?;
+    for (profile_name, profile) in bundle.profiles.iter() {
+        if profiles.contains_key(profile_name) {
+            log::info!("{}: profile already configured: not imported", profile_name);
+        } else {
+            global_config::set_exclusion_profile(profile_name, profile.clone())?;
+        }
+    }
+    let mut archive_names = vec![];
+    for (archive_name, archive_spec) in bundle.archives.iter() {
+        write_archive_spec(archive_name, archive_spec, overwrite)?;
+        archive_names.push(archive_name.to_string());
+    }
+    Ok((archive_names, repo_names))
+}
+
+/// A directory containing snapshot files found while searching for
+/// archives whose configuration has been lost.
+#[derive(Debug)]
+pub struct DiscoveredArchive {
+    pub name: String,
+    pub snapshot_dir_path: PathBuf,
+    pub snapshot_count: usize,
+    pub latest_snapshot_path: PathBuf,
+}
+
+/// Search the directory tree under `dir_path` for directories containing snapshot files.
+pub fn discover_archives<P: AsRef<Path>>(dir_path: P) -> EResult<Vec<DiscoveredArchive>> {
+    let mut discovered = vec![];
+    for entry in walkdir::WalkDir::new(dir_path.as_ref()) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                let path = err.path().unwrap_or(dir_path.as_ref()).to_path_buf();
+                match err.into_io_error() {
+                    Some(io_err) => ignore_report_or_fail(io_err.into(), &path)?,
+                    None => log::warn!("{:?}: file system loop", path),
+                }
+                continue;
+            }
+        };
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+        let snapshot_paths =
+            match snapshot::get_snapshot_paths_in_dir(entry.path(), Order::Descending) {
+                Ok(snapshot_paths) => snapshot_paths,
+                Err(err) => {
+                    log::warn!("{:?}: skipped: {}", entry.path(), err);
+                    continue;
+                }
+            };
+        if let Some(latest_snapshot_path) = snapshot_paths.first() {
+            discovered.push(DiscoveredArchive {
+                name: entry.file_name().to_string_lossy().to_string(),
+                snapshot_dir_path: entry.path().to_path_buf(),
+                snapshot_count: snapshot_paths.len(),
+                latest_snapshot_path: latest_snapshot_path.clone(),
+            });
+        }
+    }
+    Ok(discovered)
+}
+
+/// Recreate the specification for a discovered archive using the data in
+/// its latest snapshot.  The content repository's specification is also
+/// recreated if necessary.  Inclusions are a best guess and there are no
+/// exclusions so the result should be reviewed.  Returns the repository name.
+pub fn regenerate_archive_spec(discovered: &DiscoveredArchive) -> EResult<String> {
+    if get_archive_spec_file_path(&discovered.name).exists() {
+        return Err(Error::ArchiveExists(discovered.name.clone()));
+    }
+    let snapshot = SnapshotPersistentData::from_file(&discovered.latest_snapshot_path)?;
+    let key = snapshot.content_mgmt_key();
+    let content_repo_name = match get_repo_name_for_key(key) {
+        Some(repo_name) => repo_name,
+        None => {
+            let repo_name = key
+                .base_dir_path()
+                .file_name()
+                .ok_or_else(|| Error::UnknownRepo(format!("{:?}", key.base_dir_path())))?
+                .to_string_lossy()
+                .to_string();
+            write_repo_spec(&repo_name, &RepoSpec::from(key))?;
+            repo_name
+        }
+    };
+    let spec = ArchiveSpec {
+        content_repo_name: content_repo_name.clone(),
+        snapshot_dir_path: discovered.snapshot_dir_path.clone(),
+        inclusions: snapshot.root_dir().probable_inclusions(),
+        dir_exclusions: vec![],
+        file_exclusions: vec![],
+        profiles: vec![],
+        budget: SnapshotBudget::default(),
+        backup_interval_hours: None,
+        overflow: None,
+        compression: None,
+        encryption: SnapshotEncryption::default(),
+        retention: RetentionPolicy::default(),
+        prune_hook: None,
+        case_insensitive_globs: false,
+        max_depth: None,
+        unknown_fields: BTreeMap::new(),
+    };
+    write_archive_spec(&discovered.name, &spec, false)?;
+    Ok(content_repo_name)
 }
 
 #[derive(Debug)]
 pub struct ArchiveData {
     pub name: String,
     pub content_mgmt_key: ContentMgmtKey,
+    pub overflow: Option<Overflow>,
     pub snapshot_dir_path: PathBuf,
+    /// The inclusions with glob patterns replaced by their matches
     pub includes: Vec<PathBuf>,
+    pub inclusion_expansions: Vec<InclusionExpansion>,
     pub exclusions: Exclusions,
+    /// The number of levels below each inclusion that are read (if limited)
+    pub max_depth: Option<usize>,
+    pub budget: SnapshotBudget,
+    pub compression: Option<String>,
+    pub encryption: SnapshotEncryption,
+}
+
+impl ArchiveData {
+    /// Check that back ups can honour the archive's compression and
+    /// encryption requirements.
+    pub fn check_policies(&self) -> EResult<()> {
+        if let Some(ref compression) = self.compression {
+            if compression != SUPPORTED_COMPRESSION {
+                return Err(Error::UnsupportedCompression(
+                    compression.to_string(),
+                    self.name.to_string(),
+                ));
+            }
+        }
+        if self.encryption == SnapshotEncryption::Required
+            && !encryption::has_encryption_key(&self.name)
+        {
+            return Err(Error::EncryptionKeyMissing(self.name.to_string()));
+        }
+        Ok(())
+    }
 }
 
 pub fn get_archive_data(archive_name: &str) -> EResult<ArchiveData> {
     let archive_spec = read_archive_spec(archive_name)?;
     let name = archive_name.to_string();
     let content_mgmt_key = get_content_mgmt_key(&archive_spec.content_repo_name)?;
+    let overflow = match archive_spec.overflow {
+        Some(ref overflow) => Some(Overflow {
+            content_mgmt_key: get_content_mgmt_key(&overflow.content_repo_name)?,
+            min_file_size: overflow.min_file_size,
+        }),
+        None => None,
+    };
     let snapshot_dir_path = archive_spec
         .snapshot_dir_path
         .canonicalize()
         .map_err(|err| Error::ArchiveDirError(err, archive_spec.snapshot_dir_path.clone()))?;
     // recheck paths in case spec file has been manually edited
     let mut includes = Vec::new();
+    let mut inclusion_expansions = vec![];
     for inclusion in archive_spec.inclusions {
         let included_file_path = if inclusion.starts_with("~") {
             expand_home_dir(&inclusion)
@@ -270,24 +936,179 @@ pub fn get_archive_data(archive_name: &str) -> EResult<ArchiveData> {
                 archive_name.to_string(),
             ));
         };
-        includes.push(included_file_path);
+        if is_glob_inclusion(&included_file_path) {
+            let expansion = InclusionExpansion::expand(&included_file_path)?;
+            if expansion.matches.is_empty() {
+                log::warn!(
+                    "{}: inclusion {:?} matches nothing",
+                    archive_name,
+                    included_file_path
+                );
+            }
+            includes.extend(expansion.matches.iter().cloned());
+            inclusion_expansions.push(expansion);
+        } else {
+            includes.push(included_file_path);
+        }
     }
-    let exclusions = Exclusions::new(&archive_spec.dir_exclusions, &archive_spec.file_exclusions)?;
+    let includes = without_overlaps(archive_name, includes);
+    let (dir_exclusions, file_exclusions) = global_config::merge_exclusions(
+        &archive_spec.profiles,
+        &archive_spec.dir_exclusions,
+        &archive_spec.file_exclusions,
+    )?;
+    let exclusions = Exclusions::new(
+        &dir_exclusions,
+        &file_exclusions,
+        archive_spec.case_insensitive_globs,
+    )?;
 
     Ok(ArchiveData {
         name,
         content_mgmt_key,
+        overflow,
         snapshot_dir_path,
         includes,
+        inclusion_expansions,
         exclusions,
+        max_depth: archive_spec.max_depth,
+        budget: archive_spec.budget,
+        compression: archive_spec.compression,
+        encryption: archive_spec.encryption,
     })
 }
 
+pub fn get_snapshot_budget(archive_name: &str) -> EResult<SnapshotBudget> {
+    Ok(read_archive_spec(archive_name)?.budget)
+}
+
+pub fn set_snapshot_budget(archive_name: &str, budget: SnapshotBudget) -> EResult<()> {
+    let mut archive_spec = read_archive_spec(archive_name)?;
+    archive_spec.budget = budget;
+    write_archive_spec(archive_name, &archive_spec, true)
+}
+
+/// The repository (if any) for the contents of the archive's large files.
+pub fn get_archive_overflow(archive_name: &str) -> EResult<Option<OverflowSpec>> {
+    Ok(read_archive_spec(archive_name)?.overflow)
+}
+
+/// Send the contents of the archive's large files to another repository
+/// (or stop doing so).  The repository must use the same hash algorithm
+/// as the archive's repository.  Contents already stored stay where they are.
+pub fn set_archive_overflow(archive_name: &str, overflow: Option<OverflowSpec>) -> EResult<()> {
+    let mut archive_spec = read_archive_spec(archive_name)?;
+    if let Some(ref overflow) = overflow {
+        let repo_name = &overflow.content_repo_name;
+        if *repo_name == archive_spec.content_repo_name {
+            return Err(Error::OverflowRepoIsPrimary(repo_name.to_string()));
+        }
+        if !content_repo_exists(repo_name) {
+            return Err(Error::UnknownRepo(repo_name.to_string()));
+        }
+        let primary_key = get_content_mgmt_key(&archive_spec.content_repo_name)?;
+        if get_content_mgmt_key(repo_name)?.hash_algorithm() != primary_key.hash_algorithm() {
+            return Err(Error::OverflowRepoHashMismatch(repo_name.to_string()));
+        }
+    }
+    archive_spec.overflow = overflow;
+    write_archive_spec(archive_name, &archive_spec, true)
+}
+
+/// The compression algorithm (if any) required for the archive's contents.
+pub fn get_archive_compression(archive_name: &str) -> EResult<Option<String>> {
+    Ok(read_archive_spec(archive_name)?.compression)
+}
+
+pub fn set_archive_compression(archive_name: &str, compression: Option<&str>) -> EResult<()> {
+    let mut archive_spec = read_archive_spec(archive_name)?;
+    archive_spec.compression = compression.map(|text| text.to_string());
+    write_archive_spec(archive_name, &archive_spec, true)
+}
+
+pub fn get_snapshot_encryption(archive_name: &str) -> EResult<SnapshotEncryption> {
+    Ok(read_archive_spec(archive_name)?.encryption)
+}
+
+pub fn set_snapshot_encryption(archive_name: &str, encryption: SnapshotEncryption) -> EResult<()> {
+    let mut archive_spec = read_archive_spec(archive_name)?;
+    archive_spec.encryption = encryption;
+    write_archive_spec(archive_name, &archive_spec, true)
+}
+
+pub fn get_retention_policy(archive_name: &str) -> EResult<RetentionPolicy> {
+    Ok(read_archive_spec(archive_name)?.retention)
+}
+
+pub fn set_retention_policy(archive_name: &str, retention: RetentionPolicy) -> EResult<()> {
+    let mut archive_spec = read_archive_spec(archive_name)?;
+    archive_spec.retention = retention;
+    write_archive_spec(archive_name, &archive_spec, true)
+}
+
+/// The command run after the archive's snapshots are deleted (see
+/// `prune_hooks`).
+pub fn get_prune_hook(archive_name: &str) -> EResult<Option<String>> {
+    Ok(read_archive_spec(archive_name)?.prune_hook)
+}
+
+pub fn set_prune_hook(archive_name: &str, command: Option<&str>) -> EResult<()> {
+    let mut archive_spec = read_archive_spec(archive_name)?;
+    archive_spec.prune_hook = command.map(|command| command.to_string());
+    write_archive_spec(archive_name, &archive_spec, true)
+}
+
+/// Whether the archive's exclusion globs are matched ignoring case.
+pub fn get_case_insensitive_globs(archive_name: &str) -> EResult<bool> {
+    Ok(read_archive_spec(archive_name)?.case_insensitive_globs)
+}
+
+pub fn set_case_insensitive_globs(archive_name: &str, case_insensitive: bool) -> EResult<()> {
+    let mut archive_spec = read_archive_spec(archive_name)?;
+    archive_spec.case_insensitive_globs = case_insensitive;
+    write_archive_spec(archive_name, &archive_spec, true)
+}
+
+/// The names of the exclusion profiles used by the archive.
+pub fn get_archive_profiles(archive_name: &str) -> EResult<Vec<String>> {
+    Ok(read_archive_spec(archive_name)?.profiles)
+}
+
+pub fn set_archive_profiles(archive_name: &str, profiles: &[String]) -> EResult<()> {
+    global_config::check_exclusion_profiles(profiles)?;
+    let mut archive_spec = read_archive_spec(archive_name)?;
+    archive_spec.profiles = profiles.to_vec();
+    write_archive_spec(archive_name, &archive_spec, true)
+}
+
 // for read only snapshot actions we only need the snapshot directory path
 // as the content manager key data is in the snapshot file.
 // NB: this means that we can use snapshots even if the configuration
 // data has been lost due to a file system failure (but in that case
 // the user will have to browse the file system to find the snapshots).
+/// How often (in hours) the archive is expected to be backed up, if known.
+pub fn get_backup_interval_hours(archive_name: &str) -> EResult<Option<u64>> {
+    Ok(read_archive_spec(archive_name)?.backup_interval_hours)
+}
+
+pub fn set_backup_interval_hours(archive_name: &str, hours: Option<u64>) -> EResult<()> {
+    let mut archive_spec = read_archive_spec(archive_name)?;
+    archive_spec.backup_interval_hours = hours;
+    write_archive_spec(archive_name, &archive_spec, true)
+}
+
+/// The number of levels below each inclusion that the archive's back ups
+/// read (if limited).
+pub fn get_max_depth(archive_name: &str) -> EResult<Option<usize>> {
+    Ok(read_archive_spec(archive_name)?.max_depth)
+}
+
+pub fn set_max_depth(archive_name: &str, max_depth: Option<usize>) -> EResult<()> {
+    let mut archive_spec = read_archive_spec(archive_name)?;
+    archive_spec.max_depth = max_depth;
+    write_archive_spec(archive_name, &archive_spec, true)
+}
+
 pub fn get_archive_snapshot_dir_path(archive_name: &str) -> EResult<PathBuf> {
     let archive_spec = read_archive_spec(archive_name)?;
     PathBuf::from(&archive_spec.snapshot_dir_path)
@@ -295,23 +1116,133 @@ pub fn get_archive_snapshot_dir_path(archive_name: &str) -> EResult<PathBuf> {
         .map_err(|err| Error::ArchiveDirError(err, PathBuf::from(&archive_spec.snapshot_dir_path)))
 }
 
+/// The names of the archives (in alphabetical order).
 pub fn get_archive_names() -> Vec<String> {
-    let mut names = Vec::new();
-    if let Ok(dir_entries) = fs::read_dir(config::get_archive_config_dir_path()) {
-        for entry_or_err in dir_entries {
-            if let Ok(entry) = entry_or_err {
-                let path = entry.path();
-                if path.is_file() {
-                    if let Some(file_name) = path.file_name() {
-                        if let Some(file_name) = file_name.to_str() {
-                            names.push(file_name.to_string());
-                        }
-                    }
-                }
+    archive_registry::archive_names()
+}
+
+/// The names of the archives whose specifications nominate the repository
+/// for storing their file contents.
+pub fn get_archive_names_using_repo(repo_name: &str) -> Vec<String> {
+    get_archive_names()
+        .into_iter()
+        .filter(|archive_name| match read_archive_spec(archive_name) {
+            Ok(spec) => spec.uses_repo(repo_name),
+            Err(err) => {
+                log::warn!("{}: {}", archive_name, err);
+                false
             }
+        })
+        .collect()
+}
+
+/// Whether a file system object would be included in the archive's snapshots.
+#[derive(Debug, PartialEq, Clone)]
+pub enum PreviewStatus {
+    Included,
+    /// Excluded by the given glob pattern
+    Excluded(String),
+    /// Not a directory, regular file or symbolic link
+    Unsupported,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PreviewItem {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub depth: usize,
+    pub status: PreviewStatus,
+}
+
+fn preview_dir(
+    dir_path: &Path,
+    depth: usize,
+    max_depth: Option<usize>,
+    exclusions: &Exclusions,
+    items: &mut Vec<PreviewItem>,
+) {
+    let mut entries: Vec<fs::DirEntry> = match fs::read_dir(dir_path) {
+        Ok(read_dir) => read_dir.filter_map(|e| e.ok()).collect(),
+        Err(err) => {
+            log::warn!("{:?}: {:?}", dir_path, err);
+            return;
         }
     };
-    names
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries.iter() {
+        let path = entry.path();
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(err) => {
+                log::warn!("{:?}: {:?}", path, err);
+                continue;
+            }
+        };
+        let (is_dir, status) = if file_type.is_dir() {
+            match exclusions.dir_exclusion_pattern(&path) {
+                Some(pattern) => (true, PreviewStatus::Excluded(pattern.to_string())),
+                None => (true, PreviewStatus::Included),
+            }
+        } else if file_type.is_file() || file_type.is_symlink() {
+            match exclusions.file_exclusion_pattern(&path) {
+                Some(pattern) => (false, PreviewStatus::Excluded(pattern.to_string())),
+                None => (false, PreviewStatus::Included),
+            }
+        } else {
+            (false, PreviewStatus::Unsupported)
+        };
+        let descend = is_dir
+            && status == PreviewStatus::Included
+            && !matches!(max_depth, Some(max_depth) if depth >= max_depth);
+        items.push(PreviewItem {
+            path: path.clone(),
+            is_dir,
+            depth,
+            status,
+        });
+        if descend {
+            preview_dir(&path, depth + 1, max_depth, exclusions, items);
+        }
+    }
+}
+
+/// List the file system objects that the archive's next snapshot would
+/// include or exclude (and why) without storing anything.  Excluded
+/// directories aren't descended into, inclusions are at depth zero and
+/// nothing deeper than the archive's maximum depth (if any) is shown.
+pub fn preview_archive(archive_name: &str, max_depth: Option<usize>) -> EResult<Vec<PreviewItem>> {
+    let archive_data = get_archive_data(archive_name)?;
+    let max_depth = match (max_depth, archive_data.max_depth) {
+        (Some(max_depth), Some(archive_max_depth)) => Some(max_depth.min(archive_max_depth)),
+        (max_depth, archive_max_depth) => max_depth.or(archive_max_depth),
+    };
+    let mut items = vec![];
+    for inclusion in archive_data.includes.iter() {
+        let metadata = match inclusion.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                log::warn!("{:?}: {:?}", inclusion, err);
+                continue;
+            }
+        };
+        let is_dir = metadata.file_type().is_dir();
+        items.push(PreviewItem {
+            path: inclusion.clone(),
+            is_dir,
+            depth: 0,
+            status: PreviewStatus::Included,
+        });
+        if is_dir && max_depth != Some(0) {
+            preview_dir(
+                inclusion,
+                1,
+                max_depth,
+                &archive_data.exclusions,
+                &mut items,
+            );
+        }
+    }
+    Ok(items)
 }
 
 #[derive(Debug, Clone)]
@@ -332,10 +1263,116 @@ impl From<&Path> for ArchiveNameOrDirPath {
     }
 }
 
+// A plain name is relative to the target directory but anything else
+// (e.g. "~/restored" or "../restored") stands on its own.
+fn target_path_with_name(into_dir_path: &Path, with_name: &Path) -> EResult<PathBuf> {
+    match PathType::of(with_name) {
+        PathType::RelativeCurDirImplicit => Ok(into_dir_path.join(with_name)),
+        _ => snapshot::absolute_target_path(with_name),
+    }
+}
+
+/// Partial snapshot files are only considered stale once they're this old
+/// so that snapshots still being written aren't disturbed.
+pub const STALE_FILE_MIN_AGE: time::Duration = time::Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Default)]
+pub struct GarbageStats {
+    pub removed_files: Vec<PathBuf>,
+    pub removed_dirs: Vec<PathBuf>,
+    pub bytes_freed: u64,
+}
+
+// The "ergibus/archives" directory containing the hostname/user/archive snapshot directories.
+fn archives_root_dir_path(snapshot_dir_path: &Path) -> Option<&Path> {
+    snapshot_dir_path.ancestors().skip(1).find(|path| {
+        path.file_name() == Some("archives".as_ref())
+            && path.parent().and_then(|parent| parent.file_name()) == Some("ergibus".as_ref())
+    })
+}
+
+fn is_empty_dir(dir_path: &Path) -> bool {
+    match fs::read_dir(dir_path) {
+        Ok(mut read_dir) => read_dir.next().is_none(),
+        Err(_) => false,
+    }
+}
+
+// Remove the (deleted) snapshot directory's ancestors that have been left empty.
+fn remove_empty_ancestors(dir_path: &Path) -> EResult<Vec<PathBuf>> {
+    let mut removed_dirs = vec![];
+    if let Some(root_dir_path) = archives_root_dir_path(dir_path) {
+        for ancestor in dir_path.ancestors().skip(1) {
+            if ancestor == root_dir_path || !is_empty_dir(ancestor) {
+                break;
+            }
+            fs::remove_dir(ancestor)?;
+            removed_dirs.push(ancestor.to_path_buf());
+        }
+    }
+    Ok(removed_dirs)
+}
+
+/// Remove stale partial files from the archive's snapshot directory and
+/// then that directory (unless it's the snapshot directory of a defined
+/// archive) and its ancestors up to the archives' root directory while
+/// they're empty.  Other directories sharing the root are left alone as
+/// they may belong to other configurations.
+pub fn collect_garbage(archive_name: &str, min_age: time::Duration) -> EResult<GarbageStats> {
+    let snapshots = Snapshots::try_from(archive_name)?;
+    let mut stats = snapshots.collect_garbage(min_age)?;
+    let keep: Vec<PathBuf> = get_archive_names()
+        .iter()
+        .filter_map(|name| get_archive_snapshot_dir_path(name).ok())
+        .collect();
+    if let Some(root_dir_path) = archives_root_dir_path(&snapshots.dir_path) {
+        for dir_path in snapshots
+            .dir_path
+            .ancestors()
+            .take_while(|path| *path != root_dir_path)
+        {
+            if keep.iter().any(|path| path == dir_path) || !is_empty_dir(dir_path) {
+                break;
+            }
+            fs::remove_dir(dir_path)?;
+            stats.removed_dirs.push(dir_path.to_path_buf());
+        }
+    }
+    Ok(stats)
+}
+
+/// What `Snapshots::prune_until_free()` did (or would have done).
+#[derive(Debug, Default, PartialEq)]
+pub struct PruneOutcome {
+    /// The names of the (oldest) snapshots deleted
+    pub deleted: Vec<OsString>,
+    /// The estimated stored size of the contents no longer referenced
+    pub estimated_bytes: u64,
+    /// The stored size of the contents no longer referenced (zero for a
+    /// dry run).  The space is reclaimed when the repository is next pruned.
+    pub reclaimed_bytes: u64,
+}
+
+/// A file as it was in one of an archive's snapshots (see
+/// `Snapshots::file_history()`).
+#[derive(Debug, PartialEq, Clone)]
+pub struct FileVersion {
+    pub snapshot_name: OsString,
+    pub size: u64,
+    /// The file's modification time (seconds since the epoch)
+    pub mtime: i64,
+    pub content_token: String,
+    /// Whether the contents differ from those in the previous snapshot
+    /// containing the file (always true for the first)
+    pub content_changed: bool,
+}
+
 #[derive(Debug)]
 pub struct Snapshots {
     archive_name: Option<String>,
     dir_path: PathBuf,
+    repo_location: Option<PathBuf>,
+    require_signed: bool,
 }
 
 impl TryFrom<&str> for Snapshots {
@@ -347,6 +1384,8 @@ impl TryFrom<&str> for Snapshots {
         Ok(Self {
             archive_name,
             dir_path,
+            repo_location: None,
+            require_signed: false,
         })
     }
 }
@@ -361,6 +1400,8 @@ impl TryFrom<&Path> for Snapshots {
         Ok(Self {
             archive_name: None,
             dir_path,
+            repo_location: None,
+            require_signed: false,
         })
     }
 }
@@ -374,14 +1415,121 @@ impl Snapshots {
         }
     }
 
-    pub fn delete(&self) -> EResult<()> {
+    /// Override the location of the content repository recorded in the snapshots.
+    pub fn set_repo_location(&mut self, repo_location: Option<PathBuf>) {
+        self.repo_location = repo_location;
+    }
+
+    /// Refuse to use snapshots that haven't been signed.
+    pub fn set_require_signed(&mut self, require_signed: bool) {
+        self.require_signed = require_signed;
+    }
+
+    /// Read the snapshot (refusing it if it should have been signed).
+    pub fn read_snapshot(&self, snapshot_file_path: &Path) -> EResult<SnapshotPersistentData> {
+        let mut spd =
+            SnapshotPersistentData::from_file_verified(snapshot_file_path, self.require_signed)?;
+        if let Some(ref repo_location) = self.repo_location {
+            spd.set_repo_location(repo_location);
+        }
+        Ok(spd)
+    }
+
+    pub fn delete(&self) -> EResult<u64> {
         let snapshot_paths = self.get_snapshot_paths(Order::Ascending)?;
+        let mut unreferenced_size = 0;
         // NB: this necessary to free all the references to content data
         for snapshot_path in snapshot_paths.iter() {
-            snapshot::delete_snapshot_file(snapshot_path)?;
+            unreferenced_size += snapshot::delete_snapshot_file(snapshot_path)?;
+        }
+        for file_name in [
+            snapshot::LATEST_POINTER_FILE_NAME,
+            checksums::CHECKSUMS_FILE_NAME,
+        ] {
+            match fs::remove_file(self.dir_path.join(file_name)) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => (),
+            }
         }
         fs::remove_dir(&self.dir_path)?;
-        Ok(())
+        if let Err(err) = remove_empty_ancestors(&self.dir_path) {
+            log::warn!(
+                "{:?}: failed to remove empty directories: {:?}",
+                self.dir_path,
+                err
+            );
+        }
+        Ok(unreferenced_size)
+    }
+
+    /// Remove stale partial files from the snapshot directory.
+    pub fn collect_garbage(&self, min_age: time::Duration) -> EResult<GarbageStats> {
+        let mut stats = GarbageStats::default();
+        for file_path in snapshot::get_stale_partial_files_in_dir(&self.dir_path, min_age)? {
+            let size = fs::metadata(&file_path).map(|m| m.len()).unwrap_or(0);
+            fs::remove_file(&file_path)?;
+            stats.removed_files.push(file_path);
+            stats.bytes_freed += size;
+        }
+        if !stats.removed_files.is_empty() {
+            audit::record(
+                AuditOperation::CollectGarbage,
+                &self.dir_path.to_string_lossy(),
+                stats.bytes_freed,
+            );
+        }
+        Ok(stats)
+    }
+
+    // Tidying up after deletions isn't important enough to fail them
+    // Tell the archive's prune hook (if any) about the deleted snapshots.
+    fn notify_pruned(
+        &self,
+        operation: PruneOperation,
+        deleted_paths: &[PathBuf],
+        freed_bytes: u64,
+    ) {
+        let archive_name = match self.archive_name {
+            Some(ref archive_name) => archive_name,
+            None => return,
+        };
+        let command = match get_prune_hook(archive_name) {
+            Ok(command) => command,
+            Err(err) => {
+                log::warn!("{}: failed to read prune hook: {}", archive_name, err);
+                return;
+            }
+        };
+        if command.is_none() {
+            return;
+        }
+        let summary = PruneSummary {
+            archive: archive_name.clone(),
+            operation,
+            deleted: deleted_paths
+                .iter()
+                .filter_map(|path| Some(path.file_name()?.to_string_lossy().to_string()))
+                .collect(),
+            deleted_count: deleted_paths.len(),
+            freed_bytes,
+            remaining_snapshots: self
+                .get_snapshot_paths(Order::Ascending)
+                .map(|paths| paths.len())
+                .unwrap_or(0),
+        };
+        prune_hooks::notify(command.as_deref(), &summary);
+    }
+
+    fn tidy_up(&self) {
+        if let Err(err) = self.collect_garbage(STALE_FILE_MIN_AGE) {
+            log::warn!("{:?}: failed to collect garbage: {:?}", self.dir_path, err);
+        }
+    }
+
+    /// Compare each snapshot file's checksum with that recorded when it
+    /// was written (oldest first).
+    pub fn verify_checksums(&self) -> EResult<Vec<(PathBuf, checksums::ChecksumStatus)>> {
+        checksums::verify_snapshot_dir(&self.dir_path)
     }
 
     pub fn get_snapshot_paths(&self, order: Order) -> EResult<Vec<PathBuf>> {
@@ -392,6 +1540,12 @@ impl Snapshots {
         snapshot::get_snapshot_names_in_dir(&self.dir_path, order)
     }
 
+    pub fn get_latest_snapshot_path(&self) -> EResult<PathBuf> {
+        snapshot::iter_snapshot_paths_in_dir(&self.dir_path, Order::Descending)?
+            .next()
+            .ok_or_else(|| Error::ArchiveEmpty(self.id()))
+    }
+
     pub fn get_snapshot_path_back_n(&self, n: i64) -> EResult<PathBuf> {
         let snapshot_paths = self.get_snapshot_paths(Order::Ascending)?;
         if snapshot_paths.len() == 0 {
@@ -408,9 +1562,24 @@ impl Snapshots {
         Ok(snapshot_paths[index].clone())
     }
 
+    /// The path of the snapshot taken nearest to `time` (the earlier of two
+    /// equally near).
+    pub fn get_snapshot_path_nearest(&self, time: DateTime<Local>) -> EResult<PathBuf> {
+        // NB: min_by_key() returns the first of equal minima
+        self.get_snapshot_paths(Order::Ascending)?
+            .into_iter()
+            .filter_map(|snapshot_path| {
+                let snapshot_time = snapshot::snapshot_name_time(snapshot_path.file_name()?)?;
+                Some(((snapshot_time - time).abs(), snapshot_path))
+            })
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, snapshot_path)| snapshot_path)
+            .ok_or_else(|| Error::ArchiveEmpty(self.id()))
+    }
+
     pub fn get_snapshot_back_n(&self, n: i64) -> EResult<SnapshotPersistentData> {
         let snapshot_file_path = self.get_snapshot_path_back_n(n)?;
-        SnapshotPersistentData::from_file(&snapshot_file_path)
+        self.read_snapshot(&snapshot_file_path)
     }
 
     pub fn delete_all_but_newest(&self, newest_count: usize, clear_fell: bool) -> EResult<usize> {
@@ -426,13 +1595,175 @@ impl Snapshots {
             return Ok(0);
         }
         let last_index = snapshot_paths.len() - newest_count;
+        let mut unreferenced_size = 0;
         for snapshot_path in snapshot_paths[0..last_index].iter() {
-            snapshot::delete_snapshot_file(snapshot_path)?;
+            unreferenced_size += snapshot::delete_snapshot_file(snapshot_path)?;
             deleted_count += 1;
         }
+        audit::record(
+            AuditOperation::Prune,
+            &format!(
+                "{}: kept newest {}, deleted {}",
+                self.dir_path.display(),
+                newest_count,
+                deleted_count
+            ),
+            unreferenced_size,
+        );
+        self.notify_pruned(
+            PruneOperation::KeepNewest,
+            &snapshot_paths[0..last_index],
+            unreferenced_size,
+        );
+        self.tidy_up();
         Ok(deleted_count)
     }
 
+    /// Delete the snapshots that have expired under the retention policy.
+    pub fn delete_expired(&self, retention: &RetentionPolicy) -> EResult<usize> {
+        let snapshot_paths = self.get_snapshot_paths(Order::Ascending)?;
+        let now = time::SystemTime::now();
+        // snapshots whose names aren't times never expire
+        let snapshot_times: Vec<time::SystemTime> = snapshot_paths
+            .iter()
+            .map(|path| {
+                path.file_name()
+                    .and_then(snapshot::snapshot_name_time)
+                    .map(time::SystemTime::from)
+                    .unwrap_or(now)
+            })
+            .collect();
+        let expired_count = retention.expired_count(&snapshot_times, now);
+        let mut unreferenced_size = 0;
+        for snapshot_path in snapshot_paths[..expired_count].iter() {
+            unreferenced_size += snapshot::delete_snapshot_file(snapshot_path)?;
+        }
+        if expired_count > 0 {
+            audit::record(
+                AuditOperation::Prune,
+                &format!(
+                    "{}: deleted {} expired",
+                    self.dir_path.display(),
+                    expired_count
+                ),
+                unreferenced_size,
+            );
+            self.notify_pruned(
+                PruneOperation::Expired,
+                &snapshot_paths[..expired_count],
+                unreferenced_size,
+            );
+            self.tidy_up();
+        }
+        Ok(expired_count)
+    }
+
+    /// Estimate the stored size of the contents that would no longer be
+    /// referenced after deleting each successive prefix of `snapshot_paths`
+    /// (i.e. entry `i` is for deleting snapshots `0..=i`).  Contents only
+    /// count once all of their references are in the deleted snapshots.
+    pub fn estimate_reclaimable_bytes(&self, snapshot_paths: &[PathBuf]) -> EResult<Vec<u64>> {
+        let mut released: HashMap<String, u64> = HashMap::new();
+        let mut store: Option<(ContentKeys, TieredContentManager)> = None;
+        let mut reclaimable = 0;
+        let mut estimates = vec![];
+        for snapshot_path in snapshot_paths.iter() {
+            let snapshot = self.read_snapshot(snapshot_path)?;
+            let content_keys = snapshot.relocated_content_keys()?;
+            if !matches!(store, Some((ref keys, _)) if *keys == content_keys) {
+                let content_mgr = content_keys.open_content_store(Mutability::Immutable)?;
+                store = Some((content_keys, content_mgr));
+            }
+            let (_, content_mgr) = store.as_ref().expect(UNEXPECTED);
+            for (token, usage) in snapshot.content_tokens() {
+                let count = released.entry(token.to_string()).or_insert(0);
+                *count += usage.references;
+                if content_mgr.token_ref_count(token) == Some(*count) {
+                    reclaimable += content_mgr.token_stored_size(token).unwrap_or(0);
+                }
+            }
+            estimates.push(reclaimable);
+        }
+        Ok(estimates)
+    }
+
+    /// Delete the oldest snapshots (always keeping the newest `keep_newest`,
+    /// or more if the archive's retention policy says so, and at least one)
+    /// until the estimated stored size of the contents no longer referenced
+    /// reaches `target_bytes`.  Nothing is deleted if the target can't be
+    /// reached or if `dry_run`.
+    pub fn prune_until_free(
+        &self,
+        target_bytes: u64,
+        keep_newest: usize,
+        dry_run: bool,
+    ) -> EResult<PruneOutcome> {
+        let snapshot_paths = self.get_snapshot_paths(Order::Ascending)?;
+        if snapshot_paths.is_empty() {
+            return Err(Error::ArchiveEmpty(self.id()));
+        }
+        let policy_keep_newest = match self.archive_name {
+            Some(ref archive_name) => get_retention_policy(archive_name)?.keep_newest,
+            None => None,
+        };
+        let keep_newest = keep_newest.max(policy_keep_newest.unwrap_or(0)).max(1);
+        let candidates = &snapshot_paths[..snapshot_paths.len().saturating_sub(keep_newest)];
+        let estimates = self.estimate_reclaimable_bytes(candidates)?;
+        let count = if target_bytes == 0 {
+            0
+        } else {
+            match estimates
+                .iter()
+                .position(|estimate| *estimate >= target_bytes)
+            {
+                Some(index) => index + 1,
+                None => {
+                    let archive = match self.archive_name {
+                        Some(ref archive_name) => archive_name.clone(),
+                        None => self.dir_path.to_string_lossy().to_string(),
+                    };
+                    return Err(Error::PruneInsufficientSpace(
+                        archive,
+                        target_bytes,
+                        estimates.last().copied().unwrap_or(0),
+                        keep_newest,
+                    ));
+                }
+            }
+        };
+        let mut outcome = PruneOutcome::default();
+        if count > 0 {
+            outcome.estimated_bytes = estimates[count - 1];
+        }
+        for snapshot_path in candidates[..count].iter() {
+            if !dry_run {
+                outcome.reclaimed_bytes += snapshot::delete_snapshot_file(snapshot_path)?;
+            }
+            if let Some(file_name) = snapshot_path.file_name() {
+                outcome.deleted.push(file_name.to_os_string());
+            }
+        }
+        if !dry_run && count > 0 {
+            audit::record(
+                AuditOperation::Prune,
+                &format!(
+                    "{}: until {} bytes free, deleted {}",
+                    self.dir_path.display(),
+                    target_bytes,
+                    count
+                ),
+                outcome.reclaimed_bytes,
+            );
+            self.notify_pruned(
+                PruneOperation::UntilFree,
+                &candidates[..count],
+                outcome.reclaimed_bytes,
+            );
+            self.tidy_up();
+        }
+        Ok(outcome)
+    }
+
     pub fn delete_ss_back_n(&self, n: i64, clear_fell: bool) -> EResult<usize> {
         let snapshot_paths = self.get_snapshot_paths(Order::Descending)?;
         if snapshot_paths.len() == 0 {
@@ -450,6 +1781,7 @@ impl Snapshots {
             return Err(Error::LastSnapshot(self.id()));
         }
         snapshot::delete_snapshot_file(&snapshot_paths[index])?;
+        self.tidy_up();
         Ok(1)
     }
 
@@ -460,12 +1792,14 @@ impl Snapshots {
         into_dir_path: &Path,
         opt_with_name: &Option<PathBuf>,
         overwrite: bool,
+        force: bool,
     ) -> EResult<(u64, time::Duration)> {
         let started_at = time::SystemTime::now();
 
         let snapshot_file_path = self.get_snapshot_path_back_n(n)?;
+        let into_dir_path = snapshot::absolute_target_path(into_dir_path)?;
         let target_path = if let Some(with_name) = opt_with_name {
-            into_dir_path.join(with_name)
+            target_path_with_name(&into_dir_path, with_name)?
         } else if let Some(file_name) = file_path.file_name() {
             into_dir_path.join(file_name)
         } else {
@@ -476,8 +1810,8 @@ impl Snapshots {
             _ => absolute_path_buf(file_path)
                 .map_err(|e| Error::ArchiveIncludePathError(e, file_path.to_path_buf()))?,
         };
-        let spd = SnapshotPersistentData::from_file(&snapshot_file_path)?;
-        let bytes = spd.copy_file_to(&src_file_path, &target_path, overwrite)?;
+        let spd = self.read_snapshot(&snapshot_file_path)?;
+        let bytes = spd.copy_file_to(&src_file_path, &target_path, overwrite, force)?;
 
         let finished_at = time::SystemTime::now();
         let duration = match finished_at.duration_since(started_at) {
@@ -487,19 +1821,129 @@ impl Snapshots {
         Ok((bytes, duration))
     }
 
+    /// Restore a file from the snapshot to where it was when the snapshot
+    /// was made (see `SnapshotPersistentData::restore_file()`).
+    pub fn restore_file(
+        &self,
+        n: i64,
+        file_path: &Path,
+        overwrite: bool,
+        force: bool,
+    ) -> EResult<(PathBuf, u64)> {
+        self.get_snapshot_back_n(n)?
+            .restore_file(file_path, overwrite, force)
+    }
+
+    // Call `f` for each snapshot (oldest first) that contains the file.
+    fn for_each_file_version<F>(&self, file_path: &Path, mut f: F) -> EResult<()>
+    where
+        F: FnMut(&SnapshotPersistentData, FileVersion) -> EResult<()>,
+    {
+        let mut previous_token: Option<String> = None;
+        for snapshot_path in self.get_snapshot_paths(Order::Ascending)? {
+            let spd = self.read_snapshot(&snapshot_path)?;
+            let file_data = match spd.find_file(file_path) {
+                Ok(file_data) => file_data,
+                Err(Error::SnapshotUnknownFile(_)) | Err(Error::SnapshotUnknownDirectory(_)) => {
+                    continue
+                }
+                Err(err) => return Err(err),
+            };
+            let content_token = file_data.content_token().to_string();
+            let version = FileVersion {
+                snapshot_name: snapshot_path.file_name().expect(UNEXPECTED).to_os_string(),
+                size: file_data.attributes().size(),
+                mtime: file_data.attributes().mtime(),
+                content_changed: previous_token.as_ref() != Some(&content_token),
+                content_token,
+            };
+            previous_token = Some(version.content_token.clone());
+            f(&spd, version)?;
+        }
+        Ok(())
+    }
+
+    /// The versions of the file in the archive's snapshots (oldest first).
+    /// Snapshots that don't contain the file are skipped.
+    pub fn file_history(&self, file_path: &Path) -> EResult<Vec<FileVersion>> {
+        let mut versions = vec![];
+        self.for_each_file_version(file_path, |_, version| {
+            versions.push(version);
+            Ok(())
+        })?;
+        Ok(versions)
+    }
+
+    /// Copy each distinct version of the file into the given directory
+    /// (naming each copy after the file and the first snapshot containing
+    /// that version, e.g. "notes.txt@2024-03-01-10-00-00+1000") returning
+    /// the file's history with the path of the copy made for each version
+    /// whose contents changed.
+    pub fn extract_file_versions(
+        &self,
+        file_path: &Path,
+        into_dir_path: &Path,
+        overwrite: bool,
+    ) -> EResult<Vec<(FileVersion, Option<PathBuf>)>> {
+        let file_name = file_path
+            .file_name()
+            .ok_or_else(|| Error::SnapshotUnknownFile(file_path.to_path_buf()))?;
+        let into_dir_path = snapshot::absolute_target_path(into_dir_path)?;
+        fs::create_dir_all(&into_dir_path)?;
+        let mut extracted = vec![];
+        self.for_each_file_version(file_path, |spd, version| {
+            if version.content_changed {
+                let mut copy_name = file_name.to_os_string();
+                copy_name.push("@");
+                copy_name.push(&version.snapshot_name);
+                let copy_path = into_dir_path.join(copy_name);
+                spd.copy_file_to(file_path, &copy_path, overwrite, false)?;
+                extracted.push((version, Some(copy_path)));
+            } else {
+                extracted.push((version, None));
+            }
+            Ok(())
+        })?;
+        Ok(extracted)
+    }
+
+    /// Extract a file in a separate thread and return the events
+    /// describing its progress.
+    pub fn copy_file_to_with_progress(
+        self,
+        n: i64,
+        file_path: PathBuf,
+        into_dir_path: PathBuf,
+        opt_with_name: Option<PathBuf>,
+        overwrite: bool,
+        force: bool,
+    ) -> ProgressEvents<(u64, time::Duration)> {
+        progress::observe(move || {
+            self.copy_file_to(
+                n,
+                &file_path,
+                &into_dir_path,
+                &opt_with_name,
+                overwrite,
+                force,
+            )
+        })
+    }
+
     pub fn copy_dir_to(
         &self,
         n: i64,
         dir_path: &Path,
         into_dir_path: &Path,
         opt_with_name: &Option<PathBuf>,
-        overwrite: bool,
+        options: &CopyOptions,
     ) -> EResult<(ExtractionStats, time::Duration)> {
         let started_at = time::SystemTime::now();
 
         let snapshot_file_path = self.get_snapshot_path_back_n(n)?;
+        let into_dir_path = snapshot::absolute_target_path(into_dir_path)?;
         let target_path = if let Some(with_name) = opt_with_name {
-            into_dir_path.join(with_name)
+            target_path_with_name(&into_dir_path, with_name)?
         } else if let Some(dir_name) = dir_path.file_name() {
             into_dir_path.join(dir_name)
         } else {
@@ -510,8 +1954,8 @@ impl Snapshots {
             _ => absolute_path_buf(dir_path)
                 .map_err(|e| Error::ArchiveIncludePathError(e, dir_path.to_path_buf()))?,
         };
-        let spd = SnapshotPersistentData::from_file(&snapshot_file_path)?;
-        let stats = spd.copy_dir_to(&src_dir_path, &target_path, overwrite)?;
+        let spd = self.read_snapshot(&snapshot_file_path)?;
+        let stats = spd.copy_dir_to(&src_dir_path, &target_path, options)?;
 
         let finished_at = time::SystemTime::now();
         let duration = match finished_at.duration_since(started_at) {
@@ -520,18 +1964,75 @@ impl Snapshots {
         };
         Ok((stats, duration))
     }
+
+    /// Extract a directory in a separate thread and return the events
+    /// describing its progress.
+    pub fn copy_dir_to_with_progress(
+        self,
+        n: i64,
+        dir_path: PathBuf,
+        into_dir_path: PathBuf,
+        opt_with_name: Option<PathBuf>,
+        options: CopyOptions,
+    ) -> ProgressEvents<(ExtractionStats, time::Duration)> {
+        progress::observe(move || {
+            self.copy_dir_to(n, &dir_path, &into_dir_path, &opt_with_name, &options)
+        })
+    }
 }
 
 #[cfg(test)]
 mod archive_tests {
     // TODO: fix tests to use temporary directories.
     use super::*;
-    use std::env;
+    use crate::clock::ManualClock;
+    use crate::fixture::{FixtureSpec, TestConfigGuard};
+    use crate::snapshot_diff::{Change, ExpansionChange, SnapshotDiff};
+    use dychatat_lib::content::HashAlgorithm;
+    use std::sync::Arc;
+
+    // A clock that moves a second each time it's read (so that successive
+    // snapshots get distinct names without waiting)
+    fn ticking_clock() -> Arc<ManualClock> {
+        Arc::new(ManualClock::new(
+            time::SystemTime::now(),
+            time::Duration::from_secs(1),
+        ))
+    }
+
+    #[test]
+    fn snapshot_budget_limits() {
+        let budget = SnapshotBudget::default();
+        assert!(budget.is_default());
+        assert!(budget.excesses(u64::MAX, u64::MAX).is_empty());
+        let budget = SnapshotBudget {
+            max_snapshot_bytes: Some(1000),
+            max_repo_growth_per_run: Some(100),
+            on_exceeded: BudgetAction::Abort,
+        };
+        assert!(budget.excesses(1000, 100).is_empty());
+        assert_eq!(budget.excesses(1001, 100).len(), 1);
+        assert_eq!(budget.excesses(1001, 101).len(), 2);
+        let yaml = serde_yaml::to_string(&budget).unwrap();
+        assert_eq!(
+            serde_yaml::from_str::<SnapshotBudget>(&yaml).unwrap(),
+            budget
+        );
+        let budget: SnapshotBudget = serde_yaml::from_str("max_snapshot_bytes: 10").unwrap();
+        assert_eq!(budget.max_repo_growth_per_run, None);
+        assert_eq!(budget.on_exceeded, BudgetAction::Warn);
+        assert_eq!(BudgetAction::from_str("abort"), Ok(BudgetAction::Abort));
+        assert!(BudgetAction::from_str("ignore").is_err());
+    }
 
     #[test]
     fn test_file_exclusions() {
-        let excl = Exclusions::new(&vec![], &vec!["*.[ao]".to_string(), "this.*".to_string()])
-            .unwrap_or_else(|err| panic!("{:?}: line {:?}: {:?}", file!(), line!(), err));
+        let excl = Exclusions::new(
+            &vec![],
+            &vec!["*.[ao]".to_string(), "this.*".to_string()],
+            false,
+        )
+        .unwrap_or_else(|err| panic!("{:?}: line {:?}: {:?}", file!(), line!(), err));
         assert!(excl.is_excluded_file(&Path::new("whatever.o")));
         assert!(excl.is_excluded_file(&Path::new("whatever.a")));
         assert!(!excl.is_excluded_file(&Path::new("whatever.c")));
@@ -548,8 +2049,12 @@ mod archive_tests {
 
     #[test]
     fn test_dir_exclusions() {
-        let excl = Exclusions::new(&vec!["*.[ao]".to_string(), "this.*".to_string()], &vec![])
-            .unwrap_or_else(|err| panic!("{:?}: line {:?}: {:?}", file!(), line!(), err));
+        let excl = Exclusions::new(
+            &vec!["*.[ao]".to_string(), "this.*".to_string()],
+            &vec![],
+            false,
+        )
+        .unwrap_or_else(|err| panic!("{:?}: line {:?}: {:?}", file!(), line!(), err));
         assert!(excl.is_excluded_dir(&Path::new("whatever.o")));
         assert!(excl.is_excluded_dir(&Path::new("whatever.a")));
         assert!(!excl.is_excluded_dir(&Path::new("whatever.c")));
@@ -564,20 +2069,104 @@ mod archive_tests {
         assert!(excl.is_excluded_dir(&Path::new("dir/this.c")));
     }
 
-    // #[test]
-    // fn test_get_archive() {
-    //     env::set_var("ERGIBUS_CONFIG_DIR", "../TEST/config");
-    //     if let Err(err) = get_archive_data("dummy") {
-    //         match err {
-    //             Error::UnknownRepo(_) => (),
-    //             _ => panic!("ERR: {:?}", err),
-    //         }
-    //     };
-    // }
+    #[test]
+    fn exclusions_can_ignore_case() {
+        let dir_patterns = vec!["DCIM".to_string()];
+        let file_patterns = vec!["*.jpg".to_string()];
+        let excl = Exclusions::new(&dir_patterns, &file_patterns, false).unwrap();
+        assert!(excl.is_excluded_file(Path::new("/photos/a.jpg")));
+        assert!(!excl.is_excluded_file(Path::new("/photos/a.JPG")));
+        assert!(!excl.is_excluded_dir(Path::new("/photos/dcim")));
+        let excl = Exclusions::new(&dir_patterns, &file_patterns, true).unwrap();
+        assert!(excl.is_excluded_file(Path::new("/photos/a.JPG")));
+        assert!(excl.is_excluded_file(Path::new("/photos/a.Jpg")));
+        assert!(excl.is_excluded_dir(Path::new("/photos/dcim")));
+        assert_eq!(
+            excl.file_exclusion_pattern(Path::new("/photos/a.JPG")),
+            Some("*.jpg")
+        );
+
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new().file("a.JPG", "a photo").build();
+        create_new_archive(
+            "test_case",
+            Some("test_repo"),
+            &location,
+            &[fixture.root().to_path_buf()],
+            &[],
+            &file_patterns,
+            &[],
+            false,
+        )
+        .unwrap();
+        let photo_path = fixture.root().join("a.JPG");
+        assert!(!get_case_insensitive_globs("test_case").unwrap());
+        let data = get_archive_data("test_case").unwrap();
+        assert!(!data.exclusions.is_excluded_file(&photo_path));
+        set_case_insensitive_globs("test_case", true).unwrap();
+        assert!(get_case_insensitive_globs("test_case").unwrap());
+        let data = get_archive_data("test_case").unwrap();
+        assert!(data.exclusions.is_excluded_file(&photo_path));
+    }
 
     #[test]
-    fn test_yaml_decode() {
-        let yaml_str = "
+    fn exclusion_patterns_are_identified() {
+        let excl = Exclusions::new(
+            &vec!["lost+found".to_string(), "/var/cache/*".to_string()],
+            &vec!["*.[ao]".to_string(), "*.o".to_string()],
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            excl.dir_exclusion_pattern(Path::new("/home/lost+found")),
+            Some("lost+found")
+        );
+        assert_eq!(
+            excl.dir_exclusion_pattern(Path::new("/var/cache/xx")),
+            Some("/var/cache/*")
+        );
+        assert_eq!(excl.dir_exclusion_pattern(Path::new("/home/xx")), None);
+        assert_eq!(
+            excl.file_exclusion_pattern(Path::new("/src/x.o")),
+            Some("*.[ao]")
+        );
+        assert_eq!(excl.file_exclusion_pattern(Path::new("/src/x.c")), None);
+        let dir = tempdir::TempDir::new("PREVIEW_TEST").unwrap();
+        fs::create_dir_all(dir.path().join("sub/lost+found")).unwrap();
+        fs::write(dir.path().join("sub/x.o"), "").unwrap();
+        fs::write(dir.path().join("sub/x.c"), "").unwrap();
+        let mut items = vec![];
+        preview_dir(dir.path(), 1, None, &excl, &mut items);
+        let statuses: Vec<(String, PreviewStatus)> = items
+            .iter()
+            .map(|item| {
+                let rel_path = item.path.strip_prefix(dir.path()).unwrap();
+                (rel_path.to_string_lossy().to_string(), item.status.clone())
+            })
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("sub".to_string(), PreviewStatus::Included),
+                (
+                    "sub/lost+found".to_string(),
+                    PreviewStatus::Excluded("lost+found".to_string())
+                ),
+                ("sub/x.c".to_string(), PreviewStatus::Included),
+                (
+                    "sub/x.o".to_string(),
+                    PreviewStatus::Excluded("*.[ao]".to_string())
+                ),
+            ]
+        );
+        let mut items = vec![];
+        preview_dir(dir.path(), 1, Some(1), &excl, &mut items);
+        assert_eq!(items.len(), 1);
+    }
+
+    const DUMMY_SPEC_YAML: &str = "
 content_repo_name: dummy\n
 snapshot_dir_path: ./TEST/store/ergibus/archives/dummy\n
 inclusions:\n
@@ -589,7 +2178,10 @@ file_exclusions:\n
    - \"*.[oa]\"\n
    - \"*.py[co]\"\n
 ";
-        let spec: ArchiveSpec = serde_yaml::from_str(&yaml_str).unwrap();
+
+    #[test]
+    fn test_yaml_decode() {
+        let spec: ArchiveSpec = serde_yaml::from_str(DUMMY_SPEC_YAML).unwrap();
         assert_eq!(spec.content_repo_name, "dummy");
         assert_eq!(
             spec.snapshot_dir_path,
@@ -608,7 +2200,11 @@ file_exclusions:\n
 
     #[test]
     fn test_read_write_archive_spec() {
-        env::set_var("ERGIBUS_CONFIG_DIR", "../TEST/config");
+        let _guard = TestConfigGuard::new();
+        assert!(read_archive_spec("dummy").is_err());
+        let spec: ArchiveSpec = serde_yaml::from_str(DUMMY_SPEC_YAML).unwrap();
+        assert!(write_archive_spec("dummy", &spec, false).is_ok());
+        assert!(write_archive_spec("dummy", &spec, false).is_err());
         let spec: ArchiveSpec = read_archive_spec("dummy").unwrap();
         assert_eq!(spec.content_repo_name, "dummy");
         assert_eq!(
@@ -641,4 +2237,419 @@ file_exclusions:\n
         assert_eq!(spec.dir_exclusions, vec!["lost+found"]);
         assert_eq!(spec.file_exclusions, vec!["*.[oa]", "*.py[co]"]);
     }
+
+    #[test]
+    fn overlapping_inclusions_are_found() {
+        let inclusions: Vec<PathBuf> =
+            ["/home/me/work/projA", "/home/me/work", "/home/me/workshop"]
+                .iter()
+                .chain(["/etc/fstab", "/home/me/work", "/etc"].iter())
+                .map(PathBuf::from)
+                .collect();
+        let pair = |a: &str, b: &str| (PathBuf::from(a), PathBuf::from(b));
+        assert_eq!(
+            overlapping_inclusions(&inclusions),
+            vec![
+                pair("/home/me/work/projA", "/home/me/work"),
+                pair("/etc/fstab", "/etc"),
+                pair("/home/me/work", "/home/me/work"),
+            ]
+        );
+        assert_eq!(
+            without_overlaps("test", inclusions),
+            vec![
+                PathBuf::from("/home/me/work"),
+                PathBuf::from("/home/me/workshop"),
+                PathBuf::from("/etc"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_spec_fields_are_preserved() {
+        let _guard = TestConfigGuard::new();
+        let yaml = format!("{}future_field:\n  level: 3\n", DUMMY_SPEC_YAML);
+        let spec: ArchiveSpec = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(spec.encryption, SnapshotEncryption::Auto);
+        assert!(spec.retention.is_default());
+        assert!(spec.unknown_fields.contains_key("future_field"));
+        write_archive_spec("dummy", &spec, false).unwrap();
+        set_snapshot_encryption("dummy", SnapshotEncryption::Off).unwrap();
+        set_archive_compression("dummy", Some("snappy")).unwrap();
+        let spec = read_archive_spec("dummy").unwrap();
+        assert_eq!(spec.encryption, SnapshotEncryption::Off);
+        assert_eq!(spec.compression.as_deref(), Some("snappy"));
+        assert_eq!(
+            spec.unknown_fields["future_field"],
+            serde_yaml::from_str::<serde_yaml::Value>("level: 3").unwrap()
+        );
+    }
+
+    #[test]
+    fn retention_policy_expiry() {
+        let now = time::SystemTime::now();
+        let days_ago = |days: u64| now - time::Duration::from_secs(days * 24 * 60 * 60);
+        let times = [days_ago(100), days_ago(50), days_ago(40), days_ago(1)];
+        assert_eq!(RetentionPolicy::default().expired_count(&times, now), 0);
+        let retention = RetentionPolicy {
+            keep_newest: None,
+            max_age_days: Some(45),
+        };
+        assert_eq!(retention.expired_count(&times, now), 2);
+        let retention = RetentionPolicy {
+            keep_newest: None,
+            max_age_days: Some(0),
+        };
+        assert_eq!(retention.expired_count(&times, now), 3);
+        let retention = RetentionPolicy {
+            keep_newest: Some(3),
+            max_age_days: Some(45),
+        };
+        assert_eq!(retention.expired_count(&times, now), 1);
+        let retention = RetentionPolicy {
+            keep_newest: None,
+            max_age_days: Some(u64::MAX),
+        };
+        assert_eq!(retention.expired_count(&times, now), 0);
+    }
+
+    #[test]
+    fn invalid_archive_names_are_rejected() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        for bad_name in ["", "..", "a/b", ".hidden", "-a", "a b", &"x".repeat(65)] {
+            match create_new_archive(bad_name, None, &location, &[], &[], &[], &[], true) {
+                Err(Error::InvalidArchiveName(name, _)) => assert_eq!(name, bad_name),
+                result => panic!("{:?}: unexpected result {:?}", bad_name, result),
+            }
+        }
+        assert!(!location.exists());
+    }
+
+    #[test]
+    fn glob_inclusions_are_expanded_at_back_up_time() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let clock = ticking_clock();
+        let fixture = FixtureSpec::new()
+            .file("projects/a/src/main.rs", "fn main() {}")
+            .file("projects/a/target/junk", "not included")
+            .file("projects/b/src/lib.rs", "")
+            .file("projects/c/README", "no src")
+            .build();
+        let pattern = fixture.root().join("projects/*/src");
+        assert!(is_glob_inclusion(&pattern));
+        create_new_archive(
+            "test_glob",
+            Some("test_repo"),
+            &location,
+            &[pattern],
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        let archive_data = get_archive_data("test_glob").unwrap();
+        let projects = fixture.root().canonicalize().unwrap().join("projects");
+        let expected = vec![projects.join("a/src"), projects.join("b/src")];
+        assert_eq!(archive_data.includes, expected);
+        assert_eq!(archive_data.inclusion_expansions[0].matches, expected);
+
+        snapshot::generate_snapshot_with_clock("test_glob", clock.clone()).unwrap();
+        FixtureSpec::new()
+            .file("projects/d/src/new.rs", "")
+            .create_in(fixture.root());
+        snapshot::generate_snapshot_with_clock("test_glob", clock.clone()).unwrap();
+        let snapshots = Snapshots::try_from("test_glob").unwrap();
+        let paths = snapshots.get_snapshot_paths(Order::Ascending).unwrap();
+        let older = SnapshotPersistentData::from_file(&paths[0]).unwrap();
+        let newer = SnapshotPersistentData::from_file(&paths[1]).unwrap();
+        assert_eq!(older.file_stats().file_count, 2);
+        assert_eq!(newer.file_stats().file_count, 3);
+        let diff = SnapshotDiff::new(&older, &newer);
+        let changes: Vec<&ExpansionChange> = diff.expansion_changes().collect();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, projects.join("d/src"));
+        assert_eq!(changes[0].change, Change::Added);
+    }
+
+    #[test]
+    fn pruning_stops_when_enough_is_freed() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let clock = ticking_clock();
+        let fixture = FixtureSpec::new()
+            .file("shared.txt", "in every snapshot")
+            .file_of_size("changing.bin", 50_000)
+            .build();
+        let inclusions = vec![fixture.root().to_path_buf()];
+        create_new_archive(
+            "test_prune",
+            Some("test_repo"),
+            &location,
+            &inclusions,
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        for size in [60_000, 70_000] {
+            snapshot::generate_snapshot_with_clock("test_prune", clock.clone()).unwrap();
+            FixtureSpec::new()
+                .file_of_size("changing.bin", size)
+                .create_in(fixture.root());
+        }
+        snapshot::generate_snapshot_with_clock("test_prune", clock.clone()).unwrap();
+        let snapshots = Snapshots::try_from("test_prune").unwrap();
+        let paths = snapshots.get_snapshot_paths(Order::Ascending).unwrap();
+        assert_eq!(paths.len(), 3);
+        let estimates = snapshots.estimate_reclaimable_bytes(&paths).unwrap();
+        assert!(0 < estimates[0] && estimates[0] < estimates[1] && estimates[1] < estimates[2]);
+
+        let outcome = snapshots.prune_until_free(1, 1, true).unwrap();
+        assert_eq!(outcome.deleted.len(), 1);
+        assert_eq!(
+            (outcome.estimated_bytes, outcome.reclaimed_bytes),
+            (estimates[0], 0)
+        );
+        assert_eq!(
+            snapshots
+                .get_snapshot_paths(Order::Ascending)
+                .unwrap()
+                .len(),
+            3
+        );
+        // the newest snapshots are kept even if the target isn't reached
+        match snapshots.prune_until_free(estimates[1], 2, true) {
+            Err(Error::PruneInsufficientSpace(_, wanted, available, kept)) => {
+                assert_eq!((wanted, available, kept), (estimates[1], estimates[0], 2))
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        // as are those the retention policy says to keep
+        let retention = RetentionPolicy {
+            keep_newest: Some(2),
+            max_age_days: None,
+        };
+        set_retention_policy("test_prune", retention).unwrap();
+        assert!(matches!(
+            snapshots.prune_until_free(estimates[1], 0, true),
+            Err(Error::PruneInsufficientSpace(_, _, _, 2))
+        ));
+        set_retention_policy("test_prune", RetentionPolicy::default()).unwrap();
+
+        let outcome = snapshots
+            .prune_until_free(estimates[0] + 1, 0, false)
+            .unwrap();
+        assert_eq!(outcome.deleted.len(), 2);
+        assert_eq!(outcome.reclaimed_bytes, outcome.estimated_bytes);
+        assert_eq!(outcome.estimated_bytes, estimates[1]);
+        assert_eq!(
+            snapshots.get_snapshot_paths(Order::Ascending).unwrap(),
+            paths[2..].to_vec()
+        );
+    }
+
+    #[test]
+    fn file_history_marks_changed_contents() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let clock = ticking_clock();
+        let fixture = FixtureSpec::new().file("other.txt", "other").build();
+        let inclusions = vec![fixture.root().to_path_buf()];
+        create_new_archive(
+            "test_history",
+            Some("test_repo"),
+            &location,
+            &inclusions,
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        // the file is missing from the first snapshot and unchanged in the third
+        for contents in [None, Some("first"), Some("first"), Some("second")] {
+            if let Some(contents) = contents {
+                FixtureSpec::new()
+                    .file("notes.txt", contents)
+                    .create_in(fixture.root());
+            }
+            snapshot::generate_snapshot_with_clock("test_history", clock.clone()).unwrap();
+        }
+        let snapshots = Snapshots::try_from("test_history").unwrap();
+        let names = snapshots.get_snapshot_names(Order::Ascending).unwrap();
+        let file_path = fixture.root().join("notes.txt");
+        let history = snapshots.file_history(&file_path).unwrap();
+        assert_eq!(
+            history
+                .iter()
+                .map(|v| (v.snapshot_name.clone(), v.size, v.content_changed))
+                .collect::<Vec<_>>(),
+            vec![
+                (names[1].clone(), 5, true),
+                (names[2].clone(), 5, false),
+                (names[3].clone(), 6, true),
+            ]
+        );
+        assert_eq!(history[0].content_token, history[1].content_token);
+        assert!(snapshots
+            .file_history(&fixture.root().join("no/such/file"))
+            .unwrap()
+            .is_empty());
+
+        let into_dir_path = location.join("versions");
+        let extracted = snapshots
+            .extract_file_versions(&file_path, &into_dir_path, false)
+            .unwrap();
+        assert_eq!(extracted.len(), 3);
+        assert_eq!(extracted[1], (history[1].clone(), None));
+        let mut expected_name = OsString::from("notes.txt@");
+        expected_name.push(&names[3]);
+        let copy_paths: Vec<PathBuf> = extracted.into_iter().filter_map(|(_, p)| p).collect();
+        assert_eq!(copy_paths[1], into_dir_path.join(expected_name));
+        assert_eq!(fs::read_to_string(&copy_paths[0]).unwrap(), "first");
+        assert_eq!(fs::read_to_string(&copy_paths[1]).unwrap(), "second");
+        assert_eq!(fs::read_dir(&into_dir_path).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn garbage_collection_spares_other_configurations_directories() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new().file("a.txt", "some text").build();
+        let inclusions = vec![fixture.root().to_path_buf()];
+        create_new_archive(
+            "test_gc",
+            Some("test_repo"),
+            &location,
+            &inclusions,
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        let snapshot_dir_path = get_archive_snapshot_dir_path("test_gc").unwrap();
+        let root_dir_path = archives_root_dir_path(&snapshot_dir_path).unwrap();
+        // as if left by configurations using other configuration directories
+        let other_dir_paths = [
+            snapshot_dir_path.with_file_name("other_archive"),
+            root_dir_path
+                .join("other_host")
+                .join("other_user")
+                .join("other_archive"),
+        ];
+        for dir_path in other_dir_paths.iter() {
+            fs::create_dir_all(dir_path).unwrap();
+        }
+        let stats = collect_garbage("test_gc", STALE_FILE_MIN_AGE).unwrap();
+        assert!(stats.removed_dirs.is_empty());
+        assert!(snapshot_dir_path.is_dir());
+        for dir_path in other_dir_paths.iter() {
+            assert!(dir_path.is_dir());
+        }
+    }
+
+    #[test]
+    fn prune_hooks_get_a_summary() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new().file("a.txt", "some text").build();
+        let inclusions = vec![fixture.root().to_path_buf()];
+        create_new_archive(
+            "test_hook",
+            Some("test_repo"),
+            &location,
+            &inclusions,
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        let clock = ticking_clock();
+        for _ in 0..3 {
+            snapshot::generate_snapshot_with_clock("test_hook", clock.clone()).unwrap();
+        }
+        let summary_path = location.join("summary.json");
+        let env_path = location.join("env");
+        let command = format!(
+            "cat > {:?}; echo $ERGIBUS_OPERATION $ERGIBUS_DELETED_COUNT > {:?}",
+            summary_path, env_path
+        );
+        set_prune_hook("test_hook", Some(&command)).unwrap();
+        assert_eq!(get_prune_hook("test_hook").unwrap(), Some(command));
+        let snapshots = Snapshots::try_from("test_hook").unwrap();
+        let names = snapshots.get_snapshot_names(Order::Ascending).unwrap();
+        assert_eq!(snapshots.delete_all_but_newest(1, false).unwrap(), 2);
+        let summary: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&summary_path).unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&env_path).unwrap(), "keep_newest 2\n");
+        assert_eq!(summary["archive"], "test_hook");
+        assert_eq!(summary["operation"], "keep_newest");
+        assert_eq!(summary["deleted_count"], 2);
+        assert_eq!(summary["remaining_snapshots"], 1);
+        assert_eq!(
+            summary["deleted"],
+            serde_json::json!([names[0].to_string_lossy(), names[1].to_string_lossy()])
+        );
+
+        // nothing deleted so the hook isn't run
+        fs::remove_file(&summary_path).unwrap();
+        assert_eq!(snapshots.delete_all_but_newest(1, false).unwrap(), 0);
+        assert!(!summary_path.exists());
+    }
+
+    #[test]
+    fn config_bundle_round_trip() {
+        let dir = tempdir::TempDir::new("BUNDLE_TEST").unwrap();
+        let mut bundle = ConfigBundle::default();
+        bundle.archives.insert(
+            "an_archive".to_string(),
+            ArchiveSpec {
+                content_repo_name: "a_repo".to_string(),
+                snapshot_dir_path: PathBuf::from("/somewhere/ergibus/archives/an_archive"),
+                inclusions: vec![PathBuf::from("/home/me")],
+                dir_exclusions: vec!["lost+found".to_string()],
+                file_exclusions: vec!["*.o".to_string()],
+                profiles: vec!["rust-dev".to_string()],
+                budget: SnapshotBudget::default(),
+                backup_interval_hours: None,
+                overflow: None,
+                compression: None,
+                encryption: SnapshotEncryption::Required,
+                retention: RetentionPolicy {
+                    keep_newest: Some(7),
+                    max_age_days: Some(90),
+                },
+                prune_hook: Some("logger -t ergibus pruned".to_string()),
+                case_insensitive_globs: true,
+                max_depth: Some(2),
+                unknown_fields: BTreeMap::new(),
+            },
+        );
+        bundle.repos.insert(
+            "a_repo".to_string(),
+            RepoSpec::new("/somewhere/dychatat/repos/a_repo", HashAlgorithm::Sha256),
+        );
+        bundle.profiles.insert(
+            "rust-dev".to_string(),
+            ExclusionProfile {
+                dir_exclusions: vec!["target".to_string()],
+                file_exclusions: vec![],
+            },
+        );
+        let bundle_path = dir.path().join("bundle");
+        bundle.write_to_file(&bundle_path).unwrap();
+        assert_eq!(ConfigBundle::from_file(&bundle_path).unwrap(), bundle);
+        assert!(ConfigBundle::from_file(&dir.path().join("missing")).is_err());
+    }
 }
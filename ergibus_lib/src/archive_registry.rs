@@ -0,0 +1,231 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! A cache of the archives' names (and, loaded lazily, their data) so that
+//! frequent lookups (e.g. by the GUI's archive selector or the daemon)
+//! don't reread the archive configuration directory each time.  Changes
+//! are detected by the modification time of the directory (for archives
+//! created or deleted) and of the archives' specification files.  As
+//! modification times have limited resolution, a listing or specification
+//! read within `RACY_INTERVAL` of its modification is reread next time.
+//!
+//! NB: the archives' data is as it was when loaded (e.g. glob inclusions
+//! are expanded then) so back ups always load it afresh.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::archive::{get_archive_data, ArchiveData};
+use crate::config;
+use crate::EResult;
+
+const RACY_INTERVAL: Duration = Duration::from_secs(2);
+
+// The modification time of the file (or directory) if it's old enough to
+// be relied upon to detect changes made after `read_time`
+fn settled_mtime(path: &Path, read_time: SystemTime) -> Option<SystemTime> {
+    let mtime = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    match read_time.duration_since(mtime) {
+        Ok(age) if age > RACY_INTERVAL => Some(mtime),
+        _ => None,
+    }
+}
+
+fn current_mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[derive(Debug)]
+struct Listing {
+    dir_path: PathBuf,
+    settled_mtime: Option<SystemTime>,
+    names: Vec<String>,
+}
+
+#[derive(Debug)]
+struct CachedData {
+    settled_mtime: Option<SystemTime>,
+    data: Arc<ArchiveData>,
+}
+
+#[derive(Debug, Default)]
+pub struct ArchiveRegistry {
+    listing: Option<Listing>,
+    data: HashMap<String, CachedData>,
+}
+
+impl ArchiveRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn listing(&mut self) -> &Listing {
+        let dir_path = config::get_archive_config_dir_path();
+        let is_current = match self.listing {
+            Some(ref listing) => {
+                listing.dir_path == dir_path
+                    && listing.settled_mtime.is_some()
+                    && listing.settled_mtime == current_mtime(&dir_path)
+            }
+            None => false,
+        };
+        if !is_current {
+            if !matches!(self.listing, Some(ref listing) if listing.dir_path == dir_path) {
+                // a different configuration (e.g. profile)
+                self.data.clear();
+            }
+            let read_time = SystemTime::now();
+            let mut names = vec![];
+            if let Ok(dir_entries) = fs::read_dir(&dir_path) {
+                for entry in dir_entries.filter_map(|entry| entry.ok()) {
+                    if entry.path().is_file() {
+                        // NB: skipping (temporary) files that can't be archives
+                        match entry.file_name().to_str() {
+                            Some(name) if !name.starts_with('.') => names.push(name.to_string()),
+                            _ => (),
+                        }
+                    }
+                }
+            }
+            names.sort();
+            self.data
+                .retain(|name, _| names.binary_search(name).is_ok());
+            self.listing = Some(Listing {
+                settled_mtime: settled_mtime(&dir_path, read_time),
+                dir_path,
+                names,
+            });
+        }
+        self.listing.as_ref().expect("just set")
+    }
+
+    /// The names of the archives (in alphabetical order).
+    pub fn names(&mut self) -> Vec<String> {
+        self.listing().names.clone()
+    }
+
+    pub fn contains(&mut self, archive_name: &str) -> bool {
+        self.listing()
+            .names
+            .binary_search_by(|name| name.as_str().cmp(archive_name))
+            .is_ok()
+    }
+
+    /// The named archive's data (loaded if it hasn't been or its
+    /// specification has changed since).
+    pub fn archive_data(&mut self, archive_name: &str) -> EResult<Arc<ArchiveData>> {
+        let spec_file_path = self.listing().dir_path.join(archive_name);
+        if let Some(cached) = self.data.get(archive_name) {
+            if cached.settled_mtime.is_some()
+                && cached.settled_mtime == current_mtime(&spec_file_path)
+            {
+                return Ok(Arc::clone(&cached.data));
+            }
+        }
+        let read_time = SystemTime::now();
+        let data = Arc::new(get_archive_data(archive_name)?);
+        self.data.insert(
+            archive_name.to_string(),
+            CachedData {
+                settled_mtime: settled_mtime(&spec_file_path, read_time),
+                data: Arc::clone(&data),
+            },
+        );
+        Ok(data)
+    }
+}
+
+lazy_static! {
+    static ref ARCHIVE_REGISTRY: Mutex<ArchiveRegistry> = Mutex::new(ArchiveRegistry::new());
+}
+
+/// The names of the archives (in alphabetical order) from the shared registry.
+pub fn archive_names() -> Vec<String> {
+    ARCHIVE_REGISTRY.lock().unwrap().names()
+}
+
+pub fn archive_exists(archive_name: &str) -> bool {
+    ARCHIVE_REGISTRY.lock().unwrap().contains(archive_name)
+}
+
+/// The named archive's data from the shared registry.
+pub fn lookup(archive_name: &str) -> EResult<Arc<ArchiveData>> {
+    ARCHIVE_REGISTRY.lock().unwrap().archive_data(archive_name)
+}
+
+#[cfg(test)]
+mod archive_registry_tests {
+    use super::*;
+    use crate::archive::{create_new_archive, set_prune_hook};
+    use crate::fixture::{FixtureSpec, TestConfigGuard};
+    use dychatat_lib::content::create_new_repo;
+    use std::fs::File;
+
+    // Make the file (or directory) look as if it was last modified an hour ago
+    fn age(path: &Path) {
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        File::open(path).unwrap().set_modified(an_hour_ago).unwrap();
+    }
+
+    #[test]
+    fn changes_are_detected() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new().file("a.txt", "some text").build();
+        let inclusions = vec![fixture.root().to_path_buf()];
+        let create = |archive_name: &str| {
+            create_new_archive(
+                archive_name,
+                Some("test_repo"),
+                &location,
+                &inclusions,
+                &[],
+                &[],
+                &[],
+                false,
+            )
+            .unwrap()
+        };
+        create("test_b");
+        create("test_a");
+        // e.g. left by an interrupted atomic write
+        fs::write(
+            config::get_archive_config_dir_path().join(".test_a.tmp"),
+            "partial",
+        )
+        .unwrap();
+        let mut registry = ArchiveRegistry::new();
+        assert_eq!(registry.names(), vec!["test_a", "test_b"]);
+        create("test_c");
+        assert!(registry.contains("test_c"));
+
+        // a settled listing isn't reread until the directory changes
+        let dir_path = config::get_archive_config_dir_path();
+        age(&dir_path);
+        assert_eq!(registry.names().len(), 3);
+        fs::remove_file(dir_path.join("test_c")).unwrap();
+        assert!(!registry.contains("test_c"));
+
+        let spec_file_path = dir_path.join("test_a");
+        age(&spec_file_path);
+        let data = registry.archive_data("test_a").unwrap();
+        assert_eq!(data.name, "test_a");
+        assert!(Arc::ptr_eq(
+            &data,
+            &registry.archive_data("test_a").unwrap()
+        ));
+        set_prune_hook("test_a", Some("true")).unwrap();
+        assert!(!Arc::ptr_eq(
+            &data,
+            &registry.archive_data("test_a").unwrap()
+        ));
+        assert!(registry.archive_data("test_c").is_err());
+    }
+}
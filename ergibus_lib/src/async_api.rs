@@ -0,0 +1,184 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Async versions of the main operations (for use in async services such
+//! as a back up dashboard).  The work is done on tokio's blocking thread
+//! pool so that it doesn't hold up the executor.  Needs the "async" feature.
+
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+
+use crate::archive::Snapshots;
+use crate::fs_objects::{CopyOptions, ExtractionStats};
+use crate::report;
+use crate::snapshot::{self, BackUpStats, Order, SnapshotPersistentData};
+use crate::{EResult, Error};
+
+// The number of snapshots read ahead of the stream's consumer
+const SNAPSHOT_STREAM_BUFFER: usize = 4;
+
+async fn run_blocking<T, F>(f: F) -> EResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> EResult<T> + Send + 'static,
+{
+    task::spawn_blocking(report::carry_warning_sink(f))
+        .await
+        .map_err(|err| Error::AsyncTaskFailed(err.to_string()))?
+}
+
+/// Make a back up snapshot for the named archive.
+pub async fn back_up(archive_name: String) -> EResult<BackUpStats> {
+    run_blocking(move || snapshot::generate_snapshot(&archive_name)).await
+}
+
+/// The names of the archive's snapshots.
+pub async fn snapshot_names(archive_name: String, order: Order) -> EResult<Vec<OsString>> {
+    run_blocking(move || snapshot::get_snapshot_names_for_archive(&archive_name, order)).await
+}
+
+/// The archive's snapshots (read one at a time as the stream is consumed).
+/// Reading stops at the first error (which is the stream's last item).
+pub fn snapshot_stream(
+    archive_name: String,
+    order: Order,
+) -> impl Stream<Item = EResult<SnapshotPersistentData>> {
+    let (sender, receiver) = mpsc::channel(SNAPSHOT_STREAM_BUFFER);
+    task::spawn_blocking(move || {
+        let snapshot_paths = match snapshot::get_snapshot_paths_for_archive(&archive_name, order) {
+            Ok(snapshot_paths) => snapshot_paths,
+            Err(err) => {
+                sender.blocking_send(Err(err)).ok();
+                return;
+            }
+        };
+        for snapshot_path in snapshot_paths {
+            let result = SnapshotPersistentData::from_file(&snapshot_path);
+            let failed = result.is_err();
+            // stop if the consumer has lost interest
+            if sender.blocking_send(result).is_err() || failed {
+                break;
+            }
+        }
+    });
+    ReceiverStream::new(receiver)
+}
+
+/// Copy a file from the snapshot `back_n` places before the archive's
+/// most recent into the given directory.
+pub async fn extract_file(
+    archive_name: String,
+    back_n: i64,
+    file_path: PathBuf,
+    into_dir_path: PathBuf,
+    overwrite: bool,
+) -> EResult<u64> {
+    run_blocking(move || {
+        let snapshots = Snapshots::try_from(archive_name.as_str())?;
+        let (bytes, _) =
+            snapshots.copy_file_to(back_n, &file_path, &into_dir_path, &None, overwrite, false)?;
+        Ok(bytes)
+    })
+    .await
+}
+
+/// Copy a directory from the snapshot `back_n` places before the
+/// archive's most recent into the given directory.
+pub async fn extract_dir(
+    archive_name: String,
+    back_n: i64,
+    dir_path: PathBuf,
+    into_dir_path: PathBuf,
+    overwrite: bool,
+) -> EResult<ExtractionStats> {
+    run_blocking(move || {
+        let snapshots = Snapshots::try_from(archive_name.as_str())?;
+        let options = CopyOptions {
+            overwrite,
+            ..CopyOptions::default()
+        };
+        let (stats, _) =
+            snapshots.copy_dir_to(back_n, &dir_path, &into_dir_path, &None, &options)?;
+        Ok(stats)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod async_api_tests {
+    use super::*;
+    use crate::archive;
+    use crate::fixture::{FixtureSpec, TestConfigGuard};
+    use dychatat_lib::content;
+    use tokio_stream::StreamExt;
+
+    #[test]
+    fn operations_run_on_the_blocking_pool() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new().file("dir/file.txt", "contents").build();
+        let inclusions = vec![fixture.root().to_path_buf()];
+        archive::create_new_archive(
+            "test_async",
+            Some("test_repo"),
+            &location,
+            &inclusions,
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (_, file_stats, _, _) = back_up("test_async".to_string()).await.unwrap();
+            assert_eq!(file_stats.file_count, 1);
+            let names = snapshot_names("test_async".to_string(), Order::Ascending)
+                .await
+                .unwrap();
+            assert_eq!(names.len(), 1);
+            let snapshots: Vec<_> = snapshot_stream("test_async".to_string(), Order::Ascending)
+                .collect()
+                .await;
+            assert_eq!(snapshots.len(), 1);
+            assert_eq!(snapshots[0].as_ref().unwrap().archive_name(), "test_async");
+
+            let into_dir_path = location.join("extracted");
+            std::fs::create_dir_all(&into_dir_path).unwrap();
+            let bytes = extract_file(
+                "test_async".to_string(),
+                0,
+                fixture.root().join("dir/file.txt"),
+                into_dir_path.clone(),
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(bytes, 8);
+            let stats = extract_dir(
+                "test_async".to_string(),
+                0,
+                fixture.root().join("dir"),
+                into_dir_path.clone(),
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(stats.file_count, 1);
+            assert!(into_dir_path.join("dir/file.txt").is_file());
+
+            let results: Vec<_> = snapshot_stream("no_such_archive".to_string(), Order::Ascending)
+                .collect()
+                .await;
+            assert!(matches!(results[..], [Err(_)]));
+        });
+    }
+}
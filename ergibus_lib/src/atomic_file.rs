@@ -0,0 +1,71 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Replace files atomically so that a crash part way through writing one
+//! can't leave it truncated: the new contents are written to a temporary
+//! file (named ".<name>.tmp") in the same directory which is synced to
+//! disk before being renamed over the original.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+fn temp_file_path(file_path: &Path) -> PathBuf {
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_path.file_name().unwrap_or_default());
+    temp_name.push(".tmp");
+    file_path.with_file_name(temp_name)
+}
+
+/// Replace the file at `file_path` with the contents written by `write`
+/// (creating its directory if necessary).  The file is left unchanged if
+/// `write` fails.
+pub(crate) fn replace_file<E, F>(file_path: &Path, write: F) -> Result<(), E>
+where
+    E: From<io::Error>,
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), E>,
+{
+    if let Some(dir_path) = file_path.parent() {
+        fs::create_dir_all(dir_path)?;
+    }
+    let temp_path = temp_file_path(file_path);
+    let result = File::create(&temp_path)
+        .map_err(E::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            writer.flush()?;
+            let file = writer.into_inner().map_err(|err| err.into_error())?;
+            file.sync_all()?;
+            Ok(())
+        })
+        .and_then(|_| fs::rename(&temp_path, file_path).map_err(E::from));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod atomic_file_tests {
+    use super::*;
+
+    #[test]
+    fn files_are_unchanged_by_failed_writes() {
+        let dir = tempdir::TempDir::new("ATOMIC_FILE_TEST").unwrap();
+        let file_path = dir.path().join("sub").join("file");
+        replace_file(&file_path, |writer| writer.write_all(b"original")).unwrap();
+        assert_eq!(fs::read(&file_path).unwrap(), b"original");
+        let result: io::Result<()> = replace_file(&file_path, |writer| {
+            writer.write_all(b"partial")?;
+            Err(io::Error::other("oops"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(&file_path).unwrap(), b"original");
+        assert_eq!(
+            fs::read_dir(file_path.parent().unwrap()).unwrap().count(),
+            1
+        );
+        replace_file(&file_path, |writer| writer.write_all(b"replaced")).unwrap();
+        assert_eq!(fs::read(&file_path).unwrap(), b"replaced");
+    }
+}
@@ -1,23 +1,59 @@
 use std::convert::From;
 use std::ffi::CString;
-use std::fs::Metadata;
+use std::fmt;
+use std::fs::{File, Metadata};
 use std::io;
 #[cfg(target_family = "unix")]
 use std::os::unix::ffi::OsStrExt;
 #[cfg(target_family = "unix")]
 use std::os::unix::fs::MetadataExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use log;
+use schemars::JsonSchema;
+
+use crate::owner_map::OwnerMapping;
 
 use libc;
 
 pub trait AttributesIfce: From<Metadata> {
     fn size(&self) -> u64;
-    fn set_file_attributes(&self, file_path: &Path) -> Result<(), io::Error>;
+    fn set_file_attributes(
+        &self,
+        file_path: &Path,
+        owner_mapping: &OwnerMapping,
+    ) -> Result<(), io::Error>;
+}
+
+// chattr(1) flags that we preserve (from <linux/fs.h>)
+const FS_IMMUTABLE_FL: u32 = 0x00000010;
+const FS_APPEND_FL: u32 = 0x00000020;
+const PRESERVED_FS_FLAGS: u32 = FS_IMMUTABLE_FL | FS_APPEND_FL;
+
+const CAPABILITY_XATTR_NAME: &str = "security.capability";
+
+/// Attributes that require privileges to restore and may therefore be skipped
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SpecialAttribute {
+    Capabilities,
+    Immutable,
+    AppendOnly,
+}
+
+impl fmt::Display for SpecialAttribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpecialAttribute::Capabilities => write!(f, "file capabilities"),
+            SpecialAttribute::Immutable => write!(f, "immutable flag"),
+            SpecialAttribute::AppendOnly => write!(f, "append-only flag"),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
 #[cfg(target_family = "unix")]
 pub struct Attributes {
     st_dev: u64,
@@ -33,6 +69,37 @@ pub struct Attributes {
     st_mtime_nsec: i64,
     st_ctime: i64,
     st_ctime_nsec: i64,
+    #[serde(default)]
+    capabilities: Option<Vec<u8>>,
+    #[serde(default)]
+    fs_flags: u32,
+    // Creation (birth) time: only where the file system records it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    st_btime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    st_btime_nsec: Option<i64>,
+}
+
+// The access time is ignored as it changes whenever a file is read
+// (including by us while taking a snapshot) and the birth time is ignored
+// so that snapshots made before it was recorded still match.
+#[cfg(target_family = "unix")]
+impl PartialEq for Attributes {
+    fn eq(&self, other: &Self) -> bool {
+        self.st_dev == other.st_dev
+            && self.st_ino == other.st_ino
+            && self.st_nlink == other.st_nlink
+            && self.st_mode == other.st_mode
+            && self.st_uid == other.st_uid
+            && self.st_gid == other.st_gid
+            && self.st_size == other.st_size
+            && self.st_mtime == other.st_mtime
+            && self.st_mtime_nsec == other.st_mtime_nsec
+            && self.st_ctime == other.st_ctime
+            && self.st_ctime_nsec == other.st_ctime_nsec
+            && self.capabilities == other.capabilities
+            && self.fs_flags == other.fs_flags
+    }
 }
 
 #[cfg(target_family = "unix")]
@@ -50,11 +117,17 @@ impl Attributes {
         }
     }
 
-    pub fn chown_file(&self, file_path: &Path) -> Result<(), io::Error> {
+    pub fn chown_file(
+        &self,
+        file_path: &Path,
+        owner_mapping: &OwnerMapping,
+    ) -> Result<(), io::Error> {
         let c_file_path = CString::new(file_path.as_os_str().as_bytes()).unwrap();
+        let uid = owner_mapping.uid(self.st_uid);
+        let gid = owner_mapping.gid(self.st_gid);
         let failed: bool;
         unsafe {
-            failed = libc::chown(c_file_path.into_raw(), self.st_uid, self.st_gid) != 0;
+            failed = libc::chown(c_file_path.into_raw(), uid, gid) != 0;
         }
         if failed {
             Err(std::io::Error::last_os_error())
@@ -79,11 +152,313 @@ impl Attributes {
             Ok(())
         }
     }
+
+    /// Set the access and modification times (to the nanosecond).
+    pub fn utimens_file(&self, file_path: &Path) -> Result<(), io::Error> {
+        let c_file_path = CString::new(file_path.as_os_str().as_bytes()).unwrap();
+        let times = [
+            libc::timespec {
+                tv_sec: self.st_atime,
+                tv_nsec: self.st_atime_nsec,
+            },
+            libc::timespec {
+                tv_sec: self.st_mtime,
+                tv_nsec: self.st_mtime_nsec,
+            },
+        ];
+        let failed: bool;
+        unsafe {
+            failed = libc::utimensat(libc::AT_FDCWD, c_file_path.as_ptr(), times.as_ptr(), 0) != 0;
+        }
+        if failed {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Do the permissions and ownership match those of `other`?
+    pub fn has_same_mode_and_owner(&self, other: &Self) -> bool {
+        self.st_mode == other.st_mode && self.st_uid == other.st_uid && self.st_gid == other.st_gid
+    }
+
+    /// The serialized attributes (less the access time which changes
+    /// whenever a file is read) for inclusion in tree hashes.
+    pub(crate) fn tree_hash_data(&self) -> Vec<u8> {
+        let attributes = Attributes {
+            st_atime: 0,
+            st_atime_nsec: 0,
+            ..self.clone()
+        };
+        serde_json::to_vec(&attributes).expect(crate::UNEXPECTED)
+    }
+
+    pub(crate) fn owner(&self) -> (u32, u32) {
+        (self.st_uid, self.st_gid)
+    }
+
+    /// The modification time (seconds since the epoch)
+    pub fn mtime(&self) -> i64 {
+        self.st_mtime
+    }
+
+    /// The nanoseconds part of the modification time
+    pub fn mtime_nsec(&self) -> i64 {
+        self.st_mtime_nsec
+    }
+
+    /// The creation (birth) time (seconds since the epoch) if the file
+    /// system recorded it
+    pub fn btime(&self) -> Option<i64> {
+        self.st_btime
+    }
+
+    /// The creation (birth) time if the file system recorded it
+    pub fn birth_time(&self) -> Option<SystemTime> {
+        let secs = self.st_btime?;
+        let nsecs = self.st_btime_nsec.unwrap_or(0) as u32;
+        if secs >= 0 {
+            Some(UNIX_EPOCH + Duration::new(secs as u64, nsecs))
+        } else {
+            UNIX_EPOCH.checked_sub(Duration::new(secs.unsigned_abs(), 0))
+        }
+    }
+
+    /// The file type and permissions as shown by "ls -l" (e.g. "drwxr-xr-x")
+    pub fn mode_string(&self) -> String {
+        let file_type = match self.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => 'd',
+            libc::S_IFLNK => 'l',
+            libc::S_IFREG => '-',
+            _ => '?',
+        };
+        let mut string = String::with_capacity(10);
+        string.push(file_type);
+        for (bit, letter) in [
+            (0o400, 'r'),
+            (0o200, 'w'),
+            (0o100, 'x'),
+            (0o040, 'r'),
+            (0o020, 'w'),
+            (0o010, 'x'),
+            (0o004, 'r'),
+            (0o002, 'w'),
+            (0o001, 'x'),
+        ] {
+            string.push(if self.st_mode & bit != 0 { letter } else { '-' });
+        }
+        string
+    }
+
+    /// Restore the creation (birth) time (if one was recorded).  No system
+    /// call sets birth times on the platforms we support so this fails with
+    /// `Unsupported` unless the file's current birth time already matches.
+    pub fn set_birth_time(&self, file_path: &Path) -> Result<(), io::Error> {
+        let birth_time = match self.birth_time() {
+            Some(birth_time) => birth_time,
+            None => return Ok(()),
+        };
+        match file_path.symlink_metadata()?.created() {
+            Ok(current) if current == birth_time => Ok(()),
+            _ => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        }
+    }
+
+    pub fn is_immutable(&self) -> bool {
+        self.fs_flags & FS_IMMUTABLE_FL != 0
+    }
+
+    pub fn is_append_only(&self) -> bool {
+        self.fs_flags & FS_APPEND_FL != 0
+    }
+
+    pub fn has_capabilities(&self) -> bool {
+        self.capabilities.is_some()
+    }
+
+    /// Capture the file capabilities and chattr flags of a regular file or directory.
+    /// These are best effort as not all file systems support them.
+    pub fn fetch_special_attributes(&mut self, file_path: &Path) {
+        match get_xattr(file_path, CAPABILITY_XATTR_NAME) {
+            Ok(capabilities) => self.capabilities = capabilities,
+            Err(err) => log::trace!("{:?}: capabilities: {}", file_path, err),
+        }
+        match get_fs_flags(file_path) {
+            Ok(flags) => self.fs_flags = flags & PRESERVED_FS_FLAGS,
+            Err(err) => log::trace!("{:?}: chattr flags: {}", file_path, err),
+        }
+    }
+
+    /// Restore the file capabilities and chattr flags.  Those that could not be
+    /// restored due to insufficient privileges are returned (rather than failing).
+    /// NB: this should be done after all other modifications to the file are complete.
+    pub fn set_special_attributes(
+        &self,
+        file_path: &Path,
+    ) -> Result<Vec<SpecialAttribute>, io::Error> {
+        let mut skipped = vec![];
+        if let Some(ref capabilities) = self.capabilities {
+            if let Err(err) = set_xattr(file_path, CAPABILITY_XATTR_NAME, capabilities) {
+                if is_privilege_error(&err) {
+                    skipped.push(SpecialAttribute::Capabilities);
+                } else if is_unsupported_error(&err) {
+                    log::warn!("{:?}: file capabilities not supported", file_path);
+                } else {
+                    return Err(err);
+                }
+            }
+        }
+        if self.fs_flags != 0 {
+            let result = get_fs_flags(file_path)
+                .and_then(|current| set_fs_flags(file_path, current | self.fs_flags));
+            if let Err(err) = result {
+                if is_privilege_error(&err) {
+                    if self.is_immutable() {
+                        skipped.push(SpecialAttribute::Immutable);
+                    }
+                    if self.is_append_only() {
+                        skipped.push(SpecialAttribute::AppendOnly);
+                    }
+                } else if is_unsupported_error(&err) {
+                    log::warn!("{:?}: chattr flags not supported", file_path);
+                } else {
+                    return Err(err);
+                }
+            }
+        }
+        for attribute in skipped.iter() {
+            log::warn!(
+                "{:?}: {} not restored: insufficient privileges",
+                file_path,
+                attribute
+            );
+        }
+        Ok(skipped)
+    }
+}
+
+fn is_privilege_error(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::EPERM) | Some(libc::EACCES) => true,
+        _ => err.kind() == io::ErrorKind::PermissionDenied,
+    }
+}
+
+fn is_unsupported_error(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::ENOTSUP) | Some(libc::ENOTTY) => true,
+        _ => err.kind() == io::ErrorKind::Unsupported,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get_xattr(file_path: &Path, name: &str) -> Result<Option<Vec<u8>>, io::Error> {
+    let c_file_path = CString::new(file_path.as_os_str().as_bytes()).unwrap();
+    let c_name = CString::new(name).unwrap();
+    let size = unsafe {
+        libc::lgetxattr(
+            c_file_path.as_ptr(),
+            c_name.as_ptr(),
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if size < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    let mut buffer = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::lgetxattr(
+            c_file_path.as_ptr(),
+            c_name.as_ptr(),
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len(),
+        )
+    };
+    if size < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        buffer.truncate(size as usize);
+        Ok(Some(buffer))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_xattr(file_path: &Path, name: &str, value: &[u8]) -> Result<(), io::Error> {
+    let c_file_path = CString::new(file_path.as_os_str().as_bytes()).unwrap();
+    let c_name = CString::new(name).unwrap();
+    let failed = unsafe {
+        libc::lsetxattr(
+            c_file_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        ) != 0
+    };
+    if failed {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get_fs_flags(file_path: &Path) -> Result<u32, io::Error> {
+    let file = File::open(file_path)?;
+    let mut flags: libc::c_long = 0;
+    let failed = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) != 0 };
+    if failed {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(flags as u32)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_fs_flags(file_path: &Path, flags: u32) -> Result<(), io::Error> {
+    let file = File::open(file_path)?;
+    let flags = flags as libc::c_long;
+    let failed = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) != 0 };
+    if failed {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_xattr(_file_path: &Path, _name: &str) -> Result<Option<Vec<u8>>, io::Error> {
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_xattr(_file_path: &Path, _name: &str, _value: &[u8]) -> Result<(), io::Error> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_fs_flags(_file_path: &Path) -> Result<u32, io::Error> {
+    Ok(0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fs_flags(_file_path: &Path, _flags: u32) -> Result<(), io::Error> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
 }
 
 #[cfg(target_family = "unix")]
 impl From<Metadata> for Attributes {
     fn from(metadata: Metadata) -> Attributes {
+        // NB: statx() provides this on Linux (where the file system has it)
+        let btime = metadata
+            .created()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
         Attributes {
             st_dev: metadata.dev(),
             st_ino: metadata.ino(),
@@ -98,6 +473,10 @@ impl From<Metadata> for Attributes {
             st_mtime_nsec: metadata.mtime_nsec(),
             st_ctime: metadata.ctime(),
             st_ctime_nsec: metadata.ctime_nsec(),
+            capabilities: None,
+            fs_flags: 0,
+            st_btime: btime.map(|btime| btime.as_secs() as i64),
+            st_btime_nsec: btime.map(|btime| btime.subsec_nanos() as i64),
         }
     }
 }
@@ -108,14 +487,18 @@ impl AttributesIfce for Attributes {
         self.st_size
     }
 
-    fn set_file_attributes(&self, file_path: &Path) -> Result<(), io::Error> {
+    fn set_file_attributes(
+        &self,
+        file_path: &Path,
+        owner_mapping: &OwnerMapping,
+    ) -> Result<(), io::Error> {
         if let Err(err) = self.chmod_file(file_path) {
             log::error!("{:?}: {}", file_path, err);
             Err(err)
         } else if let Err(err) = self.utime_file(file_path) {
             log::error!("{:?}: {}", file_path, err);
             Err(err)
-        } else if let Err(err) = self.chown_file(file_path) {
+        } else if let Err(err) = self.chown_file(file_path, owner_mapping) {
             log::error!("{:?}: {}", file_path, err);
             Err(err)
         } else {
@@ -123,3 +506,83 @@ impl AttributesIfce for Attributes {
         }
     }
 }
+
+#[cfg(test)]
+mod attributes_tests {
+    use super::*;
+    use crate::fixture::FixtureSpec;
+
+    #[test]
+    fn old_format_attributes_deserialize() {
+        let json_str = r#"{"st_dev":1,"st_ino":2,"st_nlink":1,"st_mode":33188,"st_uid":1000,"st_gid":1000,"st_size":10,"st_atime":0,"st_atime_nsec":0,"st_mtime":0,"st_mtime_nsec":0,"st_ctime":0,"st_ctime_nsec":0}"#;
+        let attributes: Attributes = serde_json::from_str(json_str).unwrap();
+        assert!(!attributes.has_capabilities());
+        assert!(!attributes.is_immutable());
+        assert!(!attributes.is_append_only());
+        assert_eq!(attributes.birth_time(), None);
+    }
+
+    #[test]
+    fn birth_times_are_recorded_where_supported() {
+        let fixture = FixtureSpec::new().file("file", "contents").build();
+        let file_path = fixture.root().join("file");
+        let metadata = file_path.metadata().unwrap();
+        let created = metadata.created().ok();
+        let attributes = Attributes::from(metadata);
+        assert_eq!(attributes.birth_time(), created);
+        assert_eq!(attributes.mode_string().len(), 10);
+        assert!(attributes.mode_string().starts_with("-rw"));
+        // a file's own birth time "restores" trivially (unlike any other)
+        assert!(attributes.set_birth_time(&file_path).is_ok());
+        let json_str = serde_json::to_string(&attributes).unwrap();
+        let restored: Attributes = serde_json::from_str(&json_str).unwrap();
+        assert_eq!(restored.birth_time(), created);
+        let without = Attributes {
+            st_btime: None,
+            st_btime_nsec: None,
+            ..attributes.clone()
+        };
+        assert_eq!(attributes, without);
+        assert!(!serde_json::to_string(&without).unwrap().contains("btime"));
+    }
+
+    #[test]
+    fn special_attributes_flags() {
+        let attributes = Attributes {
+            fs_flags: FS_IMMUTABLE_FL,
+            ..Attributes::default()
+        };
+        assert!(attributes.is_immutable());
+        assert!(!attributes.is_append_only());
+        assert_eq!(
+            format!("{}", SpecialAttribute::AppendOnly),
+            "append-only flag"
+        );
+    }
+
+    #[test]
+    fn equality_ignores_access_time() {
+        let attributes = Attributes {
+            st_size: 10,
+            st_atime: 100,
+            st_mtime: 50,
+            ..Attributes::default()
+        };
+        let read_since = Attributes {
+            st_atime: 200,
+            st_atime_nsec: 7,
+            ..attributes.clone()
+        };
+        assert_eq!(attributes, read_since);
+        let modified_since = Attributes {
+            st_mtime: 150,
+            ..read_since.clone()
+        };
+        assert_ne!(attributes, modified_since);
+        let chmodded_since = Attributes {
+            st_mode: 0o100600,
+            ..read_since
+        };
+        assert_ne!(attributes, chmodded_since);
+    }
+}
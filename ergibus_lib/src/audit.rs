@@ -0,0 +1,133 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use chrono::Local;
+
+use crate::{config, EResult, Error};
+
+/// The kinds of destructive operation recorded in the audit log.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    DeleteSnapshot,
+    DeleteArchive,
+    Prune,
+    CollectGarbage,
+}
+
+/// A single entry in the audit log.  `bytes_freed` is the stored size of
+/// any contents no longer referenced as a result of the operation (which
+/// will be reclaimed when the repository is next pruned) or the size of
+/// any files removed.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AuditRecord {
+    pub time: String,
+    pub user: String,
+    pub operation: AuditOperation,
+    pub target: String,
+    pub bytes_freed: u64,
+}
+
+impl AuditRecord {
+    fn new(operation: AuditOperation, target: &str, bytes_freed: u64) -> Self {
+        let user = match users::get_current_username() {
+            Some(user_name) => user_name.to_string_lossy().to_string(),
+            None => users::get_current_uid().to_string(),
+        };
+        Self {
+            time: Local::now().to_rfc3339(),
+            user,
+            operation,
+            target: target.to_string(),
+            bytes_freed,
+        }
+    }
+}
+
+// Append the record as a single line.  If a previous write was torn (i.e.
+// the file doesn't end with a newline) the record is started on a new line
+// so that only the damaged record is lost.
+fn append_record(file_path: &Path, record: &AuditRecord) -> EResult<()> {
+    if let Some(dir_path) = file_path.parent() {
+        fs::create_dir_all(dir_path)?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(file_path)?;
+    let mut line = serde_json::to_string(record)
+        .map_err(|err| Error::AuditLogJsonError(err, file_path.to_path_buf()))?;
+    line.push('\n');
+    if file.metadata()?.len() > 0 {
+        let mut last_byte = [0u8; 1];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last_byte)?;
+        if last_byte[0] != b'\n' {
+            line.insert(0, '\n');
+        }
+    }
+    file.write_all(line.as_bytes())?;
+    file.sync_data()?;
+    Ok(())
+}
+
+fn read_records(file_path: &Path) -> EResult<(Vec<AuditRecord>, usize)> {
+    let file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok((vec![], 0)),
+        Err(err) => return Err(err.into()),
+    };
+    let mut records = vec![];
+    let mut damaged = 0;
+    for line in BufReader::new(file).split(b'\n') {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_slice::<AuditRecord>(&line) {
+            Ok(record) => records.push(record),
+            Err(_) => damaged += 1,
+        }
+    }
+    Ok((records, damaged))
+}
+
+// Auditing is done after the event so failure to record it is reported
+// rather than failing the (already completed) operation.
+pub(crate) fn record(operation: AuditOperation, target: &str, bytes_freed: u64) {
+    let record = AuditRecord::new(operation, target, bytes_freed);
+    let file_path = config::get_audit_log_file_path();
+    if let Err(err) = append_record(&file_path, &record) {
+        log::error!("{:?}: failed to record {:?}: {:?}", file_path, record, err);
+    }
+}
+
+/// Read the audit log returning the intact records and the number of
+/// damaged lines that were skipped.
+pub fn read_audit_log() -> EResult<(Vec<AuditRecord>, usize)> {
+    read_records(&config::get_audit_log_file_path())
+}
+
+#[cfg(test)]
+mod audit_tests {
+    use super::*;
+
+    #[test]
+    fn damaged_records_are_skipped() {
+        let dir = tempdir::TempDir::new("AUDIT_TEST").unwrap();
+        let file_path = dir.path().join("audit");
+        assert_eq!(read_records(&file_path).unwrap(), (vec![], 0));
+        let first = AuditRecord::new(AuditOperation::DeleteSnapshot, "a/b", 1024);
+        append_record(&file_path, &first).unwrap();
+        // simulate a torn write
+        let mut file = OpenOptions::new().append(true).open(&file_path).unwrap();
+        file.write_all(b"{\"time\":\"20").unwrap();
+        let second = AuditRecord::new(AuditOperation::DeleteArchive, "a", 0);
+        append_record(&file_path, &second).unwrap();
+        assert_eq!(read_records(&file_path).unwrap(), (vec![first, second], 1));
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! A manifest (named "CHECKSUMS" in each snapshot directory) of the
+//! SHA-256 checksums of the snapshot files written to the directory so
+//! that damage to them (e.g. bit rot) can be detected independently of
+//! the content repository.  Lines are only ever appended and have the
+//! same format as those written by `sha256sum` so running
+//! `sha256sum -c --ignore-missing CHECKSUMS` in the directory checks them
+//! too.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crypto_hash::{Algorithm, Hasher};
+use hex::ToHex;
+
+use crate::snapshot::{self, Order};
+use crate::{EResult, Error};
+
+pub const CHECKSUMS_FILE_NAME: &str = "CHECKSUMS";
+
+/// How a snapshot file compares with its recorded checksum.
+#[derive(Debug, PartialEq)]
+pub enum ChecksumStatus {
+    Matches,
+    Differs { recorded: String, actual: String },
+    Unrecorded,
+}
+
+/// The SHA-256 checksum (in hex) of the snapshot file.
+pub fn snapshot_file_checksum(ss_file_path: &Path) -> EResult<String> {
+    let mut file = File::open(ss_file_path)
+        .map_err(|err| Error::SnapshotReadIOError(err, ss_file_path.to_path_buf()))?;
+    let mut hasher = Hasher::new(Algorithm::SHA256);
+    io::copy(&mut file, &mut hasher)
+        .map_err(|err| Error::SnapshotReadIOError(err, ss_file_path.to_path_buf()))?;
+    Ok(hasher.finish().to_hex())
+}
+
+fn file_name(ss_file_path: &Path) -> String {
+    ss_file_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+// Append the snapshot file's checksum to its directory's manifest
+pub(crate) fn record_checksum(ss_file_path: &Path) -> EResult<()> {
+    let checksum = snapshot_file_checksum(ss_file_path)?;
+    let dir_path = ss_file_path.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir_path.join(CHECKSUMS_FILE_NAME))?;
+    writeln!(file, "{}  {}", checksum, file_name(ss_file_path))?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// The checksums recorded in the snapshot directory's manifest (by
+/// snapshot file name).  Lines that can't be understood are ignored.
+pub fn read_checksums(dir_path: &Path) -> EResult<BTreeMap<String, String>> {
+    let text = match std::fs::read_to_string(dir_path.join(CHECKSUMS_FILE_NAME)) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut checksums = BTreeMap::new();
+    for line in text.lines() {
+        match line.split_once("  ") {
+            Some((checksum, name)) if checksum.len() == 64 => {
+                checksums.insert(name.to_string(), checksum.to_string());
+            }
+            _ => log::warn!("{:?}: ignoring malformed line: {}", dir_path, line),
+        }
+    }
+    Ok(checksums)
+}
+
+/// Compare the checksum of each of the snapshot files in the directory
+/// with that recorded for it (oldest first).
+pub fn verify_snapshot_dir(dir_path: &Path) -> EResult<Vec<(PathBuf, ChecksumStatus)>> {
+    let checksums = read_checksums(dir_path)?;
+    let mut statuses = vec![];
+    for ss_file_path in snapshot::get_snapshot_paths_in_dir(dir_path, Order::Ascending)? {
+        let status = match checksums.get(&file_name(&ss_file_path)) {
+            Some(recorded) => {
+                let actual = snapshot_file_checksum(&ss_file_path)?;
+                if *recorded == actual {
+                    ChecksumStatus::Matches
+                } else {
+                    ChecksumStatus::Differs {
+                        recorded: recorded.to_string(),
+                        actual,
+                    }
+                }
+            }
+            None => ChecksumStatus::Unrecorded,
+        };
+        statuses.push((ss_file_path, status));
+    }
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod checksums_tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn damaged_snapshot_files_are_detected() {
+        let dir = tempdir::TempDir::new("CHECKSUMS_TEST").unwrap();
+        let names = [
+            "2024-01-01-00-00-00+1000",
+            "2024-01-02-00-00-00+1000",
+            "2024-01-03-00-00-00+1000",
+        ];
+        for name in names.iter() {
+            fs::write(dir.path().join(name), name.as_bytes()).unwrap();
+        }
+        record_checksum(&dir.path().join(names[0])).unwrap();
+        record_checksum(&dir.path().join(names[1])).unwrap();
+        let checksums = read_checksums(dir.path()).unwrap();
+        assert_eq!(checksums.len(), 2);
+        assert_eq!(
+            checksums[names[0]],
+            crypto_hash::hex_digest(Algorithm::SHA256, names[0].as_bytes())
+        );
+
+        fs::write(dir.path().join(names[1]), b"bit rot").unwrap();
+        let statuses: Vec<ChecksumStatus> = verify_snapshot_dir(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|(_, status)| status)
+            .collect();
+        assert_eq!(statuses[0], ChecksumStatus::Matches);
+        assert!(matches!(statuses[1], ChecksumStatus::Differs { .. }));
+        assert_eq!(statuses[2], ChecksumStatus::Unrecorded);
+    }
+}
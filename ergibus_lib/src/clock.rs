@@ -0,0 +1,79 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! The source of the times recorded in snapshots (and hence of their
+//! names and creation durations).  Snapshot generation uses the system
+//! clock unless another clock is injected (e.g. a `ManualClock` so that
+//! tests get predictable snapshot names and durations without sleeping).
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// The clock used unless another is injected.
+pub fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// A clock that only moves when it's told to (or by `tick` each time
+/// it's read).
+#[derive(Debug)]
+pub struct ManualClock {
+    time: Mutex<SystemTime>,
+    tick: Duration,
+}
+
+impl ManualClock {
+    pub fn new(start: SystemTime, tick: Duration) -> Self {
+        Self {
+            time: Mutex::new(start),
+            tick,
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.time.lock().unwrap() += duration;
+    }
+
+    pub fn set(&self, time: SystemTime) {
+        *self.time.lock().unwrap() = time;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        let mut time = self.time.lock().unwrap();
+        let now = *time;
+        *time += self.tick;
+        now
+    }
+}
+
+#[cfg(test)]
+mod clock_tests {
+    use super::*;
+
+    #[test]
+    fn manual_clocks_only_move_when_told() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = ManualClock::new(start, Duration::from_secs(2));
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.now(), start + Duration::from_secs(2));
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(clock.now(), start + Duration::from_secs(64));
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
@@ -1,16 +1,70 @@
 // Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au> <pwil3058@outlook.com>
 
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 
 use dirs;
 
 use path_ext;
 
+use crate::{EResult, Error};
+
 const DEFAULT_CONFIG_DIR_PATH: &str = "~/.config/ergibus";
 
 const DCDP_OVERRIDE_ENVAR: &str = "ERGIBUS_CONFIG_DIR";
 
+const PROFILES_DIR_NAME: &str = "profiles";
+
+/// Where the configuration lives when chosen on the command line (rather
+/// than via the environment): a root directory and/or a named profile
+/// (a separate configuration kept under the root's "profiles" directory
+/// e.g. for personal and work back ups).
+#[derive(Debug, Default)]
+struct ConfigRoot {
+    dir_path: Option<PathBuf>,
+    profile: Option<String>,
+}
+
+lazy_static! {
+    static ref CONFIG_ROOT: RwLock<ConfigRoot> = RwLock::new(ConfigRoot::default());
+}
+
+fn expand_dir_path(dir_path: &Path) -> PathBuf {
+    if dir_path.starts_with("~") {
+        match path_ext::expand_home_dir(dir_path) {
+            Ok(expanded_dir) => expanded_dir,
+            Err(_) => panic!("config dir path expansion failed",),
+        }
+    } else {
+        dir_path.to_path_buf()
+    }
+}
+
+/// Use `dir_path` as the configuration root instead of the directory
+/// given by `$ERGIBUS_CONFIG_DIR` (or the default).
+pub fn set_config_dir_path(dir_path: Option<&Path>) {
+    CONFIG_ROOT.write().unwrap().dir_path = dir_path.map(expand_dir_path);
+}
+
+/// Use the named profile's configuration (or the root's own if `None`).
+pub fn set_profile(profile: Option<&str>) -> EResult<()> {
+    if let Some(profile) = profile {
+        let mut components = Path::new(profile).components();
+        match (components.next(), components.next()) {
+            (Some(std::path::Component::Normal(_)), None) => (),
+            _ => return Err(Error::BadConfigProfileName(profile.to_string())),
+        }
+    }
+    CONFIG_ROOT.write().unwrap().profile = profile.map(str::to_string);
+    Ok(())
+}
+
+/// The name of the profile in use (if any).
+pub fn profile() -> Option<String> {
+    CONFIG_ROOT.read().unwrap().profile.clone()
+}
+
 pub fn abs_default_config_dir_path() -> PathBuf {
     match dirs::config_dir() {
         Some(config_dir) => config_dir.join("ergibus"),
@@ -21,38 +75,85 @@ pub fn abs_default_config_dir_path() -> PathBuf {
     }
 }
 
-fn get_config_dir_path() -> PathBuf {
+fn get_root_config_dir_path() -> PathBuf {
+    if let Some(ref dir_path) = CONFIG_ROOT.read().unwrap().dir_path {
+        return dir_path.clone();
+    }
     match env::var(DCDP_OVERRIDE_ENVAR) {
         Ok(dir_path) => {
             if dir_path.len() == 0 {
                 abs_default_config_dir_path()
-            } else if dir_path.starts_with("~") {
-                match path_ext::expand_home_dir(&PathBuf::from(dir_path)) {
-                    Ok(expanded_dir) => expanded_dir,
-                    Err(_) => panic!("config dir path expansion failed",),
-                }
             } else {
-                PathBuf::from(dir_path)
+                expand_dir_path(Path::new(&dir_path))
             }
         }
         Err(_) => abs_default_config_dir_path(),
     }
 }
 
+pub(crate) fn get_config_dir_path() -> PathBuf {
+    let root_dir_path = get_root_config_dir_path();
+    match CONFIG_ROOT.read().unwrap().profile {
+        Some(ref profile) => root_dir_path.join(PROFILES_DIR_NAME).join(profile),
+        None => root_dir_path,
+    }
+}
+
+/// The names of the profiles that have been set up under the configuration root.
+pub fn get_profile_names() -> Vec<String> {
+    let mut names: Vec<String> =
+        match std::fs::read_dir(get_root_config_dir_path().join(PROFILES_DIR_NAME)) {
+            Ok(dir_entries) => dir_entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_dir())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .collect(),
+            Err(_) => vec![],
+        };
+    names.sort();
+    names
+}
+
 pub fn get_archive_config_dir_path() -> PathBuf {
     get_config_dir_path().join("archives")
 }
 
+pub fn get_audit_log_file_path() -> PathBuf {
+    get_config_dir_path().join("audit.jsonl")
+}
+
+pub fn get_default_repo_file_path() -> PathBuf {
+    get_config_dir_path().join("default_repo")
+}
+
+pub fn get_global_config_file_path() -> PathBuf {
+    get_config_dir_path().join("global.yaml")
+}
+
 pub fn get_gui_config_dir_path() -> PathBuf {
     get_config_dir_path().join("gui")
 }
 
+pub fn get_jobs_dir_path() -> PathBuf {
+    get_config_dir_path().join("jobs")
+}
+
+pub fn get_signing_keys_dir_path() -> PathBuf {
+    get_config_dir_path().join("keys")
+}
+
+pub fn get_trusted_keys_file_path() -> PathBuf {
+    get_config_dir_path().join("trusted_keys")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fixture::TestConfigGuard;
 
     #[test]
     fn get_config_dir_works() {
+        let _guard = TestConfigGuard::new();
         let new_path = "./TEST/config";
         env::set_var(DCDP_OVERRIDE_ENVAR, new_path);
         assert_eq!(get_config_dir_path(), PathBuf::from(new_path));
@@ -67,4 +168,28 @@ mod tests {
             abs_default_config_dir_path().join("archives")
         );
     }
+
+    #[test]
+    fn config_dir_and_profile_can_be_chosen() {
+        let guard = TestConfigGuard::new();
+        let root = guard.data_dir();
+        set_config_dir_path(Some(&root));
+        assert_eq!(get_archive_config_dir_path(), root.join("archives"));
+        assert!(set_profile(Some("../work")).is_err());
+        assert!(set_profile(Some("")).is_err());
+        set_profile(Some("work")).unwrap();
+        assert_eq!(profile().as_deref(), Some("work"));
+        assert_eq!(
+            get_archive_config_dir_path(),
+            root.join("profiles").join("work").join("archives")
+        );
+        std::fs::create_dir_all(get_archive_config_dir_path()).unwrap();
+        assert_eq!(get_profile_names(), vec!["work".to_string()]);
+        set_profile(None).unwrap();
+        set_config_dir_path(None);
+        assert_eq!(
+            get_archive_config_dir_path(),
+            PathBuf::from(env::var(DCDP_OVERRIDE_ENVAR).unwrap()).join("archives")
+        );
+    }
 }
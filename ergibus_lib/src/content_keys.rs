@@ -0,0 +1,111 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! The content repositories used by an archive's snapshots: a primary
+//! repository and (optionally) an overflow repository that receives the
+//! contents of files above a size threshold (e.g. a local SSD for small
+//! files and a NAS for large ones).
+
+use std::path::Path;
+
+use schemars::JsonSchema;
+
+use dychatat_lib::content::{
+    self, ContentManager, ContentManagerOptions, ContentMgmtKey, TieredContentStore,
+};
+use dychatat_lib::Mutability;
+
+use crate::EResult;
+
+/// Where the contents of large files go.
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+pub struct Overflow {
+    pub content_mgmt_key: ContentMgmtKey,
+    /// Files of at least this many bytes go to the overflow repository
+    pub min_file_size: u64,
+}
+
+pub type TieredContentManager = TieredContentStore<ContentManager>;
+
+/// The keys for all of the repositories holding a snapshot's contents.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ContentKeys {
+    pub primary: ContentMgmtKey,
+    pub overflow: Option<Overflow>,
+}
+
+impl ContentKeys {
+    pub fn new(primary: &ContentMgmtKey, overflow: Option<&Overflow>) -> Self {
+        Self {
+            primary: primary.clone(),
+            overflow: overflow.cloned(),
+        }
+    }
+
+    /// The keys with any relocations recorded via `dychatat relocate` applied.
+    pub fn relocated(&self) -> EResult<Self> {
+        let overflow = match self.overflow {
+            Some(ref overflow) => Some(Overflow {
+                content_mgmt_key: content::relocated_key(&overflow.content_mgmt_key)?,
+                min_file_size: overflow.min_file_size,
+            }),
+            None => None,
+        };
+        Ok(Self {
+            primary: content::relocated_key(&self.primary)?,
+            overflow,
+        })
+    }
+
+    /// Open all of the repositories (always in the same order so that
+    /// concurrent users can't deadlock).
+    pub fn open_content_store(&self, mutability: Mutability) -> EResult<TieredContentManager> {
+        self.open_content_store_with_options(mutability, &ContentManagerOptions::default())
+    }
+
+    /// As `open_content_store()` with the repositories' content managers
+    /// behaving as specified by `options`.
+    pub fn open_content_store_with_options(
+        &self,
+        mutability: Mutability,
+        options: &ContentManagerOptions,
+    ) -> EResult<TieredContentManager> {
+        let primary = self
+            .primary
+            .open_content_manager_with_options(mutability, options)?;
+        let overflow = match self.overflow {
+            Some(ref overflow) => Some((
+                overflow
+                    .content_mgmt_key
+                    .open_content_manager_with_options(mutability, options)?,
+                overflow.min_file_size,
+            )),
+            None => None,
+        };
+        Ok(TieredContentStore::new(primary, overflow))
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &ContentMgmtKey> {
+        std::iter::once(&self.primary).chain(
+            self.overflow
+                .as_ref()
+                .map(|overflow| &overflow.content_mgmt_key),
+        )
+    }
+
+    /// Make the journalled releases with the given id conditional on the
+    /// file at `unless_exists` not existing in all of the repositories.
+    pub fn guard_journal_entry(&self, id: &str, unless_exists: &Path) -> EResult<()> {
+        for key in self.keys() {
+            key.guard_journal_entry(id, unless_exists)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the journal entry with the given id from all of the repositories.
+    pub fn discard_journal_entry(&self, id: &str) -> EResult<()> {
+        for key in self.keys() {
+            key.discard_journal_entry(id)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Checks of the environment that back ups depend on (the configuration
+//! directory, the content repositories, the archives' snapshot
+//! directories and specifications and the system clock) each with a hint
+//! about how to fix any problem found.  Nothing is changed by the checks
+//! (apart from a probe file briefly created in the configuration
+//! directory).
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local};
+
+use dychatat_lib::content;
+
+use crate::archive::{self, Snapshots};
+use crate::config;
+use crate::snapshot;
+
+// How far in the future the newest snapshot may be before the clock is
+// suspected of having gone backwards (allowing for small adjustments)
+const CLOCK_TOLERANCE: Duration = Duration::from_secs(300);
+
+const PROBE_FILE_NAME: &str = ".doctor_probe";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// The result of one check.
+#[derive(Serialize, Debug, Clone)]
+pub struct CheckResult {
+    pub check: String,
+    pub status: CheckStatus,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(check: String, detail: String) -> Self {
+        Self {
+            check,
+            status: CheckStatus::Pass,
+            detail,
+            hint: None,
+        }
+    }
+
+    fn warn(check: String, detail: String, hint: &str) -> Self {
+        Self {
+            check,
+            status: CheckStatus::Warn,
+            detail,
+            hint: Some(hint.to_string()),
+        }
+    }
+
+    fn fail(check: String, detail: String, hint: &str) -> Self {
+        Self {
+            check,
+            status: CheckStatus::Fail,
+            detail,
+            hint: Some(hint.to_string()),
+        }
+    }
+}
+
+fn check_config_dir(dir_path: &Path) -> CheckResult {
+    let check = format!("config dir {}", dir_path.display());
+    if !dir_path.exists() {
+        return CheckResult::warn(
+            check,
+            "does not exist".to_string(),
+            "it will be created when the first archive or repository is created",
+        );
+    }
+    if let Err(err) = fs::read_dir(dir_path) {
+        return CheckResult::fail(
+            check,
+            format!("not readable: {}", err),
+            "check the directory's ownership and permissions",
+        );
+    }
+    let probe_path = dir_path.join(PROBE_FILE_NAME);
+    match fs::write(&probe_path, b"") {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe_path);
+            CheckResult::pass(check, "readable and writable".to_string())
+        }
+        Err(err) => CheckResult::fail(
+            check,
+            format!("not writable: {}", err),
+            "check the directory's ownership and permissions (and that its file system isn't read only)",
+        ),
+    }
+}
+
+fn check_repo(repo_name: &str) -> CheckResult {
+    let check = format!("repository {}", repo_name);
+    let key = match content::get_content_mgmt_key(repo_name) {
+        Ok(key) => key,
+        Err(err) => {
+            return CheckResult::fail(
+                check,
+                err.to_string(),
+                "check the repository's specification in the configuration directory",
+            )
+        }
+    };
+    if !key.base_dir_path().is_dir() {
+        return CheckResult::fail(
+            check,
+            format!("{}: not reachable", key.base_dir_path().display()),
+            "mount the repository's file system (or record its new location with \"dychatat relocate\")",
+        );
+    }
+    match key.try_lock() {
+        Ok(true) => CheckResult::pass(
+            check,
+            format!("{}: lockable", key.base_dir_path().display()),
+        ),
+        Ok(false) => CheckResult::warn(
+            check,
+            format!(
+                "{}: locked by another process",
+                key.base_dir_path().display()
+            ),
+            "wait for the back up or extraction using it to finish",
+        ),
+        Err(err) => CheckResult::fail(
+            check,
+            format!(
+                "{}: cannot be locked: {}",
+                key.base_dir_path().display(),
+                err
+            ),
+            "check the repository's ownership and permissions",
+        ),
+    }
+}
+
+// The newest snapshot shouldn't appear to have been taken in the future
+fn check_clock(archive_name: &str, newest: DateTime<Local>, now: SystemTime) -> CheckResult {
+    let check = format!("clock vs {}", archive_name);
+    let now = DateTime::<Local>::from(now);
+    match (newest - now).to_std() {
+        Ok(ahead) if ahead > CLOCK_TOLERANCE => CheckResult::fail(
+            check,
+            format!(
+                "the newest snapshot ({}) is {}s in the future",
+                newest.format("%Y-%m-%d %H:%M:%S %z"),
+                ahead.as_secs()
+            ),
+            "set the system clock (snapshots are ordered by the time they were taken)",
+        ),
+        _ => CheckResult::pass(
+            check,
+            format!("newest snapshot {}", newest.format("%Y-%m-%d %H:%M:%S %z")),
+        ),
+    }
+}
+
+fn check_archive(archive_name: &str, now: SystemTime) -> Vec<CheckResult> {
+    let mut results = vec![];
+    let check = format!("snapshot dir {}", archive_name);
+    let snapshots = match Snapshots::try_from(archive_name) {
+        Ok(snapshots) => snapshots,
+        Err(err) => {
+            results.push(CheckResult::fail(
+                check,
+                err.to_string(),
+                "mount the snapshot directory's file system (or recreate the directory)",
+            ));
+            // NB: the specification can't be checked without its snapshot directory
+            return results;
+        }
+    };
+    results.push(CheckResult::pass(check, "present".to_string()));
+    let check = format!("archive {}", archive_name);
+    match archive::get_archive_data(archive_name) {
+        Ok(_) => results.push(CheckResult::pass(
+            check,
+            "specification and patterns are valid".to_string(),
+        )),
+        Err(err) => results.push(CheckResult::fail(
+            check,
+            err.to_string(),
+            "correct the archive's inclusions and exclusion patterns in its specification file",
+        )),
+    }
+    if let Some(newest) = snapshots
+        .get_latest_snapshot_path()
+        .ok()
+        .and_then(|path| snapshot::snapshot_name_time(path.file_name()?))
+    {
+        results.push(check_clock(archive_name, newest, now));
+    }
+    results
+}
+
+/// Run all of the checks.
+pub fn run_checks() -> Vec<CheckResult> {
+    let now = SystemTime::now();
+    let mut results = vec![check_config_dir(&config::get_config_dir_path())];
+    let mut repo_names = content::get_repo_names();
+    repo_names.sort();
+    results.extend(repo_names.iter().map(|repo_name| check_repo(repo_name)));
+    for archive_name in archive::get_archive_names().iter() {
+        results.extend(check_archive(archive_name, now));
+    }
+    results
+}
+
+#[cfg(test)]
+mod doctor_tests {
+    use super::*;
+    use crate::archive::create_new_archive;
+    use crate::fixture::{FixtureSpec, TestConfigGuard};
+    use dychatat_lib::content::create_new_repo;
+
+    #[test]
+    fn clocks_behind_the_newest_snapshot_fail() {
+        let now = SystemTime::now();
+        let newest = DateTime::<Local>::from(now);
+        assert_eq!(
+            check_clock("a", newest, now + Duration::from_secs(60)).status,
+            CheckStatus::Pass
+        );
+        assert_eq!(
+            check_clock("a", newest, now - Duration::from_secs(60)).status,
+            CheckStatus::Pass
+        );
+        let result = check_clock("a", newest, now - Duration::from_secs(3600));
+        assert_eq!(result.status, CheckStatus::Fail);
+        assert!(result.hint.is_some());
+    }
+
+    #[test]
+    fn problems_are_found() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new().file("a.txt", "some text").build();
+        let inclusions = vec![fixture.root().to_path_buf()];
+        for archive_name in ["test_ok", "test_lost"] {
+            create_new_archive(
+                archive_name,
+                Some("test_repo"),
+                &location,
+                &inclusions,
+                &[],
+                &[],
+                &[],
+                false,
+            )
+            .unwrap();
+            snapshot::generate_snapshot(archive_name).unwrap();
+        }
+        let results = run_checks();
+        assert!(
+            results
+                .iter()
+                .all(|result| result.status == CheckStatus::Pass),
+            "{:?}",
+            results
+        );
+        assert!(results
+            .iter()
+            .any(|result| result.check == "clock vs test_ok"));
+
+        let lost_dir_path = archive::get_archive_snapshot_dir_path("test_lost").unwrap();
+        fs::rename(&lost_dir_path, location.join("moved")).unwrap();
+        let failed: Vec<String> = run_checks()
+            .into_iter()
+            .filter(|result| result.status == CheckStatus::Fail)
+            .map(|result| result.check)
+            .collect();
+        assert_eq!(failed, vec!["snapshot dir test_lost".to_string()]);
+    }
+}
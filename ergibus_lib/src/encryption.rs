@@ -0,0 +1,220 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Optional encryption of snapshot files.  Snapshot files contain the full
+//! path names of everything backed up so archives whose snapshot files are
+//! kept somewhere less trusted can have them encrypted with a key kept (with
+//! the signing keys) in the configuration directory.  Encrypted files are
+//! recognised (and decrypted) when they're loaded so no other changes are
+//! needed to use them.  The ".stats" files contain only counts and are not
+//! encrypted.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use crypto_hash::{Algorithm, Hasher};
+use hex::{FromHex, ToHex};
+
+use crate::archive::{self, SnapshotEncryption};
+use crate::{config, EResult, Error};
+
+pub(crate) const ENCRYPTION_KEY_EXTENSION: &str = "enc";
+
+// Encrypted files start with the magic bytes followed by the identity of
+// the key (so that it can be found without knowing the archive), the nonce
+// and then the encrypted contents.
+const MAGIC: &[u8] = b"ergibus-encrypted-1\n";
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + NONCE_LEN;
+
+#[derive(Clone)]
+struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    fn id(&self) -> [u8; KEY_ID_LEN] {
+        let mut hasher = Hasher::new(Algorithm::SHA256);
+        hasher.write_all(&self.0).expect("hashing to memory");
+        let digest = hasher.finish();
+        let mut id = [0u8; KEY_ID_LEN];
+        id.copy_from_slice(&digest[..KEY_ID_LEN]);
+        id
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+}
+
+fn encryption_key_file_path(archive_name: &str) -> PathBuf {
+    config::get_signing_keys_dir_path()
+        .join(format!("{}.{}", archive_name, ENCRYPTION_KEY_EXTENSION))
+}
+
+fn read_encryption_key(key_file_path: &Path) -> EResult<Option<EncryptionKey>> {
+    let key_text = match fs::read_to_string(key_file_path) {
+        Ok(key_text) => key_text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let bytes = Vec::<u8>::from_hex(key_text.trim())
+        .map_err(|_| Error::EncryptionKeyMalformed(key_file_path.to_path_buf()))?;
+    let bytes = <[u8; 32]>::try_from(bytes.as_slice())
+        .map_err(|_| Error::EncryptionKeyMalformed(key_file_path.to_path_buf()))?;
+    Ok(Some(EncryptionKey(bytes)))
+}
+
+fn write_encryption_key(key_file_path: &Path, key: &EncryptionKey) -> EResult<()> {
+    if let Some(dir_path) = key_file_path.parent() {
+        fs::create_dir_all(dir_path)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(key_file_path)?;
+    writeln!(file, "{}", key.0.to_hex())?;
+    Ok(())
+}
+
+// All of the encryption keys in the configuration directory
+fn read_all_encryption_keys() -> EResult<Vec<EncryptionKey>> {
+    let dir_path = config::get_signing_keys_dir_path();
+    let entries = match fs::read_dir(&dir_path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut keys = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() == Some(ENCRYPTION_KEY_EXTENSION.as_ref()) {
+            if let Some(key) = read_encryption_key(&path)? {
+                keys.push(key);
+            }
+        }
+    }
+    Ok(keys)
+}
+
+fn encrypt(key: &EncryptionKey, bytes: &[u8], file_path: &Path) -> EResult<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).map_err(|err| Error::IOError(err.into()))?;
+    let ciphertext = key
+        .cipher()
+        .encrypt(Nonce::from_slice(&nonce), bytes)
+        .map_err(|_| Error::SnapshotEncryptFailed(file_path.to_path_buf()))?;
+    let mut sealed = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&key.id());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+fn decrypt(keys: &[EncryptionKey], bytes: &[u8], file_path: &Path) -> EResult<Vec<u8>> {
+    if bytes.len() < HEADER_LEN {
+        return Err(Error::SnapshotDecryptFailed(file_path.to_path_buf()));
+    }
+    let key_id = &bytes[MAGIC.len()..MAGIC.len() + KEY_ID_LEN];
+    let key = keys
+        .iter()
+        .find(|key| key.id() == key_id)
+        .ok_or_else(|| Error::SnapshotKeyUnavailable(file_path.to_path_buf(), key_id.to_hex()))?;
+    let nonce = &bytes[MAGIC.len() + KEY_ID_LEN..HEADER_LEN];
+    key.cipher()
+        .decrypt(Nonce::from_slice(nonce), &bytes[HEADER_LEN..])
+        .map_err(|_| Error::SnapshotDecryptFailed(file_path.to_path_buf()))
+}
+
+/// Generate the key with which the archive's new snapshot files will be
+/// encrypted.  The key file must be kept (and backed up separately) as
+/// the snapshot files can't be read without it.  Returns the key's path.
+pub fn generate_encryption_key(archive_name: &str) -> EResult<PathBuf> {
+    // make sure the archive exists
+    archive::get_archive_snapshot_dir_path(archive_name)?;
+    let key_file_path = encryption_key_file_path(archive_name);
+    if key_file_path.exists() {
+        return Err(Error::EncryptionKeyExists(archive_name.to_string()));
+    }
+    let mut secret = [0u8; 32];
+    getrandom::getrandom(&mut secret).map_err(|err| Error::IOError(err.into()))?;
+    write_encryption_key(&key_file_path, &EncryptionKey(secret))?;
+    Ok(key_file_path)
+}
+
+pub fn has_encryption_key(archive_name: &str) -> bool {
+    encryption_key_file_path(archive_name).exists()
+}
+
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Encrypt the snapshot file's contents if the archive has an encryption key
+// (and encryption hasn't been turned off)
+pub(crate) fn seal_snapshot_bytes(
+    archive_name: &str,
+    bytes: Vec<u8>,
+    file_path: &Path,
+) -> EResult<Vec<u8>> {
+    let policy = archive::get_snapshot_encryption(archive_name)?;
+    if policy == SnapshotEncryption::Off {
+        return Ok(bytes);
+    }
+    match read_encryption_key(&encryption_key_file_path(archive_name))? {
+        Some(key) => encrypt(&key, &bytes, file_path),
+        None if policy == SnapshotEncryption::Required => {
+            Err(Error::EncryptionKeyMissing(archive_name.to_string()))
+        }
+        None => Ok(bytes),
+    }
+}
+
+// Decrypt the snapshot file's contents if they're encrypted
+pub(crate) fn open_snapshot_bytes(bytes: Vec<u8>, file_path: &Path) -> EResult<Vec<u8>> {
+    if is_encrypted(&bytes) {
+        decrypt(&read_all_encryption_keys()?, &bytes, file_path)
+    } else {
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod encryption_tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_bytes_need_the_right_key() {
+        let dir = tempdir::TempDir::new("ENCRYPTION_TEST").unwrap();
+        let key_file_path = dir.path().join("key.enc");
+        assert!(read_encryption_key(&key_file_path).unwrap().is_none());
+        write_encryption_key(&key_file_path, &EncryptionKey([3u8; 32])).unwrap();
+        let key = read_encryption_key(&key_file_path).unwrap().unwrap();
+        assert!(write_encryption_key(&key_file_path, &key).is_err());
+
+        let file_path = dir.path().join("2021-01-01-00-00-00+1000");
+        let sealed = encrypt(&key, b"/home/me/secret plans", &file_path).unwrap();
+        assert!(is_encrypted(&sealed));
+        assert!(!is_encrypted(b"/home/me/secret plans"));
+        assert_eq!(
+            decrypt(std::slice::from_ref(&key), &sealed, &file_path).unwrap(),
+            b"/home/me/secret plans"
+        );
+        let other_key = EncryptionKey([4u8; 32]);
+        assert!(matches!(
+            decrypt(std::slice::from_ref(&other_key), &sealed, &file_path),
+            Err(Error::SnapshotKeyUnavailable(_, _))
+        ));
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decrypt(&[other_key, key], &tampered, &file_path),
+            Err(Error::SnapshotDecryptFailed(_))
+        ));
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Quick estimates of the size of archives' next back ups made by walking
+//! their inclusions (honouring their exclusions) and summing the sizes and
+//! counts of the files found without reading or storing anything.  They
+//! allow a back up's progress to be reported as a percentage with an
+//! estimated time to completion.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::archive::{get_archive_data, Exclusions};
+use crate::progress;
+use crate::EResult;
+
+/// What the next back up of an archive is expected to contain.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct BackUpEstimate {
+    pub dir_count: u64,
+    pub file_count: u64,
+    pub byte_count: u64,
+    pub sym_link_count: u64,
+    /// How long the estimate took to make
+    pub scan_duration: Duration,
+}
+
+impl BackUpEstimate {
+    fn add_dir(&mut self, dir_path: &Path, exclusions: &Exclusions) -> EResult<()> {
+        self.dir_count += 1;
+        progress::check_cancelled()?;
+        // unreadable directories will be reported by the back up itself
+        let read_dir = match fs::read_dir(dir_path) {
+            Ok(read_dir) => read_dir,
+            Err(_) => return Ok(()),
+        };
+        for entry in read_dir.filter_map(|e| e.ok()) {
+            if exclusions.is_excluded(&entry)? {
+                continue;
+            }
+            match entry.file_type() {
+                Ok(e_type) if e_type.is_dir() => self.add_dir(&entry.path(), exclusions)?,
+                Ok(e_type) if e_type.is_file() => {
+                    if let Ok(metadata) = entry.metadata() {
+                        self.file_count += 1;
+                        self.byte_count += metadata.len();
+                    }
+                }
+                Ok(e_type) if e_type.is_symlink() => self.sym_link_count += 1,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// The fraction (between 0.0 and 1.0) of the estimated bytes (or files
+    /// if there are no bytes) that `file_count` files with `byte_count`
+    /// bytes represent.
+    pub fn fraction_done(&self, file_count: u64, byte_count: u64) -> f64 {
+        let fraction = if self.byte_count > 0 {
+            byte_count as f64 / self.byte_count as f64
+        } else if self.file_count > 0 {
+            file_count as f64 / self.file_count as f64
+        } else {
+            1.0
+        };
+        fraction.clamp(0.0, 1.0)
+    }
+
+    /// The estimated time remaining for a back up that has taken `elapsed`
+    /// to do `fraction_done` of the work (if it can be estimated yet).
+    pub fn time_remaining(&self, fraction_done: f64, elapsed: Duration) -> Option<Duration> {
+        if fraction_done <= 0.0 {
+            None
+        } else {
+            Some(elapsed.mul_f64((1.0 - fraction_done) / fraction_done))
+        }
+    }
+}
+
+/// Estimate the size of the archive's next back up.
+pub fn estimate_back_up(archive_name: &str) -> EResult<BackUpEstimate> {
+    let started = Instant::now();
+    let archive_data = get_archive_data(archive_name)?;
+    let mut estimate = BackUpEstimate::default();
+    for inclusion in archive_data.includes.iter() {
+        match inclusion.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => {
+                estimate.add_dir(inclusion, &archive_data.exclusions)?
+            }
+            Ok(metadata) if metadata.is_file() => {
+                estimate.file_count += 1;
+                estimate.byte_count += metadata.len();
+            }
+            Ok(metadata) if metadata.file_type().is_symlink() => estimate.sym_link_count += 1,
+            _ => (),
+        }
+    }
+    estimate.scan_duration = started.elapsed();
+    Ok(estimate)
+}
+
+#[cfg(test)]
+mod estimate_tests {
+    use super::*;
+    use crate::archive::create_new_archive;
+    use crate::fixture::{FixtureSpec, TestConfigGuard};
+    use crate::snapshot;
+    use dychatat_lib::content::create_new_repo;
+
+    #[test]
+    fn estimates_match_back_ups() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new()
+            .file("a.txt", "some text")
+            .file_of_size("sub/big.bin", 20_000)
+            .file("sub/excluded.tmp", "not counted")
+            .dir("empty")
+            .symlink("link", "a.txt")
+            .build();
+        let inclusions = vec![fixture.root().to_path_buf()];
+        create_new_archive(
+            "test_estimate",
+            Some("test_repo"),
+            &location,
+            &inclusions,
+            &[],
+            &["*.tmp".to_string()],
+            &[],
+            false,
+        )
+        .unwrap();
+        let estimate = estimate_back_up("test_estimate").unwrap();
+        assert_eq!(
+            (
+                estimate.dir_count,
+                estimate.file_count,
+                estimate.byte_count,
+                estimate.sym_link_count
+            ),
+            (3, 2, 20_009, 1)
+        );
+        let (_, file_stats, sym_link_stats, _, _) =
+            snapshot::generate_snapshot("test_estimate").unwrap();
+        assert_eq!(estimate.file_count, file_stats.file_count);
+        assert_eq!(estimate.byte_count, file_stats.byte_count);
+        assert_eq!(
+            estimate.sym_link_count,
+            sym_link_stats.dir_sym_link_count + sym_link_stats.file_sym_link_count
+        );
+    }
+
+    #[test]
+    fn progress_is_a_fraction_of_the_estimate() {
+        let estimate = BackUpEstimate {
+            file_count: 10,
+            byte_count: 1000,
+            ..BackUpEstimate::default()
+        };
+        assert_eq!(estimate.fraction_done(5, 250), 0.25);
+        assert_eq!(estimate.fraction_done(20, 2000), 1.0);
+        assert_eq!(
+            estimate.time_remaining(0.25, Duration::from_secs(10)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(estimate.time_remaining(0.0, Duration::from_secs(10)), None);
+        let files_only = BackUpEstimate {
+            file_count: 4,
+            ..BackUpEstimate::default()
+        };
+        assert_eq!(files_only.fraction_done(1, 0), 0.25);
+        assert_eq!(BackUpEstimate::default().fraction_done(0, 0), 1.0);
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! The exit statuses used by the command line interface (so that scripts
+//! can tell what kind of failure occurred) and their mapping from errors.
+//! The statuses (and the mapping from repository errors) are shared with
+//! "dychatat" so they're defined in `dychatat_lib`.
+
+pub use dychatat_lib::exit_status::ExitStatus;
+
+use crate::Error;
+
+/// A description of the exit statuses (e.g. for help messages).
+pub const EXIT_STATUS_HELP: &str = "EXIT STATUS:
+    0  success
+    1  failure (including partial failure e.g. one of several back ups)
+    2  usage error
+    3  configuration error (e.g. unknown archive or repository)
+    4  content repository error
+    5  integrity error (damaged, altered or untrusted snapshots or contents)
+    6  file system error (including lack of space)
+    7  cancelled";
+
+impl From<&Error> for ExitStatus {
+    fn from(error: &Error) -> Self {
+        use Error::*;
+        match error {
+            InvalidArchiveName(..)
+            | RelativeIncludePath(..)
+            | PathNotInArchive(..)
+            | ArchiveIncludePathError(..)
+            | GlobError(_)
+            | ExtractTargetPathError(..)
+            | SnapshotIndexOutOfRange(..)
+            | SnapshotUnknownFile(_)
+            | SnapshotUnknownDirectory(_)
+            | BadOwnerMapping(_)
+            | LastSnapshot(_)
+            | SnapshotExists(_)
+            | ImportTargetAmbiguous(_)
+            | JobUnknown(_) => ExitStatus::Usage,
+
+            ArchiveExists(_)
+            | ArchiveUnknown(_)
+            | ArchiveYamlReadError(..)
+            | ArchiveYamlWriteError(..)
+            | UnknownRepo(_)
+            | NoRepoNominated
+            | OverflowRepoIsPrimary(_)
+            | OverflowRepoHashMismatch(_)
+            | ConfigBundleJsonError(..)
+            | GlobalConfigYamlError(..)
+            | UnknownExclusionProfile(_)
+            | UnknownArchiveGroup(_)
+            | BadConfigProfileName(_)
+            | OwnerMapYamlError(..)
+            | SigningKeyExists(_)
+            | SigningKeyMalformed(_)
+            | TrustedKeysYamlError(..)
+            | EncryptionKeyExists(_)
+            | EncryptionKeyMalformed(_)
+            | EncryptionKeyMissing(_)
+            | UnsupportedCompression(..) => ExitStatus::Config,
+
+            RepoError(repo_error) => repo_error.into(),
+
+            SnapshotMismatch(_)
+            | SnapshotMismatchDirty(..)
+            | SnapshotReadJsonError(..)
+            | SnapshotNotSigned(_)
+            | SnapshotBadSignature(_)
+            | SnapshotUntrustedKey(_)
+            | SnapshotKeyUnavailable(..)
+            | SnapshotDecryptFailed(_)
+            | SnapshotTreeHashMismatch(_)
+            | SnapshotsUnverified(_) => ExitStatus::Integrity,
+
+            IOError(_)
+            | ArchiveDirError(..)
+            | ArchiveReadError(..)
+            | ArchiveWriteError(..)
+            | ContentCopyIOError(_)
+            | ConfigBundleReadError(..)
+            | ConfigBundleWriteError(..)
+            | SnapshotDeleteIOError(..)
+            | SnapshotDirIOError(..)
+            | SnapshotDirReadOnly(_)
+            | SnapshotMoveAsideFailed(..)
+            | SnapshotReadIOError(..)
+            | SnapshotWriteIOError(..)
+            | ExtractInsufficientSpace(..)
+            | PruneInsufficientSpace(..)
+            | FSOInsufficientSpace(..) => ExitStatus::FileSystem,
+
+            Cancelled => ExitStatus::Cancelled,
+
+            ArchiveEmpty(_)
+            | NoSnapshotAvailable
+            | SnapshotSerializeError(_)
+            | SnapshotEncryptFailed(_)
+            | SnapshotsFailed(_)
+            | SnapshotOverBudget(_)
+            | RestoresFailed(_)
+            | DoctorChecksFailed(_)
+            | AuditLogJsonError(..)
+            | DuplicateFileSystemObjectName
+            | FSOMalformedPath(_)
+            | FSOBrokenSymLink(..)
+            | FSOSpecialFile(..)
+            | FSOReadTimeout(_)
+            | AsyncTaskFailed(_)
+            | JobRecordJsonError(..) => ExitStatus::Failure,
+        }
+    }
+}
+
+#[cfg(test)]
+mod exit_status_tests {
+    use super::*;
+    use dychatat_lib::RepoError;
+    use std::path::PathBuf;
+
+    #[test]
+    fn errors_map_to_exit_statuses() {
+        let status = |error: Error| ExitStatus::from(&error).code();
+        assert_eq!(status(Error::SnapshotsFailed(2)), 1);
+        assert_eq!(status(Error::JobUnknown(7)), 2);
+        assert_eq!(status(Error::ArchiveUnknown("a".to_string())), 3);
+        assert_eq!(status(RepoError::NotImplemented.into()), 4);
+        assert_eq!(status(RepoError::UnknownRepo("r".to_string()).into()), 3);
+        assert_eq!(status(Error::SnapshotBadSignature(PathBuf::new())), 5);
+        assert_eq!(status(std::io::Error::other("oops").into()), 6);
+        assert_eq!(status(Error::FSOInsufficientSpace(PathBuf::new(), 2, 1)), 6);
+        assert_eq!(status(Error::Cancelled), 7);
+        assert_eq!(ExitStatus::Success.code(), 0);
+    }
+}
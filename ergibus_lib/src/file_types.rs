@@ -0,0 +1,223 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! A breakdown of the files in a snapshot into broad categories (by their
+//! file name extensions) so that users can see what takes up the space in
+//! their back ups.
+
+use std::fmt;
+use std::ops::{Add, AddAssign};
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const CODE_EXTENSIONS: &[&str] = &[
+    "asm", "bash", "c", "cc", "clj", "cpp", "cs", "css", "cxx", "el", "erl", "ex", "exs", "f",
+    "f90", "go", "h", "hpp", "hs", "htm", "html", "java", "js", "json", "jsx", "kt", "kts", "lua",
+    "m", "ml", "mli", "mm", "php", "pl", "pm", "py", "r", "rb", "rs", "s", "scala", "scss", "sh",
+    "sql", "swift", "toml", "ts", "tsx", "vim", "xml", "yaml", "yml", "zsh",
+];
+
+const IMAGE_EXTENSIONS: &[&str] = &[
+    "arw", "bmp", "cr2", "dng", "gif", "heic", "heif", "ico", "jpeg", "jpg", "nef", "png", "psd",
+    "raw", "svg", "tif", "tiff", "webp", "xcf",
+];
+
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "3gp", "avi", "flv", "m2ts", "m4v", "mkv", "mov", "mp4", "mpeg", "mpg", "mts", "ogv", "vob",
+    "webm", "wmv",
+];
+
+const ARCHIVE_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "bz2", "cab", "deb", "dmg", "gz", "iso", "jar", "lz", "lz4", "lzma", "rar", "rpm",
+    "tar", "tbz2", "tgz", "txz", "xz", "zip", "zst",
+];
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FileCategory {
+    Code,
+    Images,
+    Video,
+    Archives,
+    Other,
+}
+
+impl FileCategory {
+    pub const ALL: [FileCategory; 5] = [
+        FileCategory::Code,
+        FileCategory::Images,
+        FileCategory::Video,
+        FileCategory::Archives,
+        FileCategory::Other,
+    ];
+
+    /// The category of the file (by its name's extension).
+    pub fn of<P: AsRef<Path>>(file_path: P) -> Self {
+        let extension = match file_path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some(extension) => extension.to_lowercase(),
+            None => return FileCategory::Other,
+        };
+        let extension = extension.as_str();
+        if CODE_EXTENSIONS.binary_search(&extension).is_ok() {
+            FileCategory::Code
+        } else if IMAGE_EXTENSIONS.binary_search(&extension).is_ok() {
+            FileCategory::Images
+        } else if VIDEO_EXTENSIONS.binary_search(&extension).is_ok() {
+            FileCategory::Video
+        } else if ARCHIVE_EXTENSIONS.binary_search(&extension).is_ok() {
+            FileCategory::Archives
+        } else {
+            FileCategory::Other
+        }
+    }
+}
+
+impl fmt::Display for FileCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileCategory::Code => write!(f, "code"),
+            FileCategory::Images => write!(f, "images"),
+            FileCategory::Video => write!(f, "video"),
+            FileCategory::Archives => write!(f, "archives"),
+            FileCategory::Other => write!(f, "other"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Default, Copy, Clone)]
+pub struct CategoryStats {
+    pub file_count: u64,
+    pub byte_count: u64,
+}
+
+impl AddAssign for CategoryStats {
+    fn add_assign(&mut self, other: CategoryStats) {
+        self.file_count += other.file_count;
+        self.byte_count += other.byte_count;
+    }
+}
+
+/// The numbers of files (and their sizes) in each category.
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Default, Copy, Clone)]
+pub struct TypeBreakdown {
+    pub code: CategoryStats,
+    pub images: CategoryStats,
+    pub video: CategoryStats,
+    pub archives: CategoryStats,
+    pub other: CategoryStats,
+}
+
+impl TypeBreakdown {
+    /// The breakdown for a single file.
+    pub fn for_file<P: AsRef<Path>>(file_path: P, size: u64) -> Self {
+        let mut breakdown = Self::default();
+        *breakdown.get_mut(FileCategory::of(file_path)) = CategoryStats {
+            file_count: 1,
+            byte_count: size,
+        };
+        breakdown
+    }
+
+    pub fn get(&self, category: FileCategory) -> CategoryStats {
+        match category {
+            FileCategory::Code => self.code,
+            FileCategory::Images => self.images,
+            FileCategory::Video => self.video,
+            FileCategory::Archives => self.archives,
+            FileCategory::Other => self.other,
+        }
+    }
+
+    fn get_mut(&mut self, category: FileCategory) -> &mut CategoryStats {
+        match category {
+            FileCategory::Code => &mut self.code,
+            FileCategory::Images => &mut self.images,
+            FileCategory::Video => &mut self.video,
+            FileCategory::Archives => &mut self.archives,
+            FileCategory::Other => &mut self.other,
+        }
+    }
+
+    /// The categories' statistics (largest first).
+    pub fn by_size(&self) -> Vec<(FileCategory, CategoryStats)> {
+        let mut list: Vec<(FileCategory, CategoryStats)> = FileCategory::ALL
+            .iter()
+            .map(|category| (*category, self.get(*category)))
+            .collect();
+        list.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.byte_count));
+        list
+    }
+
+    pub fn file_count(&self) -> u64 {
+        FileCategory::ALL
+            .iter()
+            .map(|category| self.get(*category).file_count)
+            .sum()
+    }
+
+    pub fn byte_count(&self) -> u64 {
+        FileCategory::ALL
+            .iter()
+            .map(|category| self.get(*category).byte_count)
+            .sum()
+    }
+}
+
+impl AddAssign for TypeBreakdown {
+    fn add_assign(&mut self, other: TypeBreakdown) {
+        for category in FileCategory::ALL.iter() {
+            *self.get_mut(*category) += other.get(*category);
+        }
+    }
+}
+
+impl Add for TypeBreakdown {
+    type Output = TypeBreakdown;
+
+    fn add(mut self, other: TypeBreakdown) -> TypeBreakdown {
+        self += other;
+        self
+    }
+}
+
+#[cfg(test)]
+mod file_types_tests {
+    use super::*;
+
+    #[test]
+    fn extension_lists_are_sorted() {
+        // binary search depends on it
+        for extensions in [
+            CODE_EXTENSIONS,
+            IMAGE_EXTENSIONS,
+            VIDEO_EXTENSIONS,
+            ARCHIVE_EXTENSIONS,
+        ] {
+            assert!(extensions.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+
+    #[test]
+    fn files_are_categorised_by_extension() {
+        assert_eq!(FileCategory::of("src/main.rs"), FileCategory::Code);
+        assert_eq!(FileCategory::of("/a/IMG_0001.JPG"), FileCategory::Images);
+        assert_eq!(FileCategory::of("holiday.mkv"), FileCategory::Video);
+        assert_eq!(FileCategory::of("release.tar.gz"), FileCategory::Archives);
+        assert_eq!(FileCategory::of("README"), FileCategory::Other);
+        assert_eq!(FileCategory::of(".bashrc"), FileCategory::Other);
+
+        let mut breakdown = TypeBreakdown::for_file("a.rs", 10);
+        breakdown += TypeBreakdown::for_file("b.png", 100);
+        breakdown += TypeBreakdown::for_file("c.rs", 5);
+        assert_eq!(
+            breakdown.code,
+            CategoryStats {
+                file_count: 2,
+                byte_count: 15
+            }
+        );
+        assert_eq!(breakdown.by_size()[0].0, FileCategory::Images);
+        assert_eq!((breakdown.file_count(), breakdown.byte_count()), (3, 115));
+    }
+}
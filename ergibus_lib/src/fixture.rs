@@ -0,0 +1,328 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Build temporary directory trees from a declarative description for use
+//! in tests and check that trees extracted from snapshots match them.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use dychatat_lib::content;
+use tempdir::TempDir;
+
+use crate::archive;
+use crate::snapshot::{self, Order, SnapshotPersistentData};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum FixtureEntry {
+    Dir,
+    File(Vec<u8>),
+    SymLink(PathBuf),
+    // Never backed up so not expected in extracted trees
+    Fifo,
+}
+
+/// A description of a directory tree (relative to its root).
+#[derive(Debug, Default)]
+pub(crate) struct FixtureSpec {
+    entries: BTreeMap<PathBuf, FixtureEntry>,
+    modes: BTreeMap<PathBuf, u32>,
+}
+
+impl FixtureSpec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn dir<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.entries
+            .insert(path.as_ref().to_path_buf(), FixtureEntry::Dir);
+        self
+    }
+
+    pub fn file<P: AsRef<Path>, C: AsRef<[u8]>>(mut self, path: P, contents: C) -> Self {
+        self.entries.insert(
+            path.as_ref().to_path_buf(),
+            FixtureEntry::File(contents.as_ref().to_vec()),
+        );
+        self
+    }
+
+    /// A file of the given size with (deterministic) non repeating contents.
+    pub fn file_of_size<P: AsRef<Path>>(self, path: P, size: usize) -> Self {
+        let seed = path.as_ref().as_os_str().len();
+        let contents: Vec<u8> = (0..size).map(|i| ((i * 31 + seed) % 251) as u8).collect();
+        self.file(path, contents)
+    }
+
+    pub fn symlink<P: AsRef<Path>, T: AsRef<Path>>(mut self, path: P, target: T) -> Self {
+        self.entries.insert(
+            path.as_ref().to_path_buf(),
+            FixtureEntry::SymLink(target.as_ref().to_path_buf()),
+        );
+        self
+    }
+
+    pub fn fifo<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.entries
+            .insert(path.as_ref().to_path_buf(), FixtureEntry::Fifo);
+        self
+    }
+
+    /// Set the permissions of a previously described file or directory.
+    pub fn mode<P: AsRef<Path>>(mut self, path: P, mode: u32) -> Self {
+        self.modes.insert(path.as_ref().to_path_buf(), mode);
+        self
+    }
+
+    /// Create the tree in the directory (which must exist).
+    pub fn create_in(&self, root: &Path) {
+        for (path, entry) in self.entries.iter() {
+            let path = root.join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).unwrap();
+            }
+            match entry {
+                FixtureEntry::Dir => fs::create_dir_all(&path).unwrap(),
+                FixtureEntry::File(contents) => fs::write(&path, contents).unwrap(),
+                FixtureEntry::SymLink(target) => symlink(target, &path).unwrap(),
+                FixtureEntry::Fifo => {
+                    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+                    assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+                }
+            }
+        }
+        // deepest first so that restrictive directory modes don't get in the way
+        for (path, mode) in self.modes.iter().rev() {
+            fs::set_permissions(root.join(path), fs::Permissions::from_mode(*mode)).unwrap();
+        }
+    }
+
+    /// Create the tree in a new temporary directory.
+    pub fn build(self) -> Fixture {
+        let dir = TempDir::new("FIXTURE").unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        self.create_in(&root);
+        Fixture {
+            spec: self,
+            root,
+            _dir: dir,
+        }
+    }
+}
+
+/// A directory tree built from a `FixtureSpec` that is removed when dropped.
+#[derive(Debug)]
+pub(crate) struct Fixture {
+    spec: FixtureSpec,
+    root: PathBuf,
+    _dir: TempDir,
+}
+
+impl Fixture {
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Check that the tree at `root` contains what would be expected if
+    /// this fixture had been backed up and restored there.  Named pipes
+    /// are not expected as they're never backed up.
+    pub fn assert_restored_to(&self, root: &Path) {
+        let expected: BTreeMap<PathBuf, FixtureEntry> = self
+            .spec
+            .entries
+            .iter()
+            .filter(|(_, entry)| **entry != FixtureEntry::Fifo)
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect();
+        let mut found = BTreeMap::new();
+        for entry in walkdir::WalkDir::new(root).min_depth(1) {
+            let entry = entry.unwrap();
+            let rel_path = entry.path().strip_prefix(root).unwrap().to_path_buf();
+            let file_type = entry.file_type();
+            let fixture_entry = if file_type.is_symlink() {
+                FixtureEntry::SymLink(fs::read_link(entry.path()).unwrap())
+            } else if file_type.is_dir() {
+                FixtureEntry::Dir
+            } else if file_type.is_file() {
+                FixtureEntry::File(fs::read(entry.path()).unwrap())
+            } else {
+                FixtureEntry::Fifo
+            };
+            found.insert(rel_path, fixture_entry);
+        }
+        // directories implied by the paths of other entries
+        let implied: Vec<PathBuf> = expected
+            .keys()
+            .flat_map(|path| path.ancestors().skip(1).map(|p| p.to_path_buf()))
+            .filter(|path| !path.as_os_str().is_empty())
+            .collect();
+        let mut expected = expected;
+        for path in implied {
+            expected.entry(path).or_insert(FixtureEntry::Dir);
+        }
+        assert_eq!(found, expected);
+        for (path, mode) in self.spec.modes.iter() {
+            if self.spec.entries.get(path) == Some(&FixtureEntry::Fifo) {
+                continue;
+            }
+            let metadata = fs::symlink_metadata(root.join(path)).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o7777, *mode, "{:?}", path);
+        }
+    }
+}
+
+const CONFIG_ENVARS: [&str; 2] = ["ERGIBUS_CONFIG_DIR", "DYCHATAT_CONFIG_DIR"];
+
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Point the ergibus and dychatat configuration at a new temporary
+/// directory for the lifetime of the guard.  Tests run in parallel threads
+/// and the environment is shared so guards are held one at a time and the
+/// original settings are restored when the guard is dropped.
+pub(crate) struct TestConfigGuard {
+    saved: Vec<(&'static str, Option<OsString>)>,
+    dir: TempDir,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl TestConfigGuard {
+    pub fn new() -> Self {
+        // a panicking test doesn't leave the environment in a bad state
+        let lock = CONFIG_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let dir = TempDir::new("TEST_CONFIG").unwrap();
+        let saved = CONFIG_ENVARS
+            .iter()
+            .map(|envar| (*envar, env::var_os(envar)))
+            .collect();
+        for envar in CONFIG_ENVARS.iter() {
+            env::set_var(envar, dir.path().join("config"));
+        }
+        Self {
+            saved,
+            dir,
+            _lock: lock,
+        }
+    }
+
+    /// A temporary directory (outside the configuration directory) for
+    /// the test's data.
+    pub fn data_dir(&self) -> PathBuf {
+        self.dir.path().join("data")
+    }
+}
+
+impl Drop for TestConfigGuard {
+    fn drop(&mut self) {
+        for (envar, value) in self.saved.iter() {
+            match value {
+                Some(value) => env::set_var(envar, value),
+                None => env::remove_var(envar),
+            }
+        }
+    }
+}
+
+/// A fixture backed up by an archive (stored in a repository named
+/// "test_repo") within a test configuration of its own.
+pub(crate) struct ArchivedFixture {
+    archive_name: String,
+    fixture: Fixture,
+    _guard: TestConfigGuard,
+}
+
+impl ArchivedFixture {
+    pub fn new(archive_name: &str, spec: FixtureSpec) -> Self {
+        let guard = TestConfigGuard::new();
+        let fixture = spec.build();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        archive::create_new_archive(
+            archive_name,
+            Some("test_repo"),
+            &location,
+            &[fixture.root().to_path_buf()],
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        Self {
+            archive_name: archive_name.to_string(),
+            fixture,
+            _guard: guard,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        self.fixture.root()
+    }
+
+    /// Take a snapshot of the fixture and return its file's path.
+    pub fn back_up(&self) -> PathBuf {
+        snapshot::generate_snapshot(&self.archive_name).unwrap();
+        self.latest_snapshot_path()
+    }
+
+    pub fn latest_snapshot_path(&self) -> PathBuf {
+        snapshot::get_snapshot_paths_for_archive(&self.archive_name, Order::Descending).unwrap()[0]
+            .clone()
+    }
+
+    pub fn latest_snapshot(&self) -> SnapshotPersistentData {
+        SnapshotPersistentData::from_file(self.latest_snapshot_path()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod fixture_tests {
+    use super::*;
+
+    #[test]
+    fn fixtures_match_themselves() {
+        let fixture = FixtureSpec::new()
+            .file("a/b/c", "contents")
+            .file_of_size("a/big", 100_000)
+            .dir("empty")
+            .symlink("a/link", "b/c")
+            .fifo("pipe")
+            .mode("a/b/c", 0o600)
+            .build();
+        assert_eq!(
+            fs::read(fixture.root().join("a/link")).unwrap(),
+            b"contents"
+        );
+        let copy = TempDir::new("FIXTURE_COPY").unwrap();
+        FixtureSpec::new()
+            .file("a/b/c", "contents")
+            .file_of_size("a/big", 100_000)
+            .dir("empty")
+            .symlink("a/link", "b/c")
+            .mode("a/b/c", 0o600)
+            .create_in(copy.path());
+        fixture.assert_restored_to(copy.path());
+    }
+
+    #[test]
+    fn config_guard_restores_environment() {
+        let saved = env::var_os("ERGIBUS_CONFIG_DIR");
+        {
+            let guard = TestConfigGuard::new();
+            assert_eq!(
+                crate::config::get_archive_config_dir_path(),
+                guard.dir.path().join("config").join("archives")
+            );
+            assert!(!guard
+                .data_dir()
+                .starts_with(guard.dir.path().join("config")));
+        }
+        assert_eq!(env::var_os("ERGIBUS_CONFIG_DIR"), saved);
+    }
+}
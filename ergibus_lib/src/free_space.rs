@@ -0,0 +1,56 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Keeping back ups from filling the file systems holding the content
+//! repositories.  Files whose (new) contents would eat into a reserve of
+//! free space are either skipped with a warning or abort the back up
+//! (before anything is written) as set in the global configuration or
+//! overridden by the back up's options.  Extractions are checked (before
+//! anything is written) for room on the target's file system unless
+//! forced.
+
+use std::path::Path;
+
+pub use crate::global_config::{FreeSpaceReserve, LowSpaceAction};
+use crate::{EResult, Error};
+
+/// Check that there's room for `bytes` more on the file system where
+/// `target_path` is (or will be) before extracting to it.
+pub(crate) fn check_extraction_space(target_path: &Path, bytes: u64) -> EResult<()> {
+    if bytes == 0 {
+        return Ok(());
+    }
+    let existing_path = match target_path.ancestors().find(|path| path.exists()) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let available = fs2::available_space(existing_path)?;
+    if bytes > available {
+        Err(Error::ExtractInsufficientSpace(
+            target_path.to_path_buf(),
+            bytes,
+            available,
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod free_space_tests {
+    use super::*;
+
+    #[test]
+    fn extractions_need_room() {
+        let dir = tempdir::TempDir::new("FREE_SPACE_TEST").unwrap();
+        let target_path = dir.path().join("not").join("yet");
+        assert!(check_extraction_space(&target_path, 1).is_ok());
+        match check_extraction_space(&target_path, u64::MAX) {
+            Err(Error::ExtractInsufficientSpace(path, bytes, available)) => {
+                assert_eq!(path, target_path);
+                assert_eq!(bytes, u64::MAX);
+                assert_eq!(available, fs2::available_space(dir.path()).unwrap());
+            }
+            result => panic!("unexpected: {:?}", result),
+        }
+    }
+}
@@ -2,24 +2,35 @@
 
 use crate::archive::Exclusions;
 use crate::attributes::{Attributes, AttributesIfce};
+use crate::content_keys::ContentKeys;
+use crate::file_types::TypeBreakdown;
+use crate::link_rewriting;
+use crate::move_aside::move_aside_path;
+use crate::owner_map::{OwnerMap, OwnerMapping};
 use crate::path_buf_ext::RealPathBufType;
-use crate::report::ignore_report_or_fail;
+use crate::progress::{self, ProgressEvent};
+use crate::read_policy::{self, ReadPolicy, TimedReader};
+use crate::report::{self, Severity};
 use crate::{EResult, Error, UNEXPECTED};
-use chrono::{DateTime, Local};
-use dychatat_lib::content::{ContentManager, ContentMgmtKey};
+use dychatat_lib::content::{CacheStats, ContentManagerOptions, ContentStore};
+use dychatat_lib::RepoError;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs::{self, File};
 use std::io::ErrorKind;
 use std::ops::{AddAssign, Index};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Component, Path, PathBuf};
-use std::time;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 pub trait Name {
     fn name(&self) -> &OsStr;
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, PartialEq)]
 pub struct FileData {
     file_name: OsString,
     attributes: Attributes,
@@ -33,20 +44,61 @@ impl Name for FileData {
 }
 
 impl FileData {
+    pub fn content_token(&self) -> &str {
+        &self.content_token
+    }
+
+    pub fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
     pub fn file_system_object<P: AsRef<Path>>(
         path_arg: P,
-        content_manager: &ContentManager,
+        content_manager: &dyn ContentStore,
+        read_policy: &ReadPolicy,
     ) -> EResult<(FileSystemObject, FileStats, u64)> {
         let path = path_arg.as_ref();
-        let attributes: Attributes = path.metadata()?.into();
-        let mut file = File::open(path)?;
-        let (content_token, stored_size, delta_repo_size) =
-            content_manager.store_contents(&mut file)?;
-        let file_stats = FileStats {
+        let metadata = path.metadata()?;
+        if !metadata.is_file() {
+            return Err(Error::FSOSpecialFile(
+                path.to_path_buf(),
+                read_policy::special_file_kind(&metadata.file_type()).to_string(),
+            ));
+        }
+        let mut attributes: Attributes = metadata.into();
+        attributes.fetch_special_attributes(path);
+        let mut file = read_policy::open_regular_file(path)?;
+        let result = match read_policy.timeout {
+            Some(timeout) => content_manager.store_contents(&mut TimedReader::new(file, timeout)?),
+            None => content_manager.store_contents(&mut file),
+        };
+        let (content_token, stored_size, delta_repo_size) = result.map_err(|err| match err {
+            RepoError::IOError(io_err) if io_err.kind() == ErrorKind::TimedOut => {
+                Error::FSOReadTimeout(path.to_path_buf())
+            }
+            RepoError::InsufficientSpace(_, size, available) => {
+                Error::FSOInsufficientSpace(path.to_path_buf(), size, available)
+            }
+            _ => err.into(),
+        })?;
+        progress::notify(|| ProgressEvent::FileStored {
+            path: path.to_path_buf(),
+            bytes: attributes.size(),
+        });
+        let mut file_stats = FileStats {
             file_count: 1,
             byte_count: attributes.size(),
             stored_byte_count: stored_size,
+            type_breakdown: TypeBreakdown::for_file(path, attributes.size()),
+            ..FileStats::default()
         };
+        if delta_repo_size > 0 {
+            file_stats.new_byte_count = attributes.size();
+        } else if content_manager.is_new_token(&content_token) {
+            file_stats.snapshot_dedup_byte_count = attributes.size();
+        } else {
+            file_stats.repo_dedup_byte_count = attributes.size();
+        }
         let file_name = path_arg
             .as_ref()
             .file_name()
@@ -68,7 +120,20 @@ impl FileData {
     pub fn copy_contents_to(
         &self,
         to_file_path: &Path,
-        c_mgr: &ContentManager,
+        c_mgr: &dyn ContentStore,
+        overwrite: bool,
+    ) -> EResult<u64> {
+        self.copy_contents_owned_to(to_file_path, c_mgr, None, overwrite)
+    }
+
+    // Copy the contents giving the new file its (translated) owner if
+    // `owner_mapping` is given.  NB: the owner has to be set before the
+    // special attributes as changing it clears capabilities.
+    fn copy_contents_owned_to(
+        &self,
+        to_file_path: &Path,
+        c_mgr: &dyn ContentStore,
+        owner_mapping: Option<&OwnerMapping>,
         overwrite: bool,
     ) -> EResult<u64> {
         if to_file_path.exists() {
@@ -82,7 +147,7 @@ impl FileData {
                 }
             }
             if !overwrite {
-                let new_path = move_aside_file_path(to_file_path);
+                let new_path = move_aside_path(to_file_path);
                 fs::rename(to_file_path, &new_path).map_err(|err| {
                     Error::SnapshotMoveAsideFailed(to_file_path.to_path_buf(), err)
                 })?;
@@ -90,14 +155,43 @@ impl FileData {
         }
         let mut file = File::create(to_file_path).unwrap();
         let bytes = c_mgr.write_contents_for_token(&self.content_token, &mut file)?;
+        if let Some(owner_mapping) = owner_mapping {
+            self.attributes
+                .chown_file(to_file_path, owner_mapping)
+                .map_err(Error::ContentCopyIOError)?;
+        }
+        self.attributes
+            .set_special_attributes(to_file_path)
+            .map_err(Error::ContentCopyIOError)?;
         Ok(bytes)
     }
+
+    /// The first `n` bytes of the file's contents (for previews).
+    pub fn read_prefix(&self, n: usize, c_mgr: &dyn ContentStore) -> EResult<Vec<u8>> {
+        Ok(c_mgr.read_contents_prefix(&self.content_token, n)?)
+    }
+}
+
+/// Whether the bytes (e.g. from `FileData::read_prefix()`) look like text
+/// i.e. they're UTF-8 without NULs.  A character cut off at the end is
+/// allowed.
+pub fn looks_like_text(bytes: &[u8]) -> bool {
+    if bytes.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        // error_len() is None if the bytes end part way through a character
+        Err(err) => err.error_len().is_none(),
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, PartialEq)]
 pub struct SymLinkData {
     file_name: OsString,
     attributes: Attributes,
+    #[serde(with = "os_path")]
+    #[schemars(with = "os_path::StoredPath")]
     link_target: PathBuf,
 }
 
@@ -159,24 +253,38 @@ impl SymLinkData {
 
 impl SymLinkData {
     // Interrogation/extraction/restoration methods
+    pub fn link_target(&self) -> &Path {
+        &self.link_target
+    }
+
     pub fn copy_link_as(&self, as_path: &Path, overwrite: bool) -> EResult<()> {
+        self.copy_link_with_target_as(&self.link_target, as_path, overwrite)
+    }
+
+    // Make the link with `link_target` in place of the recorded target
+    fn copy_link_with_target_as(
+        &self,
+        link_target: &Path,
+        as_path: &Path,
+        overwrite: bool,
+    ) -> EResult<()> {
         if as_path.exists() {
             if as_path.is_symlink() {
-                if let Ok(link_target) = as_path.read_link() {
-                    if self.link_target == link_target {
+                if let Ok(existing_target) = as_path.read_link() {
+                    if link_target == existing_target {
                         return Ok(());
                     }
                 }
             }
             if !overwrite {
-                let new_path = move_aside_file_path(as_path);
+                let new_path = move_aside_path(as_path);
                 fs::rename(as_path, &new_path)
                     .map_err(|err| Error::SnapshotMoveAsideFailed(as_path.to_path_buf(), err))?;
             }
         }
         if cfg!(target_family = "unix") {
             use std::os::unix::fs::symlink;
-            symlink(&self.link_target, as_path)
+            symlink(link_target, as_path)
                 .map_err(|err| Error::SnapshotMoveAsideFailed(as_path.to_path_buf(), err))?;
         } else {
             panic!("not implemented for this os")
@@ -185,18 +293,151 @@ impl SymLinkData {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+/// Serialization of paths that may not be valid UTF-8 (which is legal on
+/// Linux but which serde refuses for `Path`).  Paths that are valid UTF-8
+/// are written as strings (as they always have been) and any others as
+/// `OsString`s; either is accepted when reading.
+pub(crate) mod os_path {
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::ffi::OsString;
+    use std::path::{Path, PathBuf};
+
+    /// A path as a string or (if it isn't valid UTF-8) as an `OsString`
+    #[derive(Deserialize, JsonSchema)]
+    #[serde(untagged)]
+    pub enum StoredPath {
+        Text(String),
+        Bytes(OsString),
+    }
+
+    impl From<StoredPath> for PathBuf {
+        fn from(stored: StoredPath) -> Self {
+            match stored {
+                StoredPath::Text(text) => PathBuf::from(text),
+                StoredPath::Bytes(bytes) => PathBuf::from(bytes),
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(text) => text.serialize(serializer),
+            None => path.as_os_str().serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(StoredPath::deserialize(deserializer)?.into())
+    }
+
+    pub fn serialize_vec<S: Serializer>(
+        paths: &[PathBuf],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+        let mut seq = serializer.serialize_seq(Some(paths.len()))?;
+        for path in paths.iter() {
+            match path.to_str() {
+                Some(text) => seq.serialize_element(text)?,
+                None => seq.serialize_element(path.as_os_str())?,
+            }
+        }
+        seq.end()
+    }
+
+    pub fn deserialize_vec<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<PathBuf>, D::Error> {
+        let stored = Vec::<StoredPath>::deserialize(deserializer)?;
+        Ok(stored.into_iter().map(PathBuf::from).collect())
+    }
+
+    pub fn deserialize_option<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<PathBuf>, D::Error> {
+        Ok(Option::<StoredPath>::deserialize(deserializer)?.map(PathBuf::from))
+    }
+}
+
+// Only the root directory's full path is written to snapshot files (the
+// others are written as names and their paths are rebuilt when read).
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, PartialEq)]
+#[serde(from = "StoredDirectoryData")]
 pub struct DirectoryData {
+    /// The full path for the root directory (the name for others)
+    #[serde(rename = "name", serialize_with = "serialize_dir_name")]
+    #[schemars(with = "os_path::StoredPath")]
     pub(crate) path: PathBuf,
     attributes: Attributes,
     pub(crate) contents: Vec<FileSystemObject>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Copy, Clone)]
+fn serialize_dir_name<S: serde::Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    match path.file_name() {
+        Some(name) => os_path::serialize(Path::new(name), serializer),
+        None => os_path::serialize(path, serializer),
+    }
+}
+
+// Snapshots written by older versions have a full path for every directory.
+#[derive(Deserialize)]
+struct StoredDirectoryData {
+    #[serde(default, deserialize_with = "os_path::deserialize_option")]
+    name: Option<PathBuf>,
+    #[serde(default, deserialize_with = "os_path::deserialize_option")]
+    path: Option<PathBuf>,
+    attributes: Attributes,
+    contents: Vec<FileSystemObject>,
+}
+
+impl From<StoredDirectoryData> for DirectoryData {
+    fn from(stored: StoredDirectoryData) -> Self {
+        Self {
+            path: stored.path.or(stored.name).unwrap_or_default(),
+            attributes: stored.attributes,
+            contents: stored.contents,
+        }
+    }
+}
+
+/// Read a directory tree written to a snapshot file (in either the
+/// current or the older full path format) and rebuild its paths.
+pub(crate) fn deserialize_dir_tree<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<DirectoryData, D::Error> {
+    let mut dir_data = DirectoryData::deserialize(deserializer)?;
+    dir_data.rebuild_subdir_paths();
+    Ok(dir_data)
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Default, Copy, Clone)]
 pub struct FileStats {
     pub file_count: u64,
     pub byte_count: u64,
     pub stored_byte_count: u64,
+    /// Bytes whose contents weren't already in the repository
+    #[serde(default)]
+    pub new_byte_count: u64,
+    /// Bytes whose contents were already in the repository
+    #[serde(default)]
+    pub repo_dedup_byte_count: u64,
+    /// Bytes whose contents duplicate those of another file in the same snapshot
+    #[serde(default)]
+    pub snapshot_dedup_byte_count: u64,
+    /// Directories whose contents weren't read because they're at the
+    /// archive's maximum depth
+    #[serde(default)]
+    pub depth_skipped_dir_count: u64,
+    /// The files by category (see `file_types`)
+    #[serde(default)]
+    pub type_breakdown: TypeBreakdown,
+    /// Files (and their bytes) skipped because their contents wouldn't
+    /// fit in the repository without using the reserved free space
+    #[serde(default)]
+    pub space_skipped_file_count: u64,
+    #[serde(default)]
+    pub space_skipped_byte_count: u64,
 }
 
 impl AddAssign for FileStats {
@@ -205,11 +446,37 @@ impl AddAssign for FileStats {
             file_count: self.file_count + other.file_count,
             byte_count: self.byte_count + other.byte_count,
             stored_byte_count: self.stored_byte_count + other.stored_byte_count,
+            new_byte_count: self.new_byte_count + other.new_byte_count,
+            repo_dedup_byte_count: self.repo_dedup_byte_count + other.repo_dedup_byte_count,
+            snapshot_dedup_byte_count: self.snapshot_dedup_byte_count
+                + other.snapshot_dedup_byte_count,
+            depth_skipped_dir_count: self.depth_skipped_dir_count + other.depth_skipped_dir_count,
+            type_breakdown: self.type_breakdown + other.type_breakdown,
+            space_skipped_file_count: self.space_skipped_file_count
+                + other.space_skipped_file_count,
+            space_skipped_byte_count: self.space_skipped_byte_count
+                + other.space_skipped_byte_count,
         };
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Copy, Clone)]
+/// How the files in a snapshot use some stored contents.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default, Copy, Clone)]
+pub struct ContentTokenUsage {
+    /// The number of files with the contents
+    pub references: u64,
+    /// The size of the contents
+    pub size: u64,
+}
+
+impl ContentTokenUsage {
+    /// The total size of the files with the contents.
+    pub fn total_bytes(&self) -> u64 {
+        self.references * self.size
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Default, Copy, Clone)]
 pub struct SymLinkStats {
     pub dir_sym_link_count: u64,
     pub file_sym_link_count: u64,
@@ -229,6 +496,7 @@ impl DirectoryData {
         let mut dir_data = Self::default();
         dir_data.path = root_dir.as_ref().canonicalize()?;
         dir_data.attributes = dir_data.path.metadata()?.into();
+        dir_data.attributes.fetch_special_attributes(&dir_data.path);
 
         Ok(dir_data)
     }
@@ -242,6 +510,19 @@ impl DirectoryData {
         self.contents.binary_search_by_key(&name, |o| o.name())
     }
 
+    // Make the subdirectories' paths (which may be just their names) full paths.
+    fn rebuild_subdir_paths(&mut self) {
+        let path = &self.path;
+        for fso in self.contents.iter_mut() {
+            if let FileSystemObject::Directory(subdir) = fso {
+                if !subdir.path.is_absolute() {
+                    subdir.path = path.join(&subdir.path);
+                }
+                subdir.rebuild_subdir_paths();
+            }
+        }
+    }
+
     pub fn files(&self) -> impl Iterator<Item = &FileData> {
         self.contents.iter().filter_map(|o| o.get_file_data())
     }
@@ -250,14 +531,275 @@ impl DirectoryData {
         self.contents.iter().filter_map(|o| o.get_dir_data())
     }
 
-    pub fn release_contents(&self, content_mgr: &ContentManager) -> EResult<()> {
+    /// A (Merkle style) hash of the names, file contents (via their tokens),
+    /// attributes and link targets of this directory's contents.  The
+    /// directory's own attributes and access times (which change whenever
+    /// a file is read) are ignored.
+    pub fn tree_hash(&self) -> String {
+        let mut data: Vec<u8> = vec![];
+        let push_attributes = |data: &mut Vec<u8>, attributes: &Attributes| {
+            data.extend_from_slice(&attributes.tree_hash_data());
+            data.push(0);
+        };
+        for fso in self.contents.iter() {
+            match fso {
+                FileSystemObject::Directory(dir_data) => {
+                    data.push(b'D');
+                    data.extend_from_slice(fso.name().as_bytes());
+                    data.push(0);
+                    push_attributes(&mut data, &dir_data.attributes);
+                    data.extend_from_slice(dir_data.tree_hash().as_bytes());
+                }
+                FileSystemObject::File(file_data) => {
+                    data.push(b'F');
+                    data.extend_from_slice(fso.name().as_bytes());
+                    data.push(0);
+                    push_attributes(&mut data, &file_data.attributes);
+                    data.extend_from_slice(file_data.content_token.as_bytes());
+                }
+                FileSystemObject::SymLink(link_data, _) => {
+                    data.push(b'L');
+                    data.extend_from_slice(fso.name().as_bytes());
+                    data.push(0);
+                    push_attributes(&mut data, &link_data.attributes);
+                    data.extend_from_slice(link_data.link_target.as_os_str().as_bytes());
+                }
+            }
+            data.push(0);
+        }
+        crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, &data)
+    }
+
+    /// Add the uids and gids of the owners of this directory tree's
+    /// directories and files to those given.
+    pub(crate) fn collect_owners(&self, uids: &mut BTreeSet<u32>, gids: &mut BTreeSet<u32>) {
+        let mut add = |attributes: &Attributes| {
+            let (uid, gid) = attributes.owner();
+            uids.insert(uid);
+            gids.insert(gid);
+        };
+        add(&self.attributes);
         for file_data in self.files() {
-            content_mgr.release_contents(&file_data.content_token)?;
+            add(&file_data.attributes);
         }
         for subdir in self.subdirs() {
-            subdir.release_contents(content_mgr)?;
+            subdir.collect_owners(uids, gids);
+        }
+    }
+
+    /// Release the stored contents of every file in this directory tree
+    /// (as a single batch so that either all or none are released)
+    /// returning the stored size of those no longer referenced.
+    pub fn release_contents(&self, content_mgr: &dyn ContentStore) -> EResult<u64> {
+        let mut content_tokens = vec![];
+        self.collect_content_tokens(&mut content_tokens);
+        let unreferenced_size = content_mgr
+            .release_contents_batch(&content_tokens)?
+            .iter()
+            .filter(|rcd| !rcd.is_referenced())
+            .map(|rcd| rcd.stored_size())
+            .sum();
+        Ok(unreferenced_size)
+    }
+
+    /// The total size of the files in this directory tree.
+    pub fn total_file_bytes(&self) -> u64 {
+        self.files()
+            .map(|file_data| file_data.attributes.size())
+            .sum::<u64>()
+            + self
+                .subdirs()
+                .map(|subdir| subdir.total_file_bytes())
+                .sum::<u64>()
+    }
+
+    pub(crate) fn collect_content_tokens<'a>(&'a self, content_tokens: &mut Vec<&'a str>) {
+        for file_data in self.files() {
+            content_tokens.push(&file_data.content_token);
+        }
+        for subdir in self.subdirs() {
+            subdir.collect_content_tokens(content_tokens);
+        }
+    }
+
+    pub(crate) fn collect_content_token_usage<'a>(
+        &'a self,
+        usage: &mut BTreeMap<&'a str, ContentTokenUsage>,
+    ) {
+        for file_data in self.files() {
+            let token_usage = usage.entry(&file_data.content_token).or_default();
+            token_usage.references += 1;
+            token_usage.size = file_data.attributes.size();
+        }
+        for subdir in self.subdirs() {
+            subdir.collect_content_token_usage(usage);
+        }
+    }
+
+    /// The files in this directory tree by category.
+    pub fn type_breakdown(&self) -> TypeBreakdown {
+        let mut type_breakdown = TypeBreakdown::default();
+        for file_data in self.files() {
+            type_breakdown +=
+                TypeBreakdown::for_file(&file_data.file_name, file_data.attributes.size());
+        }
+        for subdir in self.subdirs() {
+            type_breakdown += subdir.type_breakdown();
+        }
+        type_breakdown
+    }
+
+    /// The newest modification time (seconds and nanoseconds since the
+    /// epoch) of anything in this directory tree (including itself).
+    pub fn newest_mtime(&self) -> (i64, i64) {
+        let mut newest = (self.attributes.mtime(), self.attributes.mtime_nsec());
+        for fso in self.contents.iter() {
+            let mtime = match fso {
+                FileSystemObject::Directory(subdir) => subdir.newest_mtime(),
+                _ => (fso.attributes().mtime(), fso.attributes().mtime_nsec()),
+            };
+            newest = newest.max(mtime);
+        }
+        newest
+    }
+
+    /// Move this directory tree (e.g. read from a copy made by another
+    /// back up tool) to `new_path` (and its subdirectories with it).
+    pub(crate) fn relocate(&mut self, new_path: &Path) {
+        self.path = new_path.to_path_buf();
+        for fso in self.contents.iter_mut() {
+            if let FileSystemObject::Directory(subdir) = fso {
+                let subdir_path = new_path.join(subdir.name());
+                subdir.relocate(&subdir_path);
+            }
+        }
+    }
+
+    /// Put `dir` (whose path must be within this directory's) into this
+    /// tree in place of anything already at its path.  Missing directories
+    /// between the two are read from the file system if they exist here
+    /// and are otherwise given `dir`'s attributes.
+    pub(crate) fn graft(&mut self, dir: DirectoryData) -> EResult<()> {
+        let rel_path = dir
+            .path
+            .strip_prefix(&self.path)
+            .map_err(|_| Error::FSOMalformedPath(dir.path.clone()))?
+            .to_path_buf();
+        let mut components = rel_path.components();
+        let first_name = match components.next() {
+            Some(Component::Normal(first_name)) => first_name.to_os_string(),
+            _ => return Err(Error::FSOMalformedPath(rel_path)),
+        };
+        let index = self.index_for(&first_name);
+        if components.next().is_none() {
+            match index {
+                Ok(index) => self.contents[index] = FileSystemObject::Directory(dir),
+                Err(index) => self
+                    .contents
+                    .insert(index, FileSystemObject::Directory(dir)),
+            }
+            return Ok(());
+        }
+        let subdir_path = self.path.join(&first_name);
+        let index = match index {
+            Ok(index) if self.contents[index].get_dir_data().is_some() => index,
+            index => {
+                let subdir = match DirectoryData::try_new(&subdir_path) {
+                    Ok(subdir) if subdir.path == subdir_path => subdir,
+                    _ => DirectoryData {
+                        path: subdir_path,
+                        attributes: dir.attributes.clone(),
+                        contents: vec![],
+                    },
+                };
+                match index {
+                    Ok(index) => {
+                        self.contents[index] = FileSystemObject::Directory(subdir);
+                        index
+                    }
+                    Err(index) => {
+                        self.contents
+                            .insert(index, FileSystemObject::Directory(subdir));
+                        index
+                    }
+                }
+            }
+        };
+        self.contents[index]
+            .get_dir_data_mut()
+            .expect(UNEXPECTED)
+            .graft(dir)
+    }
+
+    /// Add a reference to the stored contents of every file in this
+    /// directory tree (so that it can be reused in a new snapshot).  If
+    /// that fails the references already added are released.
+    pub fn claim_contents(
+        &self,
+        content_mgr: &dyn ContentStore,
+    ) -> EResult<(FileStats, SymLinkStats)> {
+        let mut claimed = vec![];
+        match self.claim_contents_noting(content_mgr, &mut claimed) {
+            Ok(stats) => Ok(stats),
+            Err(err) => {
+                content_mgr.release_contents_batch(&claimed)?;
+                Err(err)
+            }
+        }
+    }
+
+    fn claim_contents_noting<'a>(
+        &'a self,
+        content_mgr: &dyn ContentStore,
+        claimed: &mut Vec<&'a str>,
+    ) -> EResult<(FileStats, SymLinkStats)> {
+        let mut file_stats = FileStats::default();
+        let mut sym_link_stats = SymLinkStats {
+            dir_sym_link_count: self.dir_sym_links().count() as u64,
+            file_sym_link_count: self.file_sym_links().count() as u64,
+        };
+        for file_data in self.files() {
+            let stored_size = content_mgr.claim_contents(&file_data.content_token)?;
+            claimed.push(file_data.content_token.as_str());
+            file_stats += FileStats {
+                file_count: 1,
+                byte_count: file_data.attributes.size(),
+                stored_byte_count: stored_size,
+                repo_dedup_byte_count: file_data.attributes.size(),
+                type_breakdown: TypeBreakdown::for_file(
+                    &file_data.file_name,
+                    file_data.attributes.size(),
+                ),
+                ..FileStats::default()
+            };
+        }
+        for subdir in self.subdirs() {
+            let (sub_file_stats, sub_sym_link_stats) =
+                subdir.claim_contents_noting(content_mgr, claimed)?;
+            file_stats += sub_file_stats;
+            sym_link_stats += sub_sym_link_stats;
+        }
+        Ok((file_stats, sym_link_stats))
+    }
+
+    /// Remove the object at `abs_path` (if it's present) from this directory tree.
+    pub fn remove_object<P: AsRef<Path>>(&mut self, abs_path: P) -> Option<FileSystemObject> {
+        let abs_path = abs_path.as_ref();
+        let rel_path = abs_path.strip_prefix(&self.path).ok()?;
+        let mut components = rel_path.components();
+        match components.next() {
+            Some(Component::Normal(first_name)) => {
+                let index = self.index_for(first_name).ok()?;
+                if components.next().is_none() {
+                    Some(self.contents.remove(index))
+                } else {
+                    self.contents[index]
+                        .get_dir_data_mut()?
+                        .remove_object(abs_path)
+                }
+            }
+            _ => None,
         }
-        Ok(())
     }
 
     pub fn find_or_add_subdir<P>(&mut self, path_arg: P) -> EResult<&mut DirectoryData>
@@ -288,32 +830,58 @@ impl DirectoryData {
         }
     }
 
+    /// Add the directory's contents (that aren't excluded) reading no more
+    /// than `max_depth` (if any) levels of subdirectories as specified by
+    /// `read_policy`.  Directories at the maximum depth are added without
+    /// their contents.
     pub fn populate(
         &mut self,
         exclusions: &Exclusions,
-        content_mgr: &ContentManager,
+        content_mgr: &dyn ContentStore,
+        max_depth: Option<usize>,
+        read_policy: &ReadPolicy,
     ) -> EResult<(FileStats, SymLinkStats, u64)> {
         let mut file_stats = FileStats::default();
         let mut sym_link_stats = SymLinkStats::default();
         let mut delta_repo_size: u64 = 0;
+        if max_depth == Some(0) {
+            let message = "contents skipped: at maximum depth".to_string();
+            report::emit(Severity::Info, &self.path, message);
+            file_stats.depth_skipped_dir_count = 1;
+            return Ok((file_stats, sym_link_stats, delta_repo_size));
+        }
+        let sub_max_depth = max_depth.map(|max_depth| max_depth - 1);
+        progress::notify_dir_entered(&self.path);
+        progress::check_cancelled()?;
         match fs::read_dir(&self.path) {
             Ok(read_dir) => {
-                // TODO: use size_hint() to reserve sufficient space in contents vector
-                for entry in read_dir.filter_map(|e| e.ok()) {
+                let mut entries: Vec<fs::DirEntry> = read_dir.filter_map(|e| e.ok()).collect();
+                read_policy::sort_entries(&mut entries, read_policy.storage_order);
+                self.contents.reserve(entries.len());
+                for entry in entries {
                     if exclusions.is_excluded(&entry)? {
                         continue;
                     }
                     let name = entry.file_name();
                     match self.index_for(&name) {
                         Ok(index) => match self.contents[index].get_dir_data_mut() {
-                            Some(dir_data) => match dir_data.populate(exclusions, content_mgr) {
-                                Ok(stats) => {
-                                    file_stats += stats.0;
-                                    sym_link_stats += stats.1;
-                                    delta_repo_size += stats.2;
+                            Some(dir_data) => {
+                                match dir_data.populate(
+                                    exclusions,
+                                    content_mgr,
+                                    sub_max_depth,
+                                    read_policy,
+                                ) {
+                                    Ok(stats) => {
+                                        file_stats += stats.0;
+                                        sym_link_stats += stats.1;
+                                        delta_repo_size += stats.2;
+                                    }
+                                    Err(err) => {
+                                        read_policy.ignore_report_or_fail(err, &self.path)?
+                                    }
                                 }
-                                Err(err) => ignore_report_or_fail(err, &self.path)?,
-                            },
+                            }
                             _ => (),
                         },
                         Err(index) => match entry.file_type() {
@@ -325,27 +893,43 @@ impl DirectoryData {
                                             match file_system_object
                                                 .get_dir_data_mut()
                                                 .expect(UNEXPECTED)
-                                                .populate(exclusions, content_mgr)
-                                            {
+                                                .populate(
+                                                    exclusions,
+                                                    content_mgr,
+                                                    sub_max_depth,
+                                                    read_policy,
+                                                ) {
                                                 Ok(stats) => {
                                                     file_stats += stats.0;
                                                     sym_link_stats += stats.1;
                                                     delta_repo_size += stats.2;
                                                     self.contents.insert(index, file_system_object);
                                                 }
-                                                Err(err) => ignore_report_or_fail(err, &path)?,
+                                                Err(err) => {
+                                                    read_policy.ignore_report_or_fail(err, &path)?
+                                                }
                                             }
                                         }
-                                        Err(err) => ignore_report_or_fail(err, &path)?,
+                                        Err(err) => {
+                                            read_policy.ignore_report_or_fail(err, &path)?
+                                        }
                                     }
                                 } else if e_type.is_file() {
-                                    match FileData::file_system_object(&path, content_mgr) {
+                                    match FileData::file_system_object(
+                                        &path,
+                                        content_mgr,
+                                        read_policy,
+                                    ) {
                                         Ok((file_system_object, stats, delta)) => {
                                             file_stats += stats;
                                             delta_repo_size += delta;
                                             self.contents.insert(index, file_system_object);
                                         }
-                                        Err(err) => ignore_report_or_fail(err, &path)?,
+                                        Err(err) => read_policy.skip_file_or_fail(
+                                            err,
+                                            &path,
+                                            &mut file_stats,
+                                        )?,
                                     }
                                 } else if e_type.is_symlink() {
                                     match SymLinkData::file_system_object(&path) {
@@ -353,16 +937,24 @@ impl DirectoryData {
                                             sym_link_stats += stats;
                                             self.contents.insert(index, file_system_object);
                                         }
-                                        Err(err) => ignore_report_or_fail(err, &path)?,
+                                        Err(err) => {
+                                            read_policy.ignore_report_or_fail(err, &path)?
+                                        }
                                     }
+                                } else {
+                                    let kind = read_policy::special_file_kind(&e_type);
+                                    let err = Error::FSOSpecialFile(path.clone(), kind.to_string());
+                                    read_policy.ignore_report_or_fail(err, &path)?
                                 }
                             }
-                            Err(err) => ignore_report_or_fail(err.into(), &entry.path())?,
+                            Err(err) => {
+                                read_policy.ignore_report_or_fail(err.into(), entry.path())?
+                            }
                         },
                     }
                 }
             }
-            Err(err) => ignore_report_or_fail(err.into(), &self.path)?,
+            Err(err) => read_policy.ignore_report_or_fail(err.into(), &self.path)?,
         };
         Ok((file_stats, sym_link_stats, delta_repo_size))
     }
@@ -407,22 +999,66 @@ impl<'a> Iterator for SubdirIter<'a> {
     }
 }
 
-#[derive(PartialEq, Debug, Default, Copy, Clone)]
+#[derive(PartialEq, Debug, Default, Clone)]
 pub struct ExtractionStats {
     pub dir_count: u64,
     pub file_count: u64,
     pub bytes_count: u64,
     pub dir_sym_link_count: u64,
     pub file_sym_link_count: u64,
+    pub cache_stats: CacheStats,
+    /// recorded creation (birth) times that couldn't be restored
+    pub birth_times_not_restored: u64,
+    /// links (as extracted) whose absolute targets were outside the
+    /// extracted tree (and their targets) when links were rewritten
+    pub external_links: Vec<(PathBuf, PathBuf)>,
 }
 
 impl AddAssign for ExtractionStats {
     fn add_assign(&mut self, rhs: Self) {
+        self.cache_stats += rhs.cache_stats;
         self.dir_count += rhs.dir_count;
         self.file_count += rhs.file_count;
         self.bytes_count += rhs.bytes_count;
         self.dir_sym_link_count += rhs.dir_sym_link_count;
         self.file_sym_link_count += rhs.file_sym_link_count;
+        self.birth_times_not_restored += rhs.birth_times_not_restored;
+        self.external_links.extend(rhs.external_links);
+    }
+}
+
+/// How directory trees are copied out of snapshots.
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    /// Overwrite existing files and directories (instead of moving them aside)
+    pub overwrite: bool,
+    /// Give the copied directories their original modification times
+    /// (instead of leaving them with the time of the copy)
+    pub preserve_dir_mtimes: bool,
+    /// Make the absolute targets of links within the copied tree relative
+    /// (so that they point to the copies)
+    pub rewrite_links: bool,
+    /// How the owners recorded in the snapshot are translated (see
+    /// `SnapshotPersistentData::owner_mapping()`)
+    pub owner_map: OwnerMap,
+    /// Don't check that there's room for the copy on the target's file
+    /// system before starting
+    pub force: bool,
+    /// The maximum number of bytes of decompressed content to keep in
+    /// memory for files with the same contents.  Zero disables caching.
+    pub read_cache_capacity: usize,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            overwrite: false,
+            preserve_dir_mtimes: true,
+            rewrite_links: false,
+            owner_map: OwnerMap::default(),
+            force: false,
+            read_cache_capacity: 0,
+        }
     }
 }
 
@@ -432,10 +1068,33 @@ impl DirectoryData {
         self.path.as_path()
     }
 
+    pub fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
     pub fn contents(&self) -> impl Iterator<Item = &FileSystemObject> {
         self.contents.iter()
     }
 
+    /// The number of objects directly in this directory.  They're sorted
+    /// by name and indices in `0..len()` are valid for `get()` and indexing.
+    pub fn len(&self) -> usize {
+        self.contents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contents.is_empty()
+    }
+
+    /// The objects directly in this directory (in index order).
+    pub fn iter(&self) -> std::slice::Iter<'_, FileSystemObject> {
+        self.contents.iter()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&FileSystemObject> {
+        self.contents.get(index)
+    }
+
     pub fn dir_sym_links(&self) -> impl Iterator<Item = &SymLinkData> {
         self.contents
             .iter()
@@ -478,6 +1137,36 @@ impl DirectoryData {
         }
     }
 
+    /// Full paths of all objects in this directory and its subdirectories
+    /// whose names satisfy the predicate.
+    pub fn find_matching_paths<F: Fn(&OsStr) -> bool>(&self, predicate: &F) -> Vec<PathBuf> {
+        let mut paths = vec![];
+        for fso in self.contents.iter() {
+            if predicate(fso.name()) {
+                paths.push(self.path.join(fso.name()));
+            }
+            if let Some(subdir) = fso.get_dir_data() {
+                paths.append(&mut subdir.find_matching_paths(predicate));
+            }
+        }
+        paths
+    }
+
+    /// A best guess at the inclusions that produced this directory's contents.
+    /// Directories that only contain directories are assumed to be ancestors of
+    /// inclusions and anything else is assumed to have been included in full.
+    pub fn probable_inclusions(&self) -> Vec<PathBuf> {
+        if self.contents.is_empty() {
+            vec![]
+        } else if self.contents.iter().all(|o| o.get_dir_data().is_some()) {
+            self.subdirs()
+                .flat_map(|s| s.probable_inclusions())
+                .collect()
+        } else {
+            vec![self.path.clone()]
+        }
+    }
+
     pub fn find_subdir<P: AsRef<Path>>(&self, path_arg: P) -> EResult<&Self> {
         let subdir_path = path_arg.as_ref();
         debug_assert!(subdir_path.is_absolute());
@@ -518,47 +1207,118 @@ impl DirectoryData {
     fn copy_files_into(
         &self,
         into_dir_path: &Path,
-        c_mgr: &ContentManager,
+        c_mgr: &dyn ContentStore,
+        owner_mapping: &OwnerMapping,
         overwrite: bool,
     ) -> EResult<(u64, u64)> {
         let mut count = 0;
         let mut bytes = 0;
         for file in self.files() {
             let new_path = into_dir_path.join(&file.file_name);
-            bytes += file.copy_contents_to(&new_path, c_mgr, overwrite)?;
+            let file_bytes =
+                file.copy_contents_owned_to(&new_path, c_mgr, Some(owner_mapping), overwrite)?;
+            progress::notify(|| ProgressEvent::FileExtracted {
+                path: new_path,
+                bytes: file_bytes,
+            });
+            bytes += file_bytes;
             count += 1;
         }
         Ok((count, bytes))
     }
 
-    fn copy_dir_links_into(&self, into_dir_path: &Path, overwrite: bool) -> EResult<u64> {
+    // The number of the recorded birth times of this directory's tree
+    // (as copied to `to_dir_path`) that couldn't be restored
+    fn restore_birth_times(&self, to_dir_path: &Path) -> u64 {
         let mut count = 0;
-        for subdir_link in self.dir_sym_links() {
-            let new_link_path = into_dir_path.join(&subdir_link.file_name);
-            subdir_link.copy_link_as(&new_link_path, overwrite)?;
-            count += 1;
+        for dir in std::iter::once(self).chain(self.subdir_iter(true)) {
+            let path_tail = dir.path.strip_prefix(&self.path).unwrap(); // Should not fail
+            let new_dir_path = to_dir_path.join(path_tail);
+            let attributes = std::iter::once((new_dir_path.clone(), &dir.attributes)).chain(
+                dir.files()
+                    .map(|file| (new_dir_path.join(&file.file_name), &file.attributes)),
+            );
+            for (path, attributes) in attributes {
+                if let Err(err) = attributes.set_birth_time(&path) {
+                    log::trace!("{:?}: creation time not restored: {}", path, err);
+                    count += 1;
+                }
+            }
         }
-        Ok(count)
+        count
     }
 
-    fn copy_file_links_into(&self, into_dir_path: &Path, overwrite: bool) -> EResult<u64> {
+    // `tree` is the roots of the tree being copied and of its copy
+    fn copy_links_into<'a>(
+        links: impl Iterator<Item = &'a SymLinkData>,
+        into_dir_path: &Path,
+        tree: (&Path, &Path),
+        options: &CopyOptions,
+        stats: &mut ExtractionStats,
+    ) -> EResult<u64> {
         let mut count = 0;
-        for file_link in self.file_sym_links() {
-            let new_link_path = into_dir_path.join(&file_link.file_name);
-            file_link.copy_link_as(&new_link_path, overwrite)?;
+        for link in links {
+            let new_link_path = into_dir_path.join(&link.file_name);
+            let rewritten_target = if options.rewrite_links {
+                link_rewriting::rewritten_target(
+                    &link.link_target,
+                    tree.0,
+                    tree.1,
+                    &new_link_path,
+                    &mut stats.external_links,
+                )
+            } else {
+                None
+            };
+            match rewritten_target {
+                Some(link_target) => {
+                    link.copy_link_with_target_as(&link_target, &new_link_path, options.overwrite)?
+                }
+                None => link.copy_link_as(&new_link_path, options.overwrite)?,
+            }
             count += 1;
         }
         Ok(count)
     }
 
+    fn copy_dir_links_into(
+        &self,
+        into_dir_path: &Path,
+        tree: (&Path, &Path),
+        options: &CopyOptions,
+        stats: &mut ExtractionStats,
+    ) -> EResult<()> {
+        stats.dir_sym_link_count +=
+            Self::copy_links_into(self.dir_sym_links(), into_dir_path, tree, options, stats)?;
+        Ok(())
+    }
+
+    fn copy_file_links_into(
+        &self,
+        into_dir_path: &Path,
+        tree: (&Path, &Path),
+        options: &CopyOptions,
+        stats: &mut ExtractionStats,
+    ) -> EResult<()> {
+        stats.file_sym_link_count +=
+            Self::copy_links_into(self.file_sym_links(), into_dir_path, tree, options, stats)?;
+        Ok(())
+    }
+
+    /// Copy this directory tree to `to_dir_path` (as specified by
+    /// `options`) with the owners of its files and directories translated
+    /// by `owner_mapping`.
     pub fn copy_to(
         &self,
         to_dir_path: &Path,
-        c_mgt_key: &ContentMgmtKey,
-        overwrite: bool,
+        content_keys: &ContentKeys,
+        owner_mapping: &OwnerMapping,
+        options: &CopyOptions,
     ) -> EResult<ExtractionStats> {
         // TODO: Add hard link retention to copying of directories
+        let overwrite = options.overwrite;
         let mut stats = ExtractionStats::default();
+        let tree = (self.path.as_path(), to_dir_path);
         clear_way_for_new_dir(to_dir_path, overwrite)?;
         if !to_dir_path.is_dir() {
             fs::create_dir_all(to_dir_path)
@@ -566,7 +1326,7 @@ impl DirectoryData {
             if let Ok(to_dir) = self.find_subdir(to_dir_path) {
                 to_dir
                     .attributes
-                    .set_file_attributes(to_dir_path)
+                    .set_file_attributes(to_dir_path, owner_mapping)
                     .map_err(|err| Error::ContentCopyIOError(err))?;
             }
         }
@@ -581,41 +1341,92 @@ impl DirectoryData {
                     .map_err(|err| Error::SnapshotDirIOError(err, new_dir_path.to_path_buf()))?;
                 subdir
                     .attributes
-                    .set_file_attributes(&new_dir_path)
+                    .set_file_attributes(&new_dir_path, owner_mapping)
                     .map_err(|err| Error::ContentCopyIOError(err))?;
             }
             stats.dir_count += 1;
         }
         // then do links to subdirs
-        stats.dir_sym_link_count += self.copy_dir_links_into(&to_dir_path, overwrite)?;
+        self.copy_dir_links_into(to_dir_path, tree, options, &mut stats)?;
         for subdir in self.subdir_iter(true) {
             let path_tail = subdir.path.strip_prefix(&self.path).unwrap(); // Should not fail
             let new_dir_path = to_dir_path.join(path_tail);
-            stats.dir_sym_link_count += subdir.copy_dir_links_into(&new_dir_path, overwrite)?;
+            subdir.copy_dir_links_into(&new_dir_path, tree, options, &mut stats)?;
         }
         // then do all the files (holding lock as little as needed)
-        match c_mgt_key.open_content_manager(dychatat_lib::Mutability::Immutable) {
+        let c_mgr_options = ContentManagerOptions {
+            read_cache_capacity: options.read_cache_capacity,
+            ..ContentManagerOptions::default()
+        };
+        match content_keys
+            .open_content_store_with_options(dychatat_lib::Mutability::Immutable, &c_mgr_options)
+        {
             Ok(ref c_mgr) => {
-                let (count, bytes) = self.copy_files_into(&to_dir_path, c_mgr, overwrite)?;
+                progress::notify_dir_entered(to_dir_path);
+                progress::check_cancelled()?;
+                let (count, bytes) =
+                    self.copy_files_into(to_dir_path, c_mgr, owner_mapping, overwrite)?;
                 stats.file_count += count;
                 stats.bytes_count += bytes;
                 for subdir in self.subdir_iter(true) {
                     let path_tail = subdir.path.strip_prefix(&self.path).unwrap(); // Should not fail
                     let new_dir_path = to_dir_path.join(path_tail);
-                    let (count, bytes) = subdir.copy_files_into(&new_dir_path, c_mgr, overwrite)?;
+                    progress::notify_dir_entered(&new_dir_path);
+                    progress::check_cancelled()?;
+                    let (count, bytes) =
+                        subdir.copy_files_into(&new_dir_path, c_mgr, owner_mapping, overwrite)?;
                     stats.file_count += count;
                     stats.bytes_count += bytes;
                 }
+                stats.cache_stats = c_mgr.cache_stats();
             }
             Err(err) => return Err(err.into()),
         }
         // then do links to file
-        stats.file_sym_link_count += self.copy_file_links_into(&to_dir_path, overwrite)?;
+        self.copy_file_links_into(to_dir_path, tree, options, &mut stats)?;
         for subdir in self.subdir_iter(true) {
             let path_tail = subdir.path.strip_prefix(&self.path).unwrap(); // Should not fail
             let new_dir_path = to_dir_path.join(path_tail);
-            stats.file_sym_link_count += subdir.copy_file_links_into(&new_dir_path, overwrite)?;
+            subdir.copy_file_links_into(&new_dir_path, tree, options, &mut stats)?;
+        }
+        // then the directories' times (which the above changed) deepest first
+        if options.preserve_dir_mtimes {
+            let mut dirs: Vec<(PathBuf, &DirectoryData)> = self
+                .subdir_iter(true)
+                .map(|subdir| {
+                    let path_tail = subdir.path.strip_prefix(&self.path).unwrap(); // Should not fail
+                    (to_dir_path.join(path_tail), subdir)
+                })
+                .collect();
+            dirs.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+            dirs.push((to_dir_path.to_path_buf(), self));
+            for (path, dir) in dirs.iter() {
+                if let Err(err) = dir.attributes.utimens_file(path) {
+                    let message = format!("modification time not restored: {}", err);
+                    report::emit(Severity::Warning, path, message);
+                }
+            }
+        }
+        // then the birth times (where they were recorded and can be set)
+        stats.birth_times_not_restored = self.restore_birth_times(to_dir_path);
+        if stats.birth_times_not_restored > 0 {
+            let message = format!(
+                "{} creation (birth) times not restored: not supported",
+                stats.birth_times_not_restored
+            );
+            report::emit(Severity::Info, to_dir_path, message);
+        }
+        // and finally flags such as "immutable" that would have blocked the above
+        for subdir in self.subdir_iter(true) {
+            let path_tail = subdir.path.strip_prefix(&self.path).unwrap(); // Should not fail
+            subdir
+                .attributes
+                .set_special_attributes(&to_dir_path.join(path_tail))
+                .map_err(Error::ContentCopyIOError)?;
         }
+        self.attributes
+            .set_special_attributes(to_dir_path)
+            .map_err(Error::ContentCopyIOError)?;
         Ok(stats)
     }
 }
@@ -628,7 +1439,16 @@ impl Index<usize> for DirectoryData {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+impl<'a> IntoIterator for &'a DirectoryData {
+    type Item = &'a FileSystemObject;
+    type IntoIter = std::slice::Iter<'a, FileSystemObject>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
 pub enum FileSystemObject {
     File(FileData),
     SymLink(SymLinkData, bool),
@@ -663,6 +1483,15 @@ impl fmt::Display for FileSystemObject {
 }
 
 impl FileSystemObject {
+    pub fn attributes(&self) -> &Attributes {
+        use FileSystemObject::*;
+        match self {
+            File(file_data) => &file_data.attributes,
+            Directory(dir_data) => &dir_data.attributes,
+            SymLink(link_data, _) => &link_data.attributes,
+        }
+    }
+
     pub fn get_dir_data(&self) -> Option<&DirectoryData> {
         use FileSystemObject::*;
         match self {
@@ -704,17 +1533,6 @@ impl FileSystemObject {
     }
 }
 
-fn move_aside_file_path(path: &Path) -> PathBuf {
-    let dt = DateTime::<Local>::from(time::SystemTime::now());
-    let suffix = format!("{}", dt.format("ema-%Y-%m-%d-%H-%M-%S"));
-    let new_suffix = if let Some(current_suffix) = path.extension() {
-        format!("{:?}-{}", current_suffix, suffix)
-    } else {
-        suffix
-    };
-    path.with_extension(&new_suffix)
-}
-
 fn clear_way_for_new_dir(new_dir_path: &Path, overwrite: bool) -> EResult<()> {
     if new_dir_path.exists() && !new_dir_path.is_dir() {
         // Real dir or link to dir
@@ -723,7 +1541,7 @@ fn clear_way_for_new_dir(new_dir_path: &Path, overwrite: bool) -> EResult<()> {
             fs::remove_file(new_dir_path)
                 .map_err(|err| Error::SnapshotDeleteIOError(err, new_dir_path.to_path_buf()))?;
         } else {
-            let new_path = move_aside_file_path(new_dir_path);
+            let new_path = move_aside_path(new_dir_path);
             fs::rename(new_dir_path, &new_path)
                 .map_err(|err| Error::SnapshotMoveAsideFailed(new_dir_path.to_path_buf(), err))?;
         }
@@ -733,18 +1551,172 @@ fn clear_way_for_new_dir(new_dir_path: &Path, overwrite: bool) -> EResult<()> {
 
 #[cfg(test)]
 mod fs_objects_tests {
-    use super::DirectoryData;
-    use std::path::{Component, PathBuf};
+    use super::{deserialize_dir_tree, looks_like_text, DirectoryData, Name};
+    use crate::archive::Exclusions;
+    use crate::fixture::FixtureSpec;
+    use crate::owner_map::OwnerMapping;
+    use crate::read_policy::ReadPolicy;
+    use dychatat_lib::content::{HashAlgorithm, MemoryContentStore};
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::path::{Component, Path};
 
     #[test]
     fn find_or_add_subdir_works() {
+        let fixture = FixtureSpec::new().dir("TEST/config").build();
         let mut sd = DirectoryData::try_new(Component::RootDir).unwrap();
-        let p = PathBuf::from("../TEST").canonicalize().unwrap();
+        let p = fixture.root().join("TEST").canonicalize().unwrap();
         assert_eq!(sd.find_or_add_subdir(&p).unwrap().path, p.as_path());
         assert_eq!(sd.find_subdir(&p).unwrap().path, p.as_path());
-        let sdp = PathBuf::from("../").canonicalize().unwrap();
+        let sdp = fixture.root().canonicalize().unwrap();
         assert_eq!(sd.find_subdir(&sdp).unwrap().path, sdp.as_path());
-        let sdp1 = PathBuf::from("../TEST/config").canonicalize().unwrap();
+        let sdp1 = p.join("config");
         assert!(sd.find_subdir(&sdp1).is_err());
     }
+
+    #[test]
+    fn remove_object_works() {
+        let fixture = FixtureSpec::new().dir("TEST").build();
+        let mut sd = DirectoryData::try_new(Component::RootDir).unwrap();
+        let p = fixture.root().join("TEST").canonicalize().unwrap();
+        sd.find_or_add_subdir(&p).unwrap();
+        let sdp = fixture.root().canonicalize().unwrap();
+        assert!(sd.remove_object(sdp.join("not_there")).is_none());
+        assert!(sd.remove_object(&p).unwrap().get_dir_data().is_some());
+        assert!(sd.find_subdir(&p).is_err());
+        assert!(sd.find_subdir(&sdp).is_ok());
+        assert!(sd.remove_object(&p).is_none());
+    }
+
+    #[test]
+    fn populate_and_extract_with_memory_store() {
+        let store = MemoryContentStore::new(HashAlgorithm::Sha256);
+        let dir = tempdir::TempDir::new("MEMORY_STORE_TEST").unwrap();
+        let src_dir_path = dir.path().join("src");
+        fs::create_dir_all(src_dir_path.join("sub")).unwrap();
+        fs::write(src_dir_path.join("a"), "same").unwrap();
+        fs::write(src_dir_path.join("sub/b"), "same").unwrap();
+        fs::write(src_dir_path.join("c"), "different").unwrap();
+        let mut sd = DirectoryData::try_new(&src_dir_path).unwrap();
+        let exclusions = Exclusions::new(&vec![], &vec![], false).unwrap();
+        let (file_stats, _, delta_repo_size) = sd
+            .populate(&exclusions, &store, None, &ReadPolicy::default())
+            .unwrap();
+        assert_eq!(file_stats.file_count, 3);
+        assert_eq!(file_stats.byte_count, 17);
+        assert_eq!(file_stats.snapshot_dedup_byte_count, 4);
+        assert_eq!(delta_repo_size, 13);
+        assert_eq!(store.len(), 2);
+
+        let into_dir_path = dir.path().join("dst");
+        fs::create_dir_all(&into_dir_path).unwrap();
+        assert_eq!(
+            sd.copy_files_into(&into_dir_path, &store, &OwnerMapping::default(), false)
+                .unwrap(),
+            (2, 13)
+        );
+        assert_eq!(fs::read(into_dir_path.join("c")).unwrap(), b"different");
+        let file_data = sd.find_file(src_dir_path.join("c")).unwrap();
+        assert_eq!(file_data.read_prefix(4, &store).unwrap(), b"diff");
+        assert_eq!(file_data.read_prefix(100, &store).unwrap(), b"different");
+
+        let token = sd
+            .find_file(src_dir_path.join("a"))
+            .unwrap()
+            .content_token()
+            .to_string();
+        assert_eq!(store.ref_count_for_token(&token), Some(2));
+        assert_eq!(sd.release_contents(&store).unwrap(), 13);
+        assert_eq!(store.ref_count_for_token(&token), Some(0));
+    }
+
+    #[test]
+    fn only_directory_names_are_stored() {
+        let fixture = FixtureSpec::new()
+            .file("TEST/config/archives/a", "a")
+            .build();
+        let mut sd = DirectoryData::try_new(Component::RootDir).unwrap();
+        let p = fixture
+            .root()
+            .join("TEST/config/archives")
+            .canonicalize()
+            .unwrap();
+        sd.find_or_add_subdir(&p).unwrap();
+        let json = serde_json::to_string(&sd).unwrap();
+        assert!(json.contains(r#""name":"archives""#));
+        assert!(!json.contains(p.to_str().unwrap()));
+        let read = deserialize_dir_tree(&mut serde_json::Deserializer::from_str(&json)).unwrap();
+        assert_eq!(read, sd);
+        assert_eq!(read.find_subdir(&p).unwrap().path(), p);
+
+        // as written by older versions
+        fn use_full_paths(dir: &mut serde_json::Value, parent_path: &Path) {
+            let dir = dir.as_object_mut().unwrap();
+            let name = dir.remove("name").unwrap();
+            let path = parent_path.join(name.as_str().unwrap());
+            for fso in dir["contents"].as_array_mut().unwrap().iter_mut() {
+                if let Some(subdir) = fso.get_mut("Directory") {
+                    use_full_paths(subdir, &path);
+                }
+            }
+            dir.insert("path".to_string(), path.to_str().unwrap().into());
+        }
+        let mut old_value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        use_full_paths(&mut old_value, Path::new(""));
+        let old_json = old_value.to_string();
+        assert!(old_json.contains(p.to_str().unwrap()));
+        let read =
+            deserialize_dir_tree(&mut serde_json::Deserializer::from_str(&old_json)).unwrap();
+        assert_eq!(read, sd);
+    }
+
+    #[test]
+    fn contents_are_indexed_by_name() {
+        let fixture = FixtureSpec::new()
+            .file("b", "b")
+            .dir("c")
+            .file("a", "a")
+            .build();
+        let store = MemoryContentStore::new(HashAlgorithm::Sha256);
+        let mut sd = DirectoryData::try_new(fixture.root()).unwrap();
+        let exclusions = Exclusions::new(&vec![], &vec![], false).unwrap();
+        sd.populate(&exclusions, &store, None, &ReadPolicy::default())
+            .unwrap();
+        assert_eq!(sd.len(), 3);
+        assert!(!sd.is_empty());
+        let names: Vec<_> = sd.iter().map(|fso| fso.name().to_os_string()).collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        let index = sd.index_for(OsStr::new("c")).unwrap();
+        assert!(sd[index].get_dir_data().is_some());
+        assert_eq!(sd.get(index), Some(&sd[index]));
+        assert!(sd.get(3).is_none());
+        assert_eq!((&sd).into_iter().count(), 3);
+    }
+
+    #[test]
+    fn text_is_recognised() {
+        assert!(looks_like_text(b"plain text\n"));
+        assert!(looks_like_text("caf\u{e9}".as_bytes()));
+        // a multi byte character cut short by the prefix length
+        assert!(looks_like_text(&"caf\u{e9}".as_bytes()[..4]));
+        assert!(!looks_like_text(b"text\0with a NUL"));
+        assert!(!looks_like_text(&[0xff, 0xfe, b'a']));
+    }
+
+    #[test]
+    fn find_matching_paths_works() {
+        let fixture = FixtureSpec::new().dir("TEST/config/archives").build();
+        let mut sd = DirectoryData::try_new(Component::RootDir).unwrap();
+        let p = fixture
+            .root()
+            .join("TEST/config/archives")
+            .canonicalize()
+            .unwrap();
+        sd.find_or_add_subdir(&p).unwrap();
+        let matches = sd.find_matching_paths(&|name| name.to_string_lossy().contains("onfi"));
+        assert_eq!(matches, vec![p.parent().unwrap().to_path_buf()]);
+        assert!(sd
+            .find_matching_paths(&|name| name == "no_such_name")
+            .is_empty());
+    }
 }
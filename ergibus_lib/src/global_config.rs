@@ -0,0 +1,411 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Configuration shared by all archives: named exclusion profiles (e.g.
+//! "rust-dev" or "photos") that archives can use instead of repeating the
+//! same exclusion patterns, named groups of archives (e.g. "nightly") that
+//! are backed up together, the tag used when naming moved aside files, the
+//! free space to be left on repositories' file systems, where back ups
+//! are reported (see `reporters`) and where temporary files are staged
+//! (see `staging`).  A built in profile ("standard")
+//! excludes commonly volatile paths such as caches, trash and thumbnails;
+//! it can be redefined in the global configuration.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::atomic_file;
+use crate::reporters::Reporter;
+use crate::{config, EResult, Error};
+
+/// A reusable set of exclusion patterns.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
+pub struct ExclusionProfile {
+    #[serde(default)]
+    pub dir_exclusions: Vec<String>,
+    #[serde(default)]
+    pub file_exclusions: Vec<String>,
+}
+
+/// The name of the built in profile of volatile paths.
+pub const STANDARD_EXCLUSIONS: &str = "standard";
+
+// Patterns are matched against both the name and the absolute path.
+const STANDARD_DIR_EXCLUSIONS: &[&str] = &[
+    ".cache",
+    ".thumbnails",
+    ".Trash",
+    ".Trash-*",
+    "**/.local/share/Trash",
+    "**/.mozilla/firefox/*/cache2",
+    "**/.mozilla/firefox/*/startupCache",
+    "**/.config/**/Cache",
+    "**/.config/**/Code Cache",
+    "**/.config/**/GPUCache",
+    "**/.config/**/Service Worker/CacheStorage",
+    "**/.config/**/ShaderCache",
+    "**/.config/**/GrShaderCache",
+];
+const STANDARD_FILE_EXCLUSIONS: &[&str] = &[
+    ".xsession-errors",
+    ".xsession-errors.old",
+    "**/.mozilla/firefox/*/lock",
+    "**/.mozilla/firefox/*/.parentlock",
+    "**/.config/**/SingletonLock",
+    "**/.config/**/SingletonCookie",
+    "**/.config/**/SingletonSocket",
+];
+
+/// The built in profile excluding commonly volatile paths (caches, trash,
+/// thumbnails and browsers' caches and lock files).
+pub fn standard_exclusion_profile() -> ExclusionProfile {
+    let to_vec = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect();
+    ExclusionProfile {
+        dir_exclusions: to_vec(STANDARD_DIR_EXCLUSIONS),
+        file_exclusions: to_vec(STANDARD_FILE_EXCLUSIONS),
+    }
+}
+
+/// What a back up should do with a file whose contents would eat into
+/// the reserved free space.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LowSpaceAction {
+    /// Skip the file with a warning
+    Skip,
+    /// Abandon the back up
+    #[default]
+    Abort,
+}
+
+impl FromStr for LowSpaceAction {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "skip" => Ok(LowSpaceAction::Skip),
+            "abort" => Ok(LowSpaceAction::Abort),
+            _ => Err(format!("{}: unknown low space action", text)),
+        }
+    }
+}
+
+/// The free space to be left on repositories' file systems.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct FreeSpaceReserve {
+    pub bytes: u64,
+    #[serde(default)]
+    pub on_low_space: LowSpaceAction,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+pub(crate) struct GlobalConfig {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, ExclusionProfile>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub move_aside_tag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub free_space_reserve: Option<FreeSpaceReserve>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reporters: Vec<Reporter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staging_dir: Option<PathBuf>,
+}
+
+impl GlobalConfig {
+    fn from_file(file_path: &Path) -> EResult<Self> {
+        match File::open(file_path) {
+            Ok(file) => serde_yaml::from_reader(&file)
+                .map_err(|err| Error::GlobalConfigYamlError(err, file_path.to_path_buf())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Replaced atomically as a truncated file would lose the configuration
+    fn write_to_file(&self, file_path: &Path) -> EResult<()> {
+        atomic_file::replace_file(file_path, |writer| {
+            serde_yaml::to_writer(writer, self)
+                .map_err(|err| Error::GlobalConfigYamlError(err, file_path.to_path_buf()))
+        })
+    }
+
+    pub fn read() -> EResult<Self> {
+        Self::from_file(&config::get_global_config_file_path())
+    }
+
+    pub fn write(&self) -> EResult<()> {
+        self.write_to_file(&config::get_global_config_file_path())
+    }
+
+    // Profiles defined in the configuration take precedence over built in ones
+    fn profile(&self, profile_name: &str) -> Option<ExclusionProfile> {
+        match self.profiles.get(profile_name) {
+            Some(profile) => Some(profile.clone()),
+            None if profile_name == STANDARD_EXCLUSIONS => Some(standard_exclusion_profile()),
+            None => None,
+        }
+    }
+
+    // The profile's patterns followed by the local ones (without duplicates)
+    fn merge_exclusions(
+        &self,
+        profile_names: &[String],
+        dir_patterns: &[String],
+        file_patterns: &[String],
+    ) -> EResult<(Vec<String>, Vec<String>)> {
+        let mut dir_exclusions: Vec<String> = vec![];
+        let mut file_exclusions: Vec<String> = vec![];
+        let add = |patterns: &mut Vec<String>, new_patterns: &[String]| {
+            for pattern in new_patterns {
+                if !patterns.contains(pattern) {
+                    patterns.push(pattern.clone())
+                }
+            }
+        };
+        for profile_name in profile_names {
+            let profile = self
+                .profile(profile_name)
+                .ok_or_else(|| Error::UnknownExclusionProfile(profile_name.to_string()))?;
+            add(&mut dir_exclusions, &profile.dir_exclusions);
+            add(&mut file_exclusions, &profile.file_exclusions);
+        }
+        add(&mut dir_exclusions, dir_patterns);
+        add(&mut file_exclusions, file_patterns);
+        Ok((dir_exclusions, file_exclusions))
+    }
+
+    // The archives followed by the groups' members (without duplicates)
+    fn expand_archive_groups(
+        &self,
+        archive_names: &[String],
+        group_names: &[String],
+    ) -> EResult<Vec<String>> {
+        let mut expanded: Vec<String> = vec![];
+        for archive_name in archive_names {
+            if !expanded.contains(archive_name) {
+                expanded.push(archive_name.clone());
+            }
+        }
+        for group_name in group_names {
+            let members = self
+                .groups
+                .get(group_name)
+                .ok_or_else(|| Error::UnknownArchiveGroup(group_name.to_string()))?;
+            for archive_name in members {
+                if !expanded.contains(archive_name) {
+                    expanded.push(archive_name.clone());
+                }
+            }
+        }
+        Ok(expanded)
+    }
+}
+
+/// The defined exclusion profiles (including the built in ones).
+pub fn get_exclusion_profiles() -> EResult<BTreeMap<String, ExclusionProfile>> {
+    let mut profiles = GlobalConfig::read()?.profiles;
+    profiles
+        .entry(STANDARD_EXCLUSIONS.to_string())
+        .or_insert_with(standard_exclusion_profile);
+    Ok(profiles)
+}
+
+/// Define (or redefine) an exclusion profile.
+pub fn set_exclusion_profile(profile_name: &str, profile: ExclusionProfile) -> EResult<()> {
+    let mut global_config = GlobalConfig::read()?;
+    global_config
+        .profiles
+        .insert(profile_name.to_string(), profile);
+    global_config.write()
+}
+
+/// The defined archive groups (and their member archives).
+pub fn get_archive_groups() -> EResult<BTreeMap<String, Vec<String>>> {
+    Ok(GlobalConfig::read()?.groups)
+}
+
+/// The named archives followed by the members of the named groups (each
+/// archive only once).
+pub fn expand_archive_groups(
+    archive_names: &[String],
+    group_names: &[String],
+) -> EResult<Vec<String>> {
+    if group_names.is_empty() {
+        GlobalConfig::default().expand_archive_groups(archive_names, &[])
+    } else {
+        GlobalConfig::read()?.expand_archive_groups(archive_names, group_names)
+    }
+}
+
+/// The configured tag for naming moved aside files (if any).
+pub fn get_move_aside_tag() -> EResult<Option<String>> {
+    Ok(GlobalConfig::read()?.move_aside_tag)
+}
+
+/// The configured free space reserve (if any).
+pub fn get_free_space_reserve() -> EResult<Option<FreeSpaceReserve>> {
+    Ok(GlobalConfig::read()?.free_space_reserve)
+}
+
+/// The configured back up reporters.
+pub fn get_reporters() -> EResult<Vec<Reporter>> {
+    Ok(GlobalConfig::read()?.reporters)
+}
+
+/// The configured directory for staging temporary files (if any).
+pub fn get_staging_dir() -> EResult<Option<PathBuf>> {
+    Ok(GlobalConfig::read()?.staging_dir)
+}
+
+/// Check that the named profiles are defined.
+pub fn check_exclusion_profiles(profile_names: &[String]) -> EResult<()> {
+    let global_config = GlobalConfig::read()?;
+    for profile_name in profile_names {
+        if global_config.profile(profile_name).is_none() {
+            return Err(Error::UnknownExclusionProfile(profile_name.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Merge the named profiles' patterns with an archive's own patterns.
+pub(crate) fn merge_exclusions(
+    profile_names: &[String],
+    dir_patterns: &[String],
+    file_patterns: &[String],
+) -> EResult<(Vec<String>, Vec<String>)> {
+    if profile_names.is_empty() {
+        Ok((dir_patterns.to_vec(), file_patterns.to_vec()))
+    } else {
+        GlobalConfig::read()?.merge_exclusions(profile_names, dir_patterns, file_patterns)
+    }
+}
+
+#[cfg(test)]
+mod global_config_tests {
+    use super::*;
+    use crate::archive::Exclusions;
+
+    #[test]
+    fn profiles_are_merged_with_local_patterns() {
+        let yaml_str = "
+profiles:
+  rust-dev:
+    dir_exclusions:
+      - target
+    file_exclusions:
+      - \"*.o\"
+  photos:
+    file_exclusions:
+      - \"*.xmp\"
+      - \"*.o\"
+";
+        let global_config: GlobalConfig = serde_yaml::from_str(yaml_str).unwrap();
+        let (dirs, files) = global_config
+            .merge_exclusions(
+                &["rust-dev".to_string(), "photos".to_string()],
+                &["lost+found".to_string(), "target".to_string()],
+                &["*.bak".to_string()],
+            )
+            .unwrap();
+        assert_eq!(dirs, vec!["target", "lost+found"]);
+        assert_eq!(files, vec!["*.o", "*.xmp", "*.bak"]);
+        assert!(matches!(
+            global_config.merge_exclusions(&["nonexistent".to_string()], &[], &[]),
+            Err(Error::UnknownExclusionProfile(_))
+        ));
+
+        let dir = tempdir::TempDir::new("GLOBAL_CONFIG_TEST").unwrap();
+        let file_path = dir.path().join("global");
+        assert_eq!(
+            GlobalConfig::from_file(&file_path).unwrap(),
+            GlobalConfig::default()
+        );
+        global_config.write_to_file(&file_path).unwrap();
+        assert_eq!(GlobalConfig::from_file(&file_path).unwrap(), global_config);
+
+        let global_config: GlobalConfig =
+            serde_yaml::from_str("free_space_reserve:\n  bytes: 1000\n").unwrap();
+        assert_eq!(
+            global_config.free_space_reserve,
+            Some(FreeSpaceReserve {
+                bytes: 1000,
+                on_low_space: LowSpaceAction::Abort
+            })
+        );
+        assert_eq!(LowSpaceAction::from_str("skip"), Ok(LowSpaceAction::Skip));
+    }
+
+    #[test]
+    fn archive_groups_are_expanded() {
+        let yaml_str = "
+groups:
+  nightly: [home, etc, projects]
+  weekly: [projects, media]
+";
+        let global_config: GlobalConfig = serde_yaml::from_str(yaml_str).unwrap();
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            global_config
+                .expand_archive_groups(&names(&["etc", "misc"]), &names(&["nightly", "weekly"]))
+                .unwrap(),
+            names(&["etc", "misc", "home", "projects", "media"])
+        );
+        assert!(matches!(
+            global_config.expand_archive_groups(&[], &names(&["monthly"])),
+            Err(Error::UnknownArchiveGroup(_))
+        ));
+        assert!(serde_yaml::to_string(&GlobalConfig::default())
+            .unwrap()
+            .find("groups")
+            .is_none());
+    }
+
+    #[test]
+    fn standard_exclusions_compile_and_match() {
+        let profile = standard_exclusion_profile();
+        let exclusions =
+            Exclusions::new(&profile.dir_exclusions, &profile.file_exclusions, false).unwrap();
+        for dir_path in &[
+            "/home/user/.cache",
+            "/home/user/.local/share/Trash",
+            "/home/user/.thumbnails",
+            "/home/user/.mozilla/firefox/abcd.default/cache2",
+            "/home/user/.config/google-chrome/Default/Cache",
+            "/home/user/.config/BraveSoftware/Brave-Browser/Default/Code Cache",
+        ] {
+            assert!(exclusions
+                .dir_exclusion_pattern(Path::new(dir_path))
+                .is_some());
+        }
+        for dir_path in &["/home/user/src/cache", "/home/user/.config/Cache.d"] {
+            assert!(exclusions
+                .dir_exclusion_pattern(Path::new(dir_path))
+                .is_none());
+        }
+        assert!(exclusions
+            .file_exclusion_pattern(Path::new("/home/user/.config/chromium/SingletonLock"))
+            .is_some());
+        assert!(exclusions
+            .file_exclusion_pattern(Path::new("/home/user/notes.txt"))
+            .is_none());
+
+        // the built in profile can be redefined
+        let global_config = GlobalConfig::default();
+        let (dirs, _) = global_config
+            .merge_exclusions(&[STANDARD_EXCLUSIONS.to_string()], &[], &[])
+            .unwrap();
+        assert_eq!(dirs, profile.dir_exclusions);
+        let global_config: GlobalConfig =
+            serde_yaml::from_str("profiles:\n  standard:\n    dir_exclusions: [tmp]\n").unwrap();
+        let (dirs, files) = global_config
+            .merge_exclusions(&[STANDARD_EXCLUSIONS.to_string()], &[], &[])
+            .unwrap();
+        assert_eq!((dirs, files), (vec!["tmp".to_string()], vec![]));
+    }
+}
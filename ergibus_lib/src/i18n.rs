@@ -0,0 +1,179 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Translation of user-facing messages (using Fluent).  Messages are
+//! looked up by id in the translations that best match the user's locale
+//! (as given by LC_ALL, LC_MESSAGES or LANG) falling back to the built in
+//! English.  A translation is added by putting its messages in
+//! `locales/<language-id>/ergibus.ftl` and listing it in `TRANSLATIONS`.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+
+use fluent_bundle::concurrent::FluentBundle;
+pub use fluent_bundle::FluentArgs;
+use fluent_bundle::FluentResource;
+use fluent_langneg::{negotiate_languages, NegotiationStrategy};
+use unic_langid::LanguageIdentifier;
+
+const DEFAULT_LANGUAGE: &str = "en-US";
+
+/// The available translations: (language id, messages).
+const TRANSLATIONS: &[(&str, &str)] = &[(
+    DEFAULT_LANGUAGE,
+    include_str!("../locales/en-US/ergibus.ftl"),
+)];
+
+/// Get the translation of the message with the given id (and arguments).
+/// E.g. `tr!("error-archive-unknown", name = archive_name)`.
+#[macro_export]
+macro_rules! tr {
+    ($id:expr) => {
+        $crate::i18n::translate($id, None)
+    };
+    ($id:expr, $($name:ident = $value:expr),+ $(,)?) => {{
+        let mut args = $crate::i18n::FluentArgs::new();
+        $(args.set(stringify!($name), $value);)+
+        $crate::i18n::translate($id, Some(&args))
+    }};
+}
+
+// The language requested by a locale name such as "de_AT.UTF-8@euro".
+fn locale_language(locale: &str) -> Option<LanguageIdentifier> {
+    let name = locale.split(['.', '@']).next()?;
+    match name {
+        "" | "C" | "POSIX" => None,
+        _ => name.replace('_', "-").parse().ok(),
+    }
+}
+
+/// The languages requested by the environment (most preferred first).
+pub fn requested_languages() -> Vec<LanguageIdentifier> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|value| !value.is_empty())
+        .and_then(|locale| locale_language(&locale))
+        .into_iter()
+        .collect()
+}
+
+/// The messages for a list of languages (most preferred first).
+pub struct Localiser {
+    bundles: Vec<FluentBundle<FluentResource>>,
+}
+
+impl Localiser {
+    pub fn new(translations: &[(&str, &str)], requested: &[LanguageIdentifier]) -> Self {
+        let default: LanguageIdentifier = DEFAULT_LANGUAGE.parse().expect(crate::UNEXPECTED);
+        let available: Vec<LanguageIdentifier> = translations
+            .iter()
+            .filter_map(|(lang_id, _)| lang_id.parse().ok())
+            .collect();
+        let chosen = negotiate_languages(
+            requested,
+            &available,
+            Some(&default),
+            NegotiationStrategy::Filtering,
+        );
+        let mut bundles = vec![];
+        for lang_id in chosen {
+            let messages = translations
+                .iter()
+                .find(|(id, _)| id.parse().ok().as_ref() == Some(lang_id))
+                .map(|(_, messages)| messages.to_string())
+                .expect(crate::UNEXPECTED);
+            let resource = match FluentResource::try_new(messages) {
+                Ok(resource) => resource,
+                Err((resource, errors)) => {
+                    log::warn!("{}: {:?}", lang_id, errors);
+                    resource
+                }
+            };
+            let mut bundle = FluentBundle::new_concurrent(vec![lang_id.clone()]);
+            // the isolation marks confuse terminals
+            bundle.set_use_isolating(false);
+            bundle.add_resource_overriding(resource);
+            bundles.push(bundle);
+        }
+        Self { bundles }
+    }
+
+    /// The message with the given id from the first translation that has
+    /// it.
+    pub fn message(&self, id: &str, args: Option<&FluentArgs>) -> Option<String> {
+        self.bundles.iter().find_map(|bundle| {
+            let pattern = bundle.get_message(id)?.value()?;
+            let mut errors = vec![];
+            let text = bundle.format_pattern(pattern, args, &mut errors);
+            if !errors.is_empty() {
+                log::warn!("{}: {:?}", id, errors);
+            }
+            Some(text.into_owned())
+        })
+    }
+}
+
+lazy_static! {
+    static ref LOCALISER: Localiser = Localiser::new(TRANSLATIONS, &requested_languages());
+    static ref STATIC_TRANSLATIONS: Mutex<HashMap<&'static str, &'static str>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The message with the given id in the user's language (or the id
+/// itself if there's no such message).
+pub fn translate(id: &str, args: Option<&FluentArgs>) -> String {
+    LOCALISER
+        .message(id, args)
+        .unwrap_or_else(|| id.to_string())
+}
+
+/// The message (without arguments) with the given id for use where
+/// `'static` text is required (e.g. GUI menu specifications).  Each
+/// message is only allocated once.
+pub fn translate_static(id: &'static str) -> &'static str {
+    STATIC_TRANSLATIONS
+        .lock()
+        .unwrap()
+        .entry(id)
+        .or_insert_with(|| Box::leak(translate(id, None).into_boxed_str()))
+}
+
+#[cfg(test)]
+mod i18n_tests {
+    use super::*;
+
+    #[test]
+    fn locale_names_are_understood() {
+        assert_eq!(locale_language("de_AT.UTF-8@euro"), "de-AT".parse().ok());
+        assert_eq!(locale_language("fr"), "fr".parse().ok());
+        assert_eq!(locale_language("C.UTF-8"), None);
+        assert_eq!(locale_language("POSIX"), None);
+    }
+
+    #[test]
+    fn messages_fall_back_to_english() {
+        let translations = [TRANSLATIONS[0], ("de", "error-cancelled = Abgebrochen.\n")];
+        let requested = vec!["de-AT".parse().unwrap()];
+        let localiser = Localiser::new(&translations, &requested);
+        assert_eq!(
+            localiser.message("error-cancelled", None).unwrap(),
+            "Abgebrochen."
+        );
+        let mut args = FluentArgs::new();
+        args.set("count", 2);
+        assert_eq!(
+            localiser
+                .message("error-snapshots-failed", Some(&args))
+                .unwrap(),
+            "2 back ups failed."
+        );
+        assert_eq!(localiser.message("no-such-message", None), None);
+
+        let localiser = Localiser::new(&translations, &[]);
+        assert_eq!(
+            localiser.message("error-cancelled", None).unwrap(),
+            "Cancelled."
+        );
+    }
+}
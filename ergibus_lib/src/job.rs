@@ -0,0 +1,579 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Long running operations (back ups and extractions) run in the background
+//! as jobs that can be monitored and cancelled.  A persistent `JobRunner`
+//! also keeps a record of each job's progress in the configuration
+//! directory so that jobs started by one process (e.g. `ergibus bu
+//! --detach`) can be monitored and cancelled from another.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::archive::Snapshots;
+use crate::fs_objects::{CopyOptions, ExtractionStats};
+use crate::progress::{self, ProgressEvent};
+use crate::snapshot::{BackUpOutcome, BackUpStats};
+use crate::{config, snapshot, EResult, Error};
+
+pub type JobId = u64;
+
+// How often job records are brought up to date and checked for cancellation requests
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Only the most recent warnings are kept
+const MAX_WARNINGS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobKind {
+    BackUp,
+    Extraction,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobState {
+    Running,
+    /// Finished successfully (with a summary of the results)
+    Finished(String),
+    Failed(String),
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_running(&self) -> bool {
+        *self == JobState::Running
+    }
+}
+
+/// The state of a job and how far it has got.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub id: JobId,
+    pub kind: JobKind,
+    pub description: String,
+    /// The process running the job
+    pub pid: u32,
+    pub started: SystemTime,
+    pub state: JobState,
+    pub current_dir: Option<PathBuf>,
+    pub file_count: u64,
+    pub byte_count: u64,
+    pub warnings: Vec<String>,
+}
+
+impl JobStatus {
+    fn new(id: JobId, kind: JobKind, description: &str) -> Self {
+        Self {
+            id,
+            kind,
+            description: description.to_string(),
+            pid: std::process::id(),
+            started: SystemTime::now(),
+            state: JobState::Running,
+            current_dir: None,
+            file_count: 0,
+            byte_count: 0,
+            warnings: vec![],
+        }
+    }
+
+    fn update<S>(&mut self, event: &ProgressEvent<S>) {
+        match event {
+            ProgressEvent::DirEntered(dir_path) => self.current_dir = Some(dir_path.clone()),
+            ProgressEvent::FileStored { bytes, .. }
+            | ProgressEvent::FileExtracted { bytes, .. } => {
+                self.file_count += 1;
+                self.byte_count += bytes;
+            }
+            ProgressEvent::Warning(message) => {
+                if self.warnings.len() >= MAX_WARNINGS {
+                    self.warnings.remove(0);
+                }
+                self.warnings.push(message.clone());
+            }
+            _ => (),
+        }
+    }
+
+    /// Whether the process running the job has gone away without finishing it.
+    pub fn is_abandoned(&self) -> bool {
+        self.state.is_running()
+            && unsafe { libc::kill(self.pid as libc::pid_t, 0) } != 0
+            && std::io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+    }
+}
+
+/// The results of jobs (which need to be summarised in job records).
+pub trait JobOutcome: Send + 'static {
+    fn summary(&self) -> String;
+}
+
+impl JobOutcome for BackUpStats {
+    fn summary(&self) -> String {
+        format!(
+            "{} files ({} bytes) in {:?}: repository grew by {} bytes",
+            self.1.file_count, self.1.byte_count, self.0, self.3
+        )
+    }
+}
+
+impl JobOutcome for BackUpOutcome {
+    fn summary(&self) -> String {
+        match self {
+            BackUpOutcome::Written(stats) => stats.summary(),
+            BackUpOutcome::Unchanged(latest_path) => format!("no change since {:?}", latest_path),
+        }
+    }
+}
+
+impl JobOutcome for (ExtractionStats, Duration) {
+    fn summary(&self) -> String {
+        format!(
+            "{} files ({} bytes) and {} sym links in {} dirs in {:?}",
+            self.0.file_count,
+            self.0.bytes_count,
+            self.0.dir_sym_link_count + self.0.file_sym_link_count,
+            self.0.dir_count,
+            self.1
+        )
+    }
+}
+
+impl JobOutcome for (u64, Duration) {
+    fn summary(&self) -> String {
+        format!("{} bytes in {:?}", self.0, self.1)
+    }
+}
+
+fn job_record_path(jobs_dir: &Path, id: JobId) -> PathBuf {
+    jobs_dir.join(format!("{}.json", id))
+}
+
+fn cancel_request_path(jobs_dir: &Path, id: JobId) -> PathBuf {
+    jobs_dir.join(format!("{}.cancel", id))
+}
+
+/// Where the output of a job run by a detached process should go.
+pub fn job_log_file_path(id: JobId) -> PathBuf {
+    config::get_jobs_dir_path().join(format!("{}.log", id))
+}
+
+// Readers must never see a partially written record
+fn write_job_record(jobs_dir: &Path, status: &JobStatus) -> EResult<()> {
+    let path = job_record_path(jobs_dir, status.id);
+    let temp_path = path.with_extension("json.tmp");
+    let json_text = serde_json::to_string(status)
+        .map_err(|err| Error::JobRecordJsonError(err, path.clone()))?;
+    fs::write(&temp_path, json_text)?;
+    fs::rename(&temp_path, &path)?;
+    Ok(())
+}
+
+fn read_job_record_in(jobs_dir: &Path, id: JobId) -> EResult<JobStatus> {
+    let path = job_record_path(jobs_dir, id);
+    let json_text = fs::read_to_string(&path).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => Error::JobUnknown(id),
+        _ => err.into(),
+    })?;
+    serde_json::from_str(&json_text).map_err(|err| Error::JobRecordJsonError(err, path))
+}
+
+// Claim the lowest unused id by creating its record
+fn reserve_job_id_in(jobs_dir: &Path, kind: JobKind, description: &str) -> EResult<JobId> {
+    fs::create_dir_all(jobs_dir)?;
+    let mut id = 1;
+    loop {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(job_record_path(jobs_dir, id))
+        {
+            Ok(_) => {
+                write_job_record(jobs_dir, &JobStatus::new(id, kind, description))?;
+                return Ok(id);
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => id += 1,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Reserve an id for a job that is to be run (by `JobRunner::start_reserved()`)
+/// in another process.
+pub fn reserve_job_id(kind: JobKind, description: &str) -> EResult<JobId> {
+    reserve_job_id_in(&config::get_jobs_dir_path(), kind, description)
+}
+
+pub fn read_job_record(id: JobId) -> EResult<JobStatus> {
+    read_job_record_in(&config::get_jobs_dir_path(), id)
+}
+
+/// The records of all jobs run by persistent runners (that haven't been removed).
+pub fn read_job_records() -> EResult<Vec<JobStatus>> {
+    let jobs_dir = config::get_jobs_dir_path();
+    let entries = match fs::read_dir(&jobs_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut records = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() != Some("json".as_ref()) {
+            continue;
+        }
+        let id = match path
+            .file_stem()
+            .and_then(|stem| stem.to_str()?.parse().ok())
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        match read_job_record_in(&jobs_dir, id) {
+            Ok(record) => records.push(record),
+            // the reservation is still being written
+            Err(Error::JobRecordJsonError(..)) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    records.sort_by_key(|record| record.id);
+    Ok(records)
+}
+
+/// Ask the process running the job to cancel it.
+pub fn request_job_cancel(id: JobId) -> EResult<()> {
+    let jobs_dir = config::get_jobs_dir_path();
+    if read_job_record_in(&jobs_dir, id)?.state.is_running() {
+        fs::write(cancel_request_path(&jobs_dir, id), "")?;
+    }
+    Ok(())
+}
+
+/// Remove the records (and logs) of jobs that are no longer running.
+/// Returns the ids of the jobs removed.
+pub fn remove_finished_job_records() -> EResult<Vec<JobId>> {
+    let jobs_dir = config::get_jobs_dir_path();
+    let mut removed = vec![];
+    for record in read_job_records()? {
+        if record.state.is_running() && !record.is_abandoned() {
+            continue;
+        }
+        for path in [
+            job_log_file_path(record.id),
+            cancel_request_path(&jobs_dir, record.id),
+            job_record_path(&jobs_dir, record.id),
+        ] {
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => (),
+            }
+        }
+        removed.push(record.id);
+    }
+    Ok(removed)
+}
+
+/// A job started by a `JobRunner`.
+pub struct Job<S> {
+    status: Arc<Mutex<JobStatus>>,
+    cancel: Arc<AtomicBool>,
+    result: mpsc::Receiver<EResult<S>>,
+}
+
+impl<S> Job<S> {
+    pub fn id(&self) -> JobId {
+        self.status.lock().unwrap().id
+    }
+
+    pub fn status(&self) -> JobStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Ask the job to stop.  It will fail with `Error::Cancelled` soon after.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed)
+    }
+
+    /// The job's result if it has finished (for those who can't wait).
+    pub fn try_result(&self) -> Option<EResult<S>> {
+        self.result.try_recv().ok()
+    }
+
+    /// Wait for the job to finish and return its result.
+    pub fn wait(self) -> EResult<S> {
+        self.result
+            .recv()
+            .expect("the job's monitor always sends a result")
+    }
+}
+
+struct JobHandle {
+    status: Arc<Mutex<JobStatus>>,
+    cancel: Arc<AtomicBool>,
+}
+
+/// Starts jobs and keeps track of them.
+pub struct JobRunner {
+    jobs_dir: Option<PathBuf>,
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<JobId, JobHandle>>,
+}
+
+impl Default for JobRunner {
+    fn default() -> Self {
+        Self {
+            jobs_dir: None,
+            next_id: AtomicU64::new(1),
+            jobs: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl JobRunner {
+    /// A runner whose jobs are only visible within this process (e.g. the GUI's).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A runner that records its jobs in the configuration directory.
+    pub fn persistent() -> Self {
+        Self {
+            jobs_dir: Some(config::get_jobs_dir_path()),
+            ..Self::default()
+        }
+    }
+
+    pub fn start<S, F>(&self, kind: JobKind, description: &str, operation: F) -> EResult<Job<S>>
+    where
+        S: JobOutcome,
+        F: FnOnce() -> EResult<S> + Send + 'static,
+    {
+        let id = match self.jobs_dir {
+            Some(ref jobs_dir) => reserve_job_id_in(jobs_dir, kind, description)?,
+            None => self.next_id.fetch_add(1, Ordering::Relaxed),
+        };
+        Ok(self.launch(JobStatus::new(id, kind, description), operation))
+    }
+
+    /// Run a job whose id was reserved (with `reserve_job_id()`) by another
+    /// process.  Only persistent runners can do this.
+    pub fn start_reserved<S, F>(&self, id: JobId, operation: F) -> EResult<Job<S>>
+    where
+        S: JobOutcome,
+        F: FnOnce() -> EResult<S> + Send + 'static,
+    {
+        let jobs_dir = self.jobs_dir.as_ref().ok_or(Error::JobUnknown(id))?;
+        let reserved = read_job_record_in(jobs_dir, id)?;
+        let status = JobStatus::new(id, reserved.kind, &reserved.description);
+        write_job_record(jobs_dir, &status)?;
+        Ok(self.launch(status, operation))
+    }
+
+    fn launch<S, F>(&self, status: JobStatus, operation: F) -> Job<S>
+    where
+        S: JobOutcome,
+        F: FnOnce() -> EResult<S> + Send + 'static,
+    {
+        let id = status.id;
+        let status = Arc::new(Mutex::new(status));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let events = progress::observe_cancellable(operation, Some(Arc::clone(&cancel)));
+        let (sender, receiver) = mpsc::channel();
+        let jobs_dir = self.jobs_dir.clone();
+        let m_status = Arc::clone(&status);
+        let m_cancel = Arc::clone(&cancel);
+        thread::spawn(move || {
+            let mut last_written = Instant::now();
+            loop {
+                let event = match events.recv_timeout(POLL_INTERVAL) {
+                    Ok(event) => Some(event),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                };
+                let result = {
+                    let mut status = m_status.lock().unwrap();
+                    match event {
+                        Some(ProgressEvent::Finished { stats }) => {
+                            status.state = JobState::Finished(stats.summary());
+                            Some(Ok(stats))
+                        }
+                        Some(ProgressEvent::Failed(err)) => {
+                            status.state = match err {
+                                Error::Cancelled => JobState::Cancelled,
+                                _ => JobState::Failed(err.to_string()),
+                            };
+                            Some(Err(err))
+                        }
+                        Some(event) => {
+                            status.update(&event);
+                            None
+                        }
+                        None => None,
+                    }
+                };
+                if let Some(ref jobs_dir) = jobs_dir {
+                    if cancel_request_path(jobs_dir, id).exists() {
+                        m_cancel.store(true, Ordering::Relaxed);
+                    }
+                    if result.is_some() || last_written.elapsed() >= POLL_INTERVAL {
+                        let status = m_status.lock().unwrap().clone();
+                        if let Err(err) = write_job_record(jobs_dir, &status) {
+                            log::warn!("job {}: failed to write record: {:?}", id, err);
+                        }
+                        last_written = Instant::now();
+                    }
+                }
+                if let Some(result) = result {
+                    sender.send(result).ok();
+                    break;
+                }
+            }
+        });
+        self.jobs.lock().unwrap().insert(
+            id,
+            JobHandle {
+                status: Arc::clone(&status),
+                cancel: Arc::clone(&cancel),
+            },
+        );
+        Job {
+            status,
+            cancel,
+            result: receiver,
+        }
+    }
+
+    pub fn start_back_up(&self, archive_name: &str) -> EResult<Job<BackUpStats>> {
+        let description = format!("back up {}", archive_name);
+        let archive_name = archive_name.to_string();
+        self.start(JobKind::BackUp, &description, move || {
+            snapshot::generate_snapshot(&archive_name)
+        })
+    }
+
+    pub fn start_dir_extraction(
+        &self,
+        snapshots: Snapshots,
+        n: i64,
+        dir_path: PathBuf,
+        into_dir_path: PathBuf,
+        opt_with_name: Option<PathBuf>,
+        options: CopyOptions,
+    ) -> EResult<Job<(ExtractionStats, Duration)>> {
+        let description = format!("extract {:?} into {:?}", dir_path, into_dir_path);
+        self.start(JobKind::Extraction, &description, move || {
+            snapshots.copy_dir_to(n, &dir_path, &into_dir_path, &opt_with_name, &options)
+        })
+    }
+
+    pub fn start_file_extraction(
+        &self,
+        snapshots: Snapshots,
+        n: i64,
+        file_path: PathBuf,
+        into_dir_path: PathBuf,
+        opt_with_name: Option<PathBuf>,
+        options: CopyOptions,
+    ) -> EResult<Job<(u64, Duration)>> {
+        let description = format!("extract {:?} into {:?}", file_path, into_dir_path);
+        self.start(JobKind::Extraction, &description, move || {
+            snapshots.copy_file_to(
+                n,
+                &file_path,
+                &into_dir_path,
+                &opt_with_name,
+                options.overwrite,
+                options.force,
+            )
+        })
+    }
+
+    /// The status of each of the jobs started by this runner.
+    pub fn statuses(&self) -> Vec<JobStatus> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|handle| handle.status.lock().unwrap().clone())
+            .collect()
+    }
+
+    pub fn cancel(&self, id: JobId) -> EResult<()> {
+        match self.jobs.lock().unwrap().get(&id) {
+            Some(handle) => {
+                handle.cancel.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+            None => Err(Error::JobUnknown(id)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod job_tests {
+    use super::*;
+    use crate::fixture::TestConfigGuard;
+
+    // Keep reporting progress until cancelled
+    fn run_until_cancelled() -> EResult<(u64, Duration)> {
+        loop {
+            progress::notify_dir_entered(Path::new("/somewhere"));
+            progress::notify_warning("still going");
+            progress::check_cancelled()?;
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn jobs_can_be_cancelled() {
+        let runner = JobRunner::new();
+        let job = runner
+            .start(JobKind::Extraction, "endless", run_until_cancelled)
+            .unwrap();
+        assert!(job.status().state.is_running());
+        assert!(runner.cancel(job.id() + 1).is_err());
+        runner.cancel(job.id()).unwrap();
+        assert!(matches!(job.wait(), Err(Error::Cancelled)));
+        let status = runner.statuses().pop().unwrap();
+        assert_eq!(status.state, JobState::Cancelled);
+        assert_eq!(status.current_dir, Some(PathBuf::from("/somewhere")));
+        assert!(status.warnings.len() <= MAX_WARNINGS);
+    }
+
+    #[test]
+    fn persistent_jobs_are_recorded() {
+        let _guard = TestConfigGuard::new();
+        let runner = JobRunner::persistent();
+        let job = runner
+            .start(JobKind::BackUp, "quick", || {
+                progress::notify(|| ProgressEvent::FileStored {
+                    path: PathBuf::from("/a/file"),
+                    bytes: 42,
+                });
+                Ok((42, Duration::from_secs(1)))
+            })
+            .unwrap();
+        let id = job.id();
+        assert_eq!(job.wait().unwrap().0, 42);
+        let record = read_job_record(id).unwrap();
+        assert_eq!(record.description, "quick");
+        assert_eq!(record.file_count, 1);
+        assert_eq!(record.byte_count, 42);
+        assert!(matches!(record.state, JobState::Finished(_)));
+
+        // as if started by another process
+        let id = reserve_job_id(JobKind::Extraction, "endless").unwrap();
+        let job = runner.start_reserved(id, run_until_cancelled).unwrap();
+        request_job_cancel(id).unwrap();
+        assert!(matches!(job.wait(), Err(Error::Cancelled)));
+        assert_eq!(read_job_record(id).unwrap().state, JobState::Cancelled);
+        assert_eq!(read_job_records().unwrap().len(), 2);
+        assert_eq!(remove_finished_job_records().unwrap().len(), 2);
+        assert!(read_job_records().unwrap().is_empty());
+        assert!(matches!(read_job_record(id), Err(Error::JobUnknown(_))));
+    }
+}
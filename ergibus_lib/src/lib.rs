@@ -6,12 +6,46 @@ extern crate serde_derive;
 use path_ext;
 
 pub mod archive;
+pub mod archive_registry;
+#[cfg(feature = "async")]
+pub mod async_api;
+mod atomic_file;
 pub mod attributes;
+pub mod audit;
+pub mod checksums;
+pub mod clock;
 pub mod config;
+pub mod content_keys;
+pub mod doctor;
+pub mod encryption;
+pub mod estimate;
+pub mod exit_status;
+pub mod file_types;
+#[cfg(test)]
+mod fixture;
+pub mod free_space;
 pub mod fs_objects;
+pub mod global_config;
+pub mod i18n;
+pub mod job;
+pub mod link_rewriting;
+pub mod merged_restore;
+pub mod monitoring;
+pub mod move_aside;
+pub mod owner_map;
 pub mod path_buf_ext;
-mod report;
+pub mod progress;
+pub mod prune_hooks;
+pub mod read_policy;
+pub mod report;
+pub mod reporters;
+pub mod resource_stats;
+pub mod signing;
 pub mod snapshot;
+pub mod snapshot_diff;
+pub mod snapshot_meta;
+pub mod snapshot_schema;
+pub mod staging;
 
 use crate::archive::ArchiveNameOrDirPath;
 
@@ -22,12 +56,14 @@ pub enum Error {
     ArchiveDirError(std::io::Error, std::path::PathBuf),
     ArchiveEmpty(ArchiveNameOrDirPath),
     ArchiveExists(String),
+    InvalidArchiveName(String, &'static str),
     ArchiveUnknown(String),
     ArchiveReadError(std::io::Error, std::path::PathBuf),
     ArchiveWriteError(std::io::Error, std::path::PathBuf),
     ArchiveYamlReadError(serde_yaml::Error, String),
     ArchiveYamlWriteError(serde_yaml::Error, String),
     RelativeIncludePath(std::path::PathBuf, String),
+    PathNotInArchive(std::path::PathBuf, String),
     ArchiveIncludePathError(path_ext::Error, std::path::PathBuf),
 
     GlobError(globset::Error),
@@ -35,13 +71,18 @@ pub enum Error {
     IOError(std::io::Error),
 
     ContentCopyIOError(std::io::Error),
+    ExtractTargetPathError(path_ext::Error, std::path::PathBuf),
     RepoError(dychatat_lib::RepoError),
     UnknownRepo(String),
+    NoRepoNominated,
+    OverflowRepoIsPrimary(String),
+    OverflowRepoHashMismatch(String),
 
     LastSnapshot(ArchiveNameOrDirPath),
     NoSnapshotAvailable,
     SnapshotDeleteIOError(std::io::Error, std::path::PathBuf),
     SnapshotDirIOError(std::io::Error, std::path::PathBuf),
+    SnapshotDirReadOnly(std::path::PathBuf),
     SnapshotIndexOutOfRange(ArchiveNameOrDirPath, i64),
     SnapshotMismatch(std::path::PathBuf),
     SnapshotMismatchDirty(std::io::Error, std::path::PathBuf),
@@ -52,11 +93,56 @@ pub enum Error {
     SnapshotUnknownDirectory(std::path::PathBuf),
     SnapshotWriteIOError(std::io::Error, std::path::PathBuf),
     SnapshotSerializeError(serde_json::Error),
+    SnapshotNotSigned(std::path::PathBuf),
+    SnapshotBadSignature(std::path::PathBuf),
+    SnapshotUntrustedKey(std::path::PathBuf),
+    SnapshotKeyUnavailable(std::path::PathBuf, String),
+    SnapshotEncryptFailed(std::path::PathBuf),
+    SnapshotDecryptFailed(std::path::PathBuf),
     SnapshotsFailed(i32),
+    SnapshotOverBudget(String),
+    SnapshotExists(std::path::PathBuf),
+    ImportTargetAmbiguous(String),
+    SnapshotTreeHashMismatch(std::path::PathBuf),
+    SnapshotsUnverified(usize),
+    RestoresFailed(usize),
+    DoctorChecksFailed(usize),
+
+    ConfigBundleReadError(std::io::Error, std::path::PathBuf),
+    ConfigBundleWriteError(std::io::Error, std::path::PathBuf),
+    ConfigBundleJsonError(serde_json::Error, std::path::PathBuf),
+
+    GlobalConfigYamlError(serde_yaml::Error, std::path::PathBuf),
+    UnknownExclusionProfile(String),
+    UnknownArchiveGroup(String),
+    BadConfigProfileName(String),
+
+    OwnerMapYamlError(serde_yaml::Error, std::path::PathBuf),
+    BadOwnerMapping(String),
+
+    SigningKeyExists(String),
+    SigningKeyMalformed(std::path::PathBuf),
+    TrustedKeysYamlError(serde_yaml::Error, std::path::PathBuf),
+    EncryptionKeyExists(String),
+    EncryptionKeyMalformed(std::path::PathBuf),
+    EncryptionKeyMissing(String),
+    UnsupportedCompression(String, String),
+
+    AuditLogJsonError(serde_json::Error, std::path::PathBuf),
 
     DuplicateFileSystemObjectName,
     FSOMalformedPath(std::path::PathBuf),
     FSOBrokenSymLink(std::path::PathBuf, std::path::PathBuf),
+    FSOSpecialFile(std::path::PathBuf, String),
+    FSOReadTimeout(std::path::PathBuf),
+    FSOInsufficientSpace(std::path::PathBuf, u64, u64),
+    ExtractInsufficientSpace(std::path::PathBuf, u64, u64),
+    PruneInsufficientSpace(String, u64, u64, usize),
+    AsyncTaskFailed(String),
+
+    Cancelled,
+    JobUnknown(u64),
+    JobRecordJsonError(serde_json::Error, std::path::PathBuf),
 }
 
 impl From<dychatat_lib::RepoError> for Error {
@@ -75,7 +161,66 @@ pub type EResult<T> = Result<T, Error>;
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Ergibus library error: {:?}", self)
+        let text = match self {
+            Error::ArchiveExists(name) => tr!("error-archive-exists", name = name.as_str()),
+            Error::ArchiveUnknown(name) => tr!("error-archive-unknown", name = name.as_str()),
+            Error::InvalidArchiveName(name, problem) => tr!(
+                "error-invalid-archive-name",
+                name = name.as_str(),
+                problem = *problem
+            ),
+            Error::UnknownRepo(name) => tr!("error-unknown-repo", name = name.as_str()),
+            Error::NoRepoNominated => tr!("error-no-repo-nominated"),
+            Error::NoSnapshotAvailable => tr!("error-no-snapshot-available"),
+            Error::SnapshotsFailed(count) => tr!("error-snapshots-failed", count = *count),
+            Error::SnapshotsUnverified(count) => tr!("error-snapshots-unverified", count = *count),
+            Error::RestoresFailed(count) => tr!("error-restores-failed", count = *count),
+            Error::DoctorChecksFailed(count) => tr!("error-doctor-checks-failed", count = *count),
+            Error::SnapshotDirReadOnly(path) => tr!(
+                "error-snapshot-dir-read-only",
+                path = path.display().to_string()
+            ),
+            Error::SnapshotExists(path) => {
+                tr!("error-snapshot-exists", path = path.display().to_string())
+            }
+            Error::ImportTargetAmbiguous(name) => {
+                tr!("error-import-target-ambiguous", name = name.as_str())
+            }
+            Error::UnknownExclusionProfile(name) => {
+                tr!("error-unknown-exclusion-profile", name = name.as_str())
+            }
+            Error::UnknownArchiveGroup(name) => {
+                tr!("error-unknown-archive-group", name = name.as_str())
+            }
+            Error::BadConfigProfileName(name) => {
+                tr!("error-bad-config-profile-name", name = name.as_str())
+            }
+            Error::EncryptionKeyMissing(name) => {
+                tr!("error-encryption-key-missing", name = name.as_str())
+            }
+            Error::UnsupportedCompression(compression, name) => tr!(
+                "error-unsupported-compression",
+                compression = compression.as_str(),
+                name = name.as_str()
+            ),
+            Error::ExtractInsufficientSpace(path, needed, available) => tr!(
+                "error-extract-insufficient-space",
+                path = path.display().to_string(),
+                needed = *needed,
+                available = *available
+            ),
+            Error::PruneInsufficientSpace(archive, wanted, available, kept) => tr!(
+                "error-prune-insufficient-space",
+                archive = archive.as_str(),
+                wanted = *wanted,
+                available = *available,
+                kept = *kept
+            ),
+            Error::Cancelled => tr!("error-cancelled"),
+            Error::JobUnknown(id) => tr!("error-job-unknown", id = *id),
+            _ => tr!("error-other", details = format!("{:?}", self)),
+        };
+        write!(f, "{}", text)
     }
 }
 
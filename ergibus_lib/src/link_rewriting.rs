@@ -0,0 +1,77 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Rewriting the targets of symbolic links when directories are extracted
+//! somewhere other than where they were backed up (as absolute targets
+//! would otherwise point into the live file system).  When requested (by
+//! the extraction's options) absolute targets within the extracted tree
+//! are replaced by relative ones pointing to their extracted copies and
+//! links whose absolute targets are outside the tree are left alone but
+//! noted (in the extraction's statistics) so that they can be reported.
+
+use std::path::{Component, Path, PathBuf};
+
+use crate::report::{self, Severity};
+
+/// The target to be used for a link being extracted from the tree at
+/// `from_root` to the tree at `to_root` as `new_link_path` (if it is to
+/// be rewritten).  Links with absolute targets outside the tree are
+/// added to `external` (with their targets).
+pub(crate) fn rewritten_target(
+    link_target: &Path,
+    from_root: &Path,
+    to_root: &Path,
+    new_link_path: &Path,
+    external: &mut Vec<(PathBuf, PathBuf)>,
+) -> Option<PathBuf> {
+    if !link_target.is_absolute() {
+        return None;
+    }
+    match link_target.strip_prefix(from_root) {
+        Ok(tail) => {
+            let link_dir_path = new_link_path.parent().unwrap_or(to_root);
+            Some(relative_path(link_dir_path, &to_root.join(tail)))
+        }
+        Err(_) => {
+            let message = format!("target {:?} is outside the extracted tree", link_target);
+            report::emit(Severity::Warning, new_link_path, message);
+            external.push((new_link_path.to_path_buf(), link_target.to_path_buf()));
+            None
+        }
+    }
+}
+
+// The path of `to_path` relative to `from_dir_path` (both absolute).
+fn relative_path(from_dir_path: &Path, to_path: &Path) -> PathBuf {
+    let from: Vec<Component> = from_dir_path.components().collect();
+    let to: Vec<Component> = to_path.components().collect();
+    let common = from
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in to[common..].iter() {
+        relative.push(component);
+    }
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    relative
+}
+
+#[cfg(test)]
+mod link_rewriting_tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths() {
+        let relative = |from: &str, to: &str| relative_path(Path::new(from), Path::new(to));
+        assert_eq!(relative("/r/a/b", "/r/a/b/c"), PathBuf::from("c"));
+        assert_eq!(relative("/r/a/b", "/r/a/d/e"), PathBuf::from("../d/e"));
+        assert_eq!(relative("/r/a/b", "/r"), PathBuf::from("../.."));
+        assert_eq!(relative("/r/a", "/r/a"), PathBuf::from("."));
+    }
+}
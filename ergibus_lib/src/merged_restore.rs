@@ -0,0 +1,198 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Restoring several archives' snapshots from around the same time under
+//! a common target root in one operation (e.g. for full machine recovery
+//! drills).  Each archive's snapshot nearest the nominated time is
+//! extracted with its absolute paths re-rooted under the target so that
+//! "/etc" from one archive and "/home" from another end up as
+//! "<target>/etc" and "<target>/home".  A failure to restore one archive
+//! doesn't stop the others from being restored.
+
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+
+use crate::archive::Snapshots;
+use crate::fs_objects::{CopyOptions, ExtractionStats};
+use crate::snapshot::{self, absolute_target_path};
+use crate::EResult;
+
+/// The outcome of restoring one of the archives.
+#[derive(Debug)]
+pub struct ArchiveRestore {
+    pub archive_name: String,
+    /// The name of the snapshot restored (if one was found)
+    pub snapshot_name: Option<OsString>,
+    /// How long after the nominated time the snapshot was taken (negative
+    /// if it was taken before)
+    pub offset: Option<chrono::Duration>,
+    pub result: EResult<ExtractionStats>,
+}
+
+/// The outcome of restoring all of the archives.
+#[derive(Debug)]
+pub struct MergedRestoreReport {
+    pub target_root: PathBuf,
+    pub at: DateTime<Local>,
+    pub restores: Vec<ArchiveRestore>,
+    pub duration: Duration,
+}
+
+impl MergedRestoreReport {
+    /// The combined statistics of the successful restores.
+    pub fn totals(&self) -> ExtractionStats {
+        let mut totals = ExtractionStats::default();
+        for restore in self.restores.iter() {
+            if let Ok(stats) = &restore.result {
+                totals += stats.clone();
+            }
+        }
+        totals
+    }
+
+    pub fn failure_count(&self) -> usize {
+        self.restores
+            .iter()
+            .filter(|restore| restore.result.is_err())
+            .count()
+    }
+}
+
+fn restore_archive(
+    restore: &mut ArchiveRestore,
+    at: DateTime<Local>,
+    target_root: &Path,
+    options: &CopyOptions,
+    require_signed: bool,
+) -> EResult<ExtractionStats> {
+    let mut snapshots = Snapshots::try_from(restore.archive_name.as_str())?;
+    snapshots.set_require_signed(require_signed);
+    let snapshot_path = snapshots.get_snapshot_path_nearest(at)?;
+    if let Some(snapshot_name) = snapshot_path.file_name() {
+        restore.snapshot_name = Some(snapshot_name.to_os_string());
+        restore.offset = snapshot::snapshot_name_time(snapshot_name).map(|time| time - at);
+    }
+    let spd = snapshots.read_snapshot(&snapshot_path)?;
+    spd.copy_dir_to(spd.root_dir_path(), target_root, options)
+}
+
+/// Restore the snapshot of each of the archives taken nearest to `at`
+/// under `target_root` (as specified by `options`) refusing unsigned
+/// snapshots if `require_signed` is true.
+pub fn restore_archives(
+    archive_names: &[String],
+    at: DateTime<Local>,
+    target_root: &Path,
+    options: &CopyOptions,
+    require_signed: bool,
+) -> EResult<MergedRestoreReport> {
+    let started = Instant::now();
+    let target_root = absolute_target_path(target_root)?;
+    let mut restores = vec![];
+    for archive_name in archive_names.iter() {
+        let mut restore = ArchiveRestore {
+            archive_name: archive_name.clone(),
+            snapshot_name: None,
+            offset: None,
+            result: Ok(ExtractionStats::default()),
+        };
+        restore.result = restore_archive(&mut restore, at, &target_root, options, require_signed);
+        if let Err(err) = &restore.result {
+            log::warn!("{}: restore failed: {}", archive_name, err);
+        }
+        restores.push(restore);
+    }
+    Ok(MergedRestoreReport {
+        target_root,
+        at,
+        restores,
+        duration: started.elapsed(),
+    })
+}
+
+#[cfg(test)]
+mod merged_restore_tests {
+    use super::*;
+    use crate::archive::create_new_archive;
+    use crate::fixture::{FixtureSpec, TestConfigGuard};
+    use crate::Error;
+    use dychatat_lib::content::create_new_repo;
+    use std::fs;
+
+    #[test]
+    fn archives_are_restored_under_a_common_root() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new()
+            .file("etc/hosts", "127.0.0.1 localhost")
+            .file("home/me/notes.txt", "some notes")
+            .build();
+        for (archive_name, dir_name) in [("test_etc", "etc"), ("test_home", "home")] {
+            create_new_archive(
+                archive_name,
+                Some("test_repo"),
+                &location,
+                &[fixture.root().join(dir_name)],
+                &[],
+                &[],
+                &[],
+                false,
+            )
+            .unwrap();
+            snapshot::generate_snapshot(archive_name).unwrap();
+        }
+        create_new_archive(
+            "test_empty",
+            Some("test_repo"),
+            &location,
+            &[fixture.root().join("etc")],
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+
+        let target_root = guard.data_dir().join("restored");
+        let archive_names: Vec<String> = ["test_etc", "test_home", "test_empty"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        let report = restore_archives(
+            &archive_names,
+            Local::now(),
+            &target_root,
+            &CopyOptions::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(report.failure_count(), 1);
+        assert!(matches!(
+            report.restores[2].result,
+            Err(Error::ArchiveEmpty(_))
+        ));
+        assert!(report.restores[..2]
+            .iter()
+            .all(|restore| restore.snapshot_name.is_some()
+                && restore
+                    .offset
+                    .is_some_and(|offset| offset <= chrono::Duration::zero())));
+        assert_eq!(report.totals().file_count, 2);
+        let rerooted = |path: &str| {
+            let original_path = fixture.root().join(path);
+            target_root.join(original_path.strip_prefix("/").unwrap())
+        };
+        assert_eq!(
+            fs::read_to_string(rerooted("etc/hosts")).unwrap(),
+            "127.0.0.1 localhost"
+        );
+        assert_eq!(
+            fs::read_to_string(rerooted("home/me/notes.txt")).unwrap(),
+            "some notes"
+        );
+    }
+}
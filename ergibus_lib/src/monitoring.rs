@@ -0,0 +1,290 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! The state of the archives' back ups in forms that monitoring systems
+//! understand (Prometheus text metrics and a JSON status document) so that
+//! they can alert on stale or failing back ups.  Back up failures are
+//! counted for the life of the process (e.g. the back up daemon).
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local};
+
+use crate::archive;
+use crate::snapshot::{self, ArchiveHealth};
+use crate::{EResult, Error};
+
+#[derive(Debug, Default, Clone)]
+struct FailureRecord {
+    count: u64,
+    last_error: Option<String>,
+}
+
+lazy_static! {
+    static ref FAILURES: Mutex<HashMap<String, FailureRecord>> = Mutex::new(HashMap::new());
+}
+
+/// Count a failed back up of the named archive.
+pub fn note_back_up_failure(archive_name: &str, error: &Error) {
+    let mut failures = FAILURES.lock().unwrap();
+    let record = failures.entry(archive_name.to_string()).or_default();
+    record.count += 1;
+    record.last_error = Some(error.to_string());
+}
+
+/// Note a successful back up of the named archive (which clears its last
+/// error but not its failure count).
+pub fn note_back_up_success(archive_name: &str) {
+    if let Some(record) = FAILURES.lock().unwrap().get_mut(archive_name) {
+        record.last_error = None;
+    }
+}
+
+/// The state of an archive's back ups.
+#[derive(Debug, Serialize, Clone)]
+pub struct ArchiveStatus {
+    pub archive_name: String,
+    pub health: ArchiveHealth,
+    /// The time (RFC 3339) of the most recent snapshot
+    pub last_back_up: Option<String>,
+    /// The time (seconds since the epoch) of the most recent snapshot
+    pub last_back_up_timestamp: Option<i64>,
+    pub backup_interval_seconds: u64,
+    pub snapshot_count: usize,
+    /// The bytes in the most recent snapshot's files
+    pub byte_count: u64,
+    /// The space occupied in the repository by the most recent snapshot's files
+    pub stored_byte_count: u64,
+    pub failures: u64,
+    pub last_error: Option<String>,
+}
+
+impl ArchiveStatus {
+    fn for_archive(archive_name: &str, failure_record: FailureRecord) -> Self {
+        let mut status = Self {
+            archive_name: archive_name.to_string(),
+            health: ArchiveHealth::Overdue,
+            last_back_up: None,
+            last_back_up_timestamp: None,
+            backup_interval_seconds: snapshot::DEFAULT_BACKUP_INTERVAL.as_secs(),
+            snapshot_count: 0,
+            byte_count: 0,
+            stored_byte_count: 0,
+            failures: failure_record.count,
+            last_error: failure_record.last_error,
+        };
+        match snapshot::get_archive_summary(archive_name) {
+            Ok(summary) => {
+                status.health = summary.health;
+                status.last_back_up = summary.last_snapshot_time.map(|time| time.to_rfc3339());
+                status.last_back_up_timestamp =
+                    summary.last_snapshot_time.map(|time| time.timestamp());
+                status.backup_interval_seconds = summary.backup_interval.as_secs();
+                status.snapshot_count = summary.snapshot_count;
+                if let Some(stats) = summary.last_snapshot_stats {
+                    status.byte_count = stats.file_stats.byte_count;
+                    status.stored_byte_count = stats.file_stats.stored_byte_count;
+                }
+            }
+            Err(err) => status.last_error = Some(err.to_string()),
+        }
+        status
+    }
+}
+
+/// The status of all the archives.
+pub fn get_archive_statuses() -> Vec<ArchiveStatus> {
+    let failures = FAILURES.lock().unwrap().clone();
+    archive::get_archive_names()
+        .iter()
+        .map(|archive_name| {
+            let record = failures.get(archive_name).cloned().unwrap_or_default();
+            ArchiveStatus::for_archive(archive_name, record)
+        })
+        .collect()
+}
+
+/// Whether the archive's most recent snapshot is at least as old as its
+/// back up interval (or it has none).
+pub fn back_up_due(archive_name: &str) -> EResult<bool> {
+    let summary = snapshot::get_archive_summary(archive_name)?;
+    Ok(match summary.last_snapshot_time {
+        Some(time) => {
+            let age = Local::now()
+                .signed_duration_since(time)
+                .to_std()
+                .unwrap_or_default();
+            age >= summary.backup_interval
+        }
+        None => true,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct StatusDocument<'a> {
+    generated: String,
+    archives: &'a [ArchiveStatus],
+}
+
+/// A JSON document describing the archives' statuses.
+pub fn status_json(statuses: &[ArchiveStatus], generated: DateTime<Local>) -> String {
+    let document = StatusDocument {
+        generated: generated.to_rfc3339(),
+        archives: statuses,
+    };
+    serde_json::to_string_pretty(&document).expect(crate::UNEXPECTED)
+}
+
+fn label_value(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_metric<F: Fn(&ArchiveStatus) -> Option<String>>(
+    text: &mut String,
+    statuses: &[ArchiveStatus],
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: F,
+) {
+    writeln!(text, "# HELP {} {}", name, help).unwrap();
+    writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+    for status in statuses {
+        if let Some(value) = value(status) {
+            writeln!(
+                text,
+                "{}{{archive=\"{}\"}} {}",
+                name,
+                label_value(&status.archive_name),
+                value
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// The archives' statuses as Prometheus text format metrics.
+pub fn prometheus_metrics(statuses: &[ArchiveStatus]) -> String {
+    let mut text = String::new();
+    write_metric(
+        &mut text,
+        statuses,
+        "ergibus_last_backup_timestamp_seconds",
+        "gauge",
+        "Time of the archive's most recent snapshot.",
+        |status| status.last_back_up_timestamp.map(|ts| ts.to_string()),
+    );
+    write_metric(
+        &mut text,
+        statuses,
+        "ergibus_backup_interval_seconds",
+        "gauge",
+        "The archive's expected back up interval.",
+        |status| Some(status.backup_interval_seconds.to_string()),
+    );
+    write_metric(
+        &mut text,
+        statuses,
+        "ergibus_backup_stale",
+        "gauge",
+        "Whether the archive's back up is overdue (1) or not (0).",
+        |status| Some(((status.health != ArchiveHealth::Ok) as u8).to_string()),
+    );
+    write_metric(
+        &mut text,
+        statuses,
+        "ergibus_snapshots",
+        "gauge",
+        "The number of snapshots in the archive.",
+        |status| Some(status.snapshot_count.to_string()),
+    );
+    write_metric(
+        &mut text,
+        statuses,
+        "ergibus_snapshot_bytes",
+        "gauge",
+        "Bytes in the files of the archive's most recent snapshot.",
+        |status| Some(status.byte_count.to_string()),
+    );
+    write_metric(
+        &mut text,
+        statuses,
+        "ergibus_stored_bytes",
+        "gauge",
+        "Repository space occupied by the files of the archive's most recent snapshot.",
+        |status| Some(status.stored_byte_count.to_string()),
+    );
+    write_metric(
+        &mut text,
+        statuses,
+        "ergibus_backup_failures_total",
+        "counter",
+        "Failed back ups of the archive.",
+        |status| Some(status.failures.to_string()),
+    );
+    text
+}
+
+#[cfg(test)]
+mod monitoring_tests {
+    use super::*;
+
+    fn status(archive_name: &str, health: ArchiveHealth, timestamp: Option<i64>) -> ArchiveStatus {
+        ArchiveStatus {
+            archive_name: archive_name.to_string(),
+            health,
+            last_back_up: None,
+            last_back_up_timestamp: timestamp,
+            backup_interval_seconds: 3600,
+            snapshot_count: 3,
+            byte_count: 2048,
+            stored_byte_count: 1024,
+            failures: 2,
+            last_error: None,
+        }
+    }
+
+    #[test]
+    fn metrics_have_a_line_per_archive() {
+        let statuses = vec![
+            status("home", ArchiveHealth::Ok, Some(1_700_000_000)),
+            status("never\"run", ArchiveHealth::Overdue, None),
+        ];
+        let text = prometheus_metrics(&statuses);
+        assert!(text.contains("# TYPE ergibus_backup_failures_total counter\n"));
+        assert!(
+            text.contains("ergibus_last_backup_timestamp_seconds{archive=\"home\"} 1700000000\n")
+        );
+        assert!(!text.contains("ergibus_last_backup_timestamp_seconds{archive=\"never\\\"run\"}"));
+        assert!(text.contains("ergibus_backup_stale{archive=\"home\"} 0\n"));
+        assert!(text.contains("ergibus_backup_stale{archive=\"never\\\"run\"} 1\n"));
+        assert!(text.contains("ergibus_stored_bytes{archive=\"home\"} 1024\n"));
+        assert!(text.contains("ergibus_backup_failures_total{archive=\"home\"} 2\n"));
+    }
+
+    #[test]
+    fn status_document_is_json() {
+        let statuses = vec![status("home", ArchiveHealth::Stale, Some(1_700_000_000))];
+        let text = status_json(&statuses, Local::now());
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value["archives"][0]["archive_name"], "home");
+        assert_eq!(value["archives"][0]["health"], "stale");
+        assert_eq!(value["archives"][0]["failures"], 2);
+    }
+
+    #[test]
+    fn last_errors_are_messages() {
+        let error = Error::Cancelled;
+        note_back_up_failure("test_last_error", &error);
+        let failures = FAILURES.lock().unwrap().clone();
+        assert_eq!(
+            failures["test_last_error"].last_error,
+            Some(error.to_string())
+        );
+        note_back_up_success("test_last_error");
+        assert_eq!(FAILURES.lock().unwrap()["test_last_error"].last_error, None);
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Naming (and finding) the files and directories that extraction moves
+//! aside rather than overwriting.  They're named by appending a tag and
+//! the time they were moved aside e.g. `notes.txt.ema-2024-03-01-10-15-00`
+//! and the tag can be changed via `move_aside_tag` in the global config.
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time;
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use log::*;
+
+use crate::{global_config, EResult};
+
+pub const DEFAULT_MOVE_ASIDE_TAG: &str = "ema";
+
+const TIME_FORMAT: &str = "%Y-%m-%d-%H-%M-%S";
+
+/// The tag to use: the configured one if it's usable as part of a file name.
+pub fn move_aside_tag() -> String {
+    match global_config::get_move_aside_tag() {
+        Ok(Some(tag)) if !tag.is_empty() && !tag.contains('/') => tag,
+        Ok(Some(tag)) => {
+            warn!("{:?}: unusable move aside tag", tag);
+            DEFAULT_MOVE_ASIDE_TAG.to_string()
+        }
+        Ok(None) => DEFAULT_MOVE_ASIDE_TAG.to_string(),
+        Err(err) => {
+            warn!("move aside tag: {:?}", err);
+            DEFAULT_MOVE_ASIDE_TAG.to_string()
+        }
+    }
+}
+
+fn aside_path_with_tag(path: &Path, tag: &str, now: DateTime<Local>) -> PathBuf {
+    let mut file_name = path.file_name().map(OsString::from).unwrap_or_default();
+    file_name.push(format!(".{}-{}", tag, now.format(TIME_FORMAT)));
+    let mut aside_path = path.with_file_name(&file_name);
+    // don't clobber something moved aside earlier in the same second
+    let mut count = 0;
+    while fs::symlink_metadata(&aside_path).is_ok() {
+        count += 1;
+        let mut numbered = file_name.clone();
+        numbered.push(format!("-{}", count));
+        aside_path = path.with_file_name(numbered);
+    }
+    aside_path
+}
+
+/// The path that the object at `path` should be moved aside to.
+pub(crate) fn move_aside_path(path: &Path) -> PathBuf {
+    aside_path_with_tag(path, &move_aside_tag(), Local::now())
+}
+
+/// A file, link or directory that was moved aside.
+#[derive(Debug, PartialEq)]
+pub struct MovedAside {
+    pub path: PathBuf,
+    pub moved_at: time::SystemTime,
+}
+
+impl MovedAside {
+    pub fn is_dir(&self) -> bool {
+        fs::symlink_metadata(&self.path)
+            .map(|metadata| metadata.is_dir())
+            .unwrap_or(false)
+    }
+
+    /// Delete the moved aside object (and its contents if it's a directory).
+    pub fn delete(&self) -> EResult<()> {
+        if self.is_dir() {
+            fs::remove_dir_all(&self.path)?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+// Recognises both current names and those made by older versions (which
+// look like `notes."txt"-ema-2024-03-01-10-15-00`).
+fn moved_at(file_name: &str, tag: &str) -> Option<time::SystemTime> {
+    let re = regex::Regex::new(&format!(
+        r#"^.+\.(?:"[^"]*"-)?{}-(\d{{4}}-\d{{2}}-\d{{2}}-\d{{2}}-\d{{2}}-\d{{2}})(?:-\d+)?$"#,
+        regex::escape(tag)
+    ))
+    .expect("valid regex");
+    let captures = re.captures(file_name)?;
+    let naive = NaiveDateTime::parse_from_str(&captures[1], TIME_FORMAT).ok()?;
+    let local = Local.from_local_datetime(&naive).earliest()?;
+    Some(local.into())
+}
+
+/// Find the objects under `dir_path` that were moved aside (with the
+/// current tag) oldest first.  The contents of moved aside directories
+/// aren't examined.
+pub fn find_moved_aside<P: AsRef<Path>>(dir_path: P) -> EResult<Vec<MovedAside>> {
+    let tag = move_aside_tag();
+    let mut found = vec![];
+    let mut walker = walkdir::WalkDir::new(dir_path.as_ref())
+        .min_depth(1)
+        .into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("{:?}", err);
+                continue;
+            }
+        };
+        let moved_at = match entry.file_name().to_str() {
+            Some(file_name) => moved_at(file_name, &tag),
+            None => None,
+        };
+        if let Some(moved_at) = moved_at {
+            if entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+            found.push(MovedAside {
+                path: entry.path().to_path_buf(),
+                moved_at,
+            });
+        }
+    }
+    found.sort_by_key(|moved_aside| moved_aside.moved_at);
+    Ok(found)
+}
+
+#[cfg(test)]
+mod move_aside_tests {
+    use super::*;
+    use crate::fixture::{FixtureSpec, TestConfigGuard};
+
+    #[test]
+    fn moved_aside_names_are_readable_and_found() {
+        let _guard = TestConfigGuard::new();
+        let fixture = FixtureSpec::new()
+            .file("notes.txt", "notes")
+            .file("Makefile", "all:")
+            .dir("sub")
+            .file("sub/notes.txt.ema-2020-01-02-03-04-05/inside", "hidden")
+            .file("sub/old.\"txt\"-ema-2019-01-02-03-04-05", "old style")
+            .file("sub/other.txt.xyz-2019-01-02-03-04-05", "other tag")
+            .build();
+        let now = Local.with_ymd_and_hms(2024, 3, 1, 10, 15, 0).unwrap();
+        let notes_path = fixture.root().join("notes.txt");
+        let aside_path = aside_path_with_tag(&notes_path, "ema", now);
+        assert_eq!(
+            aside_path,
+            fixture.root().join("notes.txt.ema-2024-03-01-10-15-00")
+        );
+        fs::rename(&notes_path, &aside_path).unwrap();
+        let makefile_path = fixture.root().join("Makefile");
+        assert_eq!(
+            aside_path_with_tag(&makefile_path, "ema", now),
+            fixture.root().join("Makefile.ema-2024-03-01-10-15-00")
+        );
+        fs::write(&notes_path, "new notes").unwrap();
+        assert_eq!(
+            aside_path_with_tag(&notes_path, "ema", now),
+            fixture.root().join("notes.txt.ema-2024-03-01-10-15-00-1")
+        );
+
+        let found: Vec<PathBuf> = find_moved_aside(fixture.root())
+            .unwrap()
+            .into_iter()
+            .map(|moved_aside| moved_aside.path)
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                fixture
+                    .root()
+                    .join("sub/old.\"txt\"-ema-2019-01-02-03-04-05"),
+                fixture.root().join("sub/notes.txt.ema-2020-01-02-03-04-05"),
+                aside_path,
+            ]
+        );
+    }
+}
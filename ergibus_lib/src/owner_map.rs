@@ -0,0 +1,167 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Translating the owners (uids and gids) recorded in snapshots when their
+//! contents are restored on a different machine.  As with rsync, owners
+//! are matched by the user and group names recorded when the snapshot was
+//! made unless numeric ids are wanted, and explicit mappings (given on the
+//! command line or in a YAML file) take precedence over both.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::path::Path;
+
+use schemars::JsonSchema;
+
+use crate::{EResult, Error};
+
+/// The names of the users and groups that owned a snapshot's contents.
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Default, Clone)]
+pub struct OwnerNames {
+    #[serde(default)]
+    users: BTreeMap<u32, String>,
+    #[serde(default)]
+    groups: BTreeMap<u32, String>,
+}
+
+impl OwnerNames {
+    /// Look up the names (on this machine) of the given users and groups.
+    pub(crate) fn look_up(uids: &BTreeSet<u32>, gids: &BTreeSet<u32>) -> Self {
+        let users = uids
+            .iter()
+            .filter_map(|uid| {
+                let user = users::get_user_by_uid(*uid)?;
+                Some((*uid, user.name().to_str()?.to_string()))
+            })
+            .collect();
+        let groups = gids
+            .iter()
+            .filter_map(|gid| {
+                let group = users::get_group_by_gid(*gid)?;
+                Some((*gid, group.name().to_str()?.to_string()))
+            })
+            .collect();
+        Self { users, groups }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.groups.is_empty()
+    }
+}
+
+/// How owners are to be translated during restoration, e.g. read from
+/// ```yaml
+/// uids:
+///   1000: 1001
+/// gids:
+///   1000: 100
+/// ```
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
+pub struct OwnerMap {
+    #[serde(default)]
+    pub uids: BTreeMap<u32, u32>,
+    #[serde(default)]
+    pub gids: BTreeMap<u32, u32>,
+    /// Don't match owners by name
+    #[serde(default)]
+    pub numeric_ids: bool,
+}
+
+fn parse_id_mapping(spec: &str) -> Option<(u32, u32)> {
+    let (old, new) = spec.split_once(':')?;
+    Some((old.trim().parse().ok()?, new.trim().parse().ok()?))
+}
+
+impl OwnerMap {
+    pub fn from_file(file_path: &Path) -> EResult<Self> {
+        let file = File::open(file_path)?;
+        serde_yaml::from_reader(file)
+            .map_err(|err| Error::OwnerMapYamlError(err, file_path.to_path_buf()))
+    }
+
+    /// Add a uid mapping given as "old:new".
+    pub fn add_uid_mapping(&mut self, spec: &str) -> EResult<()> {
+        let (old, new) =
+            parse_id_mapping(spec).ok_or_else(|| Error::BadOwnerMapping(spec.to_string()))?;
+        self.uids.insert(old, new);
+        Ok(())
+    }
+
+    /// Add a gid mapping given as "old:new".
+    pub fn add_gid_mapping(&mut self, spec: &str) -> EResult<()> {
+        let (old, new) =
+            parse_id_mapping(spec).ok_or_else(|| Error::BadOwnerMapping(spec.to_string()))?;
+        self.gids.insert(old, new);
+        Ok(())
+    }
+}
+
+/// The owner translations for restoring a particular snapshot's contents.
+#[derive(Debug, Default, Clone)]
+pub struct OwnerMapping {
+    uids: HashMap<u32, u32>,
+    gids: HashMap<u32, u32>,
+}
+
+impl OwnerMapping {
+    /// The mapping given by `owner_map` for a snapshot made with the
+    /// given owner names.
+    pub fn new(owner_map: &OwnerMap, owner_names: &OwnerNames) -> Self {
+        let mut uids = HashMap::new();
+        let mut gids = HashMap::new();
+        if !owner_map.numeric_ids {
+            for (uid, name) in owner_names.users.iter() {
+                if let Some(user) = users::get_user_by_name(name) {
+                    uids.insert(*uid, user.uid());
+                }
+            }
+            for (gid, name) in owner_names.groups.iter() {
+                if let Some(group) = users::get_group_by_name(name) {
+                    gids.insert(*gid, group.gid());
+                }
+            }
+        }
+        uids.extend(owner_map.uids.iter());
+        gids.extend(owner_map.gids.iter());
+        Self { uids, gids }
+    }
+
+    pub fn uid(&self, uid: u32) -> u32 {
+        self.uids.get(&uid).copied().unwrap_or(uid)
+    }
+
+    pub fn gid(&self, gid: u32) -> u32 {
+        self.gids.get(&gid).copied().unwrap_or(gid)
+    }
+}
+
+#[cfg(test)]
+mod owner_map_tests {
+    use super::*;
+
+    #[test]
+    fn owners_are_mapped_by_name_unless_numeric() {
+        let root_names = OwnerNames::look_up(&[0].into(), &[0].into());
+        assert_eq!(root_names.users.get(&0).map(String::as_str), Some("root"));
+        // as recorded on a machine where root's ids were different
+        let recorded = OwnerNames {
+            users: [(7, "root".to_string()), (8, "no such user".to_string())].into(),
+            groups: [(7, root_names.groups[&0].clone())].into(),
+        };
+        let mapping = OwnerMapping::new(&OwnerMap::default(), &recorded);
+        assert_eq!((mapping.uid(7), mapping.gid(7)), (0, 0));
+        assert_eq!((mapping.uid(8), mapping.gid(8)), (8, 8));
+
+        let mut owner_map: OwnerMap = serde_yaml::from_str("uids:\n  8: 1001\n").unwrap();
+        owner_map.add_gid_mapping("7:100").unwrap();
+        assert!(owner_map.add_uid_mapping("7").is_err());
+        assert!(owner_map.add_uid_mapping("root:0").is_err());
+        let mapping = OwnerMapping::new(&owner_map, &recorded);
+        assert_eq!((mapping.uid(7), mapping.gid(7)), (0, 100));
+        assert_eq!(mapping.uid(8), 1001);
+
+        owner_map.numeric_ids = true;
+        let mapping = OwnerMapping::new(&owner_map, &recorded);
+        assert_eq!((mapping.uid(7), mapping.gid(7)), (7, 100));
+        assert_eq!(mapping.uid(8), 1001);
+    }
+}
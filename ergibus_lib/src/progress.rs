@@ -0,0 +1,231 @@
+// Copyright 2021 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+use std::cell::RefCell;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use crate::report;
+use crate::EResult;
+use crate::Error;
+
+/// Events generated by long running operations (back ups and extractions)
+/// for observers such as alternative front ends and test harnesses.
+#[derive(Debug)]
+pub enum ProgressEvent<S> {
+    Started,
+    DirEntered(PathBuf),
+    FileStored { path: PathBuf, bytes: u64 },
+    FileExtracted { path: PathBuf, bytes: u64 },
+    Warning(String),
+    Finished { stats: S },
+    Failed(Error),
+}
+
+impl ProgressEvent<Infallible> {
+    fn widen<S>(self) -> ProgressEvent<S> {
+        use ProgressEvent::*;
+        match self {
+            Started => Started,
+            DirEntered(path) => DirEntered(path),
+            FileStored { path, bytes } => FileStored { path, bytes },
+            FileExtracted { path, bytes } => FileExtracted { path, bytes },
+            Warning(message) => Warning(message),
+            Finished { stats } => match stats {},
+            Failed(err) => Failed(err),
+        }
+    }
+}
+
+type Sink = Box<dyn Fn(ProgressEvent<Infallible>)>;
+
+thread_local! {
+    static SINK: RefCell<Option<Sink>> = RefCell::new(None);
+    static CANCEL: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+}
+
+// Pass the event to the observer (if any) of the operation running in this thread.
+// The event is only constructed if there is an observer.
+pub(crate) fn notify<F: FnOnce() -> ProgressEvent<Infallible>>(make_event: F) {
+    SINK.with(|sink| {
+        if let Some(ref sink) = *sink.borrow() {
+            sink(make_event())
+        }
+    })
+}
+
+pub(crate) fn notify_dir_entered(dir_path: &Path) {
+    notify(|| ProgressEvent::DirEntered(dir_path.to_path_buf()))
+}
+
+pub(crate) fn notify_warning(message: &str) {
+    notify(|| ProgressEvent::Warning(message.to_string()))
+}
+
+// Fail with `Error::Cancelled` if the operation running in this thread has
+// been cancelled.  Called at points where it's safe to abandon the operation.
+pub(crate) fn check_cancelled() -> EResult<()> {
+    CANCEL.with(|cancel| match *cancel.borrow() {
+        Some(ref cancel) if cancel.load(Ordering::Relaxed) => Err(Error::Cancelled),
+        _ => Ok(()),
+    })
+}
+
+/// The events generated by an observed operation.  The last event will be
+/// either `Finished` or `Failed`.
+pub struct ProgressEvents<S> {
+    receiver: mpsc::Receiver<ProgressEvent<S>>,
+}
+
+impl<S> Iterator for ProgressEvents<S> {
+    type Item = ProgressEvent<S>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl<S> ProgressEvents<S> {
+    /// Wait for the operation to finish and return its outcome and the
+    /// warnings that it generated (e.g. files skipped by a back up).
+    pub fn outcome_and_warnings(self) -> (EResult<S>, Vec<String>) {
+        let mut warnings = vec![];
+        for event in self {
+            match event {
+                ProgressEvent::Warning(message) => warnings.push(message),
+                ProgressEvent::Finished { stats } => return (Ok(stats), warnings),
+                ProgressEvent::Failed(err) => return (Err(err), warnings),
+                _ => (),
+            }
+        }
+        let err = Error::AsyncTaskFailed("operation ended without an outcome".to_string());
+        (Err(err), warnings)
+    }
+
+    pub(crate) fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<ProgressEvent<S>, mpsc::RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+}
+
+/// Run the operation in a separate thread and return its events.
+pub fn observe<S, F>(operation: F) -> ProgressEvents<S>
+where
+    S: Send + 'static,
+    F: FnOnce() -> EResult<S> + Send + 'static,
+{
+    observe_cancellable(operation, None)
+}
+
+// As for `observe()` but the operation will fail with `Error::Cancelled`
+// soon after `cancel` is set.
+pub(crate) fn observe_cancellable<S, F>(
+    operation: F,
+    cancel: Option<Arc<AtomicBool>>,
+) -> ProgressEvents<S>
+where
+    S: Send + 'static,
+    F: FnOnce() -> EResult<S> + Send + 'static,
+{
+    let operation = report::carry_warning_sink(operation);
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        CANCEL.with(|cell| *cell.borrow_mut() = cancel);
+        sender.send(ProgressEvent::Started).ok();
+        let sink_sender = sender.clone();
+        SINK.with(|sink| {
+            *sink.borrow_mut() = Some(Box::new(move |event: ProgressEvent<Infallible>| {
+                sink_sender.send(event.widen()).ok();
+            }))
+        });
+        let last_event = match operation() {
+            Ok(stats) => ProgressEvent::Finished { stats },
+            Err(err) => ProgressEvent::Failed(err),
+        };
+        SINK.with(|sink| *sink.borrow_mut() = None);
+        CANCEL.with(|cell| *cell.borrow_mut() = None);
+        sender.send(last_event).ok();
+    });
+    ProgressEvents { receiver }
+}
+
+#[cfg(test)]
+mod progress_tests {
+    use super::*;
+
+    #[test]
+    fn events_arrive_in_order() {
+        let events: Vec<ProgressEvent<u64>> = observe(|| {
+            notify_dir_entered(Path::new("/a"));
+            notify(|| ProgressEvent::FileStored {
+                path: PathBuf::from("/a/f"),
+                bytes: 7,
+            });
+            notify_warning("oops");
+            Ok(7)
+        })
+        .collect();
+        assert_eq!(events.len(), 5);
+        assert!(matches!(events[0], ProgressEvent::Started));
+        assert!(matches!(events[1], ProgressEvent::DirEntered(ref p) if p == Path::new("/a")));
+        assert!(matches!(
+            events[2],
+            ProgressEvent::FileStored { bytes: 7, .. }
+        ));
+        assert!(matches!(events[3], ProgressEvent::Warning(ref m) if m == "oops"));
+        assert!(matches!(events[4], ProgressEvent::Finished { stats: 7 }));
+    }
+
+    #[test]
+    fn warnings_are_collected_with_the_outcome() {
+        let (outcome, warnings) = observe(|| {
+            notify_warning("one");
+            notify_dir_entered(Path::new("/a"));
+            notify_warning("two");
+            Ok(7)
+        })
+        .outcome_and_warnings();
+        assert_eq!(outcome.unwrap(), 7);
+        assert_eq!(warnings, vec!["one", "two"]);
+        let (outcome, warnings) = observe::<u64, _>(|| {
+            notify_warning("three");
+            Err(Error::NoSnapshotAvailable)
+        })
+        .outcome_and_warnings();
+        assert!(matches!(outcome, Err(Error::NoSnapshotAvailable)));
+        assert_eq!(warnings, vec!["three"]);
+    }
+
+    #[test]
+    fn failure_is_the_last_event() {
+        let events: Vec<ProgressEvent<u64>> = observe(|| Err(Error::NoSnapshotAvailable)).collect();
+        assert!(matches!(
+            events.last(),
+            Some(ProgressEvent::Failed(Error::NoSnapshotAvailable))
+        ));
+        notify_warning("not observed so ignored");
+    }
+
+    #[test]
+    fn cancelled_operations_fail() {
+        let cancel = Arc::new(AtomicBool::new(true));
+        let events: Vec<ProgressEvent<u64>> = observe_cancellable(
+            || {
+                check_cancelled()?;
+                Ok(7)
+            },
+            Some(cancel),
+        )
+        .collect();
+        assert!(matches!(
+            events.last(),
+            Some(ProgressEvent::Failed(Error::Cancelled))
+        ));
+        assert!(check_cancelled().is_ok());
+    }
+}
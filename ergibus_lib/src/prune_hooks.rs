@@ -0,0 +1,92 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Notification of external systems (monitoring, back up manifests, etc.)
+//! when an archive's snapshots are deleted.  If the archive has a
+//! "prune_hook" (a shell command) it's run after each deletion with a
+//! JSON summary of what was deleted on its standard input, e.g.
+//!
+//! ```json
+//! {"archive":"home","operation":"expired","deleted":["2024-01-01-10-00-00+1000"],"deleted_count":1,"freed_bytes":1024,"remaining_snapshots":12}
+//! ```
+//!
+//! and the environment variables ERGIBUS_ARCHIVE, ERGIBUS_OPERATION,
+//! ERGIBUS_DELETED_COUNT, ERGIBUS_FREED_BYTES and
+//! ERGIBUS_REMAINING_SNAPSHOTS set.  Failure of the hook is only ever a
+//! warning.
+
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+/// What caused the snapshots to be deleted.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PruneOperation {
+    /// All but the newest snapshots were deleted
+    KeepNewest,
+    /// The snapshots expired under the retention policy
+    Expired,
+    /// The oldest snapshots were deleted to free repository space
+    UntilFree,
+}
+
+impl PruneOperation {
+    fn as_str(self) -> &'static str {
+        match self {
+            PruneOperation::KeepNewest => "keep_newest",
+            PruneOperation::Expired => "expired",
+            PruneOperation::UntilFree => "until_free",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PruneSummary {
+    pub archive: String,
+    pub operation: PruneOperation,
+    /// The names of the snapshots deleted
+    pub deleted: Vec<String>,
+    pub deleted_count: usize,
+    /// The stored size of the contents no longer referenced
+    pub freed_bytes: u64,
+    pub remaining_snapshots: usize,
+}
+
+/// Run the hook command (with `sh -c`) passing it the summary.
+pub fn run_prune_hook(command: &str, summary: &PruneSummary) -> io::Result<()> {
+    let json = serde_json::to_string(summary).map_err(io::Error::from)?;
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("ERGIBUS_ARCHIVE", &summary.archive)
+        .env("ERGIBUS_OPERATION", summary.operation.as_str())
+        .env("ERGIBUS_DELETED_COUNT", summary.deleted_count.to_string())
+        .env("ERGIBUS_FREED_BYTES", summary.freed_bytes.to_string())
+        .env(
+            "ERGIBUS_REMAINING_SNAPSHOTS",
+            summary.remaining_snapshots.to_string(),
+        )
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // NB: hooks that only look at the environment may not read their input
+        match stdin.write_all(json.as_bytes()) {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err),
+            _ => (),
+        }
+    }
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("\"{}\" {}", command, status)))
+    }
+}
+
+/// Run the hook (if any) reporting any failure as a warning.
+pub(crate) fn notify(command: Option<&str>, summary: &PruneSummary) {
+    if let Some(command) = command {
+        if let Err(err) = run_prune_hook(command, summary) {
+            log::warn!("{}: prune hook failed: {}", summary.archive, err);
+        }
+    }
+}
@@ -0,0 +1,391 @@
+// Copyright 2021 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+use std::cmp;
+use std::ffi::OsString;
+use std::fs::{self, DirEntry, File};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::fs_objects::FileStats;
+use crate::global_config::LowSpaceAction;
+use crate::report::{self, Severity};
+use crate::{EResult, Error};
+
+/// How to treat named pipes, sockets and device files found while
+/// generating snapshots.  They are never backed up.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum SpecialFilePolicy {
+    /// Skip them silently
+    #[default]
+    Ignore,
+    /// Skip them with a warning
+    Report,
+}
+
+/// The order in which the files in each directory are read (and so the
+/// order in which their contents are added to the repository's pack
+/// files).  Grouping similar files keeps related contents together in
+/// the packs and storing them in name order matches the order in which
+/// they're extracted (reducing seeking on hard disks).
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum StorageOrder {
+    /// The order the file system lists them in
+    #[default]
+    Directory,
+    Name,
+    /// By extension and then by size (files before subdirectories)
+    Extension,
+    /// Smallest first (files before subdirectories)
+    Size,
+}
+
+impl FromStr for StorageOrder {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "directory" => Ok(StorageOrder::Directory),
+            "name" => Ok(StorageOrder::Name),
+            "extension" => Ok(StorageOrder::Extension),
+            "size" => Ok(StorageOrder::Size),
+            _ => Err(format!("{}: unknown storage order", text)),
+        }
+    }
+}
+
+/// How the files are read while generating snapshots.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct ReadPolicy {
+    /// Give up on (and warn about) files whose contents take longer than
+    /// this to arrive.  `None` (the default) means wait forever.
+    pub timeout: Option<Duration>,
+    pub special_files: SpecialFilePolicy,
+    /// The order in which each directory's files are read
+    pub storage_order: StorageOrder,
+    /// What to do with files whose contents won't fit in the repository
+    /// without using its reserved free space
+    pub on_low_space: LowSpaceAction,
+}
+
+impl ReadPolicy {
+    /// As `report::ignore_report_or_fail()` but with special files
+    /// reported as required by the policy.
+    pub(crate) fn ignore_report_or_fail<P: AsRef<Path>>(&self, err: Error, path: P) -> EResult<()> {
+        match err {
+            Error::FSOSpecialFile(file_path, kind)
+                if self.special_files == SpecialFilePolicy::Report =>
+            {
+                report::emit(Severity::Warning, file_path, format!("{} ignored", kind));
+                Ok(())
+            }
+            _ => report::ignore_report_or_fail(err, path),
+        }
+    }
+
+    /// As `ignore_report_or_fail()` but with files that won't fit in the
+    /// repository skipped (and counted in `file_stats`) if required by
+    /// the policy.
+    pub(crate) fn skip_file_or_fail<P: AsRef<Path>>(
+        &self,
+        err: Error,
+        path: P,
+        file_stats: &mut FileStats,
+    ) -> EResult<()> {
+        match err {
+            Error::FSOInsufficientSpace(file_path, size, available)
+                if self.on_low_space == LowSpaceAction::Skip =>
+            {
+                let message = format!(
+                    "skipped: {} bytes won't fit in the repository without using the reserved free space ({} bytes available)",
+                    size, available
+                );
+                report::emit(Severity::Error, file_path, message);
+                file_stats.space_skipped_file_count += 1;
+                file_stats.space_skipped_byte_count += size;
+                Ok(())
+            }
+            _ => self.ignore_report_or_fail(err, path),
+        }
+    }
+}
+
+/// Sort a directory's entries into the order in which they're to be read.
+pub(crate) fn sort_entries(entries: &mut [DirEntry], order: StorageOrder) {
+    let is_dir = |entry: &DirEntry| entry.file_type().is_ok_and(|t| t.is_dir());
+    // NB: symbolic links aren't followed so their sizes are their own
+    let size = |entry: &DirEntry| entry.metadata().map_or(0, |m| m.len());
+    match order {
+        StorageOrder::Directory => (),
+        StorageOrder::Name => entries.sort_by_key(|entry| entry.file_name()),
+        StorageOrder::Extension => entries.sort_by_cached_key(|entry| {
+            let path = entry.path();
+            let extension = path.extension().map(OsString::from).unwrap_or_default();
+            (is_dir(entry), extension, size(entry), entry.file_name())
+        }),
+        StorageOrder::Size => {
+            entries.sort_by_cached_key(|entry| (is_dir(entry), size(entry), entry.file_name()))
+        }
+    }
+}
+
+pub(crate) fn special_file_kind(file_type: &fs::FileType) -> &'static str {
+    if file_type.is_fifo() {
+        "named pipe"
+    } else if file_type.is_socket() {
+        "socket"
+    } else if file_type.is_block_device() {
+        "block device"
+    } else if file_type.is_char_device() {
+        "character device"
+    } else {
+        "special file"
+    }
+}
+
+/// Open a regular file for reading.  The file is opened in non blocking
+/// mode so that a named pipe substituted for the file after it was
+/// examined can't hang us.
+pub(crate) fn open_regular_file(path: &Path) -> EResult<File> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    let file_type = file.metadata()?.file_type();
+    if file_type.is_file() {
+        Ok(file)
+    } else {
+        Err(Error::FSOSpecialFile(
+            path.to_path_buf(),
+            special_file_kind(&file_type).to_string(),
+        ))
+    }
+}
+
+const CHUNK_SIZE: usize = 512000;
+const CHUNKS_IN_FLIGHT: usize = 2;
+
+type Chunks = mpsc::Receiver<io::Result<Vec<u8>>>;
+
+/// A file reader whose reads fail with `ErrorKind::TimedOut` if data
+/// doesn't arrive in time.  The actual reading is done by a worker thread
+/// which is abandoned (rather than waited for) if it gets stuck.  Only
+/// rewinding (`SeekFrom::Start`) is supported.
+pub(crate) struct TimedReader {
+    file: File,
+    timeout: Duration,
+    worker: Option<(Chunks, thread::JoinHandle<()>)>,
+    chunk: Vec<u8>,
+    offset: usize,
+    timed_out: bool,
+}
+
+impl TimedReader {
+    pub fn new(file: File, timeout: Duration) -> io::Result<Self> {
+        let mut reader = Self {
+            file,
+            timeout,
+            worker: None,
+            chunk: vec![],
+            offset: 0,
+            timed_out: false,
+        };
+        reader.start_worker()?;
+        Ok(reader)
+    }
+
+    fn start_worker(&mut self) -> io::Result<()> {
+        let mut file = self.file.try_clone()?;
+        let (sender, receiver) = mpsc::sync_channel(CHUNKS_IN_FLIGHT);
+        let handle = thread::spawn(move || loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            match file.read(&mut chunk) {
+                Ok(n_bytes) => {
+                    chunk.truncate(n_bytes);
+                    // an empty chunk signals end of file
+                    if sender.send(Ok(chunk)).is_err() || n_bytes == 0 {
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => {
+                    sender.send(Err(err)).ok();
+                    break;
+                }
+            }
+        });
+        self.worker = Some((receiver, handle));
+        self.chunk.clear();
+        self.offset = 0;
+        Ok(())
+    }
+
+    // NB: dropping the receiver makes the worker's next send fail
+    fn stop_worker(&mut self) {
+        if let Some((receiver, handle)) = self.worker.take() {
+            drop(receiver);
+            handle.join().ok();
+        }
+    }
+}
+
+impl Read for TimedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset == self.chunk.len() {
+            let receiver = match self.worker {
+                Some((ref receiver, _)) => receiver,
+                None => return Ok(0),
+            };
+            match receiver.recv_timeout(self.timeout) {
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.offset = 0;
+                }
+                Ok(Err(err)) => return Err(err),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    self.timed_out = true;
+                    return Err(io::Error::new(ErrorKind::TimedOut, "read timed out"));
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        let n_bytes = cmp::min(buf.len(), self.chunk.len() - self.offset);
+        buf[..n_bytes].copy_from_slice(&self.chunk[self.offset..self.offset + n_bytes]);
+        self.offset += n_bytes;
+        Ok(n_bytes)
+    }
+}
+
+impl Seek for TimedReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if self.timed_out {
+            return Err(io::Error::new(ErrorKind::TimedOut, "read timed out"));
+        }
+        if !matches!(pos, SeekFrom::Start(_)) {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "only SeekFrom::Start is supported",
+            ));
+        }
+        self.stop_worker();
+        let position = self.file.seek(pos)?;
+        self.start_worker()?;
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod read_policy_tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn timed_reader_reads_and_rewinds() {
+        let dir = tempdir::TempDir::new("TIMED_READER_TEST").unwrap();
+        let path = dir.path().join("file");
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 3 + 17).map(|i| i as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        let file = open_regular_file(&path).unwrap();
+        let mut reader = TimedReader::new(file, Duration::from_secs(10)).unwrap();
+        for _ in 0..2 {
+            let mut read_back = vec![];
+            reader.read_to_end(&mut read_back).unwrap();
+            assert_eq!(read_back, contents);
+            reader.seek(SeekFrom::Start(0)).unwrap();
+        }
+    }
+
+    #[test]
+    fn entries_are_sorted_into_storage_order() {
+        let dir = tempdir::TempDir::new("STORAGE_ORDER_TEST").unwrap();
+        fs::write(dir.path().join("b.txt"), "three").unwrap();
+        fs::write(dir.path().join("a.rs"), "four").unwrap();
+        fs::write(dir.path().join("c.txt"), "1").unwrap();
+        fs::write(dir.path().join("d.rs"), "twenty").unwrap();
+        fs::create_dir(dir.path().join("a_dir")).unwrap();
+        let sorted = |order: StorageOrder| {
+            let mut entries: Vec<DirEntry> = fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap())
+                .collect();
+            sort_entries(&mut entries, order);
+            entries
+                .iter()
+                .map(|entry| entry.file_name().into_string().unwrap())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(
+            sorted(StorageOrder::Name),
+            vec!["a.rs", "a_dir", "b.txt", "c.txt", "d.rs"]
+        );
+        assert_eq!(
+            sorted(StorageOrder::Extension),
+            vec!["a.rs", "d.rs", "c.txt", "b.txt", "a_dir"]
+        );
+        assert_eq!(
+            sorted(StorageOrder::Size),
+            vec!["c.txt", "a.rs", "b.txt", "d.rs", "a_dir"]
+        );
+        assert_eq!("extension".parse(), Ok(StorageOrder::Extension));
+        assert!("random".parse::<StorageOrder>().is_err());
+    }
+
+    #[test]
+    fn special_files_are_reported_if_required() {
+        let sink = std::sync::Arc::new(report::CollectingSink::default());
+        let severities = |policy: &ReadPolicy| {
+            let err = Error::FSOSpecialFile("/a/fifo".into(), "named pipe".to_string());
+            report::with_warning_sink(sink.clone(), || {
+                policy.ignore_report_or_fail(err, "/a/fifo").unwrap()
+            });
+            sink.take()
+                .iter()
+                .map(|warning| warning.severity)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(severities(&ReadPolicy::default()), vec![Severity::Info]);
+        let policy = ReadPolicy {
+            special_files: SpecialFilePolicy::Report,
+            ..ReadPolicy::default()
+        };
+        assert_eq!(severities(&policy), vec![Severity::Warning]);
+    }
+
+    #[test]
+    fn files_are_skipped_for_lack_of_space_if_required() {
+        let err = || Error::FSOInsufficientSpace("/a/big".into(), 1000, 10);
+        let mut file_stats = FileStats::default();
+        let policy = ReadPolicy::default();
+        assert!(policy
+            .skip_file_or_fail(err(), "/a/big", &mut file_stats)
+            .is_err());
+        let policy = ReadPolicy {
+            on_low_space: LowSpaceAction::Skip,
+            ..ReadPolicy::default()
+        };
+        for _ in 0..2 {
+            policy
+                .skip_file_or_fail(err(), "/a/big", &mut file_stats)
+                .unwrap();
+        }
+        assert_eq!(file_stats.space_skipped_file_count, 2);
+        assert_eq!(file_stats.space_skipped_byte_count, 2000);
+    }
+
+    #[test]
+    fn named_pipes_are_not_opened() {
+        let dir = tempdir::TempDir::new("SPECIAL_FILE_TEST").unwrap();
+        let path = dir.path().join("fifo");
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        match open_regular_file(&path) {
+            Err(Error::FSOSpecialFile(_, kind)) => assert_eq!(kind, "named pipe"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+}
@@ -1,29 +1,169 @@
+// Copyright 2021 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Reporting of the non fatal problems (unreadable files, broken links,
+//! etc.) met while populating or extracting snapshots.  The warnings are
+//! passed to the warning sink installed for the thread that started the
+//! operation (see `with_warning_sink()`) which, by default, logs them so
+//! that front ends can choose to print them, collect them for display or
+//! (in tests) assert on them.  Warnings (and errors) are also passed on to any
+//! progress observer of the operation.
+
+use std::cell::RefCell;
+use std::fmt;
 use std::io::ErrorKind;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
+use crate::progress;
 use crate::{EResult, Error};
 use log;
 
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Something deliberately (or harmlessly) ignored
+    Info,
+    /// Something that may need attention
+    Warning,
+    /// Something that should have been included but wasn't
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub severity: Severity,
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.path, self.message)
+    }
+}
+
+/// A destination for warnings.  Sinks are shared with the threads that
+/// operations are run in (see `carry_warning_sink()`).
+pub trait WarningSink: Send + Sync {
+    fn emit(&self, warning: &Warning);
+}
+
+/// The default sink: warnings are logged.
+#[derive(Debug, Default)]
+pub struct LogSink;
+
+impl WarningSink for LogSink {
+    fn emit(&self, warning: &Warning) {
+        match warning.severity {
+            Severity::Info => log::trace!("{}", warning),
+            Severity::Warning => log::warn!("{}", warning),
+            Severity::Error => log::error!("{}", warning),
+        }
+    }
+}
+
+/// A sink that keeps the warnings (e.g. for display in a list).
+#[derive(Debug, Default)]
+pub struct CollectingSink {
+    warnings: Mutex<Vec<Warning>>,
+}
+
+impl CollectingSink {
+    /// Remove and return the warnings collected so far.
+    pub fn take(&self) -> Vec<Warning> {
+        std::mem::take(&mut *self.warnings.lock().unwrap())
+    }
+}
+
+impl WarningSink for CollectingSink {
+    fn emit(&self, warning: &Warning) {
+        self.warnings.lock().unwrap().push(warning.clone())
+    }
+}
+
+thread_local! {
+    static WARNING_SINK: RefCell<Option<Arc<dyn WarningSink>>> = RefCell::new(None);
+}
+
+// Reinstate the previous sink even if the operation panics
+struct SinkRestorer(Option<Arc<dyn WarningSink>>);
+
+impl Drop for SinkRestorer {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        WARNING_SINK.with(|sink| *sink.borrow_mut() = previous);
+    }
+}
+
+/// Run the operation (in this thread) with its warnings going to `sink`.
+pub fn with_warning_sink<R, F: FnOnce() -> R>(sink: Arc<dyn WarningSink>, operation: F) -> R {
+    let previous = WARNING_SINK.with(|cell| cell.borrow_mut().replace(sink));
+    let _restorer = SinkRestorer(previous);
+    operation()
+}
+
+/// Wrap the operation so that its warnings go to the sink installed for
+/// the current thread even if it's run in another thread.
+pub fn carry_warning_sink<R, F: FnOnce() -> R>(operation: F) -> impl FnOnce() -> R {
+    let sink = WARNING_SINK.with(|sink| sink.borrow().clone());
+    move || match sink {
+        Some(sink) => with_warning_sink(sink, operation),
+        None => operation(),
+    }
+}
+
+pub(crate) fn emit<P: AsRef<Path>>(severity: Severity, path: P, message: String) {
+    let warning = Warning {
+        severity,
+        path: path.as_ref().to_path_buf(),
+        message,
+    };
+    let sink = WARNING_SINK.with(|sink| sink.borrow().clone());
+    match sink {
+        Some(sink) => sink.emit(&warning),
+        None => LogSink.emit(&warning),
+    }
+    if severity > Severity::Info {
+        progress::notify_warning(&warning.to_string());
+    }
+}
+
 pub fn ignore_report_or_fail<P: AsRef<Path>>(err: Error, path: P) -> EResult<()> {
     match &err {
         Error::FSOBrokenSymLink(link_path, target_path) => {
-            log::warn!(
-                "{:?} -> {:?}: broken symbolic link ignored",
-                link_path,
-                target_path
-            );
+            let message = format!("broken symbolic link to {:?} ignored", target_path);
+            emit(Severity::Warning, link_path, message);
+            Ok(())
+        }
+        Error::FSOSpecialFile(file_path, kind) => {
+            emit(Severity::Info, file_path, format!("{} ignored", kind));
+            Ok(())
+        }
+        Error::FSOReadTimeout(file_path) => {
+            let message = "read timed out: file skipped".to_string();
+            emit(Severity::Error, file_path, message);
             Ok(())
         }
         Error::IOError(io_err) => {
             match io_err.kind() {
                 // we assume that "not found" is due to a race condition
                 ErrorKind::NotFound => {
-                    log::trace!("{:?}: not found", path.as_ref());
+                    emit(Severity::Info, path, "not found".to_string());
                     Ok(())
                 }
                 // benign so just report it
                 ErrorKind::PermissionDenied => {
-                    log::warn!("{:?}: permission denied", path.as_ref());
+                    emit(Severity::Error, path, "permission denied".to_string());
                     Ok(())
                 }
                 // programming error that needs to be fixed
@@ -33,3 +173,43 @@ pub fn ignore_report_or_fail<P: AsRef<Path>>(err: Error, path: P) -> EResult<()>
         _ => Err(err),
     }
 }
+
+#[cfg(test)]
+mod report_tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn warnings_go_to_the_installed_sink() {
+        let sink = Arc::new(CollectingSink::default());
+        let result = with_warning_sink(sink.clone(), || {
+            let err = Error::FSOBrokenSymLink("/a/link".into(), "/a/gone".into());
+            ignore_report_or_fail(err, "/a/link")?;
+            let err = io::Error::from(ErrorKind::NotFound);
+            ignore_report_or_fail(err.into(), "/a/vanished")?;
+            let err = io::Error::from(ErrorKind::PermissionDenied);
+            ignore_report_or_fail(err.into(), "/a/private")?;
+            let err = io::Error::from(ErrorKind::InvalidData);
+            ignore_report_or_fail(err.into(), "/a/bad")
+        });
+        assert!(result.is_err());
+        let warnings = sink.take();
+        assert_eq!(
+            warnings
+                .iter()
+                .map(|warning| (warning.severity, warning.path.as_path()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Severity::Warning, Path::new("/a/link")),
+                (Severity::Info, Path::new("/a/vanished")),
+                (Severity::Error, Path::new("/a/private")),
+            ]
+        );
+        assert_eq!(warnings[2].to_string(), "\"/a/private\": permission denied");
+
+        // the previous (default) sink is reinstated afterwards
+        let err = io::Error::from(ErrorKind::PermissionDenied);
+        ignore_report_or_fail(err.into(), "/a/private").unwrap();
+        assert!(sink.take().is_empty());
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Reporting the outcomes of back ups to the system logs (journald or
+//! syslog) and/or a file so that unattended back ups leave a durable
+//! trail.  The reporters are selected in the global configuration e.g.
+//!
+//! ```yaml
+//! reporters:
+//!   - kind: journald
+//!   - kind: file
+//!     path: /var/log/ergibus.log
+//! ```
+//!
+//! Journal entries carry the fields ERGIBUS_ARCHIVE, ERGIBUS_RESULT,
+//! ERGIBUS_FILES, ERGIBUS_BYTES and ERGIBUS_REPO_GROWTH (so that they can
+//! be queried with e.g. `journalctl ERGIBUS_ARCHIVE=home`), syslog
+//! messages carry them as "key=value" pairs and the file gets one JSON
+//! object per line.  Failure to report is only ever a warning.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+
+use crate::global_config;
+use crate::snapshot::BackUpOutcome;
+use crate::EResult;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+const IDENTIFIER: &str = "ergibus";
+
+/// A destination for back up reports.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Reporter {
+    Journald,
+    Syslog,
+    File { path: PathBuf },
+}
+
+/// The result of backing up an archive.
+#[derive(Serialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum BackUpResult {
+    Success,
+    Unchanged,
+    Failure,
+}
+
+impl BackUpResult {
+    fn as_str(self) -> &'static str {
+        match self {
+            BackUpResult::Success => "success",
+            BackUpResult::Unchanged => "unchanged",
+            BackUpResult::Failure => "failure",
+        }
+    }
+}
+
+/// The outcome of backing up an archive.
+#[derive(Serialize, Debug, Clone)]
+pub struct BackUpReport {
+    pub archive: String,
+    pub result: BackUpResult,
+    pub files: u64,
+    pub bytes: u64,
+    pub repo_growth: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BackUpReport {
+    pub fn new(archive_name: &str, result: &EResult<BackUpOutcome>) -> Self {
+        let mut report = Self {
+            archive: archive_name.to_string(),
+            result: BackUpResult::Success,
+            files: 0,
+            bytes: 0,
+            repo_growth: 0,
+            error: None,
+        };
+        match result {
+            Ok(BackUpOutcome::Written((_, file_stats, _, repo_growth, _))) => {
+                report.files = file_stats.file_count;
+                report.bytes = file_stats.byte_count;
+                report.repo_growth = *repo_growth;
+            }
+            Ok(BackUpOutcome::Unchanged(_)) => report.result = BackUpResult::Unchanged,
+            Err(err) => {
+                report.result = BackUpResult::Failure;
+                report.error = Some(err.to_string());
+            }
+        }
+        report
+    }
+
+    fn message(&self) -> String {
+        match self.result {
+            BackUpResult::Success => format!(
+                "{}: backed up {} files ({} bytes), repository growth {} bytes",
+                self.archive, self.files, self.bytes, self.repo_growth
+            ),
+            BackUpResult::Unchanged => format!("{}: unchanged: no snapshot written", self.archive),
+            BackUpResult::Failure => format!(
+                "{}: back up failed: {}",
+                self.archive,
+                self.error.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+
+    // syslog(3) severities: error for failures otherwise informational
+    fn severity(&self) -> u8 {
+        match self.result {
+            BackUpResult::Failure => 3,
+            _ => 6,
+        }
+    }
+
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("ERGIBUS_ARCHIVE", self.archive.clone()),
+            ("ERGIBUS_RESULT", self.result.as_str().to_string()),
+            ("ERGIBUS_FILES", self.files.to_string()),
+            ("ERGIBUS_BYTES", self.bytes.to_string()),
+            ("ERGIBUS_REPO_GROWTH", self.repo_growth.to_string()),
+        ]
+    }
+
+    // An entry in journald's native protocol (values that contain new lines
+    // are length prefixed)
+    fn journal_entry(&self) -> Vec<u8> {
+        let mut fields = vec![
+            ("MESSAGE", self.message()),
+            ("PRIORITY", self.severity().to_string()),
+            ("SYSLOG_IDENTIFIER", IDENTIFIER.to_string()),
+        ];
+        fields.extend(self.fields());
+        let mut entry = vec![];
+        for (name, value) in fields.iter() {
+            entry.extend_from_slice(name.as_bytes());
+            if value.contains('\n') {
+                entry.push(b'\n');
+                entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            } else {
+                entry.push(b'=');
+            }
+            entry.extend_from_slice(value.as_bytes());
+            entry.push(b'\n');
+        }
+        entry
+    }
+
+    // An RFC 3164 style message for the "user" facility
+    fn syslog_message(&self) -> String {
+        let pairs: Vec<String> = self
+            .fields()
+            .iter()
+            .map(|(name, value)| {
+                let key = name.trim_start_matches("ERGIBUS_").to_lowercase();
+                format!("{}={:?}", key, value)
+            })
+            .collect();
+        format!(
+            "<{}>{} {}[{}]: {} [{}]",
+            8 + self.severity(),
+            Local::now().format("%b %e %H:%M:%S"),
+            IDENTIFIER,
+            std::process::id(),
+            self.message().replace('\n', " "),
+            pairs.join(" ")
+        )
+    }
+
+    fn json_line(&self) -> String {
+        let mut value = serde_json::to_value(self).expect(crate::UNEXPECTED);
+        value["time"] = Local::now().to_rfc3339().into();
+        value.to_string()
+    }
+
+    fn send_to(&self, reporter: &Reporter) -> io::Result<()> {
+        match reporter {
+            Reporter::Journald => send_datagram(JOURNALD_SOCKET, &self.journal_entry()),
+            Reporter::Syslog => send_datagram(SYSLOG_SOCKET, self.syslog_message().as_bytes()),
+            Reporter::File { path } => append_line(path, &self.json_line()),
+        }
+    }
+}
+
+fn send_datagram<P: AsRef<Path>>(socket_path: P, bytes: &[u8]) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to(bytes, socket_path)?;
+    Ok(())
+}
+
+fn append_line(file_path: &Path, line: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_path)?;
+    writeln!(file, "{}", line)
+}
+
+/// Send the report to the given reporters (warning of any that fail).
+pub fn send_report(report: &BackUpReport, reporters: &[Reporter]) {
+    for reporter in reporters.iter() {
+        if let Err(err) = report.send_to(reporter) {
+            log::warn!("{:?}: failed to report back up: {}", reporter, err);
+        }
+    }
+}
+
+/// Send the report to the configured reporters (if any).
+pub fn report_back_up(report: &BackUpReport) {
+    match global_config::get_reporters() {
+        Ok(reporters) => send_report(report, &reporters),
+        Err(err) => log::warn!("failed to report back up: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod reporters_tests {
+    use super::*;
+
+    fn report() -> BackUpReport {
+        BackUpReport {
+            archive: "home".to_string(),
+            result: BackUpResult::Success,
+            files: 12,
+            bytes: 3456,
+            repo_growth: 78,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn journal_entries_are_encoded() {
+        let entry = String::from_utf8(report().journal_entry()).unwrap();
+        assert!(entry.contains("PRIORITY=6\n"));
+        assert!(entry.contains("SYSLOG_IDENTIFIER=ergibus\n"));
+        assert!(entry.contains("ERGIBUS_ARCHIVE=home\n"));
+        assert!(entry.contains("ERGIBUS_RESULT=success\n"));
+        assert!(entry.contains("ERGIBUS_BYTES=3456\n"));
+        let mut failed = report();
+        failed.result = BackUpResult::Failure;
+        failed.error = Some("one\ntwo".to_string());
+        let entry = failed.journal_entry();
+        let mut expected = b"MESSAGE\n".to_vec();
+        let message = "home: back up failed: one\ntwo";
+        expected.extend_from_slice(&(message.len() as u64).to_le_bytes());
+        expected.extend_from_slice(message.as_bytes());
+        expected.extend_from_slice(b"\nPRIORITY=3\n");
+        assert!(entry.starts_with(&expected));
+    }
+
+    #[test]
+    fn syslog_messages_carry_the_fields() {
+        let message = report().syslog_message();
+        assert!(message.starts_with("<14>"));
+        assert!(message.contains("ergibus["));
+        assert!(message.contains("archive=\"home\" result=\"success\" files=\"12\""));
+    }
+
+    #[test]
+    fn reports_are_delivered() {
+        let temp_dir = tempdir::TempDir::new("REPORTERS_TEST").unwrap();
+        let socket_path = temp_dir.path().join("socket");
+        let socket = UnixDatagram::bind(&socket_path).unwrap();
+        send_datagram(&socket_path, &report().journal_entry()).unwrap();
+        let mut buffer = [0u8; 1024];
+        let size = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], &report().journal_entry()[..]);
+
+        let log_path = temp_dir.path().join("back_ups.log");
+        let reporters = vec![Reporter::File {
+            path: log_path.clone(),
+        }];
+        send_report(&report(), &reporters);
+        send_report(&report(), &reporters);
+        let text = std::fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let value: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(value["archive"], "home");
+        assert_eq!(value["result"], "success");
+        assert_eq!(value["repo_growth"], 78);
+        assert!(value["time"].is_string());
+    }
+
+    #[test]
+    fn reporters_are_configured_in_yaml() {
+        let text = "- kind: journald\n- kind: syslog\n- kind: file\n  path: /tmp/ergibus.log\n";
+        let reporters: Vec<Reporter> = serde_yaml::from_str(text).unwrap();
+        assert_eq!(
+            reporters,
+            vec![
+                Reporter::Journald,
+                Reporter::Syslog,
+                Reporter::File {
+                    path: PathBuf::from("/tmp/ergibus.log")
+                }
+            ]
+        );
+    }
+}
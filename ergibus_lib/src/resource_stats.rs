@@ -0,0 +1,161 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! The resources used by back ups (so that users can tune their archives
+//! and repositories).  Snapshot generation counts where its time went
+//! (returning the counts with the snapshot's statistics) and
+//! `ResourceMeter` combines them with the process's CPU time and peak
+//! memory use.
+
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+use dychatat_lib::content::StoreStats;
+
+/// Where the time went while making snapshots.
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct PhaseTimes {
+    /// Walking the file system (and everything not included below)
+    pub scan: Duration,
+    /// Calculating content digests
+    pub hash: Duration,
+    /// Compressing and writing new contents to the repository
+    pub store: Duration,
+    /// Writing snapshot files (and checking them)
+    pub serialize: Duration,
+}
+
+impl AddAssign for PhaseTimes {
+    fn add_assign(&mut self, rhs: Self) {
+        self.scan += rhs.scan;
+        self.hash += rhs.hash;
+        self.store += rhs.store;
+        self.serialize += rhs.serialize;
+    }
+}
+
+/// Where the time went while making a snapshot and how much was written
+/// to the repository.
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct ResourceCounters {
+    pub phase_times: PhaseTimes,
+    pub repo_bytes_written: u64,
+}
+
+impl ResourceCounters {
+    /// Record the time taken to add paths to a snapshot and how much of it
+    /// was spent hashing and storing contents.
+    pub(crate) fn record_generation(&mut self, duration: Duration, store_stats: StoreStats) {
+        self.phase_times += PhaseTimes {
+            scan: duration.saturating_sub(store_stats.hash_time + store_stats.store_time),
+            hash: store_stats.hash_time,
+            store: store_stats.store_time,
+            serialize: Duration::ZERO,
+        };
+        self.repo_bytes_written += store_stats.bytes_written;
+    }
+
+    pub(crate) fn record_serialization(&mut self, duration: Duration) {
+        self.phase_times.serialize += duration;
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ProcessUsage {
+    user_cpu_time: Duration,
+    system_cpu_time: Duration,
+    peak_rss_kib: u64,
+}
+
+fn timeval_duration(timeval: libc::timeval) -> Duration {
+    Duration::new(timeval.tv_sec as u64, timeval.tv_usec as u32 * 1000)
+}
+
+fn process_usage() -> ProcessUsage {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        log::warn!("getrusage: {}", std::io::Error::last_os_error());
+        return ProcessUsage::default();
+    }
+    ProcessUsage {
+        user_cpu_time: timeval_duration(usage.ru_utime),
+        system_cpu_time: timeval_duration(usage.ru_stime),
+        // Linux reports kilobytes
+        peak_rss_kib: usage.ru_maxrss as u64,
+    }
+}
+
+/// The resources used between starting and finishing a `ResourceMeter`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ResourceStats {
+    pub wall_time: Duration,
+    pub phase_times: PhaseTimes,
+    pub user_cpu_time: Duration,
+    pub system_cpu_time: Duration,
+    /// The process's peak resident set size so far (it can't be reset)
+    pub peak_rss_kib: u64,
+    pub repo_bytes_written: u64,
+}
+
+/// Measure the resources used by a back up.
+pub struct ResourceMeter {
+    started: Instant,
+    usage: ProcessUsage,
+}
+
+impl ResourceMeter {
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            usage: process_usage(),
+        }
+    }
+
+    /// The resources used since the meter started with the phase times
+    /// and bytes written taken from the back up's `counters`.
+    pub fn finish(&self, counters: &ResourceCounters) -> ResourceStats {
+        let usage = process_usage();
+        ResourceStats {
+            wall_time: self.started.elapsed(),
+            phase_times: counters.phase_times,
+            user_cpu_time: usage.user_cpu_time.saturating_sub(self.usage.user_cpu_time),
+            system_cpu_time: usage
+                .system_cpu_time
+                .saturating_sub(self.usage.system_cpu_time),
+            peak_rss_kib: usage.peak_rss_kib,
+            repo_bytes_written: counters.repo_bytes_written,
+        }
+    }
+}
+
+#[cfg(test)]
+mod resource_stats_tests {
+    use super::*;
+
+    #[test]
+    fn phases_are_metered() {
+        let meter = ResourceMeter::start();
+        let mut counters = ResourceCounters::default();
+        counters.record_generation(
+            Duration::from_millis(10),
+            StoreStats {
+                hash_time: Duration::from_millis(3),
+                store_time: Duration::from_millis(2),
+                bytes_written: 7,
+            },
+        );
+        counters.record_serialization(Duration::from_millis(4));
+        let stats = meter.finish(&counters);
+        assert_eq!(
+            stats.phase_times,
+            PhaseTimes {
+                scan: Duration::from_millis(5),
+                hash: Duration::from_millis(3),
+                store: Duration::from_millis(2),
+                serialize: Duration::from_millis(4),
+            }
+        );
+        assert_eq!(stats.repo_bytes_written, 7);
+        assert!(stats.peak_rss_kib > 0);
+        assert!(stats.wall_time <= meter.started.elapsed());
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright 2021 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hex::{FromHex, ToHex};
+
+use crate::atomic_file;
+use crate::snapshot::{self, Order};
+use crate::{archive, config, EResult, Error};
+
+pub(crate) const SIGNATURE_EXTENSION: &str = "sig";
+
+// Stored alongside the snapshot file (with the extension "sig")
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct SnapshotSignature {
+    public_key: String,
+    signature: String,
+}
+
+// Snapshot directory paths mapped to the public key trusted for that directory
+type TrustedKeys = BTreeMap<PathBuf, String>;
+
+fn read_trusted_keys(file_path: &Path) -> EResult<TrustedKeys> {
+    match File::open(file_path) {
+        Ok(file) => serde_yaml::from_reader(&file)
+            .map_err(|err| Error::TrustedKeysYamlError(err, file_path.to_path_buf())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(TrustedKeys::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Replaced atomically as a truncated file would lose the trusted keys
+fn write_trusted_keys(file_path: &Path, trusted_keys: &TrustedKeys) -> EResult<()> {
+    atomic_file::replace_file(file_path, |writer| {
+        serde_yaml::to_writer(writer, trusted_keys)
+            .map_err(|err| Error::TrustedKeysYamlError(err, file_path.to_path_buf()))
+    })
+}
+
+fn signing_key_file_path(archive_name: &str) -> PathBuf {
+    config::get_signing_keys_dir_path().join(archive_name)
+}
+
+fn read_signing_key(key_file_path: &Path) -> EResult<Option<SigningKey>> {
+    let key_text = match fs::read_to_string(key_file_path) {
+        Ok(key_text) => key_text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let bytes = Vec::<u8>::from_hex(key_text.trim())
+        .map_err(|_| Error::SigningKeyMalformed(key_file_path.to_path_buf()))?;
+    let bytes = <[u8; 32]>::try_from(bytes.as_slice())
+        .map_err(|_| Error::SigningKeyMalformed(key_file_path.to_path_buf()))?;
+    Ok(Some(SigningKey::from_bytes(&bytes)))
+}
+
+fn write_signing_key(key_file_path: &Path, signing_key: &SigningKey) -> EResult<()> {
+    if let Some(dir_path) = key_file_path.parent() {
+        fs::create_dir_all(dir_path)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(key_file_path)?;
+    writeln!(file, "{}", signing_key.to_bytes().to_hex())?;
+    Ok(())
+}
+
+// Trust on first use: the first key seen for a snapshot directory is the
+// one that it must be signed with thereafter.  Returns true if the key
+// has been added to those trusted.
+fn check_trust(
+    trusted_keys: &mut TrustedKeys,
+    snapshot_dir_path: &Path,
+    public_key: &str,
+) -> EResult<bool> {
+    match trusted_keys.get(snapshot_dir_path) {
+        Some(trusted_key) if trusted_key == public_key => Ok(false),
+        Some(_) => Err(Error::SnapshotUntrustedKey(snapshot_dir_path.to_path_buf())),
+        None => {
+            trusted_keys.insert(snapshot_dir_path.to_path_buf(), public_key.to_string());
+            Ok(true)
+        }
+    }
+}
+
+fn write_signature(signing_key: &SigningKey, ss_file_path: &Path) -> EResult<()> {
+    let bytes = fs::read(ss_file_path)?;
+    let signature = SnapshotSignature {
+        public_key: signing_key.verifying_key().to_bytes().to_hex(),
+        signature: signing_key.sign(&bytes).to_bytes().to_hex(),
+    };
+    let sig_file_path = signature_file_path(ss_file_path);
+    atomic_file::replace_file(&sig_file_path, |writer| {
+        serde_json::to_writer(writer, &signature).map_err(Error::SnapshotSerializeError)
+    })
+    .map_err(|err| match err {
+        Error::IOError(err) => Error::SnapshotWriteIOError(err, sig_file_path.clone()),
+        err => err,
+    })
+}
+
+// Returns the signer's public key if the snapshot has been signed
+fn check_signature(ss_file_path: &Path, bytes: &[u8]) -> EResult<Option<String>> {
+    let sig_file_path = signature_file_path(ss_file_path);
+    let file = match File::open(&sig_file_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::SnapshotReadIOError(err, sig_file_path)),
+    };
+    let signature: SnapshotSignature = serde_json::from_reader(file)
+        .map_err(|err| Error::SnapshotReadJsonError(err, sig_file_path.clone()))?;
+    let bad_signature = || Error::SnapshotBadSignature(ss_file_path.to_path_buf());
+    let key_bytes = Vec::<u8>::from_hex(&signature.public_key).map_err(|_| bad_signature())?;
+    let key_bytes = <[u8; 32]>::try_from(key_bytes.as_slice()).map_err(|_| bad_signature())?;
+    let verifying_key = VerifyingKey::from_bytes(&key_bytes).map_err(|_| bad_signature())?;
+    let sig_bytes = Vec::<u8>::from_hex(&signature.signature).map_err(|_| bad_signature())?;
+    let sig_bytes = <[u8; 64]>::try_from(sig_bytes.as_slice()).map_err(|_| bad_signature())?;
+    verifying_key
+        .verify(bytes, &Signature::from_bytes(&sig_bytes))
+        .map_err(|_| bad_signature())?;
+    Ok(Some(signature.public_key))
+}
+
+/// Generate the key pair used to sign the archive's snapshots and trust
+/// it for the archive's snapshot directory.  The archive's existing
+/// (unsigned) snapshots are signed with it as unsigned snapshots aren't
+/// loaded from directories with trusted keys.  Returns the public key (in hex).
+pub fn generate_signing_key(archive_name: &str) -> EResult<String> {
+    let snapshot_dir_path = archive::get_archive_snapshot_dir_path(archive_name)?;
+    let key_file_path = signing_key_file_path(archive_name);
+    if key_file_path.exists() {
+        return Err(Error::SigningKeyExists(archive_name.to_string()));
+    }
+    let mut secret = [0u8; 32];
+    getrandom::getrandom(&mut secret).map_err(|err| Error::IOError(err.into()))?;
+    let signing_key = SigningKey::from_bytes(&secret);
+    write_signing_key(&key_file_path, &signing_key)?;
+    for ss_file_path in snapshot::get_snapshot_paths_in_dir(&snapshot_dir_path, Order::Ascending)? {
+        if !signature_file_path(&ss_file_path).exists() {
+            write_signature(&signing_key, &ss_file_path)?;
+        }
+    }
+    let public_key = signing_key.verifying_key().to_bytes().to_hex();
+    let trusted_keys_file_path = config::get_trusted_keys_file_path();
+    let mut trusted_keys = read_trusted_keys(&trusted_keys_file_path)?;
+    trusted_keys.insert(snapshot_dir_path, public_key.clone());
+    write_trusted_keys(&trusted_keys_file_path, &trusted_keys)?;
+    Ok(public_key)
+}
+
+pub fn has_signing_key(archive_name: &str) -> bool {
+    signing_key_file_path(archive_name).exists()
+}
+
+// Sign the snapshot file if the archive has a signing key
+pub(crate) fn sign_snapshot_file(archive_name: &str, ss_file_path: &Path) -> EResult<()> {
+    match read_signing_key(&signing_key_file_path(archive_name))? {
+        Some(signing_key) => write_signature(&signing_key, ss_file_path),
+        None => Ok(()),
+    }
+}
+
+// Check the snapshot file's contents (`bytes`) against its signature (if
+// any).  Unsigned snapshots are refused if `require_signed` is true or a
+// key is trusted for their directory.
+pub(crate) fn verify_snapshot_file(
+    ss_file_path: &Path,
+    bytes: &[u8],
+    require_signed: bool,
+) -> EResult<()> {
+    match check_signature(ss_file_path, bytes)? {
+        Some(public_key) => {
+            let snapshot_dir_path = match ss_file_path.parent() {
+                Some(dir_path) => dir_path.canonicalize()?,
+                None => return Err(Error::SnapshotBadSignature(ss_file_path.to_path_buf())),
+            };
+            let trusted_keys_file_path = config::get_trusted_keys_file_path();
+            let mut trusted_keys = read_trusted_keys(&trusted_keys_file_path)?;
+            if check_trust(&mut trusted_keys, &snapshot_dir_path, &public_key)? {
+                log::info!(
+                    "{:?}: trusting signing key {} on first use",
+                    snapshot_dir_path,
+                    public_key
+                );
+                write_trusted_keys(&trusted_keys_file_path, &trusted_keys)?;
+            }
+            Ok(())
+        }
+        None if require_signed => Err(Error::SnapshotNotSigned(ss_file_path.to_path_buf())),
+        None => {
+            // a missing signature mustn't get around a key trusted for the directory
+            let snapshot_dir_path = match ss_file_path.parent() {
+                Some(dir_path) => dir_path.canonicalize()?,
+                None => return Ok(()),
+            };
+            let trusted_keys = read_trusted_keys(&config::get_trusted_keys_file_path())?;
+            if trusted_keys.contains_key(&snapshot_dir_path) {
+                Err(Error::SnapshotNotSigned(ss_file_path.to_path_buf()))
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The path of the file containing the snapshot file's signature.
+pub fn signature_file_path(ss_file_path: &Path) -> PathBuf {
+    ss_file_path.with_extension(SIGNATURE_EXTENSION)
+}
+
+#[cfg(test)]
+mod signing_tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::fixture::{ArchivedFixture, FixtureSpec, TestConfigGuard};
+    use crate::snapshot::SnapshotPersistentData;
+    use dychatat_lib::content::create_new_repo;
+    use std::sync::Arc;
+
+    #[test]
+    fn signatures_are_checked() {
+        let dir = tempdir::TempDir::new("SIGNING_TEST").unwrap();
+        let key_file_path = dir.path().join("key");
+        assert!(read_signing_key(&key_file_path).unwrap().is_none());
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        write_signing_key(&key_file_path, &signing_key).unwrap();
+        let signing_key = read_signing_key(&key_file_path).unwrap().unwrap();
+        assert!(write_signing_key(&key_file_path, &signing_key).is_err());
+
+        let ss_file_path = dir.path().join("2021-01-01-00-00-00+1000");
+        fs::write(&ss_file_path, b"snapshot").unwrap();
+        assert!(check_signature(&ss_file_path, b"snapshot")
+            .unwrap()
+            .is_none());
+        write_signature(&signing_key, &ss_file_path).unwrap();
+        let public_key = check_signature(&ss_file_path, b"snapshot")
+            .unwrap()
+            .unwrap();
+        assert_eq!(public_key, signing_key.verifying_key().to_bytes().to_hex());
+        assert!(matches!(
+            check_signature(&ss_file_path, b"tampered"),
+            Err(Error::SnapshotBadSignature(_))
+        ));
+
+        let mut trusted_keys = TrustedKeys::new();
+        assert!(check_trust(&mut trusted_keys, dir.path(), &public_key).unwrap());
+        assert!(!check_trust(&mut trusted_keys, dir.path(), &public_key).unwrap());
+        assert!(matches!(
+            check_trust(&mut trusted_keys, dir.path(), "0123"),
+            Err(Error::SnapshotUntrustedKey(_))
+        ));
+        let trusted_keys_file_path = dir.path().join("trusted_keys");
+        write_trusted_keys(&trusted_keys_file_path, &trusted_keys).unwrap();
+        assert_eq!(
+            read_trusted_keys(&trusted_keys_file_path).unwrap(),
+            trusted_keys
+        );
+    }
+
+    #[test]
+    fn unsigned_snapshots_are_refused_if_signatures_are_required() {
+        let archived = ArchivedFixture::new(
+            "test_unsigned",
+            FixtureSpec::new().file("a.txt", "some text"),
+        );
+        let ss_file_path = archived.back_up();
+        assert!(SnapshotPersistentData::from_file_verified(&ss_file_path, false).is_ok());
+        assert!(matches!(
+            SnapshotPersistentData::from_file_verified(&ss_file_path, true),
+            Err(Error::SnapshotNotSigned(_))
+        ));
+        let mut snapshots = archive::Snapshots::try_from("test_unsigned").unwrap();
+        assert!(snapshots.read_snapshot(&ss_file_path).is_ok());
+        snapshots.set_require_signed(true);
+        assert!(snapshots.read_snapshot(&ss_file_path).is_err());
+    }
+
+    #[test]
+    fn signatures_of_trusted_snapshots_cant_be_removed() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new().file("a.txt", "some text").build();
+        archive::create_new_archive(
+            "test_signed",
+            Some("test_repo"),
+            &location,
+            &[fixture.root().to_path_buf()],
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        let earlier = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        let clock = Arc::new(ManualClock::new(earlier, std::time::Duration::from_secs(1)));
+        snapshot::generate_snapshot_with_clock("test_signed", clock).unwrap();
+        generate_signing_key("test_signed").unwrap();
+        snapshot::generate_snapshot("test_signed").unwrap();
+        let ss_file_paths =
+            snapshot::get_snapshot_paths_for_archive("test_signed", Order::Descending).unwrap();
+        // including the one made before there was a key
+        for ss_file_path in ss_file_paths.iter() {
+            assert!(SnapshotPersistentData::from_file(ss_file_path).is_ok());
+        }
+        let ss_file_path = &ss_file_paths[0];
+        fs::remove_file(signature_file_path(ss_file_path)).unwrap();
+        assert!(matches!(
+            SnapshotPersistentData::from_file(ss_file_path),
+            Err(Error::SnapshotNotSigned(_))
+        ));
+    }
+}
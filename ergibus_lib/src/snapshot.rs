@@ -1,26 +1,47 @@
 // Copyright 2021 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
 
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
 use std::ffi::{OsStr, OsString};
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Component, Path, PathBuf};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{fs, time};
 
 use chrono::{DateTime, Local};
+use globset::Glob;
 use log::*;
 use path_ext::{absolute_path_buf, PathType};
 use path_utilities::UsableDirEntry;
+use schemars::JsonSchema;
 use serde::Serialize;
 use window_sort_iterator::WindowSortIterExt;
 
-use crate::archive::{get_archive_data, ArchiveData, Exclusions};
-use crate::fs_objects::{DirectoryData, ExtractionStats, FileData, SymLinkData};
-use crate::fs_objects::{FileStats, SymLinkStats};
-use crate::report::ignore_report_or_fail;
+use crate::archive::{get_archive_data, ArchiveData, BudgetAction, Exclusions, InclusionExpansion};
+use crate::attributes::AttributesIfce;
+use crate::audit::{self, AuditOperation};
+use crate::checksums;
+use crate::clock::{self, Clock, ManualClock, SystemClock};
+use crate::content_keys::{ContentKeys, Overflow};
+use crate::encryption;
+use crate::file_types::TypeBreakdown;
+use crate::free_space;
+use crate::fs_objects::{self, CopyOptions, DirectoryData, ExtractionStats, FileData, SymLinkData};
+use crate::fs_objects::{ContentTokenUsage, FileStats, SymLinkStats};
+use crate::global_config;
+use crate::owner_map::{OwnerMap, OwnerMapping, OwnerNames};
+use crate::progress::{self, ProgressEvents};
+use crate::read_policy::{self, ReadPolicy};
+use crate::report::{self, Severity};
+use crate::resource_stats::ResourceCounters;
+use crate::signing;
+use crate::snapshot_diff;
 use crate::{archive, EResult, Error, UNEXPECTED};
-use dychatat_lib::content::ContentMgmtKey;
+use dychatat_lib::content::{self, ContentManagerOptions, ContentMgmtKey, ContentStore};
 
 fn get_entry_for_path<P: AsRef<Path>>(path_arg: P) -> EResult<fs::DirEntry> {
     let path = path_arg.as_ref();
@@ -36,38 +57,65 @@ fn get_entry_for_path<P: AsRef<Path>>(path_arg: P) -> EResult<fs::DirEntry> {
     Err(io_error.into())
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug)]
 pub struct SnapshotPersistentData {
+    #[serde(deserialize_with = "fs_objects::deserialize_dir_tree")]
     root_dir: DirectoryData,
+    #[serde(with = "fs_objects::os_path")]
+    #[schemars(with = "fs_objects::os_path::StoredPath")]
     base_dir_path: PathBuf,
     content_mgmt_key: ContentMgmtKey,
+    /// Where the contents of large files went (if anywhere else)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    overflow: Option<Overflow>,
     archive_name: String,
     started_create: time::SystemTime,
     finished_create: time::SystemTime,
     file_stats: FileStats,
     sym_link_stats: SymLinkStats,
+    /// `DirectoryData::tree_hash()` for the root directory (not
+    /// present in snapshots made by older versions)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tree_hash: Option<String>,
+    /// The names of the snapshot's owners (for matching them by name
+    /// when restoring on another machine)
+    #[serde(default, skip_serializing_if = "OwnerNames::is_empty")]
+    owner_names: OwnerNames,
+    /// What the archive's glob inclusions matched
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    inclusion_expansions: Vec<InclusionExpansion>,
 }
 
 impl TryFrom<&ArchiveData> for SnapshotPersistentData {
     type Error = Error;
 
     fn try_from(archive_data: &ArchiveData) -> EResult<Self> {
+        Self::new(archive_data, &SystemClock)
+    }
+}
+
+impl SnapshotPersistentData {
+    // A new (empty) snapshot whose creation starts now (according to `clock`)
+    fn new(archive_data: &ArchiveData, clock: &dyn Clock) -> EResult<Self> {
         let root_dir = DirectoryData::try_new(Component::RootDir)?;
         let base_dir_path = root_dir.path.clone();
+        let now = clock.now();
         Ok(Self {
             root_dir,
             base_dir_path,
             content_mgmt_key: archive_data.content_mgmt_key.clone(),
+            overflow: archive_data.overflow.clone(),
             archive_name: archive_data.name.clone(),
-            started_create: time::SystemTime::now(),
-            finished_create: time::SystemTime::now(),
+            started_create: now,
+            finished_create: now,
             file_stats: FileStats::default(),
             sym_link_stats: SymLinkStats::default(),
+            tree_hash: None,
+            owner_names: OwnerNames::default(),
+            inclusion_expansions: archive_data.inclusion_expansions.clone(),
         })
     }
-}
 
-impl SnapshotPersistentData {
     fn serialize(&self) -> EResult<String> {
         match serde_json::to_string(self) {
             Ok(string) => Ok(string),
@@ -75,26 +123,35 @@ impl SnapshotPersistentData {
         }
     }
 
-    fn release_contents(&self) -> EResult<()> {
+    fn release_contents(&self) -> EResult<u64> {
         let content_mgr = self
-            .content_mgmt_key
-            .open_content_manager(dychatat_lib::Mutability::Mutable)?;
+            .relocated_content_keys()?
+            .open_content_store(dychatat_lib::Mutability::Mutable)?;
         self.root_dir.release_contents(&content_mgr)
     }
 
-    fn add_dir(&mut self, abs_dir_path: &Path, exclusions: &Exclusions) -> EResult<u64> {
+    fn add_dir(
+        &mut self,
+        abs_dir_path: &Path,
+        exclusions: &Exclusions,
+        content_mgr: &dyn ContentStore,
+        max_depth: Option<usize>,
+        read_policy: &ReadPolicy,
+    ) -> EResult<u64> {
         let dir = self.root_dir.find_or_add_subdir(&abs_dir_path)?;
-        let content_mgr = self
-            .content_mgmt_key
-            .open_content_manager(dychatat_lib::Mutability::Mutable)?;
         let (file_stats, sym_link_stats, delta_repo_size) =
-            dir.populate(exclusions, &content_mgr)?;
+            dir.populate(exclusions, content_mgr, max_depth, read_policy)?;
         self.file_stats += file_stats;
         self.sym_link_stats += sym_link_stats;
         Ok(delta_repo_size)
     }
 
-    fn add_other(&mut self, abs_file_path: &Path) -> EResult<u64> {
+    fn add_other(
+        &mut self,
+        abs_file_path: &Path,
+        content_mgr: &dyn ContentStore,
+        read_policy: &ReadPolicy,
+    ) -> EResult<u64> {
         let entry = get_entry_for_path(abs_file_path)?;
         let dir_path = abs_file_path.parent().expect(UNEXPECTED);
         let dir = self.root_dir.find_or_add_subdir(&dir_path)?;
@@ -104,16 +161,18 @@ impl SnapshotPersistentData {
                 Ok(_) => (),
                 Err(index) => {
                     if e_type.is_file() {
-                        let content_mgr = self
-                            .content_mgmt_key
-                            .open_content_manager(dychatat_lib::Mutability::Mutable)?;
-                        match FileData::file_system_object(abs_file_path, &content_mgr) {
+                        match FileData::file_system_object(abs_file_path, content_mgr, read_policy)
+                        {
                             Ok((file_system_object, stats, delta)) => {
                                 self.file_stats += stats;
                                 delta_repo_size = delta;
                                 dir.contents.insert(index, file_system_object);
                             }
-                            Err(err) => ignore_report_or_fail(err.into(), abs_file_path)?,
+                            Err(err) => read_policy.skip_file_or_fail(
+                                err,
+                                abs_file_path,
+                                &mut self.file_stats,
+                            )?,
                         }
                     } else if e_type.is_symlink() {
                         match SymLinkData::file_system_object(abs_file_path) {
@@ -121,25 +180,43 @@ impl SnapshotPersistentData {
                                 self.sym_link_stats += stats;
                                 dir.contents.insert(index, file_system_object);
                             }
-                            Err(err) => ignore_report_or_fail(err.into(), abs_file_path)?,
+                            Err(err) => read_policy.ignore_report_or_fail(err, abs_file_path)?,
                         }
+                    } else {
+                        let kind = read_policy::special_file_kind(&e_type);
+                        let err =
+                            Error::FSOSpecialFile(abs_file_path.to_path_buf(), kind.to_string());
+                        read_policy.ignore_report_or_fail(err, abs_file_path)?
                     }
                 }
             },
-            Err(err) => ignore_report_or_fail(err.into(), abs_file_path)?,
+            Err(err) => read_policy.ignore_report_or_fail(err.into(), abs_file_path)?,
         };
         Ok(delta_repo_size)
     }
 
-    fn add<P: AsRef<Path>>(&mut self, path_arg: P, exclusions: &Exclusions) -> EResult<u64> {
+    fn add<P: AsRef<Path>>(
+        &mut self,
+        path_arg: P,
+        exclusions: &Exclusions,
+        content_mgr: &dyn ContentStore,
+        max_depth: Option<usize>,
+        read_policy: &ReadPolicy,
+    ) -> EResult<u64> {
         if path_arg.as_ref().symlink_metadata()?.file_type().is_dir() {
-            self.add_dir(path_arg.as_ref(), exclusions)
+            self.add_dir(
+                path_arg.as_ref(),
+                exclusions,
+                content_mgr,
+                max_depth,
+                read_policy,
+            )
         } else {
-            self.add_other(path_arg.as_ref())
+            self.add_other(path_arg.as_ref(), content_mgr, read_policy)
         }
     }
 
-    fn creation_duration(&self) -> time::Duration {
+    pub fn creation_duration(&self) -> time::Duration {
         match self.finished_create.duration_since(self.started_create) {
             Ok(duration) => duration,
             Err(_) => time::Duration::new(0, 0),
@@ -168,10 +245,17 @@ impl SnapshotPersistentData {
         let json_text = self.serialize()?;
         let stats = SnapshotStats::from(self);
         let stats_json_text = stats.serialize()?;
-        let mut snappy_wtr = snap::write::FrameEncoder::new(file);
+        let mut snappy_wtr = snap::write::FrameEncoder::new(vec![]);
         snappy_wtr
             .write_all(json_text.as_bytes())
             .map_err(|err| Error::SnapshotWriteIOError(err, path.to_path_buf()))?;
+        let bytes = snappy_wtr
+            .into_inner()
+            .map_err(|err| Error::SnapshotWriteIOError(err.into_error(), path.to_path_buf()))?;
+        let bytes = encryption::seal_snapshot_bytes(&self.archive_name, bytes, &path)?;
+        let mut file = file;
+        file.write_all(&bytes)
+            .map_err(|err| Error::SnapshotWriteIOError(err, path.to_path_buf()))?;
         let mut snappy_wtr = snap::write::FrameEncoder::new(stats_file);
         if let Err(err) = snappy_wtr.write_all(stats_json_text.as_bytes()) {
             fs::remove_file(path)?;
@@ -185,11 +269,24 @@ impl SnapshotPersistentData {
     // Interrogation/extraction/restoration methods
 
     pub fn from_file<P: AsRef<Path>>(file_path_arg: P) -> EResult<SnapshotPersistentData> {
+        Self::from_file_verified(file_path_arg, false)
+    }
+
+    /// Read a snapshot from its file refusing it if it hasn't been signed
+    /// and `require_signed` is true.  Signed snapshots are always verified
+    /// and unsigned ones are always refused if a key is trusted for their
+    /// directory.
+    pub fn from_file_verified<P: AsRef<Path>>(
+        file_path_arg: P,
+        require_signed: bool,
+    ) -> EResult<SnapshotPersistentData> {
         let file_path = file_path_arg.as_ref();
-        match File::open(file_path) {
-            Ok(file) => {
+        match fs::read(file_path) {
+            Ok(bytes) => {
+                signing::verify_snapshot_file(file_path, &bytes, require_signed)?;
+                let bytes = encryption::open_snapshot_bytes(bytes, file_path)?;
                 let mut spd_str = String::new();
-                let mut snappy_rdr = snap::read::FrameDecoder::new(file);
+                let mut snappy_rdr = snap::read::FrameDecoder::new(bytes.as_slice());
                 match snappy_rdr.read_to_string(&mut spd_str) {
                     Err(err) => {
                         return Err(Error::SnapshotReadIOError(err, file_path.to_path_buf()))
@@ -214,14 +311,101 @@ impl SnapshotPersistentData {
         self.base_dir_path.as_path()
     }
 
+    pub fn root_dir(&self) -> &DirectoryData {
+        &self.root_dir
+    }
+
     pub fn root_dir_path(&self) -> &Path {
         self.root_dir.path()
     }
 
+    /// When the snapshot's creation started and finished.
+    pub fn creation_times(&self) -> (time::SystemTime, time::SystemTime) {
+        (self.started_create, self.finished_create)
+    }
+
+    pub fn file_stats(&self) -> &FileStats {
+        &self.file_stats
+    }
+
+    /// The snapshot's files by category (taken from its tree for snapshots
+    /// made before the breakdown was recorded).
+    pub fn type_breakdown(&self) -> TypeBreakdown {
+        let type_breakdown = self.file_stats.type_breakdown;
+        if type_breakdown.file_count() < self.file_stats.file_count {
+            self.root_dir.type_breakdown()
+        } else {
+            type_breakdown
+        }
+    }
+
+    pub fn sym_link_stats(&self) -> &SymLinkStats {
+        &self.sym_link_stats
+    }
+
     pub fn content_mgmt_key(&self) -> &ContentMgmtKey {
         &self.content_mgmt_key
     }
 
+    /// The keys for all of the repositories holding the snapshot's contents.
+    pub fn content_keys(&self) -> ContentKeys {
+        ContentKeys::new(&self.content_mgmt_key, self.overflow.as_ref())
+    }
+
+    /// The distinct content tokens of the snapshot's files (in token
+    /// order) with the number of files using each and their sizes.
+    pub fn content_tokens(&self) -> impl Iterator<Item = (&str, ContentTokenUsage)> {
+        let mut usage = BTreeMap::new();
+        self.root_dir.collect_content_token_usage(&mut usage);
+        usage.into_iter()
+    }
+
+    /// The paths that the archive's glob inclusions matched when the
+    /// snapshot was made.
+    pub fn inclusion_expansions(&self) -> &[InclusionExpansion] {
+        &self.inclusion_expansions
+    }
+
+    /// The hash of the snapshot's tree recorded when it was made.
+    pub fn tree_hash(&self) -> Option<&str> {
+        self.tree_hash.as_deref()
+    }
+
+    /// How the owners recorded in the snapshot are to be translated (as
+    /// specified by `owner_map`) when its contents are restored.
+    pub fn owner_mapping(&self, owner_map: &OwnerMap) -> OwnerMapping {
+        OwnerMapping::new(owner_map, &self.owner_names)
+    }
+
+    /// Whether the snapshot's tree still matches the hash recorded when
+    /// it was made (e.g. after copying it to another repository).  `None`
+    /// if no hash was recorded.
+    pub fn verify_tree_hash(&self) -> Option<bool> {
+        self.tree_hash
+            .as_ref()
+            .map(|tree_hash| *tree_hash == self.root_dir.tree_hash())
+    }
+
+    /// Whether the two snapshots are known to have identical trees (as far
+    /// as `snapshot_diff` is concerned) without walking them.
+    pub fn has_same_tree_hash(&self, other: &Self) -> bool {
+        match (self.tree_hash(), other.tree_hash()) {
+            (Some(hash), Some(other_hash)) => hash == other_hash,
+            _ => false,
+        }
+    }
+
+    /// Use the content repository at `repo_dir_path` instead of the one recorded
+    /// in the snapshot (e.g. when the repository has been moved or mounted elsewhere).
+    pub fn set_repo_location<P: AsRef<Path>>(&mut self, repo_dir_path: P) {
+        self.content_mgmt_key = self.content_mgmt_key.with_base_dir_path(repo_dir_path);
+    }
+
+    /// The keys with any relocations recorded via `dychatat relocate` applied
+    pub fn relocated_content_keys(&self) -> EResult<ContentKeys> {
+        self.content_keys().relocated()
+    }
+
     pub fn find_subdir<P: AsRef<Path>>(&self, dir_path_arg: P) -> EResult<&DirectoryData> {
         let dir_path = dir_path_arg.as_ref();
         match PathType::of(dir_path) {
@@ -252,35 +436,145 @@ impl SnapshotPersistentData {
         }
     }
 
+    /// Search the whole snapshot for objects whose names match the pattern.
+    /// Patterns containing glob meta characters are treated as globs and
+    /// anything else as a substring.
+    pub fn find_matching_paths(&self, pattern: &str) -> EResult<Vec<PathBuf>> {
+        if pattern.contains(|c| "*?[{".contains(c)) {
+            let matcher = Glob::new(pattern)
+                .map_err(Error::GlobError)?
+                .compile_matcher();
+            Ok(self
+                .root_dir
+                .find_matching_paths(&|name: &OsStr| matcher.is_match(name)))
+        } else {
+            Ok(self
+                .root_dir
+                .find_matching_paths(&|name: &OsStr| name.to_string_lossy().contains(pattern)))
+        }
+    }
+
+    /// Copy the file to `to_file_path` returning how many bytes were
+    /// written.  Unless `force`, there must be room for it on the target's
+    /// file system.
     pub fn copy_file_to(
         &self,
         fm_file_path: &Path,
         to_file_path: &Path,
         overwrite: bool,
+        force: bool,
     ) -> EResult<u64> {
+        let to_file_path = absolute_target_path(to_file_path)?;
         let file_data = self.find_file(fm_file_path)?;
+        if !force {
+            free_space::check_extraction_space(&to_file_path, file_data.attributes().size())?;
+        }
+        let c_mgr = self
+            .relocated_content_keys()?
+            .open_content_store(dychatat_lib::Mutability::Immutable)?;
+        file_data.copy_contents_to(&to_file_path, &c_mgr, overwrite)
+    }
+
+    /// Restore a file to where it was when the snapshot was made (creating
+    /// any missing parent directories) returning where that is and how many
+    /// bytes were written.  An existing file is moved aside unless `overwrite`
+    /// and, unless `force`, there must be room for the file.
+    pub fn restore_file(
+        &self,
+        file_path: &Path,
+        overwrite: bool,
+        force: bool,
+    ) -> EResult<(PathBuf, u64)> {
+        let file_path = match PathType::of(file_path) {
+            PathType::Absolute => file_path.to_path_buf(),
+            PathType::RelativeCurDirImplicit => self.base_dir_path.join(file_path),
+            PathType::Empty => return Err(Error::SnapshotUnknownFile(file_path.to_path_buf())),
+            _ => absolute_path_buf(file_path)
+                .map_err(|_| Error::SnapshotUnknownFile(file_path.to_path_buf()))?,
+        };
+        let file_data = self.root_dir.find_file(&file_path)?;
+        if !force {
+            free_space::check_extraction_space(&file_path, file_data.attributes().size())?;
+        }
+        if let Some(dir_path) = file_path.parent() {
+            fs::create_dir_all(dir_path)?;
+        }
+        let c_mgr = self
+            .relocated_content_keys()?
+            .open_content_store(dychatat_lib::Mutability::Immutable)?;
+        let bytes = file_data.copy_contents_to(&file_path, &c_mgr, overwrite)?;
+        Ok((file_path, bytes))
+    }
+
+    /// The first `n` bytes of the file's contents (for previews).
+    pub fn read_file_prefix(&self, file_path: &Path, n: usize) -> EResult<Vec<u8>> {
+        let file_data = self.find_file(file_path)?;
         let c_mgr = self
-            .content_mgmt_key
-            .open_content_manager(dychatat_lib::Mutability::Immutable)?;
-        Ok(file_data.copy_contents_to(to_file_path, &c_mgr, overwrite)?)
+            .relocated_content_keys()?
+            .open_content_store(dychatat_lib::Mutability::Immutable)?;
+        file_data.read_prefix(n, &c_mgr)
     }
 
     pub fn copy_dir_to(
         &self,
         fm_dir_path: &Path,
         to_dir_path: &Path,
-        overwrite: bool,
+        options: &CopyOptions,
     ) -> EResult<ExtractionStats> {
+        let to_dir_path = absolute_target_path(to_dir_path)?;
         let fm_subdir = self.find_subdir(fm_dir_path)?;
-        let stats = fm_subdir.copy_to(to_dir_path, &self.content_mgmt_key, overwrite)?;
+        if !options.force {
+            free_space::check_extraction_space(&to_dir_path, fm_subdir.total_file_bytes())?;
+        }
+        let stats = fm_subdir.copy_to(
+            &to_dir_path,
+            &self.relocated_content_keys()?,
+            &self.owner_mapping(&options.owner_map),
+            options,
+        )?;
         Ok(stats)
     }
 }
 
+/// Expand a user supplied extraction target path (which may be relative
+/// to the current directory or start with `~`) into an absolute path.
+pub fn absolute_target_path(path: &Path) -> EResult<PathBuf> {
+    absolute_path_buf(path).map_err(|err| Error::ExtractTargetPathError(err, path.to_path_buf()))
+}
+
+// Check that snapshot files can be created in the directory so that back
+// ups to read-only mounts fail before any work is done (rather than when
+// the snapshot is written).
+fn check_snapshot_dir_writable(dir_path: &Path) -> EResult<()> {
+    let probe_path = dir_path.join(format!(".write-test-{}", std::process::id()));
+    match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe_path)
+    {
+        Ok(_) => {
+            if let Err(err) = fs::remove_file(&probe_path) {
+                warn!("{:?}: failed to remove: {}", probe_path, err);
+            }
+            Ok(())
+        }
+        Err(err) if err.raw_os_error() == Some(libc::EROFS) => {
+            Err(Error::SnapshotDirReadOnly(dir_path.to_path_buf()))
+        }
+        Err(err) => Err(Error::SnapshotDirIOError(err, dir_path.to_path_buf())),
+    }
+}
+
 #[derive(Debug)]
 struct SnapshotGenerator {
     snapshot: Option<SnapshotPersistentData>,
     archive_data: ArchiveData,
+    clock: Arc<dyn Clock>,
+    options: BackUpOptions,
+    // Where the time went making (and writing) the current snapshot
+    counters: Cell<ResourceCounters>,
+    // The repositories' journal entry for the snapshot's references
+    journal_id: String,
 }
 
 impl Drop for SnapshotGenerator {
@@ -292,13 +586,32 @@ impl Drop for SnapshotGenerator {
 }
 
 impl SnapshotGenerator {
+    #[cfg(test)]
     pub fn new(archive_name: &str) -> EResult<SnapshotGenerator> {
+        Self::with_clock(archive_name, clock::system_clock())
+    }
+
+    pub fn with_clock(archive_name: &str, clock: Arc<dyn Clock>) -> EResult<SnapshotGenerator> {
+        Self::with_options(archive_name, clock, &BackUpOptions::from_global_config()?)
+    }
+
+    pub fn with_options(
+        archive_name: &str,
+        clock: Arc<dyn Clock>,
+        options: &BackUpOptions,
+    ) -> EResult<SnapshotGenerator> {
         let archive_data = get_archive_data(archive_name)?;
+        archive_data.check_policies()?;
+        check_snapshot_dir_writable(&archive_data.snapshot_dir_path)?;
         // Check that there'll be no problem starting the creation of snapshots
         let _dummy = SnapshotPersistentData::try_from(&archive_data)?;
         Ok(SnapshotGenerator {
             snapshot: None,
             archive_data,
+            clock,
+            options: options.clone(),
+            counters: Cell::new(ResourceCounters::default()),
+            journal_id: content::new_journal_id(),
         })
     }
 
@@ -307,34 +620,197 @@ impl SnapshotGenerator {
         self.snapshot.is_some()
     }
 
-    fn generate_snapshot(&mut self) -> EResult<(time::Duration, FileStats, SymLinkStats, u64)> {
+    fn generate_snapshot(&mut self) -> EResult<BackUpStats> {
+        if self.snapshot.is_some() {
+            // This snapshot is being thrown away so we release its contents
+            self.release_snapshot()?;
+        }
+        self.journal_id = content::new_journal_id();
+        self.counters.set(ResourceCounters::default());
+        let mut snapshot = SnapshotPersistentData::new(&self.archive_data, self.clock.as_ref())?;
+        let delta_repo_size = self.add_paths(&mut snapshot, &self.archive_data.includes)?;
+        Ok(self.complete_snapshot(snapshot, delta_repo_size))
+    }
+
+    // Generate a snapshot by rescanning only the `only` paths and reusing
+    // everything else from the most recent snapshot.
+    fn generate_partial_snapshot(&mut self, only: &[PathBuf]) -> EResult<BackUpStats> {
+        if self.snapshot.is_some() {
+            // This snapshot is being thrown away so we release its contents
+            self.release_snapshot()?;
+        }
+        self.journal_id = content::new_journal_id();
+        self.counters.set(ResourceCounters::default());
+        let mut abs_paths = vec![];
+        for path in only.iter() {
+            let abs_path = absolute_path_buf(path)
+                .map_err(|e| Error::ArchiveIncludePathError(e, path.to_path_buf()))?;
+            if !self
+                .archive_data
+                .includes
+                .iter()
+                .any(|inclusion| abs_path.starts_with(inclusion))
+            {
+                return Err(Error::PathNotInArchive(
+                    abs_path,
+                    self.archive_data.name.clone(),
+                ));
+            }
+            abs_paths.push(abs_path);
+        }
+        let previous_path =
+            get_snapshot_paths_in_dir(&self.archive_data.snapshot_dir_path, Order::Descending)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| Error::ArchiveEmpty(self.archive_data.name.as_str().into()))?;
+        let previous = SnapshotPersistentData::from_file(&previous_path)?;
+        let mut snapshot = SnapshotPersistentData::new(&self.archive_data, self.clock.as_ref())?;
+        snapshot.root_dir = previous.root_dir;
+        for abs_path in abs_paths.iter() {
+            snapshot.root_dir.remove_object(abs_path);
+        }
+        {
+            let content_mgr = snapshot
+                .content_keys()
+                .open_content_store(dychatat_lib::Mutability::Mutable)?;
+            content_mgr.journal_claims(&self.journal_id);
+            match snapshot.root_dir.claim_contents(&content_mgr) {
+                Ok((file_stats, sym_link_stats)) => {
+                    snapshot.file_stats = file_stats;
+                    snapshot.sym_link_stats = sym_link_stats;
+                }
+                Err(err) => {
+                    // the claims made have been released so only the journal
+                    // entry is left to tidy up (once the lock is released)
+                    drop(content_mgr);
+                    snapshot
+                        .content_keys()
+                        .discard_journal_entry(&self.journal_id)?;
+                    return Err(err);
+                }
+            }
+        }
+        let delta_repo_size = self.add_paths(&mut snapshot, &abs_paths)?;
+        Ok(self.complete_snapshot(snapshot, delta_repo_size))
+    }
+
+    // Generate a snapshot of the archive's directory `as_dir_path` from
+    // a copy of it at `from_dir_path` (e.g. made by rsync) as at the time
+    // of the copy's newest modification.
+    fn generate_imported_snapshot(
+        &mut self,
+        from_dir_path: &Path,
+        as_dir_path: &Path,
+        clock: &ManualClock,
+    ) -> EResult<BackUpStats> {
         if self.snapshot.is_some() {
             // This snapshot is being thrown away so we release its contents
             self.release_snapshot()?;
         }
+        self.journal_id = content::new_journal_id();
+        self.counters.set(ResourceCounters::default());
+        let mut dir = DirectoryData::try_new(from_dir_path)?;
+        let mut snapshot = SnapshotPersistentData::new(&self.archive_data, self.clock.as_ref())?;
+        let result = {
+            let content_mgr = snapshot.content_keys().open_content_store_with_options(
+                dychatat_lib::Mutability::Mutable,
+                &self.content_manager_options(),
+            )?;
+            content_mgr.journal_claims(&self.journal_id);
+            dir.populate(
+                &self.archive_data.exclusions,
+                &content_mgr,
+                self.archive_data.max_depth,
+                &self.options.read_policy,
+            )
+        };
+        let (secs, nsecs) = dir.newest_mtime();
+        dir.relocate(as_dir_path);
+        // NB: grafted even if incomplete so that its contents get released
+        let grafted = snapshot.root_dir.graft(dir);
+        let (file_stats, sym_link_stats, delta_repo_size) = match (result, grafted) {
+            (Ok(stats), Ok(())) => stats,
+            (Err(err), _) | (_, Err(err)) => {
+                self.release_contents(&snapshot)?;
+                return Err(err);
+            }
+        };
+        snapshot.file_stats = file_stats;
+        snapshot.sym_link_stats = sym_link_stats;
+        let mtime = if secs >= 0 {
+            time::UNIX_EPOCH + Duration::new(secs as u64, nsecs as u32)
+        } else {
+            time::UNIX_EPOCH
+        };
+        clock.set(mtime);
+        snapshot.started_create = mtime;
+        Ok(self.complete_snapshot(snapshot, delta_repo_size))
+    }
+
+    fn content_manager_options(&self) -> ContentManagerOptions {
+        ContentManagerOptions {
+            free_space_reserve: self.options.free_space_reserve,
+            ..ContentManagerOptions::default()
+        }
+    }
+
+    // Add the paths to the snapshot releasing its contents if a fatal error occurs.
+    // NB: a single content manager is used so that contents duplicated within
+    // the snapshot can be identified.
+    fn add_paths(
+        &self,
+        snapshot: &mut SnapshotPersistentData,
+        abs_paths: &[PathBuf],
+    ) -> EResult<u64> {
+        let started = Instant::now();
+        let content_mgr = snapshot.content_keys().open_content_store_with_options(
+            dychatat_lib::Mutability::Mutable,
+            &self.content_manager_options(),
+        )?;
+        content_mgr.journal_claims(&self.journal_id);
         let mut delta_repo_size: u64 = 0;
-        let mut snapshot = SnapshotPersistentData::try_from(&self.archive_data)?;
-        for abs_path in self.archive_data.includes.iter() {
-            match snapshot.add(abs_path, &self.archive_data.exclusions) {
+        for abs_path in abs_paths.iter() {
+            match snapshot.add(
+                abs_path,
+                &self.archive_data.exclusions,
+                &content_mgr,
+                self.archive_data.max_depth,
+                &self.options.read_policy,
+            ) {
                 Ok(drsz) => delta_repo_size += drsz,
                 Err(err) => match err {
                     Error::IOError(io_err) => match io_err.kind() {
                         ErrorKind::NotFound | ErrorKind::PermissionDenied => {
                             // non fatal errors so report and soldier on
-                            warn!("{:?}: {:?}", abs_path, io_err)
+                            let message = format!("{:?}", io_err);
+                            report::emit(Severity::Error, abs_path, message);
                         }
                         _ => {
-                            snapshot.release_contents()?;
+                            // release the repository lock before releasing contents
+                            drop(content_mgr);
+                            self.release_contents(snapshot)?;
                             return Err(io_err.into());
                         }
                     },
                     _ => {
-                        snapshot.release_contents()?;
+                        drop(content_mgr);
+                        self.release_contents(snapshot)?;
                         return Err(err);
                     }
                 },
             };
         }
+        let mut counters = self.counters.get();
+        counters.record_generation(started.elapsed(), content_mgr.store_stats());
+        self.counters.set(counters);
+        Ok(delta_repo_size)
+    }
+
+    fn complete_snapshot(
+        &mut self,
+        mut snapshot: SnapshotPersistentData,
+        delta_repo_size: u64,
+    ) -> BackUpStats {
         let mut base_dir = &snapshot.root_dir;
         while base_dir.contents.len() == 1 {
             if let Some(subdir) = base_dir.subdirs().next() {
@@ -344,12 +820,23 @@ impl SnapshotGenerator {
             }
         }
         snapshot.base_dir_path = base_dir.path.to_path_buf();
-        snapshot.finished_create = time::SystemTime::now();
+        snapshot.tree_hash = Some(snapshot.root_dir.tree_hash());
+        let mut uids = BTreeSet::new();
+        let mut gids = BTreeSet::new();
+        snapshot.root_dir.collect_owners(&mut uids, &mut gids);
+        snapshot.owner_names = OwnerNames::look_up(&uids, &gids);
+        snapshot.finished_create = self.clock.now();
         let duration = snapshot.creation_duration();
         let file_stats = snapshot.file_stats;
         let sym_link_stats = snapshot.sym_link_stats;
         self.snapshot = Some(snapshot);
-        Ok((duration, file_stats, sym_link_stats, delta_repo_size))
+        (
+            duration,
+            file_stats,
+            sym_link_stats,
+            delta_repo_size,
+            self.counters.get(),
+        )
     }
 
     #[cfg(test)]
@@ -360,9 +847,72 @@ impl SnapshotGenerator {
         }
     }
 
+    // Release the generated snapshot and return the path of the archive's
+    // most recent snapshot if nothing (contents, modes, ownership or link
+    // targets) has changed since it.
+    fn check_changed(&mut self) -> EResult<Option<PathBuf>> {
+        let latest_path = match get_snapshot_paths_in_dir(
+            &self.archive_data.snapshot_dir_path,
+            Order::Descending,
+        )?
+        .into_iter()
+        .next()
+        {
+            Some(latest_path) => latest_path,
+            None => return Ok(None),
+        };
+        let latest = SnapshotPersistentData::from_file(&latest_path)?;
+        let snapshot = self.snapshot.as_ref().ok_or(Error::NoSnapshotAvailable)?;
+        let unchanged = match latest.tree_hash() {
+            Some(_) => latest.has_same_tree_hash(snapshot),
+            None => {
+                snapshot_diff::diff_directories(latest.root_dir(), snapshot.root_dir()).is_empty()
+            }
+        };
+        if unchanged {
+            self.release_snapshot()?;
+            Ok(Some(latest_path))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Check the generated snapshot against the archive's budget.  Snapshots
+    // that exceed it are either kept with a warning or released.
+    fn check_budget(&mut self, snapshot_bytes: u64, repo_growth: u64) -> EResult<()> {
+        let budget = self.archive_data.budget;
+        let excesses = budget.excesses(snapshot_bytes, repo_growth);
+        if excesses.is_empty() {
+            return Ok(());
+        }
+        let message = format!("{}: {}", self.archive_data.name, excesses.join("; "));
+        match budget.on_exceeded {
+            BudgetAction::Warn => {
+                warn!("{}", message);
+                progress::notify_warning(&message);
+                Ok(())
+            }
+            BudgetAction::Abort => {
+                self.release_snapshot()?;
+                Err(Error::SnapshotOverBudget(message))
+            }
+        }
+    }
+
+    // NB: the journal entry goes first so that a crash can't release the
+    // references twice
+    fn release_contents(&self, snapshot: &SnapshotPersistentData) -> EResult<u64> {
+        snapshot
+            .content_keys()
+            .discard_journal_entry(&self.journal_id)?;
+        snapshot.release_contents()
+    }
+
     fn release_snapshot(&mut self) -> EResult<()> {
         match self.snapshot {
-            Some(ref snapshot) => snapshot.release_contents()?,
+            Some(ref snapshot) => {
+                self.release_contents(snapshot)?;
+            }
             None => (),
         }
         self.snapshot = None;
@@ -370,19 +920,56 @@ impl SnapshotGenerator {
     }
 
     fn write_snapshot(&mut self) -> EResult<PathBuf> {
+        let started = Instant::now();
+        let result = self.write_and_check_snapshot();
+        self.counters
+            .get_mut()
+            .record_serialization(started.elapsed());
+        result
+    }
+
+    fn write_and_check_snapshot(&mut self) -> EResult<PathBuf> {
         match self.snapshot {
             Some(ref snapshot) => {
+                // the references are needed if the snapshot file exists
+                let content_keys = snapshot.content_keys();
+                content_keys.guard_journal_entry(
+                    &self.journal_id,
+                    &self
+                        .archive_data
+                        .snapshot_dir_path
+                        .join(snapshot.snapshot_name()),
+                )?;
                 let (file_path, stats_file_path) =
                     snapshot.write_to_dir(&self.archive_data.snapshot_dir_path)?;
-                // check that the snapshot can be rebuilt from the file
-                match SnapshotPersistentData::from_file(&file_path) {
+                let sig_file_path = signing::signature_file_path(&file_path);
+                // check that the snapshot can be rebuilt from the (signed) file
+                match signing::sign_snapshot_file(&snapshot.archive_name, &file_path)
+                    .and_then(|_| SnapshotPersistentData::from_file(&file_path))
+                {
                     Ok(rb_snapshot) => {
                         if self.snapshot == Some(rb_snapshot) {
                             // don't release contents as references are stored in the file
                             self.snapshot = None;
+                            if let Err(err) = content_keys.discard_journal_entry(&self.journal_id) {
+                                warn!("{:?}: failed to tidy journal: {:?}", file_path, err);
+                            }
+                            if let Err(err) = checksums::record_checksum(&file_path) {
+                                warn!("{:?}: failed to record checksum: {:?}", file_path, err);
+                            }
+                            // the back up has succeeded even if this fails
+                            if let Err(err) =
+                                update_latest_pointer(&self.archive_data.snapshot_dir_path)
+                            {
+                                warn!(
+                                    "{:?}: failed to update latest pointer: {:?}",
+                                    file_path, err
+                                );
+                            }
                             Ok(file_path)
                         } else {
                             // The file is mangled so remove it
+                            fs::remove_file(sig_file_path).ok();
                             match fs::remove_file(&file_path) {
                                 Ok(_) => match fs::remove_file(stats_file_path) {
                                     _ => Err(Error::SnapshotMismatch(file_path.to_path_buf())),
@@ -395,6 +982,7 @@ impl SnapshotGenerator {
                     }
                     Err(err) => {
                         // The file is mangled so remove it
+                        fs::remove_file(sig_file_path).ok();
                         match fs::remove_file(&file_path) {
                             _ => match fs::remove_file(stats_file_path) {
                                 _ => Err(err),
@@ -408,23 +996,339 @@ impl SnapshotGenerator {
     }
 }
 
-pub fn generate_snapshot(
+/// How back ups are made.
+#[derive(Debug, Clone, Default)]
+pub struct BackUpOptions {
+    pub read_policy: ReadPolicy,
+    /// Don't store new contents that would leave fewer than this many
+    /// bytes free on the repository's file system.  `None` means don't
+    /// check.
+    pub free_space_reserve: Option<u64>,
+}
+
+impl BackUpOptions {
+    /// The default options with the free space reserve (and what to do
+    /// when it's reached) taken from the global configuration.
+    pub fn from_global_config() -> EResult<Self> {
+        let mut options = Self::default();
+        if let Some(reserve) = global_config::get_free_space_reserve()? {
+            options.free_space_reserve = Some(reserve.bytes);
+            options.read_policy.on_low_space = reserve.on_low_space;
+        }
+        Ok(options)
+    }
+}
+
+/// The statistics for a generated snapshot: how long it took, what was
+/// in it, how much the repository grew and where the time went.
+pub type BackUpStats = (
+    time::Duration,
+    FileStats,
+    SymLinkStats,
+    u64,
+    ResourceCounters,
+);
+
+/// What a back up that skips unchanged snapshots achieved.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum BackUpOutcome {
+    /// A snapshot was written (with these statistics)
+    Written(BackUpStats),
+    /// Nothing (apart from times) had changed since the archive's most
+    /// recent snapshot (at this path) so no snapshot was written
+    Unchanged(PathBuf),
+}
+
+// Write the generated snapshot unless `skip_if_unchanged` and it would
+// be the same as the archive's most recent snapshot.
+fn finish_back_up(
+    sg: &mut SnapshotGenerator,
+    mut stats: BackUpStats,
+    skip_if_unchanged: bool,
+) -> EResult<BackUpOutcome> {
+    if skip_if_unchanged {
+        if let Some(latest_path) = sg.check_changed()? {
+            return Ok(BackUpOutcome::Unchanged(latest_path));
+        }
+    }
+    sg.check_budget(stats.1.byte_count, stats.3)?;
+    sg.write_snapshot()?;
+    stats.4 = sg.counters.get();
+    Ok(BackUpOutcome::Written(stats))
+}
+
+pub fn generate_snapshot(archive_name: &str) -> EResult<BackUpStats> {
+    generate_snapshot_with_clock(archive_name, clock::system_clock())
+}
+
+/// Generate a snapshot for the archive as specified by `options`.
+pub fn generate_snapshot_with_options(
     archive_name: &str,
-) -> EResult<(time::Duration, FileStats, SymLinkStats, u64)> {
-    let mut sg = SnapshotGenerator::new(archive_name)?;
-    let stats = sg.generate_snapshot()?;
+    options: &BackUpOptions,
+) -> EResult<BackUpStats> {
+    let mut sg = SnapshotGenerator::with_options(archive_name, clock::system_clock(), options)?;
+    let mut stats = sg.generate_snapshot()?;
+    sg.check_budget(stats.1.byte_count, stats.3)?;
     sg.write_snapshot()?;
+    stats.4 = sg.counters.get();
     Ok(stats)
 }
 
-pub fn delete_snapshot_file(ss_file_path: &Path) -> EResult<()> {
-    let snapshot = SnapshotPersistentData::from_file(ss_file_path)?;
-    fs::remove_file(ss_file_path)
-        .map_err(|err| Error::SnapshotDeleteIOError(err, ss_file_path.to_path_buf()))?;
-    snapshot.release_contents()?;
+/// Generate a snapshot for the archive whose times (and hence name and
+/// creation duration) come from `clock`.
+pub fn generate_snapshot_with_clock(
+    archive_name: &str,
+    clock: Arc<dyn Clock>,
+) -> EResult<BackUpStats> {
+    let mut sg = SnapshotGenerator::with_clock(archive_name, clock)?;
+    let mut stats = sg.generate_snapshot()?;
+    sg.check_budget(stats.1.byte_count, stats.3)?;
+    sg.write_snapshot()?;
+    stats.4 = sg.counters.get();
+    Ok(stats)
+}
+
+/// Generate a snapshot for the archive (as specified by `options`) unless
+/// it would be the same as the archive's most recent snapshot (apart from
+/// times).
+pub fn generate_snapshot_unless_unchanged(
+    archive_name: &str,
+    options: &BackUpOptions,
+) -> EResult<BackUpOutcome> {
+    let mut sg = SnapshotGenerator::with_options(archive_name, clock::system_clock(), options)?;
+    let stats = sg.generate_snapshot()?;
+    finish_back_up(&mut sg, stats, true)
+}
+
+/// Generate a snapshot for the archive in a separate thread and return
+/// the events describing its progress.
+pub fn generate_snapshot_with_progress(archive_name: &str) -> ProgressEvents<BackUpStats> {
+    let archive_name = archive_name.to_string();
+    progress::observe(move || generate_snapshot(&archive_name))
+}
+
+/// Generate a snapshot for the archive in which only the given paths (which
+/// must be within the archive's inclusions) are rescanned and everything
+/// else is carried over unchanged from the most recent snapshot.  If
+/// `skip_if_unchanged` the snapshot isn't written if it would be the same
+/// as the most recent snapshot (apart from times).  The paths are read as
+/// specified by `options`.
+pub fn generate_partial_snapshot(
+    archive_name: &str,
+    only: &[PathBuf],
+    skip_if_unchanged: bool,
+    options: &BackUpOptions,
+) -> EResult<BackUpOutcome> {
+    let mut sg = SnapshotGenerator::with_options(archive_name, clock::system_clock(), options)?;
+    let stats = sg.generate_partial_snapshot(only)?;
+    finish_back_up(&mut sg, stats, skip_if_unchanged)
+}
+
+/// Read a list of changed paths (e.g. from `find -newer` or an external
+/// change journal) from a file.  Paths are separated by newlines or, if
+/// the file contains any NUL characters (e.g. `find -print0`), by NULs.
+pub fn read_changed_paths(file_path: &Path) -> EResult<Vec<PathBuf>> {
+    let bytes = fs::read(file_path)?;
+    let separator = if bytes.contains(&0) { 0 } else { b'\n' };
+    Ok(bytes
+        .split(|byte| *byte == separator)
+        .filter(|path| !path.is_empty())
+        .map(|path| PathBuf::from(OsStr::from_bytes(path)))
+        .collect())
+}
+
+// Reduce the changed paths to the smallest set of subtrees (within the
+// inclusions) that need to be rescanned.  Paths that no longer exist are
+// replaced by their nearest surviving ancestor so that deletions are noticed
+// and paths outside the inclusions are ignored.
+fn changed_subtrees(includes: &[PathBuf], changed_paths: &[PathBuf]) -> EResult<Vec<PathBuf>> {
+    let mut subtrees: Vec<PathBuf> = vec![];
+    for path in changed_paths.iter() {
+        let abs_path = absolute_path_buf(path)
+            .map_err(|e| Error::ArchiveIncludePathError(e, path.to_path_buf()))?;
+        let inclusion = match includes
+            .iter()
+            .find(|inclusion| abs_path.starts_with(inclusion))
+        {
+            Some(inclusion) => inclusion,
+            None => continue,
+        };
+        let mut subtree = abs_path.as_path();
+        while subtree != inclusion && fs::symlink_metadata(subtree).is_err() {
+            subtree = subtree.parent().expect(UNEXPECTED);
+        }
+        subtrees.push(subtree.to_path_buf());
+    }
+    subtrees.sort();
+    subtrees.dedup();
+    let mut minimal: Vec<PathBuf> = vec![];
+    for subtree in subtrees {
+        if !minimal.iter().any(|ancestor| subtree.starts_with(ancestor)) {
+            minimal.push(subtree);
+        }
+    }
+    Ok(minimal)
+}
+
+/// Generate a snapshot for the archive in which only the subtrees
+/// containing the changed paths are rescanned and everything else is
+/// carried over from the most recent snapshot.  Changed paths outside the
+/// archive's inclusions are ignored.  If `skip_if_unchanged` the snapshot
+/// isn't written if it would be the same as the most recent snapshot
+/// (apart from times).  The subtrees are read as specified by `options`.
+pub fn generate_snapshot_from_changes(
+    archive_name: &str,
+    changed_paths: &[PathBuf],
+    skip_if_unchanged: bool,
+    options: &BackUpOptions,
+) -> EResult<BackUpOutcome> {
+    let mut sg = SnapshotGenerator::with_options(archive_name, clock::system_clock(), options)?;
+    let subtrees = changed_subtrees(&sg.archive_data.includes, changed_paths)?;
+    let stats = sg.generate_partial_snapshot(&subtrees)?;
+    finish_back_up(&mut sg, stats, skip_if_unchanged)
+}
+
+/// Import a copy of the archive's directory `as_dir_path` (made by some
+/// other back up tool such as rsync) at `from_dir_path` as a snapshot so
+/// that the archive's history starts with it.  The snapshot is dated by
+/// the copy's newest modification time and its contents are stored in the
+/// archive's repository.  If `as_dir_path` isn't given the archive must
+/// have exactly one inclusion (which is used).
+pub fn import_snapshot_from_dir(
+    archive_name: &str,
+    from_dir_path: &Path,
+    as_dir_path: Option<&Path>,
+) -> EResult<(PathBuf, FileStats, SymLinkStats, u64)> {
+    let clock = Arc::new(ManualClock::new(time::UNIX_EPOCH, Duration::from_secs(0)));
+    let mut sg = SnapshotGenerator::with_clock(archive_name, clock.clone())?;
+    let as_dir_path = match as_dir_path {
+        Some(as_dir_path) => {
+            let abs_path = absolute_path_buf(as_dir_path)
+                .map_err(|e| Error::ArchiveIncludePathError(e, as_dir_path.to_path_buf()))?;
+            if !sg
+                .archive_data
+                .includes
+                .iter()
+                .any(|inclusion| abs_path.starts_with(inclusion))
+            {
+                return Err(Error::PathNotInArchive(abs_path, archive_name.to_string()));
+            }
+            abs_path
+        }
+        None => match sg.archive_data.includes.as_slice() {
+            [inclusion] => inclusion.clone(),
+            _ => return Err(Error::ImportTargetAmbiguous(archive_name.to_string())),
+        },
+    };
+    if !from_dir_path.is_dir() {
+        return Err(Error::SnapshotUnknownDirectory(from_dir_path.to_path_buf()));
+    }
+    let stats = sg.generate_imported_snapshot(from_dir_path, &as_dir_path, &clock)?;
+    if let Some(ref snapshot) = sg.snapshot {
+        let ss_file_path = sg
+            .archive_data
+            .snapshot_dir_path
+            .join(snapshot.snapshot_name());
+        if ss_file_path.exists() {
+            sg.release_snapshot()?;
+            return Err(Error::SnapshotExists(ss_file_path));
+        }
+    }
+    let ss_file_path = sg.write_snapshot()?;
+    Ok((ss_file_path, stats.1, stats.2, stats.3))
+}
+
+/// The name of the file in each snapshot directory containing the name of
+/// the newest snapshot (for the benefit of external tools).  It's replaced
+/// atomically after each successful back up.
+pub const LATEST_POINTER_FILE_NAME: &str = "latest";
+
+// Point the snapshot directory's pointer file at its newest snapshot (or
+// remove it if there are none).
+fn update_latest_pointer(dir_path: &Path) -> EResult<()> {
+    let pointer_path = dir_path.join(LATEST_POINTER_FILE_NAME);
+    match iter_snapshot_names_in_dir(dir_path, Order::Descending)?.next() {
+        Some(snapshot_name) => {
+            let temp_path = dir_path.join(format!(".{}.tmp", LATEST_POINTER_FILE_NAME));
+            let mut text = snapshot_name;
+            text.push("\n");
+            fs::write(&temp_path, text.as_bytes())?;
+            fs::rename(&temp_path, &pointer_path)?;
+        }
+        None => match fs::remove_file(&pointer_path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => (),
+        },
+    }
     Ok(())
 }
 
+/// The name of the newest snapshot according to the snapshot directory's
+/// pointer file (if it has one).
+pub fn read_latest_pointer(dir_path: &Path) -> EResult<Option<OsString>> {
+    match fs::read(dir_path.join(LATEST_POINTER_FILE_NAME)) {
+        Ok(bytes) => {
+            let name = bytes.strip_suffix(b"\n").unwrap_or(&bytes);
+            Ok(Some(OsStr::from_bytes(name).to_os_string()))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Delete the snapshot file (releasing its contents) and return the
+/// stored size of the contents no longer referenced.
+pub fn delete_snapshot_file(ss_file_path: &Path) -> EResult<u64> {
+    let snapshot = SnapshotPersistentData::from_file(ss_file_path)?;
+    // The releases are journalled before the file is removed so that they
+    // still happen if we die before the new counts are written.
+    let unreferenced_size = {
+        let content_mgr = snapshot
+            .relocated_content_keys()?
+            .open_content_store(dychatat_lib::Mutability::Mutable)?;
+        let unreferenced_size = snapshot.root_dir.release_contents(&content_mgr)?;
+        content_mgr.journal_releases(&content::new_journal_id(), ss_file_path)?;
+        if let Err(err) = fs::remove_file(ss_file_path) {
+            // the file still holds the references
+            snapshot.root_dir.claim_contents(&content_mgr)?;
+            return Err(Error::SnapshotDeleteIOError(
+                err,
+                ss_file_path.to_path_buf(),
+            ));
+        }
+        unreferenced_size
+    };
+    audit::record(
+        AuditOperation::DeleteSnapshot,
+        &ss_file_path.to_string_lossy(),
+        unreferenced_size,
+    );
+    // don't leave orphaned stats or signature files behind
+    for file_path in [
+        ss_file_path.with_extension("stats"),
+        signing::signature_file_path(ss_file_path),
+    ] {
+        if let Err(err) = fs::remove_file(&file_path) {
+            if err.kind() != ErrorKind::NotFound {
+                return Err(Error::SnapshotDeleteIOError(err, file_path));
+            }
+        }
+    }
+    if let Some(dir_path) = ss_file_path.parent() {
+        match read_latest_pointer(dir_path) {
+            Ok(Some(name)) if Some(name.as_os_str()) == ss_file_path.file_name() => {
+                if let Err(err) = update_latest_pointer(dir_path) {
+                    warn!("{:?}: failed to update latest pointer: {:?}", dir_path, err);
+                }
+            }
+            Ok(_) => (),
+            Err(err) => warn!("{:?}: failed to read latest pointer: {:?}", dir_path, err),
+        }
+    }
+    Ok(unreferenced_size)
+}
+
 // Doing this near where the file names are constructed for programming convenience
 lazy_static! {
     static ref SS_FILE_NAME_RE: regex::Regex =
@@ -501,6 +1405,42 @@ pub fn iter_snapshot_paths_for_archive(
     iter_snapshot_i_in_dir::<PathBuf>(dir_path, order, |ude| ude.path())
 }
 
+/// Files in the snapshot directory left behind by interrupted snapshot
+/// writes (empty snapshot files and stats or signature files without a snapshot) that
+/// haven't been modified for at least `min_age`.
+pub fn get_stale_partial_files_in_dir(dir_path: &Path, min_age: Duration) -> EResult<Vec<PathBuf>> {
+    let now = time::SystemTime::now();
+    let mut stale_files = vec![];
+    for entry in path_utilities::usable_dir_entries(dir_path)
+        .map_err(|err| Error::SnapshotDirIOError(err, dir_path.to_path_buf()))?
+    {
+        if !entry.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let is_partial = match path.extension() {
+            Some(extension)
+                if extension == "stats" || extension == signing::SIGNATURE_EXTENSION =>
+            {
+                !path.with_extension("").exists()
+            }
+            None => {
+                SS_FILE_NAME_RE.is_match(&entry.file_name().to_string_lossy())
+                    && entry.metadata()?.len() == 0
+            }
+            _ => false,
+        };
+        if is_partial {
+            let modified = entry.metadata()?.modified()?;
+            if now.duration_since(modified).unwrap_or_default() >= min_age {
+                stale_files.push(path);
+            }
+        }
+    }
+    stale_files.sort();
+    Ok(stale_files)
+}
+
 pub fn get_snapshot_paths_in_dir(dir_path: &Path, order: Order) -> EResult<Vec<PathBuf>> {
     Ok(iter_snapshot_paths_in_dir(dir_path, order)?.collect::<Vec<_>>())
 }
@@ -521,14 +1461,29 @@ pub fn get_snapshot_names_for_archive(archive_name: &str, order: Order) -> EResu
 pub fn delete_named_snapshots(archive_name: &str, snapshot_names: &[OsString]) -> EResult<()> {
     let snapshot_dir_path = archive::get_archive_snapshot_dir_path(archive_name)?;
     for snapshot_name in snapshot_names.iter() {
-        let mut snapshot_file_path = snapshot_dir_path.join(snapshot_name);
-        delete_snapshot_file(&snapshot_file_path)?;
-        snapshot_file_path.set_extension("stats");
-        fs::remove_file(&snapshot_file_path)?;
+        delete_snapshot_file(&snapshot_dir_path.join(snapshot_name))?;
+    }
+    if let Err(err) = archive::Snapshots::try_from(archive_name)
+        .and_then(|snapshots| snapshots.collect_garbage(archive::STALE_FILE_MIN_AGE))
+    {
+        warn!("{}: failed to collect garbage: {:?}", archive_name, err);
     }
     Ok(())
 }
 
+/// Estimate the repository space that deleting the named snapshots would
+/// free (i.e. the stored size of the contents only they reference).
+pub fn estimate_space_freed(archive_name: &str, snapshot_names: &[OsString]) -> EResult<u64> {
+    let snapshot_dir_path = archive::get_archive_snapshot_dir_path(archive_name)?;
+    let snapshot_paths: Vec<PathBuf> = snapshot_names
+        .iter()
+        .map(|snapshot_name| snapshot_dir_path.join(snapshot_name))
+        .collect();
+    let estimates =
+        archive::Snapshots::try_from(archive_name)?.estimate_reclaimable_bytes(&snapshot_paths)?;
+    Ok(estimates.last().copied().unwrap_or(0))
+}
+
 pub fn get_named_snapshot(
     archive_name: &str,
     snapshot_name: &OsStr,
@@ -593,14 +1548,166 @@ pub fn get_snapshot_stats(archive_name: &str, snapshot_name: &OsStr) -> EResult<
     SnapshotStats::from_file(&snapshot_file_path)
 }
 
+/// Archives whose expected back up interval hasn't been specified are
+/// expected to be backed up daily.
+pub const DEFAULT_BACKUP_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How up to date an archive's back ups are.
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveHealth {
+    /// Backed up within the expected interval
+    Ok,
+    /// Back up is overdue
+    Stale,
+    /// Not backed up for more than twice the expected interval (or never)
+    Overdue,
+}
+
+impl ArchiveHealth {
+    pub fn for_age(age: Option<Duration>, interval: Duration) -> Self {
+        match age {
+            Some(age) if age <= interval => ArchiveHealth::Ok,
+            Some(age) if age <= interval * 2 => ArchiveHealth::Stale,
+            _ => ArchiveHealth::Overdue,
+        }
+    }
+}
+
+/// The time at which a snapshot was taken (as recorded in its name).
+pub fn snapshot_name_time(snapshot_name: &OsStr) -> Option<DateTime<Local>> {
+    DateTime::parse_from_str(&snapshot_name.to_string_lossy(), "%Y-%m-%d-%H-%M-%S%z")
+        .ok()
+        .map(|dt| dt.with_timezone(&Local))
+}
+
+/// A back up in an archive's history.
+#[derive(Debug)]
+pub struct HistoryEntry {
+    pub snapshot_name: OsString,
+    pub time: DateTime<Local>,
+    pub stats: SnapshotStats,
+}
+
+/// The archive's back ups (oldest first) as recorded in its snapshots'
+/// statistics files.  Snapshots whose statistics can't be read are skipped.
+pub fn get_snapshot_history(archive_name: &str) -> EResult<Vec<HistoryEntry>> {
+    let mut history = vec![];
+    for snapshot_name in iter_snapshot_names_for_archive(archive_name, Order::Ascending)? {
+        let time = match snapshot_name_time(&snapshot_name) {
+            Some(time) => time,
+            None => continue,
+        };
+        match get_snapshot_stats(archive_name, &snapshot_name) {
+            Ok(stats) => history.push(HistoryEntry {
+                snapshot_name,
+                time,
+                stats,
+            }),
+            Err(err) => warn!("{:?}: {:?}", snapshot_name, err),
+        }
+    }
+    Ok(history)
+}
+
+/// An overview of an archive's back ups.
+#[derive(Debug)]
+pub struct ArchiveSummary {
+    pub archive_name: String,
+    pub snapshot_count: usize,
+    pub last_snapshot_time: Option<DateTime<Local>>,
+    /// The time since the most recent snapshot was taken
+    pub age: Option<Duration>,
+    /// The statistics of the most recent snapshot
+    pub last_snapshot_stats: Option<SnapshotStats>,
+    pub backup_interval: Duration,
+    pub health: ArchiveHealth,
+}
+
+fn get_backup_interval(archive_name: &str) -> EResult<Duration> {
+    Ok(match archive::get_backup_interval_hours(archive_name)? {
+        Some(hours) => Duration::from_secs(hours * 60 * 60),
+        None => DEFAULT_BACKUP_INTERVAL,
+    })
+}
+
+fn age_of(time: DateTime<Local>) -> Duration {
+    Local::now()
+        .signed_duration_since(time)
+        .to_std()
+        .unwrap_or_default()
+}
+
+pub fn get_archive_summary(archive_name: &str) -> EResult<ArchiveSummary> {
+    let backup_interval = get_backup_interval(archive_name)?;
+    let snapshot_names = get_snapshot_names_for_archive(archive_name, Order::Descending)?;
+    let last_snapshot_time = snapshot_names
+        .first()
+        .and_then(|name| snapshot_name_time(name));
+    let last_snapshot_stats = match snapshot_names.first() {
+        Some(snapshot_name) => get_snapshot_stats(archive_name, snapshot_name).ok(),
+        None => None,
+    };
+    let age = last_snapshot_time.map(age_of);
+    Ok(ArchiveSummary {
+        archive_name: archive_name.to_string(),
+        snapshot_count: snapshot_names.len(),
+        last_snapshot_time,
+        age,
+        last_snapshot_stats,
+        backup_interval,
+        health: ArchiveHealth::for_age(age, backup_interval),
+    })
+}
+
+/// The time of the archive's newest snapshot (if any) found from the
+/// snapshot directory's pointer file (or, failing that, the snapshots'
+/// names) without reading any snapshots.
+pub fn newest_snapshot_time(archive_name: &str) -> EResult<Option<DateTime<Local>>> {
+    let dir_path = archive::get_archive_snapshot_dir_path(archive_name)?;
+    if let Some(snapshot_name) = read_latest_pointer(&dir_path)? {
+        // NB: ignore pointers to snapshots that have been deleted
+        if dir_path.join(&snapshot_name).is_file() {
+            if let Some(time) = snapshot_name_time(&snapshot_name) {
+                return Ok(Some(time));
+            }
+        }
+    }
+    let newest = iter_snapshot_names_in_dir(&dir_path, Order::Descending)?.next();
+    Ok(newest.and_then(|snapshot_name| snapshot_name_time(&snapshot_name)))
+}
+
+/// How recently an archive was backed up (cheap enough to find for every
+/// archive whenever a list of them is shown).
+#[derive(Debug, Clone)]
+pub struct ArchiveFreshness {
+    pub archive_name: String,
+    pub newest_snapshot_time: Option<DateTime<Local>>,
+    /// The time since the newest snapshot was taken
+    pub age: Option<Duration>,
+    pub health: ArchiveHealth,
+}
+
+pub fn get_archive_freshness(archive_name: &str) -> EResult<ArchiveFreshness> {
+    let backup_interval = get_backup_interval(archive_name)?;
+    let newest_snapshot_time = newest_snapshot_time(archive_name)?;
+    let age = newest_snapshot_time.map(age_of);
+    Ok(ArchiveFreshness {
+        archive_name: archive_name.to_string(),
+        newest_snapshot_time,
+        age,
+        health: ArchiveHealth::for_age(age, backup_interval),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::archive;
-    use dychatat_lib::content;
-    use fs2::FileExt;
+    use crate::clock::ManualClock;
+    use crate::fixture::{ArchivedFixture, FixtureSpec, TestConfigGuard};
+    use dychatat_lib::content::{self, CacheStats};
     use std::env;
-    use std::os::unix::fs::MetadataExt;
     use tempdir::TempDir;
 
     #[test]
@@ -610,19 +1717,245 @@ mod tests {
     }
 
     #[test]
-    fn test_write_snapshot() {
-        let file = fs::OpenOptions::new()
-            .write(true)
-            .open("../test_lock_file")
-            .unwrap_or_else(|err| panic!("{:?}: line {:?}: {:?}", file!(), line!(), err));
-        if let Err(err) = file.lock_exclusive() {
-            panic!("lock failed: {:?}", err);
+    fn target_paths_are_made_absolute() {
+        let home_dir = dirs::home_dir().unwrap();
+        let cur_dir = env::current_dir().unwrap();
+        assert_eq!(
+            absolute_target_path(Path::new("~/restore")).unwrap(),
+            home_dir.join("restore")
+        );
+        assert_eq!(
+            absolute_target_path(Path::new("restore")).unwrap(),
+            cur_dir.join("restore")
+        );
+        assert_eq!(
+            absolute_target_path(Path::new("./restore")).unwrap(),
+            cur_dir.join("restore")
+        );
+        assert_eq!(
+            absolute_target_path(Path::new("/tmp/restore")).unwrap(),
+            PathBuf::from("/tmp/restore")
+        );
+    }
+
+    #[test]
+    fn archive_health_follows_age() {
+        let hour = Duration::from_secs(3600);
+        assert_eq!(
+            ArchiveHealth::for_age(Some(hour), hour * 24),
+            ArchiveHealth::Ok
+        );
+        assert_eq!(
+            ArchiveHealth::for_age(Some(hour * 25), hour * 24),
+            ArchiveHealth::Stale
+        );
+        assert_eq!(
+            ArchiveHealth::for_age(Some(hour * 49), hour * 24),
+            ArchiveHealth::Overdue
+        );
+        assert_eq!(ArchiveHealth::for_age(None, hour), ArchiveHealth::Overdue);
+        let time = snapshot_name_time(OsStr::new("2021-03-04-05-06-07+1000")).unwrap();
+        assert_eq!(
+            time.with_timezone(&chrono::Utc).to_rfc3339(),
+            "2021-03-03T19:06:07+00:00"
+        );
+        assert!(snapshot_name_time(OsStr::new("not-a-snapshot")).is_none());
+    }
+
+    #[test]
+    fn stale_partial_files_are_found() {
+        let dir = TempDir::new("STALE_TEST").unwrap();
+        for (name, contents) in [
+            ("2021-01-01-00-00-00+1000", "data"),
+            ("2021-01-01-00-00-00+1000.stats", "data"),
+            ("2021-01-02-00-00-00+1000", ""),
+            ("2021-01-02-00-00-00+1000.stats", ""),
+            ("2021-01-03-00-00-00+1000.stats", "data"),
+            ("not-a-snapshot", ""),
+        ]
+        .iter()
+        {
+            fs::write(dir.path().join(name), contents).unwrap();
+        }
+        let stale_files =
+            get_stale_partial_files_in_dir(dir.path(), Duration::from_secs(0)).unwrap();
+        assert_eq!(
+            stale_files,
+            vec![
+                dir.path().join("2021-01-02-00-00-00+1000"),
+                dir.path().join("2021-01-03-00-00-00+1000.stats"),
+            ]
+        );
+        let stale_files =
+            get_stale_partial_files_in_dir(dir.path(), Duration::from_secs(3600)).unwrap();
+        assert!(stale_files.is_empty());
+    }
+
+    #[test]
+    fn changed_paths_are_reduced_to_subtrees() {
+        let dir = TempDir::new("CHANGED_PATHS_TEST").unwrap();
+        let inclusion = dir.path().join("inc");
+        fs::create_dir_all(inclusion.join("a/b")).unwrap();
+        fs::create_dir_all(inclusion.join("c")).unwrap();
+        let list_file_path = dir.path().join("changed");
+        fs::write(
+            &list_file_path,
+            format!(
+                "{0}/a/b\n{0}/a\n{0}/c/deleted/file\n\n{1}/elsewhere\n",
+                inclusion.display(),
+                dir.path().display()
+            ),
+        )
+        .unwrap();
+        let changed_paths = read_changed_paths(&list_file_path).unwrap();
+        assert_eq!(changed_paths.len(), 4);
+        let subtrees = changed_subtrees(std::slice::from_ref(&inclusion), &changed_paths).unwrap();
+        assert_eq!(subtrees, vec![inclusion.join("a"), inclusion.join("c")]);
+        fs::write(
+            &list_file_path,
+            format!("{0}/a/b\0{0}/c\0", inclusion.display()),
+        )
+        .unwrap();
+        let changed_paths = read_changed_paths(&list_file_path).unwrap();
+        assert_eq!(
+            changed_paths,
+            vec![inclusion.join("a/b"), inclusion.join("c")]
+        );
+    }
+
+    #[test]
+    fn unchanged_snapshots_can_be_skipped() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new().file("a.txt", "some text").build();
+        archive::create_new_archive(
+            "test_skip",
+            Some("test_repo"),
+            &location,
+            &[fixture.root().to_path_buf()],
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        let clock = Arc::new(ManualClock::new(
+            time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            Duration::from_secs(60),
+        ));
+        generate_snapshot_with_clock("test_skip", clock.clone()).unwrap();
+        let ss_file_paths = get_snapshot_paths_for_archive("test_skip", Order::Ascending).unwrap();
+        {
+            let mut sg = SnapshotGenerator::new("test_skip").unwrap();
+            sg.generate_snapshot().unwrap();
+            assert_eq!(sg.check_changed().unwrap(), Some(ss_file_paths[0].clone()));
+            // the unchanged snapshot has been released
+            assert!(!sg.snapshot_available());
+        }
+        match generate_snapshot_unless_unchanged("test_skip", &BackUpOptions::default()).unwrap() {
+            BackUpOutcome::Unchanged(latest_path) => assert_eq!(latest_path, ss_file_paths[0]),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+        let changed = vec![fixture.root().join("a.txt")];
+        assert!(matches!(
+            generate_partial_snapshot("test_skip", &changed, true, &BackUpOptions::default())
+                .unwrap(),
+            BackUpOutcome::Unchanged(_)
+        ));
+        assert!(matches!(
+            generate_snapshot_from_changes("test_skip", &changed, true, &BackUpOptions::default())
+                .unwrap(),
+            BackUpOutcome::Unchanged(_)
+        ));
+        assert_eq!(
+            get_snapshot_paths_for_archive("test_skip", Order::Ascending).unwrap(),
+            ss_file_paths
+        );
+        fs::write(&changed[0], "other text").unwrap();
+        {
+            let mut sg = SnapshotGenerator::new("test_skip").unwrap();
+            sg.generate_snapshot().unwrap();
+            assert_eq!(sg.check_changed().unwrap(), None);
+            assert!(sg.snapshot_available());
+        }
+        assert!(matches!(
+            generate_snapshot_unless_unchanged("test_skip", &BackUpOptions::default()).unwrap(),
+            BackUpOutcome::Written(_)
+        ));
+        assert_eq!(
+            get_snapshot_paths_for_archive("test_skip", Order::Ascending)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn failed_partial_snapshots_release_their_claims() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new()
+            .file("a.txt", "carried over")
+            .file("c.txt", "rescanned")
+            .file("sub/b.txt", "lost")
+            .build();
+        archive::create_new_archive(
+            "test_partial",
+            Some("test_repo"),
+            &location,
+            &[fixture.root().to_path_buf()],
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        generate_snapshot("test_partial").unwrap();
+        let ss_file_paths =
+            get_snapshot_paths_for_archive("test_partial", Order::Ascending).unwrap();
+        let snapshot = SnapshotPersistentData::from_file(&ss_file_paths[0]).unwrap();
+        let token_for = |file_name: &str| {
+            snapshot
+                .find_file(fixture.root().join(file_name).canonicalize().unwrap())
+                .unwrap()
+                .content_token()
+                .to_string()
         };
-        let dir =
-            TempDir::new("SS_TEST").unwrap_or_else(|err| panic!("open temp dir failed: {:?}", err));
-        env::set_var("ERGIBUS_CONFIG_DIR", dir.path().join("config"));
-        env::set_var("DYCHATAT_CONFIG_DIR", dir.path().join("config"));
-        let data_dir = dir.path().join("data");
+        let (a_token, b_token) = (token_for("a.txt"), token_for("sub/b.txt"));
+        // lose "b.txt"'s contents from the repository so that it can't be claimed
+        {
+            let content_mgr = snapshot
+                .content_keys()
+                .open_content_store(dychatat_lib::Mutability::Mutable)
+                .unwrap();
+            content_mgr.release_contents(&b_token).unwrap();
+        }
+        content::prune_repository("test_repo").unwrap();
+        assert!(generate_partial_snapshot(
+            "test_partial",
+            &[fixture.root().join("c.txt")],
+            false,
+            &BackUpOptions::default()
+        )
+        .is_err());
+        let content_mgr = snapshot
+            .content_keys()
+            .open_content_store(dychatat_lib::Mutability::Immutable)
+            .unwrap();
+        assert_eq!(content_mgr.token_ref_count(&a_token), Some(1));
+        assert_eq!(content_mgr.token_ref_count(&b_token), None);
+        assert_eq!(
+            get_snapshot_paths_for_archive("test_partial", Order::Ascending).unwrap(),
+            ss_file_paths
+        );
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let guard = TestConfigGuard::new();
+        let data_dir = guard.data_dir();
         let data_dir_str = match data_dir.to_str() {
             Some(data_dir_str) => data_dir_str,
             None => panic!("{:?}: line {:?}", file!(), line!()),
@@ -630,62 +1963,814 @@ mod tests {
         if let Err(err) = content::create_new_repo("test_repo", data_dir_str, "Sha1") {
             panic!("new repo: {:?}", err);
         }
-        let my_file = Path::new("./src/snapshot.rs").canonicalize().unwrap();
-        let cli_dir = Path::new("../ergibus").canonicalize().unwrap();
-        let inclusions = vec![PathBuf::from("~/Documents"), cli_dir, my_file];
+        let fixture = FixtureSpec::new()
+            .file("docs/letter.txt", "Dear Sir")
+            .file("docs/copy.txt", "Dear Sir")
+            .file_of_size("docs/big.bin", 300_000)
+            .file("docs/image.iso", "excluded")
+            .dir("docs/lost+found")
+            .dir("empty")
+            .symlink("docs/link", "letter.txt")
+            .symlink("dir_link", "docs")
+            .fifo("pipe")
+            .mode("docs/letter.txt", 0o600)
+            .build();
+        let inclusions = vec![fixture.root().to_path_buf()];
         let dir_exclusions = vec!["lost+found".to_string()];
         let file_exclusions = vec!["*.iso".to_string()];
         if let Err(err) = archive::create_new_archive(
             "test_ss",
-            "test_repo",
+            Some("test_repo"),
             data_dir_str,
             &inclusions,
             &dir_exclusions,
             &file_exclusions,
+            &[],
+            false,
         ) {
             panic!("new archive: {:?}", err);
         }
-        {
+        let ss_file_path = {
             // need this to let sg finish before the temporary directory is destroyed
             let mut sg = match SnapshotGenerator::new("test_ss") {
                 Ok(snapshot_generator) => snapshot_generator,
                 Err(err) => panic!("new SG: {:?}", err),
             };
-            println!("Generating for {:?}", "test_ss");
-            assert!(sg.generate_snapshot().is_ok());
-            println!(
-                "Generating for {:?} took {:?}",
-                "test_ss",
-                sg.generation_duration()
+            let (_, file_stats, sym_link_stats, _, _) = sg.generate_snapshot().unwrap();
+            assert_eq!(file_stats.file_count, 3);
+            assert_eq!(file_stats.snapshot_dedup_byte_count, 8);
+            assert_eq!(
+                sym_link_stats.dir_sym_link_count + sym_link_stats.file_sym_link_count,
+                2
             );
             assert!(sg.snapshot_available());
-            let result = sg.write_snapshot();
-            assert!(result.is_ok());
+            assert!(sg.generation_duration().is_ok());
+            let ss_file_path = sg.write_snapshot().unwrap();
             assert!(!sg.snapshot_available());
-            match result {
-                Ok(ref ss_file_path) => {
-                    match fs::metadata(ss_file_path) {
-                        Ok(metadata) => println!("{:?}: {:?}", ss_file_path, metadata.size()),
-                        Err(err) => {
-                            panic!("Error getting size data: {:?}: {:?}", ss_file_path, err)
-                        }
-                    };
-                    match SnapshotPersistentData::from_file(ss_file_path) {
-                        Ok(ss) => println!(
-                            "{:?}: {:?} {:?}",
-                            ss.archive_name, ss.file_stats, ss.sym_link_stats
-                        ),
-                        Err(err) => panic!("Error reading: {:?}: {:?}", ss_file_path, err),
-                    };
-                }
-                Err(err) => panic!("{:?}", err),
-            }
+            ss_file_path
+        };
+        let snapshot = SnapshotPersistentData::from_file(&ss_file_path).unwrap();
+        assert_eq!(snapshot.archive_name, "test_ss");
+        let restore_dir_path = guard.data_dir().join("restored");
+        snapshot
+            .copy_dir_to(fixture.root(), &restore_dir_path, &CopyOptions::default())
+            .unwrap();
+        // excluded files/directories and the named pipe should not be restored
+        // and file permissions are not (yet) restored
+        let expected = FixtureSpec::new()
+            .file("docs/letter.txt", "Dear Sir")
+            .file("docs/copy.txt", "Dear Sir")
+            .file_of_size("docs/big.bin", 300_000)
+            .dir("empty")
+            .symlink("docs/link", "letter.txt")
+            .symlink("dir_link", "docs")
+            .build();
+        expected.assert_restored_to(&restore_dir_path);
+    }
+
+    #[test]
+    fn files_are_restored_where_they_came_from() {
+        let archived = ArchivedFixture::new(
+            "test_restore",
+            FixtureSpec::new().file("docs/letter.txt", "Dear Sir"),
+        );
+        archived.back_up();
+        let snapshot = archived.latest_snapshot();
+        fs::remove_dir_all(archived.root().join("docs")).unwrap();
+        let letter_path = archived.root().join("docs/letter.txt");
+        assert_eq!(
+            snapshot.restore_file(&letter_path, false, false).unwrap(),
+            (letter_path.clone(), 8)
+        );
+        assert_eq!(fs::read_to_string(&letter_path).unwrap(), "Dear Sir");
+        assert!(snapshot
+            .restore_file(&archived.root().join("docs/nonexistent"), false, false)
+            .is_err());
+    }
+
+    #[test]
+    fn latest_pointer_follows_writes_and_deletes() {
+        let archived =
+            ArchivedFixture::new("test_latest", FixtureSpec::new().file("a.txt", "some text"));
+        let ss_file_path = archived.back_up();
+        let snapshot_dir_path = ss_file_path.parent().unwrap();
+        assert_eq!(
+            read_latest_pointer(snapshot_dir_path).unwrap().as_deref(),
+            ss_file_path.file_name()
+        );
+        delete_snapshot_file(&ss_file_path).unwrap();
+        assert_eq!(read_latest_pointer(snapshot_dir_path).unwrap(), None);
+    }
+
+    #[test]
+    fn tree_hashes_identify_unchanged_trees() {
+        let archived = ArchivedFixture::new(
+            "test_tree_hash",
+            FixtureSpec::new()
+                .file("docs/letter.txt", "Dear Sir")
+                .symlink("docs/link", "letter.txt"),
+        );
+        archived.back_up();
+        let snapshot = archived.latest_snapshot();
+        assert_eq!(snapshot.verify_tree_hash(), Some(true));
+        let mut sg = SnapshotGenerator::new("test_tree_hash").unwrap();
+        sg.generate_snapshot().unwrap();
+        assert!(snapshot.has_same_tree_hash(sg.snapshot.as_ref().unwrap()));
+        sg.release_snapshot().unwrap();
+        fs::write(archived.root().join("docs/letter.txt"), "Dear Madam").unwrap();
+        sg.generate_snapshot().unwrap();
+        assert!(!snapshot.has_same_tree_hash(sg.snapshot.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn history_follows_writes_and_deletes() {
+        let archived = ArchivedFixture::new(
+            "test_history",
+            FixtureSpec::new()
+                .file("a.txt", "some text")
+                .file("b.txt", "more text"),
+        );
+        let ss_file_path = archived.back_up();
+        let history = get_snapshot_history("test_history").unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(
+            Some(history[0].snapshot_name.as_os_str()),
+            ss_file_path.file_name()
+        );
+        assert_eq!(history[0].stats.file_stats.file_count, 2);
+        delete_snapshot_file(&ss_file_path).unwrap();
+        assert!(get_snapshot_history("test_history").unwrap().is_empty());
+    }
+
+    #[test]
+    fn owner_names_are_recorded() {
+        let archived =
+            ArchivedFixture::new("test_owners", FixtureSpec::new().file("a.txt", "some text"));
+        archived.back_up();
+        assert!(!archived.latest_snapshot().owner_names.is_empty());
+    }
+
+    #[test]
+    fn owner_maps_apply_to_extracted_files_and_directories() {
+        use std::os::unix::fs::MetadataExt;
+        // only root can give files away
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let archived = ArchivedFixture::new(
+            "test_owner_map",
+            FixtureSpec::new().file("docs/letter.txt", "Dear Sir"),
+        );
+        archived.back_up();
+        let snapshot = archived.latest_snapshot();
+        let mut owner_map = OwnerMap::default();
+        owner_map.add_uid_mapping("0:4321").unwrap();
+        owner_map.add_gid_mapping("0:4322").unwrap();
+        let options = CopyOptions {
+            owner_map,
+            ..CopyOptions::default()
+        };
+        let dir = TempDir::new("OWNER_MAP_TEST").unwrap();
+        let restored = dir.path().join("restored");
+        snapshot
+            .copy_dir_to(archived.root(), &restored, &options)
+            .unwrap();
+        for path in [restored.join("docs"), restored.join("docs/letter.txt")] {
+            let metadata = path.metadata().unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (4321, 4322));
         }
-        if let Err(err) = dir.close() {
-            panic!("remove temporary directory failed: {:?}", err)
+    }
+
+    #[test]
+    fn back_ups_return_their_resource_counters() {
+        let _archived = ArchivedFixture::new(
+            "test_counters",
+            FixtureSpec::new().file("a.txt", "counted contents"),
+        );
+        let (duration, _, _, _, counters) = generate_snapshot("test_counters").unwrap();
+        assert!(counters.repo_bytes_written > 0);
+        assert!(counters.phase_times.serialize > Duration::ZERO);
+        assert!(counters.phase_times.scan <= duration);
+        // nothing new is stored the second time
+        let (_, _, _, _, counters) = generate_snapshot("test_counters").unwrap();
+        assert_eq!(counters.repo_bytes_written, 0);
+    }
+
+    #[test]
+    fn extractions_use_their_own_read_cache() {
+        let archived = ArchivedFixture::new(
+            "test_read_cache",
+            FixtureSpec::new()
+                .file("a/copy.txt", "same old")
+                .file("b/copy.txt", "same old")
+                .file("c/copy.txt", "same old"),
+        );
+        archived.back_up();
+        let snapshot = archived.latest_snapshot();
+        let dir = TempDir::new("READ_CACHE_TEST").unwrap();
+        let options = CopyOptions {
+            read_cache_capacity: 1024,
+            ..CopyOptions::default()
+        };
+        let stats = snapshot
+            .copy_dir_to(archived.root(), &dir.path().join("cached"), &options)
+            .unwrap();
+        assert_eq!(stats.cache_stats.hits, 2);
+        assert_eq!(stats.cache_stats.misses, 1);
+        let stats = snapshot
+            .copy_dir_to(
+                archived.root(),
+                &dir.path().join("uncached"),
+                &CopyOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(stats.cache_stats, CacheStats::default());
+    }
+
+    #[test]
+    fn references_are_journalled_until_the_snapshot_is_written() {
+        let _archived = ArchivedFixture::new(
+            "test_journal",
+            FixtureSpec::new().file("a.txt", "some text"),
+        );
+        let journal_path = content::get_content_mgmt_key("test_repo")
+            .unwrap()
+            .base_dir_path()
+            .join("ref_journal");
+        let mut sg = SnapshotGenerator::new("test_journal").unwrap();
+        sg.generate_snapshot().unwrap();
+        assert!(journal_path.exists());
+        let ss_file_path = sg.write_snapshot().unwrap();
+        assert!(!journal_path.exists());
+        // as are the releases of an unwanted snapshot's references
+        sg.generate_snapshot().unwrap();
+        assert!(journal_path.exists());
+        sg.release_snapshot().unwrap();
+        assert!(!journal_path.exists());
+        // and those of a deleted snapshot
+        delete_snapshot_file(&ss_file_path).unwrap();
+        assert!(!journal_path.exists());
+    }
+
+    #[test]
+    fn written_snapshots_have_their_checksums_recorded() {
+        let archived = ArchivedFixture::new(
+            "test_checksums",
+            FixtureSpec::new().file("a.txt", "some text"),
+        );
+        let ss_file_path = archived.back_up();
+        let checksums = checksums::read_checksums(ss_file_path.parent().unwrap()).unwrap();
+        assert_eq!(
+            checksums.get(&*ss_file_path.file_name().unwrap().to_string_lossy()),
+            Some(&checksums::snapshot_file_checksum(&ss_file_path).unwrap())
+        );
+    }
+
+    #[test]
+    fn content_tokens_account_for_the_snapshots_bytes() {
+        let archived = ArchivedFixture::new(
+            "test_tokens",
+            FixtureSpec::new()
+                .file("letter.txt", "Dear Sir")
+                .file("copy.txt", "Dear Sir")
+                .file_of_size("big.bin", 300_000),
+        );
+        archived.back_up();
+        let snapshot = archived.latest_snapshot();
+        let usage: Vec<ContentTokenUsage> = snapshot.content_tokens().map(|(_, u)| u).collect();
+        assert_eq!(usage.len(), 2);
+        assert!(usage.contains(&ContentTokenUsage {
+            references: 2,
+            size: 8
+        }));
+        assert_eq!(
+            usage.iter().map(|u| u.total_bytes()).sum::<u64>(),
+            snapshot.file_stats.byte_count
+        );
+    }
+
+    #[test]
+    fn non_utf8_names_are_backed_up_and_restored() {
+        use std::os::unix::ffi::OsStrExt;
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new().file("plain.txt", "plain").build();
+        let dir_name = OsStr::from_bytes(b"caf\xe9");
+        let file_name = OsStr::from_bytes(b"r\xe9sum\xe9.txt");
+        let dir_path = fixture.root().join(dir_name);
+        fs::create_dir(&dir_path).unwrap();
+        fs::write(dir_path.join(file_name), "latin-1").unwrap();
+        std::os::unix::fs::symlink(Path::new(file_name), dir_path.join("link")).unwrap();
+        let inclusions = vec![fixture.root().to_path_buf()];
+        archive::create_new_archive(
+            "test_nu",
+            Some("test_repo"),
+            &location,
+            &inclusions,
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        let (_, file_stats, _, _, _) = generate_snapshot("test_nu").unwrap();
+        assert_eq!(file_stats.file_count, 2);
+
+        let ss_file_path =
+            get_snapshot_paths_for_archive("test_nu", Order::Descending).unwrap()[0].clone();
+        let snapshot = SnapshotPersistentData::from_file(&ss_file_path).unwrap();
+        assert!(snapshot.find_subdir(&dir_path).is_ok());
+        assert_eq!(snapshot.verify_tree_hash(), Some(true));
+        let restore_dir_path = location.join("restored");
+        snapshot
+            .copy_dir_to(fixture.root(), &restore_dir_path, &CopyOptions::default())
+            .unwrap();
+        let restored_dir_path = restore_dir_path.join(dir_name);
+        assert_eq!(
+            fs::read_to_string(restored_dir_path.join(file_name)).unwrap(),
+            "latin-1"
+        );
+        assert_eq!(
+            fs::read_link(restored_dir_path.join("link")).unwrap(),
+            Path::new(file_name)
+        );
+    }
+
+    #[test]
+    fn absolute_link_targets_are_rewritten() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new()
+            .file("data/file.txt", "linked to")
+            .build();
+        let root = fixture.root();
+        std::os::unix::fs::symlink(root.join("data/file.txt"), root.join("data/abs_file")).unwrap();
+        std::os::unix::fs::symlink(root.join("data"), root.join("abs_dir")).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", root.join("external")).unwrap();
+        let inclusions = vec![root.to_path_buf()];
+        archive::create_new_archive(
+            "test_rl",
+            Some("test_repo"),
+            &location,
+            &inclusions,
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        generate_snapshot("test_rl").unwrap();
+        let ss_file_path =
+            get_snapshot_paths_for_archive("test_rl", Order::Descending).unwrap()[0].clone();
+        let snapshot = SnapshotPersistentData::from_file(&ss_file_path).unwrap();
+
+        let kept_dir_path = location.join("kept");
+        let kept_stats = snapshot
+            .copy_dir_to(root, &kept_dir_path, &CopyOptions::default())
+            .unwrap();
+        assert_eq!(
+            fs::read_link(kept_dir_path.join("abs_dir")).unwrap(),
+            root.join("data")
+        );
+        assert!(kept_stats.external_links.is_empty());
+
+        let rewritten_dir_path = location.join("rewritten");
+        let options = CopyOptions {
+            rewrite_links: true,
+            ..CopyOptions::default()
         };
-        if let Err(err) = file.unlock() {
-            panic!("unlock failed: {:?}", err);
+        let stats = snapshot
+            .copy_dir_to(root, &rewritten_dir_path, &options)
+            .unwrap();
+        assert_eq!(
+            fs::read_link(rewritten_dir_path.join("data/abs_file")).unwrap(),
+            Path::new("file.txt")
+        );
+        assert_eq!(
+            fs::read_link(rewritten_dir_path.join("abs_dir")).unwrap(),
+            Path::new("data")
+        );
+        assert_eq!(
+            fs::read_to_string(rewritten_dir_path.join("abs_dir/abs_file")).unwrap(),
+            "linked to"
+        );
+        assert_eq!(
+            fs::read_link(rewritten_dir_path.join("external")).unwrap(),
+            Path::new("/etc/passwd")
+        );
+        assert_eq!(
+            stats.external_links,
+            vec![(
+                rewritten_dir_path.join("external"),
+                PathBuf::from("/etc/passwd")
+            )]
+        );
+    }
+
+    #[test]
+    fn injected_clocks_give_predictable_names_and_durations() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new().file("a.txt", "some text").build();
+        let inclusions = vec![fixture.root().to_path_buf()];
+        archive::create_new_archive(
+            "test_clock",
+            Some("test_repo"),
+            &location,
+            &inclusions,
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        let start = time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let tick = Duration::from_secs(5);
+        let clock = Arc::new(ManualClock::new(start, tick));
+        let (duration, _, _, _, _) = generate_snapshot_with_clock("test_clock", clock).unwrap();
+        assert_eq!(duration, tick);
+        let ss_file_path =
+            get_snapshot_paths_for_archive("test_clock", Order::Descending).unwrap()[0].clone();
+        let expected_name = DateTime::<Local>::from(start + tick)
+            .format("%Y-%m-%d-%H-%M-%S%z")
+            .to_string();
+        assert_eq!(ss_file_path.file_name().unwrap(), expected_name.as_str());
+        let snapshot = SnapshotPersistentData::from_file(&ss_file_path).unwrap();
+        assert_eq!(snapshot.creation_duration(), tick);
+    }
+
+    #[test]
+    fn copies_made_by_other_tools_are_imported() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new()
+            .file("live/docs/a.txt", "new a")
+            .file("rsync/docs/a.txt", "old a")
+            .file("rsync/docs/sub/b.txt", "old b")
+            .build();
+        let live = fixture.root().join("live");
+        let rsync = fixture.root().join("rsync");
+        // children before their parents (whose times they'd change)
+        let times = [
+            ("docs/sub/b.txt", 1_400_000_000),
+            ("docs/a.txt", 1_500_000_000),
+            ("docs/sub", 1_300_000_000),
+            ("docs", 1_300_000_000),
+            ("", 1_300_000_000),
+        ];
+        for (tail, secs) in times.iter() {
+            let mtime = time::SystemTime::UNIX_EPOCH + Duration::from_secs(*secs);
+            fs::File::open(rsync.join(tail))
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        }
+        archive::create_new_archive(
+            "test_import",
+            Some("test_repo"),
+            &location,
+            std::slice::from_ref(&live),
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        let (ss_file_path, file_stats, _, _) =
+            import_snapshot_from_dir("test_import", &rsync, None).unwrap();
+        assert_eq!(file_stats.file_count, 2);
+        let newest = time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let expected_name = DateTime::<Local>::from(newest)
+            .format("%Y-%m-%d-%H-%M-%S%z")
+            .to_string();
+        assert_eq!(ss_file_path.file_name().unwrap(), expected_name.as_str());
+        let imported = SnapshotPersistentData::from_file(&ss_file_path).unwrap();
+        assert!(imported.find_file(live.join("docs/sub/b.txt")).is_ok());
+        let imported_dir = imported.find_subdir(&live).unwrap();
+        assert_eq!(imported_dir.newest_mtime(), (1_500_000_000, 0));
+        let copy_path = location.join("a.txt");
+        imported
+            .copy_file_to(&live.join("docs/a.txt"), &copy_path, false, false)
+            .unwrap();
+        assert_eq!(fs::read_to_string(&copy_path).unwrap(), "old a");
+
+        // the same copy can't be imported twice
+        assert!(matches!(
+            import_snapshot_from_dir("test_import", &rsync, Some(&live)),
+            Err(Error::SnapshotExists(_))
+        ));
+        assert!(matches!(
+            import_snapshot_from_dir("test_import", &rsync, Some(&rsync)),
+            Err(Error::PathNotInArchive(..))
+        ));
+        // and the archive's own snapshots follow it
+        generate_snapshot("test_import").unwrap();
+        let ss_file_paths =
+            get_snapshot_paths_for_archive("test_import", Order::Ascending).unwrap();
+        assert_eq!(ss_file_paths.len(), 2);
+        assert_eq!(ss_file_paths[0], ss_file_path);
+    }
+
+    #[test]
+    fn snapshot_dirs_are_checked_for_writability() {
+        let dir = TempDir::new("WRITABLE_TEST").unwrap();
+        check_snapshot_dir_writable(dir.path()).unwrap();
+        // without leaving anything behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        assert!(matches!(
+            check_snapshot_dir_writable(&dir.path().join("missing")),
+            Err(Error::SnapshotDirIOError(..))
+        ));
+    }
+
+    #[test]
+    fn observed_back_ups_warn_the_starting_threads_sink() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new()
+            .file("a.txt", "some text")
+            .fifo("pipe")
+            .build();
+        let inclusions = vec![fixture.root().to_path_buf()];
+        archive::create_new_archive(
+            "test_warnings",
+            Some("test_repo"),
+            &location,
+            &inclusions,
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        let sink = Arc::new(report::CollectingSink::default());
+        let outcome = report::with_warning_sink(sink.clone(), || {
+            generate_snapshot_with_progress("test_warnings")
+                .outcome_and_warnings()
+                .0
+        });
+        outcome.unwrap();
+        let warnings = sink.take();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].path, fixture.root().join("pipe"));
+    }
+
+    #[test]
+    fn deleted_snapshots_release_their_contents_in_one_batch() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new()
+            .file("a.txt", "same")
+            .file("b.txt", "same")
+            .file("c.txt", "different")
+            .build();
+        archive::create_new_archive(
+            "test_batch",
+            Some("test_repo"),
+            &location,
+            &[fixture.root().to_path_buf()],
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        let start = time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(ManualClock::new(start, Duration::from_secs(60)));
+        generate_snapshot_with_clock("test_batch", clock.clone()).unwrap();
+        generate_snapshot_with_clock("test_batch", clock).unwrap();
+        let ss_file_paths = get_snapshot_paths_for_archive("test_batch", Order::Ascending).unwrap();
+        let snapshot = SnapshotPersistentData::from_file(&ss_file_paths[0]).unwrap();
+        let same_token = snapshot
+            .find_file(fixture.root().join("a.txt").canonicalize().unwrap())
+            .unwrap()
+            .content_token()
+            .to_string();
+        let ref_count = || {
+            let content_mgr = snapshot
+                .content_keys()
+                .open_content_store(dychatat_lib::Mutability::Immutable)
+                .unwrap();
+            content_mgr.token_ref_count(&same_token).unwrap()
         };
+        assert_eq!(ref_count(), 4);
+
+        // still referenced by the other snapshot
+        assert_eq!(delete_snapshot_file(&ss_file_paths[0]).unwrap(), 0);
+        assert_eq!(ref_count(), 2);
+
+        // a batch that can't be released in full leaves everything alone
+        {
+            let content_mgr = snapshot
+                .content_keys()
+                .open_content_store(dychatat_lib::Mutability::Mutable)
+                .unwrap();
+            content_mgr.release_contents(&same_token).unwrap();
+        }
+        assert!(delete_snapshot_file(&ss_file_paths[1]).is_err());
+        assert!(ss_file_paths[1].exists());
+        assert_eq!(ref_count(), 1);
+    }
+
+    #[test]
+    fn newest_snapshot_times_come_from_names() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new().file("a.txt", "some text").build();
+        archive::create_new_archive(
+            "test_newest",
+            Some("test_repo"),
+            &location,
+            &[fixture.root().to_path_buf()],
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        assert_eq!(newest_snapshot_time("test_newest").unwrap(), None);
+        let freshness = get_archive_freshness("test_newest").unwrap();
+        assert_eq!(freshness.health, ArchiveHealth::Overdue);
+        assert_eq!(freshness.age, None);
+
+        let start = time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(ManualClock::new(start, Duration::from_secs(60)));
+        generate_snapshot_with_clock("test_newest", clock.clone()).unwrap();
+        let older = DateTime::<Local>::from(start + Duration::from_secs(60));
+        assert_eq!(newest_snapshot_time("test_newest").unwrap(), Some(older));
+        fs::write(fixture.root().join("a.txt"), "changed text").unwrap();
+        generate_snapshot_with_clock("test_newest", clock).unwrap();
+        let newer = DateTime::<Local>::from(start + Duration::from_secs(180));
+        assert_eq!(newest_snapshot_time("test_newest").unwrap(), Some(newer));
+
+        // without a usable pointer file the names are used
+        let dir_path = archive::get_archive_snapshot_dir_path("test_newest").unwrap();
+        fs::write(
+            dir_path.join(LATEST_POINTER_FILE_NAME),
+            "2000-01-01-00-00-00+0000\n",
+        )
+        .unwrap();
+        assert_eq!(newest_snapshot_time("test_newest").unwrap(), Some(newer));
+        fs::remove_file(dir_path.join(LATEST_POINTER_FILE_NAME)).unwrap();
+        assert_eq!(newest_snapshot_time("test_newest").unwrap(), Some(newer));
+        let freshness = get_archive_freshness("test_newest").unwrap();
+        assert_eq!(freshness.newest_snapshot_time, Some(newer));
+        assert_eq!(freshness.health, ArchiveHealth::Overdue);
+        assert!(freshness.age.unwrap() > Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn files_are_broken_down_by_type() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new()
+            .file("src/main.rs", "fn main() {}")
+            .file("src/lib.rs", "")
+            .file("photos/beach.JPG", "not really a photo")
+            .file("notes", "some notes")
+            .build();
+        archive::create_new_archive(
+            "test_types",
+            Some("test_repo"),
+            &location,
+            &[fixture.root().to_path_buf()],
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        let (_, file_stats, _, _, _) = generate_snapshot("test_types").unwrap();
+        let type_breakdown = file_stats.type_breakdown;
+        assert_eq!(type_breakdown.code.file_count, 2);
+        assert_eq!(type_breakdown.code.byte_count, 12);
+        assert_eq!(type_breakdown.images.byte_count, 18);
+        assert_eq!(type_breakdown.other.file_count, 1);
+        assert_eq!(type_breakdown.file_count(), file_stats.file_count);
+
+        let ss_file_path =
+            get_snapshot_paths_for_archive("test_types", Order::Descending).unwrap()[0].clone();
+        let mut snapshot = SnapshotPersistentData::from_file(&ss_file_path).unwrap();
+        assert_eq!(snapshot.type_breakdown(), type_breakdown);
+        // as if made before the breakdown was recorded
+        snapshot.file_stats.type_breakdown = TypeBreakdown::default();
+        assert_eq!(snapshot.type_breakdown(), type_breakdown);
+    }
+
+    #[test]
+    fn extracted_dirs_keep_their_mtimes() {
+        use std::os::unix::fs::MetadataExt;
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new()
+            .file("top/a.txt", "a")
+            .file("top/sub/deeper/b.txt", "b")
+            .dir("top/empty")
+            .build();
+        let top = fixture.root().join("top");
+        // whole seconds and nanosecond precision
+        let times = [
+            ("", 1_600_000_000, 0),
+            ("sub", 1_500_000_000, 123_456_789),
+            ("sub/deeper", 1_400_000_001, 999_999_999),
+            ("empty", 1_300_000_000, 1),
+        ];
+        for (tail, secs, nsecs) in times.iter() {
+            let mtime = time::SystemTime::UNIX_EPOCH + Duration::new(*secs, *nsecs);
+            fs::File::open(top.join(tail))
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        }
+        archive::create_new_archive(
+            "test_mtimes",
+            Some("test_repo"),
+            &location,
+            std::slice::from_ref(&top),
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        generate_snapshot("test_mtimes").unwrap();
+        let ss_file_path =
+            get_snapshot_paths_for_archive("test_mtimes", Order::Descending).unwrap()[0].clone();
+        let snapshot = SnapshotPersistentData::from_file(&ss_file_path).unwrap();
+
+        let restored = location.join("restored");
+        snapshot
+            .copy_dir_to(&top, &restored, &CopyOptions::default())
+            .unwrap();
+        for (tail, secs, nsecs) in times.iter() {
+            let metadata = fs::metadata(restored.join(tail)).unwrap();
+            assert_eq!(
+                (metadata.mtime(), metadata.mtime_nsec()),
+                (*secs as i64, *nsecs as i64),
+                "{:?}",
+                tail
+            );
+        }
+
+        let unrestored = location.join("unrestored");
+        snapshot
+            .copy_dir_to(
+                &top,
+                &unrestored,
+                &CopyOptions {
+                    preserve_dir_mtimes: false,
+                    ..CopyOptions::default()
+                },
+            )
+            .unwrap();
+        let metadata = fs::metadata(unrestored.join("sub")).unwrap();
+        assert_ne!(metadata.mtime(), 1_500_000_000);
+    }
+
+    #[test]
+    fn max_depth_limits_the_levels_read() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        content::create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new()
+            .file("top/a.txt", "a")
+            .file("top/one/b.txt", "b")
+            .file("top/one/two/c.txt", "c")
+            .file("top/other/d.txt", "d")
+            .build();
+        let top = fixture.root().join("top");
+        archive::create_new_archive(
+            "test_depth",
+            Some("test_repo"),
+            &location,
+            std::slice::from_ref(&top),
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        archive::set_max_depth("test_depth", Some(2)).unwrap();
+        let (_, file_stats, _, _, _) = generate_snapshot("test_depth").unwrap();
+        assert_eq!(file_stats.file_count, 3);
+        assert_eq!(file_stats.depth_skipped_dir_count, 1);
+        let ss_file_path =
+            get_snapshot_paths_for_archive("test_depth", Order::Descending).unwrap()[0].clone();
+        let snapshot = SnapshotPersistentData::from_file(&ss_file_path).unwrap();
+        assert!(snapshot.find_file(top.join("one/b.txt")).is_ok());
+        assert!(snapshot.find_file(top.join("other/d.txt")).is_ok());
+        let two = snapshot.find_subdir(top.join("one/two")).unwrap();
+        assert!(two.is_empty());
+        assert!(snapshot.find_file(top.join("one/two/c.txt")).is_err());
+        assert_eq!(snapshot.file_stats().depth_skipped_dir_count, 1);
     }
 }
@@ -0,0 +1,370 @@
+// Copyright 2021 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+use std::cmp::Ordering;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::fs_objects::{DirectoryData, FileSystemObject, Name};
+use crate::snapshot::SnapshotPersistentData;
+use crate::UNEXPECTED;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added => write!(f, "added"),
+            Change::Removed => write!(f, "removed"),
+            Change::Changed => write!(f, "changed"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EntryKind {
+    Directory,
+    File,
+    SymLink,
+}
+
+impl From<&FileSystemObject> for EntryKind {
+    fn from(fso: &FileSystemObject) -> Self {
+        match fso {
+            FileSystemObject::Directory(_) => EntryKind::Directory,
+            FileSystemObject::File(_) => EntryKind::File,
+            FileSystemObject::SymLink(_, _) => EntryKind::SymLink,
+        }
+    }
+}
+
+/// A path that differs between two snapshots.  Changed directories
+/// contain the entries for their differing contents.  The kind is
+/// that in the newer snapshot unless the path has been removed.
+#[derive(Debug, PartialEq)]
+pub struct DiffEntry {
+    path: PathBuf,
+    change: Change,
+    kind: EntryKind,
+    children: Vec<DiffEntry>,
+}
+
+impl DiffEntry {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn name(&self) -> &OsStr {
+        self.path.file_name().expect(UNEXPECTED)
+    }
+
+    pub fn change(&self) -> Change {
+        self.change
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = &DiffEntry> {
+        self.children.iter()
+    }
+
+    fn count(&self, change: Change) -> usize {
+        let here = if self.change == change && self.children.is_empty() {
+            1
+        } else {
+            0
+        };
+        here + self.children.iter().map(|c| c.count(change)).sum::<usize>()
+    }
+}
+
+/// A path that started (or stopped) matching one of the archive's glob
+/// inclusions between two snapshots.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExpansionChange {
+    pub pattern: PathBuf,
+    pub path: PathBuf,
+    /// Either `Change::Added` or `Change::Removed`
+    pub change: Change,
+}
+
+/// The paths that started or stopped matching the glob inclusions (that
+/// both snapshots recorded expansions for).
+pub fn expansion_changes(
+    older: &SnapshotPersistentData,
+    newer: &SnapshotPersistentData,
+) -> Vec<ExpansionChange> {
+    let mut changes = vec![];
+    for new_expansion in newer.inclusion_expansions().iter() {
+        let old_expansion = match older
+            .inclusion_expansions()
+            .iter()
+            .find(|expansion| expansion.pattern == new_expansion.pattern)
+        {
+            Some(old_expansion) => old_expansion,
+            None => continue,
+        };
+        let mut push_missing = |from: &[PathBuf], absent_from: &[PathBuf], change: Change| {
+            for path in from.iter().filter(|path| !absent_from.contains(path)) {
+                changes.push(ExpansionChange {
+                    pattern: new_expansion.pattern.clone(),
+                    path: path.clone(),
+                    change,
+                });
+            }
+        };
+        push_missing(
+            &new_expansion.matches,
+            &old_expansion.matches,
+            Change::Added,
+        );
+        push_missing(
+            &old_expansion.matches,
+            &new_expansion.matches,
+            Change::Removed,
+        );
+    }
+    changes
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct SnapshotDiff {
+    entries: Vec<DiffEntry>,
+    expansion_changes: Vec<ExpansionChange>,
+}
+
+impl SnapshotDiff {
+    pub fn new(older: &SnapshotPersistentData, newer: &SnapshotPersistentData) -> Self {
+        let expansion_changes = expansion_changes(older, newer);
+        if older.has_same_tree_hash(newer) {
+            return Self {
+                entries: vec![],
+                expansion_changes,
+            };
+        }
+        Self {
+            entries: diff_directories(older.root_dir(), newer.root_dir()),
+            expansion_changes,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The paths that started or stopped matching glob inclusions (and
+    /// so account for the appearance or disappearance of their trees).
+    pub fn expansion_changes(&self) -> impl Iterator<Item = &ExpansionChange> {
+        self.expansion_changes.iter()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &DiffEntry> {
+        self.entries.iter()
+    }
+
+    /// Number of leaf paths (i.e. excluding directories that are
+    /// only listed because of changes within them) with this change.
+    pub fn count(&self, change: Change) -> usize {
+        self.entries.iter().map(|e| e.count(change)).sum()
+    }
+}
+
+/// Compare the contents of two directories recursively.  Files are
+/// compared by content token, mode and ownership; symbolic links by target.
+pub fn diff_directories(older: &DirectoryData, newer: &DirectoryData) -> Vec<DiffEntry> {
+    let mut entries = vec![];
+    let mut old_iter = older.contents().peekable();
+    let mut new_iter = newer.contents().peekable();
+    loop {
+        let ordering = match (old_iter.peek(), new_iter.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(old), Some(new)) => old.name().cmp(new.name()),
+        };
+        match ordering {
+            Ordering::Less => {
+                let old = old_iter.next().expect(UNEXPECTED);
+                entries.push(DiffEntry {
+                    path: newer.path().join(old.name()),
+                    change: Change::Removed,
+                    kind: old.into(),
+                    children: vec![],
+                });
+            }
+            Ordering::Greater => {
+                let new = new_iter.next().expect(UNEXPECTED);
+                entries.push(DiffEntry {
+                    path: newer.path().join(new.name()),
+                    change: Change::Added,
+                    kind: new.into(),
+                    children: vec![],
+                });
+            }
+            Ordering::Equal => {
+                let old = old_iter.next().expect(UNEXPECTED);
+                let new = new_iter.next().expect(UNEXPECTED);
+                if let Some(entry) = diff_objects(old, new, &newer.path().join(new.name())) {
+                    entries.push(entry);
+                }
+            }
+        }
+    }
+    entries
+}
+
+fn diff_objects(old: &FileSystemObject, new: &FileSystemObject, path: &Path) -> Option<DiffEntry> {
+    use FileSystemObject::*;
+    let (changed, children) = match (old, new) {
+        (Directory(old_dir), Directory(new_dir)) => {
+            let children = diff_directories(old_dir, new_dir);
+            let changed = !children.is_empty()
+                || !old_dir
+                    .attributes()
+                    .has_same_mode_and_owner(new_dir.attributes());
+            (changed, children)
+        }
+        (File(old_file), File(new_file)) => {
+            let changed = old_file.content_token() != new_file.content_token()
+                || !old_file
+                    .attributes()
+                    .has_same_mode_and_owner(new_file.attributes());
+            (changed, vec![])
+        }
+        (SymLink(old_link, _), SymLink(new_link, _)) => {
+            (old_link.link_target() != new_link.link_target(), vec![])
+        }
+        _ => (true, vec![]),
+    };
+    if changed {
+        Some(DiffEntry {
+            path: path.to_path_buf(),
+            change: Change::Changed,
+            kind: new.into(),
+            children,
+        })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod snapshot_diff_tests {
+    use super::*;
+    use crate::attributes::Attributes;
+    use serde_json::json;
+    use std::ffi::OsString;
+
+    fn file(name: &str, token: &str) -> serde_json::Value {
+        json!({"File": {
+            "file_name": OsString::from(name),
+            "attributes": Attributes::default(),
+            "content_token": token,
+        }})
+    }
+
+    fn dir(path: &str, contents: Vec<serde_json::Value>) -> serde_json::Value {
+        json!({"Directory": {
+            "path": path,
+            "attributes": Attributes::default(),
+            "contents": contents,
+        }})
+    }
+
+    fn dir_data(value: serde_json::Value) -> DirectoryData {
+        match serde_json::from_value::<FileSystemObject>(value).unwrap() {
+            FileSystemObject::Directory(dir_data) => dir_data,
+            _ => panic!("not a directory"),
+        }
+    }
+
+    #[test]
+    fn identical_directories_have_no_diff() {
+        let old = dir_data(dir("/a", vec![file("f", "t1")]));
+        let new = dir_data(dir("/a", vec![file("f", "t1")]));
+        assert!(diff_directories(&old, &new).is_empty());
+        assert_eq!(old.tree_hash(), new.tree_hash());
+        let moved = dir_data(dir("/a", vec![file("g", "t1")]));
+        assert_ne!(old.tree_hash(), moved.tree_hash());
+    }
+
+    #[test]
+    fn added_removed_and_changed_are_detected() {
+        let old = dir_data(dir(
+            "/a",
+            vec![
+                file("changed", "t1"),
+                file("gone", "t2"),
+                dir("/a/sub", vec![file("same", "t3"), file("x", "t4")]),
+            ],
+        ));
+        let new = dir_data(dir(
+            "/a",
+            vec![
+                file("changed", "t5"),
+                file("new", "t6"),
+                dir("/a/sub", vec![file("same", "t3"), file("x", "t7")]),
+            ],
+        ));
+        assert_ne!(old.tree_hash(), new.tree_hash());
+        let entries = diff_directories(&old, &new);
+        let summary: Vec<(&Path, Change, EntryKind)> = entries
+            .iter()
+            .map(|e| (e.path(), e.change(), e.kind()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Path::new("/a/changed"), Change::Changed, EntryKind::File),
+                (Path::new("/a/gone"), Change::Removed, EntryKind::File),
+                (Path::new("/a/new"), Change::Added, EntryKind::File),
+                (Path::new("/a/sub"), Change::Changed, EntryKind::Directory),
+            ]
+        );
+        let sub_children: Vec<&Path> = entries[3].children().map(|e| e.path()).collect();
+        assert_eq!(sub_children, vec![Path::new("/a/sub/x")]);
+        let diff = SnapshotDiff {
+            entries,
+            ..SnapshotDiff::default()
+        };
+        assert_eq!(diff.count(Change::Changed), 2);
+        assert_eq!(diff.count(Change::Added), 1);
+        assert_eq!(diff.count(Change::Removed), 1);
+    }
+
+    #[test]
+    fn access_times_are_ignored() {
+        let mut read_since = file("f", "t1");
+        read_since["File"]["attributes"]["st_atime"] = json!(1_000_000);
+        let old = dir_data(dir("/a", vec![file("f", "t1")]));
+        let new = dir_data(dir("/a", vec![read_since]));
+        assert_eq!(old, new);
+        assert!(diff_directories(&old, &new).is_empty());
+        assert_eq!(old.tree_hash(), new.tree_hash());
+    }
+
+    #[test]
+    fn attribute_changes_alter_tree_hashes() {
+        let old = dir_data(dir("/a", vec![file("f", "t1")]));
+        for (attribute, value) in [
+            ("st_mode", json!(0o100600)),
+            ("st_mtime", json!(1_000_000)),
+            ("st_ctime_nsec", json!(7)),
+            ("capabilities", json!([1, 2, 3])),
+            ("fs_flags", json!(0x10)),
+            ("st_btime", json!(1_000)),
+        ] {
+            let mut altered = file("f", "t1");
+            altered["File"]["attributes"][attribute] = value;
+            let new = dir_data(dir("/a", vec![altered]));
+            assert_ne!(old.tree_hash(), new.tree_hash(), "{}", attribute);
+        }
+    }
+}
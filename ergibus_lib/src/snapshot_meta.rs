@@ -0,0 +1,346 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! The structural metadata of a snapshot (sizes, counts, depth and size
+//! distributions and timings) for sharing when reporting problems such
+//! as slow back ups.  When anonymized, the archive's name and every path
+//! component are replaced by salted hashes (so that the tree's shape is
+//! preserved) and symbolic links' targets are never included.  The salt
+//! is random for each export so that the hashes can't be matched against
+//! those of common names (or between exports).
+
+use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use crypto_hash::{Algorithm, Hasher};
+use hex::ToHex;
+use std::io::Write;
+
+use crate::attributes::AttributesIfce;
+use crate::fs_objects::{DirectoryData, FileStats, FileSystemObject, Name, SymLinkStats};
+use crate::snapshot::SnapshotPersistentData;
+use crate::{EResult, Error};
+
+// The number of hex digits kept from each name's hash
+const HASHED_NAME_LENGTH: usize = 16;
+
+/// The numbers of objects (and bytes in files) at a depth in the tree.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct DepthCounts {
+    pub dirs: u64,
+    pub files: u64,
+    pub sym_links: u64,
+    pub bytes: u64,
+}
+
+/// The number of files whose sizes are less than `below` (and not less
+/// than the previous bucket's `below`).
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct SizeBucket {
+    pub below: u64,
+    pub files: u64,
+}
+
+/// An object in the snapshot.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EntryMetadata {
+    pub path: String,
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SnapshotMetadata {
+    pub archive: String,
+    pub anonymized: bool,
+    pub started: String,
+    pub finished: String,
+    pub creation_seconds: f64,
+    pub file_stats: FileStats,
+    pub sym_link_stats: SymLinkStats,
+    pub dir_count: u64,
+    pub max_depth: usize,
+    /// Indexed by depth (the root directory is at depth 0)
+    pub depth_distribution: Vec<DepthCounts>,
+    pub size_distribution: Vec<SizeBucket>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub entries: Vec<EntryMetadata>,
+}
+
+struct NameHasher {
+    salt: Option<[u8; 16]>,
+}
+
+impl NameHasher {
+    fn new(anonymize: bool) -> EResult<Self> {
+        if anonymize {
+            let mut salt = [0u8; 16];
+            getrandom::getrandom(&mut salt).map_err(|err| Error::IOError(err.into()))?;
+            Ok(Self { salt: Some(salt) })
+        } else {
+            Ok(Self { salt: None })
+        }
+    }
+
+    fn name(&self, name: &[u8]) -> String {
+        match self.salt {
+            Some(salt) => {
+                let mut hasher = Hasher::new(Algorithm::SHA256);
+                hasher.write_all(&salt).expect(crate::UNEXPECTED);
+                hasher.write_all(name).expect(crate::UNEXPECTED);
+                let mut hashed: String = hasher.finish().to_hex();
+                hashed.truncate(HASHED_NAME_LENGTH);
+                hashed
+            }
+            None => String::from_utf8_lossy(name).to_string(),
+        }
+    }
+
+    fn path(&self, path: &Path) -> String {
+        if self.salt.is_none() {
+            return path.to_string_lossy().to_string();
+        }
+        let mut hashed = String::new();
+        for component in path.iter() {
+            if component == "/" {
+                hashed.push('/');
+            } else {
+                if !(hashed.is_empty() || hashed.ends_with('/')) {
+                    hashed.push('/');
+                }
+                hashed.push_str(&self.name(component.as_bytes()));
+            }
+        }
+        hashed
+    }
+}
+
+// The exclusive upper bound of the power of two bucket holding `size`
+fn size_bucket_bound(size: u64) -> u64 {
+    1u64.checked_shl(64 - size.leading_zeros())
+        .unwrap_or(u64::MAX)
+}
+
+struct Collector<'a> {
+    name_hasher: &'a NameHasher,
+    with_entries: bool,
+    dir_count: u64,
+    depth_distribution: Vec<DepthCounts>,
+    size_distribution: BTreeMap<u64, u64>,
+    entries: Vec<EntryMetadata>,
+}
+
+impl<'a> Collector<'a> {
+    fn at_depth(&mut self, depth: usize) -> &mut DepthCounts {
+        if self.depth_distribution.len() <= depth {
+            self.depth_distribution
+                .resize(depth + 1, DepthCounts::default());
+        }
+        &mut self.depth_distribution[depth]
+    }
+
+    fn add_entry(&mut self, path: String, kind: &'static str, size: Option<u64>) {
+        if self.with_entries {
+            self.entries.push(EntryMetadata { path, kind, size });
+        }
+    }
+
+    fn collect(&mut self, dir_data: &DirectoryData, depth: usize, dir_path: String) {
+        self.dir_count += 1;
+        self.at_depth(depth).dirs += 1;
+        self.add_entry(dir_path.clone(), "dir", None);
+        for fso in dir_data.contents() {
+            let name = self.name_hasher.name(fso.name().as_bytes());
+            let path = if dir_path.ends_with('/') {
+                format!("{}{}", dir_path, name)
+            } else {
+                format!("{}/{}", dir_path, name)
+            };
+            match fso {
+                FileSystemObject::File(file_data) => {
+                    let size = file_data.attributes().size();
+                    let counts = self.at_depth(depth + 1);
+                    counts.files += 1;
+                    counts.bytes += size;
+                    *self
+                        .size_distribution
+                        .entry(size_bucket_bound(size))
+                        .or_insert(0) += 1;
+                    self.add_entry(path, "file", Some(size));
+                }
+                FileSystemObject::SymLink(_, is_dir) => {
+                    self.at_depth(depth + 1).sym_links += 1;
+                    let kind = if *is_dir { "dir_link" } else { "file_link" };
+                    self.add_entry(path, kind, None);
+                }
+                FileSystemObject::Directory(subdir_data) => {
+                    self.collect(subdir_data, depth + 1, path)
+                }
+            }
+        }
+    }
+}
+
+impl SnapshotMetadata {
+    /// The metadata of the snapshot (with an entry for every object in it
+    /// if `with_entries` is true).
+    pub fn new(
+        snapshot: &SnapshotPersistentData,
+        anonymize: bool,
+        with_entries: bool,
+    ) -> EResult<Self> {
+        let name_hasher = NameHasher::new(anonymize)?;
+        let mut collector = Collector {
+            name_hasher: &name_hasher,
+            with_entries,
+            dir_count: 0,
+            depth_distribution: vec![],
+            size_distribution: BTreeMap::new(),
+            entries: vec![],
+        };
+        let root_dir = snapshot.root_dir();
+        collector.collect(root_dir, 0, name_hasher.path(root_dir.path()));
+        let (started, finished) = snapshot.creation_times();
+        Ok(Self {
+            archive: name_hasher.name(snapshot.archive_name().as_bytes()),
+            anonymized: anonymize,
+            started: DateTime::<Local>::from(started).to_rfc3339(),
+            finished: DateTime::<Local>::from(finished).to_rfc3339(),
+            creation_seconds: snapshot.creation_duration().as_secs_f64(),
+            file_stats: *snapshot.file_stats(),
+            sym_link_stats: *snapshot.sym_link_stats(),
+            dir_count: collector.dir_count,
+            max_depth: collector.depth_distribution.len().saturating_sub(1),
+            depth_distribution: collector.depth_distribution,
+            size_distribution: collector
+                .size_distribution
+                .into_iter()
+                .map(|(below, files)| SizeBucket { below, files })
+                .collect(),
+            entries: collector.entries,
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect(crate::UNEXPECTED)
+    }
+}
+
+#[cfg(test)]
+mod snapshot_meta_tests {
+    use super::*;
+    use crate::archive::{create_new_archive, Snapshots};
+    use crate::fixture::{FixtureSpec, TestConfigGuard};
+    use crate::snapshot;
+    use dychatat_lib::content::create_new_repo;
+
+    #[test]
+    fn size_buckets_are_powers_of_two() {
+        assert_eq!(size_bucket_bound(0), 1);
+        assert_eq!(size_bucket_bound(1), 2);
+        assert_eq!(size_bucket_bound(3), 4);
+        assert_eq!(size_bucket_bound(4), 8);
+        assert_eq!(size_bucket_bound(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn anonymized_paths_keep_their_shape() {
+        let name_hasher = NameHasher::new(true).unwrap();
+        let hashed = name_hasher.path(Path::new("/home/secret/secret"));
+        let components: Vec<&str> = hashed.split('/').collect();
+        assert_eq!(components.len(), 4);
+        assert_eq!(components[0], "");
+        assert_eq!(components[2], components[3]);
+        assert_ne!(components[1], components[2]);
+        assert!(components[1..]
+            .iter()
+            .all(|component| component.len() == HASHED_NAME_LENGTH));
+        assert!(!hashed.contains("secret"));
+        // a new export gets a new salt
+        let other_hasher = NameHasher::new(true).unwrap();
+        assert_ne!(
+            other_hasher.path(Path::new("/home")),
+            components[..2].join("/")
+        );
+        let plain_hasher = NameHasher::new(false).unwrap();
+        assert_eq!(plain_hasher.path(Path::new("/home/secret")), "/home/secret");
+    }
+
+    #[test]
+    fn metadata_describes_the_tree() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new()
+            .file("empty", "")
+            .file("secret_dir/secret.txt", "not to be revealed")
+            .file_of_size("secret_dir/deeper/big.bin", 5_000)
+            .symlink("secret_link", "secret_dir")
+            .build();
+        let inclusions = vec![fixture.root().to_path_buf()];
+        create_new_archive(
+            "test_meta",
+            Some("test_repo"),
+            &location,
+            &inclusions,
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        snapshot::generate_snapshot("test_meta").unwrap();
+        let snapshots = Snapshots::try_from("test_meta").unwrap();
+        let snapshot =
+            SnapshotPersistentData::from_file(snapshots.get_latest_snapshot_path().unwrap())
+                .unwrap();
+
+        let metadata = SnapshotMetadata::new(&snapshot, false, true).unwrap();
+        let files: u64 = metadata.depth_distribution.iter().map(|c| c.files).sum();
+        let bytes: u64 = metadata.depth_distribution.iter().map(|c| c.bytes).sum();
+        assert_eq!((files, bytes), (3, 5_018));
+        assert_eq!(files, snapshot.file_stats().file_count);
+        assert_eq!(bytes, snapshot.file_stats().byte_count);
+        let links: u64 = metadata
+            .depth_distribution
+            .iter()
+            .map(|c| c.sym_links)
+            .sum();
+        assert_eq!(links, 1);
+        assert_eq!(
+            metadata.size_distribution,
+            vec![
+                SizeBucket { below: 1, files: 1 },
+                SizeBucket {
+                    below: 32,
+                    files: 1
+                },
+                SizeBucket {
+                    below: 8192,
+                    files: 1
+                },
+            ]
+        );
+        assert_eq!(metadata.max_depth + 1, metadata.depth_distribution.len());
+        let deepest = metadata.depth_distribution[metadata.max_depth];
+        assert_eq!((deepest.files, deepest.bytes), (1, 5_000));
+        assert_eq!(metadata.archive, "test_meta");
+        assert!(metadata
+            .entries
+            .iter()
+            .any(|entry| entry.path.ends_with("secret_dir/secret.txt")));
+
+        let anonymized = SnapshotMetadata::new(&snapshot, true, true).unwrap();
+        assert_eq!(anonymized.depth_distribution, metadata.depth_distribution);
+        assert_eq!(anonymized.entries.len(), metadata.entries.len());
+        let json = anonymized.to_json();
+        assert!(!json.contains("secret"));
+        assert!(!json.contains("test_meta"));
+
+        let summary = SnapshotMetadata::new(&snapshot, true, false).unwrap();
+        assert!(summary.entries.is_empty());
+        assert!(!summary.to_json().contains("\"entries\""));
+    }
+}
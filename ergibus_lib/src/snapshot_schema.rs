@@ -0,0 +1,150 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! A JSON Schema (generated from the serde types) describing the JSON
+//! document in snapshot files so that other tools can read snapshots
+//! without linking this crate.  Snapshot files hold the document
+//! compressed with the snappy frame format (and, for archives that
+//! require it, encrypted).
+//!
+//! The schema carries the snapshot format version ("x-format-version")
+//! which must be incremented whenever a change to the types changes the
+//! schema.
+
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+
+use crate::snapshot::SnapshotPersistentData;
+
+/// The version of the snapshot file format described by the schema.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 4;
+
+/// The JSON Schema for snapshot files' documents.
+pub fn snapshot_schema() -> RootSchema {
+    let mut schema = schema_for!(SnapshotPersistentData);
+    let metadata = schema.schema.metadata();
+    metadata.title = Some("ergibus snapshot".to_string());
+    metadata.description = Some(
+        "The (snappy decompressed and decrypted) contents of an ergibus snapshot file".to_string(),
+    );
+    schema.schema.extensions.insert(
+        "x-format-version".to_string(),
+        SNAPSHOT_FORMAT_VERSION.into(),
+    );
+    schema
+}
+
+#[cfg(test)]
+mod snapshot_schema_tests {
+    use super::*;
+    use crate::archive::create_new_archive;
+    use crate::fixture::{FixtureSpec, TestConfigGuard};
+    use crate::snapshot::{self, get_snapshot_paths_for_archive, Order};
+    use dychatat_lib::content::create_new_repo;
+    use serde_json::Value;
+
+    // The names of properties in the document that the schema doesn't
+    // describe (following references to definitions)
+    fn undescribed(document: &Value, schema: &Value, definitions: &Value, at: &str) -> Vec<String> {
+        // NB: references with defaults, etc. are wrapped in an "allOf"
+        let schema = match schema["allOf"].as_array() {
+            Some(schemas) if schemas.len() == 1 => &schemas[0],
+            _ => schema,
+        };
+        let schema = match schema.get("$ref").and_then(Value::as_str) {
+            Some(reference) => {
+                let name = reference.trim_start_matches("#/definitions/");
+                &definitions[name]
+            }
+            None => schema,
+        };
+        let mut missing = vec![];
+        match document {
+            Value::Object(object) => {
+                for (key, value) in object.iter() {
+                    let key_at = format!("{}/{}", at, key);
+                    match schema["properties"].get(key) {
+                        Some(property) => {
+                            missing.extend(undescribed(value, property, definitions, &key_at))
+                        }
+                        // externally tagged enums (e.g. FileSystemObject)
+                        None => match schema["oneOf"].as_array() {
+                            Some(variants) => {
+                                let variant = variants
+                                    .iter()
+                                    .find(|variant| variant["properties"].get(key).is_some());
+                                match variant {
+                                    Some(variant) => missing.extend(undescribed(
+                                        value,
+                                        &variant["properties"][key],
+                                        definitions,
+                                        &key_at,
+                                    )),
+                                    None => missing.push(key_at),
+                                }
+                            }
+                            None if schema["additionalProperties"].is_object() => (),
+                            None => missing.push(key_at),
+                        },
+                    }
+                }
+            }
+            Value::Array(items) => {
+                for (index, item) in items.iter().enumerate() {
+                    let item_schema = match schema["items"] {
+                        Value::Array(ref schemas) => &schemas[index],
+                        ref item_schema => item_schema,
+                    };
+                    missing.extend(undescribed(
+                        item,
+                        item_schema,
+                        definitions,
+                        &format!("{}/{}", at, index),
+                    ));
+                }
+            }
+            _ => (),
+        }
+        missing
+    }
+
+    #[test]
+    fn snapshots_are_described_by_the_schema() {
+        let guard = TestConfigGuard::new();
+        let location = guard.data_dir();
+        create_new_repo("test_repo", &location, "Sha1").unwrap();
+        let fixture = FixtureSpec::new()
+            .file("docs/letter.txt", "Dear Sir")
+            .symlink("docs/link", "letter.txt")
+            .dir("empty")
+            .build();
+        create_new_archive(
+            "test_schema",
+            Some("test_repo"),
+            &location,
+            &[fixture.root().join("*")],
+            &[],
+            &[],
+            &[],
+            false,
+        )
+        .unwrap();
+        snapshot::generate_snapshot("test_schema").unwrap();
+        let ss_file_path =
+            get_snapshot_paths_for_archive("test_schema", Order::Descending).unwrap()[0].clone();
+        let snapshot = SnapshotPersistentData::from_file(&ss_file_path).unwrap();
+        let document = serde_json::to_value(&snapshot).unwrap();
+
+        let schema = serde_json::to_value(snapshot_schema()).unwrap();
+        assert_eq!(schema["x-format-version"], SNAPSHOT_FORMAT_VERSION);
+        let missing = undescribed(&document, &schema, &schema["definitions"], "");
+        assert!(missing.is_empty(), "{:?}", missing);
+        for required in schema["required"].as_array().unwrap() {
+            assert!(
+                document.get(required.as_str().unwrap()).is_some(),
+                "{}",
+                required
+            );
+        }
+        assert!(schema["definitions"].get("DirectoryData").is_some());
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Private temporary directories for staging files (e.g. copies of
+//! files being compared or opened).  Each staging area is created (only
+//! accessible by the user) under a per user staging root and is removed
+//! when it's dropped, so that cancelled or failed operations don't leave
+//! it behind.  As a process that crashes can't clean up after itself,
+//! the staging areas' names include the process id of their creator and
+//! those whose creators no longer exist are removed at start up (see
+//! `remove_abandoned_staging_areas()`).
+//!
+//! The staging root is "ergibus-<uid>" in the system's temporary
+//! directory unless the global configuration has a "staging_dir" or it's
+//! overridden for the current process.
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+use crate::{global_config, EResult};
+
+#[derive(Debug, Default)]
+struct StagingState {
+    root_override: Option<PathBuf>,
+    live: BTreeSet<PathBuf>,
+    count: u64,
+}
+
+lazy_static! {
+    static ref STAGING_STATE: Mutex<StagingState> = Mutex::new(StagingState::default());
+}
+
+/// Stage temporary files under this directory (instead of the configured
+/// or default staging root) for the current process.
+pub fn set_staging_root(dir_path: Option<PathBuf>) {
+    STAGING_STATE.lock().unwrap().root_override = dir_path;
+}
+
+/// The directory under which staging areas are created.
+pub fn staging_root() -> EResult<PathBuf> {
+    if let Some(ref dir_path) = STAGING_STATE.lock().unwrap().root_override {
+        return Ok(dir_path.clone());
+    }
+    match global_config::get_staging_dir()? {
+        Some(dir_path) => Ok(dir_path),
+        None => Ok(env::temp_dir().join(format!("ergibus-{}", users::get_current_uid()))),
+    }
+}
+
+/// The staging areas of the current process that still exist.
+pub fn live_staging_areas() -> Vec<PathBuf> {
+    STAGING_STATE.lock().unwrap().live.iter().cloned().collect()
+}
+
+// The process id of the creator of the staging area with this name
+fn creator_pid(dir_name: &str) -> Option<u32> {
+    let mut parts = dir_name.rsplitn(3, '-');
+    parts.next()?.parse::<u64>().ok()?;
+    let pid = parts.next()?.parse().ok()?;
+    parts.next().map(|_| pid)
+}
+
+fn process_exists(pid: u32) -> bool {
+    // NB: signal 0 checks for existence without sending anything
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Remove the staging areas left behind by processes that no longer
+/// exist (e.g. because they crashed) and return their paths.
+pub fn remove_abandoned_staging_areas() -> EResult<Vec<PathBuf>> {
+    let root = staging_root()?;
+    let read_dir = match fs::read_dir(&root) {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut removed = vec![];
+    for entry in read_dir.filter_map(|entry| entry.ok()) {
+        let pid = match entry.file_name().to_str().and_then(creator_pid) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if pid == process::id() || process_exists(pid) {
+            continue;
+        }
+        let path = entry.path();
+        match fs::remove_dir_all(&path) {
+            Ok(()) => removed.push(path),
+            Err(err) => log::warn!("{:?}: failed to remove staging area: {}", path, err),
+        }
+    }
+    Ok(removed)
+}
+
+/// A private temporary directory that is removed when dropped.
+#[derive(Debug)]
+pub struct StagingArea {
+    path: PathBuf,
+}
+
+impl StagingArea {
+    /// Create a new staging area whose name starts with `namespace` (e.g.
+    /// "diff" or "open").
+    pub fn new(namespace: &str) -> EResult<Self> {
+        let root = staging_root()?;
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&root)?;
+        let mut state = STAGING_STATE.lock().unwrap();
+        state.count += 1;
+        let path = root.join(format!("{}-{}-{}", namespace, process::id(), state.count));
+        fs::DirBuilder::new().mode(0o700).create(&path)?;
+        // NB: the mode given to create() is subject to the umask
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o700))?;
+        state.live.insert(path.clone());
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn remove(&mut self) -> std::io::Result<()> {
+        if self.path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let path = std::mem::take(&mut self.path);
+        STAGING_STATE.lock().unwrap().live.remove(&path);
+        match fs::remove_dir_all(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove the staging area (and its contents) reporting any failure.
+    pub fn close(mut self) -> EResult<()> {
+        Ok(self.remove()?)
+    }
+}
+
+impl Drop for StagingArea {
+    fn drop(&mut self) {
+        if let Err(err) = self.remove() {
+            log::warn!("failed to remove staging area: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod staging_tests {
+    use super::*;
+    use crate::fixture::TestConfigGuard;
+
+    #[test]
+    fn creator_pids_are_found() {
+        assert_eq!(creator_pid("diff-1234-5"), Some(1234));
+        assert_eq!(creator_pid("open-with-1234-5"), Some(1234));
+        assert_eq!(creator_pid("diff-1234"), None);
+        assert_eq!(creator_pid("diff-x-5"), None);
+        assert_eq!(creator_pid("lost+found"), None);
+    }
+
+    #[test]
+    fn staging_areas_are_cleaned_up() {
+        let guard = TestConfigGuard::new();
+        let root = guard.data_dir().join("staging");
+        set_staging_root(Some(root.clone()));
+
+        let area = StagingArea::new("test").unwrap();
+        let path = area.path().to_path_buf();
+        assert!(path.starts_with(&root));
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        assert!(live_staging_areas().contains(&path));
+        fs::write(path.join("file"), "contents").unwrap();
+        drop(area);
+        assert!(!path.exists());
+        assert!(!live_staging_areas().contains(&path));
+
+        let area = StagingArea::new("test").unwrap();
+        let path = area.path().to_path_buf();
+        area.close().unwrap();
+        assert!(!path.exists());
+
+        // one left by a crashed process (i.e. one that no longer exists)
+        let mut child = process::Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+        let abandoned = root.join(format!("test-{}-1", dead_pid));
+        fs::create_dir_all(abandoned.join("sub")).unwrap();
+        let live = StagingArea::new("test").unwrap();
+        assert_eq!(remove_abandoned_staging_areas().unwrap(), vec![abandoned]);
+        assert!(live.path().exists());
+        drop(live);
+        set_staging_root(None);
+    }
+}
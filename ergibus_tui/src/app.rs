@@ -0,0 +1,339 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::{
+    layout::{Constraint, Direction, Layout},
+    style::{Modifier, Style},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    Frame,
+};
+
+use ergibus_lib::{
+    archive,
+    fs_objects::{CopyOptions, ExtractionStats, FileSystemObject, Name},
+    progress::ProgressEvent,
+    snapshot::{self, Order, SnapshotPersistentData},
+    EResult,
+};
+
+enum Screen {
+    Archives,
+    Snapshots {
+        archive_name: String,
+    },
+    Contents {
+        archive_name: String,
+        snapshot: Box<SnapshotPersistentData>,
+        dir_path: PathBuf,
+    },
+}
+
+// The item waiting for the user to nominate where it should be restored
+struct PendingRestore {
+    path: PathBuf,
+    is_dir: bool,
+    target_dir: String,
+}
+
+pub struct App {
+    screen: Screen,
+    items: Vec<String>,
+    list_state: ListState,
+    status: String,
+    pending_restore: Option<PendingRestore>,
+    quit: bool,
+}
+
+impl App {
+    pub fn new() -> Self {
+        let mut app = Self {
+            screen: Screen::Archives,
+            items: vec![],
+            list_state: ListState::default(),
+            status: String::new(),
+            pending_restore: None,
+            quit: false,
+        };
+        app.refresh();
+        app
+    }
+
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    fn refresh(&mut self) {
+        let items = match &self.screen {
+            Screen::Archives => Ok(archive::get_archive_names()),
+            Screen::Snapshots { archive_name } => {
+                snapshot::get_snapshot_names_for_archive(archive_name, Order::Descending).map(
+                    |names| {
+                        names
+                            .iter()
+                            .map(|name| name.to_string_lossy().to_string())
+                            .collect()
+                    },
+                )
+            }
+            Screen::Contents {
+                snapshot, dir_path, ..
+            } => snapshot
+                .find_subdir(dir_path)
+                .map(|dir| dir.contents().map(|fso| fso.to_string()).collect()),
+        };
+        match items {
+            Ok(items) => self.items = items,
+            Err(err) => {
+                self.items = vec![];
+                self.status = format!("Error: {}", err);
+            }
+        }
+        self.list_state
+            .select(if self.items.is_empty() { None } else { Some(0) });
+    }
+
+    fn title(&self) -> String {
+        match &self.screen {
+            Screen::Archives => "Archives".to_string(),
+            Screen::Snapshots { archive_name } => format!("{}: snapshots", archive_name),
+            Screen::Contents {
+                archive_name,
+                dir_path,
+                ..
+            } => format!("{}: {}", archive_name, dir_path.display()),
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match self.screen {
+            Screen::Archives => "Enter: snapshots  b: back up  q: quit",
+            Screen::Snapshots { .. } => "Enter: browse  Esc: archives  q: quit",
+            Screen::Contents { .. } => {
+                "Enter: open dir  r: restore  Backspace: up  Esc: snapshots  q: quit"
+            }
+        }
+    }
+
+    fn selected_item(&self) -> Option<&str> {
+        self.list_state
+            .selected()
+            .and_then(|index| self.items.get(index))
+            .map(|item| item.as_str())
+    }
+
+    fn selected_object(&self) -> Option<(PathBuf, bool)> {
+        if let Screen::Contents {
+            snapshot, dir_path, ..
+        } = &self.screen
+        {
+            let index = self.list_state.selected()?;
+            let dir = snapshot.find_subdir(dir_path).ok()?;
+            let fso = dir.contents().nth(index)?;
+            let is_dir = matches!(fso, FileSystemObject::Directory(_));
+            Some((dir.path().join(fso.name()), is_dir))
+        } else {
+            None
+        }
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) {
+        if self.pending_restore.is_some() {
+            self.handle_restore_key(key);
+            return;
+        }
+        match key.code {
+            KeyCode::Char('q') => self.quit = true,
+            KeyCode::Down | KeyCode::Char('j') => self.list_state.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => self.list_state.select_previous(),
+            KeyCode::Enter => self.open_selection(),
+            KeyCode::Backspace => self.go_up(),
+            KeyCode::Esc => self.go_back(),
+            KeyCode::Char('b') => self.back_up_selection(),
+            KeyCode::Char('r') => self.start_restore(),
+            _ => (),
+        }
+    }
+
+    fn open_selection(&mut self) {
+        let selected = match self.selected_item() {
+            Some(item) => item.to_string(),
+            None => return,
+        };
+        match &self.screen {
+            Screen::Archives => {
+                self.screen = Screen::Snapshots {
+                    archive_name: selected,
+                }
+            }
+            Screen::Snapshots { archive_name } => {
+                let archive_name = archive_name.clone();
+                match snapshot::get_named_snapshot(&archive_name, &OsString::from(&selected)) {
+                    Ok(snapshot) => {
+                        let dir_path = snapshot.base_dir_path().to_path_buf();
+                        self.screen = Screen::Contents {
+                            archive_name,
+                            snapshot: Box::new(snapshot),
+                            dir_path,
+                        }
+                    }
+                    Err(err) => {
+                        self.status = format!("Error: {}", err);
+                        return;
+                    }
+                }
+            }
+            Screen::Contents { .. } => match self.selected_object() {
+                Some((path, true)) => {
+                    if let Screen::Contents { dir_path, .. } = &mut self.screen {
+                        *dir_path = path
+                    }
+                }
+                _ => return,
+            },
+        }
+        self.status.clear();
+        self.refresh();
+    }
+
+    fn go_up(&mut self) {
+        if let Screen::Contents { dir_path, .. } = &mut self.screen {
+            if let Some(parent) = dir_path.parent() {
+                *dir_path = parent.to_path_buf();
+                self.refresh();
+            }
+        }
+    }
+
+    fn go_back(&mut self) {
+        self.screen = match &self.screen {
+            Screen::Archives => return,
+            Screen::Snapshots { .. } => Screen::Archives,
+            Screen::Contents { archive_name, .. } => Screen::Snapshots {
+                archive_name: archive_name.clone(),
+            },
+        };
+        self.status.clear();
+        self.refresh();
+    }
+
+    fn back_up_selection(&mut self) {
+        let archive_name = match (&self.screen, self.selected_item()) {
+            (Screen::Archives, Some(archive_name)) => archive_name.to_string(),
+            _ => return,
+        };
+        let mut warnings = 0;
+        for event in snapshot::generate_snapshot_with_progress(&archive_name) {
+            match event {
+                ProgressEvent::Warning(_) => warnings += 1,
+                ProgressEvent::Finished { stats } => {
+                    self.status = format!(
+                        "{}: backed up {} files ({} bytes) in {:?} with {} warnings",
+                        archive_name, stats.1.file_count, stats.1.byte_count, stats.0, warnings
+                    )
+                }
+                ProgressEvent::Failed(err) => {
+                    self.status = format!("{}: back up failed: {}", archive_name, err)
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn start_restore(&mut self) {
+        if let Some((path, is_dir)) = self.selected_object() {
+            let target_dir = env::current_dir()
+                .map(|dir| dir.to_string_lossy().to_string())
+                .unwrap_or_default();
+            self.pending_restore = Some(PendingRestore {
+                path,
+                is_dir,
+                target_dir,
+            });
+        }
+    }
+
+    fn handle_restore_key(&mut self, key: KeyEvent) {
+        let pending = self.pending_restore.as_mut().expect("checked by caller");
+        match key.code {
+            KeyCode::Char(c) => pending.target_dir.push(c),
+            KeyCode::Backspace => {
+                pending.target_dir.pop();
+            }
+            KeyCode::Esc => self.pending_restore = None,
+            KeyCode::Enter => {
+                let pending = self.pending_restore.take().expect("checked above");
+                self.status = match self.restore(&pending) {
+                    Ok(stats) => format!(
+                        "Restored {} files ({} bytes) to {}",
+                        stats.file_count, stats.bytes_count, pending.target_dir
+                    ),
+                    Err(err) => format!("Restore of {} failed: {}", pending.path.display(), err),
+                };
+            }
+            _ => (),
+        }
+    }
+
+    fn restore(&self, pending: &PendingRestore) -> EResult<ExtractionStats> {
+        if let Screen::Contents { snapshot, .. } = &self.screen {
+            let file_name = pending.path.file_name().unwrap_or_default();
+            let target_path = Path::new(&pending.target_dir).join(file_name);
+            if pending.is_dir {
+                snapshot.copy_dir_to(&pending.path, &target_path, &CopyOptions::default())
+            } else {
+                let bytes_count =
+                    snapshot.copy_file_to(&pending.path, &target_path, false, false)?;
+                Ok(ExtractionStats {
+                    file_count: 1,
+                    bytes_count,
+                    ..ExtractionStats::default()
+                })
+            }
+        } else {
+            Ok(ExtractionStats::default())
+        }
+    }
+
+    pub fn draw(&mut self, frame: &mut Frame) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(3),
+                Constraint::Length(3),
+                Constraint::Length(1),
+            ])
+            .split(frame.area());
+
+        let items: Vec<ListItem> = self
+            .items
+            .iter()
+            .map(|item| ListItem::new(item.as_str()))
+            .collect();
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(self.title()))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, chunks[0], &mut self.list_state);
+
+        let status = match &self.pending_restore {
+            Some(pending) => format!(
+                "Restore {} into: {}",
+                pending.path.display(),
+                pending.target_dir
+            ),
+            None => self.status.clone(),
+        };
+        let status = Paragraph::new(status).block(Block::default().borders(Borders::ALL));
+        frame.render_widget(status, chunks[1]);
+
+        let help = if self.pending_restore.is_some() {
+            "Enter: restore  Esc: cancel"
+        } else {
+            self.help()
+        };
+        frame.render_widget(Paragraph::new(help), chunks[2]);
+    }
+}
@@ -0,0 +1,31 @@
+// Copyright 2024 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+mod app;
+
+use std::io;
+
+use crossterm::event::{self, Event, KeyEventKind};
+
+use crate::app::App;
+
+fn main() -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut app = App::new();
+    let result = loop {
+        if let Err(err) = terminal.draw(|frame| app.draw(frame)) {
+            break Err(err);
+        }
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                app.handle_key(key);
+                if app.quit_requested() {
+                    break Ok(());
+                }
+            }
+            Ok(_) => (),
+            Err(err) => break Err(err),
+        }
+    };
+    ratatui::restore();
+    result
+}
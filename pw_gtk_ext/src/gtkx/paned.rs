@@ -2,34 +2,24 @@
 
 use log;
 use recollections;
-use std::str::FromStr;
 
 pub trait RememberPosition: gtk::WidgetExt + gtk::PanedExt {
     fn recall_last_position(&self, paned_name: &str, default: i32) -> i32 {
         let key = format!("{}::paned::last_position", paned_name);
         let key_c = key.clone();
         self.connect_property_position_notify(move |paned| {
-            let position = paned.get_position();
-            let text = format!("{}", position);
-            recollections::remember(key_c.as_str(), text.as_str());
+            recollections::remember_int(key_c.as_str(), paned.get_position());
         });
-        match recollections::recall(key.as_str()) {
-            Some(last_position_str) => match i32::from_str(last_position_str.as_str()) {
-                Ok(last_position) => last_position,
-                Err(err) => {
-                    log::error!(
-                        "Recollections: error parsing \"{}\" for \"{}\": {}",
-                        last_position_str,
-                        key,
-                        err
-                    );
-                    default
-                }
-            },
-            None => {
+        match recollections::recall_int(key.as_str()) {
+            Ok(Some(last_position)) => last_position,
+            Ok(None) => {
                 log::warn!("Recollections: {}: unknown", key);
                 default
             }
+            Err(err) => {
+                log::error!("Recollections: {}", err);
+                default
+            }
         }
     }
 
@@ -5,25 +5,27 @@ use std::io::{self, Write};
 use gtk;
 use gtk::prelude::*;
 
-use crate::gdkx::*;
+use crate::gdkx::format_geometry;
 use crate::recollections;
 
 pub trait RememberGeometry: gtk::WidgetExt + gtk::GtkWindowExt {
     fn set_geometry_from_recollections(&self, window_name: &str, default_size: (i32, i32)) {
         let key = format!("{}::window::last_geometry", window_name);
-        if let Some(last_geometry) = recollections::recall(key.as_str()) {
-            if let Ok((width, height, x, y)) = parse_geometry(last_geometry.as_str()) {
-                self.set_default_size(width, height);
-                self.move_(x, y);
-            } else {
-                let msg = format!("Error parsing \"{}\"\n", key);
+        match recollections::recall_geometry(key.as_str()) {
+            Ok(Some(geometry)) => {
+                self.set_default_size(geometry.width, geometry.height);
+                if let Some((x, y)) = geometry.position {
+                    self.move_(x, y);
+                }
+            }
+            Ok(None) => self.set_default_size(default_size.0, default_size.1),
+            Err(err) => {
+                let msg = format!("Error parsing \"{}\": {}\n", key, err);
                 io::stderr()
                     .write_all(msg.as_bytes())
                     .expect("nowhere to go");
                 self.set_default_size(default_size.0, default_size.1)
             }
-        } else {
-            self.set_default_size(default_size.0, default_size.1)
         }
         self.connect_configure_event(move |_, event| {
             let text = format_geometry(event);
@@ -0,0 +1,425 @@
+// Copyright 2021 Peter Williams <pwil3058@gmail.com> <pwil3058@bigpond.net.au>
+
+//! Provide a mechanism for widgets to remember configuration
+//! data (size, position, etc.) from one session to the next.
+//!
+//! Values are held in memory and only written back to the data file
+//! when `flush()` is called (or the `Recollections` is dropped).  The
+//! file is replaced atomically and values remembered by other processes
+//! sharing the file in the meantime are preserved.
+
+#[macro_use]
+extern crate lazy_static;
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use fs2::FileExt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RecollectionsError {
+    #[error("{1:?}: I/O error: {0}")]
+    IOError(io::Error, PathBuf),
+    #[error("{1:?}: malformed recollections file: {0}")]
+    JsonError(serde_json::Error, PathBuf),
+    #[error("\"{0}\": unexpected value \"{1}\"")]
+    BadValue(String, String),
+}
+
+pub type RResult<T> = Result<T, RecollectionsError>;
+
+type RecollectionDb = HashMap<String, String>;
+
+/// A window's (or dialog's) size and, optionally, position in the
+/// "WxH+X+Y" (or "WxH") form understood by `gtk::Window::parse_geometry()`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Geometry {
+    pub width: i32,
+    pub height: i32,
+    pub position: Option<(i32, i32)>,
+}
+
+impl Geometry {
+    fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.splitn(2, '+');
+        let (width, height) = parts.next()?.split_once('x')?;
+        let position = match parts.next() {
+            Some(position) => {
+                let (x, y) = position.split_once('+')?;
+                Some((x.parse().ok()?, y.parse().ok()?))
+            }
+            None => None,
+        };
+        Some(Self {
+            width: width.parse().ok()?,
+            height: height.parse().ok()?,
+            position,
+        })
+    }
+}
+
+impl fmt::Display for Geometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)?;
+        if let Some((x, y)) = self.position {
+            write!(f, "+{}+{}", x, y)?;
+        }
+        Ok(())
+    }
+}
+
+fn sibling_path(file_path: &Path, suffix: &str) -> PathBuf {
+    let mut path: OsString = file_path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn read_db(file_path: &Path) -> RResult<RecollectionDb> {
+    match File::open(file_path) {
+        Ok(file) => serde_json::from_reader(&file)
+            .map_err(|err| RecollectionsError::JsonError(err, file_path.to_path_buf())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(RecollectionDb::new()),
+        Err(err) => Err(RecollectionsError::IOError(err, file_path.to_path_buf())),
+    }
+}
+
+// Write to a temporary file and rename it so that readers never see a
+// partially written file.
+fn write_db(file_path: &Path, db: &RecollectionDb) -> RResult<()> {
+    let temp_file_path = sibling_path(file_path, ".tmp");
+    let io_error = |err| RecollectionsError::IOError(err, temp_file_path.clone());
+    let mut file = File::create(&temp_file_path).map_err(io_error)?;
+    serde_json::to_writer(&file, db)
+        .map_err(|err| RecollectionsError::JsonError(err, temp_file_path.clone()))?;
+    file.flush().map_err(io_error)?;
+    file.sync_all().map_err(io_error)?;
+    fs::rename(&temp_file_path, file_path)
+        .map_err(|err| RecollectionsError::IOError(err, file_path.to_path_buf()))
+}
+
+// Serialise read-modify-write cycles between processes.  A separate lock
+// file is used as the data file itself is replaced on each write.
+fn lock_db(file_path: &Path) -> RResult<File> {
+    let lock_file_path = sibling_path(file_path, ".lock");
+    let io_error = |err| RecollectionsError::IOError(err, lock_file_path.clone());
+    if let Some(dir_path) = file_path.parent() {
+        fs::create_dir_all(dir_path).map_err(io_error)?;
+    }
+    let lock_file = File::create(&lock_file_path).map_err(io_error)?;
+    lock_file.lock_exclusive().map_err(io_error)?;
+    Ok(lock_file)
+}
+
+#[derive(Debug, Default)]
+pub struct Recollections {
+    file_path: Option<PathBuf>,
+    cache: RecollectionDb,
+    dirty: HashSet<String>,
+}
+
+impl Recollections {
+    /// Without a `file_path` values are remembered for the life of the
+    /// `Recollections` only.
+    pub fn new(file_path: Option<&Path>) -> RResult<Self> {
+        let mut recollections = Self::default();
+        if let Some(file_path) = file_path {
+            recollections.set_data_file_path(file_path)?;
+        }
+        Ok(recollections)
+    }
+
+    /// Load the values stored in `file_path` (which needn't exist yet).
+    /// Any unsaved changes are written to the previous file first.
+    pub fn set_data_file_path(&mut self, file_path: &Path) -> RResult<()> {
+        self.flush()?;
+        self.cache = read_db(file_path)?;
+        self.file_path = Some(file_path.to_path_buf());
+        Ok(())
+    }
+
+    pub fn file_path(&self) -> Option<&Path> {
+        self.file_path.as_deref()
+    }
+
+    /// Are there changes that haven't been written to the data file?
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    pub fn recall(&self, name: &str) -> Option<String> {
+        self.cache.get(name).cloned()
+    }
+
+    pub fn recall_or_else(&self, name: &str, default: &str) -> String {
+        match self.recall(name) {
+            Some(string) => string,
+            None => default.to_string(),
+        }
+    }
+
+    pub fn remember(&mut self, name: &str, value: &str) {
+        if self.cache.get(name).map(String::as_str) != Some(value) {
+            self.cache.insert(name.to_string(), value.to_string());
+            self.dirty.insert(name.to_string());
+        }
+    }
+
+    fn recall_parsed<T>(
+        &self,
+        name: &str,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> RResult<Option<T>> {
+        match self.cache.get(name) {
+            Some(value) => match parse(value) {
+                Some(parsed) => Ok(Some(parsed)),
+                None => Err(RecollectionsError::BadValue(
+                    name.to_string(),
+                    value.to_string(),
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn recall_int(&self, name: &str) -> RResult<Option<i32>> {
+        self.recall_parsed(name, |value| value.parse().ok())
+    }
+
+    pub fn recall_bool(&self, name: &str) -> RResult<Option<bool>> {
+        self.recall_parsed(name, |value| value.parse().ok())
+    }
+
+    pub fn recall_geometry(&self, name: &str) -> RResult<Option<Geometry>> {
+        self.recall_parsed(name, Geometry::parse)
+    }
+
+    pub fn remember_int(&mut self, name: &str, value: i32) {
+        self.remember(name, &value.to_string())
+    }
+
+    pub fn remember_bool(&mut self, name: &str, value: bool) {
+        self.remember(name, &value.to_string())
+    }
+
+    pub fn remember_geometry(&mut self, name: &str, value: Geometry) {
+        self.remember(name, &value.to_string())
+    }
+
+    /// Write unsaved changes to the data file merging them with the
+    /// file's current contents.  Afterwards, values remembered by other
+    /// processes are also available.
+    pub fn flush(&mut self) -> RResult<()> {
+        let file_path = match self.file_path {
+            Some(ref file_path) if self.is_dirty() => file_path,
+            _ => return Ok(()),
+        };
+        let lock_file = lock_db(file_path)?;
+        let mut db = read_db(file_path)?;
+        for name in self.dirty.iter() {
+            if let Some(value) = self.cache.get(name) {
+                db.insert(name.clone(), value.clone());
+            }
+        }
+        write_db(file_path, &db)?;
+        lock_file.unlock().ok();
+        self.cache = db;
+        self.dirty.clear();
+        Ok(())
+    }
+}
+
+impl Drop for Recollections {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::error!("Recollections: {}", err);
+        }
+    }
+}
+
+lazy_static! {
+    static ref RECOLLECTIONS: Mutex<Recollections> = Mutex::new(Recollections::default());
+}
+
+/// Initialise the mechanism by providing the path of the file
+/// where the data should be stored.  This would normally be a
+/// hidden file in the user's home directory or a hidden configuration
+/// directory for the application.
+///
+/// This function should normally be called early in the application's
+/// `main()` function and `flush()` called before it exits e.g.
+///
+/// ```no_run
+/// fn main_() {
+///     let home_dir = dirs::home_dir().expect("badly designed OS");
+///     if let Err(err) = recollections::init(&home_dir.join(".this_apps_recollections")) {
+///         log::error!("{}", err);
+///     }
+///     // run the application
+///     if let Err(err) = recollections::flush() {
+///         log::error!("{}", err);
+///     }
+/// }
+/// ```
+///
+/// If this initialisation is not performed (or fails) then values
+/// are only remembered until the application exits.  The operation of
+/// the application will not be effected otherwise.
+pub fn init<P: AsRef<Path>>(file_path: P) -> RResult<()> {
+    RECOLLECTIONS
+        .lock()
+        .unwrap()
+        .set_data_file_path(file_path.as_ref())
+}
+
+/// Write any unsaved changes to the file nominated by `init()`.
+pub fn flush() -> RResult<()> {
+    RECOLLECTIONS.lock().unwrap().flush()
+}
+
+/// Return the `String` value associated with the given `name` or
+/// `None` if nothing has been remembered for `name`.
+pub fn recall(name: &str) -> Option<String> {
+    RECOLLECTIONS.lock().unwrap().recall(name)
+}
+
+/// Return the `String` value associated with the given `name` or
+/// `default` if nothing has been remembered for `name`.
+pub fn recall_or_else(name: &str, default: &str) -> String {
+    RECOLLECTIONS.lock().unwrap().recall_or_else(name, default)
+}
+
+pub fn recall_int(name: &str) -> RResult<Option<i32>> {
+    RECOLLECTIONS.lock().unwrap().recall_int(name)
+}
+
+pub fn recall_bool(name: &str) -> RResult<Option<bool>> {
+    RECOLLECTIONS.lock().unwrap().recall_bool(name)
+}
+
+pub fn recall_geometry(name: &str) -> RResult<Option<Geometry>> {
+    RECOLLECTIONS.lock().unwrap().recall_geometry(name)
+}
+
+/// Remember the string specified by `value` and associate it with
+/// the given `name` for later recall.
+pub fn remember(name: &str, value: &str) {
+    RECOLLECTIONS.lock().unwrap().remember(name, value)
+}
+
+pub fn remember_int(name: &str, value: i32) {
+    RECOLLECTIONS.lock().unwrap().remember_int(name, value)
+}
+
+pub fn remember_bool(name: &str, value: bool) {
+    RECOLLECTIONS.lock().unwrap().remember_bool(name, value)
+}
+
+pub fn remember_geometry(name: &str, value: Geometry) {
+    RECOLLECTIONS.lock().unwrap().remember_geometry(name, value)
+}
+
+#[cfg(test)]
+mod recollections_tests {
+    use super::*;
+
+    #[test]
+    fn recollect_test() {
+        let dir = tempdir::TempDir::new("RECOLLECTIONS_TEST").unwrap();
+        let recollection_file = dir.path().join("sub_dir").join("recollections");
+        let mut recollections = Recollections::new(Some(&recollection_file)).unwrap();
+        assert_eq!(recollections.recall("anything"), None);
+        assert_eq!(recollections.recall_or_else("anything", "but"), "but");
+        recollections.remember("anything", "whatever");
+        assert_eq!(
+            recollections.recall("anything"),
+            Some("whatever".to_string())
+        );
+        assert_eq!(recollections.recall_or_else("anything", "but"), "whatever");
+        assert!(recollections.is_dirty());
+        assert!(!recollection_file.exists());
+        recollections.flush().unwrap();
+        assert!(!recollections.is_dirty());
+        assert!(recollection_file.exists());
+        assert!(!sibling_path(&recollection_file, ".tmp").exists());
+        drop(recollections);
+        let recollections = Recollections::new(Some(&recollection_file)).unwrap();
+        assert_eq!(
+            recollections.recall("anything"),
+            Some("whatever".to_string())
+        );
+    }
+
+    #[test]
+    fn typed_values() {
+        let mut recollections = Recollections::new(None).unwrap();
+        assert_eq!(recollections.recall_int("int").unwrap(), None);
+        recollections.remember_int("int", -42);
+        assert_eq!(recollections.recall_int("int").unwrap(), Some(-42));
+        recollections.remember_bool("bool", true);
+        assert_eq!(recollections.recall_bool("bool").unwrap(), Some(true));
+        for geometry in &[
+            Geometry {
+                width: 640,
+                height: 480,
+                position: Some((10, -20)),
+            },
+            Geometry {
+                width: 200,
+                height: 100,
+                position: None,
+            },
+        ] {
+            recollections.remember_geometry("geometry", *geometry);
+            assert_eq!(
+                recollections.recall_geometry("geometry").unwrap(),
+                Some(*geometry)
+            );
+        }
+        assert_eq!(recollections.recall("geometry").unwrap(), "200x100");
+        recollections.remember("geometry", "200x");
+        assert!(matches!(
+            recollections.recall_geometry("geometry"),
+            Err(RecollectionsError::BadValue(_, _))
+        ));
+        assert!(recollections.recall_bool("int").is_err());
+        // nowhere to write them so there's nothing to flush
+        recollections.flush().unwrap();
+    }
+
+    #[test]
+    fn flush_preserves_others_values() {
+        let dir = tempdir::TempDir::new("RECOLLECTIONS_TEST").unwrap();
+        let recollection_file = dir.path().join("recollections");
+        let mut first = Recollections::new(Some(&recollection_file)).unwrap();
+        let mut second = Recollections::new(Some(&recollection_file)).unwrap();
+        first.remember("first", "1");
+        first.remember("shared", "first");
+        second.remember("second", "2");
+        second.remember("shared", "second");
+        first.flush().unwrap();
+        second.flush().unwrap();
+        assert_eq!(second.recall("first"), Some("1".to_string()));
+        assert_eq!(second.recall("shared"), Some("second".to_string()));
+        let third = Recollections::new(Some(&recollection_file)).unwrap();
+        assert_eq!(third.recall("first"), Some("1".to_string()));
+        assert_eq!(third.recall("second"), Some("2".to_string()));
+        assert_eq!(third.recall("shared"), Some("second".to_string()));
+    }
+
+    #[test]
+    fn malformed_files_are_reported() {
+        let dir = tempdir::TempDir::new("RECOLLECTIONS_TEST").unwrap();
+        let recollection_file = dir.path().join("recollections");
+        fs::write(&recollection_file, "not json").unwrap();
+        assert!(matches!(
+            Recollections::new(Some(&recollection_file)),
+            Err(RecollectionsError::JsonError(_, _))
+        ));
+    }
+}